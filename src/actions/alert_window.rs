@@ -0,0 +1,156 @@
+//! Windows of time that notifications are allowed to be sent in, eg business hours
+
+use chrono::{Datelike, NaiveTime, Weekday};
+
+use crate::prelude::*;
+use crate::services::parse_timezone;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// A day of the week
+pub enum Day {
+    /// Monday
+    Mon,
+    /// Tuesday
+    Tue,
+    /// Wednesday
+    Wed,
+    /// Thursday
+    Thu,
+    /// Friday
+    Fri,
+    /// Saturday
+    Sat,
+    /// Sunday
+    Sun,
+}
+
+impl From<Weekday> for Day {
+    fn from(value: Weekday) -> Self {
+        match value {
+            Weekday::Mon => Self::Mon,
+            Weekday::Tue => Self::Tue,
+            Weekday::Wed => Self::Wed,
+            Weekday::Thu => Self::Thu,
+            Weekday::Fri => Self::Fri,
+            Weekday::Sat => Self::Sat,
+            Weekday::Sun => Self::Sun,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// When notifications can be sent, eg `{"days": ["mon", "tue", "wed", "thu", "fri"], "start": "08:00", "end": "18:00"}`
+///
+/// Results outside the window are held back, and a "still failing" notification is sent when it opens.
+pub struct AlertWindow {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Days the window opens on, every day if it's empty
+    pub days: Vec<Day>,
+    /// When the window opens, `HH:MM`
+    pub start: String,
+    /// When the window closes, `HH:MM`, if it's before `start` the window runs past midnight
+    pub end: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The timezone `start` and `end` are in, eg `Europe/Berlin`, defaults to UTC
+    pub timezone: Option<String>,
+}
+
+fn parse_time(field: &str, value: &str) -> Result<NaiveTime, Error> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").map_err(|_| {
+        Error::Configuration(format!(
+            "alert_window {} '{}' should be a time like 08:00",
+            field, value
+        ))
+    })
+}
+
+impl AlertWindow {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        parse_time("start", &self.start)?;
+        parse_time("end", &self.end)?;
+        if let Some(timezone) = &self.timezone {
+            parse_timezone(timezone)?;
+        }
+        Ok(())
+    }
+
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&Day::from(day))
+    }
+
+    /// If notifications can be sent at this time, a window that doesn't parse is always open
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        let (start, end) = match (
+            parse_time("start", &self.start),
+            parse_time("end", &self.end),
+        ) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return true,
+        };
+        let timezone = self
+            .timezone
+            .as_deref()
+            .and_then(|timezone| parse_timezone(timezone).ok())
+            .unwrap_or(chrono_tz::Tz::UTC);
+        let local = at.with_timezone(&timezone);
+        let time = local.time();
+        let today = local.weekday();
+
+        if start == end {
+            self.opens_on(today)
+        } else if start < end {
+            self.opens_on(today) && start <= time && time < end
+        } else {
+            // overnight, the part after midnight belongs to the day it opened on
+            (self.opens_on(today) && time >= start) || (self.opens_on(today.pred()) && time < end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .expect("Failed to parse time")
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_alert_window() {
+        let window: AlertWindow = serde_json::from_value(json!({
+            "days": ["mon", "tue", "wed", "thu", "fri"],
+            "start": "08:00",
+            "end": "18:00",
+            "timezone": "Europe/Berlin",
+        }))
+        .expect("Failed to parse alert window");
+        assert!(window.validate().is_ok());
+
+        // 2025-01-06 is a Monday, Berlin is UTC+1
+        assert!(window.contains(at("2025-01-06T07:00:00Z")));
+        assert!(!window.contains(at("2025-01-06T06:59:00Z")));
+        assert!(!window.contains(at("2025-01-06T17:00:00Z")));
+        assert!(!window.contains(at("2025-01-04T12:00:00Z")));
+
+        let overnight = AlertWindow {
+            days: vec![Day::Fri],
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+            timezone: None,
+        };
+        assert!(overnight.contains(at("2025-01-10T23:00:00Z")));
+        assert!(overnight.contains(at("2025-01-11T05:59:00Z")));
+        assert!(!overnight.contains(at("2025-01-11T23:00:00Z")));
+        assert!(!overnight.contains(at("2025-01-10T05:00:00Z")));
+
+        let bad = AlertWindow {
+            start: "8am".to_string(),
+            ..overnight
+        };
+        assert!(bad.validate().is_err());
+        assert!(bad.contains(at("2025-01-06T12:00:00Z")));
+    }
+}
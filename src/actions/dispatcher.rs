@@ -0,0 +1,696 @@
+//! Sends check results to the configured actions, rate limiting each of them so a big incident
+//! doesn't turn into a flood of notifications.
+
+use std::collections::VecDeque;
+use std::sync::LazyLock;
+
+use super::alert_window::AlertWindow;
+use super::routing::{EffectiveRouting, NotificationRoutes, NotificationTarget};
+use super::Action;
+use crate::labels::Labels;
+use crate::prelude::*;
+
+/// The dispatcher for check results, set up from the config with [ActionDispatcher::configure]
+pub static DISPATCHER: LazyLock<tokio::sync::Mutex<ActionDispatcher>> =
+    LazyLock::new(Default::default);
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+/// Limits how many times an action can run in a time window, eg 10 messages per 5 minutes
+pub struct RateLimit {
+    /// How many times the action can run in the window
+    pub max_messages: usize,
+    /// The length of the window, in seconds
+    pub window_seconds: u64,
+}
+
+impl RateLimit {
+    fn window(&self) -> TimeDelta {
+        TimeDelta::seconds(self.window_seconds as i64)
+    }
+}
+
+#[derive(Debug, Default)]
+/// Tracks what an action has sent, and what it's held back
+pub(crate) struct RateLimiter {
+    limit: Option<RateLimit>,
+    sent: VecDeque<DateTime<Utc>>,
+    suppressed: Vec<CheckResult>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Forget about sends which have aged out of the window
+    fn expire(&mut self, now: DateTime<Utc>) {
+        if let Some(limit) = self.limit {
+            while self
+                .sent
+                .front()
+                .is_some_and(|sent| *sent + limit.window() <= now)
+            {
+                self.sent.pop_front();
+            }
+        }
+    }
+
+    fn has_capacity(&mut self, now: DateTime<Utc>) -> bool {
+        self.expire(now);
+        match self.limit {
+            Some(limit) => self.sent.len() < limit.max_messages,
+            None => true,
+        }
+    }
+
+    /// Returns true and records the send if there's room in the window
+    pub(crate) fn try_acquire(&mut self, now: DateTime<Utc>) -> bool {
+        if self.has_capacity(now) {
+            self.sent.push_back(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn suppress(&mut self, check_result: &CheckResult) {
+        self.suppressed.push(check_result.clone());
+    }
+
+    /// If anything's been held back and the window has room, returns a single result summarising it all
+    pub(crate) fn take_summary(&mut self, now: DateTime<Utc>) -> Option<CheckResult> {
+        if self.suppressed.is_empty() || !self.try_acquire(now) {
+            return None;
+        }
+        let suppressed = std::mem::take(&mut self.suppressed);
+        let status = suppressed
+            .iter()
+            .map(|result| result.status)
+            .max()
+            .unwrap_or_default();
+        let last_text = suppressed
+            .last()
+            .map(|result| result.result_text.clone())
+            .unwrap_or_default();
+        Some(CheckResult {
+            timestamp: now,
+            time_elapsed: TimeDelta::zero(),
+            status,
+            result_text: format!(
+                "{} notifications were held back by rate limiting, the most recent was: {}",
+                suppressed.len(),
+                last_text
+            ),
+            ..Default::default()
+        })
+    }
+}
+
+struct DispatchTarget {
+    /// The target's name in the `notifications` config, which routing picks it by
+    name: String,
+    /// What the target was built from, so a reload can tell if it's changed
+    config: Option<NotificationTarget>,
+    action: Box<dyn Action + Send + Sync>,
+    limiter: RateLimiter,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// The last notification sent for a service check
+struct Notified {
+    status: ServiceStatus,
+    at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+/// A notification for a check that failed outside its alert window, sent when the window opens
+struct Held {
+    targets: Vec<String>,
+    alert_window: AlertWindow,
+    service_name: String,
+    host_name: String,
+    labels: Labels,
+    check_result: CheckResult,
+    since: DateTime<Utc>,
+}
+
+/// Adds the check's labels to the end of a notification, if it has any
+fn with_labels(text: String, labels: &Labels) -> String {
+    if labels.is_empty() {
+        text
+    } else {
+        format!("{} [{}]", text, crate::labels::describe(labels))
+    }
+}
+
+impl Held {
+    fn notification(&self) -> CheckResult {
+        CheckResult {
+            result_text: with_labels(
+                format!(
+                    "{} on {} is still {}: {} (failing since {}, outside the alert window)",
+                    self.service_name,
+                    self.host_name,
+                    self.check_result.status,
+                    self.check_result.result_text,
+                    self.since.format("%Y-%m-%d %H:%M:%S UTC")
+                ),
+                &self.labels,
+            ),
+            ..self.check_result.clone()
+        }
+    }
+}
+
+#[derive(Default)]
+/// Central place where check results are handed to actions
+pub struct ActionDispatcher {
+    targets: Vec<DispatchTarget>,
+    routes: NotificationRoutes,
+    notified: HashMap<Uuid, Notified>,
+    held: HashMap<Uuid, Held>,
+}
+
+impl ActionDispatcher {
+    #[cfg(test)]
+    /// Add a notification target which routing can send to by name
+    pub(crate) fn add_target(&mut self, name: &str, action: Box<dyn Action + Send + Sync>) {
+        let limiter = RateLimiter::new(action.rate_limit());
+        self.targets.push(DispatchTarget {
+            name: name.to_string(),
+            config: None,
+            action,
+            limiter,
+        });
+    }
+
+    /// Replaces the notification targets and routing with what's in the config, targets that haven't changed keep
+    /// their rate limits' history
+    pub fn configure(&mut self, config: &Configuration) {
+        let mut previous = std::mem::take(&mut self.targets);
+        for (name, target) in config.notifications.targets.iter() {
+            if let Some(index) = previous.iter().position(|existing| {
+                &existing.name == name && existing.config.as_ref() == Some(target)
+            }) {
+                self.targets.push(previous.swap_remove(index));
+                continue;
+            }
+            let action = target.action();
+            self.targets.push(DispatchTarget {
+                name: name.clone(),
+                config: Some(target.clone()),
+                limiter: RateLimiter::new(action.rate_limit()),
+                action,
+            });
+        }
+        self.routes = NotificationRoutes::new(config);
+    }
+
+    #[cfg(test)]
+    /// What a notification target from the config was built from
+    pub(crate) fn target_config(&self, name: &str) -> Option<&NotificationTarget> {
+        self.targets
+            .iter()
+            .find(|target| target.name == name)
+            .and_then(|target| target.config.as_ref())
+    }
+
+    /// The routing that applies to a service on a host
+    pub fn routing(&self, host_name: &str, service_name: &str) -> EffectiveRouting {
+        self.routes.resolve(host_name, service_name)
+    }
+
+    /// Sends a service check's result to the targets its routing picks, when the status changes or it's time to re-notify.
+    /// The `labels` are the host's and service's merged, see [crate::labels::merge].
+    pub async fn dispatch_check(
+        &mut self,
+        service_check_id: Uuid,
+        host_name: &str,
+        service_name: &str,
+        labels: &Labels,
+        check_result: &CheckResult,
+    ) {
+        self.dispatch_check_at(
+            service_check_id,
+            host_name,
+            service_name,
+            labels,
+            check_result,
+            chrono::Utc::now(),
+        )
+        .await
+    }
+
+    async fn dispatch_check_at(
+        &mut self,
+        service_check_id: Uuid,
+        host_name: &str,
+        service_name: &str,
+        labels: &Labels,
+        check_result: &CheckResult,
+        now: DateTime<Utc>,
+    ) {
+        let routing = self.routing(host_name, service_name);
+        if !routing.notifies(check_result.status) {
+            // it's recovered (or routing's off), so the next failure notifies straight away
+            self.notified.remove(&service_check_id);
+            self.held.remove(&service_check_id);
+            return;
+        }
+        if let Some(alert_window) = routing
+            .alert_window
+            .as_ref()
+            .filter(|alert_window| !alert_window.contains(now))
+        {
+            debug!(
+                "Holding back {} for service_check={}, it's outside the alert window",
+                check_result.status, service_check_id
+            );
+            let since = self
+                .held
+                .get(&service_check_id)
+                .map(|held| held.since)
+                .unwrap_or(now);
+            self.held.insert(
+                service_check_id,
+                Held {
+                    targets: routing.targets.clone(),
+                    alert_window: alert_window.clone(),
+                    service_name: service_name.to_string(),
+                    host_name: host_name.to_string(),
+                    labels: labels.clone(),
+                    check_result: check_result.clone(),
+                    since,
+                },
+            );
+            return;
+        }
+        if let Some(held) = self.held.remove(&service_check_id) {
+            // the window's opened before the flush got to it
+            let held = Held {
+                check_result: check_result.clone(),
+                ..held
+            };
+            self.send_held(service_check_id, &held, now).await;
+            return;
+        }
+        let due = match self.notified.get(&service_check_id) {
+            None => true,
+            Some(last) if last.status != check_result.status => true,
+            Some(last) => routing
+                .renotify_seconds
+                .is_some_and(|seconds| last.at + TimeDelta::seconds(seconds as i64) <= now),
+        };
+        if !due {
+            debug!(
+                "Not notifying for service_check={}, already sent {}",
+                service_check_id, check_result.status
+            );
+            return;
+        }
+        self.notified.insert(
+            service_check_id,
+            Notified {
+                status: check_result.status,
+                at: now,
+            },
+        );
+
+        let notification = CheckResult {
+            result_text: with_labels(
+                format!(
+                    "{} on {} is {}: {}",
+                    service_name, host_name, check_result.status, check_result.result_text
+                ),
+                labels,
+            ),
+            ..check_result.clone()
+        };
+        self.send_to_targets(&routing.targets, &notification, now)
+            .await;
+    }
+
+    /// Sends the "still failing" notification for a check that was held back by its alert window
+    async fn send_held(&mut self, service_check_id: Uuid, held: &Held, now: DateTime<Utc>) {
+        self.notified.insert(
+            service_check_id,
+            Notified {
+                status: held.check_result.status,
+                at: now,
+            },
+        );
+        self.send_to_targets(&held.targets, &held.notification(), now)
+            .await;
+    }
+
+    /// Sends a notification for an escalation level to its targets, the caller keeps track of which levels have been sent
+    pub async fn escalate(&mut self, targets: &[String], notification: &CheckResult) {
+        self.send_to_targets(targets, notification, chrono::Utc::now())
+            .await
+    }
+
+    /// Sends to the named notification targets, within their rate limits
+    async fn send_to_targets(
+        &mut self,
+        targets: &[String],
+        notification: &CheckResult,
+        now: DateTime<Utc>,
+    ) {
+        for target in self
+            .targets
+            .iter_mut()
+            .filter(|target| targets.contains(&target.name))
+        {
+            if let Some(summary) = target.limiter.take_summary(now) {
+                if let Err(err) = target.action.execute(&summary).await {
+                    error!("Failed to send rate limit summary: {:?}", err);
+                }
+            }
+            if target.limiter.try_acquire(now) {
+                if let Err(err) = target.action.execute(notification).await {
+                    error!("Failed to send notification: {:?}", err);
+                }
+            } else {
+                debug!("Notification target is rate limited, holding back result");
+                target.limiter.suppress(notification);
+            }
+        }
+    }
+
+    /// Send summaries for any held-back results once their windows have room, and anything still failing when its alert window opens, call this periodically
+    pub async fn flush(&mut self) {
+        self.flush_at(chrono::Utc::now()).await
+    }
+
+    async fn flush_at(&mut self, now: DateTime<Utc>) {
+        let opened = self
+            .held
+            .iter()
+            .filter(|(_, held)| held.alert_window.contains(now))
+            .map(|(service_check_id, _)| *service_check_id)
+            .collect::<Vec<_>>();
+        for service_check_id in opened {
+            if let Some(held) = self.held.remove(&service_check_id) {
+                self.send_held(service_check_id, &held, now).await;
+            }
+        }
+        for target in self.targets.iter_mut() {
+            if let Some(summary) = target.limiter.take_summary(now) {
+                if let Err(err) = target.action.execute(&summary).await {
+                    error!("Failed to send rate limit summary: {:?}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct TestAction {
+        limit: Option<RateLimit>,
+        executed: Arc<Mutex<Vec<CheckResult>>>,
+    }
+
+    #[async_trait]
+    impl Action for TestAction {
+        async fn execute(&self, check_result: &CheckResult) -> Result<(), Error> {
+            self.executed
+                .lock()
+                .expect("Failed to lock")
+                .push(check_result.clone());
+            Ok(())
+        }
+
+        fn run_states(&self) -> Vec<ServiceStatus> {
+            vec![ServiceStatus::Critical, ServiceStatus::Warning]
+        }
+
+        fn rate_limit(&self) -> Option<RateLimit> {
+            self.limit
+        }
+    }
+
+    fn test_result(status: ServiceStatus, text: &str) -> CheckResult {
+        CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: TimeDelta::seconds(1),
+            status,
+            result_text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_rate_limit() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let limit = RateLimit {
+            max_messages: 2,
+            window_seconds: 300,
+        };
+        let mut dispatcher = ActionDispatcher::default();
+        dispatcher.add_target(
+            "limited",
+            Box::new(TestAction {
+                limit: Some(limit),
+                executed: executed.clone(),
+            }),
+        );
+        let targets = ["limited".to_string()];
+
+        let now = chrono::Utc::now();
+        for (status, text) in [
+            (ServiceStatus::Warning, "one"),
+            (ServiceStatus::Warning, "two"),
+            (ServiceStatus::Critical, "three"),
+            (ServiceStatus::Warning, "four"),
+        ] {
+            dispatcher
+                .send_to_targets(&targets, &test_result(status, text), now)
+                .await;
+        }
+        assert_eq!(executed.lock().expect("Failed to lock").len(), 2);
+
+        // still inside the window
+        dispatcher.flush_at(now + TimeDelta::seconds(10)).await;
+        assert_eq!(executed.lock().expect("Failed to lock").len(), 2);
+
+        dispatcher.flush_at(now + limit.window()).await;
+        let executed = executed.lock().expect("Failed to lock");
+        assert_eq!(executed.len(), 3);
+        let summary = executed.last().expect("No summary sent");
+        assert_eq!(summary.status, ServiceStatus::Critical);
+        assert!(summary.result_text.starts_with("2 notifications"));
+        assert!(summary.result_text.ends_with("four"));
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_unlimited() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let mut dispatcher = ActionDispatcher::default();
+        dispatcher.add_target(
+            "unlimited",
+            Box::new(TestAction {
+                limit: None,
+                executed: executed.clone(),
+            }),
+        );
+        for _ in 0..20 {
+            dispatcher
+                .escalate(
+                    &["unlimited".to_string()],
+                    &test_result(ServiceStatus::Critical, "boop"),
+                )
+                .await;
+        }
+        dispatcher.flush().await;
+        assert_eq!(executed.lock().expect("Failed to lock").len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_check_routing() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let mut config = Configuration::load_test_config_bare().await;
+        config.notifications.routing = crate::actions::routing::NotificationRouting {
+            targets: Some(vec!["test".to_string()]),
+            severities: None,
+            renotify_seconds: Some(600),
+            escalation: None,
+            alert_window: None,
+        };
+        let mut dispatcher = ActionDispatcher::default();
+        dispatcher.routes = NotificationRoutes::new(&config);
+        dispatcher.add_target(
+            "test",
+            Box::new(TestAction {
+                limit: None,
+                executed: executed.clone(),
+            }),
+        );
+        // not routed to, so it's left alone
+        dispatcher.add_target(
+            "other",
+            Box::new(TestAction {
+                limit: None,
+                executed: Arc::new(Mutex::new(Vec::new())),
+            }),
+        );
+
+        let service_check_id = Uuid::new_v4();
+        let now = chrono::Utc::now();
+        let send = |status, offset| {
+            (
+                test_result(status, "boop"),
+                now + TimeDelta::seconds(offset),
+            )
+        };
+        for (result, at) in [
+            send(ServiceStatus::Critical, 0),
+            // same status, not time to re-notify yet
+            send(ServiceStatus::Critical, 60),
+            // changed status
+            send(ServiceStatus::Warning, 120),
+            // re-notify
+            send(ServiceStatus::Warning, 720),
+            // recovery resets it
+            send(ServiceStatus::Ok, 780),
+            send(ServiceStatus::Critical, 840),
+        ] {
+            dispatcher
+                .dispatch_check_at(
+                    service_check_id,
+                    "example.com",
+                    "ping",
+                    &Labels::new(),
+                    &result,
+                    at,
+                )
+                .await;
+        }
+        let executed = executed.lock().expect("Failed to lock");
+        assert_eq!(
+            executed
+                .iter()
+                .map(|result| result.status)
+                .collect::<Vec<_>>(),
+            vec![
+                ServiceStatus::Critical,
+                ServiceStatus::Warning,
+                ServiceStatus::Warning,
+                ServiceStatus::Critical
+            ]
+        );
+        assert_eq!(
+            executed.first().map(|result| result.result_text.as_str()),
+            Some("ping on example.com is Critical: boop")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_check_alert_window() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let mut config = Configuration::load_test_config_bare().await;
+        config.notifications.routing = crate::actions::routing::NotificationRouting {
+            targets: Some(vec!["test".to_string()]),
+            alert_window: Some(AlertWindow {
+                days: vec![],
+                start: "08:00".to_string(),
+                end: "18:00".to_string(),
+                timezone: None,
+            }),
+            ..Default::default()
+        };
+        let mut dispatcher = ActionDispatcher::default();
+        dispatcher.routes = NotificationRoutes::new(&config);
+        dispatcher.add_target(
+            "test",
+            Box::new(TestAction {
+                limit: None,
+                executed: executed.clone(),
+            }),
+        );
+
+        let night = DateTime::parse_from_rfc3339("2025-01-06T02:00:00Z")
+            .expect("Failed to parse time")
+            .with_timezone(&Utc);
+        let (failing, recovering) = (Uuid::new_v4(), Uuid::new_v4());
+        let labels = crate::labels::merge(
+            &Labels::from([("env".to_string(), "dev".to_string())]),
+            &Labels::from([("env".to_string(), "prod".to_string())]),
+        );
+        for (service_check_id, status, at) in [
+            (failing, ServiceStatus::Critical, night),
+            (
+                failing,
+                ServiceStatus::Critical,
+                night + TimeDelta::hours(1),
+            ),
+            (recovering, ServiceStatus::Warning, night),
+            (recovering, ServiceStatus::Ok, night + TimeDelta::hours(1)),
+        ] {
+            dispatcher
+                .dispatch_check_at(
+                    service_check_id,
+                    "example.com",
+                    "ping",
+                    &labels,
+                    &test_result(status, "boop"),
+                    at,
+                )
+                .await;
+        }
+        assert!(executed.lock().expect("Failed to lock").is_empty());
+
+        // still night
+        dispatcher.flush_at(night + TimeDelta::hours(2)).await;
+        assert!(executed.lock().expect("Failed to lock").is_empty());
+
+        dispatcher.flush_at(night + TimeDelta::hours(6)).await;
+        {
+            let executed = executed.lock().expect("Failed to lock");
+            assert_eq!(executed.len(), 1);
+            assert!(executed[0]
+                .result_text
+                .starts_with("ping on example.com is still Critical: boop (failing since 2025-01-06 02:00:00 UTC"));
+            // the service's label wins
+            assert!(executed[0].result_text.ends_with("[env=prod]"));
+        }
+
+        // it's already been sent, so it's not repeated
+        dispatcher
+            .dispatch_check_at(
+                failing,
+                "example.com",
+                "ping",
+                &labels,
+                &test_result(ServiceStatus::Critical, "boop"),
+                night + TimeDelta::hours(7),
+            )
+            .await;
+        dispatcher.flush_at(night + TimeDelta::hours(7)).await;
+        assert_eq!(executed.lock().expect("Failed to lock").len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_window_expiry() {
+        let mut limiter = RateLimiter::new(Some(RateLimit {
+            max_messages: 1,
+            window_seconds: 60,
+        }));
+        let now = chrono::Utc::now();
+        assert!(limiter.try_acquire(now));
+        assert!(!limiter.try_acquire(now + TimeDelta::seconds(59)));
+        assert!(limiter.try_acquire(now + TimeDelta::seconds(60)));
+        // nothing suppressed, nothing to summarise
+        assert!(limiter
+            .take_summary(now + TimeDelta::seconds(500))
+            .is_none());
+    }
+}
@@ -0,0 +1,163 @@
+//! Escalation policies, which send to more targets the longer a check stays critical without
+//! someone acknowledging it.
+
+use super::routing::NotificationRouting;
+use crate::prelude::*;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// One level of an escalation policy
+pub struct EscalationStep {
+    /// How long the check has to be critical before this level's sent
+    pub after_minutes: u64,
+    /// Names of the notification targets to send to
+    pub targets: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+/// The levels a critical check is escalated through, eg `{"steps": [{"after_minutes": 15, "targets": ["team-lead"]}]}`
+pub struct EscalationPolicy {
+    /// Sorted by `after_minutes`, each level's only sent once
+    pub steps: Vec<EscalationStep>,
+}
+
+impl EscalationPolicy {
+    /// The steps that are due, given how many have already been sent and when the check went critical
+    pub fn due_steps(
+        &self,
+        level: usize,
+        started_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> impl Iterator<Item = (usize, &EscalationStep)> {
+        self.steps
+            .iter()
+            .enumerate()
+            .skip(level)
+            .take_while(move |(_, step)| {
+                started_at + TimeDelta::minutes(step.after_minutes as i64) <= now
+            })
+    }
+}
+
+/// Makes sure the policies make sense, and every routing layer only refers to policies which exist
+pub(crate) fn check_escalations(config: &Configuration) -> Result<(), Error> {
+    let notifications = &config.notifications;
+    for (name, policy) in notifications.escalations.iter() {
+        if policy.steps.is_empty() {
+            return Err(Error::Configuration(format!(
+                "Escalation policy '{}' has no steps",
+                name
+            )));
+        }
+        if policy
+            .steps
+            .windows(2)
+            .any(|steps| steps[0].after_minutes >= steps[1].after_minutes)
+        {
+            return Err(Error::Configuration(format!(
+                "Steps in escalation policy '{}' must be in order of after_minutes, without repeats",
+                name
+            )));
+        }
+        for target in policy.steps.iter().flat_map(|step| step.targets.iter()) {
+            if !notifications.targets.contains_key(target) {
+                return Err(Error::Configuration(format!(
+                    "Escalation policy '{}' uses unknown target '{}'",
+                    name, target
+                )));
+            }
+        }
+    }
+
+    let layers = std::iter::once(&notifications.routing)
+        .chain(notifications.host_groups.values())
+        .chain(
+            config
+                .services
+                .values()
+                .filter_map(|service| service.notifications.as_ref()),
+        )
+        .chain(
+            config
+                .hosts
+                .values()
+                .filter_map(|host| host.notifications.as_ref()),
+        );
+    for escalation in layers.filter_map(|routing: &NotificationRouting| routing.escalation.as_ref())
+    {
+        if !escalation.is_empty() && !notifications.escalations.contains_key(escalation) {
+            return Err(Error::Configuration(format!(
+                "Notification routing uses unknown escalation policy '{}'",
+                escalation
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::routing::NotificationTarget;
+
+    fn step(after_minutes: u64, target: &str) -> EscalationStep {
+        EscalationStep {
+            after_minutes,
+            targets: vec![target.to_string()],
+        }
+    }
+
+    #[test]
+    fn test_due_steps() {
+        let policy = EscalationPolicy {
+            steps: vec![step(10, "lead"), step(30, "manager")],
+        };
+        let started_at = Utc::now();
+        let due = |level, minutes| {
+            policy
+                .due_steps(level, started_at, started_at + TimeDelta::minutes(minutes))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>()
+        };
+        assert!(due(0, 5).is_empty());
+        assert_eq!(due(0, 10), vec![0]);
+        assert!(due(1, 20).is_empty());
+        // the shepherd was stuck for a while, so both go at once
+        assert_eq!(due(0, 45), vec![0, 1]);
+        assert!(due(2, 600).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_escalations() {
+        let mut config = Configuration::load_test_config_bare().await;
+        config.notifications.routing.escalation = Some("oncall".to_string());
+        assert!(check_escalations(&config).is_err());
+
+        config.notifications.escalations.insert(
+            "oncall".to_string(),
+            EscalationPolicy {
+                steps: vec![step(30, "lead"), step(10, "lead")],
+            },
+        );
+        // out of order, and the target doesn't exist
+        assert!(check_escalations(&config).is_err());
+        config.notifications.escalations.insert(
+            "oncall".to_string(),
+            EscalationPolicy {
+                steps: vec![step(10, "lead"), step(30, "lead")],
+            },
+        );
+        assert!(check_escalations(&config).is_err());
+
+        config.notifications.targets.insert(
+            "lead".to_string(),
+            NotificationTarget::Pushover {
+                token: "token".to_string(),
+                user: "user".to_string(),
+                device: None,
+                title: None,
+                rate_limit: None,
+            },
+        );
+        assert!(check_escalations(&config).is_ok());
+    }
+}
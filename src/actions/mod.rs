@@ -2,7 +2,13 @@
 
 use crate::prelude::*;
 
+pub mod alert_window;
+pub mod dispatcher;
+pub mod escalation;
 pub(crate) mod pushover;
+pub mod routing;
+
+use dispatcher::RateLimit;
 
 #[async_trait]
 /// An action that'll run after a check has been performed
@@ -12,4 +18,9 @@ pub trait Action {
 
     /// What states the action would be run
     fn run_states(&self) -> Vec<ServiceStatus>;
+
+    /// How often the action is allowed to run, enforced by the [dispatcher::ActionDispatcher]
+    fn rate_limit(&self) -> Option<RateLimit> {
+        None
+    }
 }
@@ -3,6 +3,7 @@
 use reqwest::Url;
 use sea_orm::Iterable;
 
+use super::dispatcher::RateLimit;
 use super::Action;
 use crate::prelude::*;
 
@@ -46,20 +47,48 @@ pub struct PushOver {
     pub message: Option<String>,
     /// The states that this action will run on
     pub run_states: Vec<super::ServiceStatus>,
+    /// Limit how many messages are sent, eg `{"max_messages": 10, "window_seconds": 300}`
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
 
     /// current retry count
     #[serde(default)]
     retry_count: u8,
 }
 
+impl PushOver {
+    /// Builds a Pushover action for a notification target, the message is the check's result text
+    pub(crate) fn new(
+        token: String,
+        user: String,
+        device: Option<String>,
+        title: Option<String>,
+        rate_limit: Option<RateLimit>,
+    ) -> Self {
+        Self {
+            token,
+            user,
+            device,
+            title,
+            message: None,
+            run_states: vec![],
+            rate_limit,
+            retry_count: 0,
+        }
+    }
+}
+
 #[async_trait]
 impl Action for PushOver {
     async fn execute(&self, check_result: &CheckResult) -> Result<(), Error> {
-        if !self.run_states.contains(&check_result.status) {
+        if !self.run_states().contains(&check_result.status) {
             return Ok(());
         }
 
-        let payload: PushoverMessage = PushoverMessage::from(self);
+        let mut payload: PushoverMessage = PushoverMessage::from(self);
+        if payload.message.is_none() {
+            payload.message = Some(check_result.result_text.clone());
+        }
 
         debug!("Sending pushover payload: {:?}", payload);
 
@@ -121,6 +150,10 @@ impl Action for PushOver {
             self.run_states.to_vec()
         }
     }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -206,6 +239,7 @@ mod tests {
             title: None,
             message: Some(format!("test {}", chrono::Utc::now().timestamp())),
             run_states: vec![ServiceStatus::Critical],
+            rate_limit: None,
             retry_count: 0,
         };
 
@@ -214,6 +248,7 @@ mod tests {
             result_text: "result_text".to_string(),
             timestamp: chrono::Utc::now(),
             time_elapsed: TimeDelta::seconds(1),
+            ..Default::default()
         };
 
         pushover
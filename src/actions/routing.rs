@@ -0,0 +1,401 @@
+//! Works out where notifications for a check go, layering the global routing with host group,
+//! service and host overrides.
+
+use std::collections::BTreeMap;
+
+use super::alert_window::AlertWindow;
+use super::dispatcher::RateLimit;
+use super::escalation::EscalationPolicy;
+use super::pushover::PushOver;
+use super::Action;
+use crate::db::entities::name_key;
+use crate::prelude::*;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// Somewhere notifications can be sent
+pub enum NotificationTarget {
+    /// Send a message via <https://pushover.net>
+    Pushover {
+        /// API Token
+        token: String,
+        /// User or group key
+        user: String,
+        #[serde(default)]
+        /// Device name
+        device: Option<String>,
+        #[serde(default)]
+        /// Message title
+        title: Option<String>,
+        #[serde(default)]
+        /// Limit how many messages are sent, eg `{"max_messages": 10, "window_seconds": 300}`
+        rate_limit: Option<RateLimit>,
+    },
+}
+
+impl NotificationTarget {
+    /// Builds the action that sends to this target
+    pub(crate) fn action(&self) -> Box<dyn Action + Send + Sync> {
+        match self {
+            Self::Pushover {
+                token,
+                user,
+                device,
+                title,
+                rate_limit,
+            } => Box::new(PushOver::new(
+                token.clone(),
+                user.clone(),
+                device.clone(),
+                title.clone(),
+                *rate_limit,
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+/// Where notifications go, anything that's not set is inherited from the level above
+pub struct NotificationRouting {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Names of the targets to send to, an empty list turns notifications off
+    pub targets: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Which statuses send a notification, eg `["critical"]`
+    pub severities: Option<Vec<ServiceStatus>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Send another notification if the check's still in a notifying state after this many seconds, `0` turns it off
+    pub renotify_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Name of the escalation policy for critical checks, an empty string turns escalation off
+    pub escalation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Only send notifications inside this window, checks that are still failing notify when it opens
+    pub alert_window: Option<AlertWindow>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+/// The `notifications` section of the config file
+pub struct NotificationConfig {
+    #[serde(default)]
+    /// Notification targets, keyed by name
+    pub targets: HashMap<String, NotificationTarget>,
+    #[serde(default)]
+    /// The global routing, used unless something more specific overrides it
+    pub routing: NotificationRouting,
+    #[serde(default)]
+    /// Routing overrides for hosts in these host groups
+    pub host_groups: HashMap<String, NotificationRouting>,
+    #[serde(default)]
+    /// Escalation policies, keyed by name
+    pub escalations: HashMap<String, EscalationPolicy>,
+}
+
+/// The statuses which notify if nothing says otherwise
+fn default_severities() -> Vec<ServiceStatus> {
+    vec![
+        ServiceStatus::Critical,
+        ServiceStatus::Error,
+        ServiceStatus::Timeout,
+        ServiceStatus::Warning,
+    ]
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// The routing that applies to a check once all the overrides have been applied
+pub struct EffectiveRouting {
+    /// Names of the targets to send to
+    pub targets: Vec<String>,
+    /// Which statuses send a notification
+    pub severities: Vec<ServiceStatus>,
+    /// How often to re-send while the check's still notifying, if at all
+    pub renotify_seconds: Option<u64>,
+    /// The escalation policy for when the check stays critical, if any
+    pub escalation: Option<String>,
+    /// When notifications can be sent, any time if it's not set
+    pub alert_window: Option<AlertWindow>,
+    /// Where each setting came from, eg `severities: service 'ping'`
+    pub sources: BTreeMap<String, String>,
+}
+
+impl Default for EffectiveRouting {
+    fn default() -> Self {
+        Self {
+            targets: vec![],
+            severities: default_severities(),
+            renotify_seconds: None,
+            escalation: None,
+            alert_window: None,
+            sources: BTreeMap::from_iter(
+                [
+                    "targets",
+                    "severities",
+                    "renotify_seconds",
+                    "escalation",
+                    "alert_window",
+                ]
+                .map(|field| (field.to_string(), "default".to_string())),
+            ),
+        }
+    }
+}
+
+impl EffectiveRouting {
+    /// Applies a more specific layer over the top
+    fn apply(&mut self, routing: &NotificationRouting, source: &str) {
+        if let Some(targets) = &routing.targets {
+            self.targets = targets.clone();
+            self.sources
+                .insert("targets".to_string(), source.to_string());
+        }
+        if let Some(severities) = &routing.severities {
+            self.severities = severities.clone();
+            self.sources
+                .insert("severities".to_string(), source.to_string());
+        }
+        if let Some(renotify_seconds) = routing.renotify_seconds {
+            self.renotify_seconds = Some(renotify_seconds).filter(|seconds| *seconds > 0);
+            self.sources
+                .insert("renotify_seconds".to_string(), source.to_string());
+        }
+        if let Some(escalation) = &routing.escalation {
+            self.escalation = Some(escalation.clone()).filter(|name| !name.is_empty());
+            self.sources
+                .insert("escalation".to_string(), source.to_string());
+        }
+        if let Some(alert_window) = &routing.alert_window {
+            self.alert_window = Some(alert_window.clone());
+            self.sources
+                .insert("alert_window".to_string(), source.to_string());
+        }
+    }
+
+    /// If this status should send a notification
+    pub fn notifies(&self, status: ServiceStatus) -> bool {
+        !self.targets.is_empty() && self.severities.contains(&status)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Everything needed to resolve routing, taken from the config so it can be used without holding the config lock
+pub struct NotificationRoutes {
+    global: NotificationRouting,
+    host_groups: HashMap<String, (String, NotificationRouting)>,
+    services: HashMap<String, NotificationRouting>,
+    hosts: HashMap<String, (Vec<String>, Option<NotificationRouting>)>,
+}
+
+impl NotificationRoutes {
+    /// Pulls the routing out of the config
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            global: config.notifications.routing.clone(),
+            host_groups: config
+                .notifications
+                .host_groups
+                .iter()
+                .map(|(group, routing)| (name_key(group), (group.clone(), routing.clone())))
+                .collect(),
+            services: config
+                .services
+                .iter()
+                .filter_map(|(name, service)| {
+                    service
+                        .notifications
+                        .as_ref()
+                        .map(|routing| (name_key(name), routing.clone()))
+                })
+                .collect(),
+            hosts: config
+                .hosts
+                .iter()
+                .map(|(name, host)| {
+                    (
+                        name_key(name),
+                        (host.host_groups.clone(), host.notifications.clone()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Resolves the routing for a service on a host, global → host group → service → host
+    pub fn resolve(&self, host_name: &str, service_name: &str) -> EffectiveRouting {
+        let mut res = EffectiveRouting::default();
+        res.apply(&self.global, "global");
+
+        let host = self.hosts.get(&name_key(host_name));
+        // sorted so hosts in more than one group always get the same answer
+        let mut groups = host
+            .map(|(groups, _)| {
+                groups
+                    .iter()
+                    .filter_map(|group| self.host_groups.get(&name_key(group)))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        groups.sort_by_key(|(group, _)| name_key(group));
+        for (group, routing) in groups {
+            res.apply(routing, &format!("host group '{}'", group));
+        }
+
+        if let Some(routing) = self.services.get(&name_key(service_name)) {
+            res.apply(routing, &format!("service '{}'", service_name));
+        }
+        if let Some(routing) = host.and_then(|(_, routing)| routing.as_ref()) {
+            res.apply(routing, &format!("host '{}'", host_name));
+        }
+        res
+    }
+}
+
+/// Every routing layer in the config, with a description of where it is
+fn routing_layers(config: &Configuration) -> impl Iterator<Item = (String, &NotificationRouting)> {
+    std::iter::once(("global".to_string(), &config.notifications.routing))
+        .chain(
+            config
+                .notifications
+                .host_groups
+                .iter()
+                .map(|(name, routing)| (format!("host group '{}'", name), routing)),
+        )
+        .chain(config.services.iter().filter_map(|(name, service)| {
+            service
+                .notifications
+                .as_ref()
+                .map(|routing| (format!("service '{}'", name), routing))
+        }))
+        .chain(config.hosts.iter().filter_map(|(name, host)| {
+            host.notifications
+                .as_ref()
+                .map(|routing| (format!("host '{}'", name), routing))
+        }))
+}
+
+/// Makes sure every routing layer only refers to targets which exist
+pub(crate) fn check_targets(config: &Configuration) -> Result<(), Error> {
+    for (layer, routing) in routing_layers(config) {
+        for target in routing.targets.iter().flatten() {
+            if !config.notifications.targets.contains_key(target) {
+                return Err(Error::Configuration(format!(
+                    "Notification routing for {} uses unknown target '{}'",
+                    layer, target
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure every routing layer's alert window parses
+pub(crate) fn check_alert_windows(config: &Configuration) -> Result<(), Error> {
+    for (layer, routing) in routing_layers(config) {
+        if let Some(alert_window) = &routing.alert_window {
+            alert_window.validate().map_err(|err| {
+                Error::Configuration(format!(
+                    "Notification routing for {} has an invalid alert_window: {:?}",
+                    layer, err
+                ))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing(targets: Option<&[&str]>, renotify_seconds: Option<u64>) -> NotificationRouting {
+        NotificationRouting {
+            targets: targets.map(|targets| targets.iter().map(|t| t.to_string()).collect()),
+            severities: None,
+            renotify_seconds,
+            escalation: None,
+            alert_window: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_routing() {
+        let mut config = Configuration::load_test_config_bare().await;
+        let (host_name, host) = config
+            .hosts
+            .iter_mut()
+            .find(|(_, host)| !host.host_groups.is_empty())
+            .expect("No hosts with groups in the test config");
+        let host_name = host_name.clone();
+        let group = host
+            .host_groups
+            .first()
+            .cloned()
+            .expect("Host has no groups");
+        let service_name = config
+            .services
+            .keys()
+            .next()
+            .cloned()
+            .expect("No services in the test config");
+
+        config.notifications.routing = NotificationRouting {
+            severities: Some(vec![ServiceStatus::Critical]),
+            ..routing(Some(&["oncall"]), Some(3600))
+        };
+        let routes = NotificationRoutes::new(&config);
+        let res = routes.resolve(&host_name, &service_name);
+        assert_eq!(res.targets, vec!["oncall".to_string()]);
+        assert_eq!(res.renotify_seconds, Some(3600));
+        assert!(res.notifies(ServiceStatus::Critical));
+        assert!(!res.notifies(ServiceStatus::Warning));
+        assert_eq!(
+            res.sources.get("targets").map(String::as_str),
+            Some("global")
+        );
+
+        config
+            .notifications
+            .host_groups
+            .insert(group.clone(), routing(Some(&["team"]), None));
+        if let Some(service) = config.services.get_mut(&service_name) {
+            service.notifications = Some(routing(None, Some(0)));
+        }
+        let res = NotificationRoutes::new(&config).resolve(&host_name, &service_name);
+        assert_eq!(res.targets, vec!["team".to_string()]);
+        assert_eq!(res.renotify_seconds, None);
+        assert_eq!(
+            res.sources.get("targets"),
+            Some(&format!("host group '{}'", group))
+        );
+        assert_eq!(
+            res.sources.get("renotify_seconds"),
+            Some(&format!("service '{}'", service_name))
+        );
+
+        // hosts have the final say, and an empty list turns it off
+        if let Some(host) = config.hosts.get_mut(&host_name) {
+            host.notifications = Some(routing(Some(&[]), None));
+        }
+        let res = NotificationRoutes::new(&config).resolve(&host_name, &service_name);
+        assert!(res.targets.is_empty());
+        assert!(!res.notifies(ServiceStatus::Critical));
+
+        // none of the targets exist
+        assert!(check_targets(&config).is_err());
+        config
+            .notifications
+            .targets
+            .extend(["oncall", "team"].map(|name| {
+                (
+                    name.to_string(),
+                    NotificationTarget::Pushover {
+                        token: "token".to_string(),
+                        user: "user".to_string(),
+                        device: None,
+                        title: None,
+                        rate_limit: None,
+                    },
+                )
+            }));
+        assert!(check_targets(&config).is_ok());
+    }
+}
@@ -0,0 +1,452 @@
+//! Remote agent mode, for running checks from other network locations
+//!
+//! An agent registers with the central Maremma server, polls for the service checks pinned to it
+//! (by agent name or zone), runs them locally and reports the results back over the API.
+
+use std::collections::BTreeMap;
+
+use crate::check_loop::resolve_target_address;
+use crate::cli::AgentCmd;
+use crate::host::address::AddressFamily;
+use crate::host::HostCheck;
+use crate::prelude::*;
+use crate::services::service_config_parse;
+use crate::web::urls::Urls;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+/// Server-side configuration for a remote agent
+pub struct AgentConfig {
+    /// Shared secret the agent uses to authenticate, sent as a bearer token
+    pub token: String,
+    /// The zone the agent runs in, services pinned to this zone will also be run by this agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+}
+
+impl AgentConfig {
+    /// Checks if a service pinned to `pin` should be run by the agent called `agent_name`
+    pub fn matches(&self, agent_name: &str, pin: &str) -> bool {
+        pin == agent_name || self.zone.as_deref() == Some(pin)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Sent by the agent when it starts up
+pub struct AgentRegistration {
+    /// The Maremma version the agent is running
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// The server's response to an agent registering
+pub struct AgentRegistrationResponse {
+    /// The zone the server has the agent in
+    pub zone: Option<String>,
+    /// How often the agent should ask for work, in seconds
+    pub poll_interval: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The parts of a host an agent needs to run a check against it
+pub struct AgentHost {
+    /// Host ID
+    pub id: Uuid,
+    /// Host name
+    pub name: String,
+    /// The hostname to check
+    pub hostname: String,
+    /// Host-specific service configuration
+    pub config: Json,
+    /// Variables the host's services can use
+    #[serde(default)]
+    pub vars: Json,
+    /// Used instead of looking up the hostname's IPv4 address
+    #[serde(default)]
+    pub ipv4: Option<String>,
+    /// Used instead of looking up the hostname's IPv6 address
+    #[serde(default)]
+    pub ipv6: Option<String>,
+    /// Which IP versions checks use
+    #[serde(default)]
+    pub address_family: AddressFamily,
+}
+
+impl From<entities::host::Model> for AgentHost {
+    fn from(value: entities::host::Model) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            hostname: value.hostname,
+            config: value.config,
+            vars: value.vars,
+            ipv4: value.ipv4,
+            ipv6: value.ipv6,
+            address_family: value.address_family,
+        }
+    }
+}
+
+impl From<AgentHost> for entities::host::Model {
+    fn from(value: AgentHost) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            slug: entities::slugify(&value.name),
+            hostname: value.hostname,
+            // the server handles host checks, the agent only runs services
+            check: HostCheck::None,
+            config: value.config,
+            vars: value.vars,
+            ipv4: value.ipv4,
+            ipv6: value.ipv6,
+            address_family: value.address_family,
+            display_name: None,
+            labels: json!({}),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// A service check the server has handed to an agent
+pub struct AgentCheckAssignment {
+    /// The service check to report the result against
+    pub service_check_id: Uuid,
+    /// The service to run
+    pub service: entities::service::Model,
+    /// The host to run it against
+    pub host: AgentHost,
+}
+
+impl AgentCheckAssignment {
+    /// Run the check, any failures are turned into an error result so the server always hears back
+    pub async fn run(&self) -> AgentCheckResult {
+        let start_time = chrono::Utc::now();
+        let result = match service_value(&self.service).and_then(|value| {
+            service_config_parse(&self.service.name, &self.service.service_type, &value)
+        }) {
+            Ok(service) => service.run(&self.host.clone().into()).await,
+            Err(err) => Err(err),
+        };
+
+        let result = result.unwrap_or_else(|err| CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: chrono::Utc::now() - start_time,
+            status: ServiceStatus::Error,
+            result_text: format!("Error: {:?}", err),
+            ..Default::default()
+        });
+        AgentCheckResult::new(self.service_check_id, &result)
+            .with_target_address(resolve_target_address(&self.host.hostname).await)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The result of a check run by an agent
+pub struct AgentCheckResult {
+    /// The service check this is the result for
+    pub service_check_id: Uuid,
+    /// When the check finished
+    pub timestamp: DateTime<Utc>,
+    /// How long the check took, in milliseconds
+    pub time_elapsed_ms: i64,
+    /// The result
+    pub status: ServiceStatus,
+    /// Any explanatory/returned text
+    pub result_text: String,
+    /// Anything beyond the summary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_output: Option<String>,
+    /// Key/value details, eg performance data
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<String, String>,
+    /// The agent's version
+    #[serde(default)]
+    pub maremma_version: Option<String>,
+    /// The address the target hostname resolved to on the agent
+    #[serde(default)]
+    pub target_address: Option<String>,
+    /// The full output, for services with `detect_changes` set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compare_output: Option<String>,
+    /// Connection timings and the like, for services with `debug_capture` set
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub diagnostics: BTreeMap<String, String>,
+}
+
+impl AgentCheckResult {
+    /// Build a result to send back to the server
+    pub fn new(service_check_id: Uuid, result: &CheckResult) -> Self {
+        Self {
+            service_check_id,
+            timestamp: result.timestamp,
+            time_elapsed_ms: result.time_elapsed.num_milliseconds(),
+            status: result.status,
+            result_text: result.result_text.clone(),
+            long_output: result.long_output.clone(),
+            details: result.details.clone(),
+            maremma_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            target_address: None,
+            compare_output: result.compare_output.clone(),
+            diagnostics: result.diagnostics.clone(),
+        }
+    }
+
+    /// Adds the address the target resolved to
+    pub fn with_target_address(self, target_address: Option<String>) -> Self {
+        Self {
+            target_address,
+            ..self
+        }
+    }
+}
+
+impl From<AgentCheckResult> for CheckResult {
+    fn from(value: AgentCheckResult) -> Self {
+        Self {
+            timestamp: value.timestamp,
+            time_elapsed: Duration::milliseconds(value.time_elapsed_ms),
+            status: value.status,
+            result_text: value.result_text,
+            long_output: value.long_output,
+            details: value.details,
+            compare_output: value.compare_output,
+            diagnostics: value.diagnostics,
+        }
+    }
+}
+
+/// Rebuilds the service configuration from a service model, so it can be parsed without a database
+pub(crate) fn service_value(service: &entities::service::Model) -> Result<Value, Error> {
+    let mut value = match service.extra_config.as_object() {
+        Some(val) => val.clone(),
+        None => Map::new(),
+    };
+    value.insert("name".to_string(), json!(service.name));
+    value.insert(
+        "service_type".to_string(),
+        serde_json::to_value(&service.service_type)?,
+    );
+    value.insert("cron_schedule".to_string(), json!(service.cron_schedule));
+    Ok(Value::Object(value))
+}
+
+/// Talks to the central server's agent API
+pub struct AgentClient {
+    client: reqwest::Client,
+    server_url: String,
+    name: String,
+    token: String,
+}
+
+impl AgentClient {
+    /// Build a new client from the CLI options
+    pub fn new(cmd: &AgentCmd) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .user_agent(format!("Maremma Agent {}", env!("CARGO_PKG_VERSION")))
+            .https_only(true)
+            .build()?;
+        Ok(Self {
+            client,
+            server_url: cmd.server_url.trim_end_matches('/').to_string(),
+            name: cmd.name.clone(),
+            token: cmd.token.clone(),
+        })
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!(
+            "{}{}/{}/{}",
+            self.server_url,
+            Urls::AgentApi,
+            self.name,
+            endpoint
+        )
+    }
+
+    /// Tell the server we're here
+    pub async fn register(&self) -> Result<AgentRegistrationResponse, Error> {
+        self.client
+            .post(self.url("register"))
+            .bearer_auth(&self.token)
+            .json(&AgentRegistration {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Ask the server for the checks that are due
+    pub async fn fetch_checks(&self) -> Result<Vec<AgentCheckAssignment>, Error> {
+        self.client
+            .get(self.url("checks"))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Send a check result back to the server
+    pub async fn submit_result(&self, result: &AgentCheckResult) -> Result<(), Error> {
+        self.client
+            .post(self.url("result"))
+            .bearer_auth(&self.token)
+            .json(result)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+/// Runs the agent loop, polling the server for work until something breaks
+pub async fn run_agent(cmd: AgentCmd) -> Result<(), Error> {
+    crate::services::plugin::PLUGINS.configure(&crate::services::plugin::PluginConfig {
+        directory: cmd.plugin_dir.clone(),
+        ..Default::default()
+    })?;
+    let client = AgentClient::new(&cmd)?;
+
+    let registration = client.register().await.inspect_err(|err| {
+        error!(
+            "Failed to register with {} as {}: {:?}",
+            cmd.server_url, cmd.name, err
+        )
+    })?;
+    info!(
+        "🐕 Registered with {} as agent={} zone={:?} 🐕",
+        cmd.server_url, cmd.name, registration.zone
+    );
+
+    let poll_interval = std::time::Duration::from_secs(
+        cmd.poll_interval
+            .unwrap_or(registration.poll_interval)
+            .max(1),
+    );
+
+    loop {
+        match client.fetch_checks().await {
+            Ok(checks) => {
+                debug!("Got {} checks from the server", checks.len());
+                let results =
+                    futures::future::join_all(checks.iter().map(|check| check.run())).await;
+                for result in results {
+                    if let Err(err) = client.submit_result(&result).await {
+                        error!(
+                            "Failed to submit result for service_check={}: {:?}",
+                            result.service_check_id, err
+                        );
+                    }
+                }
+            }
+            Err(err) => error!("Failed to fetch checks from the server: {:?}", err),
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::service::test_service;
+
+    #[test]
+    fn test_agent_config_matches() {
+        let agent = AgentConfig {
+            token: "hunter2".to_string(),
+            zone: Some("dc2".to_string()),
+        };
+        assert!(agent.matches("agent1", "agent1"));
+        assert!(agent.matches("agent1", "dc2"));
+        assert!(!agent.matches("agent1", "dc1"));
+
+        let agent = AgentConfig::default();
+        assert!(!agent.matches("agent1", "dc2"));
+    }
+
+    #[test]
+    fn test_agent_check_result_roundtrip() {
+        let result = CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: Duration::milliseconds(1234),
+            status: ServiceStatus::Warning,
+            result_text: "hello world".to_string(),
+            ..Default::default()
+        };
+        let agent_result = AgentCheckResult::new(Uuid::new_v4(), &result);
+        assert_eq!(agent_result.time_elapsed_ms, 1234);
+
+        let back: CheckResult = agent_result.into();
+        assert_eq!(back.time_elapsed, result.time_elapsed);
+        assert_eq!(back.status, result.status);
+        assert_eq!(back.result_text, result.result_text);
+    }
+
+    #[test]
+    fn test_service_value() {
+        let service = test_service();
+        let value = service_value(&service).expect("Failed to build service value");
+        assert_eq!(value.get("name"), Some(&json!(service.name)));
+        assert_eq!(value.get("service_type"), Some(&json!("cli")));
+        assert_eq!(value.get("url"), Some(&json!("http://localhost:8080")));
+    }
+
+    #[tokio::test]
+    async fn test_agent_check_assignment_run() {
+        let _ = crate::db::tests::test_setup_quieter().await;
+        let mut service = test_service();
+        service.extra_config = json!({"command_line": "/bin/true"});
+
+        let assignment = AgentCheckAssignment {
+            service_check_id: Uuid::new_v4(),
+            service,
+            host: AgentHost {
+                id: Uuid::new_v4(),
+                name: "localhost".to_string(),
+                hostname: "localhost".to_string(),
+                config: json!({}),
+                vars: json!({}),
+                ipv4: None,
+                ipv6: None,
+                address_family: Default::default(),
+            },
+        };
+        let result = assignment.run().await;
+        assert_eq!(result.service_check_id, assignment.service_check_id);
+        assert_eq!(result.status, ServiceStatus::Ok);
+
+        // a broken config still gives the server a result
+        let assignment = AgentCheckAssignment {
+            service: entities::service::Model {
+                extra_config: json!({}),
+                ..assignment.service
+            },
+            ..assignment
+        };
+        assert_eq!(assignment.run().await.status, ServiceStatus::Error);
+    }
+
+    #[test]
+    fn test_agent_client_url() {
+        let client = AgentClient::new(&AgentCmd {
+            sharedopts: Default::default(),
+            server_url: "https://maremma.example.com/".to_string(),
+            name: "dc2".to_string(),
+            token: "hunter2".to_string(),
+            poll_interval: None,
+            plugin_dir: None,
+        })
+        .expect("Failed to build client");
+        assert_eq!(
+            client.url("checks"),
+            "https://maremma.example.com/api/v1/agent/dc2/checks"
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! Receiving webhooks from Prometheus Alertmanager
+//!
+//! Each alert becomes a passive service check on a designated host, the host and service are
+//! created the first time an alert shows up, and every webhook is recorded as a check result.
+
+use std::collections::BTreeMap;
+
+use crate::check_loop::{record_check_result, CheckEnvironment};
+use crate::host::HostCheck;
+use crate::prelude::*;
+use crate::services::passive::DEFAULT_PASSIVE_CRON;
+
+/// The default host that alerts are attached to
+pub const DEFAULT_ALERTMANAGER_HOST: &str = "Alertmanager";
+
+fn default_alertmanager_host() -> String {
+    DEFAULT_ALERTMANAGER_HOST.to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+/// Configuration for the Alertmanager webhook receiver
+pub struct AlertmanagerConfig {
+    /// Shared secret, set it as the bearer token in Alertmanager's webhook `http_config`
+    pub token: String,
+    /// The host alerts are attached to, created if it doesn't exist, defaults to [DEFAULT_ALERTMANAGER_HOST]
+    #[serde(default = "default_alertmanager_host")]
+    pub host: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// Whether an alert is still going
+pub enum AlertStatus {
+    /// The alert's still going
+    Firing,
+    /// The alert's cleared
+    Resolved,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+/// A single alert in a webhook payload
+pub struct Alert {
+    /// Firing or resolved
+    pub status: AlertStatus,
+    /// Labels, `alertname` is used for the service name
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Annotations, `summary` or `description` end up in the result text
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// When the alert started firing
+    pub starts_at: DateTime<Utc>,
+    /// When the alert was resolved, Alertmanager sends the zero time if it's still firing
+    #[serde(default)]
+    pub ends_at: Option<DateTime<Utc>>,
+    /// Link back to the source of the alert
+    #[serde(default, rename = "generatorURL")]
+    pub generator_url: String,
+    /// Alertmanager's identifier for the alert
+    #[serde(default)]
+    pub fingerprint: String,
+}
+
+impl Alert {
+    /// The service name, the `alertname` label plus the `instance` label if there is one
+    pub fn service_name(&self) -> String {
+        let alertname = self
+            .labels
+            .get("alertname")
+            .cloned()
+            .unwrap_or_else(|| format!("alert {}", self.fingerprint));
+        match self.labels.get("instance") {
+            Some(instance) => format!("{} ({})", alertname, instance),
+            None => alertname,
+        }
+    }
+
+    /// Resolved alerts are Ok, firing alerts are mapped from the `severity` label and default to Critical
+    pub fn service_status(&self) -> ServiceStatus {
+        match self.status {
+            AlertStatus::Resolved => ServiceStatus::Ok,
+            AlertStatus::Firing => match self
+                .labels
+                .get("severity")
+                .map(|severity| severity.to_lowercase())
+                .as_deref()
+            {
+                Some("warning") | Some("info") => ServiceStatus::Warning,
+                _ => ServiceStatus::Critical,
+            },
+        }
+    }
+
+    /// Uses the `summary` or `description` annotation if there is one
+    pub fn result_text(&self) -> String {
+        let text = self
+            .annotations
+            .get("summary")
+            .or_else(|| self.annotations.get("description"))
+            .cloned();
+        match (self.status, text) {
+            (AlertStatus::Firing, Some(text)) => text,
+            (AlertStatus::Resolved, Some(text)) => format!("Resolved: {}", text),
+            (AlertStatus::Firing, None) => format!("Firing since {}", self.starts_at),
+            (AlertStatus::Resolved, None) => "Resolved".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// The webhook payload Alertmanager sends
+pub struct AlertmanagerPayload {
+    /// The payload version, currently "4"
+    #[serde(default)]
+    pub version: String,
+    /// The receiver in the Alertmanager config
+    #[serde(default)]
+    pub receiver: String,
+    /// The alerts in this notification
+    pub alerts: Vec<Alert>,
+}
+
+/// Finds the designated host, creating it if needed
+async fn alert_host(db: &DatabaseConnection, name: &str) -> Result<entities::host::Model, Error> {
+    if let Some(host) = entities::host::Model::find_by_name(name, db).await? {
+        return Ok(host);
+    }
+    info!("Creating host {} for Alertmanager alerts", name);
+    entities::host::Model {
+        id: Uuid::new_v4(),
+        name: entities::normalize_name(name),
+        slug: entities::unique_slug::<entities::host::Entity>(
+            db,
+            entities::host::Column::Slug,
+            name,
+        )
+        .await?,
+        hostname: entities::normalize_name(name),
+        check: HostCheck::None,
+        config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
+    }
+    .into_active_model()
+    .insert(db)
+    .await
+    .map_err(Error::from)
+}
+
+/// Finds the passive service and its check on the host, creating them if needed
+async fn alert_service_check(
+    db: &DatabaseConnection,
+    host: &entities::host::Model,
+    alert: &Alert,
+) -> Result<(entities::service_check::Model, entities::service::Model), Error> {
+    let service_name = alert.service_name();
+    let service = match entities::service::Model::find_by_name(&service_name, db).await? {
+        Some(service) if service.service_type == ServiceType::Passive => service,
+        Some(_) => {
+            return Err(Error::Configuration(format!(
+                "Alert {} matches an existing service which isn't passive",
+                service_name
+            )))
+        }
+        None => {
+            info!("Creating passive service {} for Alertmanager", service_name);
+            entities::service::Model {
+                id: Uuid::new_v4(),
+                name: entities::normalize_name(&service_name),
+                slug: entities::unique_slug::<entities::service::Entity>(
+                    db,
+                    entities::service::Column::Slug,
+                    &service_name,
+                )
+                .await?,
+                description: alert.annotations.get("description").cloned(),
+                service_type: ServiceType::Passive,
+                cron_schedule: DEFAULT_PASSIVE_CRON.to_string(),
+                extra_config: json!({}),
+                agent: None,
+                labels: json!({}),
+            }
+            .into_active_model()
+            .insert(db)
+            .await?
+        }
+    };
+
+    let service_check = match entities::service_check::Entity::find()
+        .filter(entities::service_check::Column::HostId.eq(host.id))
+        .filter(entities::service_check::Column::ServiceId.eq(service.id))
+        .one(db)
+        .await?
+    {
+        Some(service_check) => service_check,
+        None => {
+            entities::service_check::Model {
+                id: Uuid::new_v4(),
+                service_id: service.id,
+                host_id: host.id,
+                status: ServiceStatus::Unknown,
+                last_check: chrono::Utc::now(),
+                next_check: chrono::Utc::now(),
+                last_updated: chrono::Utc::now(),
+                paused_until: None,
+            }
+            .into_active_model()
+            .insert(db)
+            .await?
+        }
+    };
+    Ok((service_check, service))
+}
+
+/// Records each alert in the payload against its passive service check, returning how many were processed
+pub async fn process_alerts(
+    db: DatabaseConnection,
+    config: &AlertmanagerConfig,
+    payload: &AlertmanagerPayload,
+) -> Result<usize, Error> {
+    let host = alert_host(&db, &config.host).await?;
+
+    for alert in payload.alerts.iter() {
+        let (service_check, service) = alert_service_check(&db, &host, alert).await?;
+        debug!(
+            "Alertmanager alert={} status={:?} service_check={}",
+            service.name, alert.status, service_check.id
+        );
+        let result = CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: TimeDelta::zero(),
+            status: alert.service_status(),
+            result_text: alert.result_text(),
+            ..Default::default()
+        };
+        let environment = CheckEnvironment {
+            runner: "alertmanager".to_string(),
+            ..CheckEnvironment::local(None)
+        };
+        record_check_result(
+            db.clone(),
+            &service_check,
+            &service,
+            &result,
+            &environment,
+            0,
+        )
+        .await?;
+    }
+    Ok(payload.alerts.len())
+}
+
+#[cfg(test)]
+pub(crate) fn test_payload(status: &str) -> AlertmanagerPayload {
+    #[allow(clippy::expect_used)]
+    serde_json::from_value(json!({
+        "version": "4",
+        "groupKey": "{}:{alertname=\"DiskFull\"}",
+        "truncatedAlerts": 0,
+        "status": status,
+        "receiver": "maremma",
+        "groupLabels": {"alertname": "DiskFull"},
+        "commonLabels": {"alertname": "DiskFull"},
+        "commonAnnotations": {},
+        "externalURL": "http://alertmanager:9093",
+        "alerts": [{
+            "status": status,
+            "labels": {"alertname": "DiskFull", "instance": "db-01:9100", "severity": "warning"},
+            "annotations": {"summary": "Disk is 95% full"},
+            "startsAt": "2025-01-08T01:02:03.456Z",
+            "endsAt": "0001-01-01T00:00:00Z",
+            "generatorURL": "http://prometheus:9090/graph",
+            "fingerprint": "c0ffee"
+        }]
+    }))
+    .expect("Failed to parse test payload")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alert_mapping() {
+        let payload = test_payload("firing");
+        let alert = payload.alerts.first().expect("No alerts in payload");
+        assert_eq!(alert.service_name(), "DiskFull (db-01:9100)");
+        assert_eq!(alert.service_status(), ServiceStatus::Warning);
+        assert_eq!(alert.result_text(), "Disk is 95% full");
+
+        let mut alert = alert.clone();
+        alert.labels.remove("severity");
+        alert.labels.remove("instance");
+        assert_eq!(alert.service_name(), "DiskFull");
+        assert_eq!(alert.service_status(), ServiceStatus::Critical);
+
+        alert.status = AlertStatus::Resolved;
+        assert_eq!(alert.service_status(), ServiceStatus::Ok);
+        assert_eq!(alert.result_text(), "Resolved: Disk is 95% full");
+    }
+
+    #[tokio::test]
+    async fn test_process_alerts() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test");
+        let config = AlertmanagerConfig {
+            token: "hunter2".to_string(),
+            host: default_alertmanager_host(),
+        };
+
+        for (status, expected) in [
+            ("firing", ServiceStatus::Warning),
+            ("resolved", ServiceStatus::Ok),
+        ] {
+            let processed = process_alerts(db.clone(), &config, &test_payload(status))
+                .await
+                .expect("Failed to process alerts");
+            assert_eq!(processed, 1);
+
+            let service = entities::service::Model::find_by_name("DiskFull (db-01:9100)", &db)
+                .await
+                .expect("Failed to query services")
+                .expect("Service wasn't created");
+            assert_eq!(service.service_type, ServiceType::Passive);
+
+            let service_checks = entities::service_check::Entity::find()
+                .filter(entities::service_check::Column::ServiceId.eq(service.id))
+                .all(&db)
+                .await
+                .expect("Failed to query service checks");
+            assert_eq!(service_checks.len(), 1);
+            assert_eq!(
+                service_checks.first().map(|check| check.status),
+                Some(expected)
+            );
+        }
+
+        // the loop should never try to run it
+        if let Some((_, service)) = crate::db::get_next_service_check(&db)
+            .await
+            .expect("Failed to get next service check")
+        {
+            assert_ne!(service.service_type, ServiceType::Passive);
+        }
+    }
+}
@@ -0,0 +1,563 @@
+//! Exporting and importing Maremma's state as a portable archive
+//!
+//! An archive is a zstd-compressed tarball holding a manifest, the config file and every table as JSON, so it doesn't depend on the database it came from.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use sea_orm::{ActiveModelTrait, ConnectionTrait, EntityTrait, TransactionTrait};
+use serde::de::DeserializeOwned;
+
+use crate::cli::{ExportCmd, ImportCmd};
+use crate::config::write_validated_config;
+use crate::prelude::*;
+
+/// Bumped whenever the layout of an archive changes, older archives are read with any tables they don't have left empty
+pub const ARCHIVE_FORMAT_VERSION: u32 = 2;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CONFIG_FILE: &str = "config.json";
+
+/// How many rows go into each insert when restoring, so we stay under SQLite's variable limit
+const INSERT_BATCH_SIZE: usize = 100;
+
+/// The tables in an archive, in the order they're restored so foreign keys are satisfied. Sessions aren't worth keeping,
+/// and host group statuses and discovered hosts are worked out again by the shepherd.
+pub const TABLES: [&str; 16] = [
+    "user",
+    "local_user",
+    "user_preferences",
+    "host",
+    "host_group",
+    "host_group_nesting",
+    "service",
+    "host_group_members",
+    "service_group_link",
+    "service_check",
+    "service_check_history",
+    "service_check_rollup",
+    "service_check_escalation",
+    "service_check_heartbeat",
+    "service_check_output",
+    "incident",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Describes where an archive came from
+pub struct Manifest {
+    /// See [ARCHIVE_FORMAT_VERSION]
+    pub format_version: u32,
+    /// The version of Maremma that made the archive
+    pub maremma_version: String,
+    /// When the archive was made
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Everything needed to move a Maremma install somewhere else
+pub struct Archive {
+    /// Where the archive came from
+    pub manifest: Manifest,
+    /// The config file, if there was one to read
+    pub config: Option<Value>,
+    /// The rows from each table in [TABLES]
+    pub tables: BTreeMap<String, Vec<Value>>,
+}
+
+async fn dump<E>(db: &DatabaseConnection) -> Result<Vec<Value>, Error>
+where
+    E: EntityTrait,
+    E::Model: Serialize,
+{
+    E::find()
+        .all(db)
+        .await?
+        .iter()
+        .map(|row| serde_json::to_value(row).map_err(Error::from))
+        .collect()
+}
+
+async fn load<E, C>(conn: &C, rows: &[Value]) -> Result<usize, Error>
+where
+    E: EntityTrait,
+    E::Model: DeserializeOwned + IntoActiveModel<E::ActiveModel>,
+    E::ActiveModel: ActiveModelTrait<Entity = E> + Send,
+    C: ConnectionTrait,
+{
+    let mut models = rows
+        .iter()
+        .map(|row| {
+            serde_json::from_value::<E::Model>(row.clone())
+                .map(|model| model.into_active_model())
+                .map_err(Error::from)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let total = models.len();
+    while !models.is_empty() {
+        let batch: Vec<_> = models
+            .drain(..INSERT_BATCH_SIZE.min(models.len()))
+            .collect();
+        E::insert_many(batch).exec_without_returning(conn).await?;
+    }
+    Ok(total)
+}
+
+async fn dump_table(db: &DatabaseConnection, table: &str) -> Result<Vec<Value>, Error> {
+    match table {
+        "user" => dump::<entities::user::Entity>(db).await,
+        "local_user" => dump::<entities::local_user::Entity>(db).await,
+        "user_preferences" => dump::<entities::user_preferences::Entity>(db).await,
+        "host" => dump::<entities::host::Entity>(db).await,
+        "host_group" => dump::<entities::host_group::Entity>(db).await,
+        "host_group_nesting" => dump::<entities::host_group_nesting::Entity>(db).await,
+        "service" => dump::<entities::service::Entity>(db).await,
+        "host_group_members" => dump::<entities::host_group_members::Entity>(db).await,
+        "service_group_link" => dump::<entities::service_group_link::Entity>(db).await,
+        "service_check" => dump::<entities::service_check::Entity>(db).await,
+        "service_check_history" => dump::<entities::service_check_history::Entity>(db).await,
+        "service_check_rollup" => dump::<entities::service_check_rollup::Entity>(db).await,
+        "service_check_escalation" => dump::<entities::service_check_escalation::Entity>(db).await,
+        "service_check_heartbeat" => dump::<entities::service_check_heartbeat::Entity>(db).await,
+        "service_check_output" => dump::<entities::service_check_output::Entity>(db).await,
+        "incident" => dump::<entities::incident::Entity>(db).await,
+        _ => Err(Error::InvalidInput(format!("Unknown table {}", table))),
+    }
+}
+
+async fn load_table<C: ConnectionTrait>(
+    conn: &C,
+    table: &str,
+    rows: &[Value],
+) -> Result<usize, Error> {
+    match table {
+        "user" => load::<entities::user::Entity, C>(conn, rows).await,
+        "local_user" => load::<entities::local_user::Entity, C>(conn, rows).await,
+        "user_preferences" => load::<entities::user_preferences::Entity, C>(conn, rows).await,
+        "host" => load::<entities::host::Entity, C>(conn, rows).await,
+        "host_group" => load::<entities::host_group::Entity, C>(conn, rows).await,
+        "host_group_nesting" => load::<entities::host_group_nesting::Entity, C>(conn, rows).await,
+        "service" => load::<entities::service::Entity, C>(conn, rows).await,
+        "host_group_members" => load::<entities::host_group_members::Entity, C>(conn, rows).await,
+        "service_group_link" => load::<entities::service_group_link::Entity, C>(conn, rows).await,
+        "service_check" => load::<entities::service_check::Entity, C>(conn, rows).await,
+        "service_check_history" => {
+            load::<entities::service_check_history::Entity, C>(conn, rows).await
+        }
+        "service_check_rollup" => {
+            load::<entities::service_check_rollup::Entity, C>(conn, rows).await
+        }
+        "service_check_escalation" => {
+            load::<entities::service_check_escalation::Entity, C>(conn, rows).await
+        }
+        "service_check_heartbeat" => {
+            load::<entities::service_check_heartbeat::Entity, C>(conn, rows).await
+        }
+        "service_check_output" => {
+            load::<entities::service_check_output::Entity, C>(conn, rows).await
+        }
+        "incident" => load::<entities::incident::Entity, C>(conn, rows).await,
+        _ => Err(Error::InvalidInput(format!("Unknown table {}", table))),
+    }
+}
+
+async fn clear_table<C: ConnectionTrait>(conn: &C, table: &str) -> Result<u64, Error> {
+    let res = match table {
+        "user" => entities::user::Entity::delete_many().exec(conn).await,
+        "local_user" => entities::local_user::Entity::delete_many().exec(conn).await,
+        "user_preferences" => {
+            entities::user_preferences::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "host" => entities::host::Entity::delete_many().exec(conn).await,
+        "host_group" => entities::host_group::Entity::delete_many().exec(conn).await,
+        "host_group_nesting" => {
+            entities::host_group_nesting::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service" => entities::service::Entity::delete_many().exec(conn).await,
+        "host_group_members" => {
+            entities::host_group_members::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_group_link" => {
+            entities::service_group_link::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_check" => {
+            entities::service_check::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_check_history" => {
+            entities::service_check_history::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_check_rollup" => {
+            entities::service_check_rollup::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_check_escalation" => {
+            entities::service_check_escalation::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_check_heartbeat" => {
+            entities::service_check_heartbeat::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "service_check_output" => {
+            entities::service_check_output::Entity::delete_many()
+                .exec(conn)
+                .await
+        }
+        "incident" => entities::incident::Entity::delete_many().exec(conn).await,
+        _ => return Err(Error::InvalidInput(format!("Unknown table {}", table))),
+    }?;
+    Ok(res.rows_affected)
+}
+
+fn append_json<W: Write, T: Serialize>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    value: &T,
+    mtime: u64,
+) -> Result<(), Error> {
+    let data = serde_json::to_vec_pretty(value)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data.as_slice())?;
+    Ok(())
+}
+
+fn table_path(table: &str) -> String {
+    format!("tables/{}.json", table)
+}
+
+impl Archive {
+    /// Reads everything out of the database, and the config file if it's given
+    pub async fn from_db(
+        db: &DatabaseConnection,
+        config_file: Option<&Path>,
+    ) -> Result<Self, Error> {
+        let config = match config_file {
+            Some(config_file) => Some(serde_json::from_str(
+                &tokio::fs::read_to_string(config_file).await?,
+            )?),
+            None => None,
+        };
+        let mut tables = BTreeMap::new();
+        for table in TABLES {
+            tables.insert(table.to_string(), dump_table(db, table).await?);
+        }
+        Ok(Self {
+            manifest: Manifest {
+                format_version: ARCHIVE_FORMAT_VERSION,
+                maremma_version: env!("CARGO_PKG_VERSION").to_string(),
+                created: Utc::now(),
+            },
+            config,
+            tables,
+        })
+    }
+
+    /// Writes the archive as a zstd-compressed tarball
+    pub fn write<W: Write>(&self, writer: W) -> Result<W, Error> {
+        let mtime = self.manifest.created.timestamp().max(0) as u64;
+        let mut builder = tar::Builder::new(zstd::Encoder::new(writer, 0)?);
+        append_json(&mut builder, MANIFEST_FILE, &self.manifest, mtime)?;
+        if let Some(config) = &self.config {
+            append_json(&mut builder, CONFIG_FILE, config, mtime)?;
+        }
+        for (table, rows) in self.tables.iter() {
+            append_json(&mut builder, &table_path(table), rows, mtime)?;
+        }
+        Ok(builder.into_inner()?.finish()?)
+    }
+
+    /// Writes the archive into a buffer
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.write(Vec::new())
+    }
+
+    /// Reads an archive made by [Archive::write]
+    pub fn read<R: Read>(reader: R) -> Result<Self, Error> {
+        let mut tarball = tar::Archive::new(zstd::Decoder::new(reader)?);
+        let mut files = HashMap::new();
+        for entry in tarball.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            files.insert(path, data);
+        }
+
+        let manifest: Manifest = serde_json::from_slice(
+            &files
+                .remove(MANIFEST_FILE)
+                .ok_or_else(|| Error::InvalidInput("Archive has no manifest".to_string()))?,
+        )?;
+        if manifest.format_version > ARCHIVE_FORMAT_VERSION {
+            return Err(Error::InvalidInput(format!(
+                "Archive format {} is newer than this version of Maremma supports ({})",
+                manifest.format_version, ARCHIVE_FORMAT_VERSION
+            )));
+        }
+        let config = files
+            .remove(CONFIG_FILE)
+            .map(|data| serde_json::from_slice(&data))
+            .transpose()?;
+        let mut tables = BTreeMap::new();
+        for table in TABLES {
+            let rows = match files.remove(&table_path(table)) {
+                Some(data) => serde_json::from_slice(&data)?,
+                None => Vec::new(),
+            };
+            tables.insert(table.to_string(), rows);
+        }
+        Ok(Self {
+            manifest,
+            config,
+            tables,
+        })
+    }
+
+    /// Replaces everything in the database with what's in the archive, in one transaction. Returns how many rows went into each table.
+    pub async fn restore(&self, db: &DatabaseConnection) -> Result<Vec<(String, usize)>, Error> {
+        let txn = db.begin().await?;
+        for table in TABLES.iter().rev() {
+            clear_table(&txn, table).await?;
+        }
+        let mut counts = Vec::with_capacity(TABLES.len());
+        for table in TABLES {
+            let rows = self
+                .tables
+                .get(table)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            counts.push((table.to_string(), load_table(&txn, table, rows).await?));
+        }
+        txn.commit().await?;
+        info!(
+            "Restored archive from Maremma {} made at {}",
+            self.manifest.maremma_version, self.manifest.created
+        );
+        Ok(counts)
+    }
+
+    /// How many rows are in each table
+    pub fn counts(&self) -> Vec<(String, usize)> {
+        TABLES
+            .iter()
+            .map(|table| {
+                (
+                    table.to_string(),
+                    self.tables.get(*table).map(Vec::len).unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Writes an archive of the config file and database to the command's output file
+pub async fn run_export(cmd: &ExportCmd, db: &DatabaseConnection) -> Result<(), Error> {
+    let archive = Archive::from_db(db, Some(&cmd.sharedopts.config)).await?;
+    tokio::fs::write(&cmd.output, archive.to_bytes()?).await?;
+    for (table, count) in archive.counts() {
+        println!("{}: {} rows", table, count);
+    }
+    println!("Wrote {}", cmd.output.display());
+    Ok(())
+}
+
+/// Restores an archive into the database, and the config file if the command asks for it
+pub async fn run_import(cmd: &ImportCmd, db: &DatabaseConnection) -> Result<(), Error> {
+    let archive = Archive::read(tokio::fs::read(&cmd.file).await?.as_slice())?;
+    println!(
+        "Archive from Maremma {} made at {}",
+        archive.manifest.maremma_version, archive.manifest.created
+    );
+    if cmd.dry_run {
+        for (table, count) in archive.counts() {
+            println!("{}: {} rows", table, count);
+        }
+        println!("Dry run, nothing restored");
+        return Ok(());
+    }
+
+    if cmd.restore_config {
+        let config = archive
+            .config
+            .as_ref()
+            .ok_or_else(|| Error::InvalidInput("Archive doesn't have a config file".to_string()))?;
+        let contents = serde_json::to_string_pretty(config)?;
+        write_validated_config(&cmd.sharedopts.config, &contents).await?;
+        println!("Restored {}", cmd.sharedopts.config.display());
+    }
+
+    for (table, count) in archive.restore(db).await? {
+        println!("{}: {} rows", table, count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use sea_orm::{PaginatorTrait, Set};
+
+    #[tokio::test]
+    async fn test_archive_round_trip() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test");
+
+        // these hang off service checks, so the restore's cascade deletes take them out before they're put back
+        let check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let heartbeat = entities::service_check_heartbeat::Entity::for_service_check(&db, check.id)
+            .await
+            .expect("Failed to create heartbeat");
+        entities::service_check_output::ActiveModel {
+            service_check_id: Set(check.id),
+            output: Set("output".to_string()),
+            updated_at: Set(Utc::now()),
+            changed_at: Set(None),
+            diff: Set(None),
+        }
+        .insert(&db)
+        .await
+        .expect("Failed to store output");
+        entities::incident::Entity::record(&db, check.id, ServiceStatus::Critical, Utc::now())
+            .await
+            .expect("Failed to open incident");
+        entities::local_user::Entity::set_password(&db, "archived", "correct horse battery staple")
+            .await
+            .expect("Failed to create local user");
+        entities::user_preferences::Entity::set_preferences(
+            &db,
+            "archived",
+            &entities::user_preferences::Preferences::default(),
+        )
+        .await
+        .expect("Failed to store preferences");
+
+        let archive = Archive::from_db(&db, Some(Path::new("maremma.example.json")))
+            .await
+            .expect("Failed to export");
+        assert!(archive.config.is_some());
+        let hosts = entities::host::Entity::find()
+            .count(&db)
+            .await
+            .expect("Failed to count hosts");
+        assert!(hosts > 0);
+        assert_eq!(
+            archive.tables.get("host").map(Vec::len),
+            Some(hosts as usize)
+        );
+
+        let bytes = archive.to_bytes().expect("Failed to write archive");
+        let read_back = Archive::read(bytes.as_slice()).expect("Failed to read archive");
+        assert_eq!(read_back, archive);
+
+        // wipe a table so we can tell the restore put it back
+        entities::service_check::Entity::delete_many()
+            .exec(&db)
+            .await
+            .expect("Failed to delete service checks");
+        let counts = read_back.restore(&db).await.expect("Failed to restore");
+        assert_eq!(counts, archive.counts());
+        // heartbeat tokens are in people's cron jobs, so they can't change
+        assert_eq!(
+            entities::service_check_heartbeat::Entity::find_by_id(check.id)
+                .one(&db)
+                .await
+                .expect("Failed to query heartbeats"),
+            Some(heartbeat)
+        );
+        assert_eq!(
+            entities::incident::Entity::find()
+                .filter(entities::incident::Column::ServiceCheckId.eq(check.id))
+                .count(&db)
+                .await
+                .expect("Failed to count incidents"),
+            1
+        );
+        assert!(entities::service_check_output::Entity::find_by_id(check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query output")
+            .is_some());
+        assert_eq!(
+            entities::local_user::Entity::find()
+                .count(&db)
+                .await
+                .expect("Failed to count local users"),
+            1
+        );
+        assert!(entities::user_preferences::Entity::find_by_id("archived")
+            .one(&db)
+            .await
+            .expect("Failed to query preferences")
+            .is_some());
+        assert_eq!(
+            entities::service_check::Entity::find()
+                .count(&db)
+                .await
+                .expect("Failed to count service checks") as usize,
+            archive
+                .tables
+                .get("service_check")
+                .map(Vec::len)
+                .unwrap_or_default()
+        );
+
+        // restoring twice doesn't duplicate anything
+        assert_eq!(
+            read_back.restore(&db).await.expect("Failed to restore"),
+            counts
+        );
+    }
+
+    #[test]
+    fn test_archive_bad_input() {
+        assert!(Archive::read(&b"definitely not an archive"[..]).is_err());
+
+        let mut archive = Archive {
+            manifest: Manifest {
+                format_version: ARCHIVE_FORMAT_VERSION + 1,
+                maremma_version: "0.0.0".to_string(),
+                created: Utc::now(),
+            },
+            config: None,
+            tables: BTreeMap::new(),
+        };
+        let bytes = archive.to_bytes().expect("Failed to write archive");
+        assert!(matches!(
+            Archive::read(bytes.as_slice()),
+            Err(Error::InvalidInput(_))
+        ));
+
+        archive.manifest.format_version = ARCHIVE_FORMAT_VERSION;
+        let read_back = Archive::read(
+            archive
+                .to_bytes()
+                .expect("Failed to write archive")
+                .as_slice(),
+        )
+        .expect("Failed to read archive");
+        assert!(read_back.counts().iter().all(|(_table, count)| *count == 0));
+    }
+}
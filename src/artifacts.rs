@@ -0,0 +1,335 @@
+//! Large check output kept outside the database, in a directory or an S3-compatible bucket, see the `artifacts` section of the config
+//!
+//! When a result's `long_output` is bigger than `max_inline_bytes` all of it's stored as an artifact, and the history
+//! entry keeps the start of it and the artifact's key. The service check page links to the rest.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use sea_orm::QuerySelect;
+
+use crate::object_store::{ObjectStore, S3Config};
+use crate::prelude::*;
+
+/// Artifact keys are `artifact-<time>-<uuid>.txt`, so they can share a bucket with other things, eg backups
+const ARTIFACT_PREFIX: &str = "artifact-";
+const ARTIFACT_SUFFIX: &str = ".txt";
+const ARTIFACT_TIME_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// Artifacts that aren't in the history yet are left alone by [ArtifactStore::sweep] for this long, so one that's just
+/// been stored isn't deleted before its result's written
+const SWEEP_GRACE_MINUTES: i64 = 60;
+
+fn default_max_inline_bytes() -> usize {
+    64 * 1024
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `artifacts` section of the config, eg `{"directory": "/var/lib/maremma/artifacts"}`
+pub struct ArtifactConfig {
+    #[serde(default = "default_max_inline_bytes")]
+    /// Output longer than this many bytes is stored as an artifact, defaults to 64KiB
+    pub max_inline_bytes: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Store artifacts in this directory, it's created if it doesn't exist
+    pub directory: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Store artifacts in a bucket instead
+    pub s3: Option<S3Config>,
+}
+
+impl ArtifactConfig {
+    /// Checks there's exactly one place for artifacts to go
+    pub fn validate(&self) -> Result<(), Error> {
+        match (&self.directory, &self.s3) {
+            (Some(_), None) => Ok(()),
+            (None, Some(s3)) => s3.validate(),
+            _ => Err(Error::Configuration(
+                "artifacts needs one of directory or s3".to_string(),
+            )),
+        }
+    }
+
+    /// The backend the config points at
+    pub fn backend(&self) -> Result<Arc<dyn ArtifactBackend>, Error> {
+        match (&self.directory, &self.s3) {
+            (Some(directory), _) => Ok(Arc::new(FilesystemArtifacts {
+                directory: directory.clone(),
+            })),
+            (None, Some(s3)) => Ok(Arc::new(ObjectStore::new(s3.clone())?)),
+            (None, None) => Err(Error::Configuration(
+                "artifacts needs one of directory or s3".to_string(),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+/// Somewhere artifacts can be stored, keys are always `artifact-<time>-<uuid>.txt`
+pub trait ArtifactBackend: Send + Sync {
+    /// Stores the artifact
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), Error>;
+
+    /// Fetches the artifact
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error>;
+
+    /// Deletes the artifact, it's not an error if it's not there
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Everything that's stored, which can include things that aren't artifacts
+    async fn list(&self) -> Result<Vec<String>, Error>;
+}
+
+/// Artifacts as files in a directory
+pub struct FilesystemArtifacts {
+    directory: PathBuf,
+}
+
+#[async_trait]
+impl ArtifactBackend for FilesystemArtifacts {
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        tokio::fs::write(self.directory.join(key), body).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        Ok(tokio::fs::read(self.directory.join(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.directory.join(key)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            // nothing's been stored yet
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            keys.push(entry.file_name().to_string_lossy().to_string());
+        }
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl ArtifactBackend for ObjectStore {
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        ObjectStore::put(self, key, body).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        ObjectStore::get(self, key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        ObjectStore::delete(self, key).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        ObjectStore::list(self, ARTIFACT_PREFIX).await
+    }
+}
+
+/// A new artifact's key
+fn artifact_key(now: DateTime<Utc>) -> String {
+    format!(
+        "{}{}-{}{}",
+        ARTIFACT_PREFIX,
+        now.format(ARTIFACT_TIME_FORMAT),
+        Uuid::new_v4(),
+        ARTIFACT_SUFFIX
+    )
+}
+
+/// When the artifact was stored, or `None` if the key isn't one of ours, which also keeps keys from the database from
+/// escaping the directory or bucket
+fn artifact_time(key: &str) -> Option<DateTime<Utc>> {
+    let name = key
+        .strip_prefix(ARTIFACT_PREFIX)?
+        .strip_suffix(ARTIFACT_SUFFIX)?;
+    let (time, id) = name.split_once('-')?;
+    Uuid::parse_str(id).ok()?;
+    chrono::NaiveDateTime::parse_from_str(time, ARTIFACT_TIME_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Cuts the text down to at most `max_bytes`, without splitting a character
+fn truncate(text: &mut String, max_bytes: usize) {
+    if text.len() > max_bytes {
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+}
+
+#[derive(Default)]
+/// The artifact backend from the config, set up with [ArtifactStore::configure]
+pub struct ArtifactStore {
+    current: std::sync::RwLock<Option<(usize, Arc<dyn ArtifactBackend>)>>,
+}
+
+/// Where large check output goes, nothing's stored as an artifact unless the config has an `artifacts` section
+pub static ARTIFACTS: LazyLock<ArtifactStore> = LazyLock::new(ArtifactStore::default);
+
+impl ArtifactStore {
+    /// Replaces the backend, `None` keeps everything in the database
+    pub fn configure(&self, config: Option<&ArtifactConfig>) -> Result<(), Error> {
+        let backend = match config {
+            Some(config) => Some((config.max_inline_bytes, config.backend()?)),
+            None => None,
+        };
+        if let Ok(mut current) = self.current.write() {
+            *current = backend;
+        }
+        Ok(())
+    }
+
+    fn current(&self) -> Option<(usize, Arc<dyn ArtifactBackend>)> {
+        self.current.read().ok().and_then(|current| current.clone())
+    }
+
+    /// Stores the long output as an artifact if it's too big to keep in the database, leaving the start of it in the
+    /// result. Returns the artifact's key if it was stored, if storing it fails the output's left as it is.
+    pub async fn offload(&self, result: &mut CheckResult, now: DateTime<Utc>) -> Option<String> {
+        let (max_inline_bytes, backend) = self.current()?;
+        let long_output = result.long_output.as_mut()?;
+        if long_output.len() <= max_inline_bytes {
+            return None;
+        }
+        let key = artifact_key(now);
+        if let Err(err) = backend.put(&key, long_output.as_bytes().to_vec()).await {
+            warn!("Failed to store artifact {}: {:?}", key, err);
+            return None;
+        }
+        truncate(long_output, max_inline_bytes);
+        Some(key)
+    }
+
+    /// Fetches an artifact
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        if artifact_time(key).is_none() {
+            return Err(Error::InvalidInput(format!("Invalid artifact key {}", key)));
+        }
+        let (_, backend) = self.current().ok_or_else(|| {
+            Error::Configuration("There's no artifacts section in the config".to_string())
+        })?;
+        backend.get(key).await
+    }
+
+    /// Deletes artifacts the history doesn't refer to any more, returns how many were deleted
+    pub async fn sweep(&self, db: &DatabaseConnection, now: DateTime<Utc>) -> Result<usize, Error> {
+        let Some((_, backend)) = self.current() else {
+            return Ok(0);
+        };
+        // listed first, so anything stored after this is left alone
+        let stored = backend.list().await?;
+        let referenced: HashSet<String> = entities::service_check_history::Entity::find()
+            .select_only()
+            .column(entities::service_check_history::Column::Artifact)
+            .filter(entities::service_check_history::Column::Artifact.is_not_null())
+            .into_tuple()
+            .all(db)
+            .await?
+            .into_iter()
+            .collect();
+
+        let mut deleted = 0;
+        for key in stored {
+            let Some(stored_at) = artifact_time(&key) else {
+                continue;
+            };
+            if referenced.contains(&key)
+                || now - stored_at < TimeDelta::minutes(SWEEP_GRACE_MINUTES)
+            {
+                continue;
+            }
+            debug!("Deleting artifact {}", key);
+            backend.delete(&key).await?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifact_keys() {
+        let now = Utc::now();
+        let key = artifact_key(now);
+        assert_eq!(
+            artifact_time(&key).map(|time| time.timestamp()),
+            Some(now.timestamp())
+        );
+        assert!(artifact_time("maremma-20250101-030000.sqlite3").is_none());
+        assert!(artifact_time("artifact-20250101030000-../../etc/passwd.txt").is_none());
+
+        let mut text = "héllo".to_string();
+        truncate(&mut text, 2);
+        assert_eq!(text, "h");
+    }
+
+    #[tokio::test]
+    async fn test_artifact_store() {
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+        let directory = std::env::temp_dir().join(format!("maremma-artifacts-{}", Uuid::new_v4()));
+        // the global's shared with the other tests, so use one of our own
+        let store = ArtifactStore::default();
+        store
+            .configure(Some(&ArtifactConfig {
+                max_inline_bytes: 10,
+                directory: Some(directory.clone()),
+                s3: None,
+            }))
+            .expect("Failed to configure artifacts");
+
+        let mut short = CheckResult {
+            long_output: Some("short".to_string()),
+            ..Default::default()
+        };
+        assert!(store.offload(&mut short, Utc::now()).await.is_none());
+
+        let output = "a".repeat(100);
+        let mut long = CheckResult {
+            long_output: Some(output.clone()),
+            ..Default::default()
+        };
+        let stored_at = Utc::now() - TimeDelta::hours(2);
+        let key = store
+            .offload(&mut long, stored_at)
+            .await
+            .expect("Failed to offload output");
+        assert_eq!(long.long_output, Some("a".repeat(10)));
+        assert_eq!(
+            store.get(&key).await.expect("Failed to get artifact"),
+            output.as_bytes()
+        );
+
+        // nothing refers to it, and it's old enough to go
+        assert_eq!(
+            store
+                .sweep(&db, Utc::now())
+                .await
+                .expect("Failed to sweep artifacts"),
+            1
+        );
+        assert!(store.get(&key).await.is_err());
+        tokio::fs::remove_dir_all(&directory)
+            .await
+            .expect("Failed to clean up");
+    }
+}
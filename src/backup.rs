@@ -0,0 +1,234 @@
+//! Scheduled backups of the database, to a directory or an S3-compatible bucket, see the `backup` section of the config
+//!
+//! Backups are taken with `VACUUM INTO`, which gives a consistent copy of a live database without stopping the
+//! checks, unlike copying the file.
+
+use std::path::{Path, PathBuf};
+
+use sea_orm::{ConnectionTrait, DatabaseBackend, Statement};
+
+use crate::object_store::{ObjectStore, S3Config};
+use crate::prelude::*;
+
+/// Backups are named `maremma-<time>.sqlite3`, so they sort by when they were taken
+const BACKUP_PREFIX: &str = "maremma-";
+const BACKUP_SUFFIX: &str = ".sqlite3";
+
+fn default_schedule() -> String {
+    "0 3 * * *".to_string()
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `backup` section of the config, eg `{"directory": "/var/backups/maremma", "keep": 14}`
+pub struct BackupConfig {
+    #[serde(default = "default_schedule")]
+    /// When to take a backup, a cron schedule in UTC, defaults to 03:00 every day
+    pub schedule: String,
+    #[serde(default = "default_keep")]
+    /// How many backups to keep, older ones are deleted after each new one's taken. Defaults to 7
+    pub keep: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Write the backups to this directory, it's created if it doesn't exist
+    pub directory: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Upload the backups to a bucket instead
+    pub s3: Option<S3Config>,
+}
+
+impl BackupConfig {
+    /// The parsed schedule
+    pub fn cron(&self) -> Result<Cron, Error> {
+        Ok(Cron::new(&self.schedule).parse()?)
+    }
+
+    /// Checks the schedule parses, at least one backup's kept, and there's exactly one place for them to go
+    pub fn validate(&self) -> Result<(), Error> {
+        self.cron().map_err(|err| {
+            Error::Configuration(format!("backup has an invalid schedule: {:?}", err))
+        })?;
+        if self.keep == 0 {
+            return Err(Error::Configuration(
+                "backup keep must be at least 1".to_string(),
+            ));
+        }
+        match (&self.directory, &self.s3) {
+            (Some(_), None) => Ok(()),
+            (None, Some(s3)) => s3.validate(),
+            _ => Err(Error::Configuration(
+                "backup needs one of directory or s3".to_string(),
+            )),
+        }
+    }
+}
+
+/// The backup's name for a time
+fn backup_name(now: DateTime<Utc>) -> String {
+    format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        now.format("%Y%m%d-%H%M%S"),
+        BACKUP_SUFFIX
+    )
+}
+
+/// The backups that should be deleted to leave the newest `keep`, ignoring anything that isn't a backup
+fn expired(mut names: Vec<String>, keep: usize) -> Vec<String> {
+    names.retain(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX));
+    names.sort();
+    names.reverse();
+    names.into_iter().skip(keep).collect()
+}
+
+/// Writes a consistent copy of the database to `path`, which can't exist yet
+async fn snapshot(db: &DatabaseConnection, path: &Path) -> Result<(), Error> {
+    if db.get_database_backend() != DatabaseBackend::Sqlite {
+        return Err(Error::NotImplemented);
+    }
+    db.execute(Statement::from_sql_and_values(
+        DatabaseBackend::Sqlite,
+        "VACUUM INTO ?",
+        [path.to_string_lossy().to_string().into()],
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Takes a backup and deletes the old ones, returning the new backup's name
+pub async fn backup_database(
+    db: &DatabaseConnection,
+    config: &BackupConfig,
+    now: DateTime<Utc>,
+) -> Result<String, Error> {
+    let name = backup_name(now);
+    match (&config.directory, &config.s3) {
+        (Some(directory), _) => {
+            tokio::fs::create_dir_all(directory).await?;
+            snapshot(db, &directory.join(&name)).await?;
+
+            let mut names = Vec::new();
+            let mut entries = tokio::fs::read_dir(directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+            for old in expired(names, config.keep) {
+                debug!("Deleting old backup {}", old);
+                tokio::fs::remove_file(directory.join(old)).await?;
+            }
+        }
+        (None, Some(s3)) => {
+            let store = ObjectStore::new(s3.clone())?;
+            // it has to go to a file first, then it's uploaded
+            let temp_file = std::env::temp_dir().join(format!("{}-{}", Uuid::new_v4(), name));
+            snapshot(db, &temp_file).await?;
+            let contents = tokio::fs::read(&temp_file).await;
+            if let Err(err) = tokio::fs::remove_file(&temp_file).await {
+                warn!(
+                    "Failed to remove temporary backup {}: {:?}",
+                    temp_file.display(),
+                    err
+                );
+            }
+            store.put(&name, contents?).await?;
+
+            for old in expired(store.list(BACKUP_PREFIX).await?, config.keep) {
+                debug!("Deleting old backup {}", old);
+                store.delete(&old).await?;
+            }
+        }
+        (None, None) => {
+            return Err(Error::Configuration(
+                "backup needs one of directory or s3".to_string(),
+            ))
+        }
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired() {
+        let names = vec![
+            "maremma-20250101-030000.sqlite3".to_string(),
+            "maremma-20250103-030000.sqlite3".to_string(),
+            "notes.txt".to_string(),
+            "maremma-20250102-030000.sqlite3".to_string(),
+        ];
+        assert_eq!(
+            expired(names.clone(), 2),
+            vec!["maremma-20250101-030000.sqlite3".to_string()]
+        );
+        assert!(expired(names, 3).is_empty());
+    }
+
+    #[test]
+    fn test_backup_config() {
+        let config: BackupConfig = serde_json::from_value(json!({"directory": "/tmp/backups"}))
+            .expect("Failed to parse backup config");
+        assert_eq!(config.keep, 7);
+        assert!(config.validate().is_ok());
+        assert!(BackupConfig {
+            keep: 0,
+            ..config.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(BackupConfig {
+            directory: None,
+            ..config.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(BackupConfig {
+            schedule: "not a schedule".to_string(),
+            ..config
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backup_database() {
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+        let directory = std::env::temp_dir().join(format!("maremma-backups-{}", Uuid::new_v4()));
+        let config = BackupConfig {
+            schedule: default_schedule(),
+            keep: 2,
+            directory: Some(directory.clone()),
+            s3: None,
+        };
+        let start = Utc::now();
+        let mut names = Vec::new();
+        for hours in 0..3 {
+            names.push(
+                backup_database(&db, &config, start + TimeDelta::hours(hours))
+                    .await
+                    .expect("Failed to back up"),
+            );
+        }
+
+        let mut remaining = Vec::new();
+        let mut entries = tokio::fs::read_dir(&directory)
+            .await
+            .expect("Failed to read backups");
+        while let Some(entry) = entries.next_entry().await.expect("Failed to read backups") {
+            remaining.push(entry.file_name().to_string_lossy().to_string());
+        }
+        remaining.sort();
+        assert_eq!(remaining, names[1..].to_vec());
+
+        let contents = tokio::fs::read(directory.join(&names[2]))
+            .await
+            .expect("Failed to read backup");
+        assert!(contents.starts_with(b"SQLite format 3\0"));
+        tokio::fs::remove_dir_all(&directory)
+            .await
+            .expect("Failed to clean up");
+    }
+}
@@ -7,7 +7,7 @@ use k8s_openapi::api::networking::v1::Ingress;
 use kube::api::ListParams;
 use kube::{Api, Client};
 use maremma::errors::Error;
-use maremma::log::setup_logging;
+use maremma::log::{setup_logging, LogFormat};
 use serde::{Deserialize, Serialize};
 use tracing::*;
 
@@ -271,7 +271,7 @@ async fn main() -> Result<(), Error> {
     let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
     let ignore_annotation = format!("{}/{}", MAREMMA_SERVICE_NAME, "ignore");
 
-    if let Err(err) = setup_logging(true, true) {
+    if let Err(err) = setup_logging(true, true, LogFormat::Text) {
         eprintln!("Error setting up logging: {:?}", err);
         return Err(Error::Generic("Error setting up logging".to_string()));
     };
@@ -1,15 +1,31 @@
 //! Runs the service checks on a loop
 
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use crate::artifacts::ARTIFACTS;
+use crate::constants::{DEFAULT_SLOW_CHECK_MS, TARGET_ADDRESS_CACHE_SECONDS};
+use crate::db::CheckFilter;
+use crate::group_status::is_failing;
+use crate::health::{Component, HEARTBEATS};
+use crate::log::CHECK_FAILURES;
 use crate::prelude::*;
+use crate::result_writer::{PendingResult, RESULT_WRITER};
+use futures::future::{abortable, AbortHandle};
+use futures::FutureExt;
 use opentelemetry::metrics::Counter;
 use opentelemetry::KeyValue;
-use rand::seq::IteratorRandom;
-use tokio::sync::Semaphore;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
 
 const DEFAULT_BACKOFF: std::time::Duration = tokio::time::Duration::from_millis(50);
 const MAX_BACKOFF: std::time::Duration = tokio::time::Duration::from_secs(1);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 /// The end result of a service check
 pub struct CheckResult {
     /// When the check finished
@@ -18,19 +34,423 @@ pub struct CheckResult {
     pub time_elapsed: Duration,
     /// The result
     pub status: ServiceStatus,
-    /// Any explanatory/returned text
+    /// Any explanatory/returned text, kept to a short summary where the service can manage it
     pub result_text: String,
+    /// Anything beyond the summary, eg the rest of a command's output
+    pub long_output: Option<String>,
+    /// Key/value details, eg performance data
+    pub details: BTreeMap<String, String>,
+    /// The full output, kept to compare with the next run's when the service has `detect_changes` set
+    pub compare_output: Option<String>,
+    /// Connection timings, exit codes and the like, for services with `debug_capture` set
+    pub diagnostics: BTreeMap<String, String>,
+}
+
+/// Parses `key=value` performance data, ignoring thresholds after the first `;`. Returns `None` if anything doesn't look like performance data.
+fn parse_perfdata(perfdata: &str) -> Option<Vec<(String, String)>> {
+    let pairs = perfdata
+        .split_whitespace()
+        .map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let key = key.trim_matches('\'');
+            let value = value.split(';').next().unwrap_or_default();
+            (!key.is_empty()).then(|| (key.to_string(), value.to_string()))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!pairs.is_empty()).then_some(pairs)
+}
+
+impl CheckResult {
+    /// Splits monitoring-plugin style output up: the first line's the summary, the rest is the long output, and `key=value` pairs after a `|` become details
+    pub fn with_plugin_output(mut self, output: &str) -> Self {
+        let mut summary = None;
+        let mut long_output = Vec::new();
+        for line in output.trim().lines() {
+            let line = match line.split_once('|') {
+                Some((text, perfdata)) => match parse_perfdata(perfdata) {
+                    Some(pairs) => {
+                        self.details.extend(pairs);
+                        text.trim_end()
+                    }
+                    None => line,
+                },
+                None => line,
+            };
+            match summary {
+                None => summary = Some(line.to_string()),
+                Some(_) => long_output.push(line),
+            }
+        }
+        self.result_text = summary.unwrap_or_default();
+        let long_output = long_output.join("\n").trim().to_string();
+        self.long_output = (!long_output.is_empty()).then_some(long_output);
+        self
+    }
+
+    /// Keeps the output to compare with the next run's, if `detect_changes` is set
+    pub fn with_compare_output(mut self, detect_changes: bool, output: &str) -> Self {
+        self.compare_output = detect_changes.then(|| output.trim().to_string());
+        self
+    }
+
+    /// Keeps the diagnostics, if `debug_capture` is set
+    pub fn with_diagnostics<I>(mut self, debug_capture: bool, diagnostics: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        if debug_capture {
+            self.diagnostics.extend(diagnostics);
+        }
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Where and how a check was run, stored with its result so discrepancies between runners or versions can be tracked down
+pub struct CheckEnvironment {
+    /// The version of Maremma that ran the check
+    pub maremma_version: String,
+    /// What ran the check, `local` for the server or `agent:<name>`
+    pub runner: String,
+    /// The address the target hostname resolved to
+    pub target_address: Option<String>,
+}
+
+impl CheckEnvironment {
+    /// A check run by this server
+    pub fn local(target_address: Option<String>) -> Self {
+        Self {
+            maremma_version: env!("CARGO_PKG_VERSION").to_string(),
+            runner: "local".to_string(),
+            target_address,
+        }
+    }
+
+    /// A check run by a remote agent, older agents don't send their version
+    pub fn agent(
+        agent_name: &str,
+        maremma_version: Option<String>,
+        target_address: Option<String>,
+    ) -> Self {
+        Self {
+            maremma_version: maremma_version.unwrap_or_else(|| "unknown".to_string()),
+            runner: format!("agent:{}", agent_name),
+            target_address,
+        }
+    }
+}
+
+type TargetAddressCache = HashMap<String, (Instant, Option<String>)>;
+
+/// What hostnames resolved to and when, so every check doesn't need its own lookup
+static TARGET_ADDRESSES: LazyLock<std::sync::Mutex<TargetAddressCache>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Looks up the address a hostname resolves to, giving up quickly because it's only informational. Lookups are reused
+/// for [TARGET_ADDRESS_CACHE_SECONDS].
+pub async fn resolve_target_address(hostname: &str) -> Option<String> {
+    let ttl = std::time::Duration::from_secs(TARGET_ADDRESS_CACHE_SECONDS);
+    if let Ok(cache) = TARGET_ADDRESSES.lock() {
+        if let Some((resolved_at, address)) = cache.get(hostname) {
+            if resolved_at.elapsed() < ttl {
+                return address.clone();
+            }
+        }
+    }
+
+    let address = lookup_target_address(hostname).await;
+    if let Ok(mut cache) = TARGET_ADDRESSES.lock() {
+        cache.retain(|_, (resolved_at, _)| resolved_at.elapsed() < ttl);
+        cache.insert(hostname.to_string(), (Instant::now(), address.clone()));
+    }
+    address
+}
+
+/// Does the lookup for [resolve_target_address]
+async fn lookup_target_address(hostname: &str) -> Option<String> {
+    match tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        tokio::net::lookup_host((hostname, 0)),
+    )
+    .await
+    {
+        Ok(Ok(mut addresses)) => addresses.next().map(|address| address.ip().to_string()),
+        Ok(Err(err)) => {
+            debug!("Failed to resolve {}: {:?}", hostname, err);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+#[derive(Debug, Default)]
+/// Limits how many checks run against each host at once, from `max_concurrent_checks_per_host` and the hosts' `max_concurrent_checks`
+pub struct HostConcurrency {
+    default_limit: Option<usize>,
+    limits: HashMap<Uuid, usize>,
+    running: std::sync::Mutex<HashMap<Uuid, usize>>,
+}
+
+impl HostConcurrency {
+    /// Works out the limit for each host in the database
+    pub async fn new(config: &Configuration, db: &DatabaseConnection) -> Result<Self, Error> {
+        let limits = entities::host::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .filter_map(|host| {
+                config
+                    .hosts
+                    .iter()
+                    .find(|(name, _)| entities::name_key(name) == entities::name_key(&host.name))
+                    .and_then(|(_, config_host)| config_host.max_concurrent_checks)
+                    .map(|limit| (host.id, limit))
+            })
+            .collect();
+        Ok(Self {
+            default_limit: config.max_concurrent_checks_per_host,
+            limits,
+            running: Default::default(),
+        })
+    }
+
+    fn limit(&self, host_id: &Uuid) -> Option<usize> {
+        self.limits.get(host_id).copied().or(self.default_limit)
+    }
+
+    /// The hosts which can't start any more checks right now
+    pub fn full_hosts(&self) -> Vec<Uuid> {
+        match self.running.lock() {
+            Ok(running) => running
+                .iter()
+                .filter(|(host_id, count)| {
+                    self.limit(host_id).is_some_and(|limit| **count >= limit)
+                })
+                .map(|(host_id, _)| *host_id)
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Takes one of the host's slots, returns `None` if it's full
+    pub(crate) fn try_start(self: &Arc<Self>, host_id: Uuid) -> Option<HostSlot> {
+        let mut running = self.running.lock().ok()?;
+        let count = running.entry(host_id).or_default();
+        if self.limit(&host_id).is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(HostSlot {
+            concurrency: self.clone(),
+            host_id,
+        })
+    }
+}
+
+/// One of a host's check slots, it's given back when this is dropped
+pub(crate) struct HostSlot {
+    concurrency: Arc<HostConcurrency>,
+    host_id: Uuid,
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.concurrency.running.lock() {
+            if let Some(count) = running.get_mut(&self.host_id) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    running.remove(&self.host_id);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Checks are scheduled as fast or slow by how long they usually take, and each kind gets its own share of the check slots
+pub enum SchedulingClass {
+    /// Most of them
+    Fast,
+    /// Checks that take longer than `slow_check_ms` on average
+    Slow,
+}
+
+#[derive(Debug, Default)]
+/// Works out each check's [SchedulingClass] from how long its recent runs took
+pub struct SchedulingClasses {
+    slow_check_ms: i64,
+    /// How many checks slow checks can run at once, `None` if there aren't enough slots to split them up
+    slow_permits: Option<usize>,
+    /// A moving average of each check's runtime in milliseconds
+    runtimes: std::sync::Mutex<HashMap<Uuid, i64>>,
+}
+
+impl SchedulingClasses {
+    /// Starts with the average runtime of each check over the last day
+    pub async fn new(config: &Configuration, db: &DatabaseConnection) -> Result<Self, Error> {
+        use entities::service_check_history::Column;
+        use sea_orm::sea_query::{Expr, Func, SimpleExpr};
+        use sea_orm::QuerySelect;
+
+        let runtimes: Vec<(Uuid, Option<f64>)> = entities::service_check_history::Entity::find()
+            .select_only()
+            .column(Column::ServiceCheckId)
+            .column_as(
+                SimpleExpr::from(Func::avg(Expr::col(Column::TimeElapsed))),
+                "time_elapsed",
+            )
+            .filter(Column::Timestamp.gt(Utc::now() - TimeDelta::days(1)))
+            .group_by(Column::ServiceCheckId)
+            .into_tuple()
+            .all(db)
+            .await?;
+        Ok(Self::with_runtimes(
+            config,
+            runtimes
+                .into_iter()
+                .filter_map(|(service_check_id, runtime)| {
+                    runtime.map(|runtime| (service_check_id, runtime as i64))
+                }),
+        ))
+    }
+
+    fn with_runtimes(
+        config: &Configuration,
+        runtimes: impl IntoIterator<Item = (Uuid, i64)>,
+    ) -> Self {
+        let slow_permits = match config.max_concurrent_slow_checks {
+            Some(slow_permits) => Some(slow_permits),
+            // with one slot there's nothing to share
+            None if config.max_concurrent_checks >= 2 => {
+                Some((config.max_concurrent_checks / 4).max(1))
+            }
+            None => None,
+        };
+        Self {
+            slow_check_ms: config.slow_check_ms.unwrap_or(DEFAULT_SLOW_CHECK_MS) as i64,
+            slow_permits,
+            runtimes: std::sync::Mutex::new(runtimes.into_iter().collect()),
+        }
+    }
+
+    /// How many check slots each class gets out of `max_permits`, fast then slow
+    pub fn permits(&self, max_permits: usize) -> (usize, usize) {
+        match self.slow_permits {
+            Some(slow_permits) => (max_permits.saturating_sub(slow_permits), slow_permits),
+            None => (max_permits, 0),
+        }
+    }
+
+    /// Checks that haven't run yet are fast until they show otherwise
+    pub fn class(&self, service_check_id: &Uuid) -> SchedulingClass {
+        if self.slow_permits.is_none() {
+            return SchedulingClass::Fast;
+        }
+        match self.runtimes.lock() {
+            Ok(runtimes) => match runtimes.get(service_check_id) {
+                Some(runtime) if *runtime > self.slow_check_ms => SchedulingClass::Slow,
+                _ => SchedulingClass::Fast,
+            },
+            Err(_) => SchedulingClass::Fast,
+        }
+    }
+
+    /// The slow checks, so a batch can be made of just them, or without them
+    pub fn slow_checks(&self) -> Vec<Uuid> {
+        if self.slow_permits.is_none() {
+            return vec![];
+        }
+        match self.runtimes.lock() {
+            Ok(runtimes) => runtimes
+                .iter()
+                .filter(|(_, runtime)| **runtime > self.slow_check_ms)
+                .map(|(service_check_id, _)| *service_check_id)
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Adds a run to the check's average, recent runs count for more so a check that's sped up moves back quickly
+    pub fn record(&self, service_check_id: Uuid, runtime: TimeDelta) {
+        if let Ok(mut runtimes) = self.runtimes.lock() {
+            let runtime = runtime.num_milliseconds();
+            runtimes
+                .entry(service_check_id)
+                .and_modify(|average| *average = (*average * 3 + runtime) / 4)
+                .or_insert(runtime);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Keeps track of the checks that are running, so they can be cancelled
+pub struct RunningChecks {
+    checks: std::sync::Mutex<HashMap<Uuid, AbortHandle>>,
+}
+
+impl RunningChecks {
+    pub(crate) fn insert(&self, service_check_id: Uuid, handle: AbortHandle) {
+        if let Ok(mut checks) = self.checks.lock() {
+            checks.insert(service_check_id, handle);
+        }
+    }
+
+    fn remove(&self, service_check_id: &Uuid) {
+        if let Ok(mut checks) = self.checks.lock() {
+            checks.remove(service_check_id);
+        }
+    }
+
+    /// Whether the check is running in this process
+    pub fn is_running(&self, service_check_id: &Uuid) -> bool {
+        self.checks
+            .lock()
+            .map(|checks| checks.contains_key(service_check_id))
+            .unwrap_or(false)
+    }
+
+    /// Cancels a running check, returns false if it wasn't running
+    pub fn cancel(&self, service_check_id: &Uuid) -> bool {
+        match self
+            .checks
+            .lock()
+            .ok()
+            .and_then(|mut checks| checks.remove(service_check_id))
+        {
+            Some(handle) => {
+                info!("Cancelling service_check={}", service_check_id);
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels every running check, returning how many there were
+    pub fn cancel_all(&self) -> usize {
+        match self.checks.lock() {
+            Ok(mut checks) => {
+                let cancelled = checks.len();
+                for (service_check_id, handle) in checks.drain() {
+                    info!("Cancelling service_check={}", service_check_id);
+                    handle.abort();
+                }
+                cancelled
+            }
+            Err(_) => 0,
+        }
+    }
 }
 
-#[instrument(level = "INFO", skip_all, fields(service_check_id=%service_check.id, service_id=%service.id))]
+#[instrument(level = "INFO", skip_all, fields(service_check_id=%service_check.id, service_id=%service.id, host_id=%service_check.host_id))]
 /// Does what it says on the tin
 pub(crate) async fn run_service_check(
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
+    running_checks: &RunningChecks,
     service_check: &entities::service_check::Model,
     service: entities::service::Model,
 ) -> Result<(), Error> {
-    let db_writer = db.write().await;
-    let check = match Service::try_from_service_model(&service, &db_writer).await {
+    let check = match Service::try_from_service_model(&service, &db).await {
         Ok(check) => check,
         Err(err) => {
             error!(
@@ -46,7 +466,7 @@ pub(crate) async fn run_service_check(
 
     let host: entities::host::Model = match service_check
         .find_related(entities::host::Entity)
-        .one(&*db_writer)
+        .one(&db)
         .await?
     {
         Some(host) => {
@@ -74,77 +494,198 @@ pub(crate) async fn run_service_check(
         );
         Error::ServiceConfigNotFound(service.id.hyphenated().to_string())
     })?;
-    drop(db_writer);
     debug!("Starting service_check={:?}", service_check);
-    let result = match service_to_run.run(&host).await {
-        Ok(val) => val,
-        Err(err) => CheckResult {
+    let max_runtime = check.max_runtime();
+    let (check, abort_handle) = abortable(tokio::time::timeout(
+        max_runtime,
+        run_isolated(service_to_run.run_local(&host, &db)),
+    ));
+    running_checks.insert(service_check.id, abort_handle);
+    let start = chrono::Utc::now();
+    let result = match check.await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => {
+            warn!(
+                "service_check={} didn't finish within {}s",
+                service_check.id.hyphenated(),
+                max_runtime.as_secs()
+            );
+            timed_out(start, max_runtime)
+        }
+        Err(_) => CheckResult {
             timestamp: chrono::Utc::now(),
-            time_elapsed: Duration::zero(),
-            status: ServiceStatus::Error,
-            result_text: format!("Error: {:?}", err),
+            time_elapsed: chrono::Utc::now() - start,
+            status: ServiceStatus::Cancelled,
+            result_text: "Check was cancelled".to_string(),
+            ..Default::default()
         },
     };
+    running_checks.remove(&service_check.id);
+    let environment = CheckEnvironment::local(resolve_target_address(&host.hostname).await);
     let jitter = service_to_run.jitter_value();
     debug!(
         "Completed service_check={:?} result={:?}",
         service_check, result.status
     );
+    log_result(
+        service_check.id,
+        &service.name,
+        host.display_name(),
+        &result,
+    );
 
-    let db_writer = db.write().await;
+    record_check_result(db, service_check, &service, &result, &environment, jitter).await
+}
 
-    entities::service_check_history::Model::from_service_check_result(service_check.id, &result)
-        .into_active_model()
-        .insert(&*db_writer)
-        .await?;
+/// Logs failing results, holding back repeats of the same failure with [CHECK_FAILURES]
+fn log_result(service_check_id: Uuid, service_name: &str, host_name: &str, result: &CheckResult) {
+    let key = service_check_id.hyphenated().to_string();
+    let now = Utc::now();
+    if is_failing(result.status) {
+        let message = format!(
+            "{} on {} is {}: {}",
+            service_name, host_name, result.status, result.result_text
+        );
+        if let Some(message) = CHECK_FAILURES.observe(&key, &message, now) {
+            warn!("{}", message);
+        }
+    } else if let Some(summary) = CHECK_FAILURES.clear(&key, now) {
+        info!(
+            "{} on {} is {} again, its last failure {}",
+            service_name, host_name, result.status, summary
+        );
+    }
+}
 
-    let mut model = service_check.clone().into_active_model();
-    model.last_check.set_if_not_equals(chrono::Utc::now());
-    model.status.set_if_not_equals(result.status);
-
-    // get a number between 0 and jitter
-    let jitter: i64 = (0..jitter).choose(&mut rand::thread_rng()).unwrap_or(0) as i64;
-
-    let next_check = Cron::new(&service.cron_schedule)
-        .parse()?
-        .find_next_occurrence(&chrono::Utc::now(), false)?
-        + chrono::Duration::seconds(jitter);
-    model.next_check.set_if_not_equals(next_check);
-
-    if model.is_changed() {
-        debug!("Saving {:?}", model);
-        model.save(&*db_writer).await.map_err(|err| {
-            error!("{} error saving {:?}", service.id.hyphenated(), err);
-            Error::from(err)
-        })?;
+/// Pulls the message out of a panic payload, which is usually a `&str` or a `String`
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
     } else {
-        debug!("set_last_check with no change? {:?}", model);
+        "unknown panic payload".to_string()
     }
+}
 
-    Ok(())
+/// The result for a check that ran past its `max_runtime`
+pub(crate) fn timed_out(start: DateTime<Utc>, max_runtime: std::time::Duration) -> CheckResult {
+    CheckResult {
+        timestamp: chrono::Utc::now(),
+        time_elapsed: chrono::Utc::now() - start,
+        status: ServiceStatus::Timeout,
+        result_text: format!(
+            "Check didn't finish within the max_runtime of {}s",
+            max_runtime.as_secs()
+        ),
+        ..Default::default()
+    }
+}
+
+/// Runs a check, turning errors and panics into an Error result so a misbehaving service can't take out the check loop
+pub(crate) async fn run_isolated<F>(check: F) -> CheckResult
+where
+    F: Future<Output = Result<CheckResult, Error>>,
+{
+    let start = chrono::Utc::now();
+    match AssertUnwindSafe(check).catch_unwind().await {
+        Ok(Ok(val)) => val,
+        Ok(Err(err)) if err.is_configuration() => CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: Duration::zero(),
+            status: ServiceStatus::ConfigError,
+            result_text: match err {
+                Error::UnknownHostVariable(host, variable) => format!(
+                    "Configuration error: unknown host variable #{}# for host {}",
+                    variable, host
+                ),
+                Error::UnknownHostVar(host, name) => format!(
+                    "Configuration error: unknown host var {{{{ {} }}}} for host {}",
+                    name, host
+                ),
+                Error::Configuration(message) => format!("Configuration error: {}", message),
+                err => format!("Configuration error: {:?}", err),
+            },
+            ..Default::default()
+        },
+        Ok(Err(err)) => CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: Duration::zero(),
+            status: ServiceStatus::Error,
+            result_text: format!("Error: {:?}", err),
+            ..Default::default()
+        },
+        Err(payload) => {
+            let message = panic_message(payload.as_ref());
+            error!("Service check panicked: {}", message);
+            CheckResult {
+                timestamp: chrono::Utc::now(),
+                time_elapsed: chrono::Utc::now() - start,
+                status: ServiceStatus::Error,
+                result_text: format!("Check panicked: {}", message),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+/// Stores the result of a check in the history table and schedules the next run, used for both local and agent-run checks
+pub(crate) async fn record_check_result(
+    db: DatabaseConnection,
+    service_check: &entities::service_check::Model,
+    service: &entities::service::Model,
+    result: &CheckResult,
+    environment: &CheckEnvironment,
+    jitter: u32,
+) -> Result<(), Error> {
+    let mut result = entities::service_check_output::Entity::compare(
+        &db,
+        service_check.id,
+        result.clone(),
+        chrono::Utc::now(),
+    )
+    .await?;
+    let artifact = ARTIFACTS.offload(&mut result, chrono::Utc::now()).await;
+    RESULT_WRITER
+        .write(
+            db,
+            PendingResult {
+                service_check: service_check.clone(),
+                service: service.clone(),
+                result,
+                environment: environment.clone(),
+                jitter,
+                artifact,
+            },
+        )
+        .await
 }
 
 #[instrument(level = "DEBUG", skip_all, fields(service_check_id = %service_check.id, service_id = %service.id))]
 async fn run_inner(
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
+    running_checks: Arc<RunningChecks>,
     service_check: entities::service_check::Model,
     service: entities::service::Model,
     checks_run_since_startup: Arc<Counter<u64>>,
 ) -> Result<(), Error> {
     let sc_id = service_check.id.hyphenated().to_string();
-    if let Err(err) = run_service_check(db.clone(), &service_check, service).await {
-        error!("Failed to run service_check {} error={:?}", sc_id, err);
+    if let Err(err) = run_service_check(db.clone(), &running_checks, &service_check, service).await
+    {
+        let message = format!("Failed to run service_check {} error={:?}", sc_id, err);
+        if let Some(message) = CHECK_FAILURES.observe(&sc_id, &message, Utc::now()) {
+            error!("{}", message);
+        }
 
-        let db_writer = db.write().await;
         if let Some(service_check) = entities::service_check::Entity::find()
             .filter(entities::service_check::Column::Id.eq(&sc_id))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .map_err(Error::from)?
         {
             let mut service_check = service_check.into_active_model();
             service_check.status.set_if_not_equals(ServiceStatus::Error);
-            service_check.update(&*db_writer).await?;
+            service_check.update(&db).await?;
         }
 
         checks_run_since_startup.add(
@@ -166,63 +707,263 @@ async fn run_inner(
     Ok(())
 }
 
+/// Runs the check in its own task, so if something outside the service itself panics the check doesn't stay stuck in [ServiceStatus::Checking]
+async fn run_supervised(
+    db: DatabaseConnection,
+    running_checks: Arc<RunningChecks>,
+    service_check: entities::service_check::Model,
+    service: entities::service::Model,
+    checks_run_since_startup: Arc<Counter<u64>>,
+) -> Result<(), Error> {
+    let task = tokio::spawn(run_inner(
+        db.clone(),
+        running_checks,
+        service_check.clone(),
+        service,
+        checks_run_since_startup,
+    ));
+    match task.await {
+        Ok(res) => res,
+        Err(err) => {
+            error!(
+                "Task for service_check {} failed: {:?}",
+                service_check.id.hyphenated(),
+                err
+            );
+            service_check
+                .set_status(ServiceStatus::Error, db)
+                .await
+                .map(|_| ())
+        }
+    }
+}
+
+/// Waits for the spawned checks to finish, cancelling any that are still going after `timeout`
+async fn drain_checks(
+    tasks: &mut JoinSet<Result<(), Error>>,
+    running_checks: &RunningChecks,
+    timeout: std::time::Duration,
+) {
+    if tasks.is_empty() {
+        return;
+    }
+    info!(
+        "Waiting up to {}s for {} running checks to finish",
+        timeout.as_secs(),
+        tasks.len()
+    );
+    let wait_all = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(timeout, wait_all).await.is_ok() {
+        return;
+    }
+    warn!(
+        "Cancelled {} checks which didn't finish in time",
+        running_checks.cancel_all()
+    );
+    // cancelled checks still record their result, but don't wait forever for them
+    if tokio::time::timeout(MAX_BACKOFF * 5, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        warn!("{} checks were still running at shutdown", tasks.len());
+        tasks.abort_all();
+    }
+}
+
+/// Counts due checks that someone else claimed first, shared by the check loop and the agent API
+pub(crate) fn check_claim_conflicts(metrics_meter: &Meter) -> Counter<u64> {
+    metrics_meter
+        .u64_counter("check_claim_conflicts")
+        .with_description("Due checks that another instance or agent claimed first")
+        .build()
+}
+
 #[cfg(not(tarpaulin_include))]
-/// Loop around and do the checks, keeping it to a limit based on `max_permits`
+/// Loop around and do the checks, keeping it to a limit based on `max_permits`, which is split between fast and slow
+/// checks so neither can hold up the other.
+///
+/// When `shutdown` changes to true it stops starting checks, and waits up to `shutdown_timeout` for the running ones.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_check_loop(
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
+    running_checks: Arc<RunningChecks>,
     max_permits: usize,
     metrics_meter: Arc<Meter>,
+    host_concurrency: Arc<HostConcurrency>,
+    scheduling: Arc<SchedulingClasses>,
+    mut shutdown: watch::Receiver<bool>,
+    shutdown_timeout: std::time::Duration,
 ) -> Result<(), Error> {
     // Create a Counter Instrument.
 
-    use crate::db::get_next_service_check;
+    use crate::db::get_due_service_checks;
 
     let checks_run_since_startup = metrics_meter
         .u64_counter("checks_run_since_startup")
         .build();
     let checks_run_since_startup = Arc::new(checks_run_since_startup);
+    let check_claim_conflicts = check_claim_conflicts(&metrics_meter);
+    let check_queue_latency = metrics_meter
+        .f64_histogram("check_queue_latency")
+        .with_description("How long checks waited between being due and starting")
+        .with_unit("s")
+        .build();
 
     let mut backoff: std::time::Duration = DEFAULT_BACKOFF;
-    // Limit to n concurrent tasks
-    let semaphore = Arc::new(Semaphore::new(max_permits));
-    info!("Max concurrent tasks set to {}", max_permits);
+    // Limit to n concurrent tasks, each running check holds a permit from its class until it's done
+    let (fast_permits, slow_permits) = scheduling.permits(max_permits);
+    let fast = Arc::new(Semaphore::new(fast_permits));
+    let slow = Arc::new(Semaphore::new(slow_permits));
+    info!(
+        "Max concurrent tasks set to {}, {} of them for slow checks",
+        max_permits, slow_permits
+    );
+    let mut tasks = JoinSet::new();
     loop {
-        while semaphore.available_permits() == 0 {
+        // tidy up the finished ones so the set doesn't grow forever
+        while tasks.try_join_next().is_some() {}
+        if *shutdown.borrow() {
+            info!("Check loop stopping, no new checks will be started");
+            drain_checks(&mut tasks, &running_checks, shutdown_timeout).await;
+            return Ok(());
+        }
+        if fast.available_permits() == 0 && slow.available_permits() == 0 {
             warn!("No spare task slots, something might be running slow!");
-            tokio::time::sleep(backoff).await;
-        }
-        match semaphore.clone().acquire_owned().await {
-            Ok(permit) => {
-                let next_service = get_next_service_check(&*db.read().await).await?;
-
-                if let Some((service_check, service)) = next_service {
-                    // set the service_check to running
-                    service_check
-                        .set_status(ServiceStatus::Checking, db.clone())
-                        .await?;
-                    tokio::spawn(run_inner(
-                        db.clone(),
-                        service_check,
-                        service,
-                        checks_run_since_startup.clone(),
-                    ));
-                    // we did a thing, so we can reset the back-off time, because there might be another
-                    backoff = DEFAULT_BACKOFF;
-                } else {
-                    // didn't get a task, increase backoff a little, but don't overflow the max
-                    backoff += DEFAULT_BACKOFF;
-                    if backoff > MAX_BACKOFF {
-                        backoff = MAX_BACKOFF;
-                    }
-                }
-                drop(permit); // Release the semaphore when the task is done
+        }
+        // wait until there's room for at least one check
+        let first_permit = tokio::select! {
+            permit = fast.clone().acquire_owned() => {
+                permit.map(|permit| (SchedulingClass::Fast, permit))
             }
+            permit = slow.clone().acquire_owned(), if slow_permits > 0 => {
+                permit.map(|permit| (SchedulingClass::Slow, permit))
+            }
+            _ = shutdown.changed() => continue,
+        };
+        let mut permits: HashMap<SchedulingClass, Vec<OwnedSemaphorePermit>> = HashMap::new();
+        match first_permit {
+            Ok((class, permit)) => permits.entry(class).or_default().push(permit),
             Err(err) => {
                 error!("Failed to acquire semaphore permit: {:?}", err);
                 // something went wrong so we want to chill a bit
                 backoff = std::cmp::max(MAX_BACKOFF / 2, DEFAULT_BACKOFF);
+                tokio::time::sleep(backoff).await;
+                continue;
             }
         };
+        // grab whatever else is free, and fill it with a batch of due checks
+        for (class, semaphore) in [
+            (SchedulingClass::Fast, &fast),
+            (SchedulingClass::Slow, &slow),
+        ] {
+            while let Ok(permit) = semaphore.clone().try_acquire_owned() {
+                permits.entry(class).or_default().push(permit);
+            }
+        }
+        let free = |permits: &HashMap<SchedulingClass, Vec<OwnedSemaphorePermit>>, class| {
+            permits.get(&class).map(Vec::len).unwrap_or_default()
+        };
+
+        // when one class is full, only ask for the other's checks so they can't be crowded out of the batch
+        let slow_checks = scheduling.slow_checks();
+        let checks = match (
+            free(&permits, SchedulingClass::Fast),
+            free(&permits, SchedulingClass::Slow),
+        ) {
+            (0, _) => CheckFilter::Only(&slow_checks),
+            (_, 0) if !slow_checks.is_empty() => CheckFilter::Except(&slow_checks),
+            _ => CheckFilter::All,
+        };
+        // hosts that are already busy are left out, so their checks don't hog the batch
+        let batch = get_due_service_checks(
+            &db,
+            permits.values().map(Vec::len).sum::<usize>() as u64,
+            &host_concurrency.full_hosts(),
+            checks,
+        )
+        .await?;
+        HEARTBEATS.beat(Component::CheckLoop);
+        if batch.is_empty() {
+            // didn't get a task, increase backoff a little, but don't overflow the max
+            backoff += DEFAULT_BACKOFF;
+            if backoff > MAX_BACKOFF {
+                backoff = MAX_BACKOFF;
+            }
+            drop(permits);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = shutdown.changed() => {},
+            }
+            continue;
+        }
+        debug!(
+            "Got {} due checks with {} fast and {} slow slots free",
+            batch.len(),
+            free(&permits, SchedulingClass::Fast),
+            free(&permits, SchedulingClass::Slow),
+        );
+
+        for (service_check, service) in batch {
+            // the batch can fill up a host on its own, the rest of its checks wait for the next one
+            let Some(host_slot) = host_concurrency.try_start(service_check.host_id) else {
+                debug!(
+                    "host_id={} is running as many checks as it's allowed, skipping service_check={}",
+                    service_check.host_id.hyphenated(),
+                    service_check.id.hyphenated()
+                );
+                continue;
+            };
+            // the batch is never bigger than the free slots, but they might all be the other class's
+            let class = scheduling.class(&service_check.id);
+            let Some(permit) = permits.get_mut(&class).and_then(Vec::pop) else {
+                debug!(
+                    "No free {:?} slots, skipping service_check={}",
+                    class,
+                    service_check.id.hyphenated()
+                );
+                continue;
+            };
+            // set the service_check to running, unless another instance sharing the database beat us to it
+            let Some(service_check) = service_check.claim(&db).await? else {
+                debug!(
+                    "service_check={} was claimed by another instance",
+                    service_check.id.hyphenated()
+                );
+                check_claim_conflicts.add(1, &[]);
+                permits.entry(class).or_default().push(permit);
+                continue;
+            };
+            let queued_for = Utc::now() - service_check.next_check;
+            check_queue_latency.record(
+                queued_for.num_milliseconds().max(0) as f64 / 1000.0,
+                &[KeyValue::new(
+                    "service_type",
+                    service.service_type.to_string(),
+                )],
+            );
+            let service_check_id = service_check.id;
+            let task = run_supervised(
+                db.clone(),
+                running_checks.clone(),
+                service_check,
+                service,
+                checks_run_since_startup.clone(),
+            );
+            let scheduling = scheduling.clone();
+            tasks.spawn(async move {
+                let start = Utc::now();
+                let res = task.await;
+                scheduling.record(service_check_id, Utc::now() - start);
+                // Release the semaphore and the host's slot when the task is done
+                drop(permit);
+                drop(host_slot);
+                res
+            });
+        }
+        // we did a thing, so we can reset the back-off time, because there might be another
+        backoff = DEFAULT_BACKOFF;
     }
 }
 
@@ -233,66 +974,362 @@ mod tests {
     use super::*;
     use crate::db::tests::test_setup;
 
+    #[test]
+    fn test_with_plugin_output() {
+        let res = CheckResult::default().with_plugin_output(
+            "DISK OK - free space: / 3326 MB | '/'=2643MB;5948;5958;0;5968 inodes=42\nfirst detail line\nsecond | /boot=68MB;88;93;0;98\n",
+        );
+        assert_eq!(res.result_text, "DISK OK - free space: / 3326 MB");
+        assert_eq!(
+            res.long_output.as_deref(),
+            Some("first detail line\nsecond")
+        );
+        assert_eq!(
+            res.details,
+            BTreeMap::from([
+                ("/".to_string(), "2643MB".to_string()),
+                ("/boot".to_string(), "68MB".to_string()),
+                ("inodes".to_string(), "42".to_string()),
+            ])
+        );
+
+        // a pipe that isn't followed by performance data is left alone
+        let res = CheckResult::default().with_plugin_output("cat foo | grep bar");
+        assert_eq!(res.result_text, "cat foo | grep bar");
+        assert!(res.long_output.is_none());
+        assert!(res.details.is_empty());
+
+        let res = CheckResult::default().with_plugin_output("");
+        assert_eq!(res.result_text, "");
+        assert!(res.long_output.is_none());
+    }
+
     #[tokio::test]
     async fn test_run_service_check() {
         let (db, _config) = test_setup().await.expect("Failed to setup test");
 
-        let db_reader = db.read().await;
-
         let service = entities::service::Entity::find()
             .filter(entities::service::Column::ServiceType.eq(ServiceType::Ping))
-            .one(&*db_reader)
+            .one(&db)
             .await
             .expect("Failed to query ping service")
             .expect("Failed to find ping service");
 
         let service_check = service_check::Entity::find()
             .filter(service_check::Column::ServiceId.eq(service.id))
-            .one(&*db_reader)
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Failed to find service check");
+
+        run_service_check(
+            db.clone(),
+            &RunningChecks::default(),
+            &service_check,
+            service,
+        )
+        .await
+        .expect("Failed to run service check");
+
+        let history = entities::service_check_history::Entity::find()
+            .filter(entities::service_check_history::Column::ServiceCheckId.eq(service_check.id))
+            .one(&db)
+            .await
+            .expect("Failed to query history")
+            .expect("Failed to find history entry");
+        assert_eq!(history.runner.as_deref(), Some("local"));
+        assert_eq!(
+            history.maremma_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_service_check_max_runtime() {
+        let (db, _config) = crate::testing::setup_with_config(json!({
+            "hosts": {"localhost": {"host_groups": ["slow"]}},
+            "services": {
+                "slow": {
+                    "service_type": "cli",
+                    "host_groups": ["slow"],
+                    "command_line": "sleep 10",
+                    "cron_schedule": "* * * * *",
+                    "max_runtime": 1
+                }
+            }
+        }))
+        .await
+        .expect("Failed to setup test");
+
+        let (service_check, service) = crate::db::get_next_service_check(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("Failed to find service check");
+        let start = std::time::Instant::now();
+        run_service_check(
+            db.clone(),
+            &RunningChecks::default(),
+            &service_check,
+            service,
+        )
+        .await
+        .expect("Failed to run service check");
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        let service_check = service_check::Entity::find_by_id(service_check.id)
+            .one(&db)
             .await
             .expect("Failed to query service check")
             .expect("Failed to find service check");
-        drop(db_reader);
+        assert_eq!(service_check.status, ServiceStatus::Timeout);
+    }
+
+    #[tokio::test]
+    async fn test_check_environment() {
+        assert_eq!(
+            resolve_target_address("127.0.0.1").await.as_deref(),
+            Some("127.0.0.1")
+        );
+        assert!(resolve_target_address("this.does.not.exist.invalid")
+            .await
+            .is_none());
+        // the lookup's reused rather than done again for every check
+        assert!(TARGET_ADDRESSES
+            .lock()
+            .expect("Failed to lock")
+            .contains_key("127.0.0.1"));
+
+        let environment = CheckEnvironment::agent("remote", None, None);
+        assert_eq!(environment.runner, "agent:remote");
+        assert_eq!(environment.maremma_version, "unknown");
+    }
+
+    #[tokio::test]
+    async fn test_run_isolated() {
+        let result = run_isolated(async {
+            Ok(CheckResult {
+                timestamp: chrono::Utc::now(),
+                time_elapsed: Duration::zero(),
+                status: ServiceStatus::Ok,
+                result_text: "fine".to_string(),
+                ..Default::default()
+            })
+        })
+        .await;
+        assert_eq!(result.status, ServiceStatus::Ok);
+
+        let result = run_isolated(async { Err(Error::Generic("nope".to_string())) }).await;
+        assert_eq!(result.status, ServiceStatus::Error);
+        assert!(result.result_text.contains("nope"));
+
+        let config_result = run_isolated(async {
+            Err(Error::UnknownHostVariable(
+                "example.com".to_string(),
+                "PORT".to_string(),
+            ))
+        })
+        .await;
+        assert_eq!(config_result.status, ServiceStatus::ConfigError);
+        assert_eq!(
+            config_result.result_text,
+            "Configuration error: unknown host variable #PORT# for host example.com"
+        );
+
+        let config_result = run_isolated(async {
+            Err(Error::UnknownHostVar(
+                "example.com".to_string(),
+                "app_port".to_string(),
+            ))
+        })
+        .await;
+        assert_eq!(config_result.status, ServiceStatus::ConfigError);
+        assert_eq!(
+            config_result.result_text,
+            "Configuration error: unknown host var {{ app_port }} for host example.com"
+        );
 
-        run_service_check(db.clone(), &service_check, service)
+        #[allow(clippy::panic)]
+        let result = run_isolated(async {
+            if result.status == ServiceStatus::Error {
+                panic!("service exploded after {} tries", 3);
+            }
+            Err(Error::Generic("unreachable".to_string()))
+        })
+        .await;
+        assert_eq!(result.status, ServiceStatus::Error);
+        assert_eq!(
+            result.result_text,
+            "Check panicked: service exploded after 3 tries"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_running_checks_cancel() {
+        let running_checks = RunningChecks::default();
+        let service_check_id = Uuid::new_v4();
+        assert!(!running_checks.cancel(&service_check_id));
+
+        let (check, abort_handle) = abortable(std::future::pending::<()>());
+        running_checks.insert(service_check_id, abort_handle);
+        assert!(running_checks.is_running(&service_check_id));
+        assert!(running_checks.cancel(&service_check_id));
+        assert!(check.await.is_err());
+        assert!(!running_checks.is_running(&service_check_id));
+        assert!(!running_checks.cancel(&service_check_id));
+    }
+
+    #[tokio::test]
+    async fn test_host_concurrency() {
+        let (db, config) = test_setup().await.expect("Failed to setup test");
+        let mut config = config.write().await;
+        config.max_concurrent_checks_per_host = Some(2);
+        let (host_name, host) = config.hosts.iter_mut().next().expect("No hosts in config");
+        host.max_concurrent_checks = Some(1);
+        let host_name = host_name.clone();
+
+        let hosts = entities::host::Entity::find()
+            .all(&db)
+            .await
+            .expect("Failed to get hosts");
+        let limited = hosts
+            .iter()
+            .find(|host| entities::name_key(&host.name) == entities::name_key(&host_name))
+            .expect("Failed to find host")
+            .id;
+        let other = hosts
+            .iter()
+            .find(|host| host.id != limited)
+            .expect("Need a second host")
+            .id;
+
+        let concurrency = Arc::new(
+            HostConcurrency::new(&config, &db)
+                .await
+                .expect("Failed to build host concurrency"),
+        );
+        let slot = concurrency
+            .try_start(limited)
+            .expect("Host should have room");
+        assert!(concurrency.try_start(limited).is_none());
+        assert_eq!(concurrency.full_hosts(), vec![limited]);
+
+        // everyone else gets the global limit
+        let others = [concurrency.try_start(other), concurrency.try_start(other)];
+        assert!(others.iter().all(Option::is_some));
+        assert!(concurrency.try_start(other).is_none());
+
+        drop(slot);
+        drop(others);
+        assert!(concurrency.full_hosts().is_empty());
+        assert!(concurrency.try_start(limited).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_scheduling_classes() {
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
+        let mut config = Configuration {
+            max_concurrent_checks: 8,
+            ..Default::default()
+        };
+        let scheduling = SchedulingClasses::new(&config, &db)
             .await
-            .expect("Failed to run service check");
+            .expect("Failed to load runtimes");
+        assert_eq!(scheduling.permits(8), (6, 2));
+
+        let slow = Uuid::new_v4();
+        let fast = Uuid::new_v4();
+        assert_eq!(scheduling.class(&slow), SchedulingClass::Fast);
+        scheduling.record(slow, TimeDelta::seconds(30));
+        scheduling.record(fast, TimeDelta::milliseconds(50));
+        assert_eq!(scheduling.class(&slow), SchedulingClass::Slow);
+        assert_eq!(scheduling.class(&fast), SchedulingClass::Fast);
+        assert_eq!(scheduling.slow_checks(), vec![slow]);
+
+        // it takes a few quick runs to stop being slow
+        scheduling.record(slow, TimeDelta::milliseconds(10));
+        assert_eq!(scheduling.class(&slow), SchedulingClass::Slow);
+        for _ in 0..6 {
+            scheduling.record(slow, TimeDelta::milliseconds(10));
+        }
+        assert_eq!(scheduling.class(&slow), SchedulingClass::Fast);
+
+        // there's nothing to split with one slot
+        config.max_concurrent_checks = 1;
+        let scheduling = SchedulingClasses::with_runtimes(&config, [(slow, 30_000)]);
+        assert_eq!(scheduling.permits(1), (1, 0));
+        assert_eq!(scheduling.class(&slow), SchedulingClass::Fast);
+        assert!(scheduling.slow_checks().is_empty());
+
+        config.max_concurrent_slow_checks = Some(3);
+        config.slow_check_ms = Some(60_000);
+        let scheduling = SchedulingClasses::with_runtimes(&config, [(slow, 30_000)]);
+        assert_eq!(scheduling.permits(10), (7, 3));
+        assert_eq!(scheduling.class(&slow), SchedulingClass::Fast);
+    }
+
+    #[tokio::test]
+    async fn test_drain_checks_cancels_after_timeout() {
+        let running_checks = RunningChecks::default();
+        assert_eq!(running_checks.cancel_all(), 0);
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..2 {
+            let (check, abort_handle) = abortable(std::future::pending::<()>());
+            running_checks.insert(Uuid::new_v4(), abort_handle);
+            tasks.spawn(async move {
+                // this is what run_supervised does with a cancelled check
+                let _ = check.await;
+                Ok(())
+            });
+        }
+        tasks.spawn(async { Ok(()) });
+
+        drain_checks(
+            &mut tasks,
+            &running_checks,
+            std::time::Duration::from_millis(100),
+        )
+        .await;
+        assert!(tasks.is_empty());
+        assert_eq!(running_checks.cancel_all(), 0);
     }
 
     #[tokio::test]
     async fn test_run_pending_service_check() {
         let (db, _config) = test_setup().await.expect("Failed to setup test");
 
-        let db_writer = db.write().await;
-
         service_check::Entity::update_many()
             .col_expr(
                 service_check::Column::Status,
                 Expr::value(ServiceStatus::Pending),
             )
-            .exec(&*db_writer)
+            .exec(&db)
             .await
             .expect("Failed to update service checks to pending");
 
         let service = entities::service::Entity::find()
             .filter(entities::service::Column::ServiceType.eq(ServiceType::Ping))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .expect("Failed to query ping service")
             .expect("Failed to find ping service");
 
         let service_check = service_check::Entity::find()
             .filter(service_check::Column::ServiceId.eq(service.id))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .expect("Failed to query service check")
             .expect("Failed to find service check");
 
-        drop(db_writer);
         dbg!(&service, &service_check);
 
-        run_service_check(db.clone(), &service_check, service)
-            .await
-            .expect("Failed to run service check");
+        run_service_check(
+            db.clone(),
+            &RunningChecks::default(),
+            &service_check,
+            service,
+        )
+        .await
+        .expect("Failed to run service check");
     }
 }
@@ -4,7 +4,8 @@ use std::path::PathBuf;
 
 use clap::*;
 
-use crate::prelude::ServiceType;
+use crate::log::LogFormat;
+use crate::prelude::{ServiceStatus, ServiceType};
 use crate::DEFAULT_CONFIG_FILE;
 
 #[derive(Parser, Clone, Default, Debug)]
@@ -16,6 +17,9 @@ pub struct SharedOpts {
     #[clap(long,action = clap::ArgAction::SetTrue)]
     /// Enable database debug logging because it's SUPER noisy
     pub db_debug: Option<bool>,
+    #[clap(long, value_enum, env = "MAREMMA_LOG_FORMAT")]
+    /// Log as text (the default) or as JSON, for log collectors like Loki or Elastic
+    pub log_format: Option<LogFormat>,
 
     #[clap(short, long, help=format!("Path to the configuration file. Defaults to {}", crate::DEFAULT_CONFIG_FILE), default_value=crate::DEFAULT_CONFIG_FILE)]
     /// Defaults to [crate::DEFAULT_CONFIG_FILE]
@@ -44,16 +48,202 @@ pub struct OneShotCmd {
     pub sharedopts: SharedOpts,
     /// The check to run
     pub check: ServiceType,
-    /// Hostname to target
+    /// Hostname to target, if it's the name of a configured host its hostname is used
     pub hostname: String,
     /// Extra configuration, parsed as JSON
+    #[clap(default_value = "{}")]
     pub service_config: String,
 
+    /// Override a config value, eg `--set port=8443`, values are parsed as JSON if they can be and dots set nested keys
+    #[clap(long = "set", value_name = "KEY=VALUE")]
+    pub set: Vec<String>,
+
+    /// How to print the result
+    #[clap(long, value_enum, default_value_t)]
+    pub output: OutputFormat,
+
     /// Show the config options for the service
     #[clap(long)]
     pub show_config: bool,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// How command output is printed
+pub enum OutputFormat {
+    /// For humans
+    #[default]
+    Text,
+    /// For scripts
+    Json,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Run as a remote agent, executing checks assigned by a central server
+pub struct AgentCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// The URL of the central Maremma server, eg `https://maremma.example.com`
+    #[clap(long, env = "MAREMMA_AGENT_SERVER_URL")]
+    pub server_url: String,
+    /// The name of this agent, must match an entry in the server's `agents` configuration
+    #[clap(long, env = "MAREMMA_AGENT_NAME")]
+    pub name: String,
+    /// The token used to authenticate with the server
+    #[clap(long, env = "MAREMMA_AGENT_TOKEN", hide_env_values = true)]
+    pub token: String,
+    /// Seconds between polls for work, defaults to the value the server sends on registration
+    #[clap(long)]
+    pub poll_interval: Option<u64>,
+    /// Where the plugins for `plugin` services pinned to this agent are
+    #[clap(long, env = "MAREMMA_AGENT_PLUGIN_DIR")]
+    pub plugin_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Show which services will generate checks for a host, and why
+pub struct ExplainHostCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// The host's name in the config file, or its hostname
+    pub name: String,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+/// Things that can be explained
+pub enum ExplainCmd {
+    #[clap(name = "host")]
+    /// Show which services will generate checks for a host, and why
+    Host(ExplainHostCmd),
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Import hosts from an inventory into the config file
+pub struct ImportCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// The inventory file to read
+    pub file: PathBuf,
+    /// The inventory format, guessed from the file extension if not set
+    #[clap(long, value_enum)]
+    pub format: Option<crate::import::InventoryFormat>,
+    /// Show what would change without writing the config file
+    #[clap(long)]
+    pub dry_run: bool,
+    /// When importing an archive, also replace the config file with the one in the archive
+    #[clap(long)]
+    pub restore_config: bool,
+}
+
+impl ImportCmd {
+    /// If the file is an archive from `maremma export`, rather than an inventory
+    pub fn is_archive(&self) -> bool {
+        self.format
+            .unwrap_or_else(|| crate::import::InventoryFormat::from_path(&self.file))
+            == crate::import::InventoryFormat::Archive
+    }
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Upgrade the config file to the current `config_version`
+pub struct MigrateConfigCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// Show the diff without writing the config file
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Export the config and database to an archive
+pub struct ExportCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// Where to write the archive, eg `backup.tar.zst`
+    #[clap(long, short)]
+    pub output: PathBuf,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Scan a network range, or sync a discovery source, for hosts which aren't in the config yet
+pub struct DiscoverCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// The range to scan, eg `10.0.0.0/24`, a single address works too
+    #[clap(long, required_unless_present = "source", conflicts_with = "source")]
+    pub cidr: Option<String>,
+    /// Sync this discovery source from the config instead of scanning
+    #[clap(long)]
+    pub source: Option<String>,
+    /// The TCP ports to try, comma separated
+    #[clap(long, value_delimiter = ',', default_values_t = crate::discovery::DEFAULT_PORTS)]
+    pub ports: Vec<u16>,
+    /// How long to wait for each ping or connection, in milliseconds
+    #[clap(long, default_value_t = 1000)]
+    pub timeout_ms: u64,
+    /// How many addresses are probed at once
+    #[clap(long, default_value_t = 64)]
+    pub concurrency: usize,
+    /// Put the hosts in this group too, can be given more than once
+    #[clap(long)]
+    pub host_group: Vec<String>,
+    /// Add the new hosts to the config file straight away, instead of leaving them for review
+    #[clap(long)]
+    pub accept: bool,
+    /// How to print the hosts
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Show the status of service checks
+pub struct StatusCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// Only show checks for this host, by name or hostname
+    #[clap(long)]
+    pub host: Option<String>,
+    /// Only show checks for this service
+    #[clap(long)]
+    pub service: Option<String>,
+    /// Only show checks with this status, eg `critical`
+    #[clap(long, value_parser = crate::status::parse_status)]
+    pub status: Option<ServiceStatus>,
+    /// How to print the checks
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Add a user who logs in with a password, change their password, or remove them
+pub struct LocalUserCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// The user's name
+    pub username: String,
+    /// Remove the user instead
+    #[clap(long)]
+    pub delete: bool,
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Make a signed link to the read-only kiosk page, for wallboards
+pub struct KioskUrlCmd {
+    #[clap(flatten)]
+    /// Shared options
+    pub sharedopts: SharedOpts,
+    /// How many days the link works for
+    #[clap(long, default_value_t = 30)]
+    pub days: u32,
+}
+
 /// Sub commands
 #[derive(Subcommand, Clone)]
 pub enum Actions {
@@ -66,12 +256,42 @@ pub enum Actions {
     #[clap(name = "show-config")]
     /// Show the system configuration
     ShowConfig(ShowConfig),
+    #[clap(name = "migrate-config")]
+    /// Upgrade a config file written for an older version of Maremma, printing what changed
+    MigrateConfig(MigrateConfigCmd),
     #[clap(name = "export-config-schema")]
     /// Export a JSON schema for the config file
     ExportConfigSchema,
     #[clap(name = "oneshot")]
     /// Run a single check manually and exit
     OneShot(OneShotCmd),
+    #[clap(name = "agent")]
+    /// Run as a remote agent for a central server
+    Agent(AgentCmd),
+    #[clap(name = "explain", subcommand)]
+    /// Explain how the configuration applies, without changing anything
+    Explain(ExplainCmd),
+    #[clap(name = "export-prometheus-rules")]
+    /// Export Prometheus alerting rules for services with `expose_alert_rule` set
+    ExportPrometheusRules(ShowConfig),
+    #[clap(name = "import")]
+    /// Import hosts and host groups from an Ansible inventory or CSV file, or restore an archive from `export`
+    Import(ImportCmd),
+    #[clap(name = "export")]
+    /// Export the config and database to an archive which `import` can restore
+    Export(ExportCmd),
+    #[clap(name = "status")]
+    /// Show the status of service checks
+    Status(StatusCmd),
+    #[clap(name = "discover")]
+    /// Scan a network range or sync a discovery source, and propose adding the hosts to the config
+    Discover(DiscoverCmd),
+    #[clap(name = "local-user")]
+    /// Manage the users who can log in when `auth.mode` is `local`, the password is read from stdin or MAREMMA_LOCAL_USER_PASSWORD
+    LocalUser(LocalUserCmd),
+    #[clap(name = "kiosk-url")]
+    /// Print a signed link to the read-only kiosk page, which needs `kiosk` in the config
+    KioskUrl(KioskUrlCmd),
 }
 
 #[derive(Parser, Clone)]
@@ -89,7 +309,17 @@ impl CliOpts {
             Actions::Run(run) => run.sharedopts.config.clone(),
             Actions::CheckConfig(run) => run.sharedopts.config.clone(),
             Actions::ShowConfig(run) => run.sharedopts.config.clone(),
+            Actions::MigrateConfig(run) => run.sharedopts.config.clone(),
             Actions::OneShot(run) => run.sharedopts.config.clone(),
+            Actions::Agent(run) => run.sharedopts.config.clone(),
+            Actions::ExportPrometheusRules(run) => run.sharedopts.config.clone(),
+            Actions::Import(run) => run.sharedopts.config.clone(),
+            Actions::Export(run) => run.sharedopts.config.clone(),
+            Actions::Status(run) => run.sharedopts.config.clone(),
+            Actions::Discover(run) => run.sharedopts.config.clone(),
+            Actions::LocalUser(run) => run.sharedopts.config.clone(),
+            Actions::KioskUrl(run) => run.sharedopts.config.clone(),
+            Actions::Explain(ExplainCmd::Host(run)) => run.sharedopts.config.clone(),
             Actions::ExportConfigSchema => PathBuf::from(DEFAULT_CONFIG_FILE),
         }
     }
@@ -100,7 +330,17 @@ impl CliOpts {
             Actions::Run(run) => run.sharedopts.debug.unwrap_or(false),
             Actions::CheckConfig(run) => run.sharedopts.debug.unwrap_or(false),
             Actions::ShowConfig(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::MigrateConfig(run) => run.sharedopts.debug.unwrap_or(false),
             Actions::OneShot(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::Agent(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::ExportPrometheusRules(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::Import(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::Export(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::Status(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::Discover(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::LocalUser(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::KioskUrl(run) => run.sharedopts.debug.unwrap_or(false),
+            Actions::Explain(ExplainCmd::Host(run)) => run.sharedopts.debug.unwrap_or(false),
             Actions::ExportConfigSchema => false,
         }
     }
@@ -110,10 +350,42 @@ impl CliOpts {
             Actions::Run(run) => run.sharedopts.db_debug.unwrap_or(false),
             Actions::CheckConfig(run) => run.sharedopts.db_debug.unwrap_or(false),
             Actions::ShowConfig(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::MigrateConfig(run) => run.sharedopts.db_debug.unwrap_or(false),
             Actions::OneShot(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::Agent(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::ExportPrometheusRules(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::Import(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::Export(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::Status(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::Discover(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::LocalUser(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::KioskUrl(run) => run.sharedopts.db_debug.unwrap_or(false),
+            Actions::Explain(ExplainCmd::Host(run)) => run.sharedopts.db_debug.unwrap_or(false),
             Actions::ExportConfigSchema => false,
         }
     }
+    /// Gets the log_format field
+    pub fn log_format(&self) -> LogFormat {
+        match &self.action {
+            Actions::Run(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::CheckConfig(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::ShowConfig(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::MigrateConfig(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::OneShot(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::Agent(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::ExportPrometheusRules(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::Import(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::Export(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::Status(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::Discover(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::LocalUser(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::KioskUrl(run) => run.sharedopts.log_format.unwrap_or_default(),
+            Actions::Explain(ExplainCmd::Host(run)) => {
+                run.sharedopts.log_format.unwrap_or_default()
+            }
+            Actions::ExportConfigSchema => LogFormat::default(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -154,6 +426,22 @@ mod tests {
                 "maremma export-config-schema",
                 PathBuf::from(crate::DEFAULT_CONFIG_FILE),
             ),
+            (
+                "maremma import --dry-run -c /tmp/maremma.json hosts.ini",
+                PathBuf::from("/tmp/maremma.json"),
+            ),
+            (
+                "maremma migrate-config --dry-run -c /tmp/maremma.json",
+                PathBuf::from("/tmp/maremma.json"),
+            ),
+            (
+                "maremma local-user -c /tmp/maremma.json admin",
+                PathBuf::from("/tmp/maremma.json"),
+            ),
+            (
+                "maremma kiosk-url -c /tmp/maremma.json --days 7",
+                PathBuf::from("/tmp/maremma.json"),
+            ),
         ];
 
         for (args, expected_config) in test_list {
@@ -164,6 +452,110 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_agent_cmd() {
+        let opts = CliOpts::parse_from(
+            "maremma agent --debug --server-url https://maremma.example.com --name dc2 --token hunter2 --plugin-dir /usr/lib/maremma/plugins"
+                .split_whitespace(),
+        );
+        assert!(opts.debug());
+        assert!(!opts.db_debug());
+        assert_eq!(opts.log_format(), LogFormat::Text);
+
+        let opts = CliOpts::parse_from("maremma run --log-format json".split_whitespace());
+        assert_eq!(opts.log_format(), LogFormat::Json);
+        match opts.action {
+            Actions::Agent(cmd) => {
+                assert_eq!(cmd.server_url, "https://maremma.example.com");
+                assert_eq!(cmd.name, "dc2");
+                assert_eq!(cmd.token, "hunter2");
+                assert_eq!(cmd.poll_interval, None);
+            }
+            _ => panic!("Expected the agent subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_oneshot_cmd() {
+        let opts = CliOpts::parse_from(
+            "maremma oneshot http example.com --set port=8443 --set http_uri=/health --output json"
+                .split_whitespace(),
+        );
+        match opts.action {
+            Actions::OneShot(cmd) => {
+                assert_eq!(cmd.service_config, "{}");
+                assert_eq!(cmd.set, vec!["port=8443", "http_uri=/health"]);
+                assert_eq!(cmd.output, OutputFormat::Json);
+            }
+            _ => panic!("Expected the oneshot subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_status_cmd() {
+        let opts = CliOpts::parse_from(
+            "maremma status --host example.com --status Critical --format json".split_whitespace(),
+        );
+        match opts.action {
+            Actions::Status(cmd) => {
+                assert_eq!(cmd.host.as_deref(), Some("example.com"));
+                assert_eq!(cmd.service, None);
+                assert_eq!(cmd.status, Some(ServiceStatus::Critical));
+                assert_eq!(cmd.format, OutputFormat::Json);
+            }
+            _ => panic!("Expected the status subcommand"),
+        }
+        assert!(
+            CliOpts::try_parse_from("maremma status --status sideways".split_whitespace()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_explain_cmd() {
+        let opts = CliOpts::parse_from(
+            "maremma explain host --config /tmp/config.json example.com".split_whitespace(),
+        );
+        assert_eq!(opts.config(), PathBuf::from("/tmp/config.json"));
+        match opts.action {
+            Actions::Explain(ExplainCmd::Host(cmd)) => assert_eq!(cmd.name, "example.com"),
+            _ => panic!("Expected the explain host subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_discover_cmd() {
+        let opts = CliOpts::parse_from(
+            "maremma discover --cidr 10.0.0.0/24 --host-group lab --accept".split_whitespace(),
+        );
+        match opts.action {
+            Actions::Discover(cmd) => {
+                assert_eq!(cmd.cidr.as_deref(), Some("10.0.0.0/24"));
+                assert_eq!(cmd.source, None);
+                assert_eq!(cmd.ports, crate::discovery::DEFAULT_PORTS.to_vec());
+                assert_eq!(cmd.host_group, vec!["lab"]);
+                assert!(cmd.accept);
+            }
+            _ => panic!("Expected the discover subcommand"),
+        }
+        let opts = CliOpts::parse_from(
+            "maremma discover --cidr 10.0.0.1 --ports 22,3389".split_whitespace(),
+        );
+        match opts.action {
+            Actions::Discover(cmd) => assert_eq!(cmd.ports, vec![22, 3389]),
+            _ => panic!("Expected the discover subcommand"),
+        }
+        let opts = CliOpts::parse_from("maremma discover --source prod".split_whitespace());
+        match opts.action {
+            Actions::Discover(cmd) => assert_eq!(cmd.source.as_deref(), Some("prod")),
+            _ => panic!("Expected the discover subcommand"),
+        }
+        assert!(CliOpts::try_parse_from("maremma discover".split_whitespace()).is_err());
+        assert!(CliOpts::try_parse_from(
+            "maremma discover --cidr 10.0.0.1 --source prod".split_whitespace()
+        )
+        .is_err());
+    }
+
     // TODO: work out how to run the export subcommand, capture the result and confirm it's doing what it says
 
     #[test]
@@ -174,6 +566,7 @@ mod tests {
             ("maremma show-config --db-debug", true),
             ("maremma show-config", false),
             ("maremma export-config-schema", false),
+            ("maremma export-prometheus-rules --db-debug", true),
         ];
 
         for (args, db_debug) in test_list {
@@ -2,16 +2,51 @@
 
 use std::collections::{HashMap, HashSet};
 use std::num::NonZeroU16;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use schemars::JsonSchema;
 
+use crate::actions::escalation::check_escalations;
+use crate::actions::routing::{check_alert_windows, check_targets, NotificationConfig};
+use crate::agent::AgentConfig;
+use crate::alertmanager::AlertmanagerConfig;
+use crate::artifacts::ArtifactConfig;
+use crate::backup::BackupConfig;
+use crate::config_events::check_webhooks;
 use crate::constants::{
-    web_server_default_port, DEFAULT_SERVICE_CHECK_HISTORY_STORAGE, WEB_SERVER_DEFAULT_STATIC_PATH,
+    web_server_default_port, CURRENT_CONFIG_VERSION, DEFAULT_SERVICE_CHECK_HISTORY_STORAGE,
+    DEFAULT_SHUTDOWN_TIMEOUT_SECONDS, STUCK_CHECK_MINUTES, WEB_SERVER_DEFAULT_STATIC_PATH,
 };
+use crate::db::entities::{find_duplicates, name_key};
+use crate::db::sqlite::SqliteConfig;
+use crate::discovery::sources::{DiscoveryConfig, DiscoverySourceKind};
+use crate::group_status::GroupStatusConfig;
 use crate::host::fakehost::FakeHost;
+use crate::host::group::{
+    check_nesting, check_selectors, expand_host_groups, parse_selector, selected_by,
+    HostGroupConfig,
+};
 use crate::host::{Host, HostCheck};
+use crate::log::RepeatedFailuresConfig;
 use crate::prelude::*;
+use crate::reports::{check_reports, ReportConfig};
+use crate::secrets::vault::VaultConfig;
+use crate::services::host_variables::{
+    find_host_variables_in_value, find_host_vars_in_value, HOST_VARIABLES,
+};
+use crate::services::parse_timezone;
+use crate::services::passive::SubmitConfig;
+use crate::services::plugin::{PluginConfig, PluginService};
+use crate::services::self_monitor::builtin_services;
+use crate::ssh_client::SshPoolConfig;
+use crate::status_page::StatusPageConfig;
+use crate::web::acme::AcmeConfig;
+use crate::web::auth::{AuthConfig, AuthMode};
+use crate::web::kiosk::KioskConfig;
+use crate::web::mtls::{ClientVerification, MtlsConfig};
+use crate::web::proxy::{parse_trusted_proxies, ListenScheme};
+use crate::web::session::SessionConfig;
+use crate::web::theme::{check_timezone, ThemeConfig};
 
 fn default_database_file() -> String {
     "maremma.sqlite".to_string()
@@ -30,6 +65,10 @@ fn default_max_concurrent_checks() -> usize {
 #[derive(Serialize, Deserialize, Debug, Default)]
 /// Parses configuration from the file
 pub struct ConfigurationParser {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The config file format, `maremma migrate-config` upgrades files from older versions
+    pub config_version: Option<u32>,
+
     #[serde(default = "default_database_file")]
     /// Path to the database file (or `:memory:` for in-memory)
     pub database_file: String,
@@ -48,6 +87,10 @@ pub struct ConfigurationParser {
     /// Target host configuration
     pub hosts: HashMap<String, Host>,
 
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Host groups which include other groups, eg `{"all-prod": {"members": ["prod-web", "prod-db"]}}`
+    pub host_groups: HashMap<String, HostGroupConfig>,
+
     #[serde(default)]
     /// Services to run locally
     pub local_services: FakeHost,
@@ -56,6 +99,10 @@ pub struct ConfigurationParser {
     /// Service configuration
     pub services: HashMap<String, Value>,
 
+    #[serde(skip_serializing, default)]
+    /// Common service fields, services use them with `"template": "name"` and override what they need to
+    pub service_templates: HashMap<String, Value>,
+
     /// The frontend URL ie `https://maremma.example.com` used for things like OIDC
     pub frontend_url: Option<String>,
     /// OIDC issuer (url)
@@ -77,8 +124,357 @@ pub struct ConfigurationParser {
     /// The maximum concurrent checks we'll run at one time
     pub max_concurrent_checks: usize,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The maximum concurrent checks against any one host, unlimited if not set
+    pub max_concurrent_checks_per_host: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How many of `max_concurrent_checks` slow checks can use, so they can't hold up the fast ones, defaults to a quarter
+    pub max_concurrent_slow_checks: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Checks that take longer than this many milliseconds on average are scheduled as slow ones, defaults to 5000 ([crate::constants::DEFAULT_SLOW_CHECK_MS])
+    pub slow_check_ms: Option<u64>,
+
     /// How many history entries to keep per check, defaults to 25000 ([crate::constants::DEFAULT_HISTORY_LIMIT]), setting this too high can cause slowdowns.
     pub max_history_entries_per_check: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Delete history entries older than this many days, history is only trimmed by count if not set
+    pub max_history_age_days: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How often pages refresh themselves in seconds, 0 turns it off, each page has its own default if not set
+    pub page_refresh_seconds: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The timezone times are shown in, eg `Australia/Brisbane`, the browser's timezone is used if not set
+    pub display_timezone: Option<String>,
+
+    /// How long to wait for running checks when shutting down, defaults to 30 seconds ([crate::constants::DEFAULT_SHUTDOWN_TIMEOUT_SECONDS])
+    pub shutdown_timeout_seconds: Option<u64>,
+
+    #[serde(default)]
+    /// Remote agents which are allowed to run checks, keyed by agent name
+    pub agents: HashMap<String, AgentConfig>,
+
+    #[serde(default)]
+    /// Accept Alertmanager webhooks and turn them into passive service checks
+    pub alertmanager: Option<AlertmanagerConfig>,
+
+    #[serde(default)]
+    /// Accept results for passive service checks on `/api/v1/submit`
+    pub submit: Option<SubmitConfig>,
+
+    #[serde(default)]
+    /// Where `{"$secret": {"vault": "<path>#<field>"}}` secret references are fetched from
+    pub vault: Option<VaultConfig>,
+
+    #[serde(default)]
+    /// Sharing SSH connections between checks on the same host
+    pub ssh_pool: SshPoolConfig,
+
+    #[serde(default)]
+    /// Holding back repeats of the same check failure in the logs
+    pub repeated_failures: RepeatedFailuresConfig,
+
+    #[serde(default)]
+    /// Executables that implement the `plugin` service type
+    pub plugins: PluginConfig,
+
+    #[serde(default)]
+    /// SQLite tuning, eg the journal mode and how long to wait on a locked database
+    pub sqlite: SqliteConfig,
+
+    #[serde(default)]
+    /// Where notifications are sent, with overrides for host groups
+    pub notifications: NotificationConfig,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Scheduled summary reports sent to notification targets, keyed by name
+    pub reports: HashMap<String, ReportConfig>,
+    #[serde(default)]
+    /// Sources hosts are discovered from and kept in sync with, eg Kubernetes clusters and DNS zones
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    /// How each host group's status is worked out from its hosts
+    pub group_status: GroupStatusConfig,
+    #[serde(default)]
+    /// The public status page, which is off unless it's enabled
+    pub status_page: StatusPageConfig,
+    #[serde(default)]
+    /// The UI's default theme, and branding
+    pub theme: ThemeConfig,
+    #[serde(default)]
+    /// How users log in, OIDC by default
+    pub auth: AuthConfig,
+    #[serde(default)]
+    /// How long web sessions last, and how the session cookie's set
+    pub session: SessionConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Ask clients for a certificate, and let the names in them log in to the API
+    pub mtls: Option<MtlsConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Signed links to a read-only dashboard for wallboards, made with `maremma kiosk-url`
+    pub kiosk: Option<KioskConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Get the web server's certificate from Let's Encrypt or another ACME CA, instead of `cert_file` and `cert_key`
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    /// `https` by default, or `http` when a reverse proxy handles TLS
+    pub listen_scheme: ListenScheme,
+    #[serde(default)]
+    /// IPs or CIDRs of reverse proxies whose X-Forwarded-For and X-Forwarded-Proto headers are believed
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    /// Add the built-in services which watch Maremma's database, scheduler and memory use
+    pub self_monitoring: bool,
+    #[serde(default)]
+    /// Stagger checks which are due at startup or reload across their schedules, instead of running them all at once
+    pub spread_initial_checks: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// URLs that are sent a JSON event when hosts, services or host groups are added, removed or changed
+    pub config_webhooks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Back the database up to a directory or an S3-compatible bucket on a schedule
+    pub backup: Option<BackupConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Keep check output that's too big for the database in a directory or an S3-compatible bucket
+    pub artifacts: Option<ArtifactConfig>,
+}
+
+impl ConfigurationParser {
+    /// Adds the built-in self-monitoring services to `local_services` if `self_monitoring` is on, services in the config with the same names win
+    pub fn add_self_monitoring(&mut self) {
+        if !self.self_monitoring {
+            return;
+        }
+        for (name, service) in builtin_services() {
+            if self
+                .services
+                .keys()
+                .any(|existing| name_key(existing) == name_key(&name))
+            {
+                continue;
+            }
+            self.services.insert(name.clone(), service);
+            if !self
+                .local_services
+                .services
+                .iter()
+                .any(|existing| name_key(existing) == name_key(&name))
+            {
+                self.local_services.services.push(name);
+            }
+        }
+    }
+
+    /// Asks the plugins if the `plugin` services' config makes sense, services pinned to agents are skipped because their plugins live there
+    pub async fn plugin_problems(&self) -> Vec<String> {
+        let mut names = self.services.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut res = Vec::new();
+        for name in names {
+            let Some(service) = self.services.get(name).and_then(|v| v.as_object()) else {
+                continue;
+            };
+            if service.get("service_type") != Some(&json!("plugin"))
+                || service.get("agent").is_some_and(|agent| !agent.is_null())
+            {
+                continue;
+            }
+            let mut service = service.clone();
+            service.entry("name").or_insert_with(|| json!(name.clone()));
+            match serde_json::from_value::<PluginService>(Value::Object(service)) {
+                Ok(service) => {
+                    if let Some(problem) = service.validate_with_plugin().await {
+                        res.push(format!("service '{}': {}", name, problem));
+                    }
+                }
+                Err(err) => res.push(format!("service '{}': {}", name, err)),
+            }
+        }
+        res
+    }
+
+    /// Finds hosts, services and host groups whose names only differ by case or surrounding whitespace
+    pub fn duplicate_names(&self) -> Vec<String> {
+        let mut res = find_duplicates("host", self.hosts.keys());
+        res.extend(find_duplicates("service", self.services.keys()));
+
+        let service_groups = self
+            .services
+            .values()
+            .filter_map(|service| service.get("host_groups").and_then(|g| g.as_array()))
+            .flatten()
+            .filter_map(|group| group.as_str().map(|g| parse_selector(g).0.to_string()));
+        let groups = self
+            .hosts
+            .values()
+            .flat_map(|host| host.host_groups.iter().cloned())
+            .chain(service_groups);
+        res.extend(find_duplicates("host group", groups));
+        res
+    }
+
+    /// Finds service and host config which uses host variables that don't exist, these fail at runtime with a configuration error
+    pub fn unknown_host_variables(&self) -> Vec<String> {
+        let unknown = |value: &Value| {
+            let mut variables = find_host_variables_in_value(value)
+                .into_iter()
+                .filter(|variable| !HOST_VARIABLES.contains(&variable.as_str()))
+                .map(|variable| format!("#{}#", variable))
+                .collect::<Vec<_>>();
+            variables.sort();
+            variables.dedup();
+            variables
+        };
+        let mut res = Vec::new();
+        for (name, service) in self.services.iter() {
+            let variables = unknown(service);
+            if !variables.is_empty() {
+                res.push(format!(
+                    "service '{}' uses unknown host variables: {}",
+                    name,
+                    variables.join(", ")
+                ));
+            }
+        }
+        for (name, host) in self.hosts.iter() {
+            for (service_name, config) in host.config.iter() {
+                let variables = unknown(config);
+                if !variables.is_empty() {
+                    res.push(format!(
+                        "host '{}' config for service '{}' uses unknown host variables: {}",
+                        name,
+                        service_name,
+                        variables.join(", ")
+                    ));
+                }
+            }
+
+            // services on the host which use vars it doesn't have, unless the host config replaces the field
+            let host_groups = expand_host_groups(&self.host_groups, &host.host_groups);
+            let on_host = |service: &Value| {
+                let selectors = service
+                    .get("host_groups")
+                    .and_then(|groups| groups.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|group| group.as_str().map(str::to_string))
+                    .collect::<Vec<_>>();
+                !selected_by(&selectors, &host_groups).is_empty()
+            };
+            let mut missing = self
+                .services
+                .iter()
+                .filter(|(_, service)| on_host(service))
+                .flat_map(|(service_name, service)| {
+                    let overrides = host.config.get(service_name);
+                    service
+                        .as_object()
+                        .into_iter()
+                        .flatten()
+                        .filter(move |(key, _)| {
+                            !overrides.is_some_and(|config| config.get(key.as_str()).is_some())
+                        })
+                        .flat_map(|(_, value)| find_host_vars_in_value(value))
+                })
+                .chain(host.config.values().flat_map(find_host_vars_in_value))
+                .filter(|var| !host.vars.contains_key(var))
+                .map(|var| format!("{{{{ {} }}}}", var))
+                .collect::<Vec<_>>();
+            missing.sort();
+            missing.dedup();
+            if !missing.is_empty() {
+                res.push(format!(
+                    "host '{}' doesn't have the vars its services use: {}",
+                    name,
+                    missing.join(", ")
+                ));
+            }
+        }
+        res.sort();
+        res
+    }
+}
+
+/// Runs the `check-config` checks against the config file and the database, returning any problems found
+pub async fn check_config(
+    filename: &PathBuf,
+    db: &DatabaseConnection,
+) -> Result<Vec<String>, Error> {
+    let parser: ConfigurationParser =
+        serde_json::from_str(&tokio::fs::read_to_string(filename).await?)?;
+    let mut res = parser
+        .duplicate_names()
+        .into_iter()
+        .chain(parser.unknown_host_variables())
+        .chain(parser.plugin_problems().await)
+        .map(|problem| format!("Config file: {}", problem))
+        .collect::<Vec<_>>();
+    res.extend(
+        crate::db::find_duplicate_names(db)
+            .await?
+            .into_iter()
+            .map(|problem| format!("Database: {}", problem)),
+    );
+    Ok(res)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Why a service generates a check for a host
+pub enum TargetReason {
+    /// The service targets a group the host is in
+    HostGroup(String),
+    /// The service is listed in `local_services`
+    LocalService,
+}
+
+impl std::fmt::Display for TargetReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HostGroup(group) => write!(f, "host group '{}'", group),
+            Self::LocalService => write!(f, "local_services"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A service that will be checked on a host, and why, see [Configuration::explain_host]
+pub struct ExplainedService {
+    /// The service name
+    pub name: String,
+    /// What kind of service it is
+    pub service_type: ServiceType,
+    /// Everything that matched
+    pub reasons: Vec<TargetReason>,
+    /// The host has its own config for this service
+    pub host_config: bool,
+    /// The agent it's pinned to, if any
+    pub agent: Option<String>,
+}
+
+impl std::fmt::Display for ExplainedService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}) matched by {}",
+            self.name,
+            self.service_type,
+            self.reasons
+                .iter()
+                .map(|reason| reason.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        if self.host_config {
+            write!(f, ", with host-specific config")?;
+        }
+        if let Some(agent) = self.agent.as_ref() {
+            write!(f, ", run by agent '{}'", agent)?;
+        }
+        Ok(())
+    }
 }
 
 /// A sendable configuration, for use across threads
@@ -87,6 +483,10 @@ pub type SendableConfig = Arc<RwLock<Configuration>>;
 #[derive(Serialize, Deserialize, Debug, Default, JsonSchema)]
 /// The result of parsing the configuration file, don't instantiate this directly!
 pub struct Configuration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The config file format, `maremma migrate-config` upgrades files from older versions
+    pub config_version: Option<u32>,
+
     #[serde(default = "default_database_file")]
     /// Path to the database file (or `:memory:` for in-memory)
     pub database_file: String,
@@ -104,6 +504,10 @@ pub struct Configuration {
     /// Host configuration
     pub hosts: HashMap<String, Host>,
 
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Host groups which include other groups, a service on a group runs on the hosts of the groups it includes too
+    pub host_groups: HashMap<String, HostGroupConfig>,
+
     #[serde(default)]
     /// Services to run locally
     pub local_services: FakeHost,
@@ -112,15 +516,22 @@ pub struct Configuration {
     #[serde(default)]
     pub services: HashMap<String, Service>,
 
+    #[serde(default)]
+    /// Common service fields, services use them with `"template": "name"` and override what they need to, templates can use other templates
+    pub service_templates: HashMap<String, Value>,
+
     /// The frontend URL ie `https://maremma.example.com` used for things like OIDC
     pub frontend_url: String,
 
-    /// OIDC issuer (url)
+    /// OIDC issuer (url), empty when `auth.mode` is `local`
     pub oidc_issuer: String,
-    /// OIDC client_id
+    /// OIDC client_id, empty when `auth.mode` is `local`
     pub oidc_client_id: String,
     /// OIDC client_secret
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "crate::serde::secret::serialize"
+    )]
     pub oidc_client_secret: Option<String>,
 
     /// the TLS certificate matter
@@ -132,23 +543,331 @@ pub struct Configuration {
     /// The maximum concurrent checks we'll run at one time
     pub max_concurrent_checks: usize,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The maximum concurrent checks against any one host, unlimited if not set
+    pub max_concurrent_checks_per_host: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How many of `max_concurrent_checks` slow checks can use, so they can't hold up the fast ones, defaults to a quarter
+    pub max_concurrent_slow_checks: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Checks that take longer than this many milliseconds on average are scheduled as slow ones, defaults to 5000 ([crate::constants::DEFAULT_SLOW_CHECK_MS])
+    pub slow_check_ms: Option<u64>,
+
     /// How many history entries to keep per check, defaults to 25000 ([crate::constants::DEFAULT_HISTORY_LIMIT]), setting this too high can cause slowdowns.
     pub(crate) max_history_entries_per_check: u64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Delete history entries older than this many days, history is only trimmed by count if not set
+    pub max_history_age_days: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How often pages refresh themselves in seconds, 0 turns it off, each page has its own default if not set
+    pub page_refresh_seconds: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The timezone times are shown in, eg `Australia/Brisbane`, the browser's timezone is used if not set
+    pub display_timezone: Option<String>,
+
+    /// How long to wait for running checks when shutting down
+    pub shutdown_timeout_seconds: u64,
+
+    #[serde(default)]
+    /// Remote agents which are allowed to run checks, keyed by agent name
+    pub agents: HashMap<String, AgentConfig>,
+
+    #[serde(default)]
+    /// Accept Alertmanager webhooks and turn them into passive service checks
+    pub alertmanager: Option<AlertmanagerConfig>,
+
+    #[serde(default)]
+    /// Accept results for passive service checks on `/api/v1/submit`
+    pub submit: Option<SubmitConfig>,
+
+    #[serde(default)]
+    /// Where `{"$secret": {"vault": "<path>#<field>"}}` secret references are fetched from
+    pub vault: Option<VaultConfig>,
+
+    #[serde(default)]
+    /// Sharing SSH connections between checks on the same host
+    pub ssh_pool: SshPoolConfig,
+
+    #[serde(default)]
+    /// Holding back repeats of the same check failure in the logs
+    pub repeated_failures: RepeatedFailuresConfig,
+
+    #[serde(default)]
+    /// Executables that implement the `plugin` service type
+    pub plugins: PluginConfig,
+
+    #[serde(default)]
+    /// SQLite tuning, eg the journal mode and how long to wait on a locked database
+    pub sqlite: SqliteConfig,
+
+    #[serde(default)]
+    /// Where notifications are sent, with overrides for host groups
+    pub notifications: NotificationConfig,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Scheduled summary reports sent to notification targets, keyed by name
+    pub reports: HashMap<String, ReportConfig>,
+    #[serde(default)]
+    /// Sources hosts are discovered from and kept in sync with, eg Kubernetes clusters and DNS zones
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    /// How each host group's status is worked out from its hosts
+    pub group_status: GroupStatusConfig,
+    #[serde(default)]
+    /// The public status page, which is off unless it's enabled
+    pub status_page: StatusPageConfig,
+    #[serde(default)]
+    /// The UI's default theme, and branding
+    pub theme: ThemeConfig,
+    #[serde(default)]
+    /// How users log in, OIDC by default
+    pub auth: AuthConfig,
+    #[serde(default)]
+    /// How long web sessions last, and how the session cookie's set
+    pub session: SessionConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Ask clients for a certificate, and let the names in them log in to the API
+    pub mtls: Option<MtlsConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Signed links to a read-only dashboard for wallboards, made with `maremma kiosk-url`
+    pub kiosk: Option<KioskConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Get the web server's certificate from Let's Encrypt or another ACME CA, instead of `cert_file` and `cert_key`
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    /// `https` by default, or `http` when a reverse proxy handles TLS
+    pub listen_scheme: ListenScheme,
+    #[serde(default)]
+    /// IPs or CIDRs of reverse proxies whose X-Forwarded-For and X-Forwarded-Proto headers are believed
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    /// Add the built-in services which watch Maremma's database, scheduler and memory use
+    pub self_monitoring: bool,
+    #[serde(default)]
+    /// Stagger checks which are due at startup or reload across their schedules, instead of running them all at once
+    pub spread_initial_checks: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// URLs that are sent a JSON event when hosts, services or host groups are added, removed or changed
+    pub config_webhooks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Back the database up to a directory or an S3-compatible bucket on a schedule
+    pub backup: Option<BackupConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Keep check output that's too big for the database in a directory or an S3-compatible bucket
+    pub artifacts: Option<ArtifactConfig>,
+}
+
+/// Merges `overrides` over `base`, objects are merged key by key and anything else is replaced
+fn merge_values(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+/// Fills in a service's config from its template, and the template's template and so on, the service's own fields win
+fn apply_service_template(
+    name: &str,
+    service: &Value,
+    templates: &HashMap<String, Value>,
+) -> Result<Value, Error> {
+    let mut chain: Vec<(&str, &Value)> = Vec::new();
+    let mut next = service.get("template");
+    while let Some(template_name) = next {
+        let template_name = template_name.as_str().ok_or_else(|| {
+            Error::Configuration(format!("template for service {} should be a name", name))
+        })?;
+        if chain.iter().any(|(seen, _)| *seen == template_name) {
+            return Err(Error::Configuration(format!(
+                "service template {} for service {} refers back to itself",
+                template_name, name
+            )));
+        }
+        let template = templates
+            .get(template_name)
+            .filter(|template| template.is_object())
+            .ok_or_else(|| {
+                Error::Configuration(format!(
+                    "service {} uses template {} which isn't in service_templates",
+                    name, template_name
+                ))
+            })?;
+        chain.push((template_name, template));
+        next = template.get("template");
+    }
+
+    let mut res = json!({});
+    for (_, template) in chain.iter().rev() {
+        merge_values(&mut res, template);
+    }
+    merge_values(&mut res, service);
+    Ok(res)
 }
 
 impl TryFrom<ConfigurationParser> for Configuration {
     fn try_from(value: ConfigurationParser) -> Result<Self, Error> {
+        match value.config_version.unwrap_or(1) {
+            version if version > CURRENT_CONFIG_VERSION => {
+                return Err(Error::Configuration(format!(
+                    "config_version {} is newer than this version of Maremma understands ({})",
+                    version, CURRENT_CONFIG_VERSION
+                )))
+            }
+            version if version < CURRENT_CONFIG_VERSION => warn!(
+                "The config file is version {}, run `maremma migrate-config` to upgrade it to {}",
+                version, CURRENT_CONFIG_VERSION
+            ),
+            _ => {}
+        }
         let services = value
             .services
             .iter()
             .map(|(name, service)| {
-                let service: Service = serde_json::from_value(service.clone()).map_err(|e| {
+                let service = apply_service_template(name, service, &value.service_templates)?;
+                let service: Service = serde_json::from_value(service).map_err(|e| {
                     Error::Configuration(format!("Failed to parse service {}: {}", name, e))
                 })?;
                 Ok((name.clone(), service))
             })
             .collect::<Result<HashMap<String, Service>, Error>>()?;
 
+        // anything that's been checking longer than STUCK_CHECK_MINUTES gets reset by the shepherd
+        if let Some((name, max_runtime)) = services.iter().find_map(|(name, service)| {
+            service
+                .max_runtime
+                .filter(|max_runtime| {
+                    *max_runtime == 0 || *max_runtime >= STUCK_CHECK_MINUTES as u64 * 60
+                })
+                .map(|max_runtime| (name, max_runtime))
+        }) {
+            return Err(Error::Configuration(format!(
+                "max_runtime for service {} is {}s, it needs to be between 1 and {}",
+                name,
+                max_runtime,
+                STUCK_CHECK_MINUTES * 60 - 1
+            )));
+        }
+
+        if let Some((name, timezone)) = services.iter().find_map(|(name, service)| {
+            service
+                .timezone
+                .as_ref()
+                .filter(|timezone| parse_timezone(timezone).is_err())
+                .map(|timezone| (name, timezone))
+        }) {
+            return Err(Error::Configuration(format!(
+                "timezone '{}' for service {} isn't a valid timezone",
+                timezone, name
+            )));
+        }
+        for (name, host) in value.hosts.iter() {
+            for (service_name, config) in host.config.iter() {
+                if let Some(timezone) = config.get("timezone").and_then(|tz| tz.as_str()) {
+                    if parse_timezone(timezone).is_err() {
+                        return Err(Error::Configuration(format!(
+                            "timezone '{}' in host {}'s config for service {} isn't a valid timezone",
+                            timezone, name, service_name
+                        )));
+                    }
+                }
+            }
+        }
+
+        for key in value
+            .hosts
+            .values()
+            .flat_map(|host| host.labels.keys())
+            .chain(services.values().flat_map(|service| service.labels.keys()))
+        {
+            crate::labels::validate_key(key)?;
+        }
+
+        if value.max_history_age_days == Some(0) {
+            return Err(Error::Configuration(
+                "max_history_age_days must be at least 1".to_string(),
+            ));
+        }
+
+        if let Some(timezone) = &value.display_timezone {
+            check_timezone(timezone)?;
+        }
+
+        if let Some(name) = value.discovery.sources.iter().find_map(|(name, source)| {
+            matches!(
+                source.kind,
+                DiscoverySourceKind::Axfr {
+                    timeout_seconds: 0,
+                    ..
+                }
+            )
+            .then_some(name)
+        }) {
+            return Err(Error::Configuration(format!(
+                "timeout_seconds for discovery source {} must be at least 1",
+                name
+            )));
+        }
+
+        check_nesting(&value.host_groups)?;
+        for (name, service) in services.iter() {
+            check_selectors(name, &service.host_groups)?;
+        }
+        value.group_status.validate()?;
+        value.status_page.validate()?;
+        value.theme.validate()?;
+        if let Some(mtls) = &value.mtls {
+            mtls.validate()?;
+        }
+        if let Some(kiosk) = &value.kiosk {
+            kiosk.validate()?;
+        }
+        parse_trusted_proxies(&value.trusted_proxies)?;
+        check_webhooks(&value.config_webhooks)?;
+        if value.listen_scheme == ListenScheme::Http
+            && (value.acme.is_some() || value.mtls.is_some())
+        {
+            return Err(Error::Configuration(
+                "acme and mtls need listen_scheme to be https".to_string(),
+            ));
+        }
+
+        if value.max_concurrent_checks_per_host == Some(0) {
+            return Err(Error::Configuration(
+                "max_concurrent_checks_per_host must be at least 1".to_string(),
+            ));
+        }
+        if let Some(slow_checks) = value.max_concurrent_slow_checks {
+            if slow_checks == 0 || slow_checks >= value.max_concurrent_checks {
+                return Err(Error::Configuration(
+                    "max_concurrent_slow_checks must be at least 1 and less than max_concurrent_checks"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(name) = value
+            .hosts
+            .iter()
+            .find_map(|(name, host)| (host.max_concurrent_checks == Some(0)).then_some(name))
+        {
+            return Err(Error::Configuration(format!(
+                "max_concurrent_checks for host {} must be at least 1",
+                name
+            )));
+        }
+
         let static_path = value
             .static_path
             .unwrap_or(PathBuf::from(WEB_SERVER_DEFAULT_STATIC_PATH));
@@ -174,10 +893,13 @@ impl TryFrom<ConfigurationParser> for Configuration {
                 Err(_) => return Err(Error::Configuration("Frontend URL not set".to_string())),
             },
         };
+        // local users don't need an OIDC provider
+        let oidc_required = value.auth.mode == AuthMode::Oidc;
         let oidc_issuer = match value.oidc_issuer {
             Some(val) => val,
             None => match std::env::var("MAREMMA_OIDC_ISSUER") {
                 Ok(val) => val,
+                Err(_) if !oidc_required => String::new(),
                 Err(_) => return Err(Error::Configuration("OIDC Issuer URL not set".to_string())),
             },
         };
@@ -186,17 +908,42 @@ impl TryFrom<ConfigurationParser> for Configuration {
             Some(val) => val,
             None => match std::env::var("MAREMMA_OIDC_CLIENT_ID") {
                 Ok(val) => val,
+                Err(_) if !oidc_required => String::new(),
                 Err(_) => return Err(Error::Configuration("OIDC Client ID not set".to_string())),
             },
         };
 
-        Ok(Configuration {
+        if let Some(acme) = &value.acme {
+            acme.validate(&frontend_url)?;
+            if value
+                .mtls
+                .as_ref()
+                .is_some_and(|mtls| mtls.verification == ClientVerification::Required)
+            {
+                return Err(Error::Configuration(
+                    "acme needs mtls.verification to be optional, the CA can't present a client certificate".to_string(),
+                ));
+            }
+        }
+        value.session.validate(&frontend_url)?;
+        value.plugins.validate()?;
+        if let Some(backup) = &value.backup {
+            backup.validate()?;
+        }
+        if let Some(artifacts) = &value.artifacts {
+            artifacts.validate()?;
+        }
+
+        let res = Configuration {
+            config_version: value.config_version,
             database_file: value.database_file,
             listen_address: value.listen_address,
             listen_port,
             hosts: value.hosts,
+            host_groups: value.host_groups,
             local_services: value.local_services,
             services,
+            service_templates: value.service_templates,
             frontend_url,
             oidc_issuer,
             oidc_client_id,
@@ -205,16 +952,95 @@ impl TryFrom<ConfigurationParser> for Configuration {
             cert_file: value.cert_file,
             cert_key: value.cert_key,
             max_concurrent_checks: value.max_concurrent_checks,
+            max_concurrent_checks_per_host: value.max_concurrent_checks_per_host,
+            max_concurrent_slow_checks: value.max_concurrent_slow_checks,
+            slow_check_ms: value.slow_check_ms,
             static_path: Some(static_path),
             max_history_entries_per_check: value
                 .max_history_entries_per_check
                 .unwrap_or(DEFAULT_SERVICE_CHECK_HISTORY_STORAGE),
-        })
+            max_history_age_days: value.max_history_age_days,
+            page_refresh_seconds: value.page_refresh_seconds,
+            display_timezone: value.display_timezone,
+            shutdown_timeout_seconds: value
+                .shutdown_timeout_seconds
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECONDS),
+            agents: value.agents,
+            alertmanager: value.alertmanager,
+            submit: value.submit,
+            vault: value.vault,
+            ssh_pool: value.ssh_pool,
+            repeated_failures: value.repeated_failures,
+            plugins: value.plugins,
+            sqlite: value.sqlite,
+            notifications: value.notifications,
+            reports: value.reports,
+            discovery: value.discovery,
+            group_status: value.group_status,
+            status_page: value.status_page,
+            theme: value.theme,
+            auth: value.auth,
+            session: value.session,
+            mtls: value.mtls,
+            kiosk: value.kiosk,
+            acme: value.acme,
+            listen_scheme: value.listen_scheme,
+            trusted_proxies: value.trusted_proxies,
+            self_monitoring: value.self_monitoring,
+            spread_initial_checks: value.spread_initial_checks,
+            config_webhooks: value.config_webhooks,
+            backup: value.backup,
+            artifacts: value.artifacts,
+        };
+        check_targets(&res)?;
+        check_alert_windows(&res)?;
+        check_escalations(&res)?;
+        check_reports(&res)?;
+        Ok(res)
     }
 
     type Error = Error;
 }
 
+#[cfg(test)]
+/// Held by tests which reload the config, so they don't swap the globals out from under each other
+pub(crate) static APPLY_CONFIG_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Hands the settings that live in globals (the SSH pool, repeated failure filter, plugins, notification dispatcher and
+/// artifact store) to them. Called on startup and whenever the config's reloaded, so changes take effect straight away.
+pub async fn apply_config(config: &Configuration) -> Result<(), Error> {
+    crate::ssh_client::POOL.configure(config.ssh_pool);
+    crate::log::CHECK_FAILURES.configure(config.repeated_failures);
+    crate::services::plugin::PLUGINS
+        .configure(&config.plugins)
+        .inspect_err(|err| error!("Failed to find plugins: {:?}", err))?;
+    crate::actions::dispatcher::DISPATCHER
+        .lock()
+        .await
+        .configure(config);
+    crate::artifacts::ARTIFACTS
+        .configure(config.artifacts.as_ref())
+        .inspect_err(|err| error!("Failed to set up the artifact store: {:?}", err))?;
+    Ok(())
+}
+
+/// Writes a config file, once it's been checked that it loads. It's written next to the old one and renamed into
+/// place, so a crash part way through doesn't leave a truncated config behind.
+pub async fn write_validated_config(config_file: &Path, contents: &str) -> Result<(), Error> {
+    Configuration::new_from_string(contents).await?;
+    let file_name = config_file
+        .file_name()
+        .ok_or_else(|| Error::Configuration(format!("{} isn't a file", config_file.display())))?
+        .to_string_lossy();
+    let temp_file = config_file.with_file_name(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+    tokio::fs::write(&temp_file, contents).await?;
+    if let Err(err) = tokio::fs::rename(&temp_file, config_file).await {
+        let _ = tokio::fs::remove_file(&temp_file).await;
+        return Err(err.into());
+    }
+    Ok(())
+}
+
 impl Configuration {
     /// New Configuration object from a file reference
     pub async fn new(filename: &PathBuf) -> Result<Self, Error> {
@@ -229,8 +1055,11 @@ impl Configuration {
 
     /// If you've got the file contents, use that to build a configuration
     pub async fn new_from_string(config: &str) -> Result<Self, Error> {
-        let mut res: ConfigurationParser = serde_json::from_str(config)?;
-
+        let mut config: Value = serde_json::from_str(config)?;
+        let fetched = crate::secrets::fetch_references(&config).await?;
+        crate::serde::secret::resolve_references(&mut config, &fetched)?;
+        let mut res: ConfigurationParser = serde_json::from_value(config)?;
+        res.add_self_monitoring();
         if !res.local_services.services.is_empty() {
             res.hosts.insert(
                 LOCAL_SERVICE_HOST_NAME.to_string(),
@@ -250,7 +1079,7 @@ impl Configuration {
                 .expect("Failed to read example config"),
         )
         .expect("Failed to parse example config");
-
+        res.add_self_monitoring();
         if !res.local_services.services.is_empty() {
             res.hosts.insert(
                 LOCAL_SERVICE_HOST_NAME.to_string(),
@@ -266,6 +1095,11 @@ impl Configuration {
         Arc::new(RwLock::new(Self::load_test_config_bare().await))
     }
 
+    /// Pretty-printed JSON of the configuration, with secrets masked
+    pub fn as_json_pretty(&self) -> Result<String, Error> {
+        crate::serde::secret::to_string_pretty(self)
+    }
+
     /// returns the listen address and port as a string ie `127.0.0.1:8888`
     pub fn listen_addr(&self) -> String {
         format!(
@@ -286,20 +1120,100 @@ impl Configuration {
         });
 
         self.services.iter().for_each(|(_service_name, service)| {
-            groups.extend(service.host_groups.iter().cloned());
+            groups.extend(
+                service
+                    .host_groups
+                    .iter()
+                    .map(|group| parse_selector(group).0.to_string()),
+            );
+        });
+
+        self.host_groups.iter().for_each(|(name, group)| {
+            groups.insert(name.clone());
+            groups.extend(group.members.iter().cloned());
         });
 
         groups.into_iter().collect()
     }
 
+    /// Works out which services will generate checks for a host, and why, without touching the database
+    pub fn explain_host(&self, name: &str) -> Result<(&Host, Vec<ExplainedService>), Error> {
+        let key = name_key(name);
+        let (host_name, host) = self
+            .hosts
+            .iter()
+            .find(|(host_name, _)| name_key(host_name) == key)
+            .or_else(|| {
+                self.hosts
+                    .iter()
+                    .find(|(_, host)| host.hostname.as_deref().map(name_key) == Some(key.clone()))
+            })
+            .ok_or_else(|| {
+                Error::Configuration(format!("Host '{}' isn't in the configuration", name))
+            })?;
+
+        // the groups which include the host's groups count too
+        let names = host
+            .host_groups
+            .iter()
+            .chain(self.host_groups.keys())
+            .map(|group| (name_key(group), group))
+            .collect::<HashMap<_, _>>();
+        let host_groups = expand_host_groups(&self.host_groups, &host.host_groups);
+        let local_services = match host_name.as_str() {
+            crate::LOCAL_SERVICE_HOST_NAME => self
+                .local_services
+                .services
+                .iter()
+                .map(|service| name_key(service.as_str()))
+                .collect::<HashSet<_>>(),
+            _ => HashSet::new(),
+        };
+
+        let mut res = self
+            .services
+            .iter()
+            .filter_map(|(service_name, service)| {
+                // nothing comes from the groups if the host's in one the service excludes
+                let mut reasons = selected_by(&service.host_groups, &host_groups)
+                    .into_iter()
+                    .map(|group| {
+                        TargetReason::HostGroup(
+                            names
+                                .get(&name_key(group))
+                                .map(|name| name.to_string())
+                                .unwrap_or_else(|| group.to_string()),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                if local_services.contains(&name_key(service_name)) {
+                    reasons.push(TargetReason::LocalService);
+                }
+                if reasons.is_empty() {
+                    return None;
+                }
+                Some(ExplainedService {
+                    name: service_name.clone(),
+                    service_type: service.service_type.clone(),
+                    reasons,
+                    host_config: host
+                        .config
+                        .keys()
+                        .any(|config_name| name_key(config_name) == name_key(service_name)),
+                    agent: service.agent.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok((host, res))
+    }
+
     /// Prune the configuration based on the database, so we can serialize it back
-    pub async fn prune(&mut self, db: Arc<RwLock<DatabaseConnection>>) -> Result<(), Error> {
+    pub async fn prune(&mut self, db: DatabaseConnection) -> Result<(), Error> {
         // TODO: prune config
 
-        let db_writer = db.write().await;
-
         // check the hosts against the config file
-        let db_hosts = entities::host::Entity::find().all(&*db_writer).await?;
+        let db_hosts = entities::host::Entity::find().all(&db).await?;
         let config_hosts = self.hosts.keys().cloned().collect::<HashSet<String>>();
 
         // keep a record of the ones we find in the db
@@ -314,9 +1228,7 @@ impl Configuration {
         }
 
         // check the groups against the config file
-        let db_host_groups = entities::host_group::Entity::find()
-            .all(&*db_writer)
-            .await?;
+        let db_host_groups = entities::host_group::Entity::find().all(&db).await?;
         let config_groups = self.groups();
         for host_group in db_host_groups {
             debug!("HostGroup: {:?}", host_group);
@@ -326,7 +1238,7 @@ impl Configuration {
         }
 
         // check the services against the config file
-        let db_services = entities::service::Entity::find().all(&*db_writer).await?;
+        let db_services = entities::service::Entity::find().all(&db).await?;
         let config_services = self.services.keys().cloned().collect::<HashSet<String>>();
         for service in db_services {
             debug!("Service: {:?}", service);
@@ -346,8 +1258,9 @@ mod tests {
     use crate::db::tests::test_setup;
 
     use schemars::schema_for;
+    use serde_json::{json, Value};
 
-    use super::ConfigurationParser;
+    use super::{write_validated_config, AuthMode, ConfigurationParser};
     #[tokio::test]
     async fn test_config_new() {
         assert!(Configuration::new(
@@ -376,6 +1289,27 @@ mod tests {
         assert_eq!(config.listen_addr(), "127.0.0.1:8888");
     }
 
+    #[tokio::test]
+    async fn test_config_secret_references() {
+        std::env::set_var("MAREMMA_TEST_OIDC_SECRET", "supersecret");
+        let config = json!({
+            "hosts": {},
+            "frontend_url": "https://example.com",
+            "oidc_issuer" : "https://example.com",
+            "oidc_client_id" : "foo",
+            "oidc_client_secret" : {"$secret": {"env": "MAREMMA_TEST_OIDC_SECRET"}},
+        })
+        .to_string();
+        let config = Configuration::new_from_string(&config)
+            .await
+            .expect("Failed to load config");
+        assert_eq!(config.oidc_client_secret.as_deref(), Some("supersecret"));
+
+        let shown = config.as_json_pretty().expect("Failed to show config");
+        assert!(!shown.contains("supersecret"));
+        assert!(shown.contains("***********"));
+    }
+
     #[tokio::test]
     async fn test_config_groups() {
         let (_db, config) = test_setup().await.expect("Failed to setup test");
@@ -385,11 +1319,389 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_explain_host() {
+        use super::TargetReason;
+
+        let config = Configuration::load_test_config_bare().await;
+
+        let (host, services) = config
+            .explain_host(" Example.com")
+            .expect("Failed to explain host");
+        assert_eq!(host.host_groups.len(), 2);
+        let names = services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["check_ntp_time", "check_tls", "ping_check"]);
+        assert_eq!(
+            services.first().map(|service| service.reasons.clone()),
+            Some(vec![TargetReason::HostGroup("check_ntp_time".to_string())])
+        );
+        assert_eq!(
+            services.first().map(|service| service.to_string()),
+            Some("check_ntp_time (SSH) matched by host group 'check_ntp_time'".to_string())
+        );
+
+        let (_, services) = config
+            .explain_host(crate::LOCAL_SERVICE_HOST_NAME)
+            .expect("Failed to explain local host");
+        assert_eq!(services.len(), 1);
+        assert_eq!(
+            services.first().map(|service| service.reasons.clone()),
+            Some(vec![TargetReason::LocalService])
+        );
+
+        assert!(config.explain_host("nope.example.com").is_err());
+    }
+
     #[test]
     fn test_default_max_concurrent_checks() {
         assert!(default_max_concurrent_checks() >= 1);
     }
 
+    #[tokio::test]
+    async fn test_zero_host_concurrency() {
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        config["max_concurrent_checks_per_host"] = json!(0);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+
+        config["max_concurrent_checks_per_host"] = json!(2);
+        config["hosts"]["example.com"]["max_concurrent_checks"] = json!(1);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_ok());
+        config["hosts"]["example.com"]["max_concurrent_checks"] = json!(0);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+        config["hosts"]["example.com"]["max_concurrent_checks"] = json!(1);
+
+        config["max_concurrent_checks"] = json!(4);
+        config["max_concurrent_slow_checks"] = json!(1);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_ok());
+        config["max_concurrent_slow_checks"] = json!(4);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+        config["max_concurrent_slow_checks"] = json!(0);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_labels() {
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        // `env` is the most common label, it mustn't be read as a secret reference
+        config["hosts"]["example.com"]["labels"] = json!({"env": "prod"});
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config with labels");
+        assert_eq!(
+            parsed.hosts["example.com"].labels.get("env"),
+            Some(&"prod".to_string())
+        );
+
+        config["hosts"]["example.com"]["labels"] = json!({"bad key": "prod"});
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_excluded_host_groups() {
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        config["services"]["ping_check"]["host_groups"] = json!(["check_ntp_time", "!check_tls"]);
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config with an excluded group");
+        let (_, services) = parsed
+            .explain_host("example.com")
+            .expect("Failed to explain host");
+        assert!(!services.iter().any(|service| service.name == "ping_check"));
+        assert!(parsed.groups().contains(&"check_tls".to_string()));
+
+        config["services"]["ping_check"]["host_groups"] = json!(["!check_tls"]);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_history_age_days() {
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        config["max_history_age_days"] = json!(0);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+
+        config["max_history_age_days"] = json!(30);
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        assert_eq!(parsed.max_history_age_days, Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_display_timezone() {
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        config["display_timezone"] = json!("<script>");
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+
+        config["display_timezone"] = json!("Australia/Brisbane");
+        config["page_refresh_seconds"] = json!(0);
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        assert_eq!(
+            parsed.display_timezone.as_deref(),
+            Some("Australia/Brisbane")
+        );
+        assert_eq!(parsed.page_refresh_seconds, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_service_timezone() {
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        config["services"]["ping_check"]["timezone"] = json!("Europe/Berlin");
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        assert_eq!(
+            parsed.services["ping_check"].timezone.as_deref(),
+            Some("Europe/Berlin")
+        );
+
+        config["services"]["ping_check"]["timezone"] = json!("Europe/Nowhere");
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+
+        config["services"]["ping_check"]["timezone"] = json!("UTC");
+        config["hosts"]["timezone_test"] = json!({
+            "hostname": "timezone.example.com",
+            "config": { "ping_check": { "timezone": "Europe/Nowhere" } }
+        });
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_service_templates() {
+        let mut config = json!({
+            "hosts": {},
+            "frontend_url": "https://example.com",
+            "auth": {"mode": "local"},
+            "service_templates": {
+                "web": {
+                    "service_type": "ping",
+                    "host_groups": ["web"],
+                    "cron_schedule": "*/5 * * * *",
+                    "max_runtime": 30,
+                },
+                "slow_web": {
+                    "template": "web",
+                    "cron_schedule": "@hourly",
+                },
+            },
+            "services": {
+                "homepage": {"template": "web", "max_runtime": 60},
+                "reports": {"template": "slow_web", "host_groups": ["reports"]},
+            },
+        });
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        let homepage = &parsed.services["homepage"];
+        assert_eq!(homepage.template.as_deref(), Some("web"));
+        assert_eq!(homepage.service_type, ServiceType::Ping);
+        assert_eq!(homepage.cron_schedule.pattern.to_string(), "*/5 * * * *");
+        assert_eq!(homepage.max_runtime, Some(60));
+        assert!(!homepage.extra_config.contains_key("template"));
+
+        let reports = &parsed.services["reports"];
+        assert_eq!(reports.template.as_deref(), Some("slow_web"));
+        assert_eq!(reports.cron_schedule.pattern.to_string(), "@hourly");
+        assert_eq!(reports.host_groups, vec!["reports".to_string()]);
+        assert_eq!(reports.max_runtime, Some(30));
+
+        let mut merged = json!({"a": {"b": 1, "c": [1, 2]}, "d": 1});
+        merge_values(&mut merged, &json!({"a": {"c": [3]}, "e": 2}));
+        assert_eq!(merged, json!({"a": {"b": 1, "c": [3]}, "d": 1, "e": 2}));
+
+        config["services"]["homepage"]["template"] = json!("nope");
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+
+        config["services"]["homepage"]["template"] = json!("web");
+        config["service_templates"]["web"]["template"] = json!("slow_web");
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_auth_without_oidc() {
+        let config = json!({
+            "hosts": {},
+            "frontend_url": "https://example.com",
+            "auth": {"mode": "local"},
+        })
+        .to_string();
+        let config = Configuration::new_from_string(&config)
+            .await
+            .expect("Local auth shouldn't need OIDC settings");
+        assert_eq!(config.auth.mode, AuthMode::Local);
+    }
+
+    #[tokio::test]
+    async fn test_session_settings() {
+        let mut config = json!({
+            "hosts": {},
+            "frontend_url": "http://localhost:8888",
+            "auth": {"mode": "local"},
+            "session": {"inactivity_seconds": 28800, "cookie_name": "maremma"},
+        });
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse session settings");
+        assert_eq!(parsed.session.inactivity_seconds, 28800);
+        assert_eq!(parsed.session.cookie_name, "maremma");
+
+        // browsers drop SameSite=None cookies that aren't secure
+        config["session"]["same_site"] = json!("none");
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acme_with_required_mtls() {
+        let mut config = json!({
+            "hosts": {},
+            "frontend_url": "https://maremma.example.com",
+            "oidc_issuer": "https://example.com",
+            "oidc_client_id": "maremma",
+            "acme": {"contact": ["admin@example.com"]},
+            "mtls": {"client_ca": "/etc/maremma/clients.pem"},
+        });
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("ACME with optional mTLS should be fine");
+        assert!(parsed.acme.is_some());
+
+        config["mtls"]["verification"] = json!("required");
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_behind_proxy() {
+        let mut config = json!({
+            "hosts": {},
+            "frontend_url": "https://maremma.example.com",
+            "oidc_issuer": "https://example.com",
+            "oidc_client_id": "maremma",
+            "listen_scheme": "http",
+            "trusted_proxies": ["10.0.0.0/8", "127.0.0.1"],
+        });
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        assert_eq!(parsed.listen_scheme, ListenScheme::Http);
+
+        config["trusted_proxies"] = json!(["not an address"]);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+
+        config["trusted_proxies"] = json!([]);
+        config["acme"] = json!({});
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_self_monitoring() {
+        let mut config = json!({
+            "hosts": {},
+            "frontend_url": "https://maremma.example.com",
+            "oidc_issuer": "https://example.com",
+            "oidc_client_id": "maremma",
+        });
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        assert!(parsed.services.is_empty());
+
+        config["self_monitoring"] = json!(true);
+        config["services"] = json!({
+            "Maremma memory": {
+                "service_type": "self",
+                "host_groups": [],
+                "cron_schedule": "* * * * *",
+                "metric": "memory",
+                "warning": 4096,
+            }
+        });
+        let parsed = Configuration::new_from_string(&config.to_string())
+            .await
+            .expect("Failed to parse config");
+        assert_eq!(parsed.services.len(), builtin_services().len());
+        assert_eq!(
+            parsed.local_services.services.len(),
+            builtin_services().len() - 1
+        );
+        assert!(parsed.hosts.contains_key(LOCAL_SERVICE_HOST_NAME));
+        assert_eq!(
+            parsed.services["Maremma memory"]
+                .extra_config
+                .get("warning"),
+            Some(&json!(4096))
+        );
+    }
+
     #[test]
     fn test_json_schema() {
         let schema = schema_for!(Configuration);
@@ -406,6 +1718,83 @@ mod tests {
         assert!(Configuration::try_from(cfg).is_err());
     }
 
+    #[tokio::test]
+    async fn test_check_config() {
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+
+        let problems = super::check_config(&"maremma.example.json".into(), &db)
+            .await
+            .expect("Failed to check config");
+        assert!(problems.is_empty());
+
+        let parser: ConfigurationParser = serde_json::from_value(serde_json::json! {{
+            "hosts": {
+                "foo.bar" : { "host_groups" : ["web"] },
+                "Foo.Bar " : { "host_groups" : ["Web"] },
+            },
+            "services": {
+                "ping": { "service_type": "ping", "host_groups": ["web "], "cron_schedule": "@hourly" }
+            }
+        }})
+        .expect("Failed to parse config");
+        let problems = parser.duplicate_names();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("Duplicate host names"));
+        assert!(problems[1].starts_with("Duplicate host group names"));
+        assert!(parser.unknown_host_variables().is_empty());
+
+        let parser: ConfigurationParser = serde_json::from_value(serde_json::json! {{
+            "hosts": {
+                "foo.bar" : {
+                    "host_groups" : ["web"],
+                    "config": { "check_port": { "command_line": "/bin/nc -z #HOSTNAME# #PORT#" } }
+                },
+            },
+            "services": {
+                "check_port": {
+                    "service_type": "cli",
+                    "host_groups": ["web"],
+                    "cron_schedule": "@hourly",
+                    "command_line": "/bin/nc -z #HOSTNAME# #PORT# #PORT#"
+                }
+            }
+        }})
+        .expect("Failed to parse config");
+        assert_eq!(
+            parser.unknown_host_variables(),
+            vec![
+                "host 'foo.bar' config for service 'check_port' uses unknown host variables: #PORT#",
+                "service 'check_port' uses unknown host variables: #PORT#",
+            ]
+        );
+
+        let parser: ConfigurationParser = serde_json::from_value(serde_json::json! {{
+            "hosts": {
+                "foo.bar" : {
+                    "host_groups" : ["web"],
+                    "vars": { "app_port": 8443 },
+                },
+                "baz.bar" : {
+                    "host_groups" : ["web"],
+                    "config": { "homepage": { "url": "https://#HOSTNAME#/" } },
+                },
+            },
+            "services": {
+                "homepage": {
+                    "service_type": "http",
+                    "host_groups": ["web"],
+                    "cron_schedule": "@hourly",
+                    "url": "https://#HOSTNAME#:{{ app_port }}/{{ path }}",
+                }
+            }
+        }})
+        .expect("Failed to parse config");
+        assert_eq!(
+            parser.unknown_host_variables(),
+            vec!["host 'foo.bar' doesn't have the vars its services use: {{ path }}"]
+        );
+    }
+
     #[tokio::test]
     async fn test_config_prune() {
         let (db, config) = test_setup().await.expect("Failed to setup test");
@@ -417,4 +1806,38 @@ mod tests {
             .await
             .expect("Failed to prune config");
     }
+
+    #[tokio::test]
+    async fn test_write_validated_config() {
+        let directory = tempfile::tempdir().expect("Failed to create temp dir");
+        let config_file = directory.path().join("maremma.json");
+        let contents = serde_json::to_string_pretty(&json!({
+            "hosts": {},
+            "frontend_url": "https://maremma.example.com",
+            "oidc_issuer": "https://example.com",
+            "oidc_client_id": "maremma",
+        }))
+        .expect("Failed to serialize config");
+        write_validated_config(&config_file, &contents)
+            .await
+            .expect("Failed to write config");
+
+        // one that won't load isn't written, and the old one's left alone
+        assert!(write_validated_config(&config_file, "{\"hosts\": []}")
+            .await
+            .is_err());
+        assert_eq!(
+            tokio::fs::read_to_string(&config_file)
+                .await
+                .expect("Failed to read config"),
+            contents
+        );
+        // and there's nothing left over
+        assert_eq!(
+            std::fs::read_dir(directory.path())
+                .expect("Failed to read temp dir")
+                .count(),
+            1
+        );
+    }
 }
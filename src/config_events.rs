@@ -0,0 +1,394 @@
+//! Events for hosts, services and host groups being added, removed or changed, from a config reload or the web UI, so
+//! things like a CMDB can keep track of what's being monitored
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use reqwest::Url;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::prelude::*;
+
+/// How many events a slow subscriber can fall behind by before it starts missing them
+const EVENTS_SIZE: usize = 64;
+/// How long a webhook gets to answer
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+
+/// Every change, for the API's event stream and the webhooks, see [publish]
+pub static CONFIG_EVENTS: LazyLock<broadcast::Sender<ConfigEvent>> =
+    LazyLock::new(|| broadcast::channel(EVENTS_SIZE).0);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+/// What sort of thing changed
+pub enum ObjectKind {
+    /// A host
+    Host,
+    /// A host group
+    HostGroup,
+    /// A service
+    Service,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// What happened to it
+pub enum ChangeKind {
+    /// It's new
+    Added,
+    /// It's gone
+    Removed,
+    /// Some of its fields are different, see [ConfigChange::fields]
+    Changed,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// What made the change
+pub enum ChangeSource {
+    /// Loading the config, at startup, a reload or a discovery sync
+    Config,
+    /// Someone using the web UI or API
+    Web,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// One host, service or host group that's different
+pub struct ConfigChange {
+    /// What sort of thing it is
+    pub object: ObjectKind,
+    /// The name from the config
+    pub name: String,
+    /// What happened to it
+    pub change: ChangeKind,
+    /// The fields that changed, only set for [ChangeKind::Changed]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// What's sent to [CONFIG_EVENTS] and the webhooks
+pub struct ConfigEvent {
+    /// When the change was made
+    pub timestamp: DateTime<Utc>,
+    /// What made the change
+    pub source: ChangeSource,
+    /// eg `2 added, 1 changed`
+    pub summary: String,
+    /// Everything that's different
+    pub changes: Vec<ConfigChange>,
+}
+
+impl ConfigEvent {
+    /// Adds up the changes for the summary
+    pub fn new(source: ChangeSource, changes: Vec<ConfigChange>) -> Self {
+        let summary = [
+            (ChangeKind::Added, "added"),
+            (ChangeKind::Removed, "removed"),
+            (ChangeKind::Changed, "changed"),
+        ]
+        .into_iter()
+        .filter_map(|(kind, label)| {
+            match changes
+                .iter()
+                .filter(|change| change.change == kind)
+                .count()
+            {
+                0 => None,
+                count => Some(format!("{} {}", count, label)),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+        Self {
+            timestamp: Utc::now(),
+            source,
+            summary,
+            changes,
+        }
+    }
+}
+
+/// The hosts, services and host groups as they are in the database, to compare with after a change
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    objects: BTreeMap<(ObjectKind, String), Map<String, Value>>,
+}
+
+/// The model as a map without the fields that are generated, so they don't show up as changes
+fn fields(model: &impl Serialize) -> Result<Map<String, Value>, Error> {
+    let mut fields = match serde_json::to_value(model)? {
+        Value::Object(fields) => fields,
+        _ => return Err(Error::Generic("Model isn't an object".to_string())),
+    };
+    fields.remove("id");
+    fields.remove("slug");
+    Ok(fields)
+}
+
+impl Snapshot {
+    /// Reads everything from the database
+    pub async fn take(db: &DatabaseConnection) -> Result<Self, Error> {
+        let hosts = entities::host::Entity::find().all(db).await?;
+        let groups = entities::host_group::Entity::find().all(db).await?;
+        let host_names = hosts
+            .iter()
+            .map(|host| (host.id, host.name.clone()))
+            .collect::<HashMap<_, _>>();
+        let group_names = groups
+            .iter()
+            .map(|group| (group.id, group.name.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let mut group_hosts: HashMap<Uuid, BTreeSet<String>> = HashMap::new();
+        for member in entities::host_group_members::Entity::find().all(db).await? {
+            if let Some(name) = host_names.get(&member.host_id) {
+                group_hosts
+                    .entry(member.group_id)
+                    .or_default()
+                    .insert(name.clone());
+            }
+        }
+        let mut group_groups: HashMap<Uuid, BTreeSet<String>> = HashMap::new();
+        for nested in entities::host_group_nesting::Entity::find().all(db).await? {
+            if let Some(name) = group_names.get(&nested.child_id) {
+                group_groups
+                    .entry(nested.parent_id)
+                    .or_default()
+                    .insert(name.clone());
+            }
+        }
+        let mut service_groups: HashMap<Uuid, BTreeSet<String>> = HashMap::new();
+        for link in entities::service_group_link::Entity::find().all(db).await? {
+            if let Some(name) = group_names.get(&link.group_id) {
+                service_groups
+                    .entry(link.service_id)
+                    .or_default()
+                    .insert(if link.exclude {
+                        format!("!{}", name)
+                    } else {
+                        name.clone()
+                    });
+            }
+        }
+
+        let mut res = Self::default();
+        for host in hosts {
+            res.objects
+                .insert((ObjectKind::Host, host.name.clone()), fields(&host)?);
+        }
+        for group in groups {
+            let mut group_fields = fields(&group)?;
+            group_fields.insert(
+                "hosts".to_string(),
+                json!(group_hosts.remove(&group.id).unwrap_or_default()),
+            );
+            group_fields.insert(
+                "members".to_string(),
+                json!(group_groups.remove(&group.id).unwrap_or_default()),
+            );
+            res.objects
+                .insert((ObjectKind::HostGroup, group.name), group_fields);
+        }
+        for service in entities::service::Entity::find().all(db).await? {
+            let mut service_fields = fields(&service)?;
+            service_fields.insert(
+                "host_groups".to_string(),
+                json!(service_groups.remove(&service.id).unwrap_or_default()),
+            );
+            res.objects
+                .insert((ObjectKind::Service, service.name), service_fields);
+        }
+        Ok(res)
+    }
+
+    /// What's different in `after`, in the order of kind then name
+    pub fn diff(&self, after: &Self) -> Vec<ConfigChange> {
+        let keys = self
+            .objects
+            .keys()
+            .chain(after.objects.keys())
+            .collect::<BTreeSet<_>>();
+        keys.into_iter()
+            .filter_map(|key| {
+                let (object, name) = key.clone();
+                match (self.objects.get(key), after.objects.get(key)) {
+                    (None, Some(_)) => Some(ConfigChange {
+                        object,
+                        name,
+                        change: ChangeKind::Added,
+                        fields: vec![],
+                    }),
+                    (Some(_), None) => Some(ConfigChange {
+                        object,
+                        name,
+                        change: ChangeKind::Removed,
+                        fields: vec![],
+                    }),
+                    (Some(before), Some(after)) => {
+                        let fields = before
+                            .keys()
+                            .chain(after.keys())
+                            .collect::<BTreeSet<_>>()
+                            .into_iter()
+                            .filter(|field| before.get(*field) != after.get(*field))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        (!fields.is_empty()).then_some(ConfigChange {
+                            object,
+                            name,
+                            change: ChangeKind::Changed,
+                            fields,
+                        })
+                    }
+                    (None, None) => None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Compares the database with `before` and if anything's different sends the event to [CONFIG_EVENTS] and the
+/// `config_webhooks`, returning it
+pub async fn publish(
+    db: &DatabaseConnection,
+    config: &SendableConfig,
+    before: &Snapshot,
+    source: ChangeSource,
+) -> Result<Option<ConfigEvent>, Error> {
+    let changes = before.diff(&Snapshot::take(db).await?);
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    let event = ConfigEvent::new(source, changes);
+    info!("Configuration changed: {}", event.summary);
+    // it's fine if nobody's listening
+    let _ = CONFIG_EVENTS.send(event.clone());
+
+    let webhooks = config.read().await.config_webhooks.clone();
+    if !webhooks.is_empty() {
+        let sending = event.clone();
+        tokio::spawn(async move { send_webhooks(&webhooks, &sending).await });
+    }
+    Ok(Some(event))
+}
+
+/// POSTs the event to each of the webhooks, failures are logged and not retried
+async fn send_webhooks(webhooks: &[String], event: &ConfigEvent) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS))
+        .user_agent(format!("maremma/{}", env!("CARGO_PKG_VERSION")))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            error!("Failed to build the config webhook client: {:?}", err);
+            return;
+        }
+    };
+    for webhook in webhooks {
+        match client
+            .post(webhook)
+            .json(event)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+        {
+            Ok(_) => debug!("Sent config change event to {}", webhook),
+            Err(err) => error!(
+                "Failed to send config change event to {}: {:?}",
+                webhook, err
+            ),
+        }
+    }
+}
+
+/// Checks the webhooks are http or https URLs
+pub(crate) fn check_webhooks(webhooks: &[String]) -> Result<(), Error> {
+    for webhook in webhooks {
+        let url = Url::parse(webhook).map_err(|err| {
+            Error::Configuration(format!("Invalid config_webhooks URL {}: {}", webhook, err))
+        })?;
+        if !["http", "https"].contains(&url.scheme()) {
+            return Err(Error::Configuration(format!(
+                "config_webhooks URL {} needs to be http or https",
+                webhook
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_config_events() {
+        let (db, config) = test_setup().await.expect("Failed to setup test");
+        let before = Snapshot::take(&db).await.expect("Failed to take snapshot");
+        assert!(before.diff(&before).is_empty());
+        assert!(publish(&db, &config, &before, ChangeSource::Config)
+            .await
+            .expect("Failed to publish")
+            .is_none());
+
+        let mut events = CONFIG_EVENTS.subscribe();
+        let host = entities::host::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+        let mut changed = host.clone().into_active_model();
+        changed
+            .hostname
+            .set_if_not_equals("changed.example.com".to_string());
+        changed.update(&db).await.expect("Failed to update host");
+        entities::host_group::ActiveModel {
+            id: sea_orm::Set(Uuid::new_v4()),
+            name: sea_orm::Set("new-group".to_string()),
+            slug: sea_orm::Set("new-group".to_string()),
+        }
+        .insert(&db)
+        .await
+        .expect("Failed to add group");
+
+        let event = publish(&db, &config, &before, ChangeSource::Web)
+            .await
+            .expect("Failed to publish")
+            .expect("Nothing changed");
+        assert_eq!(event.source, ChangeSource::Web);
+        assert!(event.changes.contains(&ConfigChange {
+            object: ObjectKind::Host,
+            name: host.name,
+            change: ChangeKind::Changed,
+            fields: vec!["hostname".to_string()],
+        }));
+        assert!(event.changes.contains(&ConfigChange {
+            object: ObjectKind::HostGroup,
+            name: "new-group".to_string(),
+            change: ChangeKind::Added,
+            fields: vec![],
+        }));
+        assert_eq!(event.summary, "1 added, 1 changed");
+
+        // other tests share the channel, so skip theirs
+        loop {
+            match events.recv().await {
+                Ok(sent) if sent == event => break,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(err) => panic!("Failed to get event: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_webhooks() {
+        assert!(check_webhooks(&["https://cmdb.example.com/hooks/maremma".to_string()]).is_ok());
+        assert!(check_webhooks(&["ftp://cmdb.example.com".to_string()]).is_err());
+        assert!(check_webhooks(&["not a url".to_string()]).is_err());
+    }
+}
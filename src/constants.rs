@@ -13,9 +13,27 @@ pub(crate) fn web_server_default_port() -> NonZeroU16 {
 /// Default location for the static resources
 pub const WEB_SERVER_DEFAULT_STATIC_PATH: &str = "./static";
 
+/// Default location for the ACME account and certificates
+pub const DEFAULT_ACME_STATE_DIR: &str = "./acme";
+
 /// Default number of history entries to show on the service check page
 pub const DEFAULT_SERVICE_CHECK_HISTORY_VIEW_ENTRIES: u64 = 50;
 
+/// How many rows a paged list shows, unless it's asked for a different amount
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+
+/// The most rows a paged list will show at once
+pub const MAX_PAGE_SIZE: u64 = 1000;
+
+/// The most of each kind of thing the search page shows
+pub const SEARCH_RESULT_LIMIT: u64 = 50;
+
+/// How many days of check results the search page looks through
+pub const SEARCH_HISTORY_DAYS: i64 = 7;
+
+/// How many incidents to show on the host and service check pages
+pub const RECENT_INCIDENTS: u64 = 20;
+
 /// Expiry time + x hours is when we clean up old sessions from the DB
 pub(crate) const SESSION_EXPIRY_WINDOW_HOURS: i64 = 8;
 
@@ -27,3 +45,33 @@ pub(crate) const SESSION_CSRF_TOKEN: &str = "csrf_token";
 
 /// Default number of history entries to keep in the database
 pub const DEFAULT_SERVICE_CHECK_HISTORY_STORAGE: u64 = 25000;
+
+/// How many seconds a remote agent waits between asking the server for work
+pub const DEFAULT_AGENT_POLL_INTERVAL_SECS: u64 = 30;
+
+/// How long to wait for running checks to finish when shutting down, before they're cancelled
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+/// How long a check can run before the check loop stops it, in seconds
+pub const DEFAULT_MAX_RUNTIME_SECONDS: u64 = 120;
+
+/// Checks that take longer than this many milliseconds on average are scheduled as slow ones
+pub const DEFAULT_SLOW_CHECK_MS: u64 = 5000;
+
+/// Local users' passwords have to be at least this long
+pub const LOCAL_USER_MIN_PASSWORD_LENGTH: usize = 12;
+
+/// The check loop's not ready if it hasn't looked for due checks in this many seconds, it waits while every slot is busy
+pub const HEALTH_CHECK_LOOP_STALE_SECONDS: i64 = 300;
+
+/// The shepherd's not ready if it hasn't finished a round in this many seconds, it normally runs every minute
+pub const HEALTH_SHEPHERD_STALE_SECONDS: i64 = 300;
+
+/// How many scheduling decisions to remember for each service check, for the scheduler insight
+pub const SCHEDULE_DECISIONS_KEPT: usize = 5;
+
+/// The config file format this version writes, see `maremma migrate-config`, files without a `config_version` are 1
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+/// How long the address a check's target resolved to is reused for, it's only recorded for information
+pub const TARGET_ADDRESS_CACHE_SECONDS: u64 = 300;
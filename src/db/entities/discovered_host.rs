@@ -0,0 +1,219 @@
+//! Hosts found by a network scan or a discovery source, waiting for someone to accept or dismiss them
+
+use std::collections::BTreeSet;
+
+use crate::discovery::DiscoveredHost;
+use crate::import::ImportedHost;
+use crate::prelude::*;
+use sea_orm::{QueryOrder, Set, TryIntoModel};
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "discovered_host")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    #[sea_orm(unique)]
+    pub address: String,
+    /// From the PTR record, if there is one
+    pub hostname: Option<String>,
+    /// List of port numbers
+    pub open_ports: Json,
+    /// List of service types
+    pub suggested_checks: Json,
+    /// The host groups it'll be added to when it's accepted
+    pub host_groups: Json,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Dismissed hosts aren't proposed again when they turn up in another scan
+    pub dismissed: bool,
+    /// `scan` for `maremma discover --cidr`, otherwise the discovery source, eg `kubernetes:prod`
+    pub source: String,
+    /// Set when the name won't resolve, so the host's configured with its address as the hostname
+    pub connect_by_address: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// The name it'll have in the config, the PTR name if there is one
+    pub fn name(&self) -> &str {
+        self.hostname.as_deref().unwrap_or(&self.address)
+    }
+
+    pub fn open_ports_list(&self) -> Vec<u16> {
+        serde_json::from_value(self.open_ports.clone()).unwrap_or_default()
+    }
+
+    pub fn suggested_checks_list(&self) -> Vec<ServiceType> {
+        serde_json::from_value(self.suggested_checks.clone()).unwrap_or_default()
+    }
+
+    pub fn host_groups_list(&self) -> Vec<String> {
+        serde_json::from_value(self.host_groups.clone()).unwrap_or_default()
+    }
+
+    /// What gets merged into the config file when it's accepted
+    pub fn to_imported_host(&self) -> ImportedHost {
+        ImportedHost {
+            name: self.name().to_string(),
+            // connect by address if we don't have a name for it, or the name won't resolve
+            hostname: (self.connect_by_address || self.hostname.is_none())
+                .then(|| self.address.clone()),
+            groups: self.host_groups_list().into_iter().collect(),
+        }
+    }
+}
+
+impl Entity {
+    /// Saves what was found, hosts which have been seen before keep their `first_seen` and `dismissed`
+    pub async fn record(
+        db: &DatabaseConnection,
+        source: &str,
+        found: &DiscoveredHost,
+        host_groups: &BTreeSet<String>,
+        connect_by_address: bool,
+    ) -> Result<Model, Error> {
+        let now = chrono::Utc::now();
+        let address = found.address.to_string();
+        let existing = Entity::find()
+            .filter(Column::Address.eq(&address))
+            .one(db)
+            .await?;
+
+        let mut model = match existing {
+            Some(existing) => existing.into_active_model(),
+            None => ActiveModel {
+                id: Set(Uuid::new_v4()),
+                address: Set(address),
+                first_seen: Set(now),
+                dismissed: Set(false),
+                ..Default::default()
+            },
+        };
+        model.hostname = Set(found.hostname.clone());
+        model.open_ports = Set(json!(found.open_ports));
+        model.suggested_checks = Set(json!(found.suggested_checks));
+        model.host_groups = Set(json!(host_groups));
+        model.last_seen = Set(now);
+        model.source = Set(source.to_string());
+        model.connect_by_address = Set(connect_by_address);
+        Ok(model.save(db).await?.try_into_model()?)
+    }
+
+    /// The hosts waiting for review
+    pub async fn pending(db: &DatabaseConnection) -> Result<Vec<Model>, Error> {
+        Ok(Entity::find()
+            .filter(Column::Dismissed.eq(false))
+            .order_by_asc(Column::Address)
+            .all(db)
+            .await?)
+    }
+
+    /// Removes the source's proposals which it stopped reporting before `before`, dismissed hosts are kept so they stay dismissed
+    pub async fn prune_source(
+        db: &DatabaseConnection,
+        source: &str,
+        before: DateTime<Utc>,
+    ) -> Result<u64, Error> {
+        Ok(Entity::delete_many()
+            .filter(Column::Source.eq(source))
+            .filter(Column::Dismissed.eq(false))
+            .filter(Column::LastSeen.lt(before))
+            .exec(db)
+            .await?
+            .rows_affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_record_discovered_host() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+
+        let found = DiscoveredHost {
+            address: "192.0.2.10".parse().expect("Failed to parse address"),
+            hostname: None,
+            open_ports: vec![22],
+            suggested_checks: vec![ServiceType::Ssh],
+        };
+        let groups = BTreeSet::from(["linux".to_string()]);
+        let first = Entity::record(&db, "scan", &found, &groups, false)
+            .await
+            .expect("Failed to record host");
+        assert_eq!(first.open_ports_list(), vec![22]);
+        assert_eq!(first.suggested_checks_list(), vec![ServiceType::Ssh]);
+
+        let imported = first.to_imported_host();
+        assert_eq!(imported.name, "192.0.2.10");
+        assert_eq!(imported.hostname.as_deref(), Some("192.0.2.10"));
+        assert_eq!(imported.groups, groups);
+
+        // dismissing it sticks when it's found again
+        let mut dismissed = first.clone().into_active_model();
+        dismissed.dismissed = Set(true);
+        dismissed.update(&db).await.expect("Failed to dismiss host");
+
+        let found = DiscoveredHost {
+            hostname: Some("web1.example.com".to_string()),
+            open_ports: vec![22, 443],
+            ..found
+        };
+        let second = Entity::record(&db, "scan", &found, &groups, false)
+            .await
+            .expect("Failed to record host");
+        assert_eq!(second.id, first.id);
+        assert_eq!(second.first_seen, first.first_seen);
+        assert!(second.dismissed);
+        assert_eq!(second.name(), "web1.example.com");
+        assert_eq!(second.to_imported_host().hostname, None);
+        assert!(Entity::pending(&db)
+            .await
+            .expect("Failed to list pending hosts")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prune_source() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+
+        let found = DiscoveredHost {
+            address: "192.0.2.20".parse().expect("Failed to parse address"),
+            hostname: Some("node1".to_string()),
+            open_ports: vec![],
+            suggested_checks: vec![],
+        };
+        let node = Entity::record(&db, "kubernetes:prod", &found, &BTreeSet::new(), true)
+            .await
+            .expect("Failed to record host");
+        assert_eq!(node.source, "kubernetes:prod");
+        let imported = node.to_imported_host();
+        assert_eq!(imported.name, "node1");
+        assert_eq!(imported.hostname.as_deref(), Some("192.0.2.20"));
+
+        assert_eq!(
+            Entity::prune_source(&db, "scan", chrono::Utc::now())
+                .await
+                .expect("Failed to prune"),
+            0
+        );
+        assert_eq!(
+            Entity::prune_source(&db, "kubernetes:prod", node.first_seen)
+                .await
+                .expect("Failed to prune"),
+            0
+        );
+        assert_eq!(
+            Entity::prune_source(&db, "kubernetes:prod", chrono::Utc::now())
+                .await
+                .expect("Failed to prune"),
+            1
+        );
+    }
+}
@@ -1,16 +1,33 @@
+use crate::host::address::AddressFamily;
 use crate::prelude::*;
 use sea_orm::entity::prelude::*;
-use sea_orm::IntoActiveModel;
+use sea_orm::{IntoActiveModel, QueryOrder};
 
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "host")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     pub name: String,
+    /// URL-friendly version of the name, generated when the host is created
+    pub slug: String,
     pub hostname: String,
     pub check: crate::host::HostCheck,
     pub config: Json,
+    /// Variables the host's services can use as `{{ name }}`
+    pub vars: Json,
+    /// Used instead of looking up the hostname's IPv4 address
+    pub ipv4: Option<String>,
+    /// Used instead of looking up the hostname's IPv6 address
+    pub ipv6: Option<String>,
+    /// Which IP versions checks use, services can override it
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    /// Shown in the UI instead of the name, eg "Payments DB primary"
+    pub display_name: Option<String>,
+    /// Free-form `key: value` labels, see [crate::labels]
+    #[serde(default)]
+    pub labels: Json,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -50,10 +67,31 @@ impl Related<super::host_group::Entity> for Entity {
 
 impl ActiveModelBehavior for ActiveModel {}
 
+/// What the UI calls the host, its `display_name` if it has one
+pub fn display_name<'a>(name: &'a str, display_name: Option<&'a str>) -> &'a str {
+    display_name.unwrap_or(name)
+}
+
+impl Model {
+    /// The host's name in the UI, see [display_name]
+    pub fn display_name(&self) -> &str {
+        display_name(&self.name, self.display_name.as_deref())
+    }
+
+    /// The host's labels, sorted by key
+    pub fn labels(&self) -> crate::labels::Labels {
+        crate::labels::from_json(&self.labels)
+    }
+}
+
 #[async_trait]
 impl MaremmaEntity for Model {
     async fn find_by_name(name: &str, db: &DatabaseConnection) -> Result<Option<Model>, Error> {
-        match Entity::find().filter(Column::Name.eq(name)).one(db).await {
+        match Entity::find()
+            .filter(super::name_matches(Column::Name, name))
+            .one(db)
+            .await
+        {
             Ok(val) => Ok(val.into_iter().next()),
             Err(err) => {
                 error!("Query failed while looking up host '{}': {:?}", name, err);
@@ -88,8 +126,29 @@ impl MaremmaEntity for Model {
                     existing_host
                         .hostname
                         .set_if_not_equals(hostname.to_owned());
-                    existing_host.name.set_if_not_equals(name.to_string());
+                    existing_host
+                        .name
+                        .set_if_not_equals(super::normalize_name(name));
                     existing_host.config.set_if_not_equals(json!(host.config));
+                    existing_host.vars.set_if_not_equals(json!(host.vars));
+                    existing_host
+                        .ipv4
+                        .set_if_not_equals(host.ipv4.map(|address| address.to_string()));
+                    existing_host
+                        .ipv6
+                        .set_if_not_equals(host.ipv6.map(|address| address.to_string()));
+                    existing_host
+                        .address_family
+                        .set_if_not_equals(host.address_family);
+                    existing_host
+                        .display_name
+                        .set_if_not_equals(host.display_name.clone());
+                    existing_host.labels.set_if_not_equals(json!(host.labels));
+                    if existing_host.slug.as_ref().is_empty() {
+                        existing_host.slug.set_if_not_equals(
+                            super::unique_slug::<Entity>(db, Column::Slug, name).await?,
+                        );
+                    }
 
                     if existing_host.is_changed() {
                         info!("Updating {:?}", &existing_host);
@@ -101,10 +160,17 @@ impl MaremmaEntity for Model {
                 None => {
                     let new_host = Model {
                         id: host.id.unwrap_or(Uuid::new_v4()),
-                        name: name.to_owned(),
-                        hostname: host.hostname.clone().unwrap_or(name.to_string()),
+                        name: super::normalize_name(name),
+                        slug: super::unique_slug::<Entity>(db, Column::Slug, name).await?,
+                        hostname: host.hostname.clone().unwrap_or(super::normalize_name(name)),
                         check: host.check.clone(),
                         config: json!(host.config.clone()),
+                        vars: json!(host.vars.clone()),
+                        ipv4: host.ipv4.map(|address| address.to_string()),
+                        ipv6: host.ipv6.map(|address| address.to_string()),
+                        address_family: host.address_family,
+                        display_name: host.display_name.clone(),
+                        labels: json!(host.labels),
                     }
                     .into_active_model();
                     info!("Creating Host {:?}", new_host.insert(db).await?);
@@ -115,14 +181,49 @@ impl MaremmaEntity for Model {
     }
 }
 
+impl Entity {
+    /// The hosts with the worst status of their checks, by name, for showing a quick summary
+    pub async fn with_worst_status(
+        db: &DatabaseConnection,
+        host_ids: &[Uuid],
+    ) -> Result<Vec<(Model, ServiceStatus)>, Error> {
+        if host_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(Entity::find()
+            .filter(Column::Id.is_in(host_ids.to_vec()))
+            .order_by_asc(Column::Name)
+            .find_with_related(super::service_check::Entity)
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|(host, checks)| {
+                let status = checks
+                    .iter()
+                    .map(|check| check.status)
+                    .max()
+                    .unwrap_or(ServiceStatus::Unknown);
+                (host, status)
+            })
+            .collect())
+    }
+}
+
 // #[cfg(test)]
 pub fn test_host() -> Model {
     Model {
         id: Uuid::new_v4(),
         name: "test_host_name".to_string(),
+        slug: "test-host-name".to_string(),
         hostname: "test_host_hostname".to_string(),
         check: crate::host::HostCheck::Ping,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     }
 }
 
@@ -140,32 +241,29 @@ mod tests {
 
     #[tokio::test]
     async fn test_host_entity() {
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
-
-        let db_writer = db.write().await;
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
         let host = super::test_host();
         info!("saving host...");
         let am = host.clone().into_active_model();
-        super::Entity::insert(am).exec(&*db_writer).await.unwrap();
+        super::Entity::insert(am).exec(&db).await.unwrap();
 
         let new_host = super::Entity::find()
             .filter(super::Column::Id.eq(host.id))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .unwrap();
         info!("found it: {:?}", new_host);
 
         super::Entity::delete_by_id(new_host.id)
-            .exec(&*db_writer)
+            .exec(&db)
             .await
             .unwrap();
 
         assert!(super::Entity::find()
             .filter(super::Column::Id.eq(new_host.id))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .is_none());
@@ -174,21 +272,19 @@ mod tests {
     #[tokio::test]
     async fn test_update_db_from_config() {
         let (db, config) = test_setup().await.expect("Failed to start test harness");
-        super::Model::update_db_from_config(&*db.write().await, config)
+        super::Model::update_db_from_config(&db, config)
             .await
             .expect("Failed to load config");
     }
     #[tokio::test]
     async fn test_create_then_search() {
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
-        let db_writer = db.write().await;
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
         let inserted_host = super::Entity::insert(super::test_host().into_active_model())
-            .exec_with_returning(&*db_writer)
+            .exec_with_returning(&db)
             .await
             .expect("Failed to insert host");
 
-        let found_host = super::Model::find_by_name(&super::test_host().name, &*db_writer)
+        let found_host = super::Model::find_by_name(&super::test_host().name, &db)
             .await
             .expect("Failed to query host");
 
@@ -197,6 +293,37 @@ mod tests {
         assert_eq!(found_host.unwrap().name, inserted_host.name);
     }
 
+    #[tokio::test]
+    async fn test_with_worst_status() {
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
+        assert!(super::Entity::with_worst_status(&db, &[])
+            .await
+            .expect("Failed to query")
+            .is_empty());
+
+        let host = super::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+        let worst = crate::db::entities::service_check::Entity::find()
+            .filter(crate::db::entities::service_check::Column::HostId.eq(host.id))
+            .all(&db)
+            .await
+            .expect("Failed to query checks")
+            .into_iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(crate::services::ServiceStatus::Unknown);
+
+        let res = super::Entity::with_worst_status(&db, &[host.id, Uuid::new_v4()])
+            .await
+            .expect("Failed to query");
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0.id, host.id);
+        assert_eq!(res[0].1, worst);
+    }
+
     #[tokio::test]
     async fn test_failing_update_db_from_config_host() {
         use sea_orm::{DatabaseBackend, MockDatabase};
@@ -205,9 +332,16 @@ mod tests {
             .append_query_results([[super::Model {
                 id: Uuid::new_v4(),
                 name: "foo".to_string(),
+                slug: "foo".to_string(),
                 hostname: "foo.example.com".to_owned(),
                 check: crate::host::HostCheck::None,
                 config: serde_json::json!({}),
+                vars: serde_json::json!({}),
+                ipv4: None,
+                ipv6: None,
+                address_family: Default::default(),
+                display_name: None,
+                labels: json!({}),
             }]])
             .into_connection();
 
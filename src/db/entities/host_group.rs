@@ -1,6 +1,6 @@
 use crate::prelude::*;
 
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "host_group")]
 /// Host group model
 pub struct Model {
@@ -8,6 +8,8 @@ pub struct Model {
     pub id: Uuid,
     #[sea_orm(database_type = "String", unique, indexed)]
     pub name: String,
+    /// URL-friendly version of the name, generated when the group is created
+    pub slug: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -50,6 +52,12 @@ impl Related<super::host_group_members::Entity> for Entity {
 }
 
 #[cfg(not(tarpaulin_include))]
+impl Related<super::host_group_status::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::host_group_status::Relation::HostGroup.def().rev()
+    }
+}
+
 impl Related<super::service_group_link::Entity> for Entity {
     fn to() -> RelationDef {
         super::service_group_link::Relation::HostGroup.def()
@@ -62,7 +70,7 @@ impl ActiveModelBehavior for ActiveModel {}
 impl MaremmaEntity for Model {
     async fn find_by_name(name: &str, db: &DatabaseConnection) -> Result<Option<Model>, Error> {
         Entity::find()
-            .filter(Column::Name.eq(name))
+            .filter(super::name_matches(Column::Name, name))
             .one(db)
             .await
             .map_err(Error::from)
@@ -76,14 +84,14 @@ impl MaremmaEntity for Model {
             .all(db)
             .await?
             .into_iter()
-            .map(|x| x.name)
+            .map(|x| super::name_key(&x.name))
             .collect();
 
         // add the group names to the known group list
         for (_host_name, host) in config.read().await.hosts.iter() {
             for group_name in &host.host_groups {
                 // if we already have the group name we don't need to add it to the db
-                if known_group_list.contains(group_name) {
+                if known_group_list.contains(&super::name_key(group_name)) {
                     debug!("already have {}", group_name);
                     continue;
                 }
@@ -93,7 +101,9 @@ impl MaremmaEntity for Model {
                     Entity::insert(
                         Model {
                             id: Uuid::new_v4(),
-                            name: group_name.to_owned(),
+                            name: super::normalize_name(group_name),
+                            slug: super::unique_slug::<Entity>(db, Column::Slug, group_name)
+                                .await?,
                         }
                         .into_active_model(),
                     )
@@ -103,13 +113,39 @@ impl MaremmaEntity for Model {
                     debug!("already have {}", group_name);
                 }
 
-                known_group_list.push(group_name.to_string());
+                known_group_list.push(super::name_key(group_name));
+            }
+        }
+
+        // groups which only exist to include other groups
+        for (group_name, group) in &config.read().await.host_groups {
+            for group_name in std::iter::once(group_name).chain(group.members.iter()) {
+                if known_group_list.contains(&super::name_key(group_name)) {
+                    continue;
+                }
+                if Model::find_by_name(group_name, db).await?.is_none() {
+                    debug!("Adding nested host group {}", group_name);
+                    Entity::insert(
+                        Model {
+                            id: Uuid::new_v4(),
+                            name: super::normalize_name(group_name),
+                            slug: super::unique_slug::<Entity>(db, Column::Slug, group_name)
+                                .await?,
+                        }
+                        .into_active_model(),
+                    )
+                    .exec(db)
+                    .await?;
+                }
+                known_group_list.push(super::name_key(group_name));
             }
         }
 
         for (service_name, service) in &config.read().await.services {
             for group_name in service.host_groups.iter() {
-                if known_group_list.contains(group_name) {
+                // excluded groups still need to exist, so we know which hosts to leave out
+                let (group_name, _) = crate::host::group::parse_selector(group_name);
+                if known_group_list.contains(&super::name_key(group_name)) {
                     continue;
                 }
                 if Model::find_by_name(group_name, db).await?.is_none() {
@@ -117,7 +153,9 @@ impl MaremmaEntity for Model {
                     Entity::insert(
                         Model {
                             id: Uuid::new_v4(),
-                            name: group_name.to_owned(),
+                            name: super::normalize_name(group_name),
+                            slug: super::unique_slug::<Entity>(db, Column::Slug, group_name)
+                                .await?,
                         }
                         .into_active_model(),
                     )
@@ -150,7 +188,7 @@ mod tests {
     async fn test_update_db_from_config() {
         let (db, config) = test_setup().await.expect("Failed to start test harness");
 
-        super::Model::update_db_from_config(&*db.write().await, config)
+        super::Model::update_db_from_config(&db, config)
             .await
             .expect("Failed to load config");
     }
@@ -162,6 +200,7 @@ mod tests {
             .append_query_results([[super::Model {
                 id: Uuid::new_v4(),
                 name: "Test".to_owned(),
+                slug: "test".to_owned(),
             }]])
             .into_connection();
 
@@ -2,7 +2,7 @@ use sea_orm::Set;
 
 use crate::prelude::*;
 
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "host_group_members")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
@@ -132,7 +132,10 @@ impl MaremmaEntity for Model {
                     host_list.push(db_host.id);
                 } else {
                     let group = super::host_group::Entity::find()
-                        .filter(super::host_group::Column::Name.eq(group_name))
+                        .filter(super::name_matches(
+                            super::host_group::Column::Name,
+                            group_name,
+                        ))
                         .one(db)
                         .await?;
 
@@ -170,7 +173,7 @@ mod tests {
         // this should error
         let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
-        let res = super::Model::find_by_name("test", &*db.read().await).await;
+        let res = super::Model::find_by_name("test", &db).await;
 
         assert!(res.is_err());
         assert_eq!(res.err().unwrap(), Error::NotImplemented);
@@ -201,7 +204,7 @@ mod tests {
 
         let hosts = super::super::host::Entity::find()
             .find_with_linked(super::HostToGroups)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to query host to groups relation");
 
@@ -214,7 +217,7 @@ mod tests {
 
         let groups = super::super::host_group::Entity::find()
             .find_with_linked(super::GroupToHosts)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to query group to hosts relation");
 
@@ -0,0 +1,198 @@
+//! Host groups which include other groups, from the `members` of the `host_groups` config
+
+use std::collections::HashSet;
+
+use sea_orm::Set;
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "host_group_nesting")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    /// The group which includes the other one
+    pub parent_id: Uuid,
+    /// The group whose hosts are in the parent too
+    pub child_id: Uuid,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    Parent,
+    Child,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::Parent => Entity::belongs_to(super::host_group::Entity)
+                .from(Column::ParentId)
+                .to(super::host_group::Column::Id)
+                .into(),
+            Self::Child => Entity::belongs_to(super::host_group::Entity)
+                .from(Column::ChildId)
+                .to(super::host_group::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Entity {
+    /// The groups and every group nested inside them, however deep
+    pub async fn expand(
+        db: &DatabaseConnection,
+        group_ids: &[Uuid],
+    ) -> Result<HashSet<Uuid>, Error> {
+        let nesting = Entity::find().all(db).await?;
+        let mut res = group_ids.iter().copied().collect::<HashSet<_>>();
+        let mut todo = group_ids.to_vec();
+        while let Some(parent_id) = todo.pop() {
+            for nested in nesting
+                .iter()
+                .filter(|nested| nested.parent_id == parent_id)
+            {
+                // the config's checked for cycles, but this stops at groups it's already seen anyway
+                if res.insert(nested.child_id) {
+                    todo.push(nested.child_id);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Every group's hosts, including the hosts of the groups nested inside it, see [Entity::expand]
+    pub async fn hosts_by_group(
+        db: &DatabaseConnection,
+    ) -> Result<HashMap<Uuid, HashSet<Uuid>>, Error> {
+        let mut direct: HashMap<Uuid, HashSet<Uuid>> = HashMap::new();
+        for member in super::host_group_members::Entity::find().all(db).await? {
+            direct
+                .entry(member.group_id)
+                .or_default()
+                .insert(member.host_id);
+        }
+        let mut res = HashMap::new();
+        for group in super::host_group::Entity::find().all(db).await? {
+            let hosts = Self::expand(db, &[group.id])
+                .await?
+                .iter()
+                .filter_map(|group_id| direct.get(group_id))
+                .flatten()
+                .copied()
+                .collect();
+            res.insert(group.id, hosts);
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl MaremmaEntity for Model {
+    async fn find_by_name(_name: &str, _db: &DatabaseConnection) -> Result<Option<Model>, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    async fn update_db_from_config(
+        db: &DatabaseConnection,
+        config: SendableConfig,
+    ) -> Result<(), Error> {
+        let mut wanted = HashSet::new();
+        for (group_name, group) in &config.read().await.host_groups {
+            let parent = super::host_group::Model::find_by_name(group_name, db)
+                .await?
+                .ok_or_else(|| Error::HostGroupNotFoundByName(group_name.clone()))?;
+            for member in &group.members {
+                let child = super::host_group::Model::find_by_name(member, db)
+                    .await?
+                    .ok_or_else(|| Error::HostGroupNotFoundByName(member.clone()))?;
+                wanted.insert((parent.id, child.id));
+            }
+        }
+
+        // nesting only comes from the config, so anything it doesn't have any more goes
+        for existing in Entity::find().all(db).await? {
+            if !wanted.remove(&(existing.parent_id, existing.child_id)) {
+                debug!(
+                    "Removing host group nesting {} -> {}",
+                    existing.parent_id, existing.child_id
+                );
+                existing.delete(db).await?;
+            }
+        }
+        for (parent_id, child_id) in wanted {
+            debug!("Adding host group nesting {} -> {}", parent_id, child_id);
+            ActiveModel {
+                id: Set(Uuid::new_v4()),
+                parent_id: Set(parent_id),
+                child_id: Set(child_id),
+            }
+            .insert(db)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use crate::host::group::HostGroupConfig;
+
+    #[tokio::test]
+    async fn test_nesting() {
+        let (db, config) = test_setup().await.expect("Failed to start test harness");
+        let groups = super::super::host_group::Entity::find()
+            .all(&db)
+            .await
+            .expect("Failed to query host groups");
+        let [first, second, ..] = groups.as_slice() else {
+            panic!("Need at least two host groups to test with");
+        };
+
+        config.write().await.host_groups = HashMap::from([(
+            first.name.clone(),
+            HostGroupConfig {
+                members: vec![second.name.clone()],
+            },
+        )]);
+        Model::update_db_from_config(&db, config.clone())
+            .await
+            .expect("Failed to update nesting");
+        // running it again doesn't duplicate anything
+        Model::update_db_from_config(&db, config.clone())
+            .await
+            .expect("Failed to update nesting");
+        assert_eq!(
+            Entity::find()
+                .all(&db)
+                .await
+                .expect("Failed to query nesting")
+                .len(),
+            1
+        );
+
+        let expanded = Entity::expand(&db, &[first.id])
+            .await
+            .expect("Failed to expand groups");
+        assert_eq!(expanded, HashSet::from([first.id, second.id]));
+        let expanded = Entity::expand(&db, &[second.id])
+            .await
+            .expect("Failed to expand groups");
+        assert_eq!(expanded, HashSet::from([second.id]));
+
+        // taking it out of the config removes it
+        config.write().await.host_groups = HashMap::new();
+        Model::update_db_from_config(&db, config)
+            .await
+            .expect("Failed to update nesting");
+        assert!(Entity::find()
+            .all(&db)
+            .await
+            .expect("Failed to query nesting")
+            .is_empty());
+    }
+}
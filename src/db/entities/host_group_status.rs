@@ -0,0 +1,239 @@
+//! The status of each host group, rolled up from its members by the shepherd
+
+use entities::{host_group, host_group_nesting, service_check};
+use sea_orm::sea_query::OnConflict;
+
+use crate::group_status::{host_status, GroupStatusConfig};
+use crate::prelude::*;
+
+/// Keeps each insert well under SQLite's limit on bound parameters
+const MAX_ROWS_PER_INSERT: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "host_group_status")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub host_group_id: Uuid,
+    pub status: ServiceStatus,
+    /// How many hosts in the group had a check result to go on
+    pub members: i64,
+    /// How many of those are critical, errored or timed out
+    pub failing: i64,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    HostGroup,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::HostGroup => Entity::belongs_to(host_group::Entity)
+                .from(Column::HostGroupId)
+                .to(host_group::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl Related<host_group::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::HostGroup.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Entity {
+    /// Works out every group's status from its hosts' checks and saves it, returning how many groups were updated
+    pub async fn refresh(
+        db: &DatabaseConnection,
+        config: &GroupStatusConfig,
+    ) -> Result<usize, Error> {
+        let mut host_checks: HashMap<Uuid, Vec<ServiceStatus>> = HashMap::new();
+        for check in service_check::Entity::find().all(db).await? {
+            host_checks
+                .entry(check.host_id)
+                .or_default()
+                .push(check.status);
+        }
+        let host_statuses = host_checks
+            .into_iter()
+            .filter_map(|(host_id, statuses)| host_status(statuses).map(|status| (host_id, status)))
+            .collect::<HashMap<_, _>>();
+
+        // nested groups' hosts count towards the groups they're in
+        let group_hosts = host_group_nesting::Entity::hosts_by_group(db)
+            .await?
+            .into_iter()
+            .map(|(group_id, hosts)| {
+                let statuses = hosts
+                    .iter()
+                    .filter_map(|host_id| host_statuses.get(host_id).copied())
+                    .collect::<Vec<_>>();
+                (group_id, statuses)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let now = Utc::now();
+        let statuses = host_group::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|group| {
+                let rollup = config.policy_for(&group.name).rollup(
+                    group_hosts
+                        .get(&group.id)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                );
+                Model {
+                    host_group_id: group.id,
+                    status: rollup.status,
+                    members: rollup.members as i64,
+                    failing: rollup.failing as i64,
+                    last_updated: now,
+                }
+                .into_active_model()
+            })
+            .collect::<Vec<_>>();
+
+        let updated = statuses.len();
+        for chunk in statuses.chunks(MAX_ROWS_PER_INSERT) {
+            Entity::insert_many(chunk.to_vec())
+                .on_conflict(
+                    OnConflict::column(Column::HostGroupId)
+                        .update_columns([
+                            Column::Status,
+                            Column::Members,
+                            Column::Failing,
+                            Column::LastUpdated,
+                        ])
+                        .to_owned(),
+                )
+                .exec_without_returning(db)
+                .await?;
+        }
+        Ok(updated)
+    }
+
+    /// Every group with a status, worst first
+    pub async fn with_groups(
+        db: &DatabaseConnection,
+    ) -> Result<Vec<(host_group::Model, Model)>, Error> {
+        let mut groups = host_group::Entity::find()
+            .find_also_related(Entity)
+            .all(db)
+            .await?
+            .into_iter()
+            .filter_map(|(group, status)| status.map(|status| (group, status)))
+            .collect::<Vec<_>>();
+        groups.sort_by(|(a_group, a), (b_group, b)| {
+            b.status
+                .cmp(&a.status)
+                .then_with(|| a_group.name.cmp(&b_group.name))
+        });
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use crate::group_status::GroupStatusPolicy;
+    use entities::host_group_members;
+    use sea_orm::Set;
+
+    #[tokio::test]
+    async fn test_refresh_group_status() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let member = host_group_members::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query group members")
+            .expect("No group members found");
+
+        // one host in the group failing
+        let checks = service_check::Entity::find()
+            .filter(service_check::Column::HostId.eq(member.host_id))
+            .all(&db)
+            .await
+            .expect("Failed to query service checks");
+        assert!(!checks.is_empty());
+        for check in checks {
+            let mut check = check.into_active_model();
+            check.status = Set(ServiceStatus::Critical);
+            check.update(&db).await.expect("Failed to update check");
+        }
+
+        let updated = Entity::refresh(&db, &GroupStatusConfig::default())
+            .await
+            .expect("Failed to refresh group statuses");
+        assert!(updated > 0);
+        let status = Entity::find_by_id(member.group_id)
+            .one(&db)
+            .await
+            .expect("Failed to query group status")
+            .expect("Group status wasn't saved");
+        assert_eq!(status.status, ServiceStatus::Critical);
+        assert!(status.failing >= 1);
+
+        // a group with no hosts of its own picks up the failing host from a group nested inside it
+        let parent = host_group::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            name: Set("parent-group".to_string()),
+            slug: Set("parent-group".to_string()),
+        }
+        .insert(&db)
+        .await
+        .expect("Failed to create parent group");
+        host_group_nesting::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            parent_id: Set(parent.id),
+            child_id: Set(member.group_id),
+        }
+        .insert(&db)
+        .await
+        .expect("Failed to nest group");
+        Entity::refresh(&db, &GroupStatusConfig::default())
+            .await
+            .expect("Failed to refresh group statuses");
+        let parent_status = Entity::find_by_id(parent.id)
+            .one(&db)
+            .await
+            .expect("Failed to query group status")
+            .expect("Parent group status wasn't saved");
+        assert_eq!(parent_status.status, ServiceStatus::Critical);
+        assert_eq!(parent_status.members, status.members);
+
+        // with a percentage policy nobody can hit, it's only a warning, and refreshing again updates the row
+        let group = host_group::Entity::find_by_id(member.group_id)
+            .one(&db)
+            .await
+            .expect("Failed to query group")
+            .expect("Group went missing");
+        let config = GroupStatusConfig {
+            groups: HashMap::from([(
+                group.name.clone(),
+                GroupStatusPolicy::Percentage {
+                    critical_percent: 100,
+                },
+            )]),
+            ..Default::default()
+        };
+        Entity::refresh(&db, &config)
+            .await
+            .expect("Failed to refresh group statuses");
+        let groups = Entity::with_groups(&db)
+            .await
+            .expect("Failed to list group statuses");
+        let (_, status) = groups
+            .iter()
+            .find(|(found, _)| found.id == group.id)
+            .expect("Group wasn't listed");
+        assert_eq!(status.status, ServiceStatus::Warning);
+    }
+}
@@ -0,0 +1,334 @@
+//! Incidents group a service check's consecutive failures, opening when it leaves Ok and closing when it recovers
+
+use entities::{host, service, service_check};
+use sea_orm::{ConnectionTrait, FromQueryResult, JoinType, QueryOrder, QuerySelect, Set};
+
+use crate::labels::LabelFilter;
+use crate::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "incident")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub service_check_id: Uuid,
+    /// When the first failing result came in
+    pub started_at: DateTime<Utc>,
+    /// When it went back to Ok, it's still open if this isn't set
+    pub ended_at: Option<DateTime<Utc>>,
+    pub worst_status: ServiceStatus,
+    pub last_status: ServiceStatus,
+    /// How many failing results there were
+    pub results: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    ServiceCheck,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::ServiceCheck => Entity::belongs_to(service_check::Entity)
+                .from(Column::ServiceCheckId)
+                .to(service_check::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl Related<service_check::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ServiceCheck.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Results which are part of an incident, anything that's not Ok and isn't a check being queued, run or switched off
+fn is_failure(status: ServiceStatus) -> bool {
+    !matches!(
+        status,
+        ServiceStatus::Ok
+            | ServiceStatus::Pending
+            | ServiceStatus::Checking
+            | ServiceStatus::Urgent
+            | ServiceStatus::Disabled
+            | ServiceStatus::Cancelled
+    )
+}
+
+/// How long something took, for showing on a page, eg `2h 5m`
+pub(crate) fn duration_text(duration: TimeDelta) -> String {
+    let minutes = duration.num_minutes();
+    match (minutes / 1440, (minutes % 1440) / 60, minutes % 60) {
+        (0, 0, 0) => format!("{}s", duration.num_seconds().max(0)),
+        (0, 0, mins) => format!("{}m", mins),
+        (0, hours, mins) => format!("{}h {}m", hours, mins),
+        (days, hours, _) => format!("{}d {}h", days, hours),
+    }
+}
+
+impl Entity {
+    /// Adds a check result to the check's open incident, opening one if it's failed or closing it if it's recovered
+    pub async fn record<C: ConnectionTrait>(
+        db: &C,
+        service_check_id: Uuid,
+        status: ServiceStatus,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if status != ServiceStatus::Ok && !is_failure(status) {
+            return Ok(());
+        }
+        let open = Entity::find()
+            .filter(Column::ServiceCheckId.eq(service_check_id))
+            .filter(Column::EndedAt.is_null())
+            .one(db)
+            .await?;
+        match open {
+            Some(incident) if status == ServiceStatus::Ok => {
+                debug!(
+                    "Closing incident={} for service_check={}",
+                    incident.id, service_check_id
+                );
+                let mut incident = incident.into_active_model();
+                incident.ended_at = Set(Some(timestamp));
+                incident.update(db).await?;
+            }
+            Some(incident) => {
+                let worst_status = incident.worst_status.max(status);
+                let results = incident.results + 1;
+                let mut incident = incident.into_active_model();
+                incident.worst_status = Set(worst_status);
+                incident.last_status = Set(status);
+                incident.results = Set(results);
+                incident.update(db).await?;
+            }
+            None if status == ServiceStatus::Ok => {}
+            None => {
+                debug!("Opening incident for service_check={}", service_check_id);
+                ActiveModel {
+                    id: Set(Uuid::new_v4()),
+                    service_check_id: Set(service_check_id),
+                    started_at: Set(timestamp),
+                    ended_at: Set(None),
+                    worst_status: Set(status),
+                    last_status: Set(status),
+                    results: Set(1),
+                }
+                .insert(db)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Incidents matching the filter, newest first
+    pub async fn search(
+        db: &DatabaseConnection,
+        filter: &IncidentFilter,
+    ) -> Result<Vec<FullIncident>, Error> {
+        let mut query = Entity::find()
+            .column_as(host::Column::Id, "host_id")
+            .column_as(host::Column::Hostname, "host_name")
+            .column_as(host::Column::Slug, "host_slug")
+            .column_as(host::Column::DisplayName, "host_display_name")
+            .column_as(service::Column::Name, "service_name")
+            .column_as(service::Column::Slug, "service_slug")
+            .join(JoinType::InnerJoin, Relation::ServiceCheck.def())
+            .join(JoinType::InnerJoin, service_check::Relation::Host.def())
+            .join(JoinType::InnerJoin, service_check::Relation::Service.def())
+            .order_by_desc(Column::StartedAt)
+            .limit(filter.limit);
+        if let Some(host_id) = filter.host_id {
+            query = query.filter(host::Column::Id.eq(host_id));
+        }
+        if let Some(service_check_id) = filter.service_check_id {
+            query = query.filter(Column::ServiceCheckId.eq(service_check_id));
+        }
+        if filter.open {
+            query = query.filter(Column::EndedAt.is_null());
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(Column::StartedAt.gte(since));
+        }
+        if let Some(label) = &filter.label {
+            query =
+                query.filter(label.condition_any(host::Column::Labels, service::Column::Labels));
+        }
+        Ok(query.into_model::<FullIncident>().all(db).await?)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// What to look for with [Entity::search]
+pub struct IncidentFilter {
+    /// Only incidents on this host's checks
+    pub host_id: Option<Uuid>,
+    /// Only this check's incidents
+    pub service_check_id: Option<Uuid>,
+    /// Only the ones that haven't recovered yet
+    pub open: bool,
+    /// Only the ones that started at or after this
+    pub since: Option<DateTime<Utc>>,
+    /// Only incidents where the host or service has this label
+    pub label: Option<LabelFilter>,
+    /// The most incidents to return
+    pub limit: u64,
+}
+
+impl Default for IncidentFilter {
+    fn default() -> Self {
+        Self {
+            host_id: None,
+            service_check_id: None,
+            open: false,
+            since: None,
+            label: None,
+            limit: 50,
+        }
+    }
+}
+
+/// An incident with the names of the host and service it's for
+#[derive(Clone, Debug, PartialEq, Eq, FromQueryResult, Serialize)]
+pub struct FullIncident {
+    pub id: Uuid,
+    pub service_check_id: Uuid,
+    pub host_id: Uuid,
+    pub host_name: String,
+    pub host_slug: String,
+    pub host_display_name: Option<String>,
+    pub service_name: String,
+    pub service_slug: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub worst_status: ServiceStatus,
+    pub last_status: ServiceStatus,
+    pub results: i64,
+}
+
+impl FullIncident {
+    /// The host's name in the UI, see [host::display_name]
+    pub fn host_display_name(&self) -> &str {
+        host::display_name(&self.host_name, self.host_display_name.as_deref())
+    }
+
+    /// How long it lasted, or has lasted so far if it's still open
+    pub fn duration(&self, now: DateTime<Utc>) -> TimeDelta {
+        self.ended_at.unwrap_or(now) - self.started_at
+    }
+
+    /// The duration for showing on a page
+    pub fn duration_text(&self) -> String {
+        duration_text(self.duration(Utc::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[test]
+    fn test_duration_text() {
+        assert_eq!(duration_text(TimeDelta::seconds(42)), "42s");
+        assert_eq!(duration_text(TimeDelta::minutes(5)), "5m");
+        assert_eq!(duration_text(TimeDelta::minutes(125)), "2h 5m");
+        assert_eq!(duration_text(TimeDelta::hours(50)), "2d 2h");
+    }
+
+    #[tokio::test]
+    async fn test_record_incident() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let check = service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+
+        let start = Utc::now();
+        for (offset, status) in [
+            // nothing to do yet
+            (0, ServiceStatus::Ok),
+            (1, ServiceStatus::Warning),
+            (2, ServiceStatus::Critical),
+            // doesn't count
+            (3, ServiceStatus::Cancelled),
+            (4, ServiceStatus::Warning),
+            (5, ServiceStatus::Ok),
+            (6, ServiceStatus::Error),
+        ] {
+            Entity::record(&db, check.id, status, start + TimeDelta::minutes(offset))
+                .await
+                .expect("Failed to record result");
+        }
+
+        let incidents = Entity::search(
+            &db,
+            &IncidentFilter {
+                service_check_id: Some(check.id),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to search incidents");
+        assert_eq!(incidents.len(), 2);
+        // newest first
+        let (open, closed) = (&incidents[0], &incidents[1]);
+        assert_eq!(open.ended_at, None);
+        assert_eq!(open.worst_status, ServiceStatus::Error);
+        assert_eq!(closed.worst_status, ServiceStatus::Critical);
+        assert_eq!(closed.last_status, ServiceStatus::Warning);
+        assert_eq!(closed.results, 3);
+        assert_eq!(closed.duration(Utc::now()), TimeDelta::minutes(4));
+        assert_eq!(closed.host_id, check.host_id);
+
+        let open_incidents = Entity::search(
+            &db,
+            &IncidentFilter {
+                host_id: Some(check.host_id),
+                open: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to search incidents");
+        assert_eq!(open_incidents.len(), 1);
+
+        let recent = Entity::search(
+            &db,
+            &IncidentFilter {
+                service_check_id: Some(check.id),
+                since: Some(start + TimeDelta::minutes(6)),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to search incidents");
+        assert_eq!(recent.len(), 1);
+
+        let mut labelled = host::Entity::find_by_id(check.host_id)
+            .one(&db)
+            .await
+            .expect("Failed to query host")
+            .expect("Failed to find host")
+            .into_active_model();
+        labelled.labels = Set(json!({"env": "prod"}));
+        labelled.update(&db).await.expect("Failed to update host");
+        for (label, expected) in [("env:prod", 2), ("env:dev", 0)] {
+            let incidents = Entity::search(
+                &db,
+                &IncidentFilter {
+                    service_check_id: Some(check.id),
+                    label: Some(label.parse().expect("Failed to parse label")),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("Failed to search incidents");
+            assert_eq!(incidents.len(), expected);
+        }
+    }
+}
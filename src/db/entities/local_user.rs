@@ -0,0 +1,196 @@
+//! Users who log in with a username and password, when `auth.mode` is `local`
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use sea_orm::Set;
+
+use crate::constants::LOCAL_USER_MIN_PASSWORD_LENGTH;
+use crate::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "local_user")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    #[sea_orm(unique)]
+    pub username: String,
+    /// An argon2 PHC string, never the password itself
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub last_login: Option<DateTime<Utc>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Hashes a password with argon2 and a random salt
+pub(crate) fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Checks a password against a stored hash
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> Result<bool, Error> {
+    match Argon2::default().verify_password(password.as_bytes(), &PasswordHash::new(password_hash)?)
+    {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl Entity {
+    /// Creates the user, or changes their password if they already exist
+    pub async fn set_password(
+        db: &DatabaseConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Model, Error> {
+        let username = super::normalize_name(username);
+        if username.is_empty() {
+            return Err(Error::InvalidInput("username can't be empty".to_string()));
+        }
+        if password.chars().count() < LOCAL_USER_MIN_PASSWORD_LENGTH {
+            return Err(Error::InvalidInput(format!(
+                "passwords need at least {} characters",
+                LOCAL_USER_MIN_PASSWORD_LENGTH
+            )));
+        }
+        let password_hash = hash_password(password)?;
+
+        match Entity::find()
+            .filter(Column::Username.eq(&username))
+            .one(db)
+            .await?
+        {
+            Some(user) => {
+                let mut user = user.into_active_model();
+                user.password_hash = Set(password_hash);
+                Ok(user.update(db).await?)
+            }
+            None => Ok(ActiveModel {
+                id: Set(Uuid::new_v4()),
+                username: Set(username),
+                password_hash: Set(password_hash),
+                created_at: Set(chrono::Utc::now()),
+                last_login: Set(None),
+            }
+            .insert(db)
+            .await?),
+        }
+    }
+
+    /// The user, if they exist and the password's right, recording when they logged in
+    pub async fn authenticate(
+        db: &DatabaseConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Model>, Error> {
+        let user = match Entity::find()
+            .filter(Column::Username.eq(super::normalize_name(username)))
+            .one(db)
+            .await?
+        {
+            Some(user) => user,
+            None => {
+                // take about as long as a wrong password would, so usernames can't be guessed by timing
+                hash_password(password)?;
+                return Ok(None);
+            }
+        };
+        if !verify_password(password, &user.password_hash)? {
+            return Ok(None);
+        }
+        let mut user = user.into_active_model();
+        user.last_login = Set(Some(chrono::Utc::now()));
+        Ok(Some(user.update(db).await?))
+    }
+
+    /// Removes the user, returns false if they didn't exist
+    pub async fn delete_by_username(
+        db: &DatabaseConnection,
+        username: &str,
+    ) -> Result<bool, Error> {
+        Ok(Entity::delete_many()
+            .filter(Column::Username.eq(super::normalize_name(username)))
+            .exec(db)
+            .await?
+            .rows_affected
+            > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("correct horse battery staple").expect("Failed to hash");
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("correct horse"));
+        assert!(verify_password("correct horse battery staple", &hash).expect("Failed to verify"));
+        assert!(!verify_password("wrong horse battery staple", &hash).expect("Failed to verify"));
+        assert!(verify_password("anything", "not a hash").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_user() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test db");
+
+        assert!(Entity::set_password(&db, "admin", "short").await.is_err());
+        assert!(
+            Entity::set_password(&db, " ", "correct horse battery staple")
+                .await
+                .is_err()
+        );
+
+        let user = Entity::set_password(&db, " admin ", "correct horse battery staple")
+            .await
+            .expect("Failed to create user");
+        assert_eq!(user.username, "admin");
+        assert!(user.last_login.is_none());
+
+        assert!(Entity::authenticate(&db, "admin", "wrong password!!")
+            .await
+            .expect("Failed to authenticate")
+            .is_none());
+        assert!(
+            Entity::authenticate(&db, "nobody", "correct horse battery staple")
+                .await
+                .expect("Failed to authenticate")
+                .is_none()
+        );
+        let logged_in = Entity::authenticate(&db, "admin", "correct horse battery staple")
+            .await
+            .expect("Failed to authenticate")
+            .expect("Should have logged in");
+        assert_eq!(logged_in.id, user.id);
+        assert!(logged_in.last_login.is_some());
+
+        // changing the password keeps the same user
+        let changed = Entity::set_password(&db, "admin", "a whole new password")
+            .await
+            .expect("Failed to change password");
+        assert_eq!(changed.id, user.id);
+        assert!(
+            Entity::authenticate(&db, "admin", "correct horse battery staple")
+                .await
+                .expect("Failed to authenticate")
+                .is_none()
+        );
+
+        assert!(Entity::delete_by_username(&db, "admin")
+            .await
+            .expect("Failed to delete"));
+        assert!(!Entity::delete_by_username(&db, "admin")
+            .await
+            .expect("Failed to delete"));
+    }
+}
@@ -1,18 +1,31 @@
 use crate::prelude::*;
 use sea_orm::prelude::*;
+use sea_orm::sea_query::{Func, SimpleExpr};
+use sea_orm::{PaginatorTrait, QueryFilter};
+use std::collections::{BTreeMap, BTreeSet};
 
+pub mod discovered_host;
 pub mod host;
 pub mod host_group;
 pub mod host_group_members;
+pub mod host_group_nesting;
+pub mod host_group_status;
+pub mod incident;
+pub mod local_user;
 pub mod service;
 pub mod service_check;
+pub mod service_check_escalation;
+pub mod service_check_heartbeat;
 pub mod service_check_history;
+pub mod service_check_output;
+pub mod service_check_rollup;
 pub mod service_group_link;
 pub mod service_v1;
 pub mod session;
 #[cfg(test)]
 pub mod tests;
 pub mod user;
+pub mod user_preferences;
 
 #[async_trait]
 pub trait MaremmaEntity {
@@ -25,3 +38,184 @@ pub trait MaremmaEntity {
     where
         Self: Sized;
 }
+
+/// Normalise a name before it's written to the database, so stray whitespace doesn't create duplicates
+pub fn normalize_name(name: &str) -> String {
+    name.trim().to_string()
+}
+
+/// The form of a name used for comparisons, trimmed and lower-cased
+pub fn name_key(name: &str) -> String {
+    normalize_name(name).to_lowercase()
+}
+
+/// Builds a filter that matches `column` against `name`, ignoring case and surrounding whitespace
+pub(crate) fn name_matches<C>(column: C, name: &str) -> SimpleExpr
+where
+    C: ColumnTrait,
+{
+    Expr::expr(Func::lower(Expr::col(column))).eq(name_key(name))
+}
+
+/// Turns a name into something that's nice to have in a URL, eg `DB 01.example.com` becomes `db-01-example-com`
+pub fn slugify(name: &str) -> String {
+    let slug = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if slug.is_empty() {
+        "unnamed".to_string()
+    } else {
+        slug
+    }
+}
+
+/// Finds a slug for `name` that isn't already used in `column`, adding `-2`, `-3` etc. until it's unique
+pub(crate) async fn unique_slug<E>(
+    db: &DatabaseConnection,
+    column: E::Column,
+    name: &str,
+) -> Result<String, Error>
+where
+    E: EntityTrait,
+{
+    let base = slugify(name);
+    let mut slug = base.clone();
+    let mut suffix = 2;
+    while E::find().filter(column.eq(slug.as_str())).count(db).await? > 0 {
+        slug = format!("{}-{}", base, suffix);
+        suffix += 1;
+    }
+    Ok(slug)
+}
+
+/// Builds a filter that matches a URL path segment against either the ID or the slug
+pub(crate) fn id_or_slug<C>(id_column: C, slug_column: C, value: &str) -> SimpleExpr
+where
+    C: ColumnTrait,
+{
+    match Uuid::parse_str(value) {
+        Ok(id) => id_column.eq(id).or(slug_column.eq(value)),
+        Err(_) => slug_column.eq(value),
+    }
+}
+
+/// Groups names by their normalised form and describes any that collide, eg `"Foo"` and `"foo "`
+pub(crate) fn find_duplicates<I, S>(kind: &str, names: I) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut spellings: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for name in names {
+        spellings
+            .entry(name_key(name.as_ref()))
+            .or_default()
+            .insert(name.as_ref().to_string());
+    }
+    spellings
+        .into_values()
+        .filter(|names| names.len() > 1)
+        .map(|names| {
+            format!(
+                "Duplicate {} names: {}",
+                kind,
+                names
+                    .iter()
+                    .map(|name| format!("{:?}", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod name_tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("  foo.example.com\t"), "foo.example.com");
+        assert_eq!(normalize_name("Foo Bar"), "Foo Bar");
+        assert_eq!(name_key(" Foo Bar "), "foo bar");
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("db-01"), "db-01");
+        assert_eq!(slugify(" DB 01.example.com "), "db-01-example-com");
+        assert_eq!(slugify("check_tls!!"), "check-tls");
+        assert_eq!(slugify("🐕"), "unnamed");
+    }
+
+    #[tokio::test]
+    async fn test_unique_slug_and_lookup() {
+        let (db, _config) = crate::db::tests::test_setup()
+            .await
+            .expect("Failed to set up test");
+
+        let existing = host::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts in test db");
+        assert!(!existing.slug.is_empty());
+
+        let slug = unique_slug::<host::Entity>(&db, host::Column::Slug, &existing.name)
+            .await
+            .expect("Failed to build slug");
+        assert_eq!(slug, format!("{}-2", existing.slug));
+
+        for value in [existing.id.to_string(), existing.slug.clone()] {
+            let found = host::Entity::find()
+                .filter(id_or_slug(host::Column::Id, host::Column::Slug, &value))
+                .one(&db)
+                .await
+                .expect("Failed to query hosts")
+                .expect("Failed to find host by id or slug");
+            assert_eq!(found.id, existing.id);
+        }
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let res = find_duplicates("host", ["foo", "Foo ", "bar", "baz", "BAZ"]);
+        assert_eq!(
+            res,
+            vec![
+                r#"Duplicate host names: "BAZ", "baz""#.to_string(),
+                r#"Duplicate host names: "Foo ", "foo""#.to_string(),
+            ]
+        );
+        assert!(find_duplicates("host", ["foo", "bar"]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_find_by_name_normalized() {
+        let (db, _config) = crate::db::tests::test_setup()
+            .await
+            .expect("Failed to set up test");
+
+        let service = service::Model::find_by_name("  LOCAL_lslah ", &db)
+            .await
+            .expect("Failed to query service")
+            .expect("Failed to find service with messy name");
+        assert_eq!(service.name, "local_lslah");
+
+        assert!(host::Model::find_by_name(" EXAMPLE.com", &db)
+            .await
+            .expect("Failed to query host")
+            .is_some());
+        assert!(host_group::Model::find_by_name("Check_TLS ", &db)
+            .await
+            .expect("Failed to query host group")
+            .is_some());
+    }
+}
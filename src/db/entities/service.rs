@@ -10,12 +10,21 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false, name = "id")]
     pub id: Uuid,
     pub name: String,
+    /// URL-friendly version of the name, generated when the service is created
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub slug: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     /// A list of host group names
     pub service_type: ServiceType,
     pub cron_schedule: String,
     pub extra_config: Json,
+    /// The agent name or zone this service is pinned to, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// Free-form `key: value` labels, see [crate::labels]
+    #[serde(default)]
+    pub labels: Json,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -55,12 +64,19 @@ impl Related<super::service_check::Entity> for Entity {
 
 impl ActiveModelBehavior for ActiveModel {}
 
+impl Model {
+    /// The service's labels, sorted by key
+    pub fn labels(&self) -> crate::labels::Labels {
+        crate::labels::from_json(&self.labels)
+    }
+}
+
 #[async_trait]
 impl MaremmaEntity for Model {
     #[instrument(level = "debug", skip(_db))]
     async fn find_by_name(name: &str, _db: &DatabaseConnection) -> Result<Option<Model>, Error> {
         Entity::find()
-            .filter(Column::Name.eq(name))
+            .filter(super::name_matches(Column::Name, name))
             .one(_db)
             .await
             .map_err(Into::into)
@@ -73,13 +89,20 @@ impl MaremmaEntity for Model {
     ) -> Result<(), Error> {
         for (service_name, service) in &config.read().await.services {
             // this is janky but we need to flatten it using serde to get the "extra" fields
-            let extra_config: Json = serde_json::to_value(service.extra_config.clone())
-                .inspect_err(|err| {
-                    error!(
-                        "Failed to convert extra_config into JSON for {} error={:?}",
-                        service_name, err
-                    )
-                })?;
+            let mut extra_config = service.extra_config.clone();
+            // there's no column for it, so it rides along with the config
+            if let Some(max_runtime) = service.max_runtime {
+                extra_config.insert("max_runtime".to_string(), json!(max_runtime));
+            }
+            if let Some(timezone) = &service.timezone {
+                extra_config.insert("timezone".to_string(), json!(timezone));
+            }
+            let extra_config: Json = serde_json::to_value(extra_config).inspect_err(|err| {
+                error!(
+                    "Failed to convert extra_config into JSON for {} error={:?}",
+                    service_name, err
+                )
+            })?;
 
             let mut service_value = serde_json::to_value(service).inspect_err(|err| {
                 error!(
@@ -102,7 +125,10 @@ impl MaremmaEntity for Model {
                         )));
                     };
                 }
-                service_object.insert("name".to_string(), json!(service_name));
+                service_object.insert(
+                    "name".to_string(),
+                    json!(super::normalize_name(service_name)),
+                );
                 service_object.insert("extra_config".to_string(), json!(extra_config));
             } else {
                 error!("Failed to convert service to object: {:?}", service_value);
@@ -114,15 +140,21 @@ impl MaremmaEntity for Model {
 
             debug!("Looking for {}", service_name);
             // check if we have one and add it if not
-            match Entity::find()
-                .filter(Column::Name.eq(service_name))
-                .one(db)
-                .await
-            {
+            match Model::find_by_name(service_name, db).await {
                 Ok(Some(res)) => {
                     debug!("found it!");
+                    let slug = match res.slug.is_empty() {
+                        true => {
+                            super::unique_slug::<Entity>(db, Column::Slug, service_name).await?
+                        }
+                        false => res.slug.clone(),
+                    };
+                    if let Some(service_object) = service_value.as_object_mut() {
+                        service_object.insert("slug".to_string(), json!(slug));
+                    }
                     let mut res = res.into_active_model();
-                    res.name.set_if_not_equals(service_name.clone());
+                    res.name
+                        .set_if_not_equals(super::normalize_name(service_name));
                     if let Err(err) = res.set_from_json(service_value) {
                         error!("Error setting service from json: {:?}", err);
                         return Err(err.into());
@@ -142,6 +174,15 @@ impl MaremmaEntity for Model {
                 }
                 Ok(None) => {
                     info!("Didn't find service name='{}' will create it", service_name);
+                    if let Some(service_object) = service_value.as_object_mut() {
+                        service_object.insert(
+                            "slug".to_string(),
+                            json!(
+                                super::unique_slug::<Entity>(db, Column::Slug, service_name)
+                                    .await?
+                            ),
+                        );
+                    }
                     // insert the service if we can't find it
                     let mut am = ActiveModel::new();
 
@@ -170,7 +211,7 @@ impl MaremmaEntity for Model {
                     Entity::insert(am).exec_with_returning(db).await?
                 }
 
-                Err(err) => return Err(err.into()),
+                Err(err) => return Err(err),
             };
         }
 
@@ -183,10 +224,13 @@ pub(crate) fn test_service() -> Model {
     Model {
         id: Uuid::new_v4(),
         name: "Test Service".to_string(),
+        slug: "test-service".to_string(),
         description: Some("Test Service Description".to_string()),
         service_type: crate::prelude::ServiceType::Cli,
         cron_schedule: "* * * * *".to_string(),
         extra_config: serde_json::json!({ "url": "http://localhost:8080" }).into(),
+        agent: None,
+        labels: json!({}),
     }
 }
 
@@ -214,29 +258,27 @@ mod tests {
     async fn test_service_entity() {
         let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
-        let db_writer = db.write().await;
-
         let service = test_service();
         info!("saving service... {:?}", &service);
         let am = service.clone().into_active_model();
-        super::Entity::insert(am).exec(&*db_writer).await.unwrap();
+        super::Entity::insert(am).exec(&db).await.unwrap();
 
         let service = super::Entity::find()
             .filter(super::Column::Id.eq(service.id))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .unwrap();
         info!("found it: {:?}", service);
 
         super::Entity::delete_by_id(service.id)
-            .exec(&*db_writer)
+            .exec(&db)
             .await
             .unwrap();
 
         assert!(super::Entity::find()
             .filter(super::Column::Id.eq("test_service".to_string()))
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .is_none());
@@ -248,7 +290,7 @@ mod tests {
 
         let service = super::Entity::find()
             .filter(super::Column::Name.eq("local_lslah".to_string()))
-            .one(&*db.read().await)
+            .one(&db)
             .await
             .unwrap()
             .unwrap();
@@ -262,7 +304,7 @@ mod tests {
 
         let service = super::Entity::find()
             .filter(super::Column::Name.eq("local_lslah".to_string()))
-            .one(&*db.read().await)
+            .one(&db)
             .await
             .unwrap()
             .expect("Couldn't find local_lslah");
@@ -290,13 +332,13 @@ mod tests {
             ),
         );
 
-        super::Model::update_db_from_config(&*db.write().await, Arc::new(RwLock::new(config)))
+        super::Model::update_db_from_config(&db, Arc::new(RwLock::new(config)))
             .await
             .expect("Failed to update db from config");
 
         let service = super::Entity::find()
             .filter(super::Column::Name.eq("local_lslah".to_string()))
-            .one(&*db.read().await)
+            .one(&db)
             .await
             .unwrap()
             .unwrap();
@@ -311,7 +353,7 @@ mod tests {
 
         let (service, groups) = super::Entity::find()
             .find_with_linked(crate::db::entities::service_group_link::ServiceToGroups)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to run query looking for a service with host groups")
             .into_iter()
@@ -325,17 +367,15 @@ mod tests {
     async fn test_find_related_service_to_service_check() {
         let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
-        let db_reader = db.read().await;
-
         let service = super::Entity::find()
-            .one(&*db_reader)
+            .one(&db)
             .await
             .expect("Failed to select service")
             .expect("Failed to find service");
 
         let service_checks = service
             .find_related(service_check::Entity)
-            .all(&*db_reader)
+            .all(&db)
             .await
             .expect("Failed to search for service_checks");
 
@@ -351,10 +391,13 @@ mod tests {
             .append_query_results([[super::Model {
                 id: Uuid::new_v4(),
                 name: "test service".to_string(),
+                slug: "test-service".to_string(),
                 description: None,
                 service_type: ServiceType::Cli,
                 cron_schedule: "@hourly".to_string(),
                 extra_config: json!({}),
+                agent: None,
+                labels: json!({}),
             }]])
             .into_connection();
 
@@ -1,10 +1,21 @@
+use std::collections::BTreeSet;
+
 use crate::prelude::*;
+use crate::services::parse_timezone;
+use chrono_tz::Tz;
 use entities::host::test_host;
-use entities::host_group;
 use rand::seq::IteratorRandom;
-use sea_orm::{FromQueryResult, JoinType, QuerySelect, Set, TryIntoModel};
+use sea_orm::prelude::Expr;
+use sea_orm::sea_query::{CaseStatement, SimpleExpr};
+use sea_orm::{
+    Condition, ConnectionTrait, FromQueryResult, JoinType, QuerySelect, Set, TransactionTrait,
+    TryIntoModel,
+};
 
-use super::{host, host_group_members, service, service_check_history, service_group_link};
+use super::{
+    host, host_group_members, host_group_nesting, service, service_check_history,
+    service_group_link,
+};
 
 #[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
 #[sea_orm(table_name = "service_check")]
@@ -17,6 +28,8 @@ pub struct Model {
     pub last_check: chrono::DateTime<chrono::Utc>,
     pub next_check: chrono::DateTime<chrono::Utc>,
     pub last_updated: chrono::DateTime<chrono::Utc>,
+    /// Set when the check was paused with an auto-resume time, the shepherd re-enables it after this
+    pub paused_until: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
@@ -70,12 +83,12 @@ impl Model {
     pub async fn set_status(
         &self,
         status: ServiceStatus,
-        db: Arc<RwLock<DatabaseConnection>>,
+        db: DatabaseConnection,
     ) -> Result<Self, Error> {
         let mut model = self.clone().into_active_model();
         model.status.set_if_not_equals(status);
         model
-            .save(&*db.write().await)
+            .save(&db)
             .await
             .map_err(|err| {
                 error!(
@@ -87,29 +100,354 @@ impl Model {
             .try_into_model()
             .map_err(Error::from)
     }
+
+    /// Atomically marks the check as running, returning `None` if another instance got to it first
+    ///
+    /// The update only matches if the check hasn't changed since it was read, so only one instance sharing the database can claim each run.
+    pub async fn claim(&self, db: &DatabaseConnection) -> Result<Option<Self>, Error> {
+        let claimed = Entity::update_many()
+            .col_expr(Column::Status, Expr::value(ServiceStatus::Checking))
+            .col_expr(Column::LastUpdated, Expr::value(chrono::Utc::now()))
+            .filter(Column::Id.eq(self.id))
+            .filter(Column::Status.ne(ServiceStatus::Checking))
+            .filter(Column::Status.eq(self.status))
+            .filter(Column::NextCheck.eq(self.next_check))
+            .exec_with_returning(db)
+            .await?;
+        Ok(claimed.into_iter().next())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Which service checks a bulk pause or resume applies to
+pub enum PauseTarget {
+    /// All the checks on a host
+    Host(Uuid),
+    /// A service's checks across all hosts
+    Service(Uuid),
+}
+
+impl PauseTarget {
+    fn condition(&self) -> Condition {
+        match self {
+            Self::Host(host_id) => Condition::all().add(Column::HostId.eq(*host_id)),
+            Self::Service(service_id) => Condition::all().add(Column::ServiceId.eq(*service_id)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+/// Something to do to a list of service checks at once
+pub enum BulkAction {
+    /// Set them to pending, so they run as soon as the check loop gets to them
+    Enable,
+    /// Stop them running until they're enabled
+    Disable,
+    /// Run them as soon as there's a free slot
+    Urgent,
+    /// Remove them
+    Delete,
+}
+
+impl BulkAction {
+    /// The status the checks end up with, none when they're deleted
+    pub fn status(self) -> Option<ServiceStatus> {
+        match self {
+            Self::Enable => Some(ServiceStatus::Pending),
+            Self::Disable => Some(ServiceStatus::Disabled),
+            Self::Urgent => Some(ServiceStatus::Urgent),
+            Self::Delete => None,
+        }
+    }
+}
+
+impl std::fmt::Display for BulkAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Enable => "enable",
+            Self::Disable => "disable",
+            Self::Urgent => "urgent",
+            Self::Delete => "delete",
+        })
+    }
+}
+
+impl Entity {
+    /// Does the action to all the checks in one transaction, if any of them don't exist nothing's changed
+    ///
+    /// Like changing a single check by hand, it takes them out of any bulk pause. Returns how many were changed.
+    pub async fn bulk(
+        db: &DatabaseConnection,
+        service_check_ids: &[Uuid],
+        action: BulkAction,
+    ) -> Result<u64, Error> {
+        let mut ids = service_check_ids.to_vec();
+        ids.sort();
+        ids.dedup();
+        if ids.is_empty() {
+            return Err(Error::InvalidInput(
+                "No service checks were selected".to_string(),
+            ));
+        }
+
+        let txn = db.begin().await?;
+        let found: Vec<Uuid> = Entity::find()
+            .select_only()
+            .column(Column::Id)
+            .filter(Column::Id.is_in(ids.iter().copied()))
+            .into_tuple()
+            .all(&txn)
+            .await?;
+        if let Some(missing) = ids.iter().find(|id| !found.contains(id)) {
+            return Err(Error::ServiceCheckNotFound(*missing));
+        }
+
+        let changed = match action.status() {
+            Some(status) => {
+                Entity::update_many()
+                    .col_expr(Column::Status, Expr::value(status))
+                    .col_expr(Column::PausedUntil, Expr::value(None::<DateTime<Utc>>))
+                    .col_expr(Column::LastUpdated, Expr::value(chrono::Utc::now()))
+                    .filter(Column::Id.is_in(ids.iter().copied()))
+                    .exec(&txn)
+                    .await?
+                    .rows_affected
+            }
+            None => {
+                Entity::delete_many()
+                    .filter(Column::Id.is_in(ids.iter().copied()))
+                    .exec(&txn)
+                    .await?
+                    .rows_affected
+            }
+        };
+        txn.commit().await?;
+
+        if action == BulkAction::Delete {
+            ids.into_iter().for_each(crate::schedule_insight::forget);
+        }
+        Ok(changed)
+    }
+
+    /// Disables all the target's checks in one statement, if `until` is set the shepherd re-enables them after it
+    ///
+    /// Checks which were already disabled by hand are left alone, so resuming automatically doesn't turn them back on.
+    pub async fn pause(
+        db: &DatabaseConnection,
+        target: PauseTarget,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<u64, Error> {
+        Ok(Entity::update_many()
+            .col_expr(Column::Status, Expr::value(ServiceStatus::Disabled))
+            .col_expr(Column::PausedUntil, Expr::value(until))
+            .col_expr(Column::LastUpdated, Expr::value(chrono::Utc::now()))
+            .filter(target.condition())
+            .filter(
+                Condition::any()
+                    .add(Column::Status.ne(ServiceStatus::Disabled))
+                    .add(Column::PausedUntil.is_not_null()),
+            )
+            .exec(db)
+            .await?
+            .rows_affected)
+    }
+
+    /// Re-enables all the target's disabled checks in one statement, they'll run as soon as the check loop gets to them
+    pub async fn resume(db: &DatabaseConnection, target: PauseTarget) -> Result<u64, Error> {
+        Ok(Entity::update_many()
+            .col_expr(Column::Status, Expr::value(ServiceStatus::Pending))
+            .col_expr(Column::PausedUntil, Expr::value(None::<DateTime<Utc>>))
+            .col_expr(Column::LastUpdated, Expr::value(chrono::Utc::now()))
+            .filter(target.condition())
+            .filter(Column::Status.eq(ServiceStatus::Disabled))
+            .exec(db)
+            .await?
+            .rows_affected)
+    }
+
+    /// Re-enables the paused checks whose auto-resume time is before `now`
+    pub async fn resume_expired(db: &DatabaseConnection, now: DateTime<Utc>) -> Result<u64, Error> {
+        Ok(Entity::update_many()
+            .col_expr(Column::Status, Expr::value(ServiceStatus::Pending))
+            .col_expr(Column::PausedUntil, Expr::value(None::<DateTime<Utc>>))
+            .col_expr(Column::LastUpdated, Expr::value(now))
+            .filter(Column::Status.eq(ServiceStatus::Disabled))
+            .filter(Column::PausedUntil.lte(now))
+            .exec(db)
+            .await?
+            .rows_affected)
+    }
+}
+
+/// A fixed offset for a service check, somewhere in its schedule's interval, so checks on the same schedule don't all run at once
+pub(crate) fn phase_offset(
+    cron: &Cron,
+    service_id: Uuid,
+    host_id: Uuid,
+) -> Result<TimeDelta, Error> {
+    let interval = cron_interval(cron)?;
+    if interval <= 1 {
+        return Ok(TimeDelta::zero());
+    }
+    // the IDs are random, so mixing them gives a stable but well spread number
+    let seed = service_id.as_u128() ^ host_id.as_u128().rotate_left(64);
+    Ok(TimeDelta::seconds((seed % interval as u128) as i64))
+}
+
+/// How many seconds apart the schedule's runs are, measured from a fixed point so it doesn't move around between runs
+pub(crate) fn cron_interval(cron: &Cron) -> Result<i64, Error> {
+    let first = cron.find_next_occurrence(&DateTime::<Utc>::UNIX_EPOCH, false)?;
+    let second = cron.find_next_occurrence(&first, false)?;
+    Ok((second - first).num_seconds())
+}
+
+/// When a new or overdue check should first run, somewhere in the next period of its schedule picked by its ID
+pub(crate) fn initial_check_time(
+    cron: &Cron,
+    check_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>, Error> {
+    let interval = cron_interval(cron)?;
+    if interval <= 1 {
+        return Ok(now);
+    }
+    Ok(now + TimeDelta::seconds((check_id.as_u128() % interval as u128) as i64))
+}
+
+/// Staggers the checks which are due now across their schedules, so they don't all run at once after a start or reload.
+///
+/// Urgent, pending, checking and disabled checks are left alone, returns how many were moved.
+pub(crate) async fn spread_due_checks(
+    db: &DatabaseConnection,
+    now: DateTime<Utc>,
+) -> Result<u64, Error> {
+    let due = Entity::find()
+        .find_also_related(service::Entity)
+        .filter(Column::NextCheck.lte(now))
+        .filter(Column::Status.is_not_in([
+            ServiceStatus::Urgent,
+            ServiceStatus::Pending,
+            ServiceStatus::Checking,
+            ServiceStatus::Disabled,
+        ]))
+        .all(db)
+        .await?;
+
+    let mut spread = 0;
+    for (check, service) in due {
+        let Some(service) = service else {
+            continue;
+        };
+        let cron = match Cron::new(&service.cron_schedule).parse() {
+            Ok(cron) => cron,
+            Err(err) => {
+                warn!(
+                    "Not spreading service_check={}, can't parse the cron schedule for {}: {:?}",
+                    check.id.hyphenated(),
+                    service.name,
+                    err
+                );
+                continue;
+            }
+        };
+        let next_check = initial_check_time(&cron, check.id, now)?;
+        if next_check == check.next_check {
+            continue;
+        }
+        let check_id = check.id;
+        let mut check = check.into_active_model();
+        check.next_check.set_if_not_equals(next_check);
+        check.update(db).await?;
+        crate::schedule_insight::record(
+            check_id,
+            crate::schedule_insight::ScheduleDecision {
+                decided_at: now,
+                reason: "Spread out after a start or reload".to_string(),
+                next_check,
+                jitter: 0,
+                max_jitter: 0,
+            },
+        );
+        spread += 1;
+    }
+    Ok(spread)
+}
+
+/// The timezone the service's schedule is evaluated in, the host's config for the service can override the service's
+pub(crate) fn schedule_timezone(service: &service::Model, host: Option<&host::Model>) -> Tz {
+    let host_timezone = host.and_then(|host| {
+        host.config
+            .get(&service.name)
+            .and_then(|config| config.get("timezone"))
+            .and_then(|timezone| timezone.as_str())
+    });
+    let service_timezone = service
+        .extra_config
+        .get("timezone")
+        .and_then(|timezone| timezone.as_str());
+
+    for timezone in [host_timezone, service_timezone].into_iter().flatten() {
+        match parse_timezone(timezone) {
+            Ok(tz) => return tz,
+            Err(err) => warn!("Ignoring timezone for {}: {:?}", service.name, err),
+        }
+    }
+    Tz::UTC
+}
+
+/// When the check should next run in the schedule's timezone, shifted by its [phase_offset]
+pub(crate) fn next_check_time(
+    cron: &Cron,
+    timezone: Tz,
+    service_id: Uuid,
+    host_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>, Error> {
+    let offset = phase_offset(cron, service_id, host_id)?;
+    let next = cron.find_next_occurrence(&(now - offset).with_timezone(&timezone), false)?;
+    Ok(next.with_timezone(&Utc) + offset)
 }
 
 #[instrument(skip_all, fields(service_check_id = model.id.to_string(), status=format!("{}", status)))]
-pub async fn set_check_result(
+pub async fn set_check_result<C: ConnectionTrait>(
     model: Model,
     service: &service::Model,
     last_check: chrono::DateTime<chrono::Utc>,
     status: ServiceStatus,
-    db: &DatabaseConnection,
+    db: &C,
     jitter: u32,
 ) -> Result<(), Error> {
+    let service_check_id = model.id;
+    let service_id = model.service_id;
+    let host_id = model.host_id;
     let mut model = model.into_active_model();
     model.last_check.set_if_not_equals(last_check);
     model.status.set_if_not_equals(status);
 
     // get a number between 0 and jitter
+    let max_jitter = jitter;
     let jitter: i64 = (0..jitter).choose(&mut rand::thread_rng()).unwrap_or(0) as i64;
 
-    let next_check = Cron::new(&service.cron_schedule)
-        .parse()?
-        .find_next_occurrence(&chrono::Utc::now(), false)?
-        + chrono::Duration::seconds(jitter);
+    let host = host::Entity::find_by_id(host_id).one(db).await?;
+    let next_check = next_check_time(
+        &Cron::new(&service.cron_schedule).parse()?,
+        schedule_timezone(service, host.as_ref()),
+        service_id,
+        host_id,
+        chrono::Utc::now(),
+    )? + chrono::Duration::seconds(jitter);
     model.next_check.set_if_not_equals(next_check);
+    crate::schedule_insight::record(
+        service_check_id,
+        crate::schedule_insight::ScheduleDecision {
+            decided_at: last_check,
+            reason: format!("After a {} result", status),
+            next_check,
+            jitter,
+            max_jitter,
+        },
+    );
 
     if model.is_changed() {
         debug!("Saving {:?}", model);
@@ -142,6 +480,7 @@ async fn update_local_services_from_db(
                     // setting it to all-zeros makes it clearer it's special
                     id: Uuid::from_u128(0),
                     name: crate::LOCAL_SERVICE_HOST_NAME.to_string(),
+                    slug: super::slugify(crate::LOCAL_SERVICE_HOST_NAME),
                     hostname: crate::LOCAL_SERVICE_HOST_NAME.to_string(),
                     check: crate::host::HostCheck::None,
                     ..test_host()
@@ -185,6 +524,7 @@ async fn update_local_services_from_db(
                     last_check: chrono::Utc::now(),
                     next_check: chrono::Utc::now(),
                     last_updated: chrono::Utc::now(),
+                    paused_until: None,
                 }
                 .into_active_model(),
             )
@@ -211,16 +551,14 @@ impl MaremmaEntity for Model {
         config: SendableConfig,
     ) -> Result<(), Error> {
         debug!("Starting update of service checks");
+        let spread_initial_checks = config.read().await.spread_initial_checks;
         // the easy ones are the locals.
         info!("Starting local updates...");
         update_local_services_from_db(db, config).await?;
 
         info!("Starting remote updates...");
         // now we're doing the other services!
-        let services: Vec<(service::Model, Vec<host_group::Model>)> = service::Entity::find()
-            .find_with_linked(service_group_link::ServiceToGroups)
-            .all(db)
-            .await?;
+        let services = service::Entity::find().all(db).await?;
 
         if services.is_empty() {
             error!("No services found, skipping service check update");
@@ -229,79 +567,129 @@ impl MaremmaEntity for Model {
             debug!("Found {} services", services.len());
         }
 
-        for (service, host_groups) in services.into_iter() {
+        for service in services.into_iter() {
             let service_id = service.id;
 
             debug!("Checking groups for service: {}", service.name);
-            for host_group in host_groups {
-                debug!(
-                    "Service {} checking group {}",
-                    service.name, host_group.name
-                );
-                // get the group data
+            let (excluded, included): (Vec<_>, Vec<_>) = service_group_link::Entity::find()
+                .filter(service_group_link::Column::ServiceId.eq(service_id))
+                .all(db)
+                .await?
+                .into_iter()
+                .partition(|link| link.exclude);
+            let excluded_hosts = hosts_in_groups(db, &excluded).await?;
+            let host_ids = hosts_in_groups(db, &included)
+                .await?
+                .difference(&excluded_hosts)
+                .copied()
+                .collect::<BTreeSet<_>>();
 
-                let host_group_members = host_group
-                    .find_linked(host_group_members::GroupToHosts)
+            // hosts in an excluded group lose any checks they had from before it was excluded
+            if !excluded_hosts.is_empty() {
+                let removed = Entity::find()
+                    .filter(Column::ServiceId.eq(service_id))
+                    .filter(Column::HostId.is_in(excluded_hosts.iter().copied()))
                     .all(db)
                     .await?;
-                for host_group_member in host_group_members {
-                    // check if we have the service check
-                    match Entity::find()
-                        .filter(Column::HostId.eq(host_group_member.id))
-                        .filter(Column::ServiceId.eq(service.id))
-                        .one(db)
-                        .await
-                        .map_err(Error::from)?
-                    {
-                        None => {
-                            info!(
-                                "Adding service check for service {} on host {:?}",
-                                service.name, host_group_member
-                            );
-                            let model = ActiveModel {
-                                id: Set(Uuid::new_v4()),
-                                service_id: Set(service_id),
-                                host_id: Set(host_group_member.id),
-                                status: Set(ServiceStatus::Unknown),
-                                last_check: Set(chrono::Utc::now()),
-                                next_check: Set(chrono::Utc::now()),
-                                last_updated: Set(chrono::Utc::now()),
-                            };
-                            debug!("Inserting... {:?}", model);
-                            model.insert(db).await.map_err(Error::from)?;
-                            debug!("Done!");
-                        }
-                        Some(service_check) => {
-                            debug!("Found existing service check: {:?}", service_check);
-                            let mut service_check = service_check.into_active_model();
-                            // if the service has been in checking for more than 10 seconds, we'll reset it.
-                            if let sea_orm::ActiveValue::Set(last_check) =
-                                service_check.last_check.clone()
-                            {
-                                if last_check + chrono::Duration::seconds(5) < chrono::Utc::now() {
-                                    if let sea_orm::ActiveValue::Set(ServiceStatus::Checking) =
-                                        service_check.status
-                                    {
-                                        service_check
-                                            .status
-                                            .set_if_not_equals(ServiceStatus::Unknown);
-                                    }
-                                }
+                for service_check in removed {
+                    info!(
+                        "Removing service check for service {} on excluded host {}",
+                        service.name, service_check.host_id
+                    );
+                    Entity::delete_by_id(service_check.id).exec(db).await?;
+                    crate::schedule_insight::forget(service_check.id);
+                }
+            }
 
-                                if service_check.is_changed() {
-                                    service_check.save(db).await.map_err(Error::from)?;
+            for host_id in host_ids {
+                // check if we have the service check
+                match Entity::find()
+                    .filter(Column::HostId.eq(host_id))
+                    .filter(Column::ServiceId.eq(service.id))
+                    .one(db)
+                    .await
+                    .map_err(Error::from)?
+                {
+                    None => {
+                        info!(
+                            "Adding service check for service {} on host {}",
+                            service.name, host_id
+                        );
+                        let model = ActiveModel {
+                            id: Set(Uuid::new_v4()),
+                            service_id: Set(service_id),
+                            host_id: Set(host_id),
+                            status: Set(ServiceStatus::Unknown),
+                            last_check: Set(chrono::Utc::now()),
+                            next_check: Set(chrono::Utc::now()),
+                            last_updated: Set(chrono::Utc::now()),
+                            paused_until: Set(None),
+                        };
+                        debug!("Inserting... {:?}", model);
+                        model.insert(db).await.map_err(Error::from)?;
+                        debug!("Done!");
+                    }
+                    Some(service_check) => {
+                        debug!("Found existing service check: {:?}", service_check);
+                        let mut service_check = service_check.into_active_model();
+                        // if the service has been in checking for more than 10 seconds, we'll reset it.
+                        if let sea_orm::ActiveValue::Set(last_check) =
+                            service_check.last_check.clone()
+                        {
+                            if last_check + chrono::Duration::seconds(5) < chrono::Utc::now() {
+                                if let sea_orm::ActiveValue::Set(ServiceStatus::Checking) =
+                                    service_check.status
+                                {
+                                    service_check
+                                        .status
+                                        .set_if_not_equals(ServiceStatus::Unknown);
                                 }
                             }
+
+                            if service_check.is_changed() {
+                                service_check.save(db).await.map_err(Error::from)?;
+                            }
                         }
                     }
                 }
             }
         }
 
+        if spread_initial_checks {
+            let spread = spread_due_checks(db, chrono::Utc::now()).await?;
+            info!(
+                "Spread {} due service checks across their schedules",
+                spread
+            );
+        }
+
         Ok(())
     }
 }
 
+/// The hosts in the linked groups, including the groups nested inside them
+async fn hosts_in_groups(
+    db: &DatabaseConnection,
+    links: &[service_group_link::Model],
+) -> Result<BTreeSet<Uuid>, Error> {
+    if links.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+    // groups can include other groups, so their hosts get the service too
+    let group_ids = host_group_nesting::Entity::expand(
+        db,
+        &links.iter().map(|link| link.group_id).collect::<Vec<_>>(),
+    )
+    .await?;
+    Ok(host_group_members::Entity::find()
+        .filter(host_group_members::Column::GroupId.is_in(group_ids))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|member| member.host_id)
+        .collect())
+}
+
 /// For when you want to see all the details of a service check
 #[derive(Clone, Debug, PartialEq, Eq, FromQueryResult)]
 
@@ -310,15 +698,24 @@ pub struct FullServiceCheck {
     pub service_name: String,
     pub service_type: ServiceType,
     pub service_id: Uuid,
+    pub service_slug: String,
     pub host_id: Uuid,
     pub host_name: String,
+    pub host_slug: String,
+    pub host_display_name: Option<String>,
 
     pub last_check: DateTime<Utc>,
     pub next_check: DateTime<Utc>,
     pub status: ServiceStatus,
+    pub paused_until: Option<DateTime<Utc>>,
 }
 
 impl FullServiceCheck {
+    /// The host's name in the UI, see [entities::host::display_name]
+    pub fn host_display_name(&self) -> &str {
+        entities::host::display_name(&self.host_name, self.host_display_name.as_deref())
+    }
+
     pub async fn all(db: &DatabaseConnection) -> Result<Vec<Self>, Error> {
         Self::all_query()
             .into_model::<FullServiceCheck>()
@@ -331,13 +728,30 @@ impl FullServiceCheck {
         Entity::find()
             .column_as(service::Column::Id, "service_id")
             .column_as(service::Column::Name, "service_name")
+            .column_as(service::Column::Slug, "service_slug")
             .column_as(host::Column::Id, "host_id")
             .column_as(host::Column::Hostname, "host_name")
+            .column_as(host::Column::Slug, "host_slug")
+            .column_as(host::Column::DisplayName, "host_display_name")
             .column_as(service::Column::ServiceType, "service_type")
             .join(JoinType::LeftJoin, Relation::Service.def())
             .join(JoinType::LeftJoin, Relation::Host.def())
     }
 
+    /// For sorting by status in the database, the same way [ServiceStatus]'s `Ord` does
+    pub fn status_rank() -> SimpleExpr {
+        use sea_orm::Iterable;
+        ServiceStatus::iter()
+            .fold(CaseStatement::new(), |case, status| {
+                case.case(
+                    Column::Status.eq(status),
+                    Expr::value(i32::from(i8::from(status))),
+                )
+            })
+            .finally(Expr::value(0))
+            .into()
+    }
+
     pub fn get_by_service_id_query(service_id: Uuid) -> Select<Entity> {
         Self::all_query().filter(service::Column::Id.eq(service_id))
     }
@@ -356,7 +770,7 @@ impl FullServiceCheck {
 
 #[cfg(test)]
 mod tests {
-    use sea_orm::{EntityTrait, ModelTrait};
+    use sea_orm::{ColumnTrait, EntityTrait, ModelTrait, QueryFilter};
     use uuid::Uuid;
 
     use crate::config::Configuration;
@@ -364,13 +778,183 @@ mod tests {
     use crate::db::{entities, MaremmaEntity};
     use crate::errors::Error;
 
+    #[test]
+    fn test_phase_offset() {
+        let cron: croner::Cron = "@hourly".parse().expect("Failed to parse cron");
+        let service_id = Uuid::new_v4();
+
+        let offsets: Vec<_> = (0..100)
+            .map(|_| {
+                super::phase_offset(&cron, service_id, Uuid::new_v4())
+                    .expect("Failed to get offset")
+            })
+            .collect();
+        assert!(offsets
+            .iter()
+            .all(|offset| offset.num_seconds() >= 0 && offset.num_seconds() < 3600));
+        // they shouldn't all land in the same minute
+        assert!(offsets
+            .iter()
+            .any(|offset| offset.num_minutes() != offsets[0].num_minutes()));
+
+        // the same pair always gets the same offset, and lands on the same phase each hour
+        let host_id = Uuid::new_v4();
+        let offset = super::phase_offset(&cron, service_id, host_id).expect("Failed to get offset");
+        assert_eq!(
+            offset,
+            super::phase_offset(&cron, service_id, host_id).expect("Failed to get offset")
+        );
+        let now = chrono::Utc::now();
+        let next = super::next_check_time(&cron, chrono_tz::Tz::UTC, service_id, host_id, now)
+            .expect("Failed to get next check");
+        assert!(next > now);
+        assert!(next <= now + chrono::TimeDelta::hours(1));
+        assert_eq!((next - offset).timestamp() % 3600, 0);
+        let after = super::next_check_time(&cron, chrono_tz::Tz::UTC, service_id, host_id, next)
+            .expect("Failed to get next check");
+        assert_eq!(after - next, chrono::TimeDelta::hours(1));
+    }
+
+    #[test]
+    fn test_next_check_timezone() {
+        use chrono::Timelike;
+
+        let cron: croner::Cron = "0 9 * * *".parse().expect("Failed to parse cron");
+        let (service_id, host_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let offset = super::phase_offset(&cron, service_id, host_id).expect("Failed to get offset");
+        let now = chrono::Utc::now();
+
+        let mut service = entities::service::test_service();
+        service.extra_config = serde_json::json!({"timezone": "Europe/Berlin"});
+        let mut host = entities::host::test_host();
+        assert_eq!(
+            super::schedule_timezone(&service, Some(&host)),
+            chrono_tz::Europe::Berlin
+        );
+        host.config =
+            serde_json::json!({ (service.name.clone()): {"timezone": "Australia/Brisbane"} });
+        let timezone = super::schedule_timezone(&service, Some(&host));
+        assert_eq!(timezone, chrono_tz::Australia::Brisbane);
+
+        let next = super::next_check_time(&cron, timezone, service_id, host_id, now)
+            .expect("Failed to get next check");
+        assert_eq!((next - offset).with_timezone(&timezone).hour(), 9);
+
+        // bad timezones fall back to UTC
+        service.extra_config = serde_json::json!({"timezone": "Mars/Olympus_Mons"});
+        assert_eq!(super::schedule_timezone(&service, None), chrono_tz::Tz::UTC);
+    }
+
+    #[test]
+    fn test_initial_check_time() {
+        let cron: croner::Cron = "@hourly".parse().expect("Failed to parse cron");
+        let now = chrono::Utc::now();
+        let check_id = Uuid::new_v4();
+
+        let first = super::initial_check_time(&cron, check_id, now).expect("Failed to spread");
+        assert!(first >= now);
+        assert!(first < now + chrono::TimeDelta::hours(1));
+        assert_eq!(
+            first,
+            super::initial_check_time(&cron, check_id, now).expect("Failed to spread")
+        );
+
+        // spread over the hour, not bunched up at the start of it
+        let spread: Vec<_> = (0..100)
+            .map(|_| super::initial_check_time(&cron, Uuid::new_v4(), now).expect("Failed"))
+            .collect();
+        assert!(spread
+            .iter()
+            .any(|time| *time - now > chrono::TimeDelta::minutes(5)));
+    }
+
+    #[tokio::test]
+    async fn test_spread_due_checks() {
+        use crate::prelude::ServiceStatus;
+        use sea_orm::prelude::Expr;
+        use sea_orm::{ColumnTrait, PaginatorTrait, QueryFilter};
+
+        let (db, config) = test_setup().await.expect("Failed to setup test");
+        let start = chrono::Utc::now();
+
+        super::Entity::update_many()
+            .col_expr(super::Column::Status, Expr::value(ServiceStatus::Unknown))
+            .col_expr(
+                super::Column::NextCheck,
+                Expr::value(start - chrono::TimeDelta::hours(1)),
+            )
+            .exec(&db)
+            .await
+            .expect("Failed to make the checks overdue");
+        let urgent = super::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query checks")
+            .expect("No checks found");
+        super::Entity::update_many()
+            .col_expr(super::Column::Status, Expr::value(ServiceStatus::Urgent))
+            .filter(super::Column::Id.eq(urgent.id))
+            .exec(&db)
+            .await
+            .expect("Failed to make a check urgent");
+
+        config.write().await.spread_initial_checks = true;
+        super::Model::update_db_from_config(&db, config.clone())
+            .await
+            .expect("Failed to update service checks");
+
+        let overdue = super::Entity::find()
+            .filter(super::Column::NextCheck.lt(start))
+            .all(&db)
+            .await
+            .expect("Failed to query checks");
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0].id, urgent.id);
+        assert!(
+            super::Entity::find()
+                .count(&db)
+                .await
+                .expect("Failed to count checks")
+                > 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claim() {
+        use crate::prelude::ServiceStatus;
+        use sea_orm::{ColumnTrait, QueryFilter};
+
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+
+        let service_check = super::Entity::find()
+            .filter(super::Column::Status.ne(ServiceStatus::Checking))
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+
+        let claimed = service_check
+            .claim(&db)
+            .await
+            .expect("Failed to claim service check")
+            .expect("Should have claimed the service check");
+        assert_eq!(claimed.id, service_check.id);
+        assert_eq!(claimed.status, ServiceStatus::Checking);
+
+        // another instance working from the same read loses
+        assert!(service_check
+            .claim(&db)
+            .await
+            .expect("Failed to claim service check")
+            .is_none());
+    }
+
     #[tokio::test]
     async fn test_find_by_name() {
         // this should error
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
-        let res = super::Model::find_by_name("test", &*db.read().await).await;
+        let res = super::Model::find_by_name("test", &db).await;
 
         assert!(res.is_err());
         assert_eq!(res.err().unwrap(), Error::NotImplemented);
@@ -379,12 +963,11 @@ mod tests {
     #[tokio::test]
     // test that service_checks auto-delete because they're linked to services/hosts via foreign keys
     async fn test_delete_service_checks_when_service_deleted() {
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
         let (service_check, services) = entities::service_check::Entity::find()
             .find_with_related(entities::service::Entity)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to find service")
             .into_iter()
@@ -396,13 +979,10 @@ mod tests {
             .expect("Failed to get a single service");
 
         let service_check_id = service_check.id;
-        service
-            .delete(&*db.write().await)
-            .await
-            .expect("Failed to delete service");
+        service.delete(&db).await.expect("Failed to delete service");
 
         let res = entities::service_check::Entity::find_by_id(service_check_id)
-            .one(&*db.read().await)
+            .one(&db)
             .await
             .expect("Failed to find service_check");
 
@@ -422,6 +1002,7 @@ mod tests {
                 last_check: chrono::Utc::now(),
                 next_check: chrono::Utc::now(),
                 last_updated: chrono::Utc::now(),
+                paused_until: None,
             }]])
             .into_connection();
 
@@ -434,21 +1015,140 @@ mod tests {
 
     #[tokio::test]
     async fn test_from_host_to_service_checks() {
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
         let host = entities::host::Entity::find()
-            .one(&*db.read().await)
+            .one(&db)
             .await
             .unwrap()
             .unwrap();
 
-        let service_checks = host
-            .find_related(super::Entity)
-            .all(&*db.read().await)
-            .await
-            .unwrap();
+        let service_checks = host.find_related(super::Entity).all(&db).await.unwrap();
 
         assert!(!service_checks.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_excluded_host_groups() {
+        let (db, config) = test_setup().await.expect("Failed to start test harness");
+
+        let host = entities::host::Model::find_by_name("example.com", &db)
+            .await
+            .expect("Failed to query host")
+            .expect("Failed to find host");
+        let service = entities::service::Model::find_by_name("ping_check", &db)
+            .await
+            .expect("Failed to query service")
+            .expect("Failed to find service");
+        let find_check = || {
+            super::Entity::find()
+                .filter(super::Column::HostId.eq(host.id))
+                .filter(super::Column::ServiceId.eq(service.id))
+                .one(&db)
+        };
+        assert!(find_check()
+            .await
+            .expect("Failed to query checks")
+            .is_some());
+
+        // example.com is in check_tls, so excluding it takes the check away
+        if let Some(service) = config.write().await.services.get_mut("ping_check") {
+            service.host_groups = vec!["check_ntp_time".to_string(), "!check_tls".to_string()];
+        }
+        entities::service_group_link::Model::update_db_from_config(&db, config.clone())
+            .await
+            .expect("Failed to update service group links");
+        super::Model::update_db_from_config(&db, config)
+            .await
+            .expect("Failed to update service checks");
+        assert!(find_check()
+            .await
+            .expect("Failed to query checks")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_status_rank() {
+        use sea_orm::{ActiveModelTrait, IntoActiveModel, QueryOrder, Set};
+
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
+        let check = super::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query checks")
+            .expect("No checks found");
+        let mut active = check.clone().into_active_model();
+        active.status = Set(crate::services::ServiceStatus::Critical);
+        active.update(&db).await.expect("Failed to update check");
+
+        let worst = super::FullServiceCheck::all_query()
+            .order_by(super::FullServiceCheck::status_rank(), sea_orm::Order::Desc)
+            .into_model::<super::FullServiceCheck>()
+            .one(&db)
+            .await
+            .expect("Failed to query checks")
+            .expect("No checks found");
+        assert_eq!(worst.status, crate::services::ServiceStatus::Critical);
+    }
+
+    #[tokio::test]
+    async fn test_bulk() {
+        use super::BulkAction;
+        use crate::prelude::ServiceStatus;
+        use sea_orm::{ColumnTrait, QueryFilter, QuerySelect};
+
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+        let checks = super::Entity::find()
+            .limit(2)
+            .all(&db)
+            .await
+            .expect("Failed to query checks");
+        assert_eq!(checks.len(), 2);
+        let ids: Vec<Uuid> = checks.iter().map(|check| check.id).collect();
+
+        assert!(super::Entity::bulk(&db, &[], BulkAction::Disable)
+            .await
+            .is_err());
+
+        // one missing check means none of them change
+        let missing = Uuid::new_v4();
+        assert_eq!(
+            super::Entity::bulk(&db, &[ids[0], missing], BulkAction::Urgent).await,
+            Err(Error::ServiceCheckNotFound(missing))
+        );
+        let unchanged = super::Entity::find_by_id(ids[0])
+            .one(&db)
+            .await
+            .expect("Failed to query check")
+            .expect("Check went missing");
+        assert_eq!(unchanged.status, checks[0].status);
+
+        assert_eq!(
+            super::Entity::bulk(&db, &[ids[0], ids[1], ids[0]], BulkAction::Disable)
+                .await
+                .expect("Failed to disable checks"),
+            2
+        );
+        let disabled = super::Entity::find()
+            .filter(super::Column::Id.is_in(ids.clone()))
+            .all(&db)
+            .await
+            .expect("Failed to query checks");
+        assert!(disabled
+            .iter()
+            .all(|check| check.status == ServiceStatus::Disabled));
+
+        assert_eq!(
+            super::Entity::bulk(&db, &ids, BulkAction::Delete)
+                .await
+                .expect("Failed to delete checks"),
+            2
+        );
+        assert!(super::Entity::find()
+            .filter(super::Column::Id.is_in(ids))
+            .all(&db)
+            .await
+            .expect("Failed to query checks")
+            .is_empty());
+    }
 }
@@ -0,0 +1,233 @@
+//! How far a critical service check has been escalated, and who's acknowledged it
+
+use entities::service_check::{self, FullServiceCheck};
+use sea_orm::prelude::Expr;
+use sea_orm::{QuerySelect, QueryTrait, Set};
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "service_check_escalation")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub service_check_id: Uuid,
+    /// When the shepherd first saw the check as critical
+    pub started_at: DateTime<Utc>,
+    /// How many of the policy's steps have been sent
+    pub level: i32,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    pub acknowledged_by: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    ServiceCheck,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::ServiceCheck => Entity::belongs_to(service_check::Entity)
+                .from(Column::ServiceCheckId)
+                .to(service_check::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl Related<service_check::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ServiceCheck.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// The IDs of all the critical service checks
+fn critical_checks() -> sea_orm::sea_query::SelectStatement {
+    service_check::Entity::find()
+        .select_only()
+        .column(service_check::Column::Id)
+        .filter(service_check::Column::Status.eq(ServiceStatus::Critical))
+        .into_query()
+}
+
+impl Entity {
+    /// Starts tracking checks which have gone critical, and forgets the ones that have recovered
+    pub async fn track(db: &DatabaseConnection, now: DateTime<Utc>) -> Result<(), Error> {
+        let recovered = Entity::delete_many()
+            .filter(Column::ServiceCheckId.not_in_subquery(critical_checks()))
+            .exec(db)
+            .await?
+            .rows_affected;
+        if recovered > 0 {
+            debug!("{} escalated service checks have recovered", recovered);
+        }
+
+        let new = service_check::Entity::find()
+            .filter(service_check::Column::Status.eq(ServiceStatus::Critical))
+            .filter(
+                service_check::Column::Id.not_in_subquery(
+                    Entity::find()
+                        .select_only()
+                        .column(Column::ServiceCheckId)
+                        .into_query(),
+                ),
+            )
+            .all(db)
+            .await?;
+        if !new.is_empty() {
+            Entity::insert_many(new.into_iter().map(|check| ActiveModel {
+                service_check_id: Set(check.id),
+                started_at: Set(now),
+                level: Set(0),
+                acknowledged_at: Set(None),
+                acknowledged_by: Set(None),
+            }))
+            .exec_without_returning(db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// The escalations nobody's acknowledged yet, with the check they're for
+    pub async fn unacknowledged(
+        db: &DatabaseConnection,
+    ) -> Result<Vec<(Model, FullServiceCheck)>, Error> {
+        let escalations = Entity::find()
+            .filter(Column::AcknowledgedAt.is_null())
+            .all(db)
+            .await?;
+        if escalations.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut checks = FullServiceCheck::all_query()
+            .filter(
+                service_check::Column::Id.is_in(
+                    escalations
+                        .iter()
+                        .map(|escalation| escalation.service_check_id),
+                ),
+            )
+            .into_model::<FullServiceCheck>()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|check| (check.id, check))
+            .collect::<HashMap<_, _>>();
+        Ok(escalations
+            .into_iter()
+            .filter_map(|escalation| {
+                checks
+                    .remove(&escalation.service_check_id)
+                    .map(|check| (escalation, check))
+            })
+            .collect())
+    }
+
+    /// Records that a level's been sent
+    pub async fn set_level(
+        db: &DatabaseConnection,
+        service_check_id: Uuid,
+        level: i32,
+    ) -> Result<(), Error> {
+        Entity::update_many()
+            .col_expr(Column::Level, Expr::value(level))
+            .filter(Column::ServiceCheckId.eq(service_check_id))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Stops the check escalating any further, returns false if it isn't escalating or someone's already acknowledged it
+    pub async fn acknowledge(
+        db: &DatabaseConnection,
+        service_check_id: Uuid,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        Ok(Entity::update_many()
+            .col_expr(Column::AcknowledgedAt, Expr::value(now))
+            .col_expr(Column::AcknowledgedBy, Expr::value(username))
+            .filter(Column::ServiceCheckId.eq(service_check_id))
+            .filter(Column::AcknowledgedAt.is_null())
+            .exec(db)
+            .await?
+            .rows_affected
+            > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    async fn set_status(
+        db: &DatabaseConnection,
+        check: &service_check::Model,
+        status: ServiceStatus,
+    ) {
+        let mut check = check.clone().into_active_model();
+        check.status = Set(status);
+        check.update(db).await.expect("Failed to update check");
+    }
+
+    #[tokio::test]
+    async fn test_escalation_tracking() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let check = service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        set_status(&db, &check, ServiceStatus::Critical).await;
+
+        let now = Utc::now();
+        Entity::track(&db, now).await.expect("Failed to track");
+        // doing it again doesn't restart the clock
+        Entity::track(&db, now + TimeDelta::minutes(5))
+            .await
+            .expect("Failed to track");
+        let open = Entity::unacknowledged(&db)
+            .await
+            .expect("Failed to list escalations");
+        let (escalation, full_check) = open
+            .iter()
+            .find(|(escalation, _)| escalation.service_check_id == check.id)
+            .expect("Check isn't being tracked");
+        assert_eq!(escalation.started_at, now);
+        assert_eq!(full_check.status, ServiceStatus::Critical);
+
+        Entity::set_level(&db, check.id, 1)
+            .await
+            .expect("Failed to set level");
+        assert!(Entity::acknowledge(&db, check.id, "testuser", now)
+            .await
+            .expect("Failed to acknowledge"));
+        assert!(!Entity::acknowledge(&db, check.id, "testuser", now)
+            .await
+            .expect("Failed to acknowledge"));
+        let escalation = Entity::find_by_id(check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query escalation")
+            .expect("Escalation went missing");
+        assert_eq!(escalation.level, 1);
+        assert_eq!(escalation.acknowledged_by.as_deref(), Some("testuser"));
+        assert!(Entity::unacknowledged(&db)
+            .await
+            .expect("Failed to list escalations")
+            .iter()
+            .all(|(escalation, _)| escalation.service_check_id != check.id));
+
+        // recovering clears it, so the next failure starts again
+        set_status(&db, &check, ServiceStatus::Ok).await;
+        Entity::track(&db, now).await.expect("Failed to track");
+        assert!(Entity::find_by_id(check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query escalation")
+            .is_none());
+    }
+}
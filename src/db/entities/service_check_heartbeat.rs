@@ -0,0 +1,136 @@
+//! The ping token for a heartbeat service check, and when it was last pinged
+
+use entities::service_check;
+use sea_orm::prelude::Expr;
+use sea_orm::Set;
+
+use crate::prelude::*;
+use crate::web::urls::Urls;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "service_check_heartbeat")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub service_check_id: Uuid,
+    /// The secret part of the ping URL
+    #[sea_orm(unique)]
+    pub token: String,
+    pub last_ping: Option<DateTime<Utc>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    ServiceCheck,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::ServiceCheck => Entity::belongs_to(service_check::Entity)
+                .from(Column::ServiceCheckId)
+                .to(service_check::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl Related<service_check::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ServiceCheck.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// The URL to hit to record a heartbeat
+    pub fn url(&self, frontend_url: &str) -> String {
+        format!(
+            "{}{}/{}",
+            frontend_url.trim_end_matches('/'),
+            Urls::HeartbeatApi,
+            self.token
+        )
+    }
+}
+
+impl Entity {
+    /// Gets the heartbeat for a service check, creating it with a new token if it doesn't have one yet
+    pub async fn for_service_check(
+        db: &DatabaseConnection,
+        service_check_id: Uuid,
+    ) -> Result<Model, Error> {
+        if let Some(heartbeat) = Entity::find_by_id(service_check_id).one(db).await? {
+            return Ok(heartbeat);
+        }
+        ActiveModel {
+            service_check_id: Set(service_check_id),
+            token: Set(Uuid::new_v4().simple().to_string()),
+            last_ping: Set(None),
+        }
+        .insert(db)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Records a ping, returns `None` if the token doesn't belong to a check
+    pub async fn ping(
+        db: &DatabaseConnection,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Model>, Error> {
+        Ok(Entity::update_many()
+            .col_expr(Column::LastPing, Expr::value(now))
+            .filter(Column::Token.eq(token))
+            .exec_with_returning(db)
+            .await?
+            .into_iter()
+            .next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_heartbeat_tokens() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let check = service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+
+        let heartbeat = Entity::for_service_check(&db, check.id)
+            .await
+            .expect("Failed to create heartbeat");
+        assert_eq!(heartbeat.last_ping, None);
+        // asking again gets the same token
+        assert_eq!(
+            Entity::for_service_check(&db, check.id)
+                .await
+                .expect("Failed to get heartbeat"),
+            heartbeat
+        );
+        assert_eq!(
+            heartbeat.url("https://maremma.example.com/"),
+            format!(
+                "https://maremma.example.com/api/v1/heartbeat/{}",
+                heartbeat.token
+            )
+        );
+
+        let now = Utc::now();
+        let pinged = Entity::ping(&db, &heartbeat.token, now)
+            .await
+            .expect("Failed to ping")
+            .expect("Token wasn't found");
+        assert_eq!(pinged.last_ping, Some(now));
+        assert!(Entity::ping(&db, "not a token", now)
+            .await
+            .expect("Failed to ping")
+            .is_none());
+    }
+}
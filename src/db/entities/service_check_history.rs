@@ -1,6 +1,7 @@
-use entities::{service_check, service_check_history};
-use sea_orm::{FromQueryResult, Order, QueryOrder, QuerySelect};
+use entities::{host, service, service_check, service_check_history};
+use sea_orm::{FromQueryResult, JoinType, Order, QueryOrder, QuerySelect};
 
+use crate::check_loop::CheckEnvironment;
 use crate::prelude::*;
 
 #[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
@@ -13,6 +14,22 @@ pub struct Model {
     pub status: ServiceStatus,
     pub time_elapsed: i64,
     pub result_text: String,
+    /// The version of Maremma that ran the check
+    pub maremma_version: Option<String>,
+    /// What ran the check, `local` or `agent:<name>`
+    pub runner: Option<String>,
+    /// The address the target hostname resolved to when the check ran
+    pub target_address: Option<String>,
+    /// Anything beyond the summary in `result_text`
+    pub long_output: Option<String>,
+    /// Key/value details, eg performance data
+    pub details: Option<Json>,
+    #[serde(default)]
+    /// Connection timings, exit codes and the like, for services with `debug_capture` set
+    pub diagnostics: Option<Json>,
+    #[serde(default)]
+    /// The key of the artifact holding all of `long_output`, when it was too big to keep here
+    pub artifact: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
@@ -123,10 +140,98 @@ impl Entity {
 
         Ok(res.rows_affected)
     }
+
+    /// Results since `since` whose text contains `text`, newest first
+    pub async fn search(
+        db: &DatabaseConnection,
+        text: &str,
+        since: DateTime<Utc>,
+        limit: u64,
+    ) -> Result<Vec<FullHistoryEntry>, Error> {
+        Ok(Entity::find()
+            .column_as(host::Column::Name, "host_name")
+            .column_as(host::Column::Slug, "host_slug")
+            .column_as(host::Column::DisplayName, "host_display_name")
+            .column_as(service::Column::Name, "service_name")
+            .join(JoinType::InnerJoin, Relation::ServiceCheck.def())
+            .join(JoinType::InnerJoin, service_check::Relation::Host.def())
+            .join(JoinType::InnerJoin, service_check::Relation::Service.def())
+            .filter(Column::Timestamp.gte(since))
+            .filter(Column::ResultText.contains(text))
+            .order_by_desc(Column::Timestamp)
+            .limit(limit)
+            .into_model::<FullHistoryEntry>()
+            .all(db)
+            .await?)
+    }
+
+    /// Deletes entries older than `before`, `batch_size` at a time so the write lock isn't held for long
+    pub async fn prune_in_batches(
+        db: &DatabaseConnection,
+        before: DateTime<Utc>,
+        batch_size: u64,
+    ) -> Result<u64, Error> {
+        if before > Utc::now() {
+            return Err(Error::DateIsInTheFuture);
+        }
+        let mut deleted = 0;
+        loop {
+            let ids: Vec<Uuid> = Entity::find()
+                .select_only()
+                .column(Column::Id)
+                .filter(Column::Timestamp.lt(before))
+                .limit(batch_size)
+                .into_tuple()
+                .all(db)
+                .await?;
+            if ids.is_empty() {
+                break;
+            }
+            let batch_len = ids.len() as u64;
+            let res = Entity::delete_many()
+                .filter(Column::Id.is_in(ids))
+                .exec(db)
+                .await?;
+            deleted += res.rows_affected;
+            debug!(
+                "deleted_count={} service check history older than {}",
+                res.rows_affected, before
+            );
+            if batch_len < batch_size {
+                break;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// A history entry with the names of the host and service it's for
+#[derive(Clone, Debug, PartialEq, Eq, FromQueryResult, Serialize)]
+pub struct FullHistoryEntry {
+    pub id: Uuid,
+    pub service_check_id: Uuid,
+    pub host_name: String,
+    pub host_slug: String,
+    pub host_display_name: Option<String>,
+    pub service_name: String,
+    pub timestamp: DateTime<Utc>,
+    pub status: ServiceStatus,
+    pub result_text: String,
+}
+
+impl FullHistoryEntry {
+    /// The host's name in the UI, see [host::display_name]
+    pub fn host_display_name(&self) -> &str {
+        host::display_name(&self.host_name, self.host_display_name.as_deref())
+    }
 }
 
 impl Model {
-    pub fn from_service_check_result(service_check_id: Uuid, result: &CheckResult) -> Self {
+    pub fn from_service_check_result(
+        service_check_id: Uuid,
+        result: &CheckResult,
+        environment: &CheckEnvironment,
+    ) -> Self {
         Self {
             id: Uuid::new_v4(),
             service_check_id,
@@ -134,8 +239,53 @@ impl Model {
             timestamp: Utc::now(),
             time_elapsed: result.time_elapsed.num_milliseconds(),
             result_text: result.result_text.clone(),
+            maremma_version: Some(environment.maremma_version.clone()),
+            runner: Some(environment.runner.clone()),
+            target_address: environment.target_address.clone(),
+            long_output: result.long_output.clone(),
+            details: (!result.details.is_empty()).then(|| json!(result.details)),
+            diagnostics: (!result.diagnostics.is_empty()).then(|| json!(result.diagnostics)),
+            artifact: None,
         }
     }
+
+    /// The details as pairs, for showing on a page
+    pub fn details_list(&self) -> Vec<(String, String)> {
+        json_pairs(self.details.as_ref())
+    }
+
+    /// The diagnostics as pairs, for showing on a page
+    pub fn diagnostics_list(&self) -> Vec<(String, String)> {
+        json_pairs(self.diagnostics.as_ref())
+    }
+
+    /// If there's anything to show beyond the summary
+    pub fn has_more_output(&self) -> bool {
+        self.long_output.is_some()
+            || !self.details_list().is_empty()
+            || !self.diagnostics_list().is_empty()
+    }
+}
+
+/// Turns a JSON object into key/value pairs, leaving strings unquoted
+fn json_pairs(value: Option<&Json>) -> Vec<(String, String)> {
+    value
+        .and_then(|value| value.as_object())
+        .map(|object| {
+            object
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.clone(),
+                        value
+                            .as_str()
+                            .map(str::to_string)
+                            .unwrap_or_else(|| value.to_string()),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 impl ActiveModelBehavior for ActiveModel {}
@@ -150,9 +300,8 @@ mod tests {
     #[tokio::test]
     async fn test_service_check_history() {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
-        let db_writer: tokio::sync::RwLockWriteGuard<'_, DatabaseConnection> = db.write().await;
         let service_check = entities::service_check::Entity::find()
-            .one(&*db_writer)
+            .one(&db)
             .await
             .expect("Failed to query service check")
             .expect("Failed to find service check");
@@ -162,21 +311,62 @@ mod tests {
             time_elapsed: chrono::Duration::milliseconds(145),
             status: ServiceStatus::Ok,
             result_text: "test".to_string(),
+            ..Default::default()
         };
-        let service_check_history = Model::from_service_check_result(service_check.id, &result);
+        let service_check_history = Model::from_service_check_result(
+            service_check.id,
+            &result,
+            &CheckEnvironment::local(None),
+        );
 
         let res = service_check_history
             .clone()
             .into_active_model()
-            .insert(&*db_writer)
+            .insert(&db)
             .await
             .expect("Failed to save service check history");
 
         assert!(res.id != Uuid::nil());
+        assert!(!res.has_more_output());
+
+        let structured = Model::from_service_check_result(
+            service_check.id,
+            &CheckResult::default().with_plugin_output("OK | load=0.5\nmore output"),
+            &CheckEnvironment::local(None),
+        )
+        .into_active_model()
+        .insert(&db)
+        .await
+        .expect("Failed to save service check history");
+        assert_eq!(structured.result_text, "OK");
+        assert_eq!(structured.long_output.as_deref(), Some("more output"));
+        assert_eq!(
+            structured.details_list(),
+            vec![("load".to_string(), "0.5".to_string())]
+        );
+        assert!(structured.has_more_output());
+        assert!(structured.diagnostics.is_none());
+
+        let debugged = Model::from_service_check_result(
+            service_check.id,
+            &CheckResult::default()
+                .with_diagnostics(false, [("exit_code".to_string(), "1".to_string())])
+                .with_diagnostics(true, [("connect_ms".to_string(), "12".to_string())]),
+            &CheckEnvironment::local(None),
+        )
+        .into_active_model()
+        .insert(&db)
+        .await
+        .expect("Failed to save service check history");
+        assert_eq!(
+            debugged.diagnostics_list(),
+            vec![("connect_ms".to_string(), "12".to_string())]
+        );
+        assert!(debugged.has_more_output());
 
         let res = Entity::find_by_id(service_check_history.id)
             .find_with_related(entities::service_check::Entity)
-            .all(&*db_writer)
+            .all(&db)
             .await
             .expect("Failed to find service check history");
 
@@ -185,7 +375,7 @@ mod tests {
         assert!(!related_model.is_empty());
 
         let res = Entity::prune(
-            &*db_writer,
+            &db,
             chrono::Utc::now() - TimeDelta::days(1),
             Some(service_check.id),
         )
@@ -199,12 +389,7 @@ mod tests {
     async fn test_future_date_prune() {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
 
-        let res = Entity::prune(
-            &*db.write().await,
-            chrono::Utc::now() + TimeDelta::days(1),
-            None,
-        )
-        .await;
+        let res = Entity::prune(&db, chrono::Utc::now() + TimeDelta::days(1), None).await;
 
         assert!(matches!(res, Err(Error::DateIsInTheFuture)));
     }
@@ -213,7 +398,7 @@ mod tests {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
 
         let res = Entity::prune(
-            &*db.write().await,
+            &db,
             chrono::Utc::now() - TimeDelta::days(1),
             Some(Uuid::new_v4()),
         )
@@ -222,11 +407,62 @@ mod tests {
 
         assert_eq!(res, 0);
     }
+    #[tokio::test]
+    async fn test_prune_in_batches() {
+        let (db, _config) = test_setup().await.expect("Failed to do test setup");
+        let service_check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Failed to find service check");
+
+        let result = CheckResult {
+            timestamp: Utc::now(),
+            time_elapsed: chrono::Duration::milliseconds(145),
+            status: ServiceStatus::Ok,
+            result_text: "test".to_string(),
+            ..Default::default()
+        };
+        for days_ago in [0, 10, 11, 12, 13, 14] {
+            let mut entry = Model::from_service_check_result(
+                service_check.id,
+                &result,
+                &CheckEnvironment::local(None),
+            );
+            entry.timestamp = Utc::now() - TimeDelta::days(days_ago);
+            entry
+                .into_active_model()
+                .insert(&db)
+                .await
+                .expect("Failed to save service check history");
+        }
+
+        let res = Entity::prune_in_batches(&db, Utc::now() - TimeDelta::days(7), 2)
+            .await
+            .expect("Failed to prune by age");
+        assert!(res >= 5);
+
+        let remaining = Entity::find()
+            .filter(Column::ServiceCheckId.eq(service_check.id))
+            .all(&db)
+            .await
+            .expect("Failed to query history");
+        assert!(!remaining.is_empty());
+        assert!(remaining
+            .iter()
+            .all(|entry| entry.timestamp > Utc::now() - TimeDelta::days(7)));
+
+        assert!(matches!(
+            Entity::prune_in_batches(&db, Utc::now() + TimeDelta::days(1), 2).await,
+            Err(Error::DateIsInTheFuture)
+        ));
+    }
+
     #[tokio::test]
     async fn test_head_service_check_history() {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
 
-        let res = Entity::head(&*db.write().await, Some(Uuid::new_v4()), 0)
+        let res = Entity::head(&db, Some(Uuid::new_v4()), 0)
             .await
             .expect("Failed to prune nothing");
 
@@ -235,9 +471,8 @@ mod tests {
     #[tokio::test]
     async fn test_head_service_check_history_sc_id() {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
-        let db_writer = db.write().await;
         let valid_service_check = entities::service_check::Entity::find()
-            .one(&*db_writer)
+            .one(&db)
             .await
             .expect("Failed to find service check")
             .expect("Failed to find service check");
@@ -247,24 +482,28 @@ mod tests {
             time_elapsed: chrono::Duration::milliseconds(145),
             status: ServiceStatus::Ok,
             result_text: "test".to_string(),
+            ..Default::default()
         };
-        let service_check_history =
-            Model::from_service_check_result(valid_service_check.id, &result);
+        let service_check_history = Model::from_service_check_result(
+            valid_service_check.id,
+            &result,
+            &CheckEnvironment::local(None),
+        );
 
         service_check_history
             .clone()
             .into_active_model()
-            .insert(&*db_writer)
+            .insert(&db)
             .await
             .expect("Failed to save service check history");
 
-        let res = Entity::head(&*db_writer, Some(valid_service_check.id), 0)
+        let res = Entity::head(&db, Some(valid_service_check.id), 0)
             .await
             .expect("Failed to prune a valid SCID");
 
         assert_eq!(res, 1);
 
-        let res = Entity::head(&*db_writer, Some(Uuid::new_v4()), 0)
+        let res = Entity::head(&db, Some(Uuid::new_v4()), 0)
             .await
             .expect("Failed to prune nothing");
 
@@ -274,9 +513,8 @@ mod tests {
     #[tokio::test]
     async fn test_head_1k() {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
-        let db_writer = db.write().await;
         let valid_service_check = entities::service_check::Entity::find()
-            .one(&*db_writer)
+            .one(&db)
             .await
             .expect("Failed to find service check")
             .expect("Failed to find service check");
@@ -288,24 +526,29 @@ mod tests {
             time_elapsed: chrono::Duration::milliseconds(145),
             status: ServiceStatus::Ok,
             result_text: "test".to_string(),
+            ..Default::default()
         };
 
         let things_to_create: u64 = 50;
         let num_to_delete = 10;
 
         for _ in 0..things_to_create {
-            let mut sch =
-                Model::from_service_check_result(valid_sc_id, &result).into_active_model();
+            let mut sch = Model::from_service_check_result(
+                valid_sc_id,
+                &result,
+                &CheckEnvironment::local(None),
+            )
+            .into_active_model();
 
             sch.id.set_if_not_equals(Uuid::new_v4());
-            sch.insert(&*db_writer)
+            sch.insert(&db)
                 .await
                 .expect("Failed to save service check history");
         }
 
         let res = Entity::find()
             .filter(Column::ServiceCheckId.eq(valid_sc_id))
-            .all(&*db_writer)
+            .all(&db)
             .await
             .expect("Failed to find service check history");
         assert_eq!(res.len() as u64, things_to_create);
@@ -315,10 +558,47 @@ mod tests {
             valid_sc_id
         );
 
-        let res = Entity::head(&*db_writer, Some(valid_service_check.id), num_to_delete)
+        let res = Entity::head(&db, Some(valid_service_check.id), num_to_delete)
             .await
             .expect("Failed to prune nothing");
 
         assert_eq!(res, (things_to_create - num_to_delete));
     }
+
+    #[tokio::test]
+    async fn test_search() {
+        let (db, _config) = test_setup().await.expect("Failed to do test setup");
+        let service_check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("No service checks found");
+        for (days_ago, result_text) in [(0, "disk full on /var"), (30, "disk full on /tmp")] {
+            ActiveModel {
+                id: sea_orm::Set(Uuid::new_v4()),
+                service_check_id: sea_orm::Set(service_check.id),
+                timestamp: sea_orm::Set(Utc::now() - TimeDelta::days(days_ago)),
+                status: sea_orm::Set(ServiceStatus::Critical),
+                result_text: sea_orm::Set(result_text.to_string()),
+                time_elapsed: sea_orm::Set(0),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await
+            .expect("Failed to insert history");
+        }
+
+        let found = Entity::search(&db, "disk full", Utc::now() - TimeDelta::days(7), 10)
+            .await
+            .expect("Failed to search history");
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found.first().map(|entry| entry.result_text.as_str()),
+            Some("disk full on /var")
+        );
+        assert_eq!(
+            found.first().map(|entry| entry.service_check_id),
+            Some(service_check.id)
+        );
+    }
 }
@@ -0,0 +1,224 @@
+//! The last successful output of a service check with `detect_changes` set, and what changed in it last time it did
+
+use entities::service_check;
+use sea_orm::{ActiveValue, Set};
+use similar::TextDiff;
+
+use crate::prelude::*;
+
+/// How many unchanged lines are shown around each change
+const DIFF_CONTEXT_LINES: usize = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "service_check_output")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub service_check_id: Uuid,
+    /// The output of the last successful run
+    pub output: String,
+    pub updated_at: DateTime<Utc>,
+    /// When the output last changed, `None` if it hasn't since it was first stored
+    pub changed_at: Option<DateTime<Utc>>,
+    /// A unified diff of the last change
+    pub diff: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    ServiceCheck,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::ServiceCheck => Entity::belongs_to(service_check::Entity)
+                .from(Column::ServiceCheckId)
+                .to(service_check::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl Related<service_check::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ServiceCheck.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// A unified diff between two outputs
+pub(crate) fn diff(previous: &str, current: &str) -> String {
+    // so the last lines don't show up as changed when only one of them ends in a newline
+    let previous = format!("{}\n", previous.trim_end());
+    let current = format!("{}\n", current.trim_end());
+    TextDiff::from_lines(&previous, &current)
+        .unified_diff()
+        .context_radius(DIFF_CONTEXT_LINES)
+        .header("previous", "current")
+        .to_string()
+}
+
+impl Model {
+    /// The lines of the diff, each with the class to colour it with
+    pub fn diff_lines(&self) -> Vec<(&'static str, &str)> {
+        self.diff
+            .as_deref()
+            .unwrap_or_default()
+            .lines()
+            .map(|line| {
+                let class = if line.starts_with("+++") || line.starts_with("---") {
+                    "fw-bold"
+                } else if line.starts_with('+') {
+                    "text-success"
+                } else if line.starts_with('-') {
+                    "text-danger"
+                } else if line.starts_with("@@") {
+                    "text-body-secondary"
+                } else {
+                    ""
+                };
+                (class, line)
+            })
+            .collect()
+    }
+}
+
+impl Entity {
+    /// Compares a successful result's output with the last one, a change makes it a warning with the diff as the long output
+    pub async fn compare(
+        db: &DatabaseConnection,
+        service_check_id: Uuid,
+        mut result: CheckResult,
+        now: DateTime<Utc>,
+    ) -> Result<CheckResult, Error> {
+        let Some(output) = result.compare_output.take() else {
+            return Ok(result);
+        };
+        // failures have their own output, which isn't what we're watching
+        if result.status != ServiceStatus::Ok {
+            return Ok(result);
+        }
+
+        match Entity::find_by_id(service_check_id).one(db).await? {
+            None => {
+                ActiveModel {
+                    service_check_id: Set(service_check_id),
+                    output: Set(output),
+                    updated_at: Set(now),
+                    changed_at: Set(None),
+                    diff: Set(None),
+                }
+                .insert(db)
+                .await?;
+            }
+            Some(previous) if previous.output == output => {
+                ActiveModel {
+                    service_check_id: ActiveValue::Unchanged(service_check_id),
+                    updated_at: Set(now),
+                    ..Default::default()
+                }
+                .update(db)
+                .await?;
+            }
+            Some(previous) => {
+                let diff = diff(&previous.output, &output);
+                ActiveModel {
+                    service_check_id: ActiveValue::Unchanged(service_check_id),
+                    output: Set(output),
+                    updated_at: Set(now),
+                    changed_at: Set(Some(now)),
+                    diff: Set(Some(diff.clone())),
+                }
+                .update(db)
+                .await?;
+                debug!("Output of service_check={} changed", service_check_id);
+                result.status = ServiceStatus::Warning;
+                result.result_text = format!("Output changed: {}", result.result_text);
+                result.long_output = Some(diff);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_compare() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let check = service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let result = |status: ServiceStatus, output: &str| {
+            CheckResult {
+                status,
+                result_text: "packages".to_string(),
+                ..Default::default()
+            }
+            .with_compare_output(true, output)
+        };
+        let now = Utc::now();
+
+        // the first run's just stored
+        let res = Entity::compare(&db, check.id, result(ServiceStatus::Ok, "a\nb\n"), now)
+            .await
+            .expect("Failed to compare");
+        assert_eq!(res.status, ServiceStatus::Ok);
+        assert_eq!(res.compare_output, None);
+
+        let res = Entity::compare(&db, check.id, result(ServiceStatus::Ok, "a\nb\n"), now)
+            .await
+            .expect("Failed to compare");
+        assert_eq!(res.status, ServiceStatus::Ok);
+
+        // failures don't replace the stored output
+        let res = Entity::compare(&db, check.id, result(ServiceStatus::Critical, "oops"), now)
+            .await
+            .expect("Failed to compare");
+        assert_eq!(res.status, ServiceStatus::Critical);
+
+        let res = Entity::compare(&db, check.id, result(ServiceStatus::Ok, "a\nc\n"), now)
+            .await
+            .expect("Failed to compare");
+        assert_eq!(res.status, ServiceStatus::Warning);
+        assert_eq!(res.result_text, "Output changed: packages");
+        let long_output = res.long_output.expect("No diff in the long output");
+        assert!(long_output.contains("-b"));
+        assert!(long_output.contains("+c"));
+
+        let stored = Entity::find_by_id(check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query output")
+            .expect("Output wasn't stored");
+        assert_eq!(stored.output, "a\nc");
+        assert_eq!(stored.changed_at, Some(now));
+        assert!(stored.diff_lines().contains(&("text-success", "+c")));
+
+        // it's back to OK once it's stayed the same
+        let res = Entity::compare(&db, check.id, result(ServiceStatus::Ok, "a\nc\n"), now)
+            .await
+            .expect("Failed to compare");
+        assert_eq!(res.status, ServiceStatus::Ok);
+
+        // checks without detect_changes aren't touched
+        let res = Entity::compare(
+            &db,
+            check.id,
+            CheckResult {
+                status: ServiceStatus::Ok,
+                ..Default::default()
+            },
+            now,
+        )
+        .await
+        .expect("Failed to compare");
+        assert_eq!(res.status, ServiceStatus::Ok);
+    }
+}
@@ -0,0 +1,461 @@
+//! Hourly and daily summaries of the service check history
+
+use std::collections::BTreeMap;
+
+use chrono::DurationRound;
+use entities::{service_check, service_check_history};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{QueryOrder, QuerySelect};
+
+use crate::prelude::*;
+
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, EnumIter, DeriveActiveEnum, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+#[sea_orm(rs_type = "String", db_type = "String(StringLen::N(8))")]
+/// How much time a rollup covers
+pub enum RollupPeriod {
+    /// Built from the raw history
+    #[default]
+    #[sea_orm(string_value = "hour")]
+    Hour,
+    /// Built from the hourly rollups
+    #[sea_orm(string_value = "day")]
+    Day,
+}
+
+impl RollupPeriod {
+    /// How long the period is
+    pub fn duration(&self) -> TimeDelta {
+        match self {
+            RollupPeriod::Hour => TimeDelta::hours(1),
+            RollupPeriod::Day => TimeDelta::days(1),
+        }
+    }
+
+    /// The start of the period `timestamp` falls in, days start at midnight UTC
+    pub fn floor(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        timestamp
+            .duration_trunc(self.duration())
+            .unwrap_or(timestamp)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "service_check_rollup")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub service_check_id: Uuid,
+    pub period: RollupPeriod,
+    pub period_start: DateTime<Utc>,
+    /// How many results there were in the period
+    pub checks: i64,
+    pub ok: i64,
+    pub warning: i64,
+    /// Critical, error and timeout results
+    pub critical: i64,
+    pub avg_latency_ms: i64,
+    pub max_latency_ms: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    ServiceCheck,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::ServiceCheck => Entity::belongs_to(service_check::Entity)
+                .from(Column::ServiceCheckId)
+                .to(service_check::Column::Id)
+                .into(),
+        }
+    }
+}
+
+impl Related<service_check::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ServiceCheck.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Totals across a number of results or rollups
+pub struct RollupSummary {
+    pub checks: i64,
+    pub ok: i64,
+    pub warning: i64,
+    pub critical: i64,
+    /// Total latency, so averages can be combined
+    total_latency_ms: i64,
+    pub max_latency_ms: i64,
+}
+
+impl RollupSummary {
+    fn add_result(&mut self, status: ServiceStatus, time_elapsed: i64) {
+        self.checks += 1;
+        match status {
+            ServiceStatus::Ok => self.ok += 1,
+            ServiceStatus::Warning => self.warning += 1,
+            ServiceStatus::Critical | ServiceStatus::Error | ServiceStatus::Timeout => {
+                self.critical += 1
+            }
+            _ => {}
+        }
+        self.total_latency_ms += time_elapsed;
+        self.max_latency_ms = self.max_latency_ms.max(time_elapsed);
+    }
+
+    fn add_rollup(&mut self, rollup: &Model) {
+        self.checks += rollup.checks;
+        self.ok += rollup.ok;
+        self.warning += rollup.warning;
+        self.critical += rollup.critical;
+        self.total_latency_ms += rollup.avg_latency_ms * rollup.checks;
+        self.max_latency_ms = self.max_latency_ms.max(rollup.max_latency_ms);
+    }
+
+    /// The average latency in milliseconds
+    pub fn avg_latency_ms(&self) -> i64 {
+        match self.checks {
+            0 => 0,
+            checks => self.total_latency_ms / checks,
+        }
+    }
+
+    /// The percentage of results that were OK, if there were any
+    pub fn availability(&self) -> Option<f64> {
+        match self.checks {
+            0 => None,
+            checks => Some(self.ok as f64 * 100.0 / checks as f64),
+        }
+    }
+
+    /// The availability for showing on a page, eg `99.95%`
+    pub fn availability_text(&self) -> String {
+        self.availability()
+            .map(|availability| format!("{:.2}%", availability))
+            .unwrap_or("-".to_string())
+    }
+
+    fn into_model(
+        self,
+        service_check_id: Uuid,
+        period: RollupPeriod,
+        period_start: DateTime<Utc>,
+    ) -> Model {
+        Model {
+            id: Uuid::new_v4(),
+            service_check_id,
+            period,
+            period_start,
+            checks: self.checks,
+            ok: self.ok,
+            warning: self.warning,
+            critical: self.critical,
+            avg_latency_ms: self.avg_latency_ms(),
+            max_latency_ms: self.max_latency_ms,
+        }
+    }
+}
+
+impl Entity {
+    /// The start of the latest period that's been rolled up
+    pub async fn latest_period_start(
+        db: &DatabaseConnection,
+        period: RollupPeriod,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(Entity::find()
+            .select_only()
+            .column(Column::PeriodStart)
+            .filter(Column::Period.eq(period))
+            .order_by_desc(Column::PeriodStart)
+            .into_tuple()
+            .one(db)
+            .await?)
+    }
+
+    /// When the first thing that'd go into a `period` rollup at or after `after` happened
+    pub async fn next_source_time(
+        db: &DatabaseConnection,
+        period: RollupPeriod,
+        after: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(match period {
+            RollupPeriod::Hour => {
+                service_check_history::Entity::find()
+                    .select_only()
+                    .column(service_check_history::Column::Timestamp)
+                    .filter(service_check_history::Column::Timestamp.gte(after))
+                    .order_by_asc(service_check_history::Column::Timestamp)
+                    .into_tuple()
+                    .one(db)
+                    .await?
+            }
+            RollupPeriod::Day => {
+                Entity::find()
+                    .select_only()
+                    .column(Column::PeriodStart)
+                    .filter(Column::Period.eq(RollupPeriod::Hour))
+                    .filter(Column::PeriodStart.gte(after))
+                    .order_by_asc(Column::PeriodStart)
+                    .into_tuple()
+                    .one(db)
+                    .await?
+            }
+        })
+    }
+
+    /// Rolls up the period starting at `period_start` for every service check, hours from the raw history and days from the hourly rollups. Returns how many rollups were written, periods that already have one are left alone.
+    pub async fn roll_up(
+        db: &DatabaseConnection,
+        period: RollupPeriod,
+        period_start: DateTime<Utc>,
+    ) -> Result<u64, Error> {
+        let period_end = period_start + period.duration();
+        let mut summaries: BTreeMap<Uuid, RollupSummary> = BTreeMap::new();
+
+        match period {
+            RollupPeriod::Hour => {
+                let results: Vec<(Uuid, ServiceStatus, i64)> =
+                    service_check_history::Entity::find()
+                        .select_only()
+                        .column(service_check_history::Column::ServiceCheckId)
+                        .column(service_check_history::Column::Status)
+                        .column(service_check_history::Column::TimeElapsed)
+                        .filter(service_check_history::Column::Timestamp.gte(period_start))
+                        .filter(service_check_history::Column::Timestamp.lt(period_end))
+                        .into_tuple()
+                        .all(db)
+                        .await?;
+                for (service_check_id, status, time_elapsed) in results {
+                    summaries
+                        .entry(service_check_id)
+                        .or_default()
+                        .add_result(status, time_elapsed);
+                }
+            }
+            RollupPeriod::Day => {
+                let hours = Entity::find()
+                    .filter(Column::Period.eq(RollupPeriod::Hour))
+                    .filter(Column::PeriodStart.gte(period_start))
+                    .filter(Column::PeriodStart.lt(period_end))
+                    .all(db)
+                    .await?;
+                for hour in hours.iter() {
+                    summaries
+                        .entry(hour.service_check_id)
+                        .or_default()
+                        .add_rollup(hour);
+                }
+            }
+        }
+
+        if summaries.is_empty() {
+            return Ok(0);
+        }
+
+        let rollups = summaries
+            .into_iter()
+            .map(|(service_check_id, summary)| {
+                summary
+                    .into_model(service_check_id, period, period_start)
+                    .into_active_model()
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Entity::insert_many(rollups)
+            .on_conflict(
+                OnConflict::columns([Column::ServiceCheckId, Column::Period, Column::PeriodStart])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?)
+    }
+
+    /// Adds up a check's rollups for `period` since `since`
+    pub async fn summary(
+        db: &DatabaseConnection,
+        service_check_id: Uuid,
+        period: RollupPeriod,
+        since: DateTime<Utc>,
+    ) -> Result<RollupSummary, Error> {
+        let rollups = Entity::find()
+            .filter(Column::ServiceCheckId.eq(service_check_id))
+            .filter(Column::Period.eq(period))
+            .filter(Column::PeriodStart.gte(period.floor(since)))
+            .all(db)
+            .await?;
+        let mut summary = RollupSummary::default();
+        rollups.iter().for_each(|rollup| summary.add_rollup(rollup));
+        Ok(summary)
+    }
+
+    /// Adds up the rollups for `period` since `since` across a number of checks, eg all the ones in a host group
+    pub async fn summary_for_checks(
+        db: &DatabaseConnection,
+        service_check_ids: &[Uuid],
+        period: RollupPeriod,
+        since: DateTime<Utc>,
+    ) -> Result<RollupSummary, Error> {
+        let mut summary = RollupSummary::default();
+        if service_check_ids.is_empty() {
+            return Ok(summary);
+        }
+        Entity::find()
+            .filter(Column::ServiceCheckId.is_in(service_check_ids.iter().copied()))
+            .filter(Column::Period.eq(period))
+            .filter(Column::PeriodStart.gte(period.floor(since)))
+            .all(db)
+            .await?
+            .iter()
+            .for_each(|rollup| summary.add_rollup(rollup));
+        Ok(summary)
+    }
+
+    /// Deletes `period` rollups that started before `before`
+    pub async fn prune(
+        db: &DatabaseConnection,
+        period: RollupPeriod,
+        before: DateTime<Utc>,
+    ) -> Result<u64, Error> {
+        Ok(Entity::delete_many()
+            .filter(Column::Period.eq(period))
+            .filter(Column::PeriodStart.lt(before))
+            .exec(db)
+            .await?
+            .rows_affected)
+    }
+}
+
+/// Where rolling up starts when there's nothing rolled up yet
+pub(crate) fn epoch() -> DateTime<Utc> {
+    DateTime::UNIX_EPOCH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_loop::CheckEnvironment;
+    use crate::db::tests::test_setup;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_rollup_period_floor() {
+        let timestamp = Utc
+            .with_ymd_and_hms(2025, 1, 10, 13, 45, 12)
+            .single()
+            .expect("Failed to build timestamp");
+        assert_eq!(
+            RollupPeriod::Hour.floor(timestamp),
+            Utc.with_ymd_and_hms(2025, 1, 10, 13, 0, 0)
+                .single()
+                .expect("Failed to build timestamp")
+        );
+        assert_eq!(
+            RollupPeriod::Day.floor(timestamp),
+            Utc.with_ymd_and_hms(2025, 1, 10, 0, 0, 0)
+                .single()
+                .expect("Failed to build timestamp")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roll_up() {
+        let (db, _config) = test_setup().await.expect("Failed to do test setup");
+        let service_check = service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Failed to find service check");
+
+        // somewhere well before anything test_setup writes
+        let hour = RollupPeriod::Hour.floor(Utc::now() - TimeDelta::days(3));
+        for (offset, status, time_elapsed) in [
+            (1, ServiceStatus::Ok, 100),
+            (2, ServiceStatus::Ok, 200),
+            (3, ServiceStatus::Critical, 600),
+            (65, ServiceStatus::Warning, 50),
+        ] {
+            let mut entry = service_check_history::Model::from_service_check_result(
+                service_check.id,
+                &CheckResult {
+                    timestamp: Utc::now(),
+                    time_elapsed: chrono::Duration::milliseconds(time_elapsed),
+                    status,
+                    result_text: "test".to_string(),
+                    ..Default::default()
+                },
+                &CheckEnvironment::local(None),
+            );
+            entry.timestamp = hour + TimeDelta::minutes(offset);
+            entry
+                .into_active_model()
+                .insert(&db)
+                .await
+                .expect("Failed to save service check history");
+        }
+
+        assert_eq!(
+            Entity::next_source_time(&db, RollupPeriod::Hour, epoch())
+                .await
+                .expect("Failed to find source time")
+                .map(|ts| RollupPeriod::Hour.floor(ts)),
+            Some(hour)
+        );
+
+        for period_start in [hour, hour + TimeDelta::hours(1)] {
+            assert_eq!(
+                Entity::roll_up(&db, RollupPeriod::Hour, period_start)
+                    .await
+                    .expect("Failed to roll up"),
+                1
+            );
+        }
+        // doing it again doesn't double up
+        assert_eq!(
+            Entity::roll_up(&db, RollupPeriod::Hour, hour)
+                .await
+                .expect("Failed to roll up"),
+            0
+        );
+
+        let summary = Entity::summary(&db, service_check.id, RollupPeriod::Hour, hour)
+            .await
+            .expect("Failed to summarise");
+        assert_eq!(summary.checks, 4);
+        assert_eq!(summary.ok, 2);
+        assert_eq!(summary.warning, 1);
+        assert_eq!(summary.critical, 1);
+        assert_eq!(summary.max_latency_ms, 600);
+        assert_eq!(summary.availability_text(), "50.00%");
+
+        let day = RollupPeriod::Day.floor(hour);
+        Entity::roll_up(&db, RollupPeriod::Day, day)
+            .await
+            .expect("Failed to roll up day");
+        let daily = Entity::summary(&db, service_check.id, RollupPeriod::Day, day)
+            .await
+            .expect("Failed to summarise");
+        assert!(daily.checks > 0);
+
+        assert!(
+            Entity::prune(&db, RollupPeriod::Hour, Utc::now())
+                .await
+                .expect("Failed to prune")
+                >= 2
+        );
+        assert_eq!(
+            Entity::latest_period_start(&db, RollupPeriod::Hour)
+                .await
+                .expect("Failed to query"),
+            None
+        );
+    }
+}
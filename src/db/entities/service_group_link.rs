@@ -1,17 +1,21 @@
-//! Links services to groups
+//! Links services to groups, or to groups whose hosts they leave out
 
 use entities::{host_group, service};
 use sea_orm::Set;
 
+use crate::host::group::{parse_selector, EXCLUDE_PREFIX};
 use crate::prelude::*;
 
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "service_group_link")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     pub service_id: Uuid,
     pub group_id: Uuid,
+    #[serde(default)]
+    /// The group's hosts don't get the service, from a `!group` in its `host_groups`
+    pub exclude: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
@@ -73,6 +77,51 @@ impl Linked for GroupToServices {
 
 impl ActiveModelBehavior for ActiveModel {}
 
+impl Entity {
+    /// A service's host groups and whether each one's excluded, included groups first
+    pub async fn groups(
+        db: &DatabaseConnection,
+        service_id: Uuid,
+    ) -> Result<Vec<(host_group::Model, bool)>, Error> {
+        let links = Entity::find()
+            .filter(Column::ServiceId.eq(service_id))
+            .all(db)
+            .await?;
+        let groups = host_group::Entity::find()
+            .filter(host_group::Column::Id.is_in(links.iter().map(|link| link.group_id)))
+            .all(db)
+            .await?;
+        let mut res = links
+            .iter()
+            .filter_map(|link| {
+                groups
+                    .iter()
+                    .find(|group| group.id == link.group_id)
+                    .map(|group| (group.clone(), link.exclude))
+            })
+            .collect::<Vec<_>>();
+        res.sort_by(|(a, a_exclude), (b, b_exclude)| {
+            a_exclude.cmp(b_exclude).then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(res)
+    }
+
+    /// A service's host groups the way they're written in the config, with a `!` in front of excluded ones
+    pub async fn selectors(
+        db: &DatabaseConnection,
+        service_id: Uuid,
+    ) -> Result<Vec<String>, Error> {
+        Ok(Self::groups(db, service_id)
+            .await?
+            .into_iter()
+            .map(|(group, exclude)| match exclude {
+                true => format!("{}{}", EXCLUDE_PREFIX, group.name),
+                false => group.name,
+            })
+            .collect())
+    }
+}
+
 #[async_trait]
 impl MaremmaEntity for Model {
     async fn find_by_name(_name: &str, _db: &DatabaseConnection) -> Result<Option<Model>, Error> {
@@ -90,12 +139,13 @@ impl MaremmaEntity for Model {
 
             for group_name in service.host_groups.iter() {
                 debug!("Service: {} Group: {}", service_name, group_name);
+                let (group_name, exclude) = parse_selector(group_name);
 
                 let group_model = host_group::Model::find_by_name(group_name, db)
                     .await?
                     .ok_or(Error::HostGroupNotFoundByName(group_name.to_string()))?;
 
-                if Entity::find()
+                match Entity::find()
                     .filter(
                         Column::ServiceId
                             .eq(service_model.id)
@@ -103,19 +153,28 @@ impl MaremmaEntity for Model {
                     )
                     .one(db)
                     .await?
-                    .is_none()
                 {
-                    debug!(
-                        "Need to create link for Service: {} Group: {}",
-                        service_name, group_name
-                    );
-                    ActiveModel {
-                        id: Set(Uuid::new_v4()),
-                        service_id: Set(service_model.id),
-                        group_id: Set(group_model.id),
+                    None => {
+                        debug!(
+                            "Need to create link for Service: {} Group: {}",
+                            service_name, group_name
+                        );
+                        ActiveModel {
+                            id: Set(Uuid::new_v4()),
+                            service_id: Set(service_model.id),
+                            group_id: Set(group_model.id),
+                            exclude: Set(exclude),
+                        }
+                        .insert(db)
+                        .await?;
+                    }
+                    // the group's moved between included and excluded
+                    Some(link) if link.exclude != exclude => {
+                        let mut link = link.into_active_model();
+                        link.exclude.set_if_not_equals(exclude);
+                        link.update(db).await?;
                     }
-                    .insert(db)
-                    .await?;
+                    Some(_) => {}
                 };
             }
         }
@@ -133,25 +192,66 @@ mod tests {
     async fn test_update_db_from_config() {
         let (db, config) = test_setup().await.expect("Failed to start test harness");
 
-        super::super::host_group::Model::update_db_from_config(&*db.write().await, config.clone())
+        super::super::host_group::Model::update_db_from_config(&db, config.clone())
             .await
             .expect("Failed to update services from config");
-        super::super::service::Model::update_db_from_config(&*db.write().await, config.clone())
+        super::super::service::Model::update_db_from_config(&db, config.clone())
             .await
             .expect("Failed to update services from config");
 
-        super::Model::update_db_from_config(&*db.write().await, config)
+        super::Model::update_db_from_config(&db, config)
             .await
             .expect("Failed to load config");
     }
 
+    #[tokio::test]
+    async fn test_selectors() {
+        let (db, config) = test_setup().await.expect("Failed to start test harness");
+
+        let (service_name, host_groups) = config
+            .read()
+            .await
+            .services
+            .iter()
+            .find(|(_, service)| !service.host_groups.is_empty())
+            .map(|(name, service)| (name.clone(), service.host_groups.clone()))
+            .expect("Couldn't find a service with host groups");
+        let service_model = super::super::service::Model::find_by_name(&service_name, &db)
+            .await
+            .expect("Failed to query service")
+            .expect("Failed to find service");
+        assert_eq!(
+            super::Entity::selectors(&db, service_model.id)
+                .await
+                .expect("Failed to get selectors"),
+            host_groups
+        );
+
+        // excluding the group updates the existing link
+        let excluded = host_groups
+            .iter()
+            .map(|group| format!("!{}", group))
+            .collect::<Vec<_>>();
+        if let Some(service) = config.write().await.services.get_mut(&service_name) {
+            service.host_groups = excluded.clone();
+        }
+        super::Model::update_db_from_config(&db, config)
+            .await
+            .expect("Failed to update links");
+        assert_eq!(
+            super::Entity::selectors(&db, service_model.id)
+                .await
+                .expect("Failed to get selectors"),
+            excluded
+        );
+    }
+
     #[tokio::test]
     async fn test_find_by_name() {
         // this should error
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
-        let res = super::Model::find_by_name("test", &*db.read().await).await;
+        let res = super::Model::find_by_name("test", &db).await;
 
         assert!(res.is_err());
         assert_eq!(res.err().unwrap(), Error::NotImplemented);
@@ -165,6 +265,7 @@ mod tests {
                 id: Uuid::new_v4(),
                 service_id: Uuid::new_v4(),
                 group_id: Uuid::new_v4(),
+                exclude: false,
             }]])
             .into_connection();
 
@@ -177,12 +278,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_linked_service_to_groups() {
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
         let services = super::super::service::Entity::find()
             .find_with_linked(super::ServiceToGroups)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to query group to hosts relation");
 
@@ -201,12 +301,11 @@ mod tests {
     }
     #[tokio::test]
     async fn test_linked_group_to_services() {
-        let (db, _config) =
-            test_setup().await.expect("Failed to start test harness");
+        let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
         let groups = super::super::host_group::Entity::find()
             .find_with_linked(super::GroupToServices)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to query group to hosts relation");
 
@@ -21,7 +21,7 @@ impl ActiveModelBehavior for ActiveModel {}
 
 #[derive(Debug, Clone)]
 pub struct ModelStore {
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
 }
 
 fn id_to_uuid(input: &Id) -> Result<Uuid, Error> {
@@ -71,7 +71,7 @@ impl SessionStore for ModelStore {
 
         dbrecord.expiry.set_if_not_equals(expiry);
         dbrecord
-            .insert(&*self.db.write().await)
+            .insert(&self.db)
             .await
             .map_err(|err| tower_sessions::session_store::Error::Backend(err.to_string()))?;
         debug!("Created session with id={} uuid={}", record.id.0, id_uuid);
@@ -90,7 +90,7 @@ impl SessionStore for ModelStore {
             .map_err(|err| tower_sessions::session_store::Error::Encode(format!("{:?}", err)))?;
 
         let mut session = match Entity::find_by_id(id)
-            .one(&*self.db.read().await)
+            .one(&self.db)
             .await
             .map_err(|err| tower_sessions::session_store::Error::Backend(err.to_string()))?
         {
@@ -116,7 +116,7 @@ impl SessionStore for ModelStore {
 
         if session.is_changed() {
             session
-                .update(&*self.db.write().await)
+                .update(&self.db)
                 .await
                 .map_err(|err| tower_sessions::session_store::Error::Backend(err.to_string()))?;
             debug!("Saved session with id={}", session_record.id.0);
@@ -134,7 +134,7 @@ impl SessionStore for ModelStore {
         let id = id_to_uuid(session_id)
             .map_err(|err| tower_sessions::session_store::Error::Decode(format!("{:?}", err)))?;
         let session = match Entity::find_by_id(id)
-            .one(&*self.db.read().await)
+            .one(&self.db)
             .await
             .map_err(|err| tower_sessions::session_store::Error::Backend(err.to_string()))?
         {
@@ -169,7 +169,7 @@ impl SessionStore for ModelStore {
                 tower_sessions::session_store::Error::Encode(format!("{:?}", err))
             })?,
         )
-        .exec(&*self.db.write().await)
+        .exec(&self.db)
         .await
         .map_err(|err| tower_sessions::session_store::Error::Backend(err.to_string()))?;
         Ok(())
@@ -177,18 +177,18 @@ impl SessionStore for ModelStore {
 }
 
 impl ModelStore {
-    pub fn new(db: Arc<RwLock<DatabaseConnection>>) -> Self {
+    pub fn new(db: DatabaseConnection) -> Self {
         Self { db }
     }
 
     /// Cleans up old/expired sessions
-    pub async fn cleanup(&self, db: Arc<RwLock<DatabaseConnection>>) -> Result<u64, Error> {
+    pub async fn cleanup(&self, db: DatabaseConnection) -> Result<u64, Error> {
         let res = Entity::delete_many()
             .filter(
                 Column::Expiry
                     .lt(chrono::Utc::now() - chrono::Duration::hours(SESSION_EXPIRY_WINDOW_HOURS)),
             )
-            .exec(&*db.write().await)
+            .exec(&db)
             .await?;
         Ok(res.rows_affected)
     }
@@ -218,7 +218,7 @@ mod tests {
 
         session
             .into_active_model()
-            .insert(&*db.write().await)
+            .insert(&db)
             .await
             .expect("Failed to insert test session!");
 
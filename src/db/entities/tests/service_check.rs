@@ -13,15 +13,14 @@ async fn test_service_check_entity() {
     let service = service::test_service();
     let host = host::test_host();
     info!("saving service...");
-    let db_writer = db.write().await;
     let service_am = service.into_active_model();
     let _service = service::Entity::insert(service_am.to_owned())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
     let host_am = host.into_active_model();
     let _host = host::Entity::insert(host_am.to_owned())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
 
@@ -36,16 +35,13 @@ async fn test_service_check_entity() {
 
     let am = service_check.into_active_model();
 
-    if let Err(err) = entities::service_check::Entity::insert(am)
-        .exec(&*db_writer)
-        .await
-    {
+    if let Err(err) = entities::service_check::Entity::insert(am).exec(&db).await {
         panic!("Failed to insert service check: {:?}", err);
     };
 
     let service_check = entities::service_check::Entity::find()
         .filter(entities::service_check::Column::Id.eq(service_check_id))
-        .one(&*db_writer)
+        .one(&db)
         .await
         .unwrap()
         .unwrap();
@@ -53,17 +49,17 @@ async fn test_service_check_entity() {
     info!("found it: {:?}", service_check);
 
     entities::service_check::Entity::delete_by_id(service_check_id)
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
     // Check we didn't delete the host when deleting the service check
     assert!(host::Entity::find_by_id(host_am.id.unwrap())
-        .one(&*db_writer)
+        .one(&db)
         .await
         .unwrap()
         .is_some());
     assert!(service::Entity::find_by_id(service_am.id.unwrap())
-        .one(&*db_writer)
+        .one(&db)
         .await
         .unwrap()
         .is_some());
@@ -74,21 +70,19 @@ async fn test_service_check_entity() {
 async fn test_service_check_fk_host() {
     let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
-    let db_writer = db.write().await;
-
     let service = service::test_service();
     let host = host::test_host();
     info!("saving service...");
 
     let service_am = service.into_active_model();
     let _service = service::Entity::insert(service_am.to_owned())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
     let host_am_id = host.id;
     let host_am = host.into_active_model();
     let _host = host::Entity::insert(host_am.to_owned())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
 
@@ -100,7 +94,7 @@ async fn test_service_check_fk_host() {
     };
     let service_check_am = service_check
         .into_active_model()
-        .insert(&*db_writer)
+        .insert(&db)
         .await
         .expect("Failed to save service check")
         .try_into_model()
@@ -108,19 +102,19 @@ async fn test_service_check_fk_host() {
 
     assert!(
         entities::service_check::Entity::find_by_id(service_check_am.id)
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .is_some()
     );
     host::Entity::delete_by_id(host_am_id)
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
     // Check we delete the service check when deleting the host
     assert!(
         entities::service_check::Entity::find_by_id(service_check_am.id)
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .is_none()
@@ -134,16 +128,15 @@ async fn test_service_check_fk_service() {
     let service = service::test_service();
     let host = host::test_host();
     info!("saving service...");
-    let db_writer = db.write().await;
 
     let service_am = service.clone().into_active_model();
     let _service = service::Entity::insert(service_am.to_owned())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
     let host_am = host.into_active_model();
     let _host = host::Entity::insert(host_am.clone())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
 
@@ -156,7 +149,7 @@ async fn test_service_check_fk_service() {
     let service_check_am = service_check.into_active_model();
     dbg!(&service_check_am);
     if let Err(err) = entities::service_check::Entity::insert(service_check_am.to_owned())
-        .exec(&*db_writer)
+        .exec(&db)
         .await
     {
         panic!("Failed to insert service check: {:?}", err);
@@ -164,19 +157,19 @@ async fn test_service_check_fk_service() {
 
     assert!(
         entities::service_check::Entity::find_by_id(service_check_am.id.clone().unwrap())
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .is_some()
     );
     service::Entity::delete_by_id(service.id)
-        .exec(&*db_writer)
+        .exec(&db)
         .await
         .unwrap();
     // Check we delete the service check when deleting the service
     assert!(
         entities::service_check::Entity::find_by_id(service_check_am.id.unwrap())
-            .one(&*db_writer)
+            .one(&db)
             .await
             .unwrap()
             .is_none()
@@ -192,7 +185,7 @@ async fn test_full_service_check() {
         .unwrap();
 
     let known_service_check_service_id = entities::service_check::Entity::find()
-        .all(&*db.write().await)
+        .all(&db)
         .await
         .unwrap()
         .into_iter()
@@ -208,12 +201,12 @@ async fn test_full_service_check() {
     let query = entities::service_check::FullServiceCheck::get_by_service_id_query(
         known_service_check_service_id,
     )
-    .build((*db.read().await).get_database_backend());
+    .build(db.get_database_backend());
     info!("Query: {}", query);
 
     let service_check = entities::service_check::FullServiceCheck::get_by_service_id(
         known_service_check_service_id,
-        &*db.write().await,
+        &db,
     )
     .await
     .expect("Failed to get service_check");
@@ -228,7 +221,7 @@ async fn test_get_urgent_service_check() {
     let (db, _config) = test_setup().await.expect("Failed to setup test db");
 
     let sc = entities::service_check::Entity::find()
-        .one(&*db.read().await)
+        .one(&db)
         .await
         .unwrap()
         .unwrap();
@@ -237,7 +230,7 @@ async fn test_get_urgent_service_check() {
         .await
         .expect("Failed to set status to urgent");
 
-    let urgent = get_next_service_check(&*db.read().await)
+    let urgent = get_next_service_check(&db)
         .await
         .expect("Failed to query DB");
     assert!(urgent.is_some());
@@ -251,7 +244,7 @@ async fn test_get_next_pending_service_check() {
     let (db, _config) = test_setup().await.expect("Failed to setup test db");
 
     let sc = entities::service_check::Entity::find()
-        .one(&*db.write().await)
+        .one(&db)
         .await
         .unwrap()
         .unwrap();
@@ -260,7 +253,7 @@ async fn test_get_next_pending_service_check() {
         .await
         .expect("Failed to set status to pending");
 
-    let urgent = get_next_service_check(&*db.read().await)
+    let urgent = get_next_service_check(&db)
         .await
         .expect("Failed to query DB");
     assert!(urgent.is_some());
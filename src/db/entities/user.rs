@@ -53,10 +53,7 @@ mod tests {
         user.groups.set_if_not_equals(json!(["test"]));
         user.claim_json.set_if_not_equals(json!({}));
 
-        let user = user
-            .insert(&*db.write().await)
-            .await
-            .expect("Failed to insert test user!");
+        let user = user.insert(&db).await.expect("Failed to insert test user!");
 
         assert_eq!(user.preferred_username, "Test User");
         assert_eq!(user.display_name, "Test User");
@@ -0,0 +1,151 @@
+//! What each user has picked on their profile page, keyed by their OIDC subject
+
+use sea_orm::sea_query::OnConflict;
+use sea_orm::Set;
+
+use crate::prelude::*;
+use crate::web::theme::Theme;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "user_preferences")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    /// The `sub` claim from the OIDC provider
+    pub subject: String,
+    /// A serialized [Preferences]
+    pub preferences: Json,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// A user's settings, anything that isn't set falls back to the configured defaults
+pub struct Preferences {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<Theme>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Seconds, 0 turns it off
+    pub page_refresh: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// An IANA timezone name
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How many rows to show in paged lists
+    pub page_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Only show checks with this status on the dashboard
+    pub dashboard_status: Option<ServiceStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Search the dashboard for this
+    pub dashboard_search: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Host IDs, shown at the top of the dashboard
+    pub favorite_hosts: Vec<Uuid>,
+}
+
+impl Preferences {
+    /// Adds the host to the favorites, or takes it off if it's already there
+    pub fn toggle_favorite(&mut self, host_id: Uuid) {
+        match self.favorite_hosts.iter().position(|id| *id == host_id) {
+            Some(index) => {
+                self.favorite_hosts.remove(index);
+            }
+            None => self.favorite_hosts.push(host_id),
+        }
+    }
+}
+
+impl Entity {
+    /// The user's preferences, or the defaults if they haven't saved any
+    pub async fn get_preferences(
+        db: &DatabaseConnection,
+        subject: &str,
+    ) -> Result<Preferences, Error> {
+        match Entity::find_by_id(subject).one(db).await? {
+            Some(model) => Ok(serde_json::from_value(model.preferences)?),
+            None => Ok(Preferences::default()),
+        }
+    }
+
+    /// Stores the user's preferences, replacing what was there
+    pub async fn set_preferences(
+        db: &DatabaseConnection,
+        subject: &str,
+        preferences: &Preferences,
+    ) -> Result<(), Error> {
+        let model = ActiveModel {
+            subject: Set(subject.to_string()),
+            preferences: Set(serde_json::to_value(preferences)?),
+            updated_at: Set(chrono::Utc::now()),
+        };
+        Entity::insert(model)
+            .on_conflict(
+                OnConflict::column(Column::Subject)
+                    .update_columns([Column::Preferences, Column::UpdatedAt])
+                    .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_user_preferences() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test db");
+
+        assert_eq!(
+            Entity::get_preferences(&db, "testuser")
+                .await
+                .expect("Failed to get preferences"),
+            Preferences::default()
+        );
+
+        let mut preferences = Preferences {
+            theme: Some(Theme::Dark),
+            page_size: Some(25),
+            dashboard_status: Some(ServiceStatus::Critical),
+            ..Default::default()
+        };
+        let host_id = Uuid::new_v4();
+        preferences.toggle_favorite(host_id);
+        assert_eq!(preferences.favorite_hosts, vec![host_id]);
+        Entity::set_preferences(&db, "testuser", &preferences)
+            .await
+            .expect("Failed to set preferences");
+        assert_eq!(
+            Entity::get_preferences(&db, "testuser")
+                .await
+                .expect("Failed to get preferences"),
+            preferences
+        );
+
+        // saving again replaces them
+        preferences.toggle_favorite(host_id);
+        assert!(preferences.favorite_hosts.is_empty());
+        Entity::set_preferences(&db, "testuser", &preferences)
+            .await
+            .expect("Failed to set preferences");
+        assert_eq!(
+            Entity::get_preferences(&db, "testuser")
+                .await
+                .expect("Failed to get preferences"),
+            preferences
+        );
+        assert_eq!(
+            Entity::get_preferences(&db, "someone else")
+                .await
+                .expect("Failed to get preferences"),
+            Preferences::default()
+        );
+    }
+}
@@ -0,0 +1,44 @@
+//! Adding the agent column to the Service table, used to pin a service to a remote agent or zone
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250106_add_service_agent_column" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(ColumnDef::new(Service::Agent).string().null())
+                    .table(Service::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(Service::Agent)
+                    .table(Service::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum Service {
+    Table,
+    Agent,
+}
@@ -0,0 +1,34 @@
+//! Trimming whitespace from host, host group and service names, so lookups by name are consistent
+
+use sea_orm::{ConnectionTrait, DbErr};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250107_normalize_names" // Make sure this matches with the file name
+    }
+}
+
+const TABLES: [&str; 3] = ["host", "host_group", "service"];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        for table in TABLES {
+            // skip anything that'd collide with an existing name, `check-config` reports those
+            let query = format!(
+                "UPDATE {table} SET name = TRIM(name) WHERE name != TRIM(name) AND TRIM(name) NOT IN (SELECT name FROM {table})"
+            );
+            db.execute_unprepared(&query).await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        // there's no way to know what the whitespace used to be
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! Adding URL-friendly slugs to hosts, host groups and services, so they can be linked to by name
+
+use std::collections::HashSet;
+
+use sea_orm::sea_query::{Alias, ColumnDef, Table};
+use sea_orm::{ConnectionTrait, DbErr, Statement};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+use crate::db::entities::slugify;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250108_add_slugs" // Make sure this matches with the file name
+    }
+}
+
+const TABLES: [&str; 3] = ["host", "host_group", "service"];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let backend = db.get_database_backend();
+        for table in TABLES {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Alias::new(table))
+                        .add_column_if_not_exists(
+                            ColumnDef::new(Alias::new("slug"))
+                                .string()
+                                .not_null()
+                                .default(""),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            // fill in the existing rows
+            let rows = db
+                .query_all(Statement::from_string(
+                    backend,
+                    format!("SELECT rowid, name FROM {table} ORDER BY name"),
+                ))
+                .await?;
+            let mut taken = HashSet::new();
+            for row in rows {
+                let rowid: i64 = row.try_get("", "rowid")?;
+                let name: String = row.try_get("", "name")?;
+                let base = slugify(&name);
+                let mut slug = base.clone();
+                let mut suffix = 2;
+                while taken.contains(&slug) {
+                    slug = format!("{}-{}", base, suffix);
+                    suffix += 1;
+                }
+                db.execute(Statement::from_sql_and_values(
+                    backend,
+                    format!("UPDATE {table} SET slug = ? WHERE rowid = ?"),
+                    [slug.clone().into(), rowid.into()],
+                ))
+                .await?;
+                taken.insert(slug);
+            }
+
+            db.execute_unprepared(&format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_{table}_slug ON {table} (slug) WHERE slug != ''"
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        for table in TABLES {
+            db.execute_unprepared(&format!("DROP INDEX IF EXISTS idx_{table}_slug"))
+                .await?;
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Alias::new(table))
+                        .drop_column(Alias::new("slug"))
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! Recording where and how each check was run in the service check history, so results from different agents or versions can be told apart
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250109_add_history_environment" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite can only add one column at a time
+        for column in [
+            ServiceCheckHistory::MaremmaVersion,
+            ServiceCheckHistory::Runner,
+            ServiceCheckHistory::TargetAddress,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .add_column_if_not_exists(ColumnDef::new(column).string().null())
+                        .table(ServiceCheckHistory::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            ServiceCheckHistory::MaremmaVersion,
+            ServiceCheckHistory::Runner,
+            ServiceCheckHistory::TargetAddress,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .drop_column(column)
+                        .table(ServiceCheckHistory::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum ServiceCheckHistory {
+    Table,
+    MaremmaVersion,
+    Runner,
+    TargetAddress,
+}
@@ -0,0 +1,125 @@
+//! Hourly and daily summaries of the service check history, so long-term trends survive the raw history being trimmed
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_service_check_table::ServiceCheck;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250110_create_service_check_rollup_table" // Make sure this matches with the file name
+    }
+}
+
+const INDEX_NAME: &str = "idx_service_check_rollup_period";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ServiceCheckRollup::Table)
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::ServiceCheckId)
+                            .uuid()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::Period)
+                            .string_len(8)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::PeriodStart)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::Checks)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::Ok)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::Warning)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::Critical)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::AvgLatencyMs)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckRollup::MaxLatencyMs)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("service_check_rollup_service_check_id")
+                            .from(
+                                ServiceCheckRollup::Table,
+                                ServiceCheckRollup::ServiceCheckId,
+                            )
+                            .to(ServiceCheck::Table, ServiceCheck::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // one rollup per check per period, and it's what the views filter on
+        manager
+            .create_index(
+                Index::create()
+                    .name(INDEX_NAME)
+                    .table(ServiceCheckRollup::Table)
+                    .col(ServiceCheckRollup::ServiceCheckId)
+                    .col(ServiceCheckRollup::Period)
+                    .col(ServiceCheckRollup::PeriodStart)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ServiceCheckRollup::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum ServiceCheckRollup {
+    Table,
+    Id,
+    ServiceCheckId,
+    Period,
+    PeriodStart,
+    Checks,
+    Ok,
+    Warning,
+    Critical,
+    AvgLatencyMs,
+    MaxLatencyMs,
+}
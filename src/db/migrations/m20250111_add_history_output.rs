@@ -0,0 +1,64 @@
+//! Storing the long output and key/value details from a check alongside the summary in `result_text`
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250111_add_history_output" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite can only add one column at a time
+        for mut column in [
+            ColumnDef::new(ServiceCheckHistory::LongOutput)
+                .text()
+                .null()
+                .to_owned(),
+            ColumnDef::new(ServiceCheckHistory::Details)
+                .json()
+                .null()
+                .to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .add_column_if_not_exists(&mut column)
+                        .table(ServiceCheckHistory::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            ServiceCheckHistory::LongOutput,
+            ServiceCheckHistory::Details,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .drop_column(column)
+                        .table(ServiceCheckHistory::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum ServiceCheckHistory {
+    Table,
+    LongOutput,
+    Details,
+}
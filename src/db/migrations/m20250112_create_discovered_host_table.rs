@@ -0,0 +1,80 @@
+//! Hosts found by `maremma discover`, waiting to be accepted into the config or dismissed
+
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250112_create_discovered_host_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(DiscoveredHost::Table)
+                    .col(
+                        ColumnDef::new(DiscoveredHost::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(DiscoveredHost::Address)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(DiscoveredHost::Hostname).string().null())
+                    .col(ColumnDef::new(DiscoveredHost::OpenPorts).json().not_null())
+                    .col(
+                        ColumnDef::new(DiscoveredHost::SuggestedChecks)
+                            .json()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(DiscoveredHost::HostGroups).json().not_null())
+                    .col(
+                        ColumnDef::new(DiscoveredHost::FirstSeen)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(DiscoveredHost::LastSeen)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(DiscoveredHost::Dismissed)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DiscoveredHost::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum DiscoveredHost {
+    Table,
+    Id,
+    Address,
+    Hostname,
+    OpenPorts,
+    SuggestedChecks,
+    HostGroups,
+    FirstSeen,
+    LastSeen,
+    Dismissed,
+}
@@ -0,0 +1,63 @@
+//! Recording where a discovered host came from, so each source can be kept in sync on its own
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250113_add_discovered_host_source" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite can only add one column at a time
+        for mut column in [
+            ColumnDef::new(DiscoveredHost::Source)
+                .string()
+                .not_null()
+                .default("scan")
+                .to_owned(),
+            ColumnDef::new(DiscoveredHost::ConnectByAddress)
+                .boolean()
+                .not_null()
+                .default(false)
+                .to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .add_column_if_not_exists(&mut column)
+                        .table(DiscoveredHost::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [DiscoveredHost::Source, DiscoveredHost::ConnectByAddress] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .drop_column(column)
+                        .table(DiscoveredHost::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum DiscoveredHost {
+    Table,
+    Source,
+    ConnectByAddress,
+}
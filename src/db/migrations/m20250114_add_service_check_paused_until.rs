@@ -0,0 +1,48 @@
+//! When a paused service check should be re-enabled by the shepherd
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250114_add_service_check_paused_until" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(
+                        ColumnDef::new(ServiceCheck::PausedUntil)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .table(ServiceCheck::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(ServiceCheck::PausedUntil)
+                    .table(ServiceCheck::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum ServiceCheck {
+    Table,
+    PausedUntil,
+}
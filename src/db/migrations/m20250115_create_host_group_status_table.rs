@@ -0,0 +1,72 @@
+//! The status of each host group, rolled up from its members by the shepherd
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_host_group_table::HostGroup;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250115_create_host_group_status_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(HostGroupStatus::Table)
+                    .col(
+                        ColumnDef::new(HostGroupStatus::HostGroupId)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(HostGroupStatus::Status).string().not_null())
+                    .col(
+                        ColumnDef::new(HostGroupStatus::Members)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(HostGroupStatus::Failing)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(HostGroupStatus::LastUpdated)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("host_group_status_host_group_id")
+                            .from(HostGroupStatus::Table, HostGroupStatus::HostGroupId)
+                            .to(HostGroup::Table, HostGroup::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(HostGroupStatus::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum HostGroupStatus {
+    Table,
+    HostGroupId,
+    Status,
+    Members,
+    Failing,
+    LastUpdated,
+}
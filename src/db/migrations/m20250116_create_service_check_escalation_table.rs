@@ -0,0 +1,84 @@
+//! Tracks how far each failing service check has been escalated, and whether someone's acknowledged it
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_service_check_table::ServiceCheck;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250116_create_service_check_escalation_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ServiceCheckEscalation::Table)
+                    .col(
+                        ColumnDef::new(ServiceCheckEscalation::ServiceCheckId)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckEscalation::StartedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckEscalation::Level)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckEscalation::AcknowledgedAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckEscalation::AcknowledgedBy)
+                            .string()
+                            .null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("service_check_escalation_service_check_id")
+                            .from(
+                                ServiceCheckEscalation::Table,
+                                ServiceCheckEscalation::ServiceCheckId,
+                            )
+                            .to(ServiceCheck::Table, ServiceCheck::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(ServiceCheckEscalation::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum ServiceCheckEscalation {
+    Table,
+    ServiceCheckId,
+    StartedAt,
+    Level,
+    AcknowledgedAt,
+    AcknowledgedBy,
+}
@@ -0,0 +1,86 @@
+//! Incidents group a service check's consecutive failures, from when it leaves Ok until it recovers
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_service_check_table::ServiceCheck;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250117_create_incident_table" // Make sure this matches with the file name
+    }
+}
+
+const INDEX_NAME: &str = "idx_incident_service_check_started";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Incident::Table)
+                    .col(ColumnDef::new(Incident::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(Incident::ServiceCheckId).uuid().not_null())
+                    .col(
+                        ColumnDef::new(Incident::StartedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Incident::EndedAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .col(ColumnDef::new(Incident::WorstStatus).string().not_null())
+                    .col(ColumnDef::new(Incident::LastStatus).string().not_null())
+                    .col(
+                        ColumnDef::new(Incident::Results)
+                            .big_integer()
+                            .not_null()
+                            .default(1),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("incident_service_check_id")
+                            .from(Incident::Table, Incident::ServiceCheckId)
+                            .to(ServiceCheck::Table, ServiceCheck::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // finding a check's open incident happens on every result
+        manager
+            .create_index(
+                Index::create()
+                    .name(INDEX_NAME)
+                    .table(Incident::Table)
+                    .col(Incident::ServiceCheckId)
+                    .col(Incident::StartedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Incident::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum Incident {
+    Table,
+    Id,
+    ServiceCheckId,
+    StartedAt,
+    EndedAt,
+    WorstStatus,
+    LastStatus,
+    Results,
+}
@@ -0,0 +1,54 @@
+//! Each user's UI preferences, keyed by their OIDC subject so they survive logging out
+
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250118_create_user_preferences_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserPreferences::Table)
+                    .col(
+                        ColumnDef::new(UserPreferences::Subject)
+                            .text()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(UserPreferences::Preferences)
+                            .json()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserPreferences::UpdatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UserPreferences::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum UserPreferences {
+    Table,
+    Subject,
+    Preferences,
+    UpdatedAt,
+}
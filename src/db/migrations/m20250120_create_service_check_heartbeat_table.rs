@@ -0,0 +1,68 @@
+//! Ping tokens for heartbeat service checks, and when they were last pinged
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_service_check_table::ServiceCheck;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250120_create_service_check_heartbeat_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ServiceCheckHeartbeat::Table)
+                    .col(
+                        ColumnDef::new(ServiceCheckHeartbeat::ServiceCheckId)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckHeartbeat::Token)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckHeartbeat::LastPing)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("service_check_heartbeat_service_check_id")
+                            .from(
+                                ServiceCheckHeartbeat::Table,
+                                ServiceCheckHeartbeat::ServiceCheckId,
+                            )
+                            .to(ServiceCheck::Table, ServiceCheck::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ServiceCheckHeartbeat::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum ServiceCheckHeartbeat {
+    Table,
+    ServiceCheckId,
+    Token,
+    LastPing,
+}
@@ -0,0 +1,46 @@
+//! Variables that a host's services can use in their config
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250121_add_host_vars_column" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Host::Vars).json().not_null().default("{}"),
+                    )
+                    .table(Host::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(Host::Vars)
+                    .table(Host::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Host {
+    Table,
+    Vars,
+}
@@ -0,0 +1,71 @@
+//! The last output of service checks with `detect_changes` set, and what changed in it
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_service_check_table::ServiceCheck;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250122_create_service_check_output_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ServiceCheckOutput::Table)
+                    .col(
+                        ColumnDef::new(ServiceCheckOutput::ServiceCheckId)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ServiceCheckOutput::Output).text().not_null())
+                    .col(
+                        ColumnDef::new(ServiceCheckOutput::UpdatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ServiceCheckOutput::ChangedAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .col(ColumnDef::new(ServiceCheckOutput::Diff).text().null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("service_check_output_service_check_id")
+                            .from(
+                                ServiceCheckOutput::Table,
+                                ServiceCheckOutput::ServiceCheckId,
+                            )
+                            .to(ServiceCheck::Table, ServiceCheck::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ServiceCheckOutput::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum ServiceCheckOutput {
+    Table,
+    ServiceCheckId,
+    Output,
+    UpdatedAt,
+    ChangedAt,
+    Diff,
+}
@@ -0,0 +1,61 @@
+//! Explicit IPv4 and IPv6 addresses for hosts, and which of them checks use
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250123_add_host_address_columns" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time
+        for column in [
+            ColumnDef::new(Host::Ipv4).string().null().to_owned(),
+            ColumnDef::new(Host::Ipv6).string().null().to_owned(),
+            ColumnDef::new(Host::AddressFamily)
+                .string_len(4)
+                .not_null()
+                .default("any")
+                .to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .add_column_if_not_exists(column)
+                        .table(Host::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [Host::Ipv4, Host::Ipv6, Host::AddressFamily] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .drop_column(column)
+                        .table(Host::Table)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Host {
+    Table,
+    Ipv4,
+    Ipv6,
+    AddressFamily,
+}
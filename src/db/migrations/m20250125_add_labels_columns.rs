@@ -0,0 +1,73 @@
+//! Free-form labels on hosts and services
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250125_add_labels_columns" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Host::Labels).json().not_null().default("{}"),
+                    )
+                    .table(Host::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Service::Labels)
+                            .json()
+                            .not_null()
+                            .default("{}"),
+                    )
+                    .table(Service::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(Service::Labels)
+                    .table(Service::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(Host::Labels)
+                    .table(Host::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Host {
+    Table,
+    Labels,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Service {
+    Table,
+    Labels,
+}
@@ -0,0 +1,71 @@
+//! Host groups which include other groups
+
+use sea_orm_migration::prelude::*;
+
+use super::m20240802_create_host_group_table::HostGroup;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250126_create_host_group_nesting_table" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(HostGroupNesting::Table)
+                    .col(
+                        ColumnDef::new(HostGroupNesting::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(HostGroupNesting::ParentId).uuid().not_null())
+                    .col(ColumnDef::new(HostGroupNesting::ChildId).uuid().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("host_group_nesting_parent_id")
+                            .from(HostGroupNesting::Table, HostGroupNesting::ParentId)
+                            .to(HostGroup::Table, HostGroup::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("host_group_nesting_child_id")
+                            .from(HostGroupNesting::Table, HostGroupNesting::ChildId)
+                            .to(HostGroup::Table, HostGroup::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .index(
+                        Index::create()
+                            .name("host_group_nesting_parent_child")
+                            .col(HostGroupNesting::ParentId)
+                            .col(HostGroupNesting::ChildId)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(HostGroupNesting::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub(crate) enum HostGroupNesting {
+    Table,
+    Id,
+    ParentId,
+    ChildId,
+}
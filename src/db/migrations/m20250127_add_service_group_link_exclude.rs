@@ -0,0 +1,49 @@
+//! Services can exclude host groups, eg `"host_groups": ["prod-web", "!canary"]`
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250127_add_service_group_link_exclude" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(
+                        ColumnDef::new(ServiceGroupLink::Exclude)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .table(ServiceGroupLink::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(ServiceGroupLink::Exclude)
+                    .table(ServiceGroupLink::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum ServiceGroupLink {
+    Table,
+    Exclude,
+}
@@ -0,0 +1,48 @@
+//! Storing the diagnostics from checks with `debug_capture` set, eg connection timings or a command's exit code
+
+use sea_orm::sea_query::{self, ColumnDef, Table};
+use sea_orm::{DbErr, Iden};
+use sea_orm_migration::{MigrationName, MigrationTrait, SchemaManager};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20250128_add_history_diagnostics" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .add_column_if_not_exists(
+                        ColumnDef::new(ServiceCheckHistory::Diagnostics)
+                            .json()
+                            .null(),
+                    )
+                    .table(ServiceCheckHistory::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .drop_column(ServiceCheckHistory::Diagnostics)
+                    .table(ServiceCheckHistory::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum ServiceCheckHistory {
+    Table,
+    Diagnostics,
+}
@@ -11,3 +11,27 @@ pub(crate) mod m20240825_drop_service_host_groups;
 pub(crate) mod m20240827_add_fk_host_group_members;
 pub(crate) mod m20240827_add_host_config_column;
 pub(crate) mod m20241202_add_sch_index;
+pub(crate) mod m20250106_add_service_agent_column;
+pub(crate) mod m20250107_normalize_names;
+pub(crate) mod m20250108_add_slugs;
+pub(crate) mod m20250109_add_history_environment;
+pub(crate) mod m20250110_create_service_check_rollup_table;
+pub(crate) mod m20250111_add_history_output;
+pub(crate) mod m20250112_create_discovered_host_table;
+pub(crate) mod m20250113_add_discovered_host_source;
+pub(crate) mod m20250114_add_service_check_paused_until;
+pub(crate) mod m20250115_create_host_group_status_table;
+pub(crate) mod m20250116_create_service_check_escalation_table;
+pub(crate) mod m20250117_create_incident_table;
+pub(crate) mod m20250118_create_user_preferences_table;
+pub(crate) mod m20250119_create_local_user_table;
+pub(crate) mod m20250120_create_service_check_heartbeat_table;
+pub(crate) mod m20250121_add_host_vars_column;
+pub(crate) mod m20250122_create_service_check_output_table;
+pub(crate) mod m20250123_add_host_address_columns;
+pub(crate) mod m20250124_add_host_display_name_column;
+pub(crate) mod m20250125_add_labels_columns;
+pub(crate) mod m20250126_create_host_group_nesting_table;
+pub(crate) mod m20250127_add_service_group_link_exclude;
+pub(crate) mod m20250128_add_history_diagnostics;
+pub(crate) mod m20250129_add_history_artifact;
@@ -19,6 +19,30 @@ impl MigratorTrait for Migrator {
             Box::new(super::migrations::m20240827_add_host_config_column::Migration),
             Box::new(super::migrations::m20240827_add_fk_host_group_members::Migration),
             Box::new(super::migrations::m20241202_add_sch_index::Migration),
+            Box::new(super::migrations::m20250106_add_service_agent_column::Migration),
+            Box::new(super::migrations::m20250107_normalize_names::Migration),
+            Box::new(super::migrations::m20250108_add_slugs::Migration),
+            Box::new(super::migrations::m20250109_add_history_environment::Migration),
+            Box::new(super::migrations::m20250110_create_service_check_rollup_table::Migration),
+            Box::new(super::migrations::m20250111_add_history_output::Migration),
+            Box::new(super::migrations::m20250112_create_discovered_host_table::Migration),
+            Box::new(super::migrations::m20250113_add_discovered_host_source::Migration),
+            Box::new(super::migrations::m20250114_add_service_check_paused_until::Migration),
+            Box::new(super::migrations::m20250115_create_host_group_status_table::Migration),
+            Box::new(super::migrations::m20250116_create_service_check_escalation_table::Migration),
+            Box::new(super::migrations::m20250117_create_incident_table::Migration),
+            Box::new(super::migrations::m20250118_create_user_preferences_table::Migration),
+            Box::new(super::migrations::m20250119_create_local_user_table::Migration),
+            Box::new(super::migrations::m20250120_create_service_check_heartbeat_table::Migration),
+            Box::new(super::migrations::m20250121_add_host_vars_column::Migration),
+            Box::new(super::migrations::m20250122_create_service_check_output_table::Migration),
+            Box::new(super::migrations::m20250123_add_host_address_columns::Migration),
+            Box::new(super::migrations::m20250124_add_host_display_name_column::Migration),
+            Box::new(super::migrations::m20250125_add_labels_columns::Migration),
+            Box::new(super::migrations::m20250126_create_host_group_nesting_table::Migration),
+            Box::new(super::migrations::m20250127_add_service_group_link_exclude::Migration),
+            Box::new(super::migrations::m20250128_add_history_diagnostics::Migration),
+            Box::new(super::migrations::m20250129_add_history_artifact::Migration),
         ]
     }
 }
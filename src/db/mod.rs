@@ -9,10 +9,12 @@ use sea_orm_migration::prelude::*;
 use tracing::{info, instrument};
 
 use crate::config::Configuration;
+use crate::config_events::{publish, ChangeSource, Snapshot};
 
 pub mod entities;
 pub(crate) mod migrations;
 pub(crate) mod migrator;
+pub mod sqlite;
 #[cfg(test)]
 pub(crate) mod tests;
 
@@ -37,8 +39,10 @@ pub async fn get_connect_string(config: SendableConfig) -> String {
 
 #[instrument(level = "info", skip_all)]
 pub async fn connect(config: SendableConfig) -> Result<DatabaseConnection, sea_orm::error::DbErr> {
+    let sqlite_config = config.read().await.sqlite;
     let mut connect_options = ConnectOptions::new(get_connect_string(config).await);
     connect_options
+        .map_sqlx_sqlite_opts(move |opts| sqlite_config.apply(opts))
         .sqlx_slow_statements_logging_settings(
             log::LevelFilter::Warn,
             std::time::Duration::from_secs(2),
@@ -55,11 +59,11 @@ pub async fn connect(config: SendableConfig) -> Result<DatabaseConnection, sea_o
 
 #[instrument(level = "debug", skip_all)]
 pub async fn update_db_from_config(
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
     config: SendableConfig,
 ) -> Result<(), Error> {
+    let before = Snapshot::take(&db).await?;
     // let's go through and update the DB
-    let db = db.write().await;
     entities::host::Model::update_db_from_config(&db, config.clone())
         .await
         .inspect_err(|err| {
@@ -84,6 +88,16 @@ pub async fn update_db_from_config(
         })?;
     info!("Updated host_group_members");
 
+    entities::host_group_nesting::Model::update_db_from_config(&db, config.clone())
+        .await
+        .inspect_err(|err| {
+            error!(
+                "Failed to update host_group_nesting DB from config: {:?}",
+                err
+            );
+        })?;
+    info!("Updated host_group_nesting");
+
     entities::service::Model::update_db_from_config(&db, config.clone())
         .await
         .inspect_err(|err| {
@@ -107,6 +121,7 @@ pub async fn update_db_from_config(
         })?;
     info!("Updated service checks");
 
+    publish(&db, &config, &before, ChangeSource::Config).await?;
     Ok(())
 }
 
@@ -114,54 +129,164 @@ pub async fn update_db_from_config(
 pub async fn get_next_service_check(
     db: &DatabaseConnection,
 ) -> Result<Option<(entities::service_check::Model, entities::service::Model)>, Error> {
-    let base_query =
-        entities::service_check::Entity::find().find_with_related(entities::service::Entity);
+    Ok(get_due_service_checks(db, 1, &[], CheckFilter::All)
+        .await?
+        .into_iter()
+        .next())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// Which service checks [get_due_service_checks] can return
+pub enum CheckFilter<'a> {
+    #[default]
+    /// Any of them
+    All,
+    /// Only these ones
+    Only(&'a [Uuid]),
+    /// Anything but these ones
+    Except(&'a [Uuid]),
+}
 
-    let mut res = base_query
+/// Get up to `limit` service checks which are due to run, most urgent first.
+///
+/// Urgent checks come first (oldest-updated first), then pending ones, then everything else that's due by `next_check`.
+/// Checks on the hosts in `exclude_hosts` are skipped, as are the ones `checks` leaves out.
+pub async fn get_due_service_checks(
+    db: &DatabaseConnection,
+    limit: u64,
+    exclude_hosts: &[Uuid],
+    checks: CheckFilter<'_>,
+) -> Result<Vec<(entities::service_check::Model, entities::service::Model)>, Error> {
+    let base_query = entities::service_check::Entity::find()
+        .find_also_related(entities::service::Entity)
+        .filter(entities::service_check::Column::HostId.is_not_in(exclude_hosts.iter().copied()))
+        // services pinned to an agent are run remotely
+        .filter(entities::service::Column::Agent.is_null())
+        // and passive and heartbeat services have their results submitted
+        .filter(
+            entities::service::Column::ServiceType
+                .is_not_in([ServiceType::Passive, ServiceType::Heartbeat]),
+        );
+    let base_query = match checks {
+        CheckFilter::All => base_query,
+        CheckFilter::Only(ids) => {
+            base_query.filter(entities::service_check::Column::Id.is_in(ids.iter().copied()))
+        }
+        CheckFilter::Except(ids) => {
+            base_query.filter(entities::service_check::Column::Id.is_not_in(ids.iter().copied()))
+        }
+    };
+
+    let mut rows = base_query
         .clone()
         .filter(entities::service_check::Column::Status.eq(ServiceStatus::Urgent))
         // oldest-last-updated is the most urgent
         .order_by_asc(entities::service_check::Column::LastUpdated)
+        .limit(limit)
         .all(db)
-        .await?
-        .into_iter()
-        .next();
+        .await?;
+
+    // all others we just care about:
+    // - the next_check time
+    let due_query = base_query
+        .order_by_asc(entities::service_check::Column::NextCheck)
+        .filter(
+            entities::service_check::Column::Status
+                .ne(ServiceStatus::Disabled)
+                .and(entities::service_check::Column::Status.ne(ServiceStatus::Checking))
+                .and(entities::service_check::Column::Status.ne(ServiceStatus::Urgent))
+                .and(entities::service_check::Column::NextCheck.lte(chrono::Utc::now())),
+        );
 
     // prioritize pending
-    if res.is_none() {
-        // all others we just care about:
-        // - the next_check time
-        let base_query = base_query
-            .order_by_asc(entities::service_check::Column::NextCheck)
-            .filter(
-                entities::service_check::Column::Status
+    if (rows.len() as u64) < limit {
+        rows.extend(
+            due_query
+                .clone()
+                .filter(entities::service_check::Column::Status.eq(ServiceStatus::Pending))
+                .limit(limit - rows.len() as u64)
+                .all(db)
+                .await?,
+        );
+    }
+    if (rows.len() as u64) < limit {
+        rows.extend(
+            due_query
+                .filter(entities::service_check::Column::Status.ne(ServiceStatus::Pending))
+                .limit(limit - rows.len() as u64)
+                .all(db)
+                .await?,
+        );
+    }
+
+    rows.into_iter()
+        .map(|(service_check, service)| {
+            let service = service.ok_or_else(|| {
+                Error::Generic("Failed to get service for service check".to_string())
+            })?;
+            Ok((service_check, service))
+        })
+        .collect()
+}
+
+/// Get the service checks which are due to be run by a remote agent, matching on the agent's name or zone
+pub async fn get_agent_service_checks(
+    db: &DatabaseConnection,
+    agent_name: &str,
+    zone: Option<&str>,
+) -> Result<Vec<(entities::service_check::Model, entities::service::Model)>, Error> {
+    let mut pinned = Condition::any().add(entities::service::Column::Agent.eq(agent_name));
+    if let Some(zone) = zone {
+        pinned = pinned.add(entities::service::Column::Agent.eq(zone));
+    }
+
+    let res = entities::service_check::Entity::find()
+        .find_with_related(entities::service::Entity)
+        .filter(pinned)
+        .filter(
+            entities::service_check::Column::Status
+                .eq(ServiceStatus::Urgent)
+                .or(entities::service_check::Column::Status
                     .ne(ServiceStatus::Disabled)
                     .and(entities::service_check::Column::Status.ne(ServiceStatus::Checking))
-                    .and(entities::service_check::Column::NextCheck.lte(chrono::Utc::now())),
-            )
-            .distinct();
+                    .and(entities::service_check::Column::NextCheck.lte(chrono::Utc::now()))),
+        )
+        .order_by_asc(entities::service_check::Column::NextCheck)
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|(service_check, mut services)| {
+            services.pop().map(|service| (service_check, service))
+        })
+        .collect();
+    Ok(res)
+}
 
-        if let Some(row) = base_query
-            .clone()
-            .filter(entities::service_check::Column::Status.eq(ServiceStatus::Pending))
+/// Finds hosts, host groups and services in the database whose names only differ by case or whitespace
+pub async fn find_duplicate_names(db: &DatabaseConnection) -> Result<Vec<String>, Error> {
+    let mut res = entities::find_duplicates(
+        "host",
+        entities::host::Entity::find()
             .all(db)
             .await?
             .into_iter()
-            .next()
-        {
-            res = Some(row)
-        } else {
-            res = base_query.all(db).await?.into_iter().next();
-        }
-    }
-
-    match res {
-        Some((service_check, mut services)) => {
-            let service = services.pop().ok_or_else(|| {
-                Error::Generic("Failed to get service for service check".to_string())
-            })?;
-            Ok(Some((service_check, service)))
-        }
-        None => Ok(None),
-    }
+            .map(|host| host.name),
+    );
+    res.extend(entities::find_duplicates(
+        "host group",
+        entities::host_group::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|group| group.name),
+    ));
+    res.extend(entities::find_duplicates(
+        "service",
+        entities::service::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|service| service.name),
+    ));
+    Ok(res)
 }
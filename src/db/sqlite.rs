@@ -0,0 +1,175 @@
+//! SQLite tuning, applied to every connection in the pool
+
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+
+use crate::prelude::*;
+
+/// The default for [SqliteConfig::busy_timeout_ms]
+pub const DEFAULT_SQLITE_BUSY_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+/// How SQLite keeps its journal, see <https://www.sqlite.org/pragma.html#pragma_journal_mode>
+pub enum JournalMode {
+    /// Write-ahead logging, readers don't block the writer and the writer doesn't block readers
+    #[default]
+    Wal,
+    /// SQLite's default, the journal's deleted at the end of each transaction
+    Delete,
+    /// Truncate the journal instead of deleting it
+    Truncate,
+    /// Overwrite the journal's header instead of deleting it
+    Persist,
+    /// Keep the journal in memory
+    Memory,
+    /// No journal at all, a crash can corrupt the database
+    Off,
+}
+
+impl From<JournalMode> for SqliteJournalMode {
+    fn from(value: JournalMode) -> Self {
+        match value {
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Off => SqliteJournalMode::Off,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+/// How often SQLite waits for writes to hit the disk, see <https://www.sqlite.org/pragma.html#pragma_synchronous>
+pub enum Synchronous {
+    /// Never wait, fastest but a power cut can corrupt the database
+    Off,
+    /// Wait at the critical moments, safe with WAL
+    #[default]
+    Normal,
+    /// Wait on every write
+    Full,
+    /// Like full, and also syncs the directory when the journal's deleted
+    Extra,
+}
+
+impl From<Synchronous> for SqliteSynchronous {
+    fn from(value: Synchronous) -> Self {
+        match value {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        }
+    }
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    DEFAULT_SQLITE_BUSY_TIMEOUT_MS
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+/// SQLite settings, the defaults suit most installs
+pub struct SqliteConfig {
+    /// The journal mode, defaults to `wal`
+    #[serde(default)]
+    pub journal_mode: JournalMode,
+    /// How careful SQLite is about syncing to disk, defaults to `normal`
+    #[serde(default)]
+    pub synchronous: Synchronous,
+    /// How long to wait for a lock before giving up with "database is locked", defaults to [DEFAULT_SQLITE_BUSY_TIMEOUT_MS]
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    /// The page cache size, positive numbers are pages and negative numbers are KiB, eg `-20000` for about 20MB. SQLite's default if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_size: Option<i64>,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            busy_timeout_ms: DEFAULT_SQLITE_BUSY_TIMEOUT_MS,
+            cache_size: None,
+        }
+    }
+}
+
+impl SqliteConfig {
+    /// Sets the pragmas on the connection options
+    pub fn apply(&self, options: SqliteConnectOptions) -> SqliteConnectOptions {
+        let options = options
+            .journal_mode(self.journal_mode.into())
+            .synchronous(self.synchronous.into())
+            .busy_timeout(Duration::from_millis(self.busy_timeout_ms));
+        match self.cache_size {
+            Some(cache_size) => options.pragma("cache_size", cache_size.to_string()),
+            None => options,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{ConnectionTrait, Statement};
+
+    #[tokio::test]
+    async fn test_sqlite_pragmas() {
+        let tempfile = tempfile::NamedTempFile::new().expect("Failed to create tempfile");
+        let config = Configuration {
+            database_file: tempfile
+                .path()
+                .to_str()
+                .expect("Failed to get filepath")
+                .to_string(),
+            sqlite: SqliteConfig {
+                cache_size: Some(-4000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let db = crate::db::connect(Arc::new(RwLock::new(config)))
+            .await
+            .expect("Failed to connect");
+
+        for (pragma, expected) in [
+            ("journal_mode", json!("wal")),
+            ("synchronous", json!(1)),
+            ("busy_timeout", json!(DEFAULT_SQLITE_BUSY_TIMEOUT_MS)),
+            ("cache_size", json!(-4000)),
+        ] {
+            let row = db
+                .query_one(Statement::from_string(
+                    db.get_database_backend(),
+                    format!("PRAGMA {}", pragma),
+                ))
+                .await
+                .expect("Failed to query pragma")
+                .expect("No pragma result");
+            let value: Value = match expected {
+                Value::String(_) => json!(row
+                    .try_get_by_index::<String>(0)
+                    .expect("Failed to read pragma")),
+                _ => json!(row
+                    .try_get_by_index::<i64>(0)
+                    .expect("Failed to read pragma")),
+            };
+            assert_eq!(value, expected, "PRAGMA {}", pragma);
+        }
+
+        assert_eq!(
+            serde_json::from_value::<SqliteConfig>(json!({"journal_mode": "delete"}))
+                .expect("Failed to parse config"),
+            SqliteConfig {
+                journal_mode: JournalMode::Delete,
+                ..Default::default()
+            }
+        );
+    }
+}
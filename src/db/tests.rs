@@ -1,7 +1,9 @@
-use crate::db::{get_next_service_check, update_db_from_config};
+use crate::db::{
+    get_due_service_checks, get_next_service_check, update_db_from_config, CheckFilter,
+};
 use crate::prelude::*;
 
-use crate::log::setup_logging;
+use crate::log::{setup_logging, LogFormat};
 
 #[tokio::test]
 async fn test_next_service_check() {
@@ -11,31 +13,76 @@ async fn test_next_service_check() {
         .await
         .unwrap();
 
-    let next_check = get_next_service_check(&*db.read().await).await.unwrap();
+    let next_check = get_next_service_check(&db).await.unwrap();
     dbg!(&next_check);
     assert!(next_check.is_some());
 }
 
-pub(crate) async fn test_setup() -> Result<(Arc<RwLock<DatabaseConnection>>, SendableConfig), Error>
-{
+#[tokio::test]
+async fn test_due_service_checks_batch() {
+    let (db, _config) = test_setup().await.expect("Failed to start test harness");
+
+    let all = get_due_service_checks(&db, 1000, &[], CheckFilter::All)
+        .await
+        .expect("Failed to get due checks");
+    assert!(all.len() > 2);
+    let mut ids = all.iter().map(|(sc, _)| sc.id).collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), all.len(), "Checks showed up more than once");
+
+    // urgent checks jump the queue, even if they're not due yet
+    let (last, _) = all.last().expect("No checks").clone();
+    last.set_status(ServiceStatus::Urgent, db.clone())
+        .await
+        .expect("Failed to set status to urgent");
+    let batch = get_due_service_checks(&db, 2, &[], CheckFilter::All)
+        .await
+        .expect("Failed to get due checks");
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch.first().map(|(sc, _)| sc.id), Some(last.id));
+    assert!(batch
+        .iter()
+        .skip(1)
+        .all(|(sc, _)| sc.status != ServiceStatus::Urgent));
+
+    let excluded = get_due_service_checks(&db, 1000, &[last.host_id], CheckFilter::All)
+        .await
+        .expect("Failed to get due checks");
+    assert!(!excluded.is_empty());
+    assert!(excluded.iter().all(|(sc, _)| sc.host_id != last.host_id));
+
+    let only = get_due_service_checks(&db, 1000, &[], CheckFilter::Only(&[last.id]))
+        .await
+        .expect("Failed to get due checks");
+    assert_eq!(
+        only.iter().map(|(sc, _)| sc.id).collect::<Vec<_>>(),
+        vec![last.id]
+    );
+    let except = get_due_service_checks(&db, 1000, &[], CheckFilter::Except(&[last.id]))
+        .await
+        .expect("Failed to get due checks");
+    assert_eq!(except.len(), all.len() - 1);
+    assert!(except.iter().all(|(sc, _)| sc.id != last.id));
+}
+
+pub(crate) async fn test_setup() -> Result<(DatabaseConnection, SendableConfig), Error> {
     test_setup_harness(true, false).await
 }
 
 pub(crate) async fn test_setup_harness(
     debug: bool,
     db_debug: bool,
-) -> Result<(Arc<RwLock<DatabaseConnection>>, SendableConfig), Error> {
+) -> Result<(DatabaseConnection, SendableConfig), Error> {
     // make sure logging is happening
 
-    let _ = setup_logging(debug, db_debug);
+    let _ = setup_logging(debug, db_debug, LogFormat::Text);
     // enable the rustls crypto provider
     let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
-    let db = Arc::new(RwLock::new(
-        crate::db::test_connect()
-            .await
-            .expect("Failed to connect to database"),
-    ));
+    let db = crate::db::test_connect()
+        .await
+        .expect("Failed to connect to database");
 
     let config = Configuration::load_test_config().await;
 
@@ -45,21 +92,14 @@ pub(crate) async fn test_setup_harness(
     Ok((db, config))
 }
 
-pub(crate) async fn test_setup_quieter(
-) -> Result<(Arc<RwLock<DatabaseConnection>>, SendableConfig), Error> {
+pub(crate) async fn test_setup_quieter() -> Result<(DatabaseConnection, SendableConfig), Error> {
     test_setup_harness(false, false).await
 }
 
-pub(crate) async fn test_setup_with_real_db() -> Result<
-    (
-        tempfile::NamedTempFile,
-        Arc<RwLock<DatabaseConnection>>,
-        SendableConfig,
-    ),
-    Error,
-> {
+pub(crate) async fn test_setup_with_real_db(
+) -> Result<(tempfile::NamedTempFile, DatabaseConnection, SendableConfig), Error> {
     // make sure logging is happening
-    let _ = setup_logging(true, true);
+    let _ = setup_logging(true, true, LogFormat::Text);
     // enable the rustls crypto provider
     let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
@@ -74,11 +114,9 @@ pub(crate) async fn test_setup_with_real_db() -> Result<
         .expect("Failed to get filepath")
         .to_string();
 
-    let db = Arc::new(RwLock::new(
-        crate::db::connect(config.clone())
-            .await
-            .expect("Failed to connect to database"),
-    ));
+    let db = crate::db::connect(config.clone())
+        .await
+        .expect("Failed to connect to database");
 
     crate::db::update_db_from_config(db.clone(), config.clone())
         .await
@@ -91,7 +129,7 @@ async fn test_get_related() {
     let (db, _config) = test_setup().await.expect("Failed to start test harness");
 
     for host in entities::host::Entity::find()
-        .all(&*db.read().await)
+        .all(&db)
         .await
         .unwrap()
         .into_iter()
@@ -99,7 +137,7 @@ async fn test_get_related() {
         info!("Found host: {:?}", host);
 
         let host_group_members = entities::host_group_members::Entity::find()
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .unwrap();
 
@@ -107,7 +145,7 @@ async fn test_get_related() {
 
         let linked = host
             .find_linked(entities::host_group_members::HostToGroups)
-            .all(&*db.read().await)
+            .all(&db)
             .await
             .expect("Failed to find linked");
         println!("linked {:?}", linked);
@@ -122,17 +160,20 @@ async fn test_failing_update_db_from_config() {
         .append_query_results([[entities::host::Model {
             id: Uuid::new_v4(),
             name: "Apple Pie".to_owned(),
+            slug: "apple-pie".to_string(),
             hostname: "localhost".to_owned(),
             check: crate::host::HostCheck::Ping,
             config: serde_json::json!({}),
+            vars: serde_json::json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         }]])
         .into_connection();
 
-    let res = update_db_from_config(
-        Arc::new(RwLock::new(db)),
-        Configuration::load_test_config().await,
-    )
-    .await;
+    let res = update_db_from_config(db, Configuration::load_test_config().await).await;
 
     dbg!(&res);
     assert!(res.is_err());
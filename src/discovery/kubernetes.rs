@@ -0,0 +1,154 @@
+//! Finding hosts from a Kubernetes cluster's nodes
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::api::ListParams;
+use kube::config::KubeConfigOptions;
+use kube::{Api, Client};
+
+use super::sources::Candidate;
+use crate::prelude::*;
+
+/// Nodes with this annotation set to `true` are skipped, the same as everything else Maremma finds in a cluster
+pub const IGNORE_ANNOTATION: &str = "maremma.terminaloutcomes.com/ignore";
+
+/// The host group a label maps to, its value, or the last part of its name if the value's empty (like `node-role.kubernetes.io/control-plane`)
+fn label_group(key: &str, value: &str) -> Option<String> {
+    let group = match value.trim() {
+        "" => key.rsplit('/').next().unwrap_or(key).trim(),
+        value => value,
+    };
+    (!group.is_empty()).then(|| group.to_string())
+}
+
+/// Maps the node's labels to host groups, only the labels listed in `group_labels` are used
+pub fn label_groups(
+    labels: &BTreeMap<String, String>,
+    group_labels: &[String],
+) -> BTreeSet<String> {
+    group_labels
+        .iter()
+        .filter_map(|key| labels.get(key).and_then(|value| label_group(key, value)))
+        .collect()
+}
+
+/// Turns a node into a candidate host, preferring its internal address
+pub fn node_candidate(node: &Node, group_labels: &[String]) -> Option<Candidate> {
+    let name = node.metadata.name.clone()?;
+    if node
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(IGNORE_ANNOTATION))
+        .is_some_and(|value| value == "true")
+    {
+        debug!("Ignoring node {}", name);
+        return None;
+    }
+
+    let addresses = node
+        .status
+        .as_ref()
+        .and_then(|status| status.addresses.as_ref())?;
+    let address = ["InternalIP", "ExternalIP"]
+        .iter()
+        .find_map(|address_type| {
+            addresses
+                .iter()
+                .filter(|address| address.type_ == *address_type)
+                .find_map(|address| address.address.parse::<IpAddr>().ok())
+        })?;
+
+    Some(Candidate {
+        name,
+        address,
+        groups: node
+            .metadata
+            .labels
+            .as_ref()
+            .map(|labels| label_groups(labels, group_labels))
+            .unwrap_or_default(),
+        connect_by_address: true,
+    })
+}
+
+/// Lists the nodes in the cluster, using `context` from the kubeconfig or the default client
+pub async fn node_candidates(
+    context: Option<&str>,
+    group_labels: &[String],
+) -> Result<Vec<Candidate>, Error> {
+    let client = match context {
+        Some(context) => Client::try_from(
+            kube::Config::from_kubeconfig(&KubeConfigOptions {
+                context: Some(context.to_string()),
+                cluster: None,
+                user: None,
+            })
+            .await?,
+        )?,
+        None => Client::try_default().await?,
+    };
+    let nodes: Api<Node> = Api::all(client);
+    Ok(nodes
+        .list(&ListParams::default())
+        .await?
+        .iter()
+        .filter_map(|node| node_candidate(node, group_labels))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(annotations: &[(&str, &str)]) -> Node {
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": "node1",
+                "labels": {
+                    "kubernetes.io/os": "linux",
+                    "node-role.kubernetes.io/control-plane": "",
+                    "topology.kubernetes.io/zone": "zone-a",
+                },
+                "annotations": annotations
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), json!(value)))
+                    .collect::<Map<String, Value>>(),
+            },
+            "status": {
+                "addresses": [
+                    {"type": "Hostname", "address": "node1"},
+                    {"type": "ExternalIP", "address": "203.0.113.5"},
+                    {"type": "InternalIP", "address": "10.0.0.5"},
+                ]
+            }
+        }))
+        .expect("Failed to build node")
+    }
+
+    #[test]
+    fn test_node_candidate() {
+        let group_labels = vec![
+            "kubernetes.io/os".to_string(),
+            "node-role.kubernetes.io/control-plane".to_string(),
+            "not-a-label".to_string(),
+        ];
+        let candidate = node_candidate(&node(&[]), &group_labels).expect("No candidate");
+        assert_eq!(candidate.name, "node1");
+        assert_eq!(
+            candidate.address,
+            "10.0.0.5"
+                .parse::<IpAddr>()
+                .expect("Failed to parse address")
+        );
+        assert_eq!(
+            candidate.groups,
+            BTreeSet::from(["control-plane".to_string(), "linux".to_string()])
+        );
+
+        assert!(node_candidate(&node(&[(IGNORE_ANNOTATION, "true")]), &group_labels).is_none());
+        assert!(node_candidate(&node(&[(IGNORE_ANNOTATION, "false")]), &group_labels).is_some());
+    }
+}
@@ -0,0 +1,578 @@
+//! Finding hosts on the network with `maremma discover`, or from the configured discovery sources, so they can be proposed for the config
+
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use futures::StreamExt;
+use ipnet::IpNet;
+use tokio::net::TcpStream;
+
+use crate::cli::{DiscoverCmd, OutputFormat};
+use crate::import::{merge_into_config_file, ImportChange, ImportedHost};
+use crate::prelude::*;
+use entities::discovered_host;
+use sources::DiscoverySource;
+
+pub mod kubernetes;
+pub mod sources;
+pub mod zone;
+
+/// The ports checked if none are given, they map to the SSH, HTTP and TLS services
+pub const DEFAULT_PORTS: [u16; 3] = [22, 80, 443];
+
+/// Stops someone scanning a /8 by accident
+const MAX_DISCOVERY_ADDRESSES: usize = 65536;
+
+#[derive(Clone, Debug)]
+/// How a scan is run
+pub struct DiscoveryOptions {
+    /// The TCP ports to try
+    pub ports: Vec<u16>,
+    /// How long to wait for each ping or connection
+    pub timeout: std::time::Duration,
+    /// How many addresses are probed at once
+    pub concurrency: usize,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            ports: DEFAULT_PORTS.to_vec(),
+            timeout: std::time::Duration::from_secs(1),
+            concurrency: 64,
+        }
+    }
+}
+
+impl From<&DiscoverCmd> for DiscoveryOptions {
+    fn from(cmd: &DiscoverCmd) -> Self {
+        let mut ports = cmd.ports.clone();
+        ports.sort_unstable();
+        ports.dedup();
+        Self {
+            ports,
+            timeout: std::time::Duration::from_millis(cmd.timeout_ms),
+            concurrency: cmd.concurrency.max(1),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+/// Something which answered a ping or had an open port
+pub struct DiscoveredHost {
+    /// The address it answered on
+    pub address: IpAddr,
+    /// The name from its PTR record
+    pub hostname: Option<String>,
+    /// The ports which accepted a connection
+    pub open_ports: Vec<u16>,
+    /// The kinds of check the open ports suggest
+    pub suggested_checks: Vec<ServiceType>,
+}
+
+/// Expands a CIDR range into the addresses to scan, a single address works too
+pub fn parse_cidr(cidr: &str) -> Result<Vec<IpAddr>, Error> {
+    if let Ok(address) = IpAddr::from_str(cidr.trim()) {
+        return Ok(vec![address]);
+    }
+    let network = IpNet::from_str(cidr.trim())
+        .map_err(|err| Error::InvalidInput(format!("Invalid CIDR range '{}': {}", cidr, err)))?;
+    let addresses = network
+        .hosts()
+        .take(MAX_DISCOVERY_ADDRESSES + 1)
+        .collect::<Vec<_>>();
+    if addresses.len() > MAX_DISCOVERY_ADDRESSES {
+        return Err(Error::InvalidInput(format!(
+            "{} has more than {} addresses, scan a smaller range",
+            cidr, MAX_DISCOVERY_ADDRESSES
+        )));
+    }
+    Ok(addresses)
+}
+
+/// The kinds of check worth running against a host with these ports open
+pub fn suggested_checks(open_ports: &[u16]) -> Vec<ServiceType> {
+    let mut checks = Vec::new();
+    for port in open_ports {
+        let suggested: &[ServiceType] = match port {
+            22 => &[ServiceType::Ssh],
+            80 | 8080 => &[ServiceType::Http],
+            443 | 8443 => &[ServiceType::Http, ServiceType::Tls],
+            _ => &[],
+        };
+        for check in suggested {
+            if !checks.contains(check) {
+                checks.push(check.clone());
+            }
+        }
+    }
+    checks
+}
+
+/// The host groups a discovered host joins: the ones asked for, plus the groups of any configured services of the suggested kinds
+pub fn suggested_groups(
+    config: &Configuration,
+    checks: &[ServiceType],
+    extra: &[String],
+) -> BTreeSet<String> {
+    let mut groups = extra.iter().cloned().collect::<BTreeSet<_>>();
+    for service in config.services.values() {
+        if checks.contains(&service.service_type) {
+            // a discovered host shouldn't join the groups the service leaves out
+            groups.extend(
+                service
+                    .host_groups
+                    .iter()
+                    .map(|group| crate::host::group::parse_selector(group))
+                    .filter(|(_, exclude)| !exclude)
+                    .map(|(group, _)| group.to_string()),
+            );
+        }
+    }
+    groups
+}
+
+/// The name of the configured host this already is, if any
+pub fn configured_as(config: &Configuration, found: &DiscoveredHost) -> Option<String> {
+    let mut names = vec![entities::name_key(&found.address.to_string())];
+    if let Some(hostname) = &found.hostname {
+        names.push(entities::name_key(hostname));
+    }
+    config
+        .hosts
+        .iter()
+        .find(|(name, host)| {
+            names.contains(&entities::name_key(name))
+                || host
+                    .hostname
+                    .as_ref()
+                    .is_some_and(|hostname| names.contains(&entities::name_key(hostname)))
+        })
+        .map(|(name, _)| name.clone())
+}
+
+async fn ping(address: IpAddr, timeout: std::time::Duration) -> bool {
+    match tokio::time::timeout(timeout, surge_ping::ping(address, &[0; 8])).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(err)) => {
+            // unprivileged users often can't ping, the port checks still work
+            debug!("Ping to {} failed: {}", address, err);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+async fn port_open(address: IpAddr, port: u16, timeout: std::time::Duration) -> bool {
+    matches!(
+        tokio::time::timeout(timeout, TcpStream::connect((address, port))).await,
+        Ok(Ok(_))
+    )
+}
+
+async fn reverse_lookup(address: IpAddr) -> Option<String> {
+    let name = tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&address))
+        .await
+        .ok()?
+        .ok()?;
+    let name = name.trim_end_matches('.');
+    // without a PTR record some resolvers hand back the address
+    (!name.is_empty() && IpAddr::from_str(name).is_err()).then(|| name.to_string())
+}
+
+/// Pings and port-scans one address, returns `None` if nothing answered
+pub async fn probe(address: IpAddr, options: &DiscoveryOptions) -> Option<DiscoveredHost> {
+    let (pinged, ports) = tokio::join!(
+        ping(address, options.timeout),
+        futures::future::join_all(options.ports.iter().map(|port| port_open(
+            address,
+            *port,
+            options.timeout
+        )))
+    );
+    let open_ports = options
+        .ports
+        .iter()
+        .zip(ports)
+        .filter(|(_, open)| *open)
+        .map(|(port, _)| *port)
+        .collect::<Vec<_>>();
+    if !pinged && open_ports.is_empty() {
+        return None;
+    }
+    Some(DiscoveredHost {
+        address,
+        hostname: reverse_lookup(address).await,
+        suggested_checks: suggested_checks(&open_ports),
+        open_ports,
+    })
+}
+
+/// Probes all the addresses, `concurrency` at a time
+pub async fn scan(addresses: Vec<IpAddr>, options: &DiscoveryOptions) -> Vec<DiscoveredHost> {
+    let mut found = futures::stream::iter(addresses)
+        .map(|address| probe(address, options))
+        .buffer_unordered(options.concurrency.max(1))
+        .filter_map(|host| async move { host })
+        .collect::<Vec<_>>()
+        .await;
+    found.sort_by_key(|host| host.address);
+    found
+}
+
+/// Adds the discovered hosts to the config file, and removes them from the list waiting for review
+pub async fn accept(
+    config_file: &Path,
+    db: &DatabaseConnection,
+    proposals: &[discovered_host::Model],
+) -> Result<Vec<ImportChange>, Error> {
+    accept_hosts(
+        config_file,
+        db,
+        proposals
+            .iter()
+            .map(discovered_host::Model::to_imported_host)
+            .collect(),
+        proposals,
+    )
+    .await
+}
+
+/// Merges the hosts into the config file, then removes the proposals from the list waiting for review
+pub async fn accept_hosts(
+    config_file: &Path,
+    db: &DatabaseConnection,
+    hosts: Vec<ImportedHost>,
+    proposals: &[discovered_host::Model],
+) -> Result<Vec<ImportChange>, Error> {
+    let changes = merge_into_config_file(config_file, hosts, false).await?;
+    discovered_host::Entity::delete_many()
+        .filter(discovered_host::Column::Id.is_in(proposals.iter().map(|proposal| proposal.id)))
+        .exec(db)
+        .await?;
+    Ok(changes)
+}
+
+#[derive(Debug, Default)]
+/// What syncing a discovery source found
+pub struct SourceSync {
+    /// New hosts waiting for review
+    pub proposals: Vec<discovered_host::Model>,
+    /// Everything the source reported which isn't dismissed, already configured hosts keep their configured name so their groups stay in sync
+    pub hosts: Vec<ImportedHost>,
+}
+
+/// Fetches the source's hosts, records the new ones for review and clears out proposals the source no longer reports
+pub async fn sync_source(
+    db: &DatabaseConnection,
+    config: &Configuration,
+    name: &str,
+    source: &DiscoverySource,
+) -> Result<SourceSync, Error> {
+    let started = chrono::Utc::now();
+    let key = source.key(name);
+    let mut sync = SourceSync::default();
+    for candidate in source.candidates().await? {
+        let found = candidate.discovered_host();
+        if let Some(configured) = configured_as(config, &found) {
+            sync.hosts.push(ImportedHost {
+                name: configured,
+                hostname: None,
+                groups: candidate.groups,
+            });
+            continue;
+        }
+        let proposal = discovered_host::Entity::record(
+            db,
+            &key,
+            &found,
+            &candidate.groups,
+            candidate.connect_by_address,
+        )
+        .await?;
+        if !proposal.dismissed {
+            sync.hosts.push(proposal.to_imported_host());
+            sync.proposals.push(proposal);
+        }
+    }
+    let pruned = discovered_host::Entity::prune_source(db, &key, started).await?;
+    if pruned > 0 {
+        debug!("Removed {} stale proposals from {}", pruned, key);
+    }
+    Ok(sync)
+}
+
+struct Proposal<'a>(&'a discovered_host::Model);
+
+impl Display for Proposal<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |items: Vec<String>| match items.is_empty() {
+            true => "none".to_string(),
+            false => items.join(", "),
+        };
+        write!(
+            f,
+            "? {} ({}) ports: {} checks: {} groups: {}",
+            self.0.name(),
+            self.0.address,
+            join(
+                self.0
+                    .open_ports_list()
+                    .iter()
+                    .map(|port| port.to_string())
+                    .collect()
+            ),
+            join(
+                self.0
+                    .suggested_checks_list()
+                    .iter()
+                    .map(|check| check.to_string())
+                    .collect()
+            ),
+            join(self.0.host_groups_list()),
+        )
+    }
+}
+
+/// Scans the range or syncs the source, records what's found for review and prints it, adding it all to the config straight away with `--accept`
+pub async fn run_discover(
+    cmd: &DiscoverCmd,
+    config: &Configuration,
+    db: &DatabaseConnection,
+) -> Result<(), Error> {
+    let (proposals, hosts) = match (&cmd.source, &cmd.cidr) {
+        (Some(name), _) => {
+            let source = config.discovery.sources.get(name).ok_or_else(|| {
+                Error::InvalidInput(format!("Discovery source {} isn't in the config", name))
+            })?;
+            info!("Syncing discovery source {}", name);
+            let sync = sync_source(db, config, name, source).await?;
+            (sync.proposals, sync.hosts)
+        }
+        (None, Some(cidr)) => {
+            let proposals = scan_cidr(cmd, cidr, config, db).await?;
+            let hosts = proposals
+                .iter()
+                .map(discovered_host::Model::to_imported_host)
+                .collect();
+            (proposals, hosts)
+        }
+        (None, None) => {
+            return Err(Error::InvalidInput(
+                "Either --cidr or --source is needed".to_string(),
+            ))
+        }
+    };
+
+    match cmd.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&proposals)?),
+        OutputFormat::Text => {
+            for proposal in proposals.iter() {
+                println!("{}", Proposal(proposal));
+            }
+        }
+    }
+
+    if proposals.is_empty() && cmd.format == OutputFormat::Text {
+        println!("No new hosts found");
+    }
+    // a source can change the groups of hosts which are already configured, so this runs even with no new hosts
+    if cmd.accept {
+        for change in accept_hosts(&cmd.sharedopts.config, db, hosts, &proposals).await? {
+            println!("{}", change);
+        }
+    } else if !proposals.is_empty() && cmd.format == OutputFormat::Text {
+        println!("Add them with --accept, or review them on the discovery page");
+    }
+    Ok(())
+}
+
+/// Scans the range and records the hosts which aren't configured yet
+async fn scan_cidr(
+    cmd: &DiscoverCmd,
+    cidr: &str,
+    config: &Configuration,
+    db: &DatabaseConnection,
+) -> Result<Vec<discovered_host::Model>, Error> {
+    let addresses = parse_cidr(cidr)?;
+    info!("Scanning {} addresses in {}", addresses.len(), cidr);
+    let found = scan(addresses, &DiscoveryOptions::from(cmd)).await;
+
+    let mut proposals = Vec::new();
+    for host in found {
+        if let Some(name) = configured_as(config, &host) {
+            if cmd.format == OutputFormat::Text {
+                println!("= {} is already configured as {}", host.address, name);
+            }
+            continue;
+        }
+        let groups = suggested_groups(config, &host.suggested_checks, &cmd.host_group);
+        let proposal = discovered_host::Entity::record(db, "scan", &host, &groups, false).await?;
+        if !proposal.dismissed {
+            proposals.push(proposal);
+        }
+    }
+    Ok(proposals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(
+            parse_cidr("192.0.2.1").expect("Failed to parse address"),
+            vec![IpAddr::from([192, 0, 2, 1])]
+        );
+        assert_eq!(
+            parse_cidr("192.0.2.0/30").expect("Failed to parse range"),
+            vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]
+        );
+        assert_eq!(
+            parse_cidr("10.0.0.0/24")
+                .expect("Failed to parse range")
+                .len(),
+            254
+        );
+        assert!(parse_cidr("10.0.0.0/8").is_err());
+        assert!(parse_cidr("2001:db8::/64").is_err());
+        assert!(parse_cidr("not a range").is_err());
+    }
+
+    #[test]
+    fn test_suggested_checks() {
+        assert_eq!(
+            suggested_checks(&[22, 80, 443]),
+            vec![ServiceType::Ssh, ServiceType::Http, ServiceType::Tls]
+        );
+        assert_eq!(
+            suggested_checks(&[443, 8080]),
+            vec![ServiceType::Http, ServiceType::Tls]
+        );
+        assert!(suggested_checks(&[3306]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe_and_suggest() {
+        let (_db, config) = test_setup().await.expect("Failed to set up tests");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to listen");
+        let port = listener
+            .local_addr()
+            .expect("Failed to get listener address")
+            .port();
+
+        let options = DiscoveryOptions {
+            ports: vec![port],
+            ..Default::default()
+        };
+        let found = probe(IpAddr::from([127, 0, 0, 1]), &options)
+            .await
+            .expect("Didn't find the listener");
+        assert_eq!(found.open_ports, vec![port]);
+
+        let config = config.read().await;
+        let ssh_groups = config
+            .services
+            .values()
+            .filter(|service| service.service_type == ServiceType::Ssh)
+            .flat_map(|service| service.host_groups.iter().cloned())
+            .collect::<BTreeSet<_>>();
+        let groups = suggested_groups(&config, &[ServiceType::Ssh], &["extra".to_string()]);
+        assert!(groups.contains("extra"));
+        assert!(groups.is_superset(&ssh_groups));
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create config file");
+        tokio::fs::copy("maremma.example.json", config_file.path())
+            .await
+            .expect("Failed to copy example config");
+
+        let proposal = discovered_host::Entity::record(
+            &db,
+            "scan",
+            &DiscoveredHost {
+                address: IpAddr::from([192, 0, 2, 10]),
+                hostname: None,
+                open_ports: vec![22],
+                suggested_checks: vec![ServiceType::Ssh],
+            },
+            &BTreeSet::from(["discovered".to_string()]),
+            false,
+        )
+        .await
+        .expect("Failed to record host");
+
+        let changes = accept(config_file.path(), &db, &[proposal])
+            .await
+            .expect("Failed to accept host");
+        assert_eq!(
+            changes,
+            vec![ImportChange::AddHost {
+                name: "192.0.2.10".to_string(),
+                groups: BTreeSet::from(["discovered".to_string()]),
+            }]
+        );
+        let config = Configuration::new(&config_file.path().to_path_buf())
+            .await
+            .expect("Failed to load updated config");
+        assert!(config.hosts.contains_key("192.0.2.10"));
+        assert!(discovered_host::Entity::pending(&db)
+            .await
+            .expect("Failed to list pending hosts")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_zone_file_source() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        let zone_file = tempfile::NamedTempFile::new().expect("Failed to create zone file");
+        tokio::fs::write(
+            zone_file.path(),
+            "$ORIGIN example.com.\n@ IN A 192.0.2.1\nnew IN A 192.0.2.2\n",
+        )
+        .await
+        .expect("Failed to write zone file");
+        let source: DiscoverySource = serde_json::from_value(json!({
+            "type": "zone_file",
+            "path": zone_file.path(),
+            "zone": "example.com",
+            "host_groups": ["from_dns"],
+        }))
+        .expect("Failed to parse source");
+
+        let config = config.read().await;
+        let sync = sync_source(&db, &config, "corp", &source)
+            .await
+            .expect("Failed to sync source");
+        assert_eq!(sync.proposals.len(), 1);
+        assert_eq!(sync.proposals[0].name(), "new.example.com");
+        assert_eq!(sync.proposals[0].source, "zone_file:corp");
+        // example.com is already configured, so it only gets the source's groups
+        assert!(sync.hosts.iter().any(|host| host.name == "example.com"
+            && host.hostname.is_none()
+            && host.groups.contains("from_dns")));
+
+        // hosts which disappear from the source stop being proposed
+        tokio::fs::write(zone_file.path(), "$ORIGIN example.com.\n@ IN A 192.0.2.1\n")
+            .await
+            .expect("Failed to write zone file");
+        let sync = sync_source(&db, &config, "corp", &source)
+            .await
+            .expect("Failed to sync source");
+        assert!(sync.proposals.is_empty());
+        assert!(discovered_host::Entity::pending(&db)
+            .await
+            .expect("Failed to list pending hosts")
+            .is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+//! Configured places hosts are discovered from, other than scanning a range
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use super::kubernetes::node_candidates;
+use super::zone::{axfr, parse_zone_file, AddressRecord};
+use super::DiscoveredHost;
+use crate::prelude::*;
+
+fn default_axfr_timeout_seconds() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// Where a source gets its hosts from
+pub enum DiscoverySourceKind {
+    /// The nodes in a Kubernetes cluster
+    Kubernetes {
+        /// The kubeconfig context to use, defaults to the current context or the in-cluster config
+        #[serde(default)]
+        context: Option<String>,
+        /// Node labels which become host groups, the group is the label's value
+        #[serde(default)]
+        group_labels: Vec<String>,
+    },
+    /// The A and AAAA records in a zone file
+    ZoneFile {
+        /// The zone file to read
+        path: PathBuf,
+        /// The zone's origin, for names which aren't fully qualified
+        zone: String,
+    },
+    /// The A and AAAA records from a zone transfer
+    Axfr {
+        /// The DNS server, the port defaults to 53
+        server: String,
+        /// The zone to transfer
+        zone: String,
+        /// How long the transfer can take, defaults to 30 seconds
+        #[serde(default = "default_axfr_timeout_seconds")]
+        timeout_seconds: u64,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+/// A place hosts are discovered from, which is kept in sync by the shepherd
+pub struct DiscoverySource {
+    #[serde(flatten)]
+    /// Where the hosts come from
+    pub kind: DiscoverySourceKind,
+    /// Host groups every host from this source is put in
+    #[serde(default)]
+    pub host_groups: Vec<String>,
+    /// Add hosts to the config file automatically, instead of leaving them for review
+    #[serde(default)]
+    pub auto_accept: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+/// Discovering hosts automatically
+pub struct DiscoveryConfig {
+    /// Sources to sync, keyed by name
+    #[serde(default)]
+    pub sources: HashMap<String, DiscoverySource>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A host found by a source
+pub struct Candidate {
+    /// The name it'll have in the config
+    pub name: String,
+    /// Its address
+    pub address: IpAddr,
+    /// The host groups it should be in
+    pub groups: BTreeSet<String>,
+    /// Set if the name can't be relied on to resolve, like a Kubernetes node name
+    pub connect_by_address: bool,
+}
+
+impl Candidate {
+    /// For recording it alongside the hosts found by scanning
+    pub fn discovered_host(&self) -> DiscoveredHost {
+        DiscoveredHost {
+            address: self.address,
+            hostname: Some(self.name.clone()),
+            open_ports: vec![],
+            suggested_checks: vec![],
+        }
+    }
+}
+
+/// One candidate per name and per address, the first record wins
+fn zone_candidates(records: Vec<AddressRecord>) -> Vec<Candidate> {
+    let mut addresses = BTreeSet::new();
+    let mut names = BTreeSet::new();
+    records
+        .into_iter()
+        .filter(|record| addresses.insert(record.address) && names.insert(record.name.clone()))
+        .map(|record| Candidate {
+            name: record.name,
+            address: record.address,
+            groups: BTreeSet::new(),
+            connect_by_address: false,
+        })
+        .collect()
+}
+
+impl DiscoverySource {
+    /// What's recorded as the source of the hosts it finds, eg `kubernetes:prod`
+    pub fn key(&self, name: &str) -> String {
+        let kind = match self.kind {
+            DiscoverySourceKind::Kubernetes { .. } => "kubernetes",
+            DiscoverySourceKind::ZoneFile { .. } => "zone_file",
+            DiscoverySourceKind::Axfr { .. } => "axfr",
+        };
+        format!("{}:{}", kind, name)
+    }
+
+    /// Gets the source's current list of hosts
+    pub async fn candidates(&self) -> Result<Vec<Candidate>, Error> {
+        let mut candidates = match &self.kind {
+            DiscoverySourceKind::Kubernetes {
+                context,
+                group_labels,
+            } => node_candidates(context.as_deref(), group_labels).await?,
+            DiscoverySourceKind::ZoneFile { path, zone } => zone_candidates(parse_zone_file(
+                &tokio::fs::read_to_string(path).await?,
+                zone,
+            )?),
+            DiscoverySourceKind::Axfr {
+                server,
+                zone,
+                timeout_seconds,
+            } => zone_candidates(
+                axfr(
+                    server,
+                    zone,
+                    std::time::Duration::from_secs(*timeout_seconds),
+                )
+                .await?,
+            ),
+        };
+        for candidate in candidates.iter_mut() {
+            candidate.groups.extend(self.host_groups.iter().cloned());
+        }
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_zone_file_source() {
+        let zone_file = tempfile::NamedTempFile::new().expect("Failed to create zone file");
+        tokio::fs::write(
+            zone_file.path(),
+            "www IN A 192.0.2.10\nwww IN AAAA 2001:db8::10\nalias IN A 192.0.2.10\ndb IN A 192.0.2.20\n",
+        )
+        .await
+        .expect("Failed to write zone file");
+
+        let source: DiscoverySource = serde_json::from_value(json!({
+            "type": "zone_file",
+            "path": zone_file.path(),
+            "zone": "example.com",
+            "host_groups": ["dns"],
+        }))
+        .expect("Failed to parse source");
+        assert_eq!(source.key("corp"), "zone_file:corp");
+        assert!(!source.auto_accept);
+
+        let candidates = source.candidates().await.expect("Failed to get candidates");
+        assert_eq!(
+            candidates
+                .iter()
+                .map(|candidate| candidate.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["www.example.com", "db.example.com"]
+        );
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.groups.contains("dns") && !candidate.connect_by_address));
+    }
+}
@@ -0,0 +1,404 @@
+//! Reading address records from a DNS zone, either a zone file or a zone transfer (AXFR)
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::prelude::*;
+
+const RECORD_TYPE_A: u16 = 1;
+const RECORD_TYPE_SOA: u16 = 6;
+const RECORD_TYPE_AAAA: u16 = 28;
+const QUERY_TYPE_AXFR: u16 = 252;
+const CLASS_IN: u16 = 1;
+
+/// Compression pointers can loop, this is far more than a real name needs
+const MAX_NAME_POINTERS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An A or AAAA record
+pub struct AddressRecord {
+    /// The fully qualified name, without the trailing dot
+    pub name: String,
+    /// Where it points
+    pub address: IpAddr,
+}
+
+/// Makes a name from a zone fully qualified, lower-cased and without the trailing dot
+fn qualify(name: &str, origin: &str) -> String {
+    let name = name.to_lowercase();
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// Strips comments and joins records split over lines with `( )`, keeping the line number each record started on
+fn logical_lines(contents: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    let mut depth = 0;
+    for (index, line) in contents.lines().enumerate() {
+        let mut in_quotes = false;
+        let mut cleaned = String::new();
+        for c in line.chars() {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                ';' if !in_quotes => break,
+                '(' if !in_quotes => {
+                    depth += 1;
+                    cleaned.push(' ');
+                    continue;
+                }
+                ')' if !in_quotes => {
+                    depth -= 1;
+                    cleaned.push(' ');
+                    continue;
+                }
+                _ => {}
+            }
+            cleaned.push(c);
+        }
+        match current.as_mut() {
+            Some((_, text)) => {
+                text.push(' ');
+                text.push_str(&cleaned);
+            }
+            None => current = Some((index + 1, cleaned)),
+        }
+        if depth <= 0 {
+            depth = 0;
+            if let Some(line) = current.take() {
+                lines.push(line);
+            }
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+fn is_ttl(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn is_class(token: &str) -> bool {
+    ["IN", "CH", "HS", "CS"].contains(&token.to_uppercase().as_str())
+}
+
+/// Pulls the A and AAAA records out of a zone file, `origin` is used until a `$ORIGIN` changes it
+pub fn parse_zone_file(contents: &str, origin: &str) -> Result<Vec<AddressRecord>, Error> {
+    let mut origin = qualify(origin.trim_end_matches('.'), "");
+    let mut owner: Option<String> = None;
+    let mut records = Vec::new();
+
+    for (line_number, line) in logical_lines(contents) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut tokens = line.split_whitespace().peekable();
+        if line.starts_with('$') {
+            match (tokens.next(), tokens.next()) {
+                (Some("$ORIGIN"), Some(new_origin)) => origin = qualify(new_origin, &origin),
+                (Some("$TTL"), _) => {}
+                (Some(directive), _) => warn!(
+                    "Skipping unsupported zone file directive {} on line {}",
+                    directive, line_number
+                ),
+                (None, _) => {}
+            }
+            continue;
+        }
+        // records starting with whitespace belong to the last name
+        if !line.starts_with(char::is_whitespace) {
+            owner = tokens.next().map(|name| qualify(name, &origin));
+        }
+        let Some(name) = owner.clone() else {
+            return Err(Error::InvalidInput(format!(
+                "Record without a name on line {}",
+                line_number
+            )));
+        };
+        while tokens
+            .peek()
+            .is_some_and(|token| is_ttl(token) || is_class(token))
+        {
+            tokens.next();
+        }
+        let record_type = tokens.next().unwrap_or_default().to_uppercase();
+        if !["A", "AAAA"].contains(&record_type.as_str()) || name.starts_with('*') {
+            continue;
+        }
+        let address = tokens
+            .next()
+            .and_then(|address| IpAddr::from_str(address).ok())
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "Invalid {} record on line {}",
+                    record_type, line_number
+                ))
+            })?;
+        records.push(AddressRecord { name, address });
+    }
+    Ok(records)
+}
+
+fn truncated() -> Error {
+    Error::InvalidInput("Truncated DNS message".to_string())
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Result<u16, Error> {
+    message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(truncated)
+}
+
+/// Reads a possibly-compressed name, returns it and the offset just past it
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize), Error> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *message.get(offset).ok_or_else(truncated)? as usize;
+        if length & 0xC0 == 0xC0 {
+            let low = *message.get(offset + 1).ok_or_else(truncated)? as usize;
+            end.get_or_insert(offset + 2);
+            pointers += 1;
+            if pointers > MAX_NAME_POINTERS {
+                return Err(Error::InvalidInput(
+                    "DNS name has too many pointers".to_string(),
+                ));
+            }
+            offset = ((length & 0x3F) << 8) | low;
+            continue;
+        }
+        if length == 0 {
+            return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        let label = message
+            .get(offset + 1..offset + 1 + length)
+            .ok_or_else(truncated)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        offset += 1 + length;
+    }
+}
+
+fn encode_name(name: &str) -> Result<Vec<u8>, Error> {
+    let mut encoded = Vec::new();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::InvalidInput(format!("Invalid zone name {}", name)));
+        }
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    Ok(encoded)
+}
+
+/// Builds the AXFR query, without the TCP length prefix
+fn axfr_query(id: u16, zone: &str) -> Result<Vec<u8>, Error> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // a standard query, and one question
+    query.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    query.extend(encode_name(zone)?);
+    query.extend_from_slice(&QUERY_TYPE_AXFR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Adds the address records in one message of a transfer to `records`, returns how many SOA records it had
+fn parse_axfr_message(message: &[u8], records: &mut Vec<AddressRecord>) -> Result<usize, Error> {
+    let flags = read_u16(message, 2)?;
+    if flags & 0x000F != 0 {
+        return Err(Error::Generic(format!(
+            "Zone transfer refused, response code {}",
+            flags & 0x000F
+        )));
+    }
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(message, offset)?;
+        offset = next + 4;
+    }
+
+    let mut soa_records = 0;
+    for _ in 0..answers {
+        let (name, next) = read_name(message, offset)?;
+        let record_type = read_u16(message, next)?;
+        let data_length = read_u16(message, next + 8)? as usize;
+        let data_start = next + 10;
+        let data = message
+            .get(data_start..data_start + data_length)
+            .ok_or_else(truncated)?;
+        offset = data_start + data_length;
+
+        let address = match (record_type, data_length) {
+            (RECORD_TYPE_SOA, _) => {
+                soa_records += 1;
+                None
+            }
+            (RECORD_TYPE_A, 4) => Some(IpAddr::from([data[0], data[1], data[2], data[3]])),
+            (RECORD_TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                Some(IpAddr::from(octets))
+            }
+            _ => None,
+        };
+        if let Some(address) = address.filter(|_| !name.starts_with('*')) {
+            records.push(AddressRecord { name, address });
+        }
+    }
+    Ok(soa_records)
+}
+
+/// Works out where to connect, the port defaults to 53
+async fn server_address(server: &str) -> Result<SocketAddr, Error> {
+    if let Ok(address) = SocketAddr::from_str(server) {
+        return Ok(address);
+    }
+    if let Ok(address) = IpAddr::from_str(server) {
+        return Ok(SocketAddr::new(address, 53));
+    }
+    let server = match server.contains(':') {
+        true => server.to_string(),
+        false => format!("{}:53", server),
+    };
+    tokio::net::lookup_host(&server)
+        .await?
+        .next()
+        .ok_or(Error::DnsFailed)
+}
+
+/// Transfers the zone from `server` and returns its address records, the server has to allow transfers to us
+pub async fn axfr(
+    server: &str,
+    zone: &str,
+    timeout: std::time::Duration,
+) -> Result<Vec<AddressRecord>, Error> {
+    let address = server_address(server).await?;
+    let transfer = async {
+        let mut stream = TcpStream::connect(address).await?;
+        let query = axfr_query(rand::random(), zone)?;
+        stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(&query).await?;
+
+        // the transfer starts and ends with the SOA record
+        let mut records = Vec::new();
+        let mut soa_records = 0;
+        while soa_records < 2 {
+            let length = stream.read_u16().await? as usize;
+            let mut message = vec![0; length];
+            stream.read_exact(&mut message).await?;
+            soa_records += parse_axfr_message(&message, &mut records)?;
+        }
+        Ok::<_, Error>(records)
+    };
+    tokio::time::timeout(timeout, transfer)
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_zone_file() {
+        let records = parse_zone_file(
+            r#"
+$TTL 3600
+@   IN  SOA ns1.example.com. admin.example.com. (
+        2024010101 ; serial
+        3600 900 604800 300 )
+    IN  NS  ns1
+ns1 IN  A   192.0.2.53
+www 300 IN A 192.0.2.10
+    IN  AAAA 2001:db8::10
+mail.example.com. A 192.0.2.25
+txt IN TXT "v=spf1; -all"
+* IN A 192.0.2.99
+$ORIGIN lab.example.com.
+router IN A 192.0.2.1
+"#,
+            "example.com.",
+        )
+        .expect("Failed to parse zone");
+
+        assert_eq!(
+            records,
+            vec![
+                AddressRecord {
+                    name: "ns1.example.com".to_string(),
+                    address: "192.0.2.53".parse().expect("Failed to parse address"),
+                },
+                AddressRecord {
+                    name: "www.example.com".to_string(),
+                    address: "192.0.2.10".parse().expect("Failed to parse address"),
+                },
+                AddressRecord {
+                    name: "www.example.com".to_string(),
+                    address: "2001:db8::10".parse().expect("Failed to parse address"),
+                },
+                AddressRecord {
+                    name: "mail.example.com".to_string(),
+                    address: "192.0.2.25".parse().expect("Failed to parse address"),
+                },
+                AddressRecord {
+                    name: "router.lab.example.com".to_string(),
+                    address: "192.0.2.1".parse().expect("Failed to parse address"),
+                },
+            ]
+        );
+
+        assert!(parse_zone_file("www IN A not-an-address", "example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_axfr_message() {
+        let mut message = vec![0, 1, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // example.com SOA, the data isn't read
+        message.extend(encode_name("example.com").expect("Failed to encode name"));
+        message.extend_from_slice(&[0, 6, 0, 1, 0, 0, 0, 60, 0, 0]);
+        // www, pointing back at example.com, A 192.0.2.10
+        message.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, 12]);
+        message.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 10]);
+
+        let mut records = Vec::new();
+        let soa_records =
+            parse_axfr_message(&message, &mut records).expect("Failed to parse message");
+        assert_eq!(soa_records, 1);
+        assert_eq!(
+            records,
+            vec![AddressRecord {
+                name: "www.example.com".to_string(),
+                address: "192.0.2.10".parse().expect("Failed to parse address"),
+            }]
+        );
+
+        // refused
+        assert!(
+            parse_axfr_message(&[0, 1, 0x84, 5, 0, 0, 0, 0, 0, 0, 0, 0], &mut records).is_err()
+        );
+        // truncated
+        assert!(parse_axfr_message(&message[..message.len() - 2], &mut records).is_err());
+
+        let query = axfr_query(1, "example.com.").expect("Failed to build query");
+        assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&query[25..], &[0, 252, 0, 1]);
+    }
+}
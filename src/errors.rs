@@ -37,6 +37,8 @@ pub enum Error {
     HostGroupNotFoundByName(String),
     /// When the host is not found
     HostNotFound(Uuid),
+    /// When the host is not found
+    HostNotFoundByName(String),
     /// When you've specified something wrong
     InvalidInput(String),
     /// When the IO operation failed
@@ -45,8 +47,14 @@ pub enum Error {
     KubeError(String),
     /// Something you asked for isn't implemented yet
     NotImplemented,
+    /// Service or host config refers to a host variable that doesn't exist, (host name, variable)
+    UnknownHostVariable(String, String),
+    /// Service config refers to a `{{ name }}` host var the host doesn't have, (host name, var)
+    UnknownHostVar(String, String),
     /// Oneshot command failed
     OneShotFailed,
+    /// Hashing or checking a password failed
+    Password(String),
     /// When the OIDC token is invalid or some other error gets thrown
     Oidc(String),
     /// When something went wrong while invoking reqwest
@@ -63,6 +71,8 @@ pub enum Error {
     ServiceNotFoundByName(String),
     /// When the SQL operation failed
     SqlError(sea_orm::error::DbErr),
+    /// Connecting, authenticating or running a command over SSH failed
+    Ssh(String),
     /// When the TLS operation failed
     TlsError(String),
     /// When the timeout is reached
@@ -74,6 +84,16 @@ pub enum Error {
     IPCSendError(String),
 }
 
+impl Error {
+    /// Errors caused by the service or host configuration, rather than the thing being checked
+    pub fn is_configuration(&self) -> bool {
+        matches!(
+            self,
+            Self::Configuration(_) | Self::UnknownHostVariable(_, _) | Self::UnknownHostVar(_, _)
+        )
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Self {
         Error::Deserialization(err.to_string())
@@ -105,6 +125,12 @@ impl From<axum_oidc::error::Error> for Error {
     }
 }
 
+impl From<argon2::password_hash::Error> for Error {
+    fn from(value: argon2::password_hash::Error) -> Self {
+        Error::Password(value.to_string())
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
@@ -119,6 +145,20 @@ impl From<rustls::Error> for Error {
     }
 }
 
+#[cfg(not(tarpaulin_include))]
+impl From<russh::Error> for Error {
+    fn from(value: russh::Error) -> Self {
+        Self::Ssh(value.to_string())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl From<russh_keys::Error> for Error {
+    fn from(value: russh_keys::Error) -> Self {
+        Self::Ssh(value.to_string())
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 impl From<tower_sessions::session::Error> for Error {
     fn from(value: tower_sessions::session::Error) -> Self {
@@ -178,6 +218,16 @@ impl IntoResponse for Error {
                 (StatusCode::FORBIDDEN, "CSRF token mismatch".to_string())
             }
             Self::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+            Self::HostNotFoundByName(name) => {
+                (StatusCode::NOT_FOUND, format!("Host {} not found", name))
+            }
+            Self::ServiceNotFoundByName(name) => {
+                (StatusCode::NOT_FOUND, format!("Service {} not found", name))
+            }
+            Self::ServiceCheckNotFound(id) => (
+                StatusCode::NOT_FOUND,
+                format!("Service check {} not found", id.hyphenated()),
+            ),
             _ => {
                 error!("Response error occurred: {:?}", self);
                 (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", self))
@@ -0,0 +1,201 @@
+//! Working out a single status for each host group from the status of its members
+
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+/// How a group's status is worked out from its hosts
+pub enum GroupStatusPolicy {
+    /// The worst status of any host in the group
+    #[default]
+    Worst,
+    /// Only critical if more than `critical_percent` of the hosts are failing, otherwise a warning while any are
+    Percentage {
+        /// The percentage of hosts which have to be failing for the group to be critical
+        critical_percent: u8,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+/// Group status policies, eg `{"default_policy": {"policy": "worst"}, "groups": {"web": {"policy": "percentage", "critical_percent": 25}}}`
+pub struct GroupStatusConfig {
+    /// Used for groups which aren't in `groups`
+    #[serde(default)]
+    pub default_policy: GroupStatusPolicy,
+    /// Policies for specific host groups, by name
+    #[serde(default)]
+    pub groups: HashMap<String, GroupStatusPolicy>,
+}
+
+impl GroupStatusConfig {
+    /// The policy for the group, names are matched the same way as the rest of the config
+    pub fn policy_for(&self, group_name: &str) -> GroupStatusPolicy {
+        let key = entities::name_key(group_name);
+        self.groups
+            .iter()
+            .find(|(name, _)| entities::name_key(name) == key)
+            .map(|(_, policy)| *policy)
+            .unwrap_or(self.default_policy)
+    }
+
+    /// Checks the percentages make sense
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, policy) in std::iter::once(("default_policy", &self.default_policy)).chain(
+            self.groups
+                .iter()
+                .map(|(name, policy)| (name.as_str(), policy)),
+        ) {
+            if let GroupStatusPolicy::Percentage { critical_percent } = policy {
+                if *critical_percent > 100 {
+                    return Err(Error::Configuration(format!(
+                        "critical_percent for group status {} must be between 0 and 100",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Statuses which come from a check's result, rather than it being queued, running or switched off
+fn is_result(status: ServiceStatus) -> bool {
+    !matches!(
+        status,
+        ServiceStatus::Pending
+            | ServiceStatus::Checking
+            | ServiceStatus::Urgent
+            | ServiceStatus::Disabled
+            | ServiceStatus::Cancelled
+    )
+}
+
+/// The statuses that count as a host failing
+pub fn is_failing(status: ServiceStatus) -> bool {
+    matches!(
+        status,
+        ServiceStatus::Critical | ServiceStatus::Error | ServiceStatus::Timeout
+    )
+}
+
+/// A host's status is the worst result of its checks, `None` if none of them have a result
+pub fn host_status(
+    check_statuses: impl IntoIterator<Item = ServiceStatus>,
+) -> Option<ServiceStatus> {
+    check_statuses
+        .into_iter()
+        .filter(|status| is_result(*status))
+        .max()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A group's status, with the numbers behind it
+pub struct GroupRollup {
+    /// The group's status
+    pub status: ServiceStatus,
+    /// How many hosts had a result to go on
+    pub members: usize,
+    /// How many of those are failing
+    pub failing: usize,
+}
+
+impl GroupStatusPolicy {
+    /// Rolls the hosts' statuses up into the group's, a group with nothing to go on is pending
+    pub fn rollup(self, host_statuses: &[ServiceStatus]) -> GroupRollup {
+        let members = host_statuses.len();
+        let failing = host_statuses
+            .iter()
+            .filter(|status| is_failing(**status))
+            .count();
+        let worst = host_statuses
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or(ServiceStatus::Pending);
+        let status = match self {
+            Self::Worst => worst,
+            Self::Percentage { critical_percent } => {
+                if failing == 0 {
+                    worst
+                } else if failing * 100 > critical_percent as usize * members {
+                    ServiceStatus::Critical
+                } else {
+                    ServiceStatus::Warning
+                }
+            }
+        };
+        GroupRollup {
+            status,
+            members,
+            failing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_status() {
+        assert_eq!(
+            host_status([
+                ServiceStatus::Ok,
+                ServiceStatus::Warning,
+                ServiceStatus::Disabled
+            ]),
+            Some(ServiceStatus::Warning)
+        );
+        // a check that's running doesn't make the host look worse
+        assert_eq!(
+            host_status([ServiceStatus::Ok, ServiceStatus::Checking]),
+            Some(ServiceStatus::Ok)
+        );
+        assert_eq!(host_status([ServiceStatus::Pending]), None);
+    }
+
+    #[test]
+    fn test_rollup() {
+        let mut statuses = vec![ServiceStatus::Ok; 9];
+        statuses.push(ServiceStatus::Critical);
+
+        let worst = GroupStatusPolicy::Worst.rollup(&statuses);
+        assert_eq!(worst.status, ServiceStatus::Critical);
+        assert_eq!((worst.members, worst.failing), (10, 1));
+
+        let percentage = GroupStatusPolicy::Percentage {
+            critical_percent: 20,
+        };
+        assert_eq!(percentage.rollup(&statuses).status, ServiceStatus::Warning);
+        statuses.push(ServiceStatus::Error);
+        statuses.push(ServiceStatus::Timeout);
+        assert_eq!(percentage.rollup(&statuses).status, ServiceStatus::Critical);
+        assert_eq!(
+            percentage.rollup(&[ServiceStatus::Ok]).status,
+            ServiceStatus::Ok
+        );
+        assert_eq!(percentage.rollup(&[]).status, ServiceStatus::Pending);
+    }
+
+    #[test]
+    fn test_policy_for() {
+        let config: GroupStatusConfig = serde_json::from_value(json!({
+            "groups": { "Web": { "policy": "percentage", "critical_percent": 25 } }
+        }))
+        .expect("Failed to parse group status config");
+        assert_eq!(
+            config.policy_for("web "),
+            GroupStatusPolicy::Percentage {
+                critical_percent: 25
+            }
+        );
+        assert_eq!(config.policy_for("db"), GroupStatusPolicy::Worst);
+        assert!(config.validate().is_ok());
+
+        let config: GroupStatusConfig = serde_json::from_value(json!({
+            "default_policy": { "policy": "percentage", "critical_percent": 101 }
+        }))
+        .expect("Failed to parse group status config");
+        assert!(config.validate().is_err());
+    }
+}
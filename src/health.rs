@@ -0,0 +1,223 @@
+//! Liveness and readiness, for Kubernetes probes and load balancers
+//!
+//! The check loop and the shepherd record a heartbeat each time around, and the web server reports
+//! how long ago that was along with whether the database answers.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::LazyLock;
+
+use sea_orm::ConnectionTrait;
+
+use crate::constants::{HEALTH_CHECK_LOOP_STALE_SECONDS, HEALTH_SHEPHERD_STALE_SECONDS};
+use crate::prelude::*;
+
+/// Where the check loop and the shepherd record that they're still going
+pub static HEARTBEATS: LazyLock<Heartbeats> = LazyLock::new(Heartbeats::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The parts of Maremma which record heartbeats
+pub enum Component {
+    /// Runs the due service checks
+    CheckLoop,
+    /// Runs the housekeeping tasks
+    Shepherd,
+}
+
+impl Component {
+    fn name(self) -> &'static str {
+        match self {
+            Self::CheckLoop => "check_loop",
+            Self::Shepherd => "shepherd",
+        }
+    }
+
+    fn stale_after(self) -> TimeDelta {
+        match self {
+            Self::CheckLoop => TimeDelta::seconds(HEALTH_CHECK_LOOP_STALE_SECONDS),
+            Self::Shepherd => TimeDelta::seconds(HEALTH_SHEPHERD_STALE_SECONDS),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The last time each component said it was alive, as unix timestamps with 0 for never
+pub struct Heartbeats {
+    check_loop: AtomicI64,
+    shepherd: AtomicI64,
+}
+
+impl Heartbeats {
+    fn slot(&self, component: Component) -> &AtomicI64 {
+        match component {
+            Component::CheckLoop => &self.check_loop,
+            Component::Shepherd => &self.shepherd,
+        }
+    }
+
+    /// Records that the component's still going
+    pub fn beat(&self, component: Component) {
+        self.slot(component)
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// When the component last recorded a heartbeat
+    pub fn last(&self, component: Component) -> Option<DateTime<Utc>> {
+        match self.slot(component).load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => DateTime::from_timestamp(timestamp, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How a component's doing
+pub enum HealthStatus {
+    /// Working
+    Ok,
+    /// Hasn't recorded a heartbeat yet, which is normal just after starting up
+    Starting,
+    /// Broken, or hasn't recorded a heartbeat in too long
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// One component's part of the health report
+pub struct ComponentHealth {
+    /// How it's doing
+    pub status: HealthStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// When it last recorded a heartbeat
+    pub last_seen: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// What's wrong
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What `/healthz` and `/readyz` return
+pub struct HealthReport {
+    /// The overall status, which is `ok` or `fail`
+    pub status: HealthStatus,
+    /// Each component's status
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl HealthReport {
+    /// Whether the probe should pass
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+fn component_health(
+    heartbeats: &Heartbeats,
+    component: Component,
+    now: DateTime<Utc>,
+) -> ComponentHealth {
+    let last_seen = heartbeats.last(component);
+    let (status, message) = match last_seen {
+        None => (HealthStatus::Starting, None),
+        Some(last_seen) if now - last_seen > component.stale_after() => (
+            HealthStatus::Fail,
+            Some(format!(
+                "No heartbeat in {}s",
+                (now - last_seen).num_seconds()
+            )),
+        ),
+        Some(_) => (HealthStatus::Ok, None),
+    };
+    ComponentHealth {
+        status,
+        last_seen,
+        message,
+    }
+}
+
+/// Checks the database and the heartbeats
+///
+/// Liveness only fails for things a restart would fix, readiness also waits for everything to have started.
+pub async fn check_health(
+    db: &DatabaseConnection,
+    heartbeats: &Heartbeats,
+    readiness: bool,
+) -> HealthReport {
+    let now = Utc::now();
+    let mut components = BTreeMap::new();
+
+    let database = match db.execute_unprepared("SELECT 1").await {
+        Ok(_) => ComponentHealth {
+            status: HealthStatus::Ok,
+            last_seen: Some(now),
+            message: None,
+        },
+        Err(err) => {
+            error!("Health check couldn't query the database: {:?}", err);
+            ComponentHealth {
+                status: HealthStatus::Fail,
+                last_seen: None,
+                message: Some(err.to_string()),
+            }
+        }
+    };
+    components.insert("database".to_string(), database);
+    for component in [Component::CheckLoop, Component::Shepherd] {
+        components.insert(
+            component.name().to_string(),
+            component_health(heartbeats, component, now),
+        );
+    }
+
+    let failed = components.values().any(|component| match component.status {
+        HealthStatus::Ok => false,
+        HealthStatus::Starting => readiness,
+        HealthStatus::Fail => true,
+    });
+    HealthReport {
+        status: if failed {
+            HealthStatus::Fail
+        } else {
+            HealthStatus::Ok
+        },
+        components,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_check_health() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test");
+        let heartbeats = Heartbeats::default();
+
+        // nothing's started yet, which is alive but not ready
+        let report = check_health(&db, &heartbeats, false).await;
+        assert!(report.is_ok());
+        assert_eq!(report.components["database"].status, HealthStatus::Ok);
+        assert_eq!(
+            report.components["check_loop"].status,
+            HealthStatus::Starting
+        );
+        assert!(!check_health(&db, &heartbeats, true).await.is_ok());
+
+        heartbeats.beat(Component::CheckLoop);
+        heartbeats.beat(Component::Shepherd);
+        let report = check_health(&db, &heartbeats, true).await;
+        assert!(report.is_ok(), "{:?}", report);
+
+        // a wedged shepherd fails both
+        heartbeats.shepherd.store(
+            (Utc::now() - TimeDelta::seconds(HEALTH_SHEPHERD_STALE_SECONDS + 10)).timestamp(),
+            Ordering::Relaxed,
+        );
+        let report = check_health(&db, &heartbeats, false).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.components["shepherd"].status, HealthStatus::Fail);
+        assert!(report.components["shepherd"].message.is_some());
+        assert!(!check_health(&db, &heartbeats, true).await.is_ok());
+    }
+}
@@ -0,0 +1,230 @@
+//! Picking which of a host's addresses a check connects to
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use schemars::JsonSchema;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query;
+use tokio::net::lookup_host;
+
+use crate::prelude::*;
+
+#[derive(
+    Deserialize,
+    Debug,
+    Serialize,
+    Default,
+    PartialEq,
+    Eq,
+    Clone,
+    Copy,
+    DeriveActiveEnum,
+    EnumIter,
+    Iden,
+    JsonSchema,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(StringLen::N(4))")]
+#[serde(rename_all = "lowercase")]
+/// Which IP versions a check uses to reach a host
+pub enum AddressFamily {
+    /// Whichever address comes first, the host's `ipv6` or `ipv4` if they're set, otherwise the hostname's first DNS result
+    #[default]
+    #[sea_orm(string_value = "any")]
+    Any,
+    /// Only IPv4
+    #[sea_orm(string_value = "ipv4")]
+    Ipv4,
+    /// Only IPv6
+    #[sea_orm(string_value = "ipv6")]
+    Ipv6,
+    /// Check over IPv4 and IPv6, it fails if either does
+    #[sea_orm(string_value = "both")]
+    Both,
+}
+
+impl std::fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::Ipv4 => write!(f, "IPv4"),
+            Self::Ipv6 => write!(f, "IPv6"),
+            Self::Both => write!(f, "IPv4 and IPv6"),
+        }
+    }
+}
+
+/// "IPv4" or "IPv6", for saying which one a result's from
+pub fn family_name(address: &IpAddr) -> &'static str {
+    match address {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
+
+/// The addresses a check against `target` connects to, one for each IP version it uses
+///
+/// The host's own `ipv4` and `ipv6` are only used when `target` is its hostname, services that point somewhere else have that looked up.
+pub async fn target_addresses(
+    host: &entities::host::Model,
+    target: &str,
+    family: AddressFamily,
+) -> Result<Vec<IpAddr>, Error> {
+    let (mut ipv4, mut ipv6) = (None, None);
+    if target == host.hostname {
+        ipv4 = host
+            .ipv4
+            .as_deref()
+            .map(|address| address.parse::<Ipv4Addr>().map(IpAddr::V4))
+            .transpose()
+            .map_err(|err| {
+                Error::Configuration(format!("Invalid ipv4 for {}: {}", host.name, err))
+            })?;
+        ipv6 = host
+            .ipv6
+            .as_deref()
+            .map(|address| address.parse::<Ipv6Addr>().map(IpAddr::V6))
+            .transpose()
+            .map_err(|err| {
+                Error::Configuration(format!("Invalid ipv6 for {}: {}", host.name, err))
+            })?;
+    }
+
+    if family == AddressFamily::Any {
+        if let Some(address) = ipv6.or(ipv4) {
+            return Ok(vec![address]);
+        }
+    }
+
+    // only ask DNS for what wasn't set on the host
+    let resolved = match (family, ipv4, ipv6) {
+        (AddressFamily::Ipv4, Some(_), _) | (AddressFamily::Ipv6, _, Some(_)) => Vec::new(),
+        (AddressFamily::Both, Some(_), Some(_)) => Vec::new(),
+        _ => lookup_host(format!("{}:0", target))
+            .await
+            .map_err(|err| {
+                debug!("Failed to look up {}: {:?}", target, err);
+                Error::DnsFailed
+            })?
+            .map(|address| address.ip())
+            .collect::<Vec<_>>(),
+    };
+    let ipv4 = ipv4.or_else(|| resolved.iter().find(|address| address.is_ipv4()).copied());
+    let ipv6 = ipv6.or_else(|| resolved.iter().find(|address| address.is_ipv6()).copied());
+
+    let missing = |version: &str| Error::Generic(format!("{} has no {} address", target, version));
+    match family {
+        AddressFamily::Any => resolved
+            .first()
+            .map(|address| vec![*address])
+            .ok_or(Error::DnsFailed),
+        AddressFamily::Ipv4 => Ok(vec![ipv4.ok_or_else(|| missing("IPv4"))?]),
+        AddressFamily::Ipv6 => Ok(vec![ipv6.ok_or_else(|| missing("IPv6"))?]),
+        AddressFamily::Both => Ok(vec![
+            ipv4.ok_or_else(|| missing("IPv4"))?,
+            ipv6.ok_or_else(|| missing("IPv6"))?,
+        ]),
+    }
+}
+
+/// Merges the results from each address into one, the worst status wins and each part says which IP version it's from
+pub fn combine(results: Vec<(IpAddr, String, ServiceStatus)>) -> (String, ServiceStatus) {
+    let status = results
+        .iter()
+        .map(|(_, _, status)| *status)
+        .max()
+        .unwrap_or(ServiceStatus::Unknown);
+    if let [(_, result_text, _)] = results.as_slice() {
+        return (result_text.clone(), status);
+    }
+    let mut results = results;
+    // the one that failed is what people need to see first
+    results.sort_by(|a, b| b.2.cmp(&a.2));
+    let result_text = results
+        .iter()
+        .map(|(address, result_text, _)| {
+            format!("{} {}: {}", family_name(address), address, result_text)
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    (result_text, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_target_addresses() {
+        let host = entities::host::Model {
+            hostname: "dualstack.example.com".to_string(),
+            ipv4: Some("192.0.2.1".to_string()),
+            ipv6: Some("2001:db8::1".to_string()),
+            ..entities::host::test_host()
+        };
+        let ipv4: IpAddr = "192.0.2.1".parse().expect("Failed to parse address");
+        let ipv6: IpAddr = "2001:db8::1".parse().expect("Failed to parse address");
+
+        for (family, expected) in [
+            (AddressFamily::Any, vec![ipv6]),
+            (AddressFamily::Ipv4, vec![ipv4]),
+            (AddressFamily::Ipv6, vec![ipv6]),
+            (AddressFamily::Both, vec![ipv4, ipv6]),
+        ] {
+            assert_eq!(
+                target_addresses(&host, &host.hostname, family)
+                    .await
+                    .expect("Failed to get addresses"),
+                expected,
+                "{}",
+                family
+            );
+        }
+
+        // the host's addresses aren't used for other targets
+        assert_eq!(
+            target_addresses(&host, "127.0.0.1", AddressFamily::Any)
+                .await
+                .expect("Failed to get addresses"),
+            vec!["127.0.0.1"
+                .parse::<IpAddr>()
+                .expect("Failed to parse address")]
+        );
+        assert!(target_addresses(&host, "127.0.0.1", AddressFamily::Ipv6)
+            .await
+            .is_err());
+        assert!(target_addresses(&host, "127.0.0.1", AddressFamily::Both)
+            .await
+            .is_err());
+
+        let broken = entities::host::Model {
+            ipv4: Some("2001:db8::1".to_string()),
+            ..host
+        };
+        assert!(
+            target_addresses(&broken, &broken.hostname, AddressFamily::Ipv4)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_combine() {
+        let ipv4: IpAddr = "192.0.2.1".parse().expect("Failed to parse address");
+        let ipv6: IpAddr = "2001:db8::1".parse().expect("Failed to parse address");
+
+        assert_eq!(
+            combine(vec![(ipv4, "fine".to_string(), ServiceStatus::Ok)]),
+            ("fine".to_string(), ServiceStatus::Ok)
+        );
+        assert_eq!(
+            combine(vec![
+                (ipv4, "fine".to_string(), ServiceStatus::Ok),
+                (ipv6, "timed out".to_string(), ServiceStatus::Critical),
+            ]),
+            (
+                "IPv6 2001:db8::1: timed out; IPv4 192.0.2.1: fine".to_string(),
+                ServiceStatus::Critical
+            )
+        );
+    }
+}
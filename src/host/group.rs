@@ -0,0 +1,236 @@
+//! Host groups which include other groups, eg `all-prod` made up of `prod-web` and `prod-db`
+//!
+//! Services can also leave a group's hosts out with a `!` in front of it, eg `["prod-web", "!canary"]`
+
+use std::collections::{BTreeSet, HashMap};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::db::entities::name_key;
+use crate::errors::Error;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+/// Configuration for a host group, hosts still join groups with their `host_groups`
+pub struct HostGroupConfig {
+    #[serde(default)]
+    /// Other host groups whose hosts are in this group too, eg `["prod-web", "prod-db"]`
+    pub members: Vec<String>,
+}
+
+/// Checks the groups don't include themselves, directly or through other groups
+pub fn check_nesting(groups: &HashMap<String, HostGroupConfig>) -> Result<(), Error> {
+    let members = members_by_key(groups);
+    let mut names = members.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let mut path = vec![name.clone()];
+        if let Some(cycle) = find_cycle(&members, &mut path) {
+            return Err(Error::Configuration(format!(
+                "host group {} includes itself: {}",
+                name,
+                cycle.join(" -> ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Depth-first search from the end of `path`, returning the path if it gets back to a group that's already on it
+fn find_cycle(
+    members: &HashMap<String, Vec<String>>,
+    path: &mut Vec<String>,
+) -> Option<Vec<String>> {
+    let current = path.last()?.clone();
+    for member in members.get(&current).into_iter().flatten() {
+        if path.contains(member) {
+            let mut cycle = path.clone();
+            cycle.push(member.clone());
+            return Some(cycle);
+        }
+        path.push(member.clone());
+        if let Some(cycle) = find_cycle(members, path) {
+            return Some(cycle);
+        }
+        path.pop();
+    }
+    None
+}
+
+fn members_by_key(groups: &HashMap<String, HostGroupConfig>) -> HashMap<String, Vec<String>> {
+    groups
+        .iter()
+        .map(|(name, group)| {
+            (
+                name_key(name),
+                group
+                    .members
+                    .iter()
+                    .map(|member| name_key(member))
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Put in front of a service's host group to leave that group's hosts out
+pub const EXCLUDE_PREFIX: char = '!';
+
+/// Splits an entry in a service's `host_groups` into the group's name and whether it's excluded
+pub fn parse_selector(group: &str) -> (&str, bool) {
+    match group.trim().strip_prefix(EXCLUDE_PREFIX) {
+        Some(name) => (name.trim(), true),
+        None => (group.trim(), false),
+    }
+}
+
+/// Checks a service's `host_groups` make sense, exclusions need a group name and something to exclude from
+pub fn check_selectors(service_name: &str, selectors: &[String]) -> Result<(), Error> {
+    let parsed = selectors
+        .iter()
+        .map(|group| parse_selector(group))
+        .collect::<Vec<_>>();
+    if parsed.iter().any(|(name, _)| name.is_empty()) {
+        return Err(Error::Configuration(format!(
+            "service {} has an empty host group",
+            service_name
+        )));
+    }
+    if !parsed.is_empty() && parsed.iter().all(|(_, exclude)| *exclude) {
+        return Err(Error::Configuration(format!(
+            "service {} only excludes host groups, it needs at least one to run on",
+            service_name
+        )));
+    }
+    Ok(())
+}
+
+/// Does a service with these `host_groups` run on a host in `host_groups`, which should be expanded name keys from [expand_host_groups]
+///
+/// Returns the groups which put it there, or nothing if the host's in an excluded group
+pub fn selected_by<'a>(selectors: &'a [String], host_groups: &BTreeSet<String>) -> Vec<&'a str> {
+    let mut res = Vec::new();
+    for (name, exclude) in selectors.iter().map(|group| parse_selector(group)) {
+        if host_groups.contains(&name_key(name)) {
+            if exclude {
+                return Vec::new();
+            }
+            res.push(name);
+        }
+    }
+    res
+}
+
+/// The groups a host is in once nesting's taken into account, ie its own `host_groups` and every group that includes them, as name keys
+pub fn expand_host_groups(
+    groups: &HashMap<String, HostGroupConfig>,
+    host_groups: &[String],
+) -> BTreeSet<String> {
+    let members = members_by_key(groups);
+    let mut res = host_groups
+        .iter()
+        .map(|group| name_key(group))
+        .collect::<BTreeSet<_>>();
+    // keep adding parents until nothing changes, cycles are rejected when the config's loaded but this still stops if there is one
+    loop {
+        let parents = members
+            .iter()
+            .filter(|(parent, children)| {
+                !res.contains(*parent) && children.iter().any(|child| res.contains(child))
+            })
+            .map(|(parent, _)| parent.clone())
+            .collect::<Vec<_>>();
+        if parents.is_empty() {
+            return res;
+        }
+        res.extend(parents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(nesting: &[(&str, &[&str])]) -> HashMap<String, HostGroupConfig> {
+        nesting
+            .iter()
+            .map(|(name, members)| {
+                (
+                    name.to_string(),
+                    HostGroupConfig {
+                        members: members.iter().map(|member| member.to_string()).collect(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check_nesting() {
+        let nested = groups(&[
+            ("all-prod", &["prod-web", "prod-db"]),
+            ("prod-web", &["prod-web-canary"]),
+        ]);
+        assert!(check_nesting(&nested).is_ok());
+
+        let cycle = groups(&[("a", &["b"]), ("b", &["c"]), ("C", &["a"])]);
+        let err = check_nesting(&cycle).expect_err("Cycle wasn't found");
+        assert_eq!(
+            err,
+            Error::Configuration("host group a includes itself: a -> b -> c -> a".to_string())
+        );
+
+        assert!(check_nesting(&groups(&[("self", &["Self"])])).is_err());
+    }
+
+    #[test]
+    fn test_selectors() {
+        assert_eq!(parse_selector("prod-web"), ("prod-web", false));
+        assert_eq!(parse_selector(" !canary"), ("canary", true));
+
+        let selectors = vec!["all-prod".to_string(), "!Canary".to_string()];
+        assert!(check_selectors("test", &selectors).is_ok());
+        assert!(check_selectors("test", &["!canary".to_string()]).is_err());
+        assert!(check_selectors("test", &["!".to_string(), "prod".to_string()]).is_err());
+
+        let nested = groups(&[("all-prod", &["prod-web", "canary"])]);
+        assert_eq!(
+            selected_by(
+                &selectors,
+                &expand_host_groups(&nested, &["prod-web".to_string()])
+            ),
+            vec!["all-prod"]
+        );
+        // canary's in all-prod, but it's excluded
+        assert!(selected_by(
+            &selectors,
+            &expand_host_groups(&nested, &["canary".to_string()])
+        )
+        .is_empty());
+        assert!(selected_by(
+            &selectors,
+            &expand_host_groups(&nested, &["staging".to_string()])
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_expand_host_groups() {
+        let nested = groups(&[
+            ("all-prod", &["prod-web", "prod-db"]),
+            ("everything", &["all-prod"]),
+        ]);
+        assert_eq!(
+            expand_host_groups(&nested, &["Prod-Web".to_string()]),
+            BTreeSet::from([
+                "all-prod".to_string(),
+                "everything".to_string(),
+                "prod-web".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_host_groups(&nested, &["staging".to_string()]),
+            BTreeSet::from(["staging".to_string()])
+        );
+    }
+}
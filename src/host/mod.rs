@@ -3,12 +3,21 @@
 use schemars::JsonSchema;
 use sea_orm::entity::prelude::*;
 use sea_orm::sea_query;
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
+use crate::actions::routing::NotificationRouting;
+use crate::labels::Labels;
 use crate::prelude::*;
+use address::AddressFamily;
 
+/// Explicit IPv4 and IPv6 addresses, and which of them checks use
+pub mod address;
 /// Implements "Fakehost" which is used for local checks
 pub mod fakehost;
+/// Host groups which include other groups
+pub mod group;
 /// Implements the Kubernetes host check
 pub mod kube;
 /// Implements the SSH-based host check
@@ -29,10 +38,42 @@ pub struct Host {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub hostname: Option<String>,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Shown in the UI instead of the host's name, checks still use the hostname
+    pub display_name: Option<String>,
+
     #[serde(default)]
     /// Groups that this host is part of
     pub host_groups: Vec<String>,
 
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    /// Free-form labels, eg `{"env": "prod"}`, list views and the API can filter on them with `?label=env:prod`
+    pub labels: Labels,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Override the notification routing for this host's checks
+    pub notifications: Option<NotificationRouting>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How many checks can run against this host at once, overrides `max_concurrent_checks_per_host`
+    pub max_concurrent_checks: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Variables this host's services can use as `{{ name }}`, eg `{"app_port": 8443}`
+    pub vars: HashMap<String, serde_json::Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Checks use this instead of looking up the hostname's IPv4 address
+    pub ipv4: Option<Ipv4Addr>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Checks use this instead of looking up the hostname's IPv6 address
+    pub ipv6: Option<Ipv6Addr>,
+
+    #[serde(default)]
+    /// Which IP versions checks use: `any` (the default), `ipv4`, `ipv6` or `both`, services can override it
+    pub address_family: AddressFamily,
+
     #[serde(default)]
     /// Extra configuration for services, the key matches the service name
     #[serde(skip_serializing_if = "HashMap::is_empty")]
@@ -50,10 +91,18 @@ impl Host {
         let id = Uuid::new_v4();
         Self {
             hostname: Some(hostname),
+            display_name: None,
             check,
             host_groups: vec![],
+            labels: Labels::new(),
             id: Some(id),
             config: HashMap::new(),
+            vars: HashMap::new(),
+            ipv4: None,
+            ipv6: None,
+            address_family: AddressFamily::default(),
+            notifications: None,
+            max_concurrent_checks: None,
             extra: HashMap::new(),
         }
     }
@@ -64,9 +113,17 @@ impl From<crate::db::entities::host::Model> for Host {
         Self {
             check: model.check,
             hostname: Some(model.hostname),
+            display_name: model.display_name,
             host_groups: vec![],
+            labels: crate::labels::from_json(&model.labels),
             id: Some(model.id),
             config: HashMap::new(),
+            vars: HashMap::new(),
+            ipv4: model.ipv4.and_then(|address| address.parse().ok()),
+            ipv6: model.ipv6.and_then(|address| address.parse().ok()),
+            address_family: model.address_family,
+            notifications: None,
+            max_concurrent_checks: None,
             extra: HashMap::new(),
         }
     }
@@ -144,7 +201,7 @@ mod tests {
         let (db, _config) = test_setup().await.expect("Failed to setup test");
 
         let host: Host = entities::host::Entity::find()
-            .one(&*db.write().await)
+            .one(&db)
             .await
             .expect("Failed to query host")
             .expect("Failed to find test host")
@@ -1,11 +1,14 @@
 use std::net::ToSocketAddrs;
 use std::num::NonZeroU16;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use chrono::Utc;
 use serde::Deserialize;
+use tokio::net::TcpStream;
 
 use crate::prelude::*;
+use crate::ssh_client::{self, HostKeyVerification};
 
 /// The default timeout
 pub const DEFAULT_SSH_TIMEOUT_SECONDS: u16 = 30;
@@ -38,6 +41,13 @@ pub struct SshHost {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote_user: Option<String>,
 
+    #[serde(default)]
+    /// How to verify the host key, defaults to checking known_hosts
+    pub host_key_verification: HostKeyVerification,
+    /// The known_hosts file to check against, defaults to `~/.ssh/known_hosts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known_hosts_file: Option<PathBuf>,
+
     #[serde(default)]
     /// Groups that this host is part of
     pub host_groups: Vec<String>,
@@ -98,14 +108,34 @@ impl GenericHost for SshHost {
                 None => return Err(Error::DnsFailed),
             },
         };
-        let result = std::net::TcpStream::connect_timeout(
-            &socket_address,
-            Duration::from_secs(self.timeout_seconds as u64),
-        );
-        match result {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
+        // checks are already talking to it, no need to open another connection and annoy fail2ban
+        if ssh_client::POOL.has_connection(&self.hostname, socket_address.port()) {
+            return Ok(true);
         }
+
+        let timeout = Duration::from_secs(self.timeout_seconds as u64);
+        let stream = match tokio::time::timeout(timeout, TcpStream::connect(socket_address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(_)) | Err(_) => return Ok(false),
+        };
+
+        // it's listening, so make sure it's actually SSH, and the host key is the one we expect
+        let mut session = ssh_client::handshake(
+            stream,
+            &self.hostname,
+            socket_address.port(),
+            &self.host_key_verification,
+            self.known_hosts_file.as_deref(),
+            timeout,
+            timeout,
+        )
+        .await?;
+        // this doesn't finish until the key exchange has, the result doesn't matter
+        session
+            .authenticate_none(self.remote_user.as_deref().unwrap_or("maremma"))
+            .await?;
+        ssh_client::disconnect(&session).await;
+        Ok(true)
     }
 
     fn try_from_config(config: serde_json::Value) -> Result<Self, Error>
@@ -160,6 +190,14 @@ mod test {
         assert_eq!(host.hostname, "example.com");
         assert_eq!(host.port, None);
         assert_eq!(host.timeout_seconds, 1234);
+        assert_eq!(host.host_key_verification, HostKeyVerification::KnownHosts);
+
+        let host: SshHost = serde_json::from_value(serde_json::json!({
+            "hostname": "example.com",
+            "host_key_verification": "insecure",
+        }))
+        .unwrap();
+        assert_eq!(host.host_key_verification, HostKeyVerification::Insecure);
     }
     #[test]
     fn test_try_from_value() {
@@ -0,0 +1,629 @@
+//! Importing hosts from external inventories (Ansible INI/YAML or CSV) into the config file
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::config::write_validated_config;
+use crate::prelude::*;
+
+/// Inventory groups which every host is in, so they're not worth importing
+const IMPLICIT_GROUPS: [&str; 2] = ["all", "ungrouped"];
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+/// The kinds of inventory we can read
+pub enum InventoryFormat {
+    /// Ansible INI inventory
+    AnsibleIni,
+    /// Ansible YAML inventory
+    AnsibleYaml,
+    /// CSV with a hostname column and a groups column, groups are separated by `;` or spaces
+    Csv,
+    /// An archive from `maremma export`, which replaces the database rather than adding hosts to the config
+    Archive,
+}
+
+impl InventoryFormat {
+    /// Guesses the format from the file extension, anything unknown is treated as Ansible INI
+    pub fn from_path(path: &Path) -> Self {
+        if path.to_string_lossy().to_lowercase().ends_with(".tar.zst") {
+            return Self::Archive;
+        }
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .as_deref()
+        {
+            Some("csv") => Self::Csv,
+            Some("yml") | Some("yaml") => Self::AnsibleYaml,
+            _ => Self::AnsibleIni,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// A host found in an inventory
+pub struct ImportedHost {
+    /// The inventory name, which becomes the host's name in the config
+    pub name: String,
+    /// The address to connect to, if it's different to the name (`ansible_host`)
+    pub hostname: Option<String>,
+    /// The groups it's in
+    pub groups: BTreeSet<String>,
+}
+
+/// Collects hosts as they're found, the same host can turn up in more than one group
+#[derive(Default)]
+struct Inventory {
+    hosts: BTreeMap<String, ImportedHost>,
+}
+
+impl Inventory {
+    fn add(&mut self, name: &str, hostname: Option<String>, group: Option<&str>) {
+        let host = self
+            .hosts
+            .entry(name.to_string())
+            .or_insert_with(|| ImportedHost {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        if hostname.is_some() {
+            host.hostname = hostname;
+        }
+        if let Some(group) = group.filter(|group| !IMPLICIT_GROUPS.contains(group)) {
+            host.groups.insert(group.to_string());
+        }
+    }
+
+    /// Hosts in child groups are in the parent groups too
+    fn apply_children(&mut self, children: &BTreeMap<String, BTreeSet<String>>) {
+        fn parents_of(
+            group: &str,
+            children: &BTreeMap<String, BTreeSet<String>>,
+            found: &mut BTreeSet<String>,
+        ) {
+            for (parent, kids) in children {
+                if kids.contains(group) && found.insert(parent.clone()) {
+                    parents_of(parent, children, found);
+                }
+            }
+        }
+        for host in self.hosts.values_mut() {
+            let mut found = BTreeSet::new();
+            for group in host.groups.iter() {
+                parents_of(group, children, &mut found);
+            }
+            host.groups.extend(
+                found
+                    .into_iter()
+                    .filter(|group| !IMPLICIT_GROUPS.contains(&group.as_str())),
+            );
+        }
+    }
+
+    fn into_hosts(self) -> Vec<ImportedHost> {
+        self.hosts.into_values().collect()
+    }
+}
+
+/// Parses an Ansible INI inventory, `[group:children]` sections are followed and `[group:vars]` are ignored
+pub fn parse_ansible_ini(contents: &str) -> Result<Vec<ImportedHost>, Error> {
+    enum Section {
+        Hosts(Option<String>),
+        Children(String),
+        Vars,
+    }
+
+    let mut inventory = Inventory::default();
+    let mut children: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut section = Section::Hosts(None);
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header.strip_suffix(']').ok_or_else(|| {
+                Error::Configuration(format!("Invalid section header on line {}", index + 1))
+            })?;
+            section = match header.split_once(':') {
+                Some((group, "children")) => Section::Children(group.to_string()),
+                Some((_, "vars")) => Section::Vars,
+                Some((_, other)) => {
+                    return Err(Error::Configuration(format!(
+                        "Unknown section type '{}' on line {}",
+                        other,
+                        index + 1
+                    )))
+                }
+                None => Section::Hosts(Some(header.to_string())),
+            };
+            continue;
+        }
+        match &section {
+            Section::Hosts(group) => {
+                let mut parts = line.split_whitespace();
+                let Some(name) = parts.next() else {
+                    continue;
+                };
+                let hostname = parts
+                    .filter_map(|part| part.split_once('='))
+                    .find(|(key, _)| *key == "ansible_host")
+                    .map(|(_, value)| value.trim_matches(|c| c == '"' || c == '\'').to_string());
+                inventory.add(name, hostname, group.as_deref());
+            }
+            Section::Children(parent) => {
+                children
+                    .entry(parent.clone())
+                    .or_default()
+                    .insert(line.to_string());
+            }
+            Section::Vars => {}
+        }
+    }
+    inventory.apply_children(&children);
+    Ok(inventory.into_hosts())
+}
+
+/// Parses an Ansible YAML inventory
+pub fn parse_ansible_yaml(contents: &str) -> Result<Vec<ImportedHost>, Error> {
+    fn walk(
+        group: &str,
+        value: &serde_yaml::Value,
+        inventory: &mut Inventory,
+        children: &mut BTreeMap<String, BTreeSet<String>>,
+    ) {
+        if let Some(hosts) = value.get("hosts").and_then(|hosts| hosts.as_mapping()) {
+            for (name, vars) in hosts {
+                let Some(name) = name.as_str() else {
+                    continue;
+                };
+                let hostname = vars
+                    .get("ansible_host")
+                    .and_then(|hostname| hostname.as_str())
+                    .map(|hostname| hostname.to_string());
+                inventory.add(name, hostname, Some(group));
+            }
+        }
+        if let Some(kids) = value.get("children").and_then(|kids| kids.as_mapping()) {
+            for (child, child_value) in kids {
+                let Some(child) = child.as_str() else {
+                    continue;
+                };
+                children
+                    .entry(group.to_string())
+                    .or_default()
+                    .insert(child.to_string());
+                walk(child, child_value, inventory, children);
+            }
+        }
+    }
+
+    let parsed: serde_yaml::Value = serde_yaml::from_str(contents)
+        .map_err(|err| Error::Deserialization(format!("Failed to parse inventory: {}", err)))?;
+    let groups = parsed
+        .as_mapping()
+        .ok_or_else(|| Error::Configuration("Inventory should be a map of groups".to_string()))?;
+
+    let mut inventory = Inventory::default();
+    let mut children = BTreeMap::new();
+    for (group, value) in groups {
+        if let Some(group) = group.as_str() {
+            walk(group, value, &mut inventory, &mut children);
+        }
+    }
+    inventory.apply_children(&children);
+    Ok(inventory.into_hosts())
+}
+
+/// Parses a CSV of `hostname,groups`, a header row starting with `hostname` is skipped
+pub fn parse_csv(contents: &str) -> Result<Vec<ImportedHost>, Error> {
+    let mut inventory = Inventory::default();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.splitn(2, ',').map(|column| column.trim());
+        let name = columns.next().unwrap_or_default();
+        if index == 0 && name.eq_ignore_ascii_case("hostname") {
+            continue;
+        }
+        if name.is_empty() {
+            return Err(Error::Configuration(format!(
+                "Missing hostname on line {}",
+                index + 1
+            )));
+        }
+        inventory.add(name, None, None);
+        for group in columns
+            .next()
+            .unwrap_or_default()
+            .trim_matches('"')
+            .split(|c: char| c == ';' || c.is_whitespace())
+            .filter(|group| !group.is_empty())
+        {
+            inventory.add(name, None, Some(group));
+        }
+    }
+    Ok(inventory.into_hosts())
+}
+
+/// Parses an inventory in the given format
+pub fn parse_inventory(
+    contents: &str,
+    format: InventoryFormat,
+) -> Result<Vec<ImportedHost>, Error> {
+    match format {
+        InventoryFormat::AnsibleIni => parse_ansible_ini(contents),
+        InventoryFormat::AnsibleYaml => parse_ansible_yaml(contents),
+        InventoryFormat::Csv => parse_csv(contents),
+        InventoryFormat::Archive => Err(Error::InvalidInput(
+            "Archives are restored into the database, not parsed as an inventory".to_string(),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A change made to the config by an import
+pub enum ImportChange {
+    /// A new host
+    AddHost {
+        /// The host name
+        name: String,
+        /// The groups it's in
+        groups: BTreeSet<String>,
+    },
+    /// An existing host was added to more groups
+    AddGroups {
+        /// The host name
+        name: String,
+        /// The groups it's been added to
+        groups: BTreeSet<String>,
+    },
+    /// An existing host's hostname changed
+    SetHostname {
+        /// The host name
+        name: String,
+        /// The new hostname
+        hostname: String,
+    },
+}
+
+impl Display for ImportChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |groups: &BTreeSet<String>| {
+            groups
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            Self::AddHost { name, groups } if groups.is_empty() => write!(f, "+ host {}", name),
+            Self::AddHost { name, groups } => {
+                write!(f, "+ host {} (groups: {})", name, join(groups))
+            }
+            Self::AddGroups { name, groups } => {
+                write!(f, "~ host {} added to groups: {}", name, join(groups))
+            }
+            Self::SetHostname { name, hostname } => {
+                write!(f, "~ host {} hostname set to {}", name, hostname)
+            }
+        }
+    }
+}
+
+/// Merges the hosts into the config's `hosts`, existing hosts keep their settings and only gain groups
+pub fn merge_hosts(
+    config: &mut Value,
+    hosts: Vec<ImportedHost>,
+) -> Result<Vec<ImportChange>, Error> {
+    let config_hosts = config
+        .as_object_mut()
+        .ok_or_else(|| Error::Configuration("Config file should be a JSON object".to_string()))?
+        .entry("hosts")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| Error::Configuration("hosts should be a JSON object".to_string()))?;
+
+    let mut changes = Vec::new();
+    for imported in hosts {
+        // match names the same way the rest of the config does
+        let existing = config_hosts
+            .keys()
+            .find(|name| entities::name_key(name) == entities::name_key(&imported.name))
+            .cloned();
+
+        let Some(existing) = existing else {
+            let mut host = json!({
+                "host_groups": imported.groups.iter().collect::<Vec<_>>(),
+            });
+            if let Some(hostname) = imported.hostname.as_ref() {
+                host["hostname"] = json!(hostname);
+            }
+            config_hosts.insert(imported.name.clone(), host);
+            changes.push(ImportChange::AddHost {
+                name: imported.name,
+                groups: imported.groups,
+            });
+            continue;
+        };
+
+        let host = config_hosts
+            .get_mut(&existing)
+            .and_then(|host| host.as_object_mut())
+            .ok_or_else(|| {
+                Error::Configuration(format!("Host {} should be a JSON object", existing))
+            })?;
+
+        if let Some(hostname) = imported.hostname {
+            if host.get("hostname").and_then(|value| value.as_str()) != Some(hostname.as_str()) {
+                host.insert("hostname".to_string(), json!(hostname));
+                changes.push(ImportChange::SetHostname {
+                    name: existing.clone(),
+                    hostname,
+                });
+            }
+        }
+
+        let current = host
+            .get("host_groups")
+            .and_then(|groups| groups.as_array())
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(|group| group.as_str())
+                    .map(entities::name_key)
+                    .collect::<BTreeSet<_>>()
+            })
+            .unwrap_or_default();
+        let new_groups = imported
+            .groups
+            .into_iter()
+            .filter(|group| !current.contains(&entities::name_key(group)))
+            .collect::<BTreeSet<_>>();
+        if !new_groups.is_empty() {
+            let groups = host
+                .entry("host_groups")
+                .or_insert_with(|| json!([]))
+                .as_array_mut()
+                .ok_or_else(|| {
+                    Error::Configuration(format!("host_groups for {} should be a list", existing))
+                })?;
+            groups.extend(new_groups.iter().map(|group| json!(group)));
+            changes.push(ImportChange::AddGroups {
+                name: existing,
+                groups: new_groups,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Reads the inventory and merges it into the config file, which is only written if it's not a dry run
+pub async fn import_inventory(
+    config_file: &Path,
+    inventory_file: &Path,
+    format: Option<InventoryFormat>,
+    dry_run: bool,
+) -> Result<Vec<ImportChange>, Error> {
+    let format = format.unwrap_or_else(|| InventoryFormat::from_path(inventory_file));
+    let hosts = parse_inventory(&tokio::fs::read_to_string(inventory_file).await?, format)?;
+    merge_into_config_file(config_file, hosts, dry_run).await
+}
+
+/// Merges the hosts into the config file, which is only written if it's not a dry run and the result still loads
+pub async fn merge_into_config_file(
+    config_file: &Path,
+    hosts: Vec<ImportedHost>,
+    dry_run: bool,
+) -> Result<Vec<ImportChange>, Error> {
+    let mut config: Value = serde_json::from_str(&tokio::fs::read_to_string(config_file).await?)?;
+    let changes = merge_hosts(&mut config, hosts)?;
+
+    if !dry_run && !changes.is_empty() {
+        let contents = serde_json::to_string_pretty(&config)?;
+        write_validated_config(config_file, &contents).await?;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(groups: &[&str]) -> BTreeSet<String> {
+        groups.iter().map(|group| group.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_ansible_ini() {
+        let hosts = parse_ansible_ini(
+            r#"
+loose.example.com
+
+[web]
+web1.example.com ansible_host=10.0.0.1 ansible_user=admin
+web2.example.com
+
+[db]
+db1.example.com ansible_host="10.0.0.10"
+
+[prod:children]
+web
+db
+
+[prod:vars]
+ntp_server=ntp.example.com
+"#,
+        )
+        .expect("Failed to parse inventory");
+
+        assert_eq!(hosts.len(), 4);
+        let web1 = hosts
+            .iter()
+            .find(|host| host.name == "web1.example.com")
+            .expect("Missing web1");
+        assert_eq!(web1.hostname.as_deref(), Some("10.0.0.1"));
+        assert_eq!(web1.groups, groups(&["prod", "web"]));
+        let db1 = hosts
+            .iter()
+            .find(|host| host.name == "db1.example.com")
+            .expect("Missing db1");
+        assert_eq!(db1.hostname.as_deref(), Some("10.0.0.10"));
+        assert_eq!(db1.groups, groups(&["db", "prod"]));
+        let loose = hosts
+            .iter()
+            .find(|host| host.name == "loose.example.com")
+            .expect("Missing loose host");
+        assert!(loose.groups.is_empty());
+
+        assert!(parse_ansible_ini("[broken").is_err());
+    }
+
+    #[test]
+    fn test_parse_ansible_yaml() {
+        let hosts = parse_ansible_yaml(
+            r#"
+all:
+  hosts:
+    loose.example.com:
+  children:
+    prod:
+      children:
+        web:
+          hosts:
+            web1.example.com:
+              ansible_host: 10.0.0.1
+            web2.example.com:
+"#,
+        )
+        .expect("Failed to parse inventory");
+        assert_eq!(hosts.len(), 3);
+        let web1 = hosts
+            .iter()
+            .find(|host| host.name == "web1.example.com")
+            .expect("Missing web1");
+        assert_eq!(web1.hostname.as_deref(), Some("10.0.0.1"));
+        assert_eq!(web1.groups, groups(&["prod", "web"]));
+        assert!(hosts
+            .iter()
+            .any(|host| host.name == "loose.example.com" && host.groups.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let hosts = parse_csv("hostname,groups\nweb1.example.com,web;prod\ndb1.example.com,\"db prod\"\nbare.example.com\n")
+            .expect("Failed to parse CSV");
+        assert_eq!(hosts.len(), 3);
+        assert!(hosts
+            .iter()
+            .any(|host| host.name == "db1.example.com" && host.groups == groups(&["db", "prod"])));
+        assert!(parse_csv(",web").is_err());
+        assert_eq!(
+            InventoryFormat::from_path(Path::new("hosts.CSV")),
+            InventoryFormat::Csv
+        );
+        assert_eq!(
+            InventoryFormat::from_path(Path::new("inventory")),
+            InventoryFormat::AnsibleIni
+        );
+        assert_eq!(
+            InventoryFormat::from_path(Path::new("backup.tar.zst")),
+            InventoryFormat::Archive
+        );
+    }
+
+    #[test]
+    fn test_merge_hosts() {
+        let mut config = json!({
+            "hosts": {
+                "Web1.example.com": {"host_groups": ["web"], "check": "ping"}
+            }
+        });
+        let changes = merge_hosts(
+            &mut config,
+            vec![
+                ImportedHost {
+                    name: "web1.example.com".to_string(),
+                    hostname: None,
+                    groups: groups(&["WEB", "prod"]),
+                },
+                ImportedHost {
+                    name: "db1.example.com".to_string(),
+                    hostname: Some("10.0.0.10".to_string()),
+                    groups: groups(&["db"]),
+                },
+            ],
+        )
+        .expect("Failed to merge hosts");
+
+        assert_eq!(
+            changes,
+            vec![
+                ImportChange::AddGroups {
+                    name: "Web1.example.com".to_string(),
+                    groups: groups(&["prod"]),
+                },
+                ImportChange::AddHost {
+                    name: "db1.example.com".to_string(),
+                    groups: groups(&["db"]),
+                },
+            ]
+        );
+        assert_eq!(
+            config["hosts"]["Web1.example.com"],
+            json!({"host_groups": ["web", "prod"], "check": "ping"})
+        );
+        assert_eq!(
+            config["hosts"]["db1.example.com"],
+            json!({"host_groups": ["db"], "hostname": "10.0.0.10"})
+        );
+
+        // doing it again changes nothing
+        let changes = merge_hosts(
+            &mut config,
+            vec![ImportedHost {
+                name: "db1.example.com".to_string(),
+                hostname: Some("10.0.0.10".to_string()),
+                groups: groups(&["db"]),
+            }],
+        )
+        .expect("Failed to merge hosts");
+        assert!(changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_inventory_dry_run() {
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create config file");
+        let original = tokio::fs::read_to_string("maremma.example.json")
+            .await
+            .expect("Failed to read example config");
+        tokio::fs::write(config_file.path(), &original)
+            .await
+            .expect("Failed to write config file");
+        let inventory = tempfile::Builder::new()
+            .suffix(".csv")
+            .tempfile()
+            .expect("Failed to create inventory");
+        tokio::fs::write(inventory.path(), "imported.example.com,imported\n")
+            .await
+            .expect("Failed to write inventory");
+
+        let changes = import_inventory(config_file.path(), inventory.path(), None, true)
+            .await
+            .expect("Failed to import");
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            tokio::fs::read_to_string(config_file.path())
+                .await
+                .expect("Failed to read config file"),
+            original
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Free-form `key: value` labels on hosts and services, eg `{"env": "prod", "team": "payments"}`
+//!
+//! They're shown as chips in the UI, list views and the API can filter on them with `?label=env:prod`,
+//! they're added to the Prometheus metrics as `label_<key>`, and they're listed in notifications.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use sea_orm::sea_query::{Alias, Func, SimpleExpr};
+use sea_orm::Condition;
+
+use crate::prelude::*;
+
+/// Labels, sorted by key so they show up in a consistent order
+pub type Labels = BTreeMap<String, String>;
+
+/// Label keys can only use these characters (plus ASCII letters and numbers) so they're safe in JSON paths and URLs
+const KEY_CHARACTERS: &[char] = &['_', '-', '.', '/'];
+
+/// Checks a label key is something we can filter on, eg `env` or `app.kubernetes.io/name`
+pub fn validate_key(key: &str) -> Result<(), Error> {
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || KEY_CHARACTERS.contains(&c))
+    {
+        return Err(Error::Configuration(format!(
+            "label '{}' should only have letters, numbers and {}",
+            key,
+            KEY_CHARACTERS
+                .iter()
+                .map(|c| format!("'{}'", c))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the labels stored in a JSON column, ignoring anything that isn't a string
+pub fn from_json(value: &Json) -> Labels {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string())))
+        .collect()
+}
+
+/// A check's labels, the service's value wins if the host and service both have one
+pub fn merge(host: &Labels, service: &Labels) -> Labels {
+    let mut labels = host.clone();
+    labels.extend(service.clone());
+    labels
+}
+
+/// Labels as `env=prod, team=payments`, for notifications
+pub fn describe(labels: &Labels) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Prometheus label names can only have letters, numbers and underscores, so `app.kubernetes.io/name` becomes `label_app_kubernetes_io_name`
+pub fn metric_label_name(key: &str) -> String {
+    format!(
+        "label_{}",
+        key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>()
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+/// A `?label=` filter, `env:prod` matches that value and `env` matches anything with the label
+pub struct LabelFilter {
+    /// The label's key
+    pub key: String,
+    /// The value it needs to have, if any
+    pub value: Option<String>,
+}
+
+impl FromStr for LabelFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (key, value) = match value.split_once(':') {
+            Some((key, value)) => (key.trim(), Some(value.trim().to_string())),
+            None => (value.trim(), None),
+        };
+        validate_key(key).map_err(|_| format!("Invalid label filter '{}'", key))?;
+        Ok(Self {
+            key: key.to_string(),
+            value,
+        })
+    }
+}
+
+impl TryFrom<String> for LabelFilter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for LabelFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}:{}", self.key, value),
+            None => write!(f, "{}", self.key),
+        }
+    }
+}
+
+impl LabelFilter {
+    /// Does a set of labels match the filter
+    pub fn matches(&self, labels: &Labels) -> bool {
+        match (labels.get(&self.key), &self.value) {
+            (Some(found), Some(value)) => found == value,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Matches the labels stored in a JSON `column`
+    pub fn condition<C>(&self, column: C) -> SimpleExpr
+    where
+        C: ColumnTrait,
+    {
+        // the key's been validated, so it doesn't need escaping in the path
+        let value = Expr::expr(
+            Func::cust(Alias::new("json_extract"))
+                .arg(Expr::col(column.as_column_ref()))
+                .arg(format!("$.\"{}\"", self.key)),
+        );
+        match &self.value {
+            Some(expected) => value.eq(expected.as_str()),
+            None => value.is_not_null(),
+        }
+    }
+
+    /// Matches when either of the columns have the label, eg the host or the service of a check
+    pub fn condition_any<C, D>(&self, column: C, other: D) -> Condition
+    where
+        C: ColumnTrait,
+        D: ColumnTrait,
+    {
+        Condition::any()
+            .add(self.condition(column))
+            .add(self.condition(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_filter_parse() {
+        let filter: LabelFilter = "env:prod".parse().expect("Failed to parse filter");
+        assert_eq!(filter.key, "env");
+        assert_eq!(filter.value.as_deref(), Some("prod"));
+        assert_eq!(filter.to_string(), "env:prod");
+
+        let filter: LabelFilter = "app.kubernetes.io/name"
+            .parse()
+            .expect("Failed to parse filter");
+        assert_eq!(filter.value, None);
+
+        assert!("".parse::<LabelFilter>().is_err());
+        assert!("bad\"key:prod".parse::<LabelFilter>().is_err());
+    }
+
+    #[test]
+    fn test_label_filter_matches() {
+        let labels = Labels::from([("env".to_string(), "prod".to_string())]);
+        assert!(LabelFilter::from_str("env:prod")
+            .expect("Failed to parse filter")
+            .matches(&labels));
+        assert!(LabelFilter::from_str("env")
+            .expect("Failed to parse filter")
+            .matches(&labels));
+        assert!(!LabelFilter::from_str("env:dev")
+            .expect("Failed to parse filter")
+            .matches(&labels));
+        assert!(!LabelFilter::from_str("team")
+            .expect("Failed to parse filter")
+            .matches(&labels));
+    }
+
+    #[test]
+    fn test_from_json_and_metric_names() {
+        let labels = from_json(&json!({"env": "prod", "count": 3}));
+        assert_eq!(labels.len(), 1);
+        assert!(from_json(&json!(null)).is_empty());
+        assert_eq!(
+            metric_label_name("app.kubernetes.io/name"),
+            "label_app_kubernetes_io_name"
+        );
+    }
+
+    #[test]
+    fn test_merge_and_describe() {
+        let host = from_json(&json!({"env": "prod", "team": "infra"}));
+        let service = from_json(&json!({"team": "payments"}));
+        let labels = merge(&host, &service);
+        assert_eq!(describe(&labels), "env=prod, team=payments");
+        assert_eq!(describe(&Labels::new()), "");
+    }
+}
@@ -18,19 +18,42 @@
 #![deny(clippy::unwrap_used)]
 
 pub mod actions;
+pub mod agent;
+pub mod alertmanager;
+pub mod archive;
+pub mod artifacts;
+pub mod backup;
 pub mod check_loop;
 pub mod cli;
 pub mod config;
+pub mod config_events;
 pub mod constants;
 pub mod db;
+pub mod discovery;
 pub mod errors;
+pub mod group_status;
+pub mod health;
 pub mod host;
+pub mod import;
+pub mod labels;
 pub mod log;
 pub mod metrics;
+pub mod migrate_config;
+pub mod object_store;
 pub mod prelude;
+pub mod reports;
+pub mod result_writer;
+pub mod schedule_insight;
+pub mod secrets;
 pub(crate) mod serde;
 pub mod services;
 pub mod shepherd;
+pub mod shutdown;
+pub mod ssh_client;
+pub mod status;
+pub mod status_page;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(test)]
 pub(crate) mod tests;
 pub mod web;
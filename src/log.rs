@@ -1,51 +1,111 @@
 //! log configuration and setup module
 
+use std::collections::HashMap;
 use std::env;
+use std::sync::LazyLock;
 
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::ValueEnum;
 use env_logger::{Builder, Target};
 use log::LevelFilter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::db::entities::incident::duration_text;
+use crate::errors::Error;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// How log lines are written
+pub enum LogFormat {
+    /// For humans
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields from the surrounding spans like `service_check_id`
+    Json,
+}
+
+/// Targets which are too noisy at the normal level
+fn target_filters(level: LevelFilter, db_debug: bool) -> Vec<(&'static str, LevelFilter)> {
+    let mut filters = Vec::new();
+    if level == LevelFilter::Info {
+        filters.push(("ssh::", LevelFilter::Warn));
+    }
+
+    if !db_debug {
+        // We don't want to see the SQL queries in the logs
+        filters.push(("sea_orm::driver::sqlx_sqlite", LevelFilter::Error));
+        filters.push(("sqlx::query", LevelFilter::Warn));
+    }
+
+    filters.push(("ssh::channel::local::channel", LevelFilter::Warn));
+    filters.push(("h2", LevelFilter::Warn));
+    filters.push(("tracing::span", LevelFilter::Warn));
+    filters
+}
+
+fn json_filter(level: LevelFilter, db_debug: bool) -> EnvFilter {
+    let mut filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level.as_str().to_lowercase()));
+    for (target, target_level) in target_filters(level, db_debug) {
+        let directive = format!(
+            "{}={}",
+            target.trim_end_matches("::"),
+            target_level.as_str().to_lowercase()
+        );
+        match directive.parse() {
+            Ok(directive) => filter = filter.add_directive(directive),
+            Err(err) => eprintln!("Invalid log filter {}: {:?}", directive, err),
+        }
+    }
+    filter
+}
 
 /// Sets up logging
-pub fn setup_logging(debug: bool, db_debug: bool) -> Result<(), log::SetLoggerError> {
+pub fn setup_logging(debug: bool, db_debug: bool, format: LogFormat) -> Result<(), Error> {
     // check the env vars
     #[cfg(not(any(debug_assertions, test)))]
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info");
     }
 
-    let mut builder = Builder::from_default_env();
-
     let level = if debug && env::var("RUST_LOG").is_err() {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
 
-    builder.filter_level(level);
-
-    if level == LevelFilter::Info {
-        builder.filter(Some("ssh::"), LevelFilter::Warn);
-    }
-
-    if !db_debug {
-        // We don't want to see the SQL queries in the logs
-        builder.filter(Some("sea_orm::driver::sqlx_sqlite"), LevelFilter::Error);
-        builder.filter(Some("sqlx::query"), LevelFilter::Warn);
-    }
-
-    builder.filter(Some("ssh::channel::local::channel"), LevelFilter::Warn);
-    builder.filter(Some("h2"), LevelFilter::Warn);
-    builder.filter(Some("tracing::span"), LevelFilter::Warn);
-    builder.target(Target::Stdout);
+    let res = match format {
+        LogFormat::Text => {
+            let mut builder = Builder::from_default_env();
+            builder.filter_level(level);
+            for (target, target_level) in target_filters(level, db_debug) {
+                builder.filter(Some(target), target_level);
+            }
+            builder.target(Target::Stdout);
+            builder
+                .try_init()
+                .map_err(|err| Error::Generic(format!("Failed to set up logging: {:?}", err)))
+        }
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_env_filter(json_filter(level, db_debug))
+            .with_writer(std::io::stdout)
+            .try_init()
+            .map_err(|err| Error::Generic(format!("Failed to set up logging: {:?}", err))),
+    };
 
     #[cfg(not(test))]
     {
-        builder.try_init()
+        res
     }
 
     #[cfg(test)]
     {
-        if let Err(err) = builder.try_init() {
+        if let Err(err) = res {
             use tracing::debug;
             debug!("Error init logging: {:?}", err);
         }
@@ -53,22 +113,216 @@ pub fn setup_logging(debug: bool, db_debug: bool) -> Result<(), log::SetLoggerEr
     }
 }
 
+/// How long repeats of the same failure are held back before they're summarised, in seconds
+pub const DEFAULT_REPEAT_WINDOW_SECONDS: u64 = 3600;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_window_seconds() -> u64 {
+    DEFAULT_REPEAT_WINDOW_SECONDS
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+/// Settings for holding back repeats of the same check failure, so a host that's down doesn't flood the logs
+pub struct RepeatedFailuresConfig {
+    /// Hold back repeats, if it's off every failure's logged, defaults to true
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// How long repeats are held back before they're summarised, defaults to [DEFAULT_REPEAT_WINDOW_SECONDS]
+    #[serde(default = "default_window_seconds")]
+    pub window_seconds: u64,
+}
+
+impl Default for RepeatedFailuresConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_seconds: DEFAULT_REPEAT_WINDOW_SECONDS,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Repeats {
+    message: String,
+    /// When the message was last logged
+    logged_at: DateTime<Utc>,
+    /// How many times it's been seen since then
+    held_back: u64,
+}
+
+impl Repeats {
+    fn summary(&self, now: DateTime<Utc>) -> String {
+        let times = match self.held_back {
+            1 => "once".to_string(),
+            held_back => format!("{} times", held_back),
+        };
+        format!(
+            "repeated {} in the last {}",
+            times,
+            duration_text(now - self.logged_at)
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+/// Keeps track of the failures that have been logged, so the same one isn't logged over and over
+pub struct RepeatFilter {
+    config: std::sync::RwLock<RepeatedFailuresConfig>,
+    seen: std::sync::Mutex<HashMap<String, Repeats>>,
+}
+
+/// The filter used for check failures, keyed by service check
+pub static CHECK_FAILURES: LazyLock<RepeatFilter> = LazyLock::new(RepeatFilter::default);
+
+impl RepeatFilter {
+    /// Updates the settings, anything that's been held back is forgotten if it's turned off
+    pub fn configure(&self, config: RepeatedFailuresConfig) {
+        if let Ok(mut current) = self.config.write() {
+            *current = config;
+        }
+        if !config.enabled {
+            if let Ok(mut seen) = self.seen.lock() {
+                seen.clear();
+            }
+        }
+    }
+
+    fn config(&self) -> RepeatedFailuresConfig {
+        self.config.read().map(|config| *config).unwrap_or_default()
+    }
+
+    /// Returns what to log for `message`, or `None` if it's a repeat that's being held back. Once the window's
+    /// passed the next repeat is logged again, with how many times it was held back.
+    pub fn observe(&self, key: &str, message: &str, now: DateTime<Utc>) -> Option<String> {
+        let config = self.config();
+        if !config.enabled {
+            return Some(message.to_string());
+        }
+        let Ok(mut seen) = self.seen.lock() else {
+            return Some(message.to_string());
+        };
+        let window = TimeDelta::seconds(config.window_seconds.min(i64::MAX as u64) as i64);
+        let res = match seen.get_mut(key) {
+            Some(repeats) if repeats.message == message => {
+                if now - repeats.logged_at < window {
+                    repeats.held_back += 1;
+                    return None;
+                }
+                match repeats.held_back {
+                    0 => message.to_string(),
+                    _ => format!("{} ({})", message, repeats.summary(now)),
+                }
+            }
+            // it's failing in a different way, which is worth knowing about straight away
+            Some(repeats) if repeats.held_back > 0 => format!(
+                "{} (the previous failure {})",
+                message,
+                repeats.summary(now)
+            ),
+            _ => message.to_string(),
+        };
+        seen.insert(
+            key.to_string(),
+            Repeats {
+                message: message.to_string(),
+                logged_at: now,
+                held_back: 0,
+            },
+        );
+        Some(res)
+    }
+
+    /// Forgets about `key`, eg when the check's OK again, returning a summary of the repeats that weren't logged
+    pub fn clear(&self, key: &str, now: DateTime<Utc>) -> Option<String> {
+        let repeats = self.seen.lock().ok()?.remove(key)?;
+        (repeats.held_back > 0).then(|| repeats.summary(now))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::setup_logging;
+    use super::*;
 
     #[test]
     fn test_setup_logging() {
-        let test1 = setup_logging(false, true);
+        let test1 = setup_logging(false, true, LogFormat::Text);
         dbg!(&test1);
         assert!(test1.is_ok());
         // it'll probably throw an error because we're trying to re-init the logger, but we're in test so it's OK.
-        let test2 = setup_logging(true, true);
+        let test2 = setup_logging(true, true, LogFormat::Text);
         dbg!(&test2);
         assert!(test2.is_ok());
 
-        let test3 = setup_logging(true, false);
+        let test3 = setup_logging(true, false, LogFormat::Json);
         dbg!(&test3);
         assert!(test3.is_ok());
     }
+
+    #[test]
+    fn test_target_filters() {
+        let filters = target_filters(LevelFilter::Info, false);
+        assert!(filters.contains(&("ssh::", LevelFilter::Warn)));
+        assert!(filters.contains(&("sqlx::query", LevelFilter::Warn)));
+
+        let filters = target_filters(LevelFilter::Debug, true);
+        assert!(!filters.contains(&("ssh::", LevelFilter::Warn)));
+        assert!(!filters.contains(&("sqlx::query", LevelFilter::Warn)));
+
+        let filter = json_filter(LevelFilter::Info, false).to_string();
+        assert!(filter.contains("sqlx::query=warn"), "{}", filter);
+        assert!(filter.contains("ssh=warn"), "{}", filter);
+    }
+
+    #[test]
+    fn test_repeat_filter() {
+        let filter = RepeatFilter::default();
+        let now = Utc::now();
+        let later = |minutes| now + TimeDelta::minutes(minutes);
+
+        assert_eq!(
+            filter.observe("check", "ping failed", now).as_deref(),
+            Some("ping failed")
+        );
+        for minute in 1..=57 {
+            assert!(filter
+                .observe("check", "ping failed", later(minute))
+                .is_none());
+        }
+        // other keys aren't held back
+        assert!(filter.observe("other", "ping failed", later(1)).is_some());
+
+        // the window's over, so it's logged with what was held back
+        assert_eq!(
+            filter.observe("check", "ping failed", later(60)).as_deref(),
+            Some("ping failed (repeated 57 times in the last 1h 0m)")
+        );
+        assert!(filter.observe("check", "ping failed", later(61)).is_none());
+
+        // a different failure's logged straight away
+        assert_eq!(
+            filter
+                .observe("check", "ping timed out", later(62))
+                .as_deref(),
+            Some("ping timed out (the previous failure repeated once in the last 2m)")
+        );
+        assert!(filter
+            .observe("check", "ping timed out", later(63))
+            .is_none());
+
+        assert_eq!(
+            filter.clear("check", later(70)).as_deref(),
+            Some("repeated once in the last 8m")
+        );
+        assert!(filter.clear("check", later(70)).is_none());
+
+        filter.configure(RepeatedFailuresConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        assert!(filter.observe("check", "ping failed", now).is_some());
+        assert!(filter.observe("check", "ping failed", now).is_some());
+    }
 }
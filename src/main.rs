@@ -1,12 +1,14 @@
 use clap::Parser;
-use maremma::cli::{Actions, CliOpts};
+use maremma::cli::{Actions, CliOpts, ExplainCmd};
 use maremma::config::Configuration;
 use maremma::prelude::*;
 use maremma::web::run_web_server;
 
 use maremma::log::setup_logging;
+use maremma::result_writer::RESULT_WRITER;
 
-use maremma::check_loop::run_check_loop;
+use futures::future::{FusedFuture, FutureExt};
+use maremma::check_loop::{run_check_loop, HostConcurrency, RunningChecks, SchedulingClasses};
 use maremma::db::update_db_from_config;
 use opentelemetry::metrics::MeterProvider;
 use std::process::ExitCode;
@@ -17,9 +19,11 @@ async fn main() -> Result<(), ExitCode> {
     use maremma::db::get_connect_string;
     use maremma::services::oneshot::run_oneshot;
     use maremma::shepherd::shepherd;
+    use maremma::shutdown::shutdown_signal;
+    use maremma::web::controller::WebServerControl;
 
     let cli = CliOpts::parse();
-    if let Err(err) = setup_logging(cli.debug(), cli.db_debug()) {
+    if let Err(err) = setup_logging(cli.debug(), cli.db_debug(), cli.log_format()) {
         println!("Failed to setup logging: {:?}", err);
         return Err(ExitCode::from(1));
     };
@@ -32,22 +36,124 @@ async fn main() -> Result<(), ExitCode> {
         return Ok(());
     }
 
+    if let Actions::Agent(cmd) = cli.action {
+        // agents don't have a config file or database, they get everything from the server
+        return maremma::agent::run_agent(cmd).await.map_err(|err| {
+            error!("Agent failed: {:?}", err);
+            ExitCode::FAILURE
+        });
+    }
+
+    if let Actions::Import(cmd) = &cli.action {
+        if !cmd.is_archive() {
+            // this edits the config file directly, the database catches up on the next run
+            let changes = maremma::import::import_inventory(
+                &cmd.sharedopts.config,
+                &cmd.file,
+                cmd.format,
+                cmd.dry_run,
+            )
+            .await
+            .map_err(|err| {
+                error!("Failed to import inventory: {:?}", err);
+                ExitCode::FAILURE
+            })?;
+            if changes.is_empty() {
+                println!("No changes");
+            }
+            for change in changes.iter() {
+                println!("{}", change);
+            }
+            if cmd.dry_run && !changes.is_empty() {
+                println!("Dry run, {} not updated", cmd.sharedopts.config.display());
+            }
+            return Ok(());
+        }
+    }
+
+    if let Actions::MigrateConfig(cmd) = &cli.action {
+        // older files might not load, so this works on the file rather than the parsed config
+        let migration =
+            maremma::migrate_config::migrate_config_file(&cmd.sharedopts.config, cmd.dry_run)
+                .await
+                .map_err(|err| {
+                    error!("Failed to migrate config: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+        if migration.changes.is_empty() {
+            println!("Already up to date");
+            return Ok(());
+        }
+        print!("{}", migration.diff);
+        for change in migration.changes.iter() {
+            println!("{}", change);
+        }
+        if cmd.dry_run {
+            println!("Dry run, {} not updated", cmd.sharedopts.config.display());
+        }
+        return Ok(());
+    }
+
     // parse the config file
     let config = Configuration::new(&cli.config()).await.map_err(|err| {
         error!("Failed to load config: {:?}", err);
         ExitCode::from(1)
     })?;
 
+    maremma::config::apply_config(&config)
+        .await
+        .map_err(|_| ExitCode::FAILURE)?;
+
+    if let Actions::Explain(ExplainCmd::Host(cmd)) = &cli.action {
+        // this only looks at the config file, so nothing's applied to the database
+        let (host, services) = config.explain_host(&cmd.name).map_err(|err| {
+            error!("Failed to explain host: {:?}", err);
+            ExitCode::FAILURE
+        })?;
+        println!(
+            "Host: {} (groups: {})",
+            cmd.name,
+            host.host_groups.join(", ")
+        );
+        if services.is_empty() {
+            println!("No services will be checked on this host");
+        }
+        for service in services {
+            println!("- {}", service);
+        }
+        return Ok(());
+    }
+
+    if let Actions::ExportPrometheusRules(_) = &cli.action {
+        println!(
+            "{}",
+            maremma::metrics::rules::alert_rules_yaml(&config).map_err(|err| {
+                error!("Failed to export Prometheus rules: {:?}", err);
+                ExitCode::FAILURE
+            })?
+        );
+        return Ok(());
+    }
+
+    if let Actions::KioskUrl(cmd) = &cli.action {
+        println!(
+            "{}",
+            maremma::web::kiosk::kiosk_url(&config, cmd.days).map_err(|err| {
+                error!("Failed to make a kiosk link: {:?}", err);
+                ExitCode::FAILURE
+            })?
+        );
+        return Ok(());
+    }
+
     let config = Arc::new(RwLock::new(config));
 
     // in case we need it, get the connect string
     let connect_string = get_connect_string(config.clone()).await;
-    let db = Arc::new(RwLock::new(
-        maremma::db::connect(config.clone()).await.map_err(|err| {
-            error!("Failed to start up db from '{}' {:?}", connect_string, err);
-            ExitCode::FAILURE
-        })?,
-    ));
+    let db = maremma::db::connect(config.clone()).await.map_err(|err| {
+        error!("Failed to start up db from '{}' {:?}", connect_string, err);
+        ExitCode::FAILURE
+    })?;
 
     match cli.action {
         Actions::Run(_) => {
@@ -67,40 +173,143 @@ async fn main() -> Result<(), ExitCode> {
             // Create a meter from the above MeterProvider.
             let metrics_meter = Arc::new(provider.meter("maremma"));
 
+            let result_writer = RESULT_WRITER.start(db.clone());
             let (web_tx, web_rx) = tokio::sync::mpsc::channel(1);
+            let running_checks = Arc::new(RunningChecks::default());
+            let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+            let shutdown_timeout =
+                std::time::Duration::from_secs(config.read().await.shutdown_timeout_seconds);
 
-            tokio::select! {
+            let host_concurrency = HostConcurrency::new(&*config.read().await, &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to work out host concurrency limits: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+            let scheduling = SchedulingClasses::new(&*config.read().await, &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to load check runtimes for scheduling: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+
+            let check_loop = run_check_loop(
+                db.clone(),
+                running_checks.clone(),
+                config.read().await.max_concurrent_checks,
+                metrics_meter.clone(),
+                Arc::new(host_concurrency),
+                Arc::new(scheduling),
+                shutdown_rx,
+                shutdown_timeout,
+            )
+            .fuse();
+            let web_server = run_web_server(
+                cli.config(),
+                config.clone(),
+                db.clone(),
+                Arc::new(registry),
+                running_checks,
+                metrics_meter.clone(),
+                web_tx.clone(),
+                web_rx,
+            )
+            .fuse();
+            let shepherd = shepherd(
+                db.clone(),
+                config.clone(),
+                cli.config(),
+                web_tx.clone(),
+                metrics_meter.clone(),
+            )
+            .fuse();
+            futures::pin_mut!(check_loop, web_server, shepherd);
 
-                check_loop_result = run_check_loop(
-                    db.clone(),
-                    config.read().await.max_concurrent_checks,
-                    metrics_meter.clone()
-                ) => {
+            let mut failed = true;
+            tokio::select! {
+                check_loop_result = &mut check_loop => {
                     error!("Check loop bailed: {:?}", check_loop_result);
                 },
-                web_server_result = run_web_server(
-                    cli.config(),
-                    config.clone(),
-                    db.clone(),
-                    Arc::new(registry),
-                    web_tx.clone(),
-                    web_rx,
-                ) => {
+                web_server_result = &mut web_server => {
                     error!("Web server bailed: {:?}", web_server_result);
                 },
-                shepherd_result = shepherd(db.clone(), config.clone(), web_tx) => {
+                shepherd_result = &mut shepherd => {
                     error!("Shepherd bailed: {:?}", shepherd_result);
+                },
+                signal = shutdown_signal() => {
+                    info!("Received {}, shutting down", signal);
+                    failed = false;
                 }
+            }
 
+            // stop taking new checks, and keep the web server up until the running ones are done
+            let _ = shutdown_tx.send(true);
+            let drain_checks = async {
+                if !check_loop.is_terminated() {
+                    if let Err(err) = (&mut check_loop).await {
+                        error!("Check loop failed while shutting down: {:?}", err);
+                        failed = true;
+                    }
+                }
+                let _ = web_tx.send(WebServerControl::Stop).await;
+            };
+            let stop_web_server = async {
+                if !web_server.is_terminated() {
+                    // the check loop cancels anything left after shutdown_timeout, so this is only a backstop
+                    if tokio::time::timeout(
+                        shutdown_timeout + std::time::Duration::from_secs(10),
+                        &mut web_server,
+                    )
+                    .await
+                    .is_err()
+                    {
+                        warn!("Web server didn't stop in time");
+                    }
+                }
+            };
+            tokio::join!(drain_checks, stop_web_server);
+
+            // nothing else is sending results now, so write what's left
+            RESULT_WRITER.stop();
+            if let Err(err) = result_writer.await {
+                error!("Result writer failed while shutting down: {:?}", err);
+                failed = true;
             }
+
+            // send anything that's still waiting in a rate limit summary
+            maremma::actions::dispatcher::DISPATCHER
+                .lock()
+                .await
+                .flush()
+                .await;
+
+            if failed {
+                return Err(ExitCode::FAILURE);
+            }
+            info!("Shutdown complete");
         }
         Actions::CheckConfig(_show_config) => {
-            todo!("Check config CLI hasn't been implemented")
+            let problems = maremma::config::check_config(&cli.config(), &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to check config: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+            if !problems.is_empty() {
+                for problem in problems {
+                    println!("{}", problem);
+                }
+                return Err(ExitCode::FAILURE);
+            }
+            println!("Configuration OK");
         }
         Actions::ShowConfig(_show_config) => {
             println!(
                 "{}",
-                serde_json::to_string_pretty(&*config.read().await)
+                config
+                    .read()
+                    .await
+                    .as_json_pretty()
                     .unwrap_or(format!("Failed to serialize config: {:?}", &config))
             );
         }
@@ -109,7 +318,51 @@ async fn main() -> Result<(), ExitCode> {
             Err(err) => error!("Failed to run oneshot: {:?}", err),
             Ok(_) => {}
         },
-        Actions::ExportConfigSchema => unreachable!(),
+        Actions::Status(cmd) => {
+            maremma::status::run_status(cmd, &db).await.map_err(|err| {
+                error!("Failed to get status: {:?}", err);
+                ExitCode::FAILURE
+            })?;
+        }
+        Actions::Discover(cmd) => {
+            maremma::discovery::run_discover(&cmd, &*config.read().await, &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to discover hosts: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+        }
+        Actions::Export(cmd) => {
+            maremma::archive::run_export(&cmd, &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to export: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+        }
+        // inventories were handled before the database was opened
+        Actions::Import(cmd) => {
+            maremma::archive::run_import(&cmd, &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to import archive: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+        }
+        Actions::LocalUser(cmd) => {
+            maremma::web::auth::run_local_user(&cmd, &db)
+                .await
+                .map_err(|err| {
+                    error!("Failed to update local user: {:?}", err);
+                    ExitCode::FAILURE
+                })?;
+        }
+        Actions::ExportConfigSchema
+        | Actions::MigrateConfig(_)
+        | Actions::Agent(_)
+        | Actions::Explain(_)
+        | Actions::ExportPrometheusRules(_)
+        | Actions::KioskUrl(_) => unreachable!(),
     }
     Ok(())
 }
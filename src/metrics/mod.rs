@@ -1,5 +1,7 @@
 //! Prometheus metrics magic
 
+pub mod rules;
+
 use crate::prelude::*;
 use std::time::Duration;
 
@@ -0,0 +1,316 @@
+//! Prometheus alerting rules for services with `expose_alert_rule` set
+//!
+//! The rules use the [STATUS_METRIC] gauge served on `/metrics`, so teams already running
+//! Alertmanager can alert on Maremma's checks without maintaining the rules by hand.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::config::Configuration;
+use crate::prelude::*;
+
+/// The gauge the alerting rules are written against
+pub const STATUS_METRIC: &str = "maremma_service_check_status";
+/// The rule group name in the generated file
+pub const RULE_GROUP_NAME: &str = "maremma";
+
+#[derive(Serialize, Debug)]
+/// A Prometheus rule file
+pub struct RuleFile {
+    /// The rule groups, there's only ever one
+    pub groups: Vec<RuleGroup>,
+}
+
+#[derive(Serialize, Debug)]
+/// A group of Prometheus rules
+pub struct RuleGroup {
+    /// The group name
+    pub name: String,
+    /// The rules
+    pub rules: Vec<AlertRule>,
+}
+
+#[derive(Serialize, Debug)]
+/// A Prometheus alerting rule
+pub struct AlertRule {
+    /// The alert name
+    pub alert: String,
+    /// The PromQL expression
+    pub expr: String,
+    /// How long the expression has to be true before it fires
+    #[serde(rename = "for", skip_serializing_if = "Option::is_none")]
+    pub for_duration: Option<String>,
+    /// Labels added to the alert
+    pub labels: BTreeMap<String, String>,
+    /// Annotations added to the alert
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// Turns a status into the number reported in [STATUS_METRIC], using the Nagios convention
+pub fn status_value(status: ServiceStatus) -> Option<u8> {
+    match status {
+        ServiceStatus::Ok => Some(0),
+        ServiceStatus::Warning => Some(1),
+        ServiceStatus::Critical | ServiceStatus::Error | ServiceStatus::Timeout => Some(2),
+        ServiceStatus::Unknown | ServiceStatus::ConfigError => Some(3),
+        ServiceStatus::Pending
+        | ServiceStatus::Checking
+        | ServiceStatus::Urgent
+        | ServiceStatus::Disabled
+        | ServiceStatus::Cancelled => None,
+    }
+}
+
+/// Alert names have to be valid metric names, so `check_ntp_time` becomes `MaremmaCheckNtpTime`
+pub(crate) fn alert_name(service_name: &str) -> String {
+    service_name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .fold("Maremma".to_string(), |mut res, part| {
+            let mut chars = part.chars();
+            if let Some(first) = chars.next() {
+                res.push(first.to_ascii_uppercase());
+                res.push_str(chars.as_str());
+            }
+            res
+        })
+}
+
+/// Escapes a string for use in a PromQL or exposition format label value
+pub(crate) fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Builds the rules for every service with `expose_alert_rule` set
+pub fn alert_rules(config: &Configuration) -> RuleFile {
+    let mut services = config
+        .services
+        .iter()
+        .filter(|(_, service)| service.expose_alert_rule)
+        .collect::<Vec<_>>();
+    services.sort_by(|a, b| a.0.cmp(b.0));
+
+    let rules = services
+        .into_iter()
+        .flat_map(|(service_name, service)| {
+            let selector = format!(
+                "{}{{service=\"{}\"}}",
+                STATUS_METRIC,
+                escape_label_value(service_name)
+            );
+            let mut annotations = BTreeMap::from([(
+                "summary".to_string(),
+                format!(
+                    "{} is {{{{ $labels.status }}}} on {{{{ $labels.host }}}}",
+                    service_name
+                ),
+            )]);
+            if let Some(description) = service.description.as_ref() {
+                annotations.insert("description".to_string(), description.clone());
+            }
+            [
+                ("Warning", "== 1", "warning"),
+                ("Critical", ">= 2", "critical"),
+            ]
+            .map(|(suffix, comparison, severity)| AlertRule {
+                alert: format!("{}{}", alert_name(service_name), suffix),
+                expr: format!("{} {}", selector, comparison),
+                for_duration: service.alert_rule_for.clone(),
+                labels: BTreeMap::from([
+                    ("severity".to_string(), severity.to_string()),
+                    ("source".to_string(), "maremma".to_string()),
+                ]),
+                annotations: annotations.clone(),
+            })
+        })
+        .collect();
+
+    RuleFile {
+        groups: vec![RuleGroup {
+            name: RULE_GROUP_NAME.to_string(),
+            rules,
+        }],
+    }
+}
+
+/// Renders [alert_rules] as YAML, ready to drop into Prometheus' `rule_files`
+pub fn alert_rules_yaml(config: &Configuration) -> Result<String, Error> {
+    serde_yaml::to_string(&alert_rules(config)).map_err(|err| Error::Generic(err.to_string()))
+}
+
+/// Renders [STATUS_METRIC] for the checks of every service with `expose_alert_rule` set
+pub async fn status_metrics(
+    db: &DatabaseConnection,
+    config: &Configuration,
+) -> Result<String, Error> {
+    let exposed = config
+        .services
+        .iter()
+        .filter(|(_, service)| service.expose_alert_rule)
+        .map(|(name, _)| entities::name_key(name))
+        .collect::<Vec<_>>();
+
+    let mut res = String::new();
+    if exposed.is_empty() {
+        return Ok(res);
+    }
+
+    let services = entities::service::Entity::find()
+        .all(db)
+        .await?
+        .into_iter()
+        .filter(|service| exposed.contains(&entities::name_key(&service.name)))
+        .map(|service| (service.id, service))
+        .collect::<HashMap<_, _>>();
+    let service_checks = entities::service_check::Entity::find()
+        .filter(entities::service_check::Column::ServiceId.is_in(services.keys().copied()))
+        .all(db)
+        .await?;
+    let hosts = entities::host::Entity::find()
+        .filter(entities::host::Column::Id.is_in(service_checks.iter().map(|check| check.host_id)))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|host| (host.id, host))
+        .collect::<HashMap<_, _>>();
+
+    let _ = writeln!(
+        res,
+        "# HELP {} Last result of the service check, 0 is OK, 1 Warning, 2 Critical and 3 Unknown",
+        STATUS_METRIC
+    );
+    let _ = writeln!(res, "# TYPE {} gauge", STATUS_METRIC);
+
+    for service_check in service_checks {
+        let (Some(service), Some(host)) = (
+            services.get(&service_check.service_id),
+            hosts.get(&service_check.host_id),
+        ) else {
+            continue;
+        };
+        // while it's running (or about to) report the last result, so alerts don't flap
+        let status = match service_check.status {
+            ServiceStatus::Checking | ServiceStatus::Urgent => {
+                entities::service_check_history::Entity::find()
+                    .filter(
+                        entities::service_check_history::Column::ServiceCheckId
+                            .eq(service_check.id),
+                    )
+                    .order_by_desc(entities::service_check_history::Column::Timestamp)
+                    .one(db)
+                    .await?
+                    .map(|history| history.status)
+            }
+            status => Some(status),
+        };
+        let Some((status, value)) =
+            status.and_then(|status| status_value(status).map(|value| (status, value)))
+        else {
+            continue;
+        };
+        let labels = crate::labels::merge(&host.labels(), &service.labels())
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    ",{}=\"{}\"",
+                    crate::labels::metric_label_name(key),
+                    escape_label_value(value)
+                )
+            })
+            .collect::<String>();
+        let _ = writeln!(
+            res,
+            "{}{{host=\"{}\",service=\"{}\",status=\"{}\"{}}} {}",
+            STATUS_METRIC,
+            escape_label_value(&host.name),
+            escape_label_value(&service.name),
+            status,
+            labels,
+            value
+        );
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use sea_orm::prelude::Expr;
+
+    #[test]
+    fn test_alert_name() {
+        assert_eq!(alert_name("check_ntp_time"), "MaremmaCheckNtpTime");
+        assert_eq!(alert_name("HTTP - example.com"), "MaremmaHTTPExampleCom");
+        assert_eq!(escape_label_value("a\"b\\c"), "a\\\"b\\\\c");
+    }
+
+    #[tokio::test]
+    async fn test_alert_rules() {
+        let (db, config) = test_setup().await.expect("Failed to set up test");
+
+        let rules = alert_rules(&*config.read().await);
+        assert!(rules.groups.iter().all(|group| group.rules.is_empty()));
+
+        if let Some(service) = config.write().await.services.get_mut("ping_check") {
+            service.expose_alert_rule = true;
+            service.alert_rule_for = Some("5m".to_string());
+        }
+        let rules = alert_rules(&*config.read().await);
+        let group = rules.groups.first().expect("No rule groups");
+        assert_eq!(group.rules.len(), 2);
+        let critical = group.rules.last().expect("No critical rule");
+        assert_eq!(critical.alert, "MaremmaPingCheckCritical");
+        assert_eq!(
+            critical.expr,
+            "maremma_service_check_status{service=\"ping_check\"} >= 2"
+        );
+
+        let yaml = alert_rules_yaml(&*config.read().await).expect("Failed to render rules");
+        assert!(yaml.contains("for: 5m"));
+        assert!(yaml.contains("severity: warning"));
+
+        // make sure there's a result to report
+        entities::service_check::Entity::update_many()
+            .col_expr(
+                entities::service_check::Column::Status,
+                Expr::value(ServiceStatus::Critical),
+            )
+            .exec(&db)
+            .await
+            .expect("Failed to update service checks");
+        let metrics = status_metrics(&db, &*config.read().await)
+            .await
+            .expect("Failed to render metrics");
+        assert!(metrics.contains("# TYPE maremma_service_check_status gauge"));
+        assert!(metrics.contains("service=\"ping_check\",status=\"Critical\"} 2"));
+        assert!(!metrics.contains("check_tls"));
+
+        // labels ride along, the service's winning over the host's
+        entities::host::Entity::update_many()
+            .col_expr(
+                entities::host::Column::Labels,
+                Expr::value(json!({"env": "prod", "team": "ops"})),
+            )
+            .exec(&db)
+            .await
+            .expect("Failed to update hosts");
+        entities::service::Entity::update_many()
+            .col_expr(
+                entities::service::Column::Labels,
+                Expr::value(json!({"team": "payments"})),
+            )
+            .exec(&db)
+            .await
+            .expect("Failed to update services");
+        let metrics = status_metrics(&db, &*config.read().await)
+            .await
+            .expect("Failed to render metrics");
+        assert!(
+            metrics.contains("status=\"Critical\",label_env=\"prod\",label_team=\"payments\"} 2")
+        );
+    }
+}
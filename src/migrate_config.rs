@@ -0,0 +1,261 @@
+//! Upgrades config files written for older versions of Maremma, for `maremma migrate-config`
+//!
+//! Each change to the config file format that'd need hand edits gets a [Migration] and bumps
+//! [CURRENT_CONFIG_VERSION], so the steps between a file's `config_version` and the current one can be applied in order.
+
+use std::path::Path;
+
+use similar::TextDiff;
+
+use crate::config::write_validated_config;
+use crate::constants::CURRENT_CONFIG_VERSION;
+use crate::prelude::*;
+
+/// One step between config versions
+struct Migration {
+    /// The version the config's at after this step
+    version: u32,
+    /// Makes the changes, returning what it did
+    apply: fn(&mut Map<String, Value>) -> Vec<String>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 2,
+    apply: http_latency_names,
+}];
+
+/// The `config_version` in the file, files from before it existed are version 1
+pub fn config_version(config: &Value) -> Result<u32, Error> {
+    match config.get("config_version") {
+        None => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| {
+                Error::Configuration(format!("config_version {} isn't a number", version))
+            }),
+    }
+}
+
+/// The service's type, from its template if it doesn't set one
+fn service_type<'a>(service: &'a Value, templates: &'a Map<String, Value>) -> Option<&'a str> {
+    let mut service = service;
+    // templates using each other in a loop are caught when the config's loaded
+    for _ in 0..=templates.len() {
+        if let Some(service_type) = service.get("service_type").and_then(Value::as_str) {
+            return Some(service_type);
+        }
+        service = templates.get(service.get("template")?.as_str()?)?;
+    }
+    None
+}
+
+/// Version 2 uses the HTTP service's own names for its latency thresholds, rather than the ping service's names which
+/// it accepts as aliases
+fn http_latency_names(config: &mut Map<String, Value>) -> Vec<String> {
+    let templates = config
+        .get("service_templates")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    let mut changes = Vec::new();
+    for section in ["service_templates", "services"] {
+        let Some(services) = config.get_mut(section).and_then(Value::as_object_mut) else {
+            continue;
+        };
+        for (name, service) in services.iter_mut() {
+            if service_type(service, &templates) != Some("http") {
+                continue;
+            }
+            let Some(service) = service.as_object_mut() else {
+                continue;
+            };
+            for (old, new) in [
+                ("warn_latency_ms", "response_time_warn_ms"),
+                ("crit_latency_ms", "response_time_critical_ms"),
+            ] {
+                let Some(value) = service.remove(old) else {
+                    continue;
+                };
+                if service.contains_key(new) {
+                    // both won't load, and the real name's the one that's documented
+                    changes.push(format!(
+                        "{}.{}: removed {}, {} is already set",
+                        section, name, old, new
+                    ));
+                } else {
+                    service.insert(new.to_string(), value);
+                    changes.push(format!("{}.{}: renamed {} to {}", section, name, old, new));
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// Applies the migrations the config needs and sets its `config_version`, returning what changed
+pub fn migrate(config: &mut Value) -> Result<Vec<String>, Error> {
+    let from = config_version(config)?;
+    if from > CURRENT_CONFIG_VERSION {
+        return Err(Error::Configuration(format!(
+            "config_version {} is newer than this version of Maremma understands ({})",
+            from, CURRENT_CONFIG_VERSION
+        )));
+    }
+    let config = config
+        .as_object_mut()
+        .ok_or_else(|| Error::Configuration("Config file should be a JSON object".to_string()))?;
+
+    let mut changes = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > from)
+    {
+        changes.extend((migration.apply)(config));
+    }
+    if from < CURRENT_CONFIG_VERSION {
+        config.insert("config_version".to_string(), json!(CURRENT_CONFIG_VERSION));
+        changes.push(format!(
+            "config_version: set to {} (was {})",
+            CURRENT_CONFIG_VERSION, from
+        ));
+    }
+    Ok(changes)
+}
+
+/// What `maremma migrate-config` did
+#[derive(Debug, Default)]
+pub struct ConfigMigration {
+    /// What each migration changed
+    pub changes: Vec<String>,
+    /// A unified diff of the file
+    pub diff: String,
+}
+
+/// Migrates the config file, which is only written if it's not a dry run and the result loads
+pub async fn migrate_config_file(
+    config_file: &Path,
+    dry_run: bool,
+) -> Result<ConfigMigration, Error> {
+    let original = tokio::fs::read_to_string(config_file).await?;
+    let mut config: Value = serde_json::from_str(&original)?;
+    let changes = migrate(&mut config)?;
+    if changes.is_empty() {
+        return Ok(ConfigMigration::default());
+    }
+
+    let contents = serde_json::to_string_pretty(&config)?;
+    let name = config_file.display().to_string();
+    let diff = TextDiff::from_lines(&original, &contents)
+        .unified_diff()
+        .header(&name, &name)
+        .to_string();
+    if !dry_run {
+        write_validated_config(config_file, &contents).await?;
+    }
+    Ok(ConfigMigration { changes, diff })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let mut config = json!({
+            "hosts": {},
+            "service_templates": {
+                "web": {"service_type": "http", "crit_latency_ms": 2000}
+            },
+            "services": {
+                "homepage": {"template": "web", "warn_latency_ms": 500},
+                "pinger": {"service_type": "ping", "warn_latency_ms": 100},
+                "both": {
+                    "service_type": "http",
+                    "warn_latency_ms": 1,
+                    "response_time_warn_ms": 2
+                }
+            }
+        });
+        assert_eq!(config_version(&config).expect("Failed to get version"), 1);
+        let changes = migrate(&mut config).expect("Failed to migrate");
+        assert_eq!(changes.len(), 4);
+        assert_eq!(config["config_version"], json!(CURRENT_CONFIG_VERSION));
+        assert_eq!(
+            config["service_templates"]["web"]["response_time_critical_ms"],
+            json!(2000)
+        );
+        assert_eq!(
+            config["services"]["homepage"]["response_time_warn_ms"],
+            json!(500)
+        );
+        // ping keeps its names, and a file that sets both keeps the right one
+        assert_eq!(config["services"]["pinger"]["warn_latency_ms"], json!(100));
+        assert_eq!(
+            config["services"]["both"]["response_time_warn_ms"],
+            json!(2)
+        );
+        assert!(config["services"]["both"].get("warn_latency_ms").is_none());
+
+        // running it again doesn't do anything
+        assert!(migrate(&mut config).expect("Failed to migrate").is_empty());
+
+        config["config_version"] = json!(CURRENT_CONFIG_VERSION + 1);
+        assert!(migrate(&mut config).is_err());
+        config["config_version"] = json!("two");
+        assert!(migrate(&mut config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_config_file() {
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+        let original = serde_json::to_string_pretty(&json!({
+            "hosts": {},
+            "frontend_url": "https://maremma.example.com",
+            "oidc_issuer": "https://example.com",
+            "oidc_client_id": "maremma",
+        }))
+        .expect("Failed to serialize config");
+        tokio::fs::write(config_file.path(), &original)
+            .await
+            .expect("Failed to write config file");
+
+        let migration = migrate_config_file(config_file.path(), true)
+            .await
+            .expect("Failed to migrate");
+        assert!(migration.diff.contains(&format!(
+            "+  \"config_version\": {}",
+            CURRENT_CONFIG_VERSION
+        )));
+        assert_eq!(
+            tokio::fs::read_to_string(config_file.path())
+                .await
+                .expect("Failed to read config file"),
+            original
+        );
+
+        migrate_config_file(config_file.path(), false)
+            .await
+            .expect("Failed to migrate");
+        let migrated = Configuration::new(&config_file.path().to_path_buf())
+            .await
+            .expect("Migrated config doesn't load");
+        assert_eq!(migrated.config_version, Some(CURRENT_CONFIG_VERSION));
+        assert!(migrate_config_file(config_file.path(), false)
+            .await
+            .expect("Failed to migrate")
+            .changes
+            .is_empty());
+        // and one from a newer version doesn't load
+        let mut config: Value = serde_json::from_str(
+            &tokio::fs::read_to_string(config_file.path())
+                .await
+                .expect("Failed to read config file"),
+        )
+        .expect("Failed to parse config file");
+        config["config_version"] = json!(CURRENT_CONFIG_VERSION + 1);
+        assert!(Configuration::new_from_string(&config.to_string())
+            .await
+            .is_err());
+    }
+}
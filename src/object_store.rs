@@ -0,0 +1,436 @@
+//! A small client for S3-compatible object storage, eg AWS S3, MinIO or Garage
+//!
+//! Requests are signed with [AWS Signature Version 4](https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-authenticating-requests.html)
+//! and use path-style URLs (`<endpoint>/<bucket>/<key>`), which everything S3-compatible understands.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Url};
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+
+use crate::prelude::*;
+
+/// How long a request gets, uploads included
+const REQUEST_TIMEOUT_SECONDS: u64 = 300;
+
+/// The hash of an empty body, which GET and DELETE requests sign
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// An S3-compatible bucket, eg `{"endpoint": "https://minio.example.com", "bucket": "maremma", "access_key_id": "maremma", "secret_access_key": {"$secret": {"env": "S3_SECRET"}}}`
+pub struct S3Config {
+    /// eg `https://s3.ap-southeast-2.amazonaws.com` or `https://minio.example.com:9000`
+    pub endpoint: String,
+    /// The bucket, which has to exist already
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    /// Defaults to `us-east-1`, which is what most S3-compatible servers expect
+    pub region: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    /// Put in front of every key, eg `maremma/`
+    pub prefix: String,
+    /// The access key's ID
+    pub access_key_id: String,
+    #[serde(serialize_with = "crate::serde::secret::serialize_str")]
+    /// The access key's secret, can be a secret reference
+    pub secret_access_key: String,
+}
+
+impl S3Config {
+    /// Checks the endpoint's a URL and the bucket's set
+    pub fn validate(&self) -> Result<(), Error> {
+        let endpoint = Url::parse(&self.endpoint).map_err(|err| {
+            Error::Configuration(format!("Invalid S3 endpoint {}: {}", self.endpoint, err))
+        })?;
+        if !["http", "https"].contains(&endpoint.scheme()) {
+            return Err(Error::Configuration(format!(
+                "S3 endpoint {} needs to be http or https",
+                self.endpoint
+            )));
+        }
+        if self.bucket.is_empty() || self.bucket.contains('/') {
+            return Err(Error::Configuration(format!(
+                "Invalid S3 bucket name '{}'",
+                self.bucket
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Result<Vec<u8>, Error> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|err| Error::Configuration(format!("Invalid S3 secret: {:?}", err)))?;
+    mac.update(data.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Percent-encodes everything but the unreserved characters, and `/` if it's a path
+fn uri_encode(value: &str, path: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if path => "/".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The contents of each `<tag>` in the response, S3's XML is simple enough not to need a parser
+fn xml_values(body: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    body.split(&open)
+        .skip(1)
+        .filter_map(|rest| rest.split_once(&close))
+        .map(|(value, _)| xml_unescape(value))
+        .collect()
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// A request that's ready to be signed
+struct Request<'a> {
+    method: &'a Method,
+    /// Already encoded
+    path: &'a str,
+    /// Not encoded yet
+    query: &'a [(&'a str, &'a str)],
+    /// Lowercase names
+    headers: BTreeMap<String, String>,
+    payload_hash: &'a str,
+}
+
+impl Request<'_> {
+    fn canonical_query(&self) -> String {
+        let mut query = self
+            .query
+            .iter()
+            .map(|(key, value)| (uri_encode(key, false), uri_encode(value, false)))
+            .collect::<Vec<_>>();
+        query.sort();
+        query
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    fn signed_headers(&self) -> String {
+        self.headers
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// The signature for the request, from the credentials' secret, region and the time in `x-amz-date`
+    fn signature(&self, secret: &str, region: &str, now: DateTime<Utc>) -> Result<String, Error> {
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            self.method,
+            self.path,
+            self.canonical_query(),
+            self.headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+                .collect::<String>(),
+            self.signed_headers(),
+            self.payload_hash,
+        );
+        let date = now.format("%Y%m%d").to_string();
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}/{}/s3/aws4_request\n{}",
+            now.format("%Y%m%dT%H%M%SZ"),
+            date,
+            region,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key = hmac(format!("AWS4{}", secret).as_bytes(), &date)?;
+        let key = hmac(&key, region)?;
+        let key = hmac(&key, "s3")?;
+        let key = hmac(&key, "aws4_request")?;
+        Ok(hex(&hmac(&key, &string_to_sign)?))
+    }
+}
+
+/// Talks to the bucket in an [S3Config]
+pub struct ObjectStore {
+    config: S3Config,
+    client: reqwest::Client,
+}
+
+impl ObjectStore {
+    /// Builds the HTTP client, [S3Config::validate] should've been called already
+    pub fn new(config: S3Config) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+            .user_agent(format!("maremma/{}", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self { config, client })
+    }
+
+    /// The encoded path to the object with the prefix, or to the bucket if there's no key
+    fn path(&self, key: Option<&str>) -> Result<String, Error> {
+        let endpoint = Url::parse(&self.config.endpoint)
+            .map_err(|err| Error::Configuration(format!("Invalid S3 endpoint: {}", err)))?;
+        let bucket = format!(
+            "{}/{}",
+            endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.config.bucket, false)
+        );
+        Ok(match key {
+            Some(key) => format!(
+                "{}/{}",
+                bucket,
+                uri_encode(&format!("{}{}", self.config.prefix, key), true)
+            ),
+            None => bucket,
+        })
+    }
+
+    /// Signs and sends the request, returning the response if it was successful
+    async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, Error> {
+        let mut url = Url::parse(&self.config.endpoint)
+            .map_err(|err| Error::Configuration(format!("Invalid S3 endpoint: {}", err)))?;
+        let path = self.path(key)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => {
+                return Err(Error::Configuration(
+                    "S3 endpoint doesn't have a host".to_string(),
+                ))
+            }
+        };
+        let now = Utc::now();
+        let payload_hash = if body.is_empty() {
+            EMPTY_PAYLOAD_HASH.to_string()
+        } else {
+            sha256_hex(&body)
+        };
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let request = Request {
+            method: &method,
+            path: &path,
+            query,
+            headers: BTreeMap::from([
+                ("host".to_string(), host),
+                ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+                ("x-amz-date".to_string(), amz_date.clone()),
+            ]),
+            payload_hash: &payload_hash,
+        };
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}/{}/s3/aws4_request, SignedHeaders={}, Signature={}",
+            self.config.access_key_id,
+            now.format("%Y%m%d"),
+            self.config.region,
+            request.signed_headers(),
+            request.signature(&self.config.secret_access_key, &self.config.region, now)?
+        );
+        url.set_path(&path);
+        if query.is_empty() {
+            url.set_query(None);
+        } else {
+            url.set_query(Some(&request.canonical_query()));
+        }
+
+        let response = self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::Reqwest(format!(
+                "S3 request for {} failed with {}: {}",
+                key.unwrap_or(&self.config.bucket),
+                status,
+                body
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Uploads the object, replacing it if it's already there
+    pub async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        self.send(Method::PUT, Some(key), &[], body).await?;
+        Ok(())
+    }
+
+    /// Downloads the object
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .send(Method::GET, Some(key), &[], vec![])
+            .await?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+
+    /// Deletes the object, it's not an error if it's not there
+    pub async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, Some(key), &[], vec![]).await?;
+        Ok(())
+    }
+
+    /// The keys starting with `prefix`, without the config's prefix
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let full_prefix = format!("{}{}", self.config.prefix, prefix);
+        let mut keys = Vec::new();
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+            if let Some(token) = continuation.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            let body = self
+                .send(Method::GET, None, &query, vec![])
+                .await?
+                .text()
+                .await?;
+            keys.extend(
+                xml_values(&body, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&self.config.prefix).map(str::to_string)),
+            );
+            continuation = xml_values(&body, "NextContinuationToken")
+                .into_iter()
+                .next();
+            if continuation.is_none() {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_signature() {
+        // the "GET Object" example from the AWS Signature Version 4 documentation
+        let now = Utc
+            .with_ymd_and_hms(2013, 5, 24, 0, 0, 0)
+            .single()
+            .expect("Failed to build the time");
+        let request = Request {
+            method: &Method::GET,
+            path: "/test.txt",
+            query: &[],
+            headers: BTreeMap::from([
+                (
+                    "host".to_string(),
+                    "examplebucket.s3.amazonaws.com".to_string(),
+                ),
+                ("range".to_string(), "bytes=0-9".to_string()),
+                (
+                    "x-amz-content-sha256".to_string(),
+                    EMPTY_PAYLOAD_HASH.to_string(),
+                ),
+                ("x-amz-date".to_string(), "20130524T000000Z".to_string()),
+            ]),
+            payload_hash: EMPTY_PAYLOAD_HASH,
+        };
+        assert_eq!(
+            request
+                .signature("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY", "us-east-1", now)
+                .expect("Failed to sign"),
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+        assert_eq!(sha256_hex(b""), EMPTY_PAYLOAD_HASH);
+    }
+
+    #[test]
+    fn test_object_store_paths() {
+        let config = S3Config {
+            endpoint: "https://minio.example.com:9000".to_string(),
+            bucket: "maremma".to_string(),
+            region: default_region(),
+            prefix: "backups/".to_string(),
+            access_key_id: "maremma".to_string(),
+            secret_access_key: "secret".to_string(),
+        };
+        assert!(config.validate().is_ok());
+        let store = ObjectStore::new(config.clone()).expect("Failed to build store");
+        assert_eq!(
+            store
+                .path(Some("db 1.sqlite3"))
+                .expect("Failed to build path"),
+            "/maremma/backups/db%201.sqlite3"
+        );
+        assert_eq!(store.path(None).expect("Failed to build path"), "/maremma");
+
+        let request = Request {
+            method: &Method::GET,
+            path: "/maremma",
+            query: &[("prefix", "backups/maremma-"), ("list-type", "2")],
+            headers: BTreeMap::new(),
+            payload_hash: EMPTY_PAYLOAD_HASH,
+        };
+        assert_eq!(
+            request.canonical_query(),
+            "list-type=2&prefix=backups%2Fmaremma-"
+        );
+
+        assert!(S3Config {
+            endpoint: "ftp://minio.example.com".to_string(),
+            ..config.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(S3Config {
+            bucket: "".to_string(),
+            ..config
+        }
+        .validate()
+        .is_err());
+        assert_eq!(
+            xml_values(
+                "<ListBucketResult><Contents><Key>a&amp;b</Key></Contents><Contents><Key>c</Key></Contents></ListBucketResult>",
+                "Key"
+            ),
+            vec!["a&b".to_string(), "c".to_string()]
+        );
+    }
+}
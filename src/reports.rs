@@ -0,0 +1,365 @@
+//! Scheduled summary reports, eg a weekly round-up of new incidents, what's failing right now and
+//! each host group's availability, sent to notification targets.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use sea_orm::QuerySelect;
+
+use crate::actions::routing::NotificationTarget;
+use crate::db::entities::incident::{duration_text, FullIncident, IncidentFilter};
+use crate::db::entities::service_check_rollup::{RollupPeriod, RollupSummary};
+use crate::db::entities::{
+    host_group, host_group_members, host_group_nesting, incident, name_key, service_check,
+    service_check_rollup,
+};
+use crate::prelude::*;
+
+/// How many incidents each section lists, the rest are counted
+const MAX_LISTED_INCIDENTS: usize = 10;
+
+/// The most incidents a report looks at
+const MAX_REPORT_INCIDENTS: u64 = 1000;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+/// How far back a report looks
+pub enum ReportPeriod {
+    /// The last 24 hours
+    #[default]
+    Day,
+    /// The last 7 days
+    Week,
+}
+
+impl ReportPeriod {
+    /// How long the period is
+    pub fn duration(&self) -> TimeDelta {
+        match self {
+            ReportPeriod::Day => TimeDelta::days(1),
+            ReportPeriod::Week => TimeDelta::weeks(1),
+        }
+    }
+
+    /// What the report's called, eg `weekly`
+    pub fn adjective(&self) -> &'static str {
+        match self {
+            ReportPeriod::Day => "daily",
+            ReportPeriod::Week => "weekly",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+/// A summary report, eg `{"schedule": "0 8 * * 1", "period": "week", "targets": ["ops"]}`
+pub struct ReportConfig {
+    /// When to send it, a cron schedule in UTC
+    pub schedule: String,
+    #[serde(default)]
+    /// How far back it looks
+    pub period: ReportPeriod,
+    /// Names of the notification targets it's sent to
+    pub targets: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Only report on these host groups, all of them if it's empty
+    pub host_groups: Vec<String>,
+}
+
+impl ReportConfig {
+    /// The parsed schedule
+    pub fn cron(&self) -> Result<Cron, Error> {
+        Ok(Cron::new(&self.schedule).parse()?)
+    }
+}
+
+/// Makes sure each report's schedule parses and it only sends to targets which exist
+pub(crate) fn check_reports(config: &Configuration) -> Result<(), Error> {
+    for (name, report) in config.reports.iter() {
+        report.cron().map_err(|err| {
+            Error::Configuration(format!(
+                "Report '{}' has an invalid schedule: {:?}",
+                name, err
+            ))
+        })?;
+        if report.targets.is_empty() {
+            return Err(Error::Configuration(format!(
+                "Report '{}' has no targets",
+                name
+            )));
+        }
+        if let Some(target) = report
+            .targets
+            .iter()
+            .find(|target| !config.notifications.targets.contains_key(*target))
+        {
+            return Err(Error::Configuration(format!(
+                "Report '{}' uses unknown target '{}'",
+                name, target
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+/// A report's contents, see [Report::build]
+pub struct Report {
+    /// The report's name from the config
+    pub name: String,
+    /// How far back it looks
+    pub period: ReportPeriod,
+    /// The start of the time it covers
+    pub since: DateTime<Utc>,
+    /// The end of the time it covers
+    pub until: DateTime<Utc>,
+    /// Incidents which started in the period, newest first
+    pub new_incidents: Vec<FullIncident>,
+    /// Incidents which are still open, newest first
+    pub failing: Vec<FullIncident>,
+    /// The availability of each host group over the period, by name
+    pub groups: Vec<(String, RollupSummary)>,
+}
+
+impl Report {
+    /// Works out the report for the period up to `until`
+    pub async fn build(
+        db: &DatabaseConnection,
+        name: &str,
+        config: &ReportConfig,
+        until: DateTime<Utc>,
+    ) -> Result<Self, Error> {
+        let since = until - config.period.duration();
+        let wanted = config
+            .host_groups
+            .iter()
+            .map(|group| name_key(group))
+            .collect::<BTreeSet<_>>();
+        let mut groups = host_group::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .filter(|group| wanted.is_empty() || wanted.contains(&name_key(&group.name)))
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut host_ids = BTreeSet::new();
+        let mut availability = Vec::new();
+        for group in groups {
+            // nested groups count towards the ones they're in
+            let group_ids = host_group_nesting::Entity::expand(db, &[group.id]).await?;
+            let group_host_ids = host_group_members::Entity::find()
+                .filter(host_group_members::Column::GroupId.is_in(group_ids))
+                .all(db)
+                .await?
+                .into_iter()
+                .map(|member| member.host_id)
+                .collect::<Vec<_>>();
+            let service_check_ids: Vec<Uuid> = service_check::Entity::find()
+                .select_only()
+                .column(service_check::Column::Id)
+                .filter(service_check::Column::HostId.is_in(group_host_ids.iter().copied()))
+                .into_tuple()
+                .all(db)
+                .await?;
+            let summary = service_check_rollup::Entity::summary_for_checks(
+                db,
+                &service_check_ids,
+                RollupPeriod::Hour,
+                since,
+            )
+            .await?;
+            host_ids.extend(group_host_ids);
+            availability.push((group.name, summary));
+        }
+
+        let in_report =
+            |incident: &FullIncident| wanted.is_empty() || host_ids.contains(&incident.host_id);
+        let new_incidents = incident::Entity::search(
+            db,
+            &IncidentFilter {
+                since: Some(since),
+                limit: MAX_REPORT_INCIDENTS,
+                ..Default::default()
+            },
+        )
+        .await?
+        .into_iter()
+        .filter(|incident| incident.started_at <= until && in_report(incident))
+        .collect();
+        let failing = incident::Entity::search(
+            db,
+            &IncidentFilter {
+                open: true,
+                limit: MAX_REPORT_INCIDENTS,
+                ..Default::default()
+            },
+        )
+        .await?
+        .into_iter()
+        .filter(in_report)
+        .collect();
+
+        Ok(Self {
+            name: name.to_string(),
+            period: config.period,
+            since,
+            until,
+            new_incidents,
+            failing,
+            groups: availability,
+        })
+    }
+
+    /// The worst status of anything that's still failing, so targets which filter on status still get it
+    pub fn status(&self) -> ServiceStatus {
+        self.failing
+            .iter()
+            .map(|incident| incident.last_status)
+            .max()
+            .unwrap_or(ServiceStatus::Ok)
+    }
+
+    /// The report as plain text, for sending as a message
+    pub fn text(&self) -> String {
+        let mut res = format!(
+            "Maremma {} report '{}' for {} to {}\n",
+            self.period.adjective(),
+            self.name,
+            self.since.format("%Y-%m-%d %H:%M UTC"),
+            self.until.format("%Y-%m-%d %H:%M UTC"),
+        );
+        for (title, incidents) in [
+            ("New incidents", &self.new_incidents),
+            ("Currently failing", &self.failing),
+        ] {
+            let _ = writeln!(res, "\n{}: {}", title, incidents.len());
+            for incident in incidents.iter().take(MAX_LISTED_INCIDENTS) {
+                let _ = writeln!(
+                    res,
+                    "- {} / {}: {} for {}",
+                    incident.host_display_name(),
+                    incident.service_name,
+                    incident.worst_status,
+                    duration_text(incident.duration(self.until))
+                );
+            }
+            if incidents.len() > MAX_LISTED_INCIDENTS {
+                let _ = writeln!(res, "- and {} more", incidents.len() - MAX_LISTED_INCIDENTS);
+            }
+        }
+        if !self.groups.is_empty() {
+            let _ = writeln!(res, "\nAvailability:");
+            for (name, summary) in self.groups.iter() {
+                let _ = writeln!(
+                    res,
+                    "- {}: {} ({} checks)",
+                    name,
+                    summary.availability_text(),
+                    summary.checks
+                );
+            }
+        }
+        res
+    }
+
+    /// Sends the report to the targets, returning how many it was sent to
+    pub async fn send(&self, targets: &[&NotificationTarget]) -> usize {
+        let message = CheckResult {
+            timestamp: self.until,
+            time_elapsed: TimeDelta::zero(),
+            status: self.status(),
+            result_text: self.text(),
+            ..Default::default()
+        };
+        let mut sent = 0;
+        for target in targets {
+            match target.action().execute(&message).await {
+                Ok(()) => sent += 1,
+                Err(err) => error!("Failed to send report '{}': {:?}", self.name, err),
+            }
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    fn report_config() -> ReportConfig {
+        ReportConfig {
+            schedule: "0 8 * * 1".to_string(),
+            period: ReportPeriod::Week,
+            targets: vec!["ops".to_string()],
+            host_groups: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_reports() {
+        let mut config = Configuration::load_test_config_bare().await;
+        config.reports.insert("weekly".to_string(), report_config());
+        assert!(check_reports(&config).is_err());
+
+        config.notifications.targets.insert(
+            "ops".to_string(),
+            NotificationTarget::Pushover {
+                token: "token".to_string(),
+                user: "user".to_string(),
+                device: None,
+                title: None,
+                rate_limit: None,
+            },
+        );
+        assert!(check_reports(&config).is_ok());
+
+        if let Some(report) = config.reports.get_mut("weekly") {
+            report.schedule = "not a schedule".to_string();
+        }
+        assert!(check_reports(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_report() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let check = service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let now = Utc::now();
+        incident::Entity::record(
+            &db,
+            check.id,
+            ServiceStatus::Critical,
+            now - TimeDelta::hours(2),
+        )
+        .await
+        .expect("Failed to record result");
+
+        let report = Report::build(&db, "weekly", &report_config(), now)
+            .await
+            .expect("Failed to build report");
+        assert_eq!(report.new_incidents.len(), 1);
+        assert_eq!(report.failing.len(), 1);
+        assert_eq!(report.status(), ServiceStatus::Critical);
+        assert!(!report.groups.is_empty());
+        let text = report.text();
+        assert!(text.starts_with("Maremma weekly report 'weekly'"));
+        assert!(text.contains("Currently failing: 1"));
+        assert!(text.contains("Availability:"));
+
+        // a report on a group the check's host isn't in leaves it out
+        let config = ReportConfig {
+            host_groups: vec!["not_a_group".to_string()],
+            ..report_config()
+        };
+        let report = Report::build(&db, "weekly", &config, now)
+            .await
+            .expect("Failed to build report");
+        assert!(report.failing.is_empty());
+        assert!(report.groups.is_empty());
+        assert_eq!(report.status(), ServiceStatus::Ok);
+    }
+}
@@ -0,0 +1,352 @@
+//! Writes check results to the database in batches, so lots of checks finishing at once don't fight over the write lock
+
+use std::sync::LazyLock;
+
+use sea_orm::{ConnectionTrait, TransactionTrait};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::actions::dispatcher::DISPATCHER;
+use crate::check_loop::CheckEnvironment;
+use crate::prelude::*;
+
+/// The most results written in one transaction
+const MAX_BATCH_SIZE: usize = 100;
+/// How many results can be waiting before the checks have to wait for the writer
+const QUEUE_SIZE: usize = 1000;
+/// How many updates a slow subscriber can fall behind by before it starts missing them
+const UPDATES_SIZE: usize = 256;
+
+/// The writer that all check results go through, see [ResultWriter::start]
+pub static RESULT_WRITER: LazyLock<ResultWriter> = LazyLock::new(ResultWriter::default);
+
+/// Every result once it's been written, for the pages watching a check, see [CheckUpdate]
+pub static CHECK_UPDATES: LazyLock<broadcast::Sender<CheckUpdate>> =
+    LazyLock::new(|| broadcast::channel(UPDATES_SIZE).0);
+
+/// What's sent to [CHECK_UPDATES] when a check's result has been stored
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CheckUpdate {
+    /// The service check the result is for
+    pub service_check_id: Uuid,
+    /// The check's new status
+    pub status: ServiceStatus,
+    /// What the check said
+    pub result_text: String,
+    /// When the check ran
+    pub timestamp: DateTime<Utc>,
+    /// How the page shows the status, so the page doesn't need its own copy
+    pub label: String,
+    /// Bootstrap background class for the status badge
+    pub background: &'static str,
+    /// Bootstrap text class for the status badge
+    pub text: &'static str,
+}
+
+impl CheckUpdate {
+    /// Fills in the status label and classes
+    pub fn new(
+        service_check_id: Uuid,
+        status: ServiceStatus,
+        result_text: String,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            service_check_id,
+            status,
+            result_text,
+            timestamp,
+            label: status.to_string(),
+            background: status.as_html_class_background(),
+            text: status.as_html_class_text(),
+        }
+    }
+}
+
+impl From<entities::service_check_history::Model> for CheckUpdate {
+    fn from(history: entities::service_check_history::Model) -> Self {
+        Self::new(
+            history.service_check_id,
+            history.status,
+            history.result_text,
+            history.timestamp,
+        )
+    }
+}
+
+/// A check result waiting to be written
+#[derive(Debug, Clone)]
+pub(crate) struct PendingResult {
+    pub service_check: entities::service_check::Model,
+    pub service: entities::service::Model,
+    pub result: CheckResult,
+    pub environment: CheckEnvironment,
+    pub jitter: u32,
+    /// Where the rest of the long output went, if it was too big to keep in the history
+    pub artifact: Option<String>,
+}
+
+type Queued = (PendingResult, oneshot::Sender<Result<(), Error>>);
+
+#[derive(Debug, Default)]
+/// Queues check results for a single task to write, until it's started results are written straight away
+pub struct ResultWriter {
+    tx: std::sync::Mutex<Option<mpsc::Sender<Queued>>>,
+}
+
+impl ResultWriter {
+    /// Starts the task which writes the queued results
+    pub fn start(&self, db: DatabaseConnection) -> JoinHandle<()> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        if let Ok(mut writer_tx) = self.tx.lock() {
+            *writer_tx = Some(tx);
+        }
+        tokio::spawn(run_writer(db, rx))
+    }
+
+    /// Stops queueing results, the task finishes once everything that's queued has been written
+    pub fn stop(&self) {
+        if let Ok(mut writer_tx) = self.tx.lock() {
+            writer_tx.take();
+        }
+    }
+
+    /// Writes the result, returning once it's in the database
+    pub(crate) async fn write(
+        &self,
+        db: DatabaseConnection,
+        pending: PendingResult,
+    ) -> Result<(), Error> {
+        let tx = self.tx.lock().ok().and_then(|tx| tx.clone());
+        let pending = match tx {
+            Some(tx) => {
+                let (done_tx, done_rx) = oneshot::channel();
+                match tx.send((pending, done_tx)).await {
+                    Ok(()) => {
+                        return done_rx.await.unwrap_or_else(|_| {
+                            Err(Error::Generic(
+                                "Result writer stopped before writing the result".to_string(),
+                            ))
+                        })
+                    }
+                    // the writer's gone, so do it ourselves
+                    Err(err) => err.0 .0,
+                }
+            }
+            None => pending,
+        };
+        let host = store_result(&db, &pending).await?;
+        notify(pending, &host);
+        Ok(())
+    }
+}
+
+/// Stores the result in the history table and schedules the next run, returning the check's host
+async fn store_result<C: ConnectionTrait>(
+    db: &C,
+    pending: &PendingResult,
+) -> Result<entities::host::Model, Error> {
+    entities::service_check_history::Model {
+        artifact: pending.artifact.clone(),
+        ..entities::service_check_history::Model::from_service_check_result(
+            pending.service_check.id,
+            &pending.result,
+            &pending.environment,
+        )
+    }
+    .into_active_model()
+    .insert(db)
+    .await?;
+    entities::incident::Entity::record(
+        db,
+        pending.service_check.id,
+        pending.result.status,
+        pending.result.timestamp,
+    )
+    .await?;
+
+    entities::service_check::set_check_result(
+        pending.service_check.clone(),
+        &pending.service,
+        chrono::Utc::now(),
+        pending.result.status,
+        db,
+        pending.jitter,
+    )
+    .await?;
+
+    entities::host::Entity::find_by_id(pending.service_check.host_id)
+        .one(db)
+        .await?
+        .ok_or(Error::HostNotFound(pending.service_check.host_id))
+}
+
+/// Sends the notifications for a stored result, in the background because they can be slow
+fn notify(pending: PendingResult, host: &entities::host::Model) {
+    // it's fine if nobody's listening
+    let _ = CHECK_UPDATES.send(CheckUpdate::new(
+        pending.service_check.id,
+        pending.result.status,
+        pending.result.result_text.clone(),
+        pending.result.timestamp,
+    ));
+    let host_name = host.name.clone();
+    let labels = crate::labels::merge(&host.labels(), &pending.service.labels());
+    tokio::spawn(async move {
+        DISPATCHER
+            .lock()
+            .await
+            .dispatch_check(
+                pending.service_check.id,
+                &host_name,
+                &pending.service.name,
+                &labels,
+                &pending.result,
+            )
+            .await
+    });
+}
+
+/// Writes the batch in one transaction, returning the hosts in the same order
+async fn store_batch(
+    db: &DatabaseConnection,
+    batch: &[PendingResult],
+) -> Result<Vec<entities::host::Model>, Error> {
+    let txn = db.begin().await?;
+    let mut hosts = Vec::with_capacity(batch.len());
+    for pending in batch {
+        hosts.push(store_result(&txn, pending).await?);
+    }
+    txn.commit().await?;
+    Ok(hosts)
+}
+
+/// Takes whatever's queued, up to [MAX_BATCH_SIZE] at a time, and writes it
+async fn run_writer(db: DatabaseConnection, mut rx: mpsc::Receiver<Queued>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+    while rx.recv_many(&mut batch, MAX_BATCH_SIZE).await > 0 {
+        let (results, replies): (Vec<PendingResult>, Vec<_>) = batch.drain(..).unzip();
+        debug!("Writing {} check results", results.len());
+
+        let stored = match store_batch(&db, &results).await {
+            Ok(hosts) => hosts.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(err) => {
+                // one bad result shouldn't lose the rest, so try them one at a time
+                warn!(
+                    "Failed to write {} check results together, writing them one by one: {:?}",
+                    results.len(),
+                    err
+                );
+                let mut stored = Vec::with_capacity(results.len());
+                for pending in results.iter() {
+                    stored.push(store_result(&db, pending).await);
+                }
+                stored
+            }
+        };
+
+        for ((pending, reply), stored) in results.into_iter().zip(replies).zip(stored) {
+            let res = stored.map(|host| notify(pending, &host));
+            // the check might not be waiting any more, which is fine
+            let _ = reply.send(res);
+        }
+    }
+    debug!("Result writer stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_result_writer() {
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+        let checks = entities::service_check::Entity::find()
+            .find_also_related(entities::service::Entity)
+            .all(&db)
+            .await
+            .expect("Failed to get service checks");
+        assert!(checks.len() > 1);
+
+        let writer = ResultWriter::default();
+        let handle = writer.start(db.clone());
+        let writes = checks.iter().map(|(service_check, service)| {
+            writer.write(
+                db.clone(),
+                PendingResult {
+                    service_check: service_check.clone(),
+                    service: service.clone().expect("Service missing"),
+                    result: CheckResult {
+                        timestamp: chrono::Utc::now(),
+                        time_elapsed: chrono::Duration::zero(),
+                        status: ServiceStatus::Warning,
+                        result_text: "batched".to_string(),
+                        ..Default::default()
+                    },
+                    environment: CheckEnvironment::local(None),
+                    jitter: 0,
+                    artifact: None,
+                },
+            )
+        });
+        for res in futures::future::join_all(writes).await {
+            res.expect("Failed to write result");
+        }
+        writer.stop();
+        handle.await.expect("Writer task failed");
+
+        let history = entities::service_check_history::Entity::find()
+            .filter(entities::service_check_history::Column::ResultText.eq("batched"))
+            .all(&db)
+            .await
+            .expect("Failed to get history");
+        assert_eq!(history.len(), checks.len());
+        assert!(entities::service_check::Entity::find()
+            .all(&db)
+            .await
+            .expect("Failed to get service checks")
+            .iter()
+            .all(|service_check| service_check.status == ServiceStatus::Warning));
+
+        // once it's stopped, results are written directly
+        let (service_check, service) = checks.first().expect("No checks").clone();
+        let service_check_id = service_check.id;
+        let mut updates = CHECK_UPDATES.subscribe();
+        writer
+            .write(
+                db.clone(),
+                PendingResult {
+                    service_check,
+                    service: service.expect("Service missing"),
+                    result: CheckResult {
+                        timestamp: chrono::Utc::now(),
+                        time_elapsed: chrono::Duration::zero(),
+                        status: ServiceStatus::Ok,
+                        result_text: "direct".to_string(),
+                        ..Default::default()
+                    },
+                    environment: CheckEnvironment::local(None),
+                    jitter: 0,
+                    artifact: None,
+                },
+            )
+            .await
+            .expect("Failed to write result directly");
+
+        // and the pages watching the check hear about it, other tests share the channel so skip theirs
+        loop {
+            let update = match updates.recv().await {
+                Ok(update) => update,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(err) => panic!("Failed to get update: {:?}", err),
+            };
+            if update.service_check_id == service_check_id && update.result_text == "direct" {
+                assert_eq!(update.status, ServiceStatus::Ok);
+                assert_eq!(update.background, "success");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Why and when a service check's going to run next
+//!
+//! The schedule only lives in the `next_check` column, so each time a check's rescheduled the
+//! decision's kept in memory along with the jitter it got, and [ScheduleInsight] puts that together
+//! with the parsed cron schedule.
+
+use std::collections::VecDeque;
+use std::sync::LazyLock;
+
+use crate::constants::SCHEDULE_DECISIONS_KEPT;
+use crate::db::entities::incident::duration_text;
+use crate::db::entities::service_check::{
+    cron_interval, next_check_time, phase_offset, schedule_timezone,
+};
+use crate::prelude::*;
+
+/// The recent scheduling decisions for each service check, newest first
+static DECISIONS: LazyLock<std::sync::RwLock<HashMap<Uuid, VecDeque<ScheduleDecision>>>> =
+    LazyLock::new(Default::default);
+
+/// How many upcoming runs to show
+const UPCOMING_RUNS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// A time a check's next run was worked out
+pub struct ScheduleDecision {
+    /// When it was worked out
+    pub decided_at: DateTime<Utc>,
+    /// What caused it, eg `After a Critical result`
+    pub reason: String,
+    /// When the check was set to run next
+    pub next_check: DateTime<Utc>,
+    /// How many seconds of jitter were added
+    pub jitter: i64,
+    /// The most jitter the service allows, in seconds
+    pub max_jitter: u32,
+}
+
+/// Remembers a scheduling decision for the check, dropping the oldest once there's more than [SCHEDULE_DECISIONS_KEPT]
+pub(crate) fn record(service_check_id: Uuid, decision: ScheduleDecision) {
+    let mut decisions = match DECISIONS.write() {
+        Ok(decisions) => decisions,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let entry = decisions.entry(service_check_id).or_default();
+    entry.push_front(decision);
+    entry.truncate(SCHEDULE_DECISIONS_KEPT);
+}
+
+/// The check's recent scheduling decisions, newest first
+pub(crate) fn recent(service_check_id: Uuid) -> Vec<ScheduleDecision> {
+    let decisions = match DECISIONS.read() {
+        Ok(decisions) => decisions,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    decisions
+        .get(&service_check_id)
+        .map(|entry| entry.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Forgets a check's decisions, for when it's deleted
+pub(crate) fn forget(service_check_id: Uuid) {
+    if let Ok(mut decisions) = DECISIONS.write() {
+        decisions.remove(&service_check_id);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// Everything that goes into when a service check runs next
+pub struct ScheduleInsight {
+    /// The service check
+    pub service_check_id: Uuid,
+    /// The service's cron schedule as configured
+    pub cron_schedule: String,
+    /// The timezone the schedule's evaluated in
+    pub timezone: String,
+    /// Seconds between runs of the schedule
+    pub interval_seconds: i64,
+    /// How far this check's shifted into the schedule's interval, so checks on the same schedule don't all run at once
+    pub phase_offset_seconds: i64,
+    /// The most jitter the service adds after each run, in seconds
+    pub max_jitter: u32,
+    /// The check's current status
+    pub status: ServiceStatus,
+    /// When the check last ran
+    pub last_check: DateTime<Utc>,
+    /// When the check's due to run, from the database
+    pub next_check: DateTime<Utc>,
+    /// Set while the check's paused with an auto-resume time
+    pub paused_until: Option<DateTime<Utc>>,
+    /// The schedule's next few runs after `next_check`, without jitter
+    pub upcoming: Vec<DateTime<Utc>>,
+    /// Why the check will, or won't, run when it's due
+    pub explanation: String,
+    /// The last few times the check was rescheduled, newest first
+    pub decisions: Vec<ScheduleDecision>,
+}
+
+impl ScheduleInsight {
+    /// Works out the schedule for a service check
+    pub async fn for_service_check(
+        db: &DatabaseConnection,
+        service_check_id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<Self, Error> {
+        let check = entities::service_check::Entity::find_by_id(service_check_id)
+            .one(db)
+            .await?
+            .ok_or(Error::ServiceCheckNotFound(service_check_id))?;
+        let service = entities::service::Entity::find_by_id(check.service_id)
+            .one(db)
+            .await?
+            .ok_or(Error::ServiceNotFound(check.service_id))?;
+        let host = entities::host::Entity::find_by_id(check.host_id)
+            .one(db)
+            .await?
+            .ok_or(Error::HostNotFound(check.host_id))?;
+
+        let cron = Cron::new(&service.cron_schedule).parse()?;
+        let timezone = schedule_timezone(&service, Some(&host));
+
+        let max_jitter = Service::try_from_service_model(&service, db)
+            .await?
+            .parse_config()?
+            .config()
+            .map(|config| config.jitter_value())
+            .unwrap_or(0);
+
+        let mut upcoming = Vec::with_capacity(UPCOMING_RUNS);
+        let mut from = check.next_check.max(now);
+        for _ in 0..UPCOMING_RUNS {
+            from = next_check_time(&cron, timezone, service.id, host.id, from)?;
+            upcoming.push(from);
+        }
+
+        Ok(Self {
+            service_check_id,
+            cron_schedule: service.cron_schedule.clone(),
+            timezone: timezone.to_string(),
+            interval_seconds: cron_interval(&cron)?,
+            phase_offset_seconds: phase_offset(&cron, service.id, host.id)?.num_seconds(),
+            max_jitter,
+            status: check.status,
+            last_check: check.last_check,
+            next_check: check.next_check,
+            paused_until: check.paused_until,
+            upcoming,
+            explanation: explain(&check, &service, now),
+            decisions: recent(service_check_id),
+        })
+    }
+}
+
+/// Says why the check will or won't run, following the order the check loop picks them in
+fn explain(
+    check: &entities::service_check::Model,
+    service: &entities::service::Model,
+    now: DateTime<Utc>,
+) -> String {
+    if matches!(
+        service.service_type,
+        ServiceType::Passive | ServiceType::Heartbeat
+    ) {
+        return format!(
+            "{} results are submitted to Maremma, so the check loop doesn't run it",
+            service.service_type
+        );
+    }
+    match check.status {
+        ServiceStatus::Disabled => {
+            return match check.paused_until {
+                Some(paused_until) => format!(
+                    "Paused, it goes back to pending in {}",
+                    duration_text(paused_until - now)
+                ),
+                None => "Disabled, it won't run until it's enabled".to_string(),
+            }
+        }
+        ServiceStatus::Checking => return "Running now".to_string(),
+        ServiceStatus::Urgent => {
+            return "Marked urgent, it runs as soon as there's a free slot".to_string()
+        }
+        _ => {}
+    }
+    let runner = match &service.agent {
+        Some(agent) => format!("the agent {} polls for it", agent),
+        None => "there's a free slot in the check loop".to_string(),
+    };
+    if check.next_check <= now {
+        format!(
+            "Due {} ago, it runs when {}",
+            duration_text(now - check.next_check),
+            runner
+        )
+    } else {
+        format!(
+            "Due in {}, then it runs when {}",
+            duration_text(check.next_check - now),
+            runner
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decision(jitter: i64) -> ScheduleDecision {
+        ScheduleDecision {
+            decided_at: Utc::now(),
+            reason: "test".to_string(),
+            next_check: Utc::now(),
+            jitter,
+            max_jitter: 10,
+        }
+    }
+
+    #[test]
+    fn test_record_keeps_the_newest() {
+        let check_id = Uuid::new_v4();
+        assert!(recent(check_id).is_empty());
+        for jitter in 0..(SCHEDULE_DECISIONS_KEPT as i64 + 2) {
+            record(check_id, decision(jitter));
+        }
+        let decisions = recent(check_id);
+        assert_eq!(decisions.len(), SCHEDULE_DECISIONS_KEPT);
+        assert_eq!(
+            decisions.first().map(|decision| decision.jitter),
+            Some(SCHEDULE_DECISIONS_KEPT as i64 + 1)
+        );
+        forget(check_id);
+        assert!(recent(check_id).is_empty());
+    }
+
+    #[test]
+    fn test_explain() {
+        let now = Utc::now();
+        let service = entities::service::Model {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            slug: "test".to_string(),
+            description: None,
+            service_type: ServiceType::Ping,
+            cron_schedule: "* * * * *".to_string(),
+            extra_config: json!({}),
+            agent: None,
+            labels: json!({}),
+        };
+        let check = entities::service_check::Model {
+            status: ServiceStatus::Ok,
+            next_check: now + TimeDelta::minutes(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            explain(&check, &service, now),
+            "Due in 5m, then it runs when there's a free slot in the check loop"
+        );
+
+        let paused = entities::service_check::Model {
+            status: ServiceStatus::Disabled,
+            paused_until: Some(now + TimeDelta::hours(2)),
+            ..check.clone()
+        };
+        assert_eq!(
+            explain(&paused, &service, now),
+            "Paused, it goes back to pending in 2h 0m"
+        );
+
+        let agent_service = entities::service::Model {
+            agent: Some("edge".to_string()),
+            ..service.clone()
+        };
+        let overdue = entities::service_check::Model {
+            next_check: now - TimeDelta::minutes(1),
+            ..check
+        };
+        assert_eq!(
+            explain(&overdue, &agent_service, now),
+            "Due 1m ago, it runs when the agent edge polls for it"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_for_service_check() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test");
+        let check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        record(check.id, decision(3));
+
+        let insight = ScheduleInsight::for_service_check(&db, check.id, Utc::now())
+            .await
+            .expect("Failed to get the schedule");
+        assert_eq!(insight.service_check_id, check.id);
+        assert_eq!(insight.upcoming.len(), UPCOMING_RUNS);
+        assert!(insight
+            .upcoming
+            .windows(2)
+            .all(|pair| pair.first() < pair.last()));
+        assert!(insight.phase_offset_seconds < insight.interval_seconds.max(1));
+        assert_eq!(insight.decisions.len(), 1);
+
+        assert!(
+            ScheduleInsight::for_service_check(&db, Uuid::new_v4(), Utc::now())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Fetching config values from external secret managers, eg `{"$secret": {"vault": "secret/data/maremma#ssh_password"}}`
+
+use std::collections::BTreeSet;
+use std::sync::LazyLock;
+
+use crate::prelude::*;
+use vault::{VaultBackend, VaultConfig};
+
+pub mod vault;
+
+/// Values fetched from secret managers when the config was loaded, keyed by (backend, reference)
+pub(crate) type FetchedSecrets = HashMap<(String, String), String>;
+
+/// What was fetched the last time the config was loaded, so the shepherd can tell when it's changed
+static FETCHED: LazyLock<std::sync::RwLock<FetchedSecrets>> =
+    LazyLock::new(|| std::sync::RwLock::new(HashMap::new()));
+
+#[async_trait]
+/// A secret manager that config values can be fetched from
+pub trait SecretsBackend: Send + Sync {
+    /// The key references to this backend use in the config, eg `vault`
+    fn kind(&self) -> &'static str;
+
+    /// Fetches the value a reference points to
+    async fn fetch(&self, reference: &str) -> Result<String, Error>;
+}
+
+/// Finds the references to a backend in the config, ie objects like `{"$secret": {"vault": "secret/data/maremma#ssh_password"}}`
+pub(crate) fn find_references(value: &Value, kind: &str) -> BTreeSet<String> {
+    if let Some((found, reference)) = crate::serde::secret::reference(value) {
+        return match found == kind {
+            true => BTreeSet::from([reference.to_string()]),
+            false => BTreeSet::new(),
+        };
+    }
+    match value {
+        Value::Object(object) => object
+            .values()
+            .flat_map(|value| find_references(value, kind))
+            .collect(),
+        Value::Array(array) => array
+            .iter()
+            .flat_map(|value| find_references(value, kind))
+            .collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
+/// Fetches each of the references from a backend
+pub(crate) async fn fetch_all(
+    backend: &dyn SecretsBackend,
+    references: &BTreeSet<String>,
+) -> Result<FetchedSecrets, Error> {
+    let mut res = HashMap::new();
+    for reference in references {
+        let value = backend.fetch(reference).await.inspect_err(|err| {
+            error!(
+                "Failed to fetch {} secret {}: {:?}",
+                backend.kind(),
+                reference,
+                err
+            )
+        })?;
+        res.insert((backend.kind().to_string(), reference.clone()), value);
+    }
+    Ok(res)
+}
+
+/// Fetches everything the raw config references from the configured secret managers
+pub(crate) async fn fetch_references(config: &Value) -> Result<FetchedSecrets, Error> {
+    let references = find_references(config, vault::KIND);
+    if references.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut vault_config = config.get("vault").cloned().ok_or_else(|| {
+        Error::Configuration(
+            "The config references secrets in Vault, but the vault section isn't set".to_string(),
+        )
+    })?;
+    // the vault token can come from the environment or a file
+    crate::serde::secret::resolve_references(&mut vault_config, &HashMap::new())?;
+    let vault_config: VaultConfig = serde_json::from_value(vault_config)?;
+
+    let fetched = fetch_all(&VaultBackend::new(&vault_config)?, &references).await?;
+    *FETCHED
+        .write()
+        .map_err(|err| Error::Generic(err.to_string()))? = fetched.clone();
+    Ok(fetched)
+}
+
+/// Fetches the secrets the running config uses again, returning true if any of them have changed
+pub(crate) async fn secrets_changed(vault_config: &VaultConfig) -> Result<bool, Error> {
+    let previous = FETCHED
+        .read()
+        .map_err(|err| Error::Generic(err.to_string()))?
+        .clone();
+    let references = previous
+        .keys()
+        .filter(|(kind, _)| kind == vault::KIND)
+        .map(|(_, reference)| reference.clone())
+        .collect::<BTreeSet<_>>();
+    if references.is_empty() {
+        return Ok(false);
+    }
+    let fetched = fetch_all(&VaultBackend::new(vault_config)?, &references).await?;
+    Ok(fetched != previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_references() {
+        let config = json!({
+            "vault": {"address": "https://vault.example.com", "token": {"$secret": {"env": "VAULT_TOKEN"}}},
+            "oidc_client_secret": {"$secret": {"vault": "secret/data/maremma#oidc"}},
+            "services": {
+                "ssh": {"password": {"$secret": {"vault": "secret/data/maremma#ssh_password"}}},
+                "not_a_reference": {"vault": "x"},
+            },
+            "list": [{"$secret": {"vault": "secret/data/maremma#oidc"}}],
+        });
+        assert_eq!(
+            find_references(&config, vault::KIND),
+            BTreeSet::from([
+                "secret/data/maremma#oidc".to_string(),
+                "secret/data/maremma#ssh_password".to_string(),
+            ])
+        );
+        assert!(find_references(&config, "other").is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+//! HashiCorp Vault's KV version 2 secrets engine
+
+use std::path::PathBuf;
+
+use reqwest::Url;
+
+use super::SecretsBackend;
+use crate::prelude::*;
+
+/// The key references to Vault use in the config
+pub const KIND: &str = "vault";
+
+/// How often secrets are fetched again if `refresh_minutes` isn't set
+pub const DEFAULT_VAULT_REFRESH_MINUTES: u32 = 15;
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+/// Where to fetch `{"$secret": {"vault": "<path>#<field>"}}` references from
+pub struct VaultConfig {
+    /// The Vault server, eg `https://vault.example.com:8200`
+    pub address: String,
+    /// The token to authenticate with, it can be a secret reference
+    pub token: String,
+    /// The Vault Enterprise namespace
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// CA certificate to trust when connecting to Vault
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// How often the shepherd fetches secrets again, defaults to [DEFAULT_VAULT_REFRESH_MINUTES]
+    #[serde(default)]
+    pub refresh_minutes: Option<u32>,
+}
+
+impl VaultConfig {
+    /// How often the shepherd fetches secrets again
+    pub fn refresh_minutes(&self) -> u32 {
+        self.refresh_minutes
+            .unwrap_or(DEFAULT_VAULT_REFRESH_MINUTES)
+            .max(1)
+    }
+}
+
+/// Fetches secrets from a KV version 2 mount
+pub struct VaultBackend {
+    address: Url,
+    token: String,
+    namespace: Option<String>,
+    client: reqwest::Client,
+}
+
+impl VaultBackend {
+    /// Sets up a client for the configured Vault server
+    pub fn new(config: &VaultConfig) -> Result<Self, Error> {
+        let mut client = reqwest::ClientBuilder::new().user_agent(format!(
+            "{}/{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ));
+        if let Some(ca_file) = config.ca_file.as_ref() {
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(
+                &std::fs::read(ca_file).map_err(|err| {
+                    Error::IoError(format!(
+                        "Failed to read CA file {}: {}",
+                        ca_file.display(),
+                        err
+                    ))
+                })?,
+            )?);
+        }
+        Ok(Self {
+            address: Url::parse(&config.address).map_err(|err| {
+                Error::Configuration(format!("Invalid Vault address {}: {}", config.address, err))
+            })?,
+            token: config.token.clone(),
+            namespace: config.namespace.clone(),
+            client: client.build()?,
+        })
+    }
+}
+
+/// Splits a reference like `secret/data/maremma#ssh_password` into the path and the field
+fn parse_reference(reference: &str) -> Result<(&str, &str), Error> {
+    match reference.split_once('#') {
+        Some((path, field)) if !path.is_empty() && !field.is_empty() => {
+            Ok((path.trim_matches('/'), field))
+        }
+        _ => Err(Error::Configuration(format!(
+            "Vault reference {} should look like <path>#<field>, eg secret/data/maremma#ssh_password",
+            reference
+        ))),
+    }
+}
+
+#[async_trait]
+impl SecretsBackend for VaultBackend {
+    fn kind(&self) -> &'static str {
+        KIND
+    }
+
+    async fn fetch(&self, reference: &str) -> Result<String, Error> {
+        let (path, field) = parse_reference(reference)?;
+        let url = self
+            .address
+            .join(&format!("v1/{}", path))
+            .map_err(|err| Error::Configuration(format!("Invalid Vault path {}: {}", path, err)))?;
+
+        let mut request = self.client.get(url).header("X-Vault-Token", &self.token);
+        if let Some(namespace) = self.namespace.as_ref() {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(Error::Configuration(format!(
+                "Vault returned {} for {}",
+                response.status(),
+                path
+            )));
+        }
+
+        // KV version 2 nests the secret's fields under data.data
+        let body: Value = response.json().await?;
+        match body.pointer(&format!("/data/data/{}", field)) {
+            Some(Value::String(value)) => Ok(value.clone()),
+            Some(Value::Null) | None => Err(Error::Configuration(format!(
+                "Vault secret {} doesn't have a {} field",
+                path, field
+            ))),
+            Some(value) => Ok(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Path;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+
+    async fn fake_vault(
+        headers: HeaderMap,
+        Path(path): Path<String>,
+    ) -> Result<axum::Json<Value>, StatusCode> {
+        if headers.get("X-Vault-Token").and_then(|t| t.to_str().ok()) != Some("s.hunter2") {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        match path.as_str() {
+            "secret/data/maremma" => Ok(axum::Json(json!({
+                "data": {
+                    "data": {"ssh_password": "correct horse", "port": 22},
+                    "metadata": {"version": 3},
+                }
+            }))),
+            _ => Err(StatusCode::NOT_FOUND),
+        }
+    }
+
+    async fn start_fake_vault() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to listen");
+        let address = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let app = axum::Router::new().route("/v1/*path", get(fake_vault));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            parse_reference("secret/data/maremma#ssh_password").expect("Failed to parse"),
+            ("secret/data/maremma", "ssh_password")
+        );
+        assert!(parse_reference("secret/data/maremma").is_err());
+        assert!(parse_reference("#field").is_err());
+        assert!(parse_reference("secret/data/maremma#").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_vault_fetch() {
+        let config = VaultConfig {
+            address: start_fake_vault().await,
+            token: "s.hunter2".to_string(),
+            namespace: None,
+            ca_file: None,
+            refresh_minutes: None,
+        };
+        let backend = VaultBackend::new(&config).expect("Failed to build backend");
+
+        assert_eq!(
+            backend
+                .fetch("secret/data/maremma#ssh_password")
+                .await
+                .expect("Failed to fetch"),
+            "correct horse"
+        );
+        assert_eq!(
+            backend
+                .fetch("secret/data/maremma#port")
+                .await
+                .expect("Failed to fetch"),
+            "22"
+        );
+        assert!(backend.fetch("secret/data/maremma#missing").await.is_err());
+        assert!(backend.fetch("secret/data/other#field").await.is_err());
+
+        let backend = VaultBackend::new(&VaultConfig {
+            token: "wrong".to_string(),
+            ..config.clone()
+        })
+        .expect("Failed to build backend");
+        assert!(backend
+            .fetch("secret/data/maremma#ssh_password")
+            .await
+            .is_err());
+
+        // the config loader swaps references for what's in vault
+        let raw = json!({
+            "vault": {"address": config.address, "token": "s.hunter2"},
+            "password": {"$secret": {"vault": "secret/data/maremma#ssh_password"}},
+        });
+        let fetched = crate::secrets::fetch_references(&raw)
+            .await
+            .expect("Failed to fetch references");
+        let mut resolved = raw.clone();
+        crate::serde::secret::resolve_references(&mut resolved, &fetched)
+            .expect("Failed to resolve references");
+        assert_eq!(resolved["password"], "correct horse");
+        assert!(!crate::secrets::secrets_changed(&config)
+            .await
+            .expect("Failed to check for changes"));
+    }
+}
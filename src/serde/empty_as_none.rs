@@ -0,0 +1,40 @@
+//! HTML forms send an empty string for "nothing picked", this turns it into `None`
+
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+
+pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.trim().is_empty() => {
+            T::deserialize(value.into_deserializer()).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    #[test]
+    fn test_empty_as_none() {
+        #[derive(Deserialize, Debug)]
+        struct Form {
+            #[serde(default, deserialize_with = "super::deserialize")]
+            status: Option<ServiceStatus>,
+        }
+
+        let form: Form = serde_json::from_value(json!({"status": ""})).expect("Failed to parse");
+        assert_eq!(form.status, None);
+        let form: Form = serde_json::from_value(json!({})).expect("Failed to parse");
+        assert_eq!(form.status, None);
+        let form: Form =
+            serde_json::from_value(json!({"status": "critical"})).expect("Failed to parse");
+        assert_eq!(form.status, Some(ServiceStatus::Critical));
+        assert!(serde_json::from_value::<Form>(json!({"status": "nope"})).is_err());
+    }
+}
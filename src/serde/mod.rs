@@ -1 +1,3 @@
 pub(crate) mod cron;
+pub(crate) mod empty_as_none;
+pub(crate) mod secret;
@@ -0,0 +1,325 @@
+//! Resolving secret references in configuration, and masking secrets when configuration is shown to people
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, RwLock};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::errors::Error;
+use crate::secrets::FetchedSecrets;
+
+/// Values pulled in from secret references, so they can be masked wherever they end up
+static RESOLVED_SECRETS: LazyLock<RwLock<HashSet<String>>> =
+    LazyLock::new(|| RwLock::new(HashSet::new()));
+
+/// Headers whose values are credentials
+const SENSITIVE_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+fn mask(value: &str) -> String {
+    "*".repeat(value.len())
+}
+
+/// Header names that look like they carry a credential, eg `X-Api-Key`
+fn is_sensitive_header(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE_HEADERS.contains(&name.as_str())
+        || ["token", "key", "secret", "password"]
+            .iter()
+            .any(|word| name.contains(word))
+}
+
+/// Secret references are wrapped in this key, eg `{"$secret": {"env": "SSH_PASS"}}`, so config that happens to look
+/// like a reference (a `{"env": "prod"}` label) is left alone
+pub(crate) const SECRET_KEY: &str = "$secret";
+
+/// The kind and target of a secret reference, eg `("env", "SSH_PASS")` for `{"$secret": {"env": "SSH_PASS"}}`
+pub(crate) fn reference(value: &Value) -> Option<(&str, &str)> {
+    let object = value.as_object().filter(|object| object.len() == 1)?;
+    let reference = object
+        .get(SECRET_KEY)?
+        .as_object()
+        .filter(|reference| reference.len() == 1)?;
+    let (kind, target) = reference.iter().next()?;
+    Some((kind.as_str(), target.as_str()?))
+}
+
+/// Reads the value a secret reference points to, if this is one - eg `{"$secret": {"env": "SSH_PASS"}}` or
+/// `{"$secret": {"file": "/run/secrets/ssh_pass"}}`, references to secret managers come from what's already been fetched
+fn read_reference(value: &Value, fetched: &FetchedSecrets) -> Option<Result<String, Error>> {
+    if !value
+        .as_object()
+        .is_some_and(|object| object.contains_key(SECRET_KEY))
+    {
+        return None;
+    }
+    let Some((kind, target)) = reference(value) else {
+        return Some(Err(Error::Configuration(format!(
+            "Invalid secret reference {}, it should look like {{\"{}\": {{\"env\": \"NAME\"}}}}",
+            value, SECRET_KEY
+        ))));
+    };
+    match kind {
+        "env" => Some(std::env::var(target).map_err(|err| {
+            Error::Configuration(format!(
+                "Couldn't read secret from environment variable {}: {}",
+                target, err
+            ))
+        })),
+        "file" => Some(
+            std::fs::read_to_string(target)
+                .map(|contents| contents.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|err| {
+                    Error::Configuration(format!(
+                        "Couldn't read secret from file {}: {}",
+                        target, err
+                    ))
+                }),
+        ),
+        _ => Some(
+            fetched
+                .get(&(kind.to_string(), target.to_string()))
+                .cloned()
+                .ok_or_else(|| {
+                    Error::Configuration(format!("Couldn't find {} secret {}", kind, target))
+                }),
+        ),
+    }
+}
+
+/// Replaces secret references anywhere in the config with the values they point to
+pub(crate) fn resolve_references(value: &mut Value, fetched: &FetchedSecrets) -> Result<(), Error> {
+    if let Some(secret) = read_reference(value, fetched) {
+        let secret = secret?;
+        if !secret.is_empty() {
+            RESOLVED_SECRETS
+                .write()
+                .map_err(|err| Error::Generic(err.to_string()))?
+                .insert(secret.clone());
+        }
+        *value = Value::String(secret);
+        return Ok(());
+    }
+    match value {
+        Value::Object(object) => object
+            .values_mut()
+            .try_for_each(|value| resolve_references(value, fetched)),
+        Value::Array(array) => array
+            .iter_mut()
+            .try_for_each(|value| resolve_references(value, fetched)),
+        _ => Ok(()),
+    }
+}
+
+/// Masks any value that came from a secret reference
+pub(crate) fn redact(value: &mut Value) {
+    let Ok(secrets) = RESOLVED_SECRETS.read() else {
+        return;
+    };
+    redact_with(value, &secrets);
+}
+
+fn redact_with(value: &mut Value, secrets: &HashSet<String>) {
+    match value {
+        Value::String(string) => {
+            for secret in secrets.iter() {
+                if string.contains(secret.as_str()) {
+                    *string = string.replace(secret.as_str(), &mask(secret));
+                }
+            }
+        }
+        Value::Object(object) => object
+            .values_mut()
+            .for_each(|value| redact_with(value, secrets)),
+        Value::Array(array) => array
+            .iter_mut()
+            .for_each(|value| redact_with(value, secrets)),
+        _ => {}
+    }
+}
+
+/// Pretty-prints something as JSON with any resolved secrets masked
+pub(crate) fn to_string_pretty<T: Serialize>(value: &T) -> Result<String, Error> {
+    let mut value = serde_json::to_value(value)?;
+    redact(&mut value);
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Serializes an optional secret as a string of `*` the same length
+pub(crate) fn serialize<S>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match secret {
+        Some(secret) => serializer.serialize_str(&mask(secret)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes a secret as a string of `*` the same length
+pub(crate) fn serialize_str<S>(secret: &str, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&mask(secret))
+}
+
+/// Serializes a URL-style connection string with the password masked, eg `postgres://user:****@db/app`
+pub(crate) fn serialize_url<S>(url: &str, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&mask_url_password(url))
+}
+
+fn mask_url_password(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let Some((userinfo, host)) = authority.rsplit_once('@') else {
+        return url.to_string();
+    };
+    let Some((username, password)) = userinfo.split_once(':') else {
+        return url.to_string();
+    };
+    format!(
+        "{}://{}:{}@{}{}",
+        scheme,
+        username,
+        mask(password),
+        host,
+        &rest[authority.len()..]
+    )
+}
+
+/// Serializes HTTP headers, masking the values of any that look like credentials
+pub(crate) fn serialize_headers<S>(
+    headers: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(
+        headers
+            .iter()
+            .map(|(name, value)| match is_sensitive_header(name) {
+                true => (name.clone(), mask(value)),
+                false => (name.clone(), value.clone()),
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    #[test]
+    fn test_resolve_references() {
+        let mut secret_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+        std::io::Write::write_all(&mut secret_file, b"filesecret\n")
+            .expect("Failed to write secret");
+        std::env::set_var("MAREMMA_TEST_SECRET_REF", "envsecret");
+
+        let mut config = json!({
+            "password": {"$secret": {"env": "MAREMMA_TEST_SECRET_REF"}},
+            "nested": [{"token": {"$secret": {"file": secret_file.path()}}}],
+            "from_vault": {"$secret": {"vault": "secret/data/maremma#token"}},
+            // user data that looks like a reference without the marker is left alone
+            "labels": {"env": "prod"},
+            "vars": {"file": "/etc/hosts"},
+        });
+        let fetched = HashMap::from([(
+            ("vault".to_string(), "secret/data/maremma#token".to_string()),
+            "vaultsecret".to_string(),
+        )]);
+        super::resolve_references(&mut config, &fetched).expect("Failed to resolve references");
+        assert_eq!(
+            config,
+            json!({
+                "password": "envsecret",
+                "nested": [{"token": "filesecret"}],
+                "from_vault": "vaultsecret",
+                "labels": {"env": "prod"},
+                "vars": {"file": "/etc/hosts"},
+            })
+        );
+
+        super::redact(&mut config);
+        assert_eq!(config["password"], "*********");
+        assert_eq!(config["nested"][0]["token"], "**********");
+        assert_eq!(config["from_vault"], "***********");
+
+        for reference in [
+            json!({"env": "MAREMMA_TEST_SECRET_REF_MISSING"}),
+            json!({"file": "/this/does/not/exist"}),
+            json!({"vault": "secret/data/maremma#not_fetched"}),
+            json!({"env": "MAREMMA_TEST_SECRET_REF", "file": "/etc/hosts"}),
+            json!("MAREMMA_TEST_SECRET_REF"),
+        ] {
+            let mut config = json!({"password": {"$secret": reference}});
+            assert!(matches!(
+                super::resolve_references(&mut config, &HashMap::new()),
+                Err(Error::Configuration(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_serde_secret() {
+        #[derive(Serialize)]
+        struct SecretTest {
+            #[serde(serialize_with = "super::serialize")]
+            password: Option<String>,
+            #[serde(serialize_with = "super::serialize_headers")]
+            headers: HashMap<String, String>,
+        }
+
+        assert_eq!(
+            super::mask_url_password("postgres://maremma:hunter2@db:5432/app?sslmode=require"),
+            "postgres://maremma:*******@db:5432/app?sslmode=require"
+        );
+        assert_eq!(
+            super::mask_url_password("redis://:hunter2@cache"),
+            "redis://:*******@cache"
+        );
+        assert_eq!(
+            super::mask_url_password("mysql://root@db/app"),
+            "mysql://root@db/app"
+        );
+        assert_eq!(
+            super::mask_url_password("sqlite::memory:"),
+            "sqlite::memory:"
+        );
+
+        let test = SecretTest {
+            password: Some("hunter2".to_string()),
+            headers: HashMap::from([
+                ("Authorization".to_string(), "Bearer abc".to_string()),
+                ("X-Api-Key".to_string(), "abc".to_string()),
+                ("Accept".to_string(), "application/json".to_string()),
+            ]),
+        };
+        assert_eq!(
+            serde_json::to_value(&test).expect("Failed to serialize"),
+            json!({
+                "password": "*******",
+                "headers": {
+                    "Authorization": "**********",
+                    "X-Api-Key": "***",
+                    "Accept": "application/json",
+                }
+            })
+        );
+
+        let test = SecretTest {
+            password: None,
+            headers: HashMap::new(),
+        };
+        assert_eq!(
+            serde_json::to_value(&test).expect("Failed to serialize"),
+            json!({"password": null, "headers": {}})
+        );
+    }
+}
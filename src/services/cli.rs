@@ -2,11 +2,18 @@
 
 use schemars::JsonSchema;
 
+use super::host_variables::expand_host_variables;
 use super::prelude::*;
+use super::threshold::{self, NumericThreshold};
 use crate::prelude::*;
 use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
 use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a command can run before it's killed, in seconds
+pub const DEFAULT_CLI_TIMEOUT_SECONDS: u32 = 60;
 
 #[derive(Debug, Deserialize, Serialize, clap::Parser, JsonSchema)]
 /// A service that runs on the command line, typically on the Maremma server
@@ -15,7 +22,7 @@ pub struct CliService {
     pub name: String,
     /// Hostname for overlaying on the service
     pub hostname: Option<String>,
-    /// Command line to run, you can use #HOSTNAME# to substitute the hostname
+    /// Command line to run, you can use #HOSTNAME# to substitute the hostname, see [super::host_variables] for the others
     pub command_line: String,
     #[serde(default)]
     /// If we should run the command in a shell
@@ -26,6 +33,67 @@ pub struct CliService {
     pub cron_schedule: Cron,
     /// Add random jitter in 0..n seconds to the check
     pub jitter: Option<u16>,
+    #[serde(default)]
+    #[clap(skip)]
+    /// Environment variables to set for the command, you can use host variables like #HOSTNAME# in the values
+    pub environment: HashMap<String, String>,
+    /// The directory to run the command in, defaults to Maremma's
+    pub working_directory: Option<PathBuf>,
+    /// How long the command can run before it's killed (seconds), defaults to [DEFAULT_CLI_TIMEOUT_SECONDS]
+    pub timeout: Option<u32>,
+    #[serde(default)]
+    /// Warn when the output's different to the last successful run's, and show what changed
+    pub detect_changes: bool,
+    #[serde(default)]
+    /// Record the exit code and stderr with each result
+    pub debug_capture: bool,
+    #[serde(default)]
+    #[clap(skip)]
+    /// Warning and critical thresholds on numbers in the output, see [super::threshold]
+    pub thresholds: Vec<NumericThreshold>,
+}
+
+/// Reads everything from the child's stdout or stderr
+async fn read_pipe<R: AsyncRead + Unpin>(pipe: Option<R>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        if let Err(err) = pipe.read_to_end(&mut buf).await {
+            debug!("Failed to read command output: {:?}", err);
+        }
+    }
+    buf
+}
+
+/// The exit code, or the signal that killed the command, and stderr if there was any
+fn diagnostics(status: &std::process::ExitStatus, stderr: &[u8]) -> Vec<(String, String)> {
+    let mut res = vec![(
+        "exit_code".to_string(),
+        match (status.code(), status.signal()) {
+            (Some(code), _) => code.to_string(),
+            (None, Some(signal)) => format!("killed by signal {}", signal),
+            (None, None) => "unknown".to_string(),
+        },
+    )];
+    let stderr = String::from_utf8_lossy(stderr).trim().to_string();
+    if !stderr.is_empty() {
+        res.push(("stderr".to_string(), stderr));
+    }
+    res
+}
+
+/// Keeps stdout and stderr apart in the result text, so it's obvious which one said what
+fn result_text(stdout: &[u8], stderr: &[u8]) -> String {
+    let stdout = String::from_utf8_lossy(stdout)
+        .trim()
+        .replace(r#"\\n"#, " ");
+    let stderr = String::from_utf8_lossy(stderr)
+        .trim()
+        .replace(r#"\\n"#, " ");
+    match (stdout.is_empty(), stderr.is_empty()) {
+        (_, true) => stdout,
+        (true, false) => format!("stderr: {}", stderr),
+        (false, false) => format!("{}\nstderr: {}", stdout, stderr),
+    }
 }
 
 impl ConfigOverlay for CliService {
@@ -42,6 +110,16 @@ impl ConfigOverlay for CliService {
             command_line,
             run_in_shell: self.extract_bool(value, "run_in_shell", self.run_in_shell),
             jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            environment: self.extract_value(value, "environment", &self.environment)?,
+            working_directory: self.extract_value(
+                value,
+                "working_directory",
+                &self.working_directory,
+            )?,
+            timeout: self.extract_value(value, "timeout", &self.timeout)?,
+            detect_changes: self.extract_bool(value, "detect_changes", self.detect_changes),
+            debug_capture: self.extract_bool(value, "debug_capture", self.debug_capture),
+            thresholds: self.extract_value(value, "thresholds", &self.thresholds)?,
         }))
     }
 }
@@ -59,7 +137,12 @@ impl ServiceTrait for CliService {
             None => host.hostname.to_owned(),
         };
 
-        let command_line = config.command_line.replace("#HOSTNAME#", &hostname);
+        let command_line = expand_host_variables(&config.command_line, host, &hostname)?;
+        let environment = config
+            .environment
+            .iter()
+            .map(|(key, value)| Ok((key, expand_host_variables(value, host, &hostname)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
 
         let mut cmd_split = command_line.split(" ");
         let cmd = match cmd_split.next() {
@@ -74,57 +157,114 @@ impl ServiceTrait for CliService {
                 result_text: format!("Command not found: {}", cmd),
                 status: ServiceStatus::Critical,
                 time_elapsed: chrono::Utc::now() - start_time,
+                ..Default::default()
             });
         }
 
+        if let Some(working_directory) = &config.working_directory {
+            if !working_directory.is_dir() {
+                return Ok(CheckResult {
+                    timestamp: chrono::Utc::now(),
+                    result_text: format!(
+                        "Working directory not found: {}",
+                        working_directory.display()
+                    ),
+                    status: ServiceStatus::Critical,
+                    time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
+                });
+            }
+        }
+
         let args = cmd_split.collect::<Vec<&str>>();
 
-        let child = tokio::process::Command::new(cmd)
+        let mut command = tokio::process::Command::new(cmd);
+        command
             .args(args)
+            .envs(environment)
             .kill_on_drop(true)
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(working_directory) = &config.working_directory {
+            command.current_dir(working_directory);
+        }
+        let mut child = command
             .spawn()
             .map_err(|err| Error::Generic(err.to_string()))?;
 
-        let res = child
-            .wait_with_output()
-            .await
-            .map_err(|err| Error::Generic(err.to_string()))?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let timeout = config.timeout.unwrap_or(DEFAULT_CLI_TIMEOUT_SECONDS);
+        let res = tokio::time::timeout(Duration::from_secs(timeout as u64), async {
+            tokio::join!(child.wait(), read_pipe(stdout), read_pipe(stderr))
+        })
+        .await;
+
+        let (status, stdout, stderr) = match res {
+            Ok((status, stdout, stderr)) => (
+                status.map_err(|err| Error::Generic(err.to_string()))?,
+                stdout,
+                stderr,
+            ),
+            Err(_) => {
+                // don't leave it hanging around holding a check slot
+                if let Err(err) = child.kill().await {
+                    warn!("Failed to kill timed out command {}: {:?}", cmd, err);
+                }
+                return Ok(CheckResult {
+                    timestamp: chrono::Utc::now(),
+                    result_text: format!("Command timed out after {} seconds", timeout),
+                    status: ServiceStatus::Critical,
+                    time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
+                });
+            }
+        };
 
         let time_elapsed = chrono::Utc::now() - start_time;
 
-        if res.status != std::process::ExitStatus::from_raw(0) {
-            let mut combined = res.stderr.to_vec();
-            combined.extend(res.stdout);
+        if status != std::process::ExitStatus::from_raw(0) {
             return Ok(CheckResult {
                 timestamp: chrono::Utc::now(),
-                result_text: String::from_utf8_lossy(&combined)
-                    .to_string()
-                    .replace(r#"\\n"#, " "),
                 status: ServiceStatus::Critical,
                 time_elapsed,
-            });
+                ..Default::default()
+            }
+            .with_plugin_output(&result_text(&stdout, &stderr))
+            .with_diagnostics(config.debug_capture, diagnostics(&status, &stderr)));
         }
 
-        Ok(CheckResult {
+        let output = result_text(&stdout, &stderr);
+        let result = CheckResult {
             timestamp: chrono::Utc::now(),
-            result_text: String::from_utf8_lossy(&res.stdout)
-                .to_string()
-                .replace(r#"\\n"#, " "),
             status: ServiceStatus::Ok,
             time_elapsed,
-        })
+            ..Default::default()
+        }
+        .with_plugin_output(&output)
+        .with_compare_output(config.detect_changes, &output)
+        .with_diagnostics(config.debug_capture, diagnostics(&status, &stderr));
+        Ok(threshold::apply(
+            &config.thresholds,
+            result,
+            &String::from_utf8_lossy(&stdout),
+        ))
     }
 
     fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
-        Ok(serde_json::to_string_pretty(&config)?)
+        crate::serde::secret::to_string_pretty(&config)
     }
 
     fn jitter_value(&self) -> u32 {
         self.jitter.unwrap_or(0) as u32
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.thresholds
+            .iter()
+            .try_for_each(NumericThreshold::validate)
+    }
 }
 
 #[cfg(test)]
@@ -142,6 +282,12 @@ mod tests {
             run_in_shell: false,
             cron_schedule: "@hourly".parse().expect("Failed to parse cron schedule"),
             jitter: None,
+            environment: HashMap::new(),
+            working_directory: None,
+            timeout: None,
+            detect_changes: false,
+            debug_capture: false,
+            thresholds: Vec::new(),
         };
         let host = entities::host::Model {
             check: crate::host::HostCheck::None,
@@ -153,6 +299,82 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_cliservice_environment_and_timeout() {
+        let host = entities::host::Model {
+            check: crate::host::HostCheck::None,
+            ..test_host()
+        };
+        let service = |command_line: &str| super::CliService {
+            name: "test".to_string(),
+            hostname: None,
+            command_line: command_line.to_string(),
+            run_in_shell: false,
+            cron_schedule: "@hourly".parse().expect("Failed to parse cron schedule"),
+            jitter: None,
+            environment: HashMap::from([("MAREMMA_TEST".to_string(), "#HOSTNAME#".to_string())]),
+            working_directory: Some(std::env::temp_dir()),
+            timeout: Some(1),
+            detect_changes: true,
+            debug_capture: false,
+            thresholds: Vec::new(),
+        };
+
+        let res = service("/usr/bin/env")
+            .run(&host)
+            .await
+            .expect("Failed to run env");
+        assert_eq!(res.status, ServiceStatus::Ok);
+        let output = format!(
+            "{}\n{}",
+            res.result_text,
+            res.long_output.unwrap_or_default()
+        );
+        assert!(output.contains(&format!("MAREMMA_TEST={}", host.hostname)));
+
+        let res = service("/bin/pwd")
+            .run(&host)
+            .await
+            .expect("Failed to run pwd");
+        let temp_dir = std::env::temp_dir()
+            .canonicalize()
+            .expect("Failed to canonicalize temp dir");
+        assert!(res.result_text.contains(&*temp_dir.to_string_lossy()));
+
+        let res = service("/bin/ls /this/does/not/exist")
+            .run(&host)
+            .await
+            .expect("Failed to run ls");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(res.result_text.starts_with("stderr: "));
+        assert!(res.diagnostics.is_empty());
+
+        let res = super::CliService {
+            debug_capture: true,
+            ..service("/bin/ls /this/does/not/exist")
+        }
+        .run(&host)
+        .await
+        .expect("Failed to run ls");
+        assert_ne!(
+            res.diagnostics.get("exit_code").map(String::as_str),
+            Some("0")
+        );
+        assert!(res.diagnostics.contains_key("exit_code"));
+        assert!(res.diagnostics.contains_key("stderr"));
+
+        let res = service("/bin/sleep 10")
+            .run(&host)
+            .await
+            .expect("Failed to run sleep");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(res.result_text.contains("timed out"));
+        assert!(res.time_elapsed < TimeDelta::seconds(5));
+
+        assert_eq!(super::result_text(b"out\n", b""), "out");
+        assert_eq!(super::result_text(b"out", b"err"), "out\nstderr: err");
+    }
+
     #[test]
     fn test_parse_cliservice() {
         let service: super::CliService = match serde_json::from_str(
@@ -176,7 +398,15 @@ mod tests {
             id: Default::default(),
             description: None,
             host_groups: vec![],
+            labels: Default::default(),
             cron_schedule: Cron::new("@hourly").parse().expect("Failed to parse cron"),
+            agent: None,
+            expose_alert_rule: false,
+            alert_rule_for: None,
+            notifications: None,
+            max_runtime: None,
+            timezone: None,
+            template: None,
             extra_config: HashMap::from_iter([("hello".to_string(), json!("world"))]),
             config: None
         }
@@ -0,0 +1,419 @@
+//! Filesystem usage, from `df` over SSH, or run locally for the local checks host
+
+use std::collections::BTreeMap;
+
+use super::prelude::*;
+use super::ssh::SshConnection;
+use super::Thresholds;
+use crate::prelude::*;
+
+/// POSIX output in 1K blocks, so it parses the same everywhere
+const DF_COMMAND: &str = "LC_ALL=C df -P -k";
+
+/// Warn at or above this percentage used
+pub const DEFAULT_DISK_WARNING_PERCENT: f64 = 80.0;
+/// Critical at or above this percentage used
+pub const DEFAULT_DISK_CRITICAL_PERCENT: f64 = 90.0;
+
+/// Filesystems which aren't worth checking, matched against the start of df's first column
+pub const DEFAULT_IGNORED_FILESYSTEMS: [&str; 7] = [
+    "tmpfs",
+    "devtmpfs",
+    "udev",
+    "overlay",
+    "shm",
+    "none",
+    "/dev/loop",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+/// A line of `df` output
+pub(crate) struct Filesystem {
+    pub(crate) filesystem: String,
+    pub(crate) mountpoint: String,
+    pub(crate) used_kb: u64,
+    pub(crate) available_kb: u64,
+}
+
+impl Filesystem {
+    /// Percentage used the way `df` works it out, ignoring blocks reserved for root
+    pub(crate) fn used_percent(&self) -> f64 {
+        match self.used_kb + self.available_kb {
+            0 => 0.0,
+            total => self.used_kb as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// Parses `df -P -k` output, mountpoints can have spaces in them so they're the rest of the line
+pub(crate) fn parse_df(output: &str) -> Result<Vec<Filesystem>, Error> {
+    output
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 6 {
+                return Err(Error::Generic(format!(
+                    "Couldn't parse df output line: {}",
+                    line
+                )));
+            }
+            let parse = |value: &str| {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| Error::Generic(format!("Couldn't parse df output line: {}", line)))
+            };
+            Ok(Filesystem {
+                filesystem: fields[0].to_string(),
+                used_kb: parse(fields[2])?,
+                available_kb: parse(fields[3])?,
+                mountpoint: fields[5..].join(" "),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// Checks how full the filesystems on a host are
+pub struct DiskService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// Only check these mountpoints, defaults to everything that isn't ignored
+    #[serde(default)]
+    pub mountpoints: Vec<String>,
+
+    /// Skip filesystems starting with these, defaults to [DEFAULT_IGNORED_FILESYSTEMS]
+    #[serde(default)]
+    pub ignore_filesystems: Option<Vec<String>>,
+
+    /// Warn at or above this percentage used, defaults to [DEFAULT_DISK_WARNING_PERCENT]
+    #[serde(default)]
+    pub warning: Option<f64>,
+
+    /// Critical at or above this percentage used, defaults to [DEFAULT_DISK_CRITICAL_PERCENT]
+    #[serde(default)]
+    pub critical: Option<f64>,
+
+    /// Thresholds for particular mountpoints, eg a `/backup` that's meant to be full
+    #[serde(default)]
+    pub thresholds: BTreeMap<String, Thresholds>,
+
+    /// How to connect to the host, these sit alongside the other options
+    #[serde(flatten)]
+    pub ssh: SshConnection,
+}
+
+impl ConfigOverlay for DiskService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            mountpoints: self.extract_value(value, "mountpoints", &self.mountpoints)?,
+            ignore_filesystems: self.extract_value(
+                value,
+                "ignore_filesystems",
+                &self.ignore_filesystems,
+            )?,
+            warning: self.extract_value(value, "warning", &self.warning)?,
+            critical: self.extract_value(value, "critical", &self.critical)?,
+            thresholds: self.extract_value(value, "thresholds", &self.thresholds)?,
+            ssh: *self.ssh.overlay_host_config(value)?,
+        }))
+    }
+}
+
+impl DiskService {
+    /// The (warning, critical) thresholds for a mountpoint
+    fn thresholds(&self, mountpoint: &str) -> (f64, f64) {
+        let specific = self.thresholds.get(mountpoint);
+        (
+            specific
+                .and_then(|t| t.warning)
+                .or(self.warning)
+                .unwrap_or(DEFAULT_DISK_WARNING_PERCENT),
+            specific
+                .and_then(|t| t.critical)
+                .or(self.critical)
+                .unwrap_or(DEFAULT_DISK_CRITICAL_PERCENT),
+        )
+    }
+
+    fn is_ignored(&self, filesystem: &Filesystem) -> bool {
+        match self.ignore_filesystems.as_ref() {
+            Some(ignored) => ignored
+                .iter()
+                .any(|prefix| filesystem.filesystem.starts_with(prefix.as_str())),
+            None => DEFAULT_IGNORED_FILESYSTEMS
+                .iter()
+                .any(|prefix| filesystem.filesystem.starts_with(prefix)),
+        }
+    }
+
+    /// Works out the status of each filesystem, returning the status, summary, long output and details
+    fn evaluate(
+        &self,
+        filesystems: &[Filesystem],
+    ) -> (ServiceStatus, String, String, BTreeMap<String, String>) {
+        let checked = filesystems
+            .iter()
+            .filter(|fs| match self.mountpoints.is_empty() {
+                true => !self.is_ignored(fs),
+                false => self.mountpoints.contains(&fs.mountpoint),
+            })
+            .collect::<Vec<_>>();
+
+        let mut status = ServiceStatus::Ok;
+        let mut problems = Vec::new();
+        let mut details = BTreeMap::new();
+        let mut long_output = Vec::new();
+
+        for mountpoint in self.mountpoints.iter() {
+            if !checked.iter().any(|fs| &fs.mountpoint == mountpoint) {
+                status = ServiceStatus::Critical;
+                problems.push(format!("{} isn't mounted", mountpoint));
+            }
+        }
+
+        for fs in checked.iter() {
+            let used = fs.used_percent();
+            let (warning, critical) = self.thresholds(&fs.mountpoint);
+            let fs_status = Thresholds::status(used, warning, critical);
+            if fs_status != ServiceStatus::Ok {
+                problems.push(format!("{} is {:.1}% used", fs.mountpoint, used));
+                status = status.max(fs_status);
+            }
+            details.insert(
+                format!("used_percent:{}", fs.mountpoint),
+                format!("{:.2}", used),
+            );
+            details.insert(
+                format!("free_mb:{}", fs.mountpoint),
+                format!("{}", fs.available_kb / 1024),
+            );
+            long_output.push(format!(
+                "{} ({}): {:.1}% used, {} MB free",
+                fs.mountpoint,
+                fs.filesystem,
+                used,
+                fs.available_kb / 1024
+            ));
+        }
+
+        let summary = match (problems.is_empty(), checked.is_empty()) {
+            (false, _) => problems.join(", "),
+            (true, true) => {
+                status = ServiceStatus::Unknown;
+                "No filesystems to check".to_string()
+            }
+            (true, false) => format!(
+                "{} filesystems checked, the fullest is {}",
+                checked.len(),
+                checked
+                    .iter()
+                    .max_by(|a, b| a.used_percent().total_cmp(&b.used_percent()))
+                    .map(|fs| format!("{} at {:.1}%", fs.mountpoint, fs.used_percent()))
+                    .unwrap_or_default()
+            ),
+        };
+        (status, summary, long_output.join("\n"), details)
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for DiskService {
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = chrono::Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+
+        let output = match config.ssh.run_on_host(&self.name, host, DF_COMMAND).await? {
+            Ok(output) => output,
+            Err(result_text) => {
+                return Ok(CheckResult {
+                    timestamp: start_time,
+                    result_text,
+                    status: ServiceStatus::Critical,
+                    time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
+                })
+            }
+        };
+
+        let (status, result_text, long_output, details) = config.evaluate(&parse_df(&output)?);
+        Ok(CheckResult {
+            timestamp: start_time,
+            status,
+            result_text,
+            long_output: (!long_output.is_empty()).then_some(long_output),
+            details,
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..Default::default()
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let mountpoints = std::iter::once(None).chain(self.thresholds.keys().map(Some));
+        for mountpoint in mountpoints {
+            let (warning, critical) = self.thresholds(mountpoint.map(|m| m.as_str()).unwrap_or(""));
+            if warning > critical {
+                return Err(Error::Configuration(format!(
+                    "{}: warning ({}) can't be above critical ({}){}",
+                    self.name,
+                    warning,
+                    critical,
+                    mountpoint
+                        .map(|m| format!(" for {}", m))
+                        .unwrap_or_default()
+                )));
+            }
+        }
+        self.ssh.validate()
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::service_from_json;
+
+    const DF_OUTPUT: &str = "Filesystem     1024-blocks      Used Available Capacity Mounted on
+/dev/sda1         41152736  37037462   4115274      91% /
+tmpfs              8133432         0   8133432       0% /dev/shm
+/dev/sdb1        103081248  84526624  18554624      83% /var/lib/docker
+/dev/sdc1        206293688  20629368 185664320      10% /mnt/my backups
+/dev/loop0           65536     65536         0     100% /snap/core/1
+";
+
+    #[test]
+    fn test_parse_df() {
+        let filesystems = parse_df(DF_OUTPUT).expect("Failed to parse df output");
+        assert_eq!(filesystems.len(), 5);
+        assert_eq!(filesystems[0].mountpoint, "/");
+        assert_eq!(filesystems[3].mountpoint, "/mnt/my backups");
+        assert_eq!(filesystems[3].used_kb, 20629368);
+        assert_eq!(filesystems[1].used_percent(), 0.0);
+        assert!((filesystems[0].used_percent() - 90.0).abs() < 0.01);
+
+        assert!(
+            parse_df("Filesystem 1024-blocks Used Available Capacity Mounted on\nbroken line")
+                .is_err()
+        );
+        assert!(
+            parse_df("Filesystem 1024-blocks Used Available Capacity Mounted on\n")
+                .expect("Failed to parse empty df output")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_disk_evaluate() {
+        let filesystems = parse_df(DF_OUTPUT).expect("Failed to parse df output");
+
+        let service = service_from_json::<DiskService>("disk", json!({}))
+            .expect("Failed to parse disk service");
+        let (status, summary, long_output, details) = service.evaluate(&filesystems);
+        assert_eq!(status, ServiceStatus::Critical);
+        assert!(summary.contains("/ is 90.0% used"));
+        assert!(summary.contains("/var/lib/docker is 82.0% used"));
+        // tmpfs and loop devices are skipped
+        assert!(!long_output.contains("/dev/shm"));
+        assert!(!long_output.contains("/snap/core/1"));
+        assert_eq!(
+            details.get("free_mb:/mnt/my backups").map(String::as_str),
+            Some("181312")
+        );
+        assert!(details.contains_key("used_percent:/"));
+
+        let service = service_from_json::<DiskService>(
+            "disk",
+            json!({
+                "thresholds": {"/": {"warning": 95.0, "critical": 98.0}},
+            }),
+        )
+        .expect("Failed to parse disk service");
+        let (status, summary, _, _) = service.evaluate(&filesystems);
+        assert_eq!(status, ServiceStatus::Warning);
+        assert_eq!(summary, "/var/lib/docker is 82.0% used");
+
+        let service =
+            service_from_json::<DiskService>("disk", json!({"mountpoints": ["/mnt/my backups"]}))
+                .expect("Failed to parse disk service");
+        let (status, summary, _, details) = service.evaluate(&filesystems);
+        assert_eq!(status, ServiceStatus::Ok);
+        assert_eq!(
+            summary,
+            "1 filesystems checked, the fullest is /mnt/my backups at 10.0%"
+        );
+        assert_eq!(details.len(), 2);
+
+        let service = service_from_json::<DiskService>("disk", json!({"mountpoints": ["/data"]}))
+            .expect("Failed to parse disk service");
+        let (status, summary, _, _) = service.evaluate(&filesystems);
+        assert_eq!(status, ServiceStatus::Critical);
+        assert_eq!(summary, "/data isn't mounted");
+    }
+
+    #[test]
+    fn test_disk_validate() {
+        assert!(service_from_json::<DiskService>("disk", json!({}))
+            .expect("Failed to parse disk service")
+            .validate()
+            .is_ok());
+        assert!(service_from_json::<DiskService>(
+            "disk",
+            json!({"warning": 95.0, "critical": 90.0})
+        )
+        .expect("Failed to parse disk service")
+        .validate()
+        .is_err());
+        assert!(service_from_json::<DiskService>(
+            "disk",
+            json!({
+                "thresholds": {"/backup": {"warning": 99.0}},
+            })
+        )
+        .expect("Failed to parse disk service")
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disk_local() {
+        let host = entities::host::Model {
+            name: LOCAL_SERVICE_HOST_NAME.to_string(),
+            hostname: LOCAL_SERVICE_HOST_NAME.to_string(),
+            check: crate::host::HostCheck::None,
+            ..entities::host::test_host()
+        };
+
+        let result =
+            service_from_json::<DiskService>("disk", json!({"warning": 100.0, "critical": 100.0}))
+                .expect("Failed to parse disk service")
+                .run(&host)
+                .await
+                .expect("Failed to run disk check");
+        assert!(
+            [ServiceStatus::Ok, ServiceStatus::Unknown].contains(&result.status),
+            "{:?}",
+            result
+        );
+    }
+}
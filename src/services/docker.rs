@@ -0,0 +1,425 @@
+//! Docker/Podman container checks
+//!
+//! Talks to the engine API over a local unix socket or TCP (optionally with TLS), and checks that
+//! the named containers are running and healthy.
+
+use std::path::PathBuf;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::host_variables::expand_host_variables;
+use super::prelude::*;
+use crate::prelude::*;
+
+/// The default Docker socket, for Podman use `unix:///run/podman/podman.sock`
+pub const DEFAULT_DOCKER_ENDPOINT: &str = "unix:///var/run/docker.sock";
+/// Default timeout for talking to the engine API, in seconds
+pub const DEFAULT_DOCKER_TIMEOUT: u64 = 10;
+
+fn default_endpoint() -> String {
+    DEFAULT_DOCKER_ENDPOINT.to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// Checks that containers are running (and healthy, if they have a health check)
+pub struct DockerService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// The engine API to connect to - `unix:///path/to/socket`, `tcp://host:port` or `https://host:port`, defaults to [DEFAULT_DOCKER_ENDPOINT]. You can use #HOSTNAME# to substitute the hostname
+    #[serde(default = "default_endpoint")]
+    pub endpoint: String,
+
+    /// The names (or IDs) of the containers which should be running
+    pub containers: Vec<String>,
+
+    /// CA cert file for TLS connections, setting this uses TLS for `tcp://` endpoints
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate for TLS connections, PEM format
+    pub client_cert: Option<PathBuf>,
+    /// Client key for TLS connections, PEM format
+    pub client_key: Option<PathBuf>,
+
+    /// Warn if a container has restarted more than this many times
+    pub max_restarts: Option<u64>,
+
+    /// Timeout for talking to the engine API, defaults to 10 seconds ([DEFAULT_DOCKER_TIMEOUT])
+    pub timeout: Option<u64>,
+}
+
+impl ConfigOverlay for DockerService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            endpoint: self.extract_string(value, "endpoint", &self.endpoint),
+            containers: self.extract_value(value, "containers", &self.containers)?,
+            ca_file: self.extract_value(value, "ca_file", &self.ca_file)?,
+            client_cert: self.extract_value(value, "client_cert", &self.client_cert)?,
+            client_key: self.extract_value(value, "client_key", &self.client_key)?,
+            max_restarts: self.extract_value(value, "max_restarts", &self.max_restarts)?,
+            timeout: self.extract_value(value, "timeout", &self.timeout)?,
+        }))
+    }
+}
+
+/// Container names end up in the request path, so keep them to what the engines allow
+fn valid_container_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ['_', '.', '-'].contains(&c))
+}
+
+/// Splits a raw HTTP response into the status code and body
+fn parse_http_response(response: &[u8]) -> Result<(u16, Vec<u8>), Error> {
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| Error::Generic("Invalid response from engine API".to_string()))?;
+    let headers = String::from_utf8_lossy(&response[..header_end]);
+    let status = headers
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| Error::Generic("Invalid status line from engine API".to_string()))?;
+    Ok((status, response[header_end + 4..].to_vec()))
+}
+
+/// Works out the status of a single container from its `inspect` output
+fn container_status(
+    name: &str,
+    inspect: &Value,
+    max_restarts: Option<u64>,
+) -> (ServiceStatus, String) {
+    let state = inspect.get("State");
+    let running = state
+        .and_then(|state| state.get("Running"))
+        .and_then(|running| running.as_bool())
+        .unwrap_or(false);
+    let state_text = state
+        .and_then(|state| state.get("Status"))
+        .and_then(|status| status.as_str())
+        .unwrap_or("unknown");
+    let health = state
+        .and_then(|state| state.get("Health"))
+        .and_then(|health| health.get("Status"))
+        .and_then(|status| status.as_str());
+    let restarts = inspect
+        .get("RestartCount")
+        .and_then(|count| count.as_u64())
+        .unwrap_or(0);
+
+    let mut status = match (running, health) {
+        (false, _) | (true, Some("unhealthy")) => ServiceStatus::Critical,
+        (true, Some("starting")) => ServiceStatus::Warning,
+        (true, _) => ServiceStatus::Ok,
+    };
+    if max_restarts.is_some_and(|max_restarts| restarts > max_restarts) {
+        status = status.max(ServiceStatus::Warning);
+    }
+
+    (
+        status,
+        format!(
+            "{}: {}, health={}, restarts={}",
+            name,
+            state_text,
+            health.unwrap_or("none"),
+            restarts
+        ),
+    )
+}
+
+impl DockerService {
+    fn use_tls(&self) -> bool {
+        self.ca_file.is_some() || self.client_cert.is_some()
+    }
+
+    /// Turns a `tcp://` or `https://` endpoint into a base URL for reqwest
+    fn base_url(&self, endpoint: &str) -> Result<String, Error> {
+        let scheme = if self.use_tls() { "https" } else { "http" };
+        if let Some(address) = endpoint.strip_prefix("tcp://") {
+            Ok(format!("{}://{}", scheme, address.trim_end_matches('/')))
+        } else if endpoint.starts_with("http://") || endpoint.starts_with("https://") {
+            Ok(endpoint.trim_end_matches('/').to_string())
+        } else {
+            Err(Error::Configuration(format!(
+                "Unsupported docker endpoint {}",
+                endpoint
+            )))
+        }
+    }
+
+    /// Plain HTTP/1.0 over the socket, so the engine closes the connection and doesn't chunk the response
+    async fn inspect_unix(&self, socket: &str, container: &str) -> Result<(u16, Vec<u8>), Error> {
+        let mut stream = tokio::net::UnixStream::connect(socket)
+            .await
+            .map_err(|err| Error::IoError(format!("Failed to connect to {}: {}", socket, err)))?;
+        stream
+            .write_all(
+                format!(
+                    "GET /containers/{}/json HTTP/1.0\r\nHost: docker\r\nUser-Agent: {}/{}\r\n\r\n",
+                    container,
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        parse_http_response(&response)
+    }
+
+    fn http_client(&self) -> Result<reqwest::Client, Error> {
+        let mut client = reqwest::ClientBuilder::new().user_agent(format!(
+            "{}/{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ));
+        if let Some(ca_file) = self.ca_file.as_ref() {
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(
+                &std::fs::read(ca_file).map_err(|err| {
+                    Error::IoError(format!(
+                        "Failed to read CA file {}: {}",
+                        ca_file.display(),
+                        err
+                    ))
+                })?,
+            )?);
+        }
+        match (self.client_cert.as_ref(), self.client_key.as_ref()) {
+            (Some(cert), Some(key)) => {
+                let mut pem = std::fs::read(cert).map_err(|err| {
+                    Error::IoError(format!("Failed to read {}: {}", cert.display(), err))
+                })?;
+                pem.extend(std::fs::read(key).map_err(|err| {
+                    Error::IoError(format!("Failed to read {}: {}", key.display(), err))
+                })?);
+                client = client.identity(reqwest::Identity::from_pem(&pem)?);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(Error::Configuration(
+                    "client_cert and client_key need to be set together".to_string(),
+                ))
+            }
+        }
+        client
+            .timeout(std::time::Duration::from_secs(
+                self.timeout.unwrap_or(DEFAULT_DOCKER_TIMEOUT),
+            ))
+            .build()
+            .map_err(Error::from)
+    }
+
+    /// Returns the HTTP status and body of the `inspect` call for each container
+    async fn inspect_containers(&self, endpoint: &str) -> Result<Vec<(u16, Vec<u8>)>, Error> {
+        let mut res = Vec::with_capacity(self.containers.len());
+        if let Some(socket) = endpoint.strip_prefix("unix://") {
+            let timeout =
+                std::time::Duration::from_secs(self.timeout.unwrap_or(DEFAULT_DOCKER_TIMEOUT));
+            for container in self.containers.iter() {
+                res.push(
+                    tokio::time::timeout(timeout, self.inspect_unix(socket, container))
+                        .await
+                        .map_err(|_| Error::Timeout)??,
+                );
+            }
+        } else {
+            let base_url = self.base_url(endpoint)?;
+            let client = self.http_client()?;
+            for container in self.containers.iter() {
+                let response = client
+                    .get(format!("{}/containers/{}/json", base_url, container))
+                    .send()
+                    .await?;
+                let status = response.status().as_u16();
+                res.push((status, response.bytes().await?.to_vec()));
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for DockerService {
+    fn validate(&self) -> Result<(), Error> {
+        if self.containers.is_empty() {
+            return Err(Error::Configuration(
+                "Docker service needs at least one container".to_string(),
+            ));
+        }
+        if let Some(container) = self
+            .containers
+            .iter()
+            .find(|container| !valid_container_name(container))
+        {
+            return Err(Error::Configuration(format!(
+                "Invalid container name: {}",
+                container
+            )));
+        }
+        Ok(())
+    }
+
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = chrono::Utc::now();
+
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        config.validate()?;
+        let endpoint = expand_host_variables(&config.endpoint, host, &host.hostname)?;
+
+        let responses = config.inspect_containers(&endpoint).await?;
+
+        let mut status = ServiceStatus::Ok;
+        let mut results = Vec::with_capacity(responses.len());
+        for (container, (http_status, body)) in config.containers.iter().zip(responses) {
+            let (container_status, text) = match http_status {
+                200 => {
+                    let inspect: Value = serde_json::from_slice(&body)?;
+                    container_status(container, &inspect, config.max_restarts)
+                }
+                404 => (
+                    ServiceStatus::Critical,
+                    format!("{}: container not found", container),
+                ),
+                _ => (
+                    ServiceStatus::Error,
+                    format!(
+                        "{}: engine API returned {}: {}",
+                        container,
+                        http_status,
+                        String::from_utf8_lossy(&body).trim()
+                    ),
+                ),
+            };
+            status = status.max(container_status);
+            results.push(text);
+        }
+
+        Ok(CheckResult {
+            timestamp: chrono::Utc::now(),
+            result_text: results.join("; "),
+            status,
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..Default::default()
+        })
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::host::test_host;
+
+    fn test_service() -> DockerService {
+        serde_json::from_value(json!({
+            "name": "containers",
+            "cron_schedule": "@hourly",
+            "containers": ["web", "db"],
+        }))
+        .expect("Failed to parse docker service")
+    }
+
+    #[test]
+    fn test_docker_config() {
+        let service = test_service();
+        assert_eq!(service.endpoint, DEFAULT_DOCKER_ENDPOINT);
+        assert!(service.validate().is_ok());
+
+        let mut value = Map::new();
+        value.insert("endpoint".to_string(), "tcp://#HOSTNAME#:2376".into());
+        value.insert("max_restarts".to_string(), 5.into());
+        let overlaid = service
+            .overlay_host_config(&value)
+            .expect("Failed to overlay config");
+        assert_eq!(overlaid.endpoint, "tcp://#HOSTNAME#:2376");
+        assert_eq!(overlaid.max_restarts, Some(5));
+        assert_eq!(
+            overlaid
+                .base_url("tcp://example.com:2375")
+                .expect("Failed to build url"),
+            "http://example.com:2375"
+        );
+
+        let service = DockerService {
+            containers: vec!["../../etc".to_string()],
+            ..test_service()
+        };
+        assert!(service.validate().is_err());
+        let service = DockerService {
+            containers: vec![],
+            ..test_service()
+        };
+        assert!(service.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let (status, body) = parse_http_response(
+            b"HTTP/1.0 404 Not Found\r\nContent-Type: application/json\r\n\r\n{\"message\":\"nope\"}",
+        )
+        .expect("Failed to parse response");
+        assert_eq!(status, 404);
+        assert_eq!(body, b"{\"message\":\"nope\"}");
+
+        assert!(parse_http_response(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_container_status() {
+        let inspect = json!({
+            "RestartCount": 2,
+            "State": {"Status": "running", "Running": true, "Health": {"Status": "healthy"}}
+        });
+        let (status, text) = container_status("web", &inspect, None);
+        assert_eq!(status, ServiceStatus::Ok);
+        assert_eq!(text, "web: running, health=healthy, restarts=2");
+
+        let (status, _) = container_status("web", &inspect, Some(1));
+        assert_eq!(status, ServiceStatus::Warning);
+
+        let inspect = json!({
+            "RestartCount": 0,
+            "State": {"Status": "running", "Running": true, "Health": {"Status": "unhealthy"}}
+        });
+        assert_eq!(
+            container_status("web", &inspect, None).0,
+            ServiceStatus::Critical
+        );
+
+        let inspect = json!({"RestartCount": 0, "State": {"Status": "exited", "Running": false}});
+        let (status, text) = container_status("db", &inspect, None);
+        assert_eq!(status, ServiceStatus::Critical);
+        assert_eq!(text, "db: exited, health=none, restarts=0");
+    }
+
+    #[tokio::test]
+    async fn test_docker_missing_socket() {
+        let service = DockerService {
+            endpoint: "unix:///this/socket/does/not/exist.sock".to_string(),
+            ..test_service()
+        };
+        assert!(service.run(&test_host()).await.is_err());
+    }
+}
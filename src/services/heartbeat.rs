@@ -0,0 +1,113 @@
+//! Heartbeat (dead man's switch) services, which go Critical if their ping URL isn't hit often enough
+
+use super::prelude::*;
+use crate::prelude::*;
+
+/// How long past `interval_minutes` a heartbeat can be late before it's Critical
+pub const DEFAULT_HEARTBEAT_GRACE_MINUTES: u32 = 5;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// A service that expects something to ping it regularly, eg a cron job calling `curl` when it finishes
+pub struct HeartbeatService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service, heartbeat services aren't run on it
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// How often a ping is expected
+    pub interval_minutes: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How late a ping can be before the check goes Critical, defaults to [DEFAULT_HEARTBEAT_GRACE_MINUTES]
+    pub grace_minutes: Option<u32>,
+}
+
+impl HeartbeatService {
+    /// How long the check can go without a ping before it's Critical
+    pub fn deadline_minutes(&self) -> u32 {
+        self.interval_minutes.saturating_add(
+            self.grace_minutes
+                .unwrap_or(DEFAULT_HEARTBEAT_GRACE_MINUTES),
+        )
+    }
+}
+
+impl ConfigOverlay for HeartbeatService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            interval_minutes: self.extract_value(
+                value,
+                "interval_minutes",
+                &self.interval_minutes,
+            )?,
+            grace_minutes: self.extract_value(value, "grace_minutes", &self.grace_minutes)?,
+        }))
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for HeartbeatService {
+    async fn run(&self, _host: &entities::host::Model) -> Result<CheckResult, Error> {
+        Err(Error::Generic(format!(
+            "{} is a heartbeat service, it's updated when its ping URL is called",
+            self.name
+        )))
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.interval_minutes == 0 {
+            return Err(Error::Configuration(format!(
+                "{}: interval_minutes has to be more than 0",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::host::test_host;
+
+    #[tokio::test]
+    async fn test_heartbeat_service() {
+        let service: HeartbeatService = serde_json::from_value(json!({
+            "name": "heartbeat",
+            "cron_schedule": "@daily",
+            "interval_minutes": 60,
+        }))
+        .expect("Failed to parse heartbeat service");
+        assert!(service.run(&test_host()).await.is_err());
+        assert!(service.as_json_pretty(&test_host()).is_ok());
+        assert!(service.validate().is_ok());
+        assert_eq!(
+            service.deadline_minutes(),
+            60 + DEFAULT_HEARTBEAT_GRACE_MINUTES
+        );
+
+        let service = HeartbeatService {
+            interval_minutes: 0,
+            grace_minutes: Some(1),
+            ..service
+        };
+        assert!(service.validate().is_err());
+        assert_eq!(service.deadline_minutes(), 1);
+    }
+}
@@ -0,0 +1,281 @@
+//! Substitutes `#VARIABLE#` host variables, and `{{ name }}` references to a host's `vars`, into service config values
+
+use crate::db::entities::host;
+use crate::prelude::*;
+
+/// The host variables that can be used in service config
+pub const HOST_VARIABLES: [&str; 2] = ["HOSTNAME", "HOST_NAME"];
+
+/// Finds the `#VARIABLE#` references in a string, returning the variable names
+pub fn find_host_variables(value: &str) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('#') {
+        let after = &rest[start + 1..];
+        match after.find('#') {
+            Some(end)
+                if end > 0
+                    && after[..end]
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') =>
+            {
+                res.push(&after[..end]);
+                rest = &after[end + 1..];
+            }
+            // not a variable, so the closing # might start the next one
+            _ => rest = after,
+        }
+    }
+    res
+}
+
+/// Walks a config value, returning every host variable referenced in its strings
+pub fn find_host_variables_in_value(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(value) => find_host_variables(value)
+            .into_iter()
+            .map(|variable| variable.to_string())
+            .collect(),
+        Value::Array(values) => values
+            .iter()
+            .flat_map(find_host_variables_in_value)
+            .collect(),
+        Value::Object(values) => values
+            .values()
+            .flat_map(find_host_variables_in_value)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Substitutes the host variables into a string, `hostname` is the address being checked which can differ from the host's
+pub fn expand_host_variables(
+    value: &str,
+    host: &host::Model,
+    hostname: &str,
+) -> Result<String, Error> {
+    let mut res = value.to_string();
+    for variable in find_host_variables(value) {
+        let replacement = match variable {
+            "HOSTNAME" => hostname,
+            "HOST_NAME" => host.name.as_str(),
+            _ => {
+                return Err(Error::UnknownHostVariable(
+                    host.name.clone(),
+                    variable.to_string(),
+                ))
+            }
+        };
+        res = res.replace(&format!("#{}#", variable), replacement);
+    }
+    Ok(res)
+}
+
+/// Finds the `{{ name }}` references to host vars in a string, returning the names
+pub fn find_host_vars(value: &str) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        let name = after[..end].trim();
+        if !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            res.push(name);
+        }
+        rest = &after[end + 2..];
+    }
+    res
+}
+
+/// Walks a config value, returning every host var referenced in its strings
+pub fn find_host_vars_in_value(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(value) => find_host_vars(value)
+            .into_iter()
+            .map(|name| name.to_string())
+            .collect(),
+        Value::Array(values) => values.iter().flat_map(find_host_vars_in_value).collect(),
+        Value::Object(values) => values.values().flat_map(find_host_vars_in_value).collect(),
+        _ => vec![],
+    }
+}
+
+/// Fills in `{{ name }}` references to the host's `vars`, a string that's only a reference becomes the var's value so numbers stay numbers
+pub fn expand_host_vars(value: &Value, host: &host::Model) -> Result<Value, Error> {
+    let lookup = |name: &str| {
+        host.vars
+            .get(name)
+            .ok_or_else(|| Error::UnknownHostVar(host.name.clone(), name.to_string()))
+    };
+    match value {
+        Value::String(text) => {
+            let names = find_host_vars(text);
+            if let [name] = names.as_slice() {
+                if text
+                    .trim()
+                    .strip_prefix("{{")
+                    .and_then(|rest| rest.strip_suffix("}}"))
+                    .map(str::trim)
+                    == Some(*name)
+                {
+                    return lookup(name).cloned();
+                }
+            }
+            let mut res = text.clone();
+            for name in names {
+                let replacement = match lookup(name)? {
+                    Value::String(replacement) => replacement.clone(),
+                    other => other.to_string(),
+                };
+                let mut expanded = String::new();
+                let mut rest = res.as_str();
+                while let Some(start) = rest.find("{{") {
+                    let after = &rest[start + 2..];
+                    match after.find("}}") {
+                        Some(end) if after[..end].trim() == name => {
+                            expanded.push_str(&rest[..start]);
+                            expanded.push_str(&replacement);
+                            rest = &after[end + 2..];
+                        }
+                        _ => {
+                            expanded.push_str(&rest[..start + 2]);
+                            rest = after;
+                        }
+                    }
+                }
+                expanded.push_str(rest);
+                res = expanded;
+            }
+            Ok(Value::String(res))
+        }
+        Value::Array(values) => Ok(Value::Array(
+            values
+                .iter()
+                .map(|value| expand_host_vars(value, host))
+                .collect::<Result<_, _>>()?,
+        )),
+        Value::Object(values) => Ok(Value::Object(
+            values
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), expand_host_vars(value, host)?)))
+                .collect::<Result<_, Error>>()?,
+        )),
+        other => Ok(other.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::host::test_host;
+
+    #[test]
+    fn test_find_host_variables() {
+        assert_eq!(
+            find_host_variables("ssh #HOSTNAME# -p #PORT_2#"),
+            vec!["HOSTNAME", "PORT_2"]
+        );
+        assert!(find_host_variables("# not a variable #").is_empty());
+        assert!(find_host_variables("##").is_empty());
+        assert_eq!(find_host_variables("#no #HOSTNAME#"), vec!["HOSTNAME"]);
+        assert_eq!(
+            find_host_variables_in_value(&json!({
+                "command_line": "ping #HOSTNAME#",
+                "environment": {"NAME": "#HOST_NAME#"},
+                "args": ["#MISSING#", 1]
+            }))
+            .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_expand_host_variables() {
+        let host = test_host();
+        assert_eq!(
+            expand_host_variables("#HOSTNAME#/#HOST_NAME#", &host, "example.com")
+                .expect("Failed to expand"),
+            format!("example.com/{}", host.name)
+        );
+        assert_eq!(
+            expand_host_variables("ping #NOPE#", &host, "example.com"),
+            Err(Error::UnknownHostVariable(
+                host.name.clone(),
+                "NOPE".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_expand_host_vars() {
+        let host = entities::host::Model {
+            vars: json!({"app_port": 8443, "env": "prod"}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
+            ..test_host()
+        };
+        assert_eq!(
+            find_host_vars("{{ app_port }}/{{env}} {{ not a var }} {{"),
+            vec!["app_port", "env"]
+        );
+        assert_eq!(
+            expand_host_vars(&json!("{{ app_port }}"), &host).expect("Failed to expand"),
+            json!(8443)
+        );
+        assert_eq!(
+            expand_host_vars(
+                &json!({"url": ["https://#HOSTNAME#:{{ app_port }}/{{env}}/{{ env }}"], "jitter": 5}),
+                &host
+            )
+            .expect("Failed to expand"),
+            json!({"url": ["https://#HOSTNAME#:8443/prod/prod"], "jitter": 5})
+        );
+        assert_eq!(
+            expand_host_vars(&json!("{{ missing }}"), &host),
+            Err(Error::UnknownHostVar(
+                host.name.clone(),
+                "missing".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_host_vars_overlay() {
+        use crate::services::passive::PassiveService;
+        use crate::services::ConfigOverlay;
+
+        let service: PassiveService = serde_json::from_value(json!({
+            "name": "{{ env }} backup",
+            "cron_schedule": "@daily",
+        }))
+        .expect("Failed to parse service");
+        let host = entities::host::Model {
+            vars: json!({"env": "prod", "jitter": 30}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
+            config: json!({"backup": {"jitter": "{{ jitter }}"}}),
+            ..test_host()
+        };
+        let config = service
+            .overlay_host_config(
+                &service
+                    .get_host_config("backup", &host)
+                    .expect("Failed to get host config"),
+            )
+            .expect("Failed to overlay host config");
+        assert_eq!(config.name, "prod backup");
+        assert_eq!(config.jitter, Some(30));
+    }
+}
@@ -1,13 +1,21 @@
 //! HTTP Checks
 
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroU16;
 use std::path::PathBuf;
 
 use super::prelude::*;
+use super::root_store::{load_ca_file, RootStore};
+use super::threshold::{self, NumericThreshold};
+use super::timings::probe;
+use crate::host::address::{combine, target_addresses, AddressFamily};
 use crate::prelude::*;
+use regex::Regex;
 use reqwest::redirect::Policy;
 use reqwest::{Response, StatusCode};
+use rustls::pki_types::ServerName;
 use schemars::JsonSchema;
 
 #[derive(Debug, Deserialize, Serialize, Default, Copy, Clone, Eq, PartialEq)]
@@ -75,6 +83,178 @@ fn default_true() -> bool {
     true
 }
 
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
+/// Credentials for HTTP basic auth
+pub struct BasicAuth {
+    /// Username to send
+    pub username: String,
+    /// Password to send, masked when the config's displayed
+    #[serde(serialize_with = "crate::serde::secret::serialize")]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Default)]
+/// Checks a value in a JSON response body, found with a JSONPath expression like `$.items[0].status`
+pub struct JsonAssertion {
+    /// JSONPath expression, supports `.field`, `['field']` and `[index]`
+    pub path: String,
+    /// The value has to equal this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<Value>,
+    /// The value has to be a number less than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub less_than: Option<f64>,
+    /// The value has to be a number greater than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub greater_than: Option<f64>,
+    /// Whether the value has to exist (or not), defaults to true
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exists: Option<bool>,
+}
+
+impl JsonAssertion {
+    /// Returns a description of the failure, if it fails
+    fn check(&self, body: &Value) -> Result<Option<String>, Error> {
+        let found = json_path(body, &self.path)?;
+        let should_exist = self.exists.unwrap_or(true);
+        let Some(found) = found else {
+            return Ok(should_exist.then(|| format!("{} not found in response", self.path)));
+        };
+        if !should_exist {
+            return Ok(Some(format!("{} was found in response", self.path)));
+        }
+        if let Some(expected) = self.equals.as_ref() {
+            if found != expected {
+                return Ok(Some(format!(
+                    "{} is {}, expected {}",
+                    self.path, found, expected
+                )));
+            }
+        }
+        if self.less_than.is_some() || self.greater_than.is_some() {
+            let Some(number) = found.as_f64() else {
+                return Ok(Some(format!("{} is {}, not a number", self.path, found)));
+            };
+            if let Some(less_than) = self.less_than {
+                if number >= less_than {
+                    return Ok(Some(format!(
+                        "{} is {}, expected less than {}",
+                        self.path, number, less_than
+                    )));
+                }
+            }
+            if let Some(greater_than) = self.greater_than {
+                if number <= greater_than {
+                    return Ok(Some(format!(
+                        "{} is {}, expected greater than {}",
+                        self.path, number, greater_than
+                    )));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum JsonPathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Parses the subset of JSONPath we support, `$` followed by `.field`, `['field']` or `[index]`
+fn parse_json_path(path: &str) -> Result<Vec<JsonPathSegment>, Error> {
+    let invalid = || Error::Configuration(format!("Invalid JSONPath expression: {}", path));
+    let mut rest = path.trim().strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = Vec::new();
+    while let Some(next) = rest.chars().next() {
+        match next {
+            '.' => {
+                let key_end = rest[1..]
+                    .find(['.', '['])
+                    .map(|index| index + 1)
+                    .unwrap_or(rest.len());
+                let key = &rest[1..key_end];
+                if key.is_empty() {
+                    return Err(invalid());
+                }
+                segments.push(JsonPathSegment::Key(key.to_string()));
+                rest = &rest[key_end..];
+            }
+            '[' => {
+                let end = rest.find(']').ok_or_else(invalid)?;
+                let inner = rest[1..end].trim();
+                let quoted = inner
+                    .strip_prefix('\'')
+                    .and_then(|inner| inner.strip_suffix('\''))
+                    .or_else(|| {
+                        inner
+                            .strip_prefix('"')
+                            .and_then(|inner| inner.strip_suffix('"'))
+                    });
+                segments.push(match quoted {
+                    Some(key) => JsonPathSegment::Key(key.to_string()),
+                    None => JsonPathSegment::Index(inner.parse().map_err(|_| invalid())?),
+                });
+                rest = &rest[end + 1..];
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(segments)
+}
+
+/// Finds the value at a JSONPath expression, see [parse_json_path] for what's supported
+pub(crate) fn json_path<'a>(value: &'a Value, path: &str) -> Result<Option<&'a Value>, Error> {
+    let mut current = value;
+    for segment in parse_json_path(path)? {
+        let next = match segment {
+            JsonPathSegment::Key(key) => current.get(&key),
+            JsonPathSegment::Index(index) => current.get(index),
+        };
+        match next {
+            Some(next) => current = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// What the HTTP check does when it gets a redirect
+pub enum RedirectMode {
+    /// Don't follow redirects, the redirect response is checked as-is
+    #[default]
+    None,
+    /// Follow up to this many redirects, and check the final response
+    Follow(usize),
+    /// Expect a redirect to this location, without following it
+    Expect(String),
+}
+
+impl RedirectMode {
+    /// Builds the client's redirect policy, counting how many redirects were followed
+    fn policy(&self, followed: Arc<std::sync::atomic::AtomicUsize>) -> Policy {
+        match self {
+            Self::Follow(max) => {
+                let max = *max;
+                Policy::custom(move |attempt| {
+                    // previous() includes the original URL
+                    let redirects = attempt.previous().len();
+                    if redirects > max {
+                        attempt.error(format!("Too many redirects, stopped after {}", max))
+                    } else {
+                        followed.store(redirects, std::sync::atomic::Ordering::SeqCst);
+                        attempt.follow()
+                    }
+                })
+            }
+            Self::None | Self::Expect(_) => Policy::none(),
+        }
+    }
+}
+
 /// Default timeout for HTTP checks
 pub const DEFAULT_TIMEOUT: u64 = 10;
 /// Default expected status code for HTTP checks
@@ -121,14 +301,212 @@ pub struct HttpService {
     /// CA cert file to use
     pub ca_file: Option<PathBuf>,
 
+    /// Which roots to trust: `webpki` (the default), `system` or `file:<path>` for internal PKI
+    #[serde(default)]
+    pub root_store: RootStore,
+
     /// Actually use HTTP, not HTTPS...
     pub use_http: Option<bool>,
 
     /// Add random jitter in 0..n seconds to the check
     pub jitter: Option<u16>,
+
+    /// Extra headers to send, values of credential-looking headers are masked when the config's displayed
+    #[serde(default, serialize_with = "crate::serde::secret::serialize_headers")]
+    pub headers: HashMap<String, String>,
+
+    /// Request body to send
+    pub body: Option<String>,
+
+    /// File to read the request body from, can't be used with `body`
+    pub body_file: Option<PathBuf>,
+
+    /// Content-Type header for the body
+    pub content_type: Option<String>,
+
+    /// HTTP basic auth credentials, can't be used with `bearer_token`
+    pub basic_auth: Option<BasicAuth>,
+
+    /// Bearer token to send in the Authorization header
+    #[serde(default, serialize_with = "crate::serde::secret::serialize")]
+    pub bearer_token: Option<String>,
+
+    /// Regular expression the body has to match
+    pub body_regex: Option<String>,
+
+    /// Assertions on values in a JSON response body
+    #[serde(default)]
+    pub json_assertions: Vec<JsonAssertion>,
+
+    /// Headers the response has to include, with a value it has to equal or `null` if it just has to be there
+    #[serde(default)]
+    pub expected_headers: HashMap<String, Option<String>>,
+
+    /// Warn if the response takes longer than this many milliseconds, `warn_latency_ms` works too
+    #[serde(alias = "warn_latency_ms")]
+    pub response_time_warn_ms: Option<u64>,
+
+    /// Critical if the response takes longer than this many milliseconds, `crit_latency_ms` works too
+    #[serde(alias = "crit_latency_ms")]
+    pub response_time_critical_ms: Option<u64>,
+
+    /// What to do with redirects: `"none"` (the default), `{"follow": n}` or `{"expect": "https://example.com/"}`
+    #[serde(default)]
+    pub redirect: RedirectMode,
+
+    /// The URL the check has to end up at after following redirects
+    pub expected_final_url: Option<String>,
+
+    /// How many redirects have to be followed to get to the final URL
+    pub expected_redirects: Option<usize>,
+
+    /// Warn when the response body's different to the last successful run's, and show what changed
+    #[serde(default)]
+    pub detect_changes: bool,
+
+    /// Warning and critical thresholds on numbers in the response body, see [super::threshold]
+    #[serde(default)]
+    pub thresholds: Vec<NumericThreshold>,
+
+    /// Which IP versions to connect over, defaults to the host's `address_family`
+    #[serde(default)]
+    pub address_family: Option<AddressFamily>,
+
+    /// Record how long the DNS lookup, connection and TLS handshake took with each result, from a second connection
+    #[serde(default)]
+    pub debug_capture: bool,
 }
 
 impl HttpService {
+    /// Adds the headers, auth and body to the request
+    async fn build_request(
+        &self,
+        client: &reqwest::Client,
+        url: String,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let mut request = client.request(self.http_method.into(), url);
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        if let Some(content_type) = self.content_type.as_ref() {
+            request = request.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+        if let Some(basic_auth) = self.basic_auth.as_ref() {
+            request = request.basic_auth(&basic_auth.username, basic_auth.password.as_ref());
+        }
+        if let Some(bearer_token) = self.bearer_token.as_ref() {
+            request = request.bearer_auth(bearer_token);
+        }
+        if let Some(body) = self.body.as_ref() {
+            request = request.body(body.clone());
+        } else if let Some(body_file) = self.body_file.as_ref() {
+            request = request.body(tokio::fs::read(body_file).await.map_err(|err| {
+                Error::Generic(format!(
+                    "Failed to read body file {}: {}",
+                    body_file.display(),
+                    err
+                ))
+            })?);
+        }
+        Ok(request)
+    }
+
+    /// Makes the request, connecting to `address` if it's set, instead of whatever the hostname resolves to
+    async fn check_address(
+        &self,
+        config: &HttpService,
+        url: &str,
+        hostname: &str,
+        address: Option<IpAddr>,
+    ) -> Result<CheckResult, Error> {
+        let redirects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut client = reqwest::ClientBuilder::new()
+            .user_agent(format!(
+                "{}/{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ))
+            .danger_accept_invalid_certs(!config.validate_tls)
+            .danger_accept_invalid_hostnames(!config.validate_tls)
+            .redirect(config.redirect.policy(redirects.clone()));
+
+        if let Some(address) = address {
+            // the port comes from the URL
+            client = client.resolve(hostname, SocketAddr::new(address, 0));
+        }
+        if let Some(roots) = config.root_store.certificates()? {
+            debug!(
+                "Trusting {} instead of the built-in roots",
+                config.root_store
+            );
+            client = client.tls_built_in_root_certs(false);
+            for cert in roots.iter() {
+                client = client.add_root_certificate(reqwest::Certificate::from_der(cert)?);
+            }
+        }
+        if let Some(ca_file) = config.ca_file.as_ref() {
+            debug!("adding CA file");
+            for cert in load_ca_file(ca_file)?.iter() {
+                client = client.add_root_certificate(reqwest::Certificate::from_der(cert)?);
+            }
+        }
+        let client = client
+            .connect_timeout(std::time::Duration::from_secs(
+                config.connect_timeout.unwrap_or(DEFAULT_TIMEOUT),
+            ))
+            .build()?;
+
+        let request = config.build_request(&client, url.to_string()).await?;
+        let request_start = chrono::Utc::now();
+        let mut details = BTreeMap::new();
+        let (result_text, status, body) = match request.send().await {
+            Ok(val) => {
+                let elapsed = chrono::Utc::now() - request_start;
+                details.insert(
+                    threshold::LATENCY_DETAIL.to_string(),
+                    elapsed.num_milliseconds().to_string(),
+                );
+                let redirects = redirects.load(std::sync::atomic::Ordering::SeqCst);
+                self.validate_response(val, Box::new(config.clone()), elapsed, redirects)
+                    .await?
+            }
+            Err(err) => (format!("{:?}", err), ServiceStatus::Critical, None),
+        };
+
+        let mut result = CheckResult {
+            result_text,
+            status,
+            details,
+            ..Default::default()
+        };
+        if config.debug_capture {
+            // reqwest doesn't say how long each step took, so time a connection of our own
+            let tls = !config.use_http.unwrap_or(false);
+            let port = config
+                .port
+                .map(u16::from)
+                .unwrap_or(if tls { 443 } else { 80 });
+            let server_name = match tls {
+                true => ServerName::try_from(hostname.to_string()).ok(),
+                false => None,
+            };
+            let timings = probe(
+                hostname,
+                port,
+                address,
+                server_name,
+                std::time::Duration::from_secs(config.connect_timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            )
+            .await;
+            result = result.with_diagnostics(true, timings.diagnostics());
+        }
+        Ok(match body {
+            Some(body) => threshold::apply(&config.thresholds, result, &body)
+                .with_compare_output(config.detect_changes, &body),
+            None => result,
+        })
+    }
+
     /// Get the expected status code for the service and throw an error if it's bad
     fn expected_status_code(&self, client_config: &Self) -> Result<reqwest::StatusCode, Error> {
         reqwest::StatusCode::from_u16(
@@ -150,10 +528,71 @@ impl HttpService {
         &self,
         response: Response,
         client_config: Box<HttpService>,
-    ) -> Result<(String, ServiceStatus), Error> {
+        elapsed: TimeDelta,
+        redirects: usize,
+    ) -> Result<(String, ServiceStatus, Option<String>), Error> {
+        if let RedirectMode::Expect(expected_location) = &client_config.redirect {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string());
+            if !response.status().is_redirection() {
+                return Ok((
+                    format!(
+                        "Expected a redirect to '{}', got status code {}",
+                        expected_location,
+                        response.status()
+                    ),
+                    ServiceStatus::Critical,
+                    None,
+                ));
+            }
+            if location.as_deref() != Some(expected_location.as_str()) {
+                return Ok((
+                    format!(
+                        "Redirected to '{}', expected '{}'",
+                        location.unwrap_or_default(),
+                        expected_location
+                    ),
+                    ServiceStatus::Critical,
+                    None,
+                ));
+            }
+        }
+
+        if let Some(expected_final_url) = client_config.expected_final_url.as_ref() {
+            if response.url().as_str() != expected_final_url {
+                return Ok((
+                    format!(
+                        "Ended up at '{}', expected '{}'",
+                        response.url(),
+                        expected_final_url
+                    ),
+                    ServiceStatus::Critical,
+                    None,
+                ));
+            }
+        }
+
+        if let Some(expected_redirects) = client_config.expected_redirects {
+            if redirects != expected_redirects {
+                return Ok((
+                    format!(
+                        "Followed {} redirects, expected {}",
+                        redirects, expected_redirects
+                    ),
+                    ServiceStatus::Critical,
+                    None,
+                ));
+            }
+        }
+
         let expected_status_code = self.expected_status_code(&client_config)?;
 
-        if response.status() != expected_status_code {
+        // when we're expecting a redirect, any redirect status is fine unless a specific one's been asked for
+        let expecting_redirect = matches!(client_config.redirect, RedirectMode::Expect(_))
+            && client_config.http_status.is_none();
+        if !expecting_redirect && response.status() != expected_status_code {
             return Ok((
                 format!(
                     "Expected status code {}, got {}",
@@ -161,30 +600,132 @@ impl HttpService {
                     response.status()
                 ),
                 ServiceStatus::Critical,
+                None,
             ));
         };
 
-        let mut body: String = String::new();
-
-        if let Some(expected_string) = client_config.contains_string.as_ref() {
-            body = response.text().await?;
-            if !body.contains(expected_string) {
-                debug!("Couldn't find {} in boxy", expected_string);
-                return Ok((
-                    format!("Expected string '{}' not found in body", expected_string),
-                    ServiceStatus::Critical,
-                ));
-            } else {
-                debug!("Found '{}' in body", expected_string);
+        for (name, expected) in client_config.expected_headers.iter() {
+            match (response.headers().get(name), expected) {
+                (None, _) => {
+                    return Ok((
+                        format!("Expected header '{}' not found", name),
+                        ServiceStatus::Critical,
+                        None,
+                    ))
+                }
+                (Some(value), Some(expected)) if value.to_str().ok() != Some(expected.as_str()) => {
+                    return Ok((
+                        format!(
+                            "Header '{}' is '{}', expected '{}'",
+                            name,
+                            String::from_utf8_lossy(value.as_bytes()),
+                            expected
+                        ),
+                        ServiceStatus::Critical,
+                        None,
+                    ))
+                }
+                _ => {}
             }
-        } else {
+        }
+
+        let needs_body = client_config.contains_string.is_some()
+            || client_config.body_regex.is_some()
+            || !client_config.json_assertions.is_empty()
+            || client_config.detect_changes
+            || !client_config.thresholds.is_empty();
+        let mut response_body = None;
+        if needs_body {
+            let body = response.text().await?;
             trace!("{}", body);
+            response_body = Some(body.clone());
+
+            if let Some(expected_string) = client_config.contains_string.as_ref() {
+                if !body.contains(expected_string) {
+                    debug!("Couldn't find {} in body", expected_string);
+                    return Ok((
+                        format!("Expected string '{}' not found in body", expected_string),
+                        ServiceStatus::Critical,
+                        None,
+                    ));
+                } else {
+                    debug!("Found '{}' in body", expected_string);
+                }
+            }
+
+            if let Some(body_regex) = client_config.body_regex.as_ref() {
+                let regex = Regex::new(body_regex).map_err(|err| {
+                    Error::Configuration(format!("Invalid body_regex {}: {}", body_regex, err))
+                })?;
+                if !regex.is_match(&body) {
+                    return Ok((
+                        format!("Body doesn't match regex '{}'", body_regex),
+                        ServiceStatus::Critical,
+                        None,
+                    ));
+                }
+            }
+
+            if !client_config.json_assertions.is_empty() {
+                let body: Value = match serde_json::from_str(&body) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        return Ok((
+                            format!("Failed to parse body as JSON: {}", err),
+                            ServiceStatus::Critical,
+                            None,
+                        ))
+                    }
+                };
+                let failures = client_config
+                    .json_assertions
+                    .iter()
+                    .map(|assertion| assertion.check(&body))
+                    .collect::<Result<Vec<_>, Error>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>();
+                if !failures.is_empty() {
+                    return Ok((failures.join(", "), ServiceStatus::Critical, None));
+                }
+            }
+        }
+
+        let elapsed_ms = elapsed.num_milliseconds().max(0) as u64;
+        if let Some((status, message)) = threshold::latency(
+            elapsed_ms,
+            client_config.response_time_warn_ms,
+            client_config.response_time_critical_ms,
+        ) {
+            return Ok((
+                format!("Response took {}ms, {}", elapsed_ms, message),
+                status,
+                response_body,
+            ));
         }
 
-        Ok(("OK".to_string(), ServiceStatus::Ok))
+        Ok(("OK".to_string(), ServiceStatus::Ok, response_body))
     }
 }
 
+/// Merges the results' key/values, prefixing the keys with the address they came from
+fn by_address(
+    results: &[(Option<IpAddr>, CheckResult)],
+    values: impl Fn(&CheckResult) -> &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    results
+        .iter()
+        .flat_map(|(address, result)| {
+            values(result)
+                .iter()
+                .map(move |(key, value)| match address {
+                    Some(address) => (format!("{} {}", address, key), value.clone()),
+                    None => (key.clone(), value.clone()),
+                })
+        })
+        .collect()
+}
+
 #[tokio::test]
 async fn test_overlay_host_config() {
     let _ = test_setup().await.expect("Failed to setup test");
@@ -203,7 +744,26 @@ async fn test_overlay_host_config() {
         use_http: None,
         contains_string: None,
         ca_file: None,
+        root_store: Default::default(),
         jitter: None,
+        headers: HashMap::new(),
+        body: None,
+        body_file: None,
+        content_type: None,
+        basic_auth: None,
+        bearer_token: None,
+        body_regex: None,
+        json_assertions: Vec::new(),
+        expected_headers: HashMap::new(),
+        response_time_warn_ms: None,
+        response_time_critical_ms: None,
+        redirect: RedirectMode::None,
+        expected_final_url: None,
+        expected_redirects: None,
+        detect_changes: false,
+        thresholds: Vec::new(),
+        address_family: None,
+        debug_capture: false,
     };
     let mut value = Map::new();
     value.insert("port".to_string(), 12345.into());
@@ -257,8 +817,51 @@ impl ConfigOverlay for HttpService {
             port: self.extract_value(value, "port", &self.port)?,
             contains_string: self.extract_value(value, "contains_string", &self.contains_string)?,
             ca_file: self.extract_value(value, "ca_file", &self.ca_file)?,
+            root_store: self.extract_value(value, "root_store", &self.root_store)?,
             use_http: self.extract_value(value, "use_http", &self.use_http)?,
             jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            headers: self.extract_value(value, "headers", &self.headers)?,
+            body: self.extract_value(value, "body", &self.body)?,
+            body_file: self.extract_value(value, "body_file", &self.body_file)?,
+            content_type: self.extract_value(value, "content_type", &self.content_type)?,
+            basic_auth: self.extract_value(value, "basic_auth", &self.basic_auth)?,
+            bearer_token: self.extract_value(value, "bearer_token", &self.bearer_token)?,
+            body_regex: self.extract_value(value, "body_regex", &self.body_regex)?,
+            json_assertions: self.extract_value(value, "json_assertions", &self.json_assertions)?,
+            expected_headers: self.extract_value(
+                value,
+                "expected_headers",
+                &self.expected_headers,
+            )?,
+            response_time_warn_ms: self.extract_value(
+                value,
+                "warn_latency_ms",
+                &self.extract_value(value, "response_time_warn_ms", &self.response_time_warn_ms)?,
+            )?,
+            response_time_critical_ms: self.extract_value(
+                value,
+                "crit_latency_ms",
+                &self.extract_value(
+                    value,
+                    "response_time_critical_ms",
+                    &self.response_time_critical_ms,
+                )?,
+            )?,
+            redirect: self.extract_value(value, "redirect", &self.redirect)?,
+            expected_final_url: self.extract_value(
+                value,
+                "expected_final_url",
+                &self.expected_final_url,
+            )?,
+            expected_redirects: self.extract_value(
+                value,
+                "expected_redirects",
+                &self.expected_redirects,
+            )?,
+            detect_changes: self.extract_bool(value, "detect_changes", self.detect_changes),
+            thresholds: self.extract_value(value, "thresholds", &self.thresholds)?,
+            address_family: self.extract_value(value, "address_family", &self.address_family)?,
+            debug_capture: self.extract_bool(value, "debug_capture", self.debug_capture),
         }))
     }
 }
@@ -274,6 +877,83 @@ impl ServiceTrait for HttpService {
                 )));
             }
         }
+        if self.body.is_some() && self.body_file.is_some() {
+            return Err(Error::Configuration(
+                "Only one of body and body_file can be set".to_string(),
+            ));
+        }
+        if self.basic_auth.is_some() && self.bearer_token.is_some() {
+            return Err(Error::Configuration(
+                "Only one of basic_auth and bearer_token can be set".to_string(),
+            ));
+        }
+        for (name, value) in self.headers.iter() {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(value).is_err()
+            {
+                return Err(Error::Configuration(format!(
+                    "Invalid HTTP header: {}",
+                    name
+                )));
+            }
+        }
+        if let Some(body_regex) = self.body_regex.as_ref() {
+            Regex::new(body_regex).map_err(|err| {
+                Error::Configuration(format!("Invalid body_regex {}: {}", body_regex, err))
+            })?;
+        }
+        for assertion in self.json_assertions.iter() {
+            parse_json_path(&assertion.path)?;
+        }
+        if let (Some(warn), Some(critical)) =
+            (self.response_time_warn_ms, self.response_time_critical_ms)
+        {
+            if warn > critical {
+                return Err(Error::Configuration(
+                    "response_time_warn_ms can't be more than response_time_critical_ms"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(expected_redirects) = self.expected_redirects {
+            match self.redirect {
+                RedirectMode::Follow(max) if expected_redirects <= max => {}
+                RedirectMode::Follow(max) => {
+                    return Err(Error::Configuration(format!(
+                        "expected_redirects is {} but only {} redirects will be followed",
+                        expected_redirects, max
+                    )))
+                }
+                _ if expected_redirects == 0 => {}
+                _ => {
+                    return Err(Error::Configuration(
+                        "expected_redirects needs redirect to be set to follow".to_string(),
+                    ))
+                }
+            }
+        }
+        if let Some(expected_final_url) = self.expected_final_url.as_ref() {
+            reqwest::Url::parse(expected_final_url).map_err(|err| {
+                Error::Configuration(format!(
+                    "Invalid expected_final_url {}: {}",
+                    expected_final_url, err
+                ))
+            })?;
+        }
+        for threshold in self.thresholds.iter() {
+            if threshold
+                .perfdata
+                .as_deref()
+                .is_some_and(|perfdata| perfdata != threshold::LATENCY_DETAIL)
+            {
+                return Err(Error::Configuration(format!(
+                    "Threshold {} can't use perfdata other than {}, it's all HTTP checks have",
+                    threshold.name(),
+                    threshold::LATENCY_DETAIL
+                )));
+            }
+            threshold.validate()?;
+        }
         Ok(())
     }
 
@@ -302,57 +982,63 @@ impl ServiceTrait for HttpService {
             config.http_uri.as_ref().unwrap_or(&"".to_string())
         );
 
-        let mut client = reqwest::ClientBuilder::new()
-            .user_agent(format!(
-                "{}/{}",
-                env!("CARGO_PKG_NAME"),
-                env!("CARGO_PKG_VERSION")
-            ))
-            .danger_accept_invalid_certs(!config.validate_tls)
-            .danger_accept_invalid_hostnames(!config.validate_tls)
-            // don't allow us to be redirected!
-            .redirect(Policy::none());
-
-        if let Some(ca_file) = config.ca_file.as_ref() {
-            debug!("adding CA file");
-            client = client.add_root_certificate(reqwest::Certificate::from_pem(
-                &std::fs::read(ca_file).map_err(|e| {
-                    Error::Generic(format!(
-                        "Failed to read CA file {}: {}",
-                        ca_file.display(),
-                        e
-                    ))
-                })?,
-            )?);
+        let family = config.address_family.unwrap_or(host.address_family);
+        let addresses =
+            if family == AddressFamily::Any && host.ipv4.is_none() && host.ipv6.is_none() {
+                // let the client pick, like it always has
+                vec![None]
+            } else {
+                target_addresses(host, &host.hostname, family)
+                    .await?
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            };
+
+        let mut results = Vec::new();
+        for address in addresses {
+            let result = self
+                .check_address(&config, &url, &host.hostname, address)
+                .await?;
+            results.push((address, result));
         }
-        let client = client
-            .connect_timeout(std::time::Duration::from_secs(
-                config.connect_timeout.unwrap_or(DEFAULT_TIMEOUT),
-            ))
-            .build()?;
 
-        let (result_text, status) = match client
-            .request(config.as_ref().http_method.into(), url)
-            .send()
-            .await
-        {
-            Ok(val) => self.validate_response(val, config).await?,
-            Err(err) => (format!("{:?}", err), ServiceStatus::Critical),
+        let result = match results.len() {
+            1 => results.remove(0).1,
+            _ => {
+                let (result_text, status) = combine(
+                    results
+                        .iter()
+                        .filter_map(|(address, result)| {
+                            address
+                                .map(|address| (address, result.result_text.clone(), result.status))
+                        })
+                        .collect(),
+                );
+                CheckResult {
+                    result_text,
+                    status,
+                    // the details and diagnostics are kept apart by address
+                    details: by_address(&results, |result| &result.details),
+                    diagnostics: by_address(&results, |result| &result.diagnostics),
+                    compare_output: results
+                        .into_iter()
+                        .find_map(|(_, result)| result.compare_output),
+                    ..Default::default()
+                }
+            }
         };
 
-        let time_elapsed = chrono::Utc::now() - start_time;
-
         Ok(CheckResult {
             timestamp: start_time,
-            result_text,
-            status,
-            time_elapsed,
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..result
         })
     }
 
     fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
-        Ok(serde_json::to_string_pretty(&config)?)
+        crate::serde::secret::to_string_pretty(&config)
     }
 
     fn jitter_value(&self) -> u32 {
@@ -366,6 +1052,7 @@ mod tests {
     use super::*;
 
     use crate::db::tests::test_setup;
+    use crate::testing::service_from_json;
     use crate::tests::testcontainers::TestContainer;
     use crate::tests::tls_utils::TestCertificateBuilder;
     use crate::web::urls::Urls;
@@ -383,16 +1070,42 @@ mod tests {
             contains_string: None,
             http_status: None,
             ca_file: None,
+            root_store: Default::default(),
             jitter: None,
             use_http: None,
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
 
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: "example.com".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -433,15 +1146,41 @@ mod tests {
             port: Some(NonZeroU16::new(test_container.tls_port).expect("Failed to parse port")),
             contains_string: Some("Welcome to nginx!".to_string()),
             ca_file: Some(PathBuf::from(certs.ca_file.as_ref())),
+            root_store: Default::default(),
             jitter: None,
             use_http: None,
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
         let mut host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "localhost".to_string(),
+            slug: "localhost".to_string(),
             hostname: "localhost".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -482,15 +1221,41 @@ mod tests {
             port: None,
             contains_string: None,
             ca_file: None,
+            root_store: Default::default(),
             jitter: None,
             use_http: Some(true),
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
         let mut host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: "github.com".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -541,15 +1306,41 @@ mod tests {
             port: NonZeroU16::new(test_container.tls_port),
             contains_string: None,
             ca_file: None,
+            root_store: Default::default(),
             jitter: None,
             use_http: None,
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "localhost".to_string(),
+            slug: "localhost".to_string(),
             hostname: "localhost".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -579,15 +1370,41 @@ mod tests {
             port: NonZeroU16::new(test_container.tls_port),
             contains_string: None,
             ca_file: None,
+            root_store: Default::default(),
             jitter: None,
             use_http: None,
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "localhost".to_string(),
+            slug: "localhost".to_string(),
             hostname: "localhost".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -609,16 +1426,42 @@ mod tests {
             port: None,
             contains_string: None,
             ca_file: None,
+            root_store: Default::default(),
             jitter: None,
             use_http: None,
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
 
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: "localhost".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -711,12 +1554,455 @@ mod tests {
             port: None,
             contains_string: None,
             ca_file: None,
+            root_store: Default::default(),
             jitter: None,
             use_http: None,
+            headers: HashMap::new(),
+            body: None,
+            body_file: None,
+            content_type: None,
+            basic_auth: None,
+            bearer_token: None,
+            body_regex: None,
+            json_assertions: Vec::new(),
+            expected_headers: HashMap::new(),
+            response_time_warn_ms: None,
+            response_time_critical_ms: None,
+            redirect: RedirectMode::None,
+            expected_final_url: None,
+            expected_redirects: None,
+            detect_changes: false,
+            thresholds: Vec::new(),
+            address_family: None,
+            debug_capture: false,
         };
 
         let client_config = Box::new(service.clone());
 
         assert!(service.expected_status_code(&client_config).is_err());
     }
+
+    fn test_service(extra: Value) -> HttpService {
+        service_from_json("test", extra).expect("Failed to parse service")
+    }
+
+    #[tokio::test]
+    async fn test_build_request() {
+        let service = test_service(json!({
+            "http_method": "post",
+            "headers": {"X-Api-Key": "hunter2", "Accept": "application/json"},
+            "body": "{\"hello\": \"world\"}",
+            "content_type": "application/json",
+            "bearer_token": "sekrit",
+        }));
+        assert!(service.validate().is_ok());
+
+        let client = reqwest::Client::new();
+        let request = service
+            .build_request(&client, "https://example.com/api".to_string())
+            .await
+            .expect("Failed to build request")
+            .build()
+            .expect("Failed to build request");
+        assert_eq!(request.method(), reqwest::Method::POST);
+        let headers = request.headers();
+        assert_eq!(headers["x-api-key"], "hunter2");
+        assert_eq!(headers["accept"], "application/json");
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["authorization"], "Bearer sekrit");
+        assert_eq!(
+            request.body().and_then(|body| body.as_bytes()),
+            Some(b"{\"hello\": \"world\"}".as_slice())
+        );
+
+        let body_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+        std::fs::write(body_file.path(), "from a file").expect("Failed to write body file");
+        let service = test_service(json!({
+            "body_file": body_file.path(),
+            "basic_auth": {"username": "user", "password": "pass"},
+        }));
+        let request = service
+            .build_request(&client, "https://example.com/api".to_string())
+            .await
+            .expect("Failed to build request")
+            .build()
+            .expect("Failed to build request");
+        assert!(request.headers()["authorization"]
+            .to_str()
+            .expect("Invalid header")
+            .starts_with("Basic "));
+        assert_eq!(
+            request.body().and_then(|body| body.as_bytes()),
+            Some(b"from a file".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_validate_request_options() {
+        assert!(test_service(json!({"body": "a", "body_file": "/dev/null"}))
+            .validate()
+            .is_err());
+        assert!(test_service(json!({
+            "basic_auth": {"username": "user", "password": "pass"},
+            "bearer_token": "sekrit",
+        }))
+        .validate()
+        .is_err());
+        assert!(test_service(json!({"headers": {"bad header": "value"}}))
+            .validate()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_masked() {
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+        let host = entities::host::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to search for host")
+            .expect("Failed to find host");
+
+        let service = test_service(json!({
+            "headers": {"Authorization": "Bearer sekrit", "Accept": "text/plain"},
+            "basic_auth": {"username": "user", "password": "hunter2"},
+            "bearer_token": "sekrit",
+        }));
+        let pretty = service
+            .as_json_pretty(&host)
+            .expect("Failed to serialize service");
+        assert!(!pretty.contains("sekrit"));
+        assert!(!pretty.contains("hunter2"));
+        assert!(pretty.contains("text/plain"));
+        assert!(pretty.contains("user"));
+    }
+
+    #[test]
+    fn test_json_path() {
+        let body = json!({
+            "status": "ok",
+            "items": [{"name": "one", "count": 3}, {"name": "two"}],
+            "odd key": true,
+        });
+        assert_eq!(json_path(&body, "$").expect("Failed"), Some(&body));
+        assert_eq!(
+            json_path(&body, "$.status").expect("Failed"),
+            Some(&json!("ok"))
+        );
+        assert_eq!(
+            json_path(&body, "$.items[1].name").expect("Failed"),
+            Some(&json!("two"))
+        );
+        assert_eq!(
+            json_path(&body, "$['odd key']").expect("Failed"),
+            Some(&json!(true))
+        );
+        assert_eq!(json_path(&body, "$.items[5]").expect("Failed"), None);
+        assert_eq!(json_path(&body, "$.nope.nope").expect("Failed"), None);
+        assert!(json_path(&body, "status").is_err());
+        assert!(json_path(&body, "$.items[one]").is_err());
+        assert!(json_path(&body, "$..status").is_err());
+    }
+
+    #[test]
+    fn test_json_assertion() {
+        let body = json!({"status": "ok", "queue": 12});
+        let assertion = |value: Value| -> JsonAssertion {
+            serde_json::from_value(value).expect("Failed to parse assertion")
+        };
+        assert_eq!(
+            assertion(json!({"path": "$.status", "equals": "ok"})).check(&body),
+            Ok(None)
+        );
+        assert!(assertion(json!({"path": "$.status", "equals": "bad"}))
+            .check(&body)
+            .expect("Failed")
+            .is_some());
+        assert_eq!(
+            assertion(json!({"path": "$.queue", "less_than": 100, "greater_than": 1})).check(&body),
+            Ok(None)
+        );
+        assert!(assertion(json!({"path": "$.queue", "less_than": 10}))
+            .check(&body)
+            .expect("Failed")
+            .is_some());
+        assert!(assertion(json!({"path": "$.status", "greater_than": 1}))
+            .check(&body)
+            .expect("Failed")
+            .is_some());
+        assert!(assertion(json!({"path": "$.missing"}))
+            .check(&body)
+            .expect("Failed")
+            .is_some());
+        assert_eq!(
+            assertion(json!({"path": "$.missing", "exists": false})).check(&body),
+            Ok(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_response_assertions() {
+        let response = || -> Response {
+            axum::http::Response::builder()
+                .status(200)
+                .header("X-Cache", "HIT")
+                .header("Content-Type", "application/json")
+                .body(r#"{"status": "ok", "build": "v1.2.3"}"#.to_string())
+                .expect("Failed to build response")
+                .into()
+        };
+        let run = |extra: Value, elapsed_ms: i64| {
+            let service = test_service(extra);
+            assert!(service.validate().is_ok());
+            async move {
+                service
+                    .validate_response(
+                        response(),
+                        Box::new(service.clone()),
+                        TimeDelta::milliseconds(elapsed_ms),
+                        0,
+                    )
+                    .await
+                    .expect("Failed to validate response")
+                    .1
+            }
+        };
+
+        assert_eq!(
+            run(
+                json!({
+                    "body_regex": r"v\d+\.\d+",
+                    "expected_headers": {"x-cache": "HIT", "content-type": null},
+                    "json_assertions": [{"path": "$.status", "equals": "ok"}],
+                    "response_time_warn_ms": 100,
+                }),
+                50
+            )
+            .await,
+            ServiceStatus::Ok
+        );
+        assert_eq!(
+            run(json!({"body_regex": "^nope$"}), 0).await,
+            ServiceStatus::Critical
+        );
+        assert_eq!(
+            run(json!({"expected_headers": {"x-cache": "MISS"}}), 0).await,
+            ServiceStatus::Critical
+        );
+        assert_eq!(
+            run(json!({"expected_headers": {"x-missing": null}}), 0).await,
+            ServiceStatus::Critical
+        );
+        assert_eq!(
+            run(
+                json!({"json_assertions": [{"path": "$.status", "equals": "down"}]}),
+                0
+            )
+            .await,
+            ServiceStatus::Critical
+        );
+        let thresholds = json!({"response_time_warn_ms": 100, "response_time_critical_ms": 500});
+        assert_eq!(run(thresholds.clone(), 200).await, ServiceStatus::Warning);
+        assert_eq!(run(thresholds, 600).await, ServiceStatus::Critical);
+        let thresholds = json!({"warn_latency_ms": 100, "crit_latency_ms": 500});
+        assert_eq!(run(thresholds.clone(), 50).await, ServiceStatus::Ok);
+        assert_eq!(run(thresholds, 600).await, ServiceStatus::Critical);
+
+        assert!(test_service(json!({"body_regex": "(unclosed"}))
+            .validate()
+            .is_err());
+        assert!(test_service(json!({"json_assertions": [{"path": "nope"}]}))
+            .validate()
+            .is_err());
+        assert!(test_service(json!({
+            "response_time_warn_ms": 500,
+            "response_time_critical_ms": 100,
+        }))
+        .validate()
+        .is_err());
+        assert!(
+            test_service(json!({"thresholds": [{"perfdata": "latency_ms", "warn": "> 100"}]}))
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            test_service(json!({"thresholds": [{"perfdata": "load1", "warn": "> 1"}]}))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_response_redirects() {
+        let redirect = || -> Response {
+            axum::http::Response::builder()
+                .status(301)
+                .header("Location", "https://example.com/")
+                .body(String::new())
+                .expect("Failed to build response")
+                .into()
+        };
+        let run = |extra: Value, redirects: usize| {
+            let service = test_service(extra);
+            assert!(service.validate().is_ok());
+            async move {
+                service
+                    .validate_response(
+                        redirect(),
+                        Box::new(service.clone()),
+                        TimeDelta::zero(),
+                        redirects,
+                    )
+                    .await
+                    .expect("Failed to validate response")
+                    .1
+            }
+        };
+
+        // not following, so the 301 isn't the 200 we expected
+        assert_eq!(run(json!({}), 0).await, ServiceStatus::Critical);
+        assert_eq!(
+            run(json!({"redirect": {"expect": "https://example.com/"}}), 0).await,
+            ServiceStatus::Ok
+        );
+        assert_eq!(
+            run(
+                json!({"redirect": {"expect": "https://example.com/"}, "http_status": 302}),
+                0
+            )
+            .await,
+            ServiceStatus::Critical
+        );
+        assert_eq!(
+            run(json!({"redirect": {"expect": "https://example.org/"}}), 0).await,
+            ServiceStatus::Critical
+        );
+        assert_eq!(
+            run(
+                json!({"redirect": {"follow": 5}, "expected_redirects": 1, "http_status": 301}),
+                1
+            )
+            .await,
+            ServiceStatus::Ok
+        );
+        assert_eq!(
+            run(
+                json!({"redirect": {"follow": 5}, "expected_redirects": 2, "http_status": 301}),
+                1
+            )
+            .await,
+            ServiceStatus::Critical
+        );
+        // responses built without a URL get a placeholder one
+        assert_eq!(
+            run(
+                json!({"expected_final_url": "https://example.com/", "http_status": 301}),
+                0
+            )
+            .await,
+            ServiceStatus::Critical
+        );
+
+        assert!(test_service(json!({"expected_redirects": 1}))
+            .validate()
+            .is_err());
+        assert!(
+            test_service(json!({"redirect": {"follow": 1}, "expected_redirects": 2}))
+                .validate()
+                .is_err()
+        );
+        assert!(test_service(json!({"expected_final_url": "not a url"}))
+            .validate()
+            .is_err());
+        assert_eq!(
+            test_service(json!({"redirect": "none"})).redirect,
+            RedirectMode::None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_address_family() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // only listens on IPv4, so checking over both has to fail on IPv6
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind listener");
+        let port = listener
+            .local_addr()
+            .expect("Failed to get listener address")
+            .port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                    )
+                    .await;
+            }
+        });
+
+        let host = entities::host::Model {
+            check: crate::host::HostCheck::None,
+            hostname: "maremma.invalid".to_string(),
+            ipv4: Some("127.0.0.1".to_string()),
+            ipv6: Some("::1".to_string()),
+            ..entities::host::test_host()
+        };
+        let service = |address_family: &str| {
+            test_service(json!({
+                "http_method": "get",
+                "use_http": true,
+                "port": port,
+                "connect_timeout": 2,
+                "address_family": address_family,
+            }))
+        };
+
+        let res = service("ipv4")
+            .run(&host)
+            .await
+            .expect("Failed to run check");
+        assert_eq!(res.status, ServiceStatus::Ok, "{}", res.result_text);
+        assert!(res.diagnostics.is_empty());
+
+        // the timings are only kept with debug_capture
+        let res = test_service(json!({
+            "http_method": "get",
+            "use_http": true,
+            "port": port,
+            "address_family": "ipv4",
+            "debug_capture": true,
+        }))
+        .run(&host)
+        .await
+        .expect("Failed to run check");
+        assert_eq!(res.status, ServiceStatus::Ok, "{}", res.result_text);
+        assert!(res.diagnostics.contains_key("connect_ms"));
+        assert!(!res.diagnostics.contains_key("tls_handshake_ms"));
+
+        let res = service("both")
+            .run(&host)
+            .await
+            .expect("Failed to run check");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(
+            res.result_text.starts_with("IPv6 ::1: "),
+            "{}",
+            res.result_text
+        );
+        assert!(res.result_text.contains("IPv4 127.0.0.1: "));
+
+        // the host's own preference is used when the service doesn't have one
+        let res = test_service(json!({"http_method": "get", "use_http": true, "port": port}))
+            .run(&entities::host::Model {
+                address_family: AddressFamily::Ipv4,
+                ipv6: None,
+                ..host
+            })
+            .await
+            .expect("Failed to run check");
+        assert_eq!(res.status, ServiceStatus::Ok, "{}", res.result_text);
+    }
 }
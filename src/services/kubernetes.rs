@@ -49,6 +49,7 @@ impl ServiceTrait for KubernetesService {
                     result_text: format!("UNKNOWN: Unable to configure Kubernetes client: {}", err),
                     status: ServiceStatus::Unknown,
                     time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
                 })
             }
         };
@@ -63,12 +64,13 @@ impl ServiceTrait for KubernetesService {
             result_text,
             status,
             time_elapsed: chrono::Utc::now() - start_time,
+            ..Default::default()
         })
     }
 
     fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
-        Ok(serde_json::to_string_pretty(&config)?)
+        crate::serde::secret::to_string_pretty(&config)
     }
 
     fn jitter_value(&self) -> u32 {
@@ -119,9 +121,16 @@ mod tests {
             .run(&entities::host::Model {
                 id: Uuid::new_v4(),
                 name: "test host".to_string(),
+                slug: "test-host".to_string(),
                 hostname,
                 check: crate::host::HostCheck::None,
                 config: json!({}),
+                vars: json!({}),
+                ipv4: None,
+                ipv6: None,
+                address_family: Default::default(),
+                display_name: None,
+                labels: json!({}),
             })
             .await
             .unwrap();
@@ -0,0 +1,786 @@
+//! Mail server checks for SMTP, IMAP and POP3 - connect, optionally STARTTLS, log in, and for SMTP send a test message
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::num::NonZeroU16;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::Engine;
+use rustls::pki_types::ServerName;
+use rustls::RootCertStore;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+use super::prelude::*;
+use super::root_store::RootStore;
+use super::tls::{custom_root_store, recording_connector, TlsPeerState};
+use crate::prelude::*;
+
+/// How long the whole conversation can take, in seconds
+pub const DEFAULT_MAIL_TIMEOUT_SECONDS: u16 = 10;
+/// What we call ourselves in `EHLO`
+pub const DEFAULT_HELO_NAME: &str = "localhost";
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// Which protocol the server speaks
+pub enum MailProtocol {
+    /// SMTP, including submission
+    Smtp,
+    /// IMAP4
+    Imap,
+    /// POP3
+    Pop3,
+}
+
+impl Display for MailProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Smtp => write!(f, "SMTP"),
+            Self::Imap => write!(f, "IMAP"),
+            Self::Pop3 => write!(f, "POP3"),
+        }
+    }
+}
+
+impl MailProtocol {
+    /// The usual port for the protocol
+    pub fn default_port(&self, tls: MailTls) -> u16 {
+        match (self, tls) {
+            (Self::Smtp, MailTls::Implicit) => 465,
+            (Self::Smtp, _) => 25,
+            (Self::Imap, MailTls::Implicit) => 993,
+            (Self::Imap, _) => 143,
+            (Self::Pop3, MailTls::Implicit) => 995,
+            (Self::Pop3, _) => 110,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+/// How the connection's secured
+pub enum MailTls {
+    /// Plain text the whole way
+    None,
+    /// Connect in plain text then upgrade with STARTTLS (STLS for POP3)
+    #[default]
+    Starttls,
+    /// TLS from the start, eg SMTPS on 465 or IMAPS on 993
+    Implicit,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// Checks an SMTP, IMAP or POP3 server
+pub struct MailService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// Which protocol to speak
+    pub protocol: MailProtocol,
+
+    /// Port to connect to, defaults to the protocol's usual one
+    pub port: Option<NonZeroU16>,
+
+    /// How the connection's secured, defaults to STARTTLS
+    #[serde(default)]
+    pub tls: MailTls,
+
+    /// Log in as this user, `password` has to be set too
+    pub username: Option<String>,
+
+    /// Password to log in with
+    #[serde(default, serialize_with = "crate::serde::secret::serialize")]
+    pub password: Option<String>,
+
+    /// SMTP only, send a test message to this address, eg a sink that discards it
+    pub send_to: Option<String>,
+
+    /// The sender of the test message, needed with `send_to`
+    pub mail_from: Option<String>,
+
+    /// The name sent with `EHLO`, defaults to [DEFAULT_HELO_NAME]
+    pub helo_name: Option<String>,
+
+    /// How long the whole check can take (seconds), defaults to [DEFAULT_MAIL_TIMEOUT_SECONDS]
+    pub timeout: Option<u16>,
+
+    /// Critical expiry in days, defaults to the same as the TLS check
+    pub expiry_critical: Option<u16>,
+    /// Warning expiry in days, defaults to the same as the TLS check
+    pub expiry_warn: Option<u16>,
+
+    /// PEM bundle of CA certificates to verify the chain against, instead of the webpki roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Which roots to trust: `webpki` (the default), `system` or `file:<path>`
+    #[serde(default)]
+    pub root_store: RootStore,
+
+    /// The name to send in SNI and check the certificate against, if it's different to the host's hostname
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni_hostname: Option<String>,
+}
+
+impl ConfigOverlay for MailService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            protocol: self.extract_value(value, "protocol", &self.protocol)?,
+            port: self.extract_value(value, "port", &self.port)?,
+            tls: self.extract_value(value, "tls", &self.tls)?,
+            username: self.extract_value(value, "username", &self.username)?,
+            password: self.extract_value(value, "password", &self.password)?,
+            send_to: self.extract_value(value, "send_to", &self.send_to)?,
+            mail_from: self.extract_value(value, "mail_from", &self.mail_from)?,
+            helo_name: self.extract_value(value, "helo_name", &self.helo_name)?,
+            timeout: self.extract_value(value, "timeout", &self.timeout)?,
+            expiry_critical: self.extract_value(value, "expiry_critical", &self.expiry_critical)?,
+            expiry_warn: self.extract_value(value, "expiry_warn", &self.expiry_warn)?,
+            ca_file: self.extract_value(value, "ca_file", &self.ca_file)?,
+            root_store: self.extract_value(value, "root_store", &self.root_store)?,
+            sni_hostname: self.extract_value(value, "sni_hostname", &self.sni_hostname)?,
+        }))
+    }
+}
+
+/// A line-based conversation with a mail server, failures are the text for the check result
+struct MailSession<S> {
+    stream: BufStream<S>,
+    protocol: MailProtocol,
+    /// IMAP commands are tagged, this numbers them
+    tag: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MailSession<S> {
+    fn new(stream: S, protocol: MailProtocol) -> Self {
+        Self {
+            stream: BufStream::new(stream),
+            protocol,
+            tag: 0,
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn send(&mut self, line: &str) -> Result<(), String> {
+        self.stream
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await
+            .map_err(|err| format!("Failed to send to the server: {}", err))?;
+        self.stream
+            .flush()
+            .await
+            .map_err(|err| format!("Failed to send to the server: {}", err))
+    }
+
+    async fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.stream.read_line(&mut line).await {
+            Ok(0) => Err("The server closed the connection".to_string()),
+            Ok(_) => Ok(line.trim_end().to_string()),
+            Err(err) => Err(format!("Failed to read from the server: {}", err)),
+        }
+    }
+
+    /// Sends a command (if there is one) and checks the reply, `what` describes it for errors
+    async fn command(&mut self, command: Option<&str>, what: &str) -> Result<String, String> {
+        let tag = match (self.protocol, command) {
+            (MailProtocol::Imap, Some(command)) => {
+                self.tag += 1;
+                let tag = format!("m{}", self.tag);
+                self.send(&format!("{} {}", tag, command)).await?;
+                Some(tag)
+            }
+            (_, Some(command)) => {
+                self.send(command).await?;
+                None
+            }
+            (_, None) => None,
+        };
+
+        match self.protocol {
+            MailProtocol::Smtp => {
+                // replies can run over several lines, `250-...` until `250 ...`
+                let mut text = Vec::new();
+                loop {
+                    let line = self.read_line().await?;
+                    let code = line.get(..3).unwrap_or_default().to_string();
+                    text.push(line.get(4..).unwrap_or_default().to_string());
+                    if line.as_bytes().get(3) != Some(&b'-') {
+                        return match code.starts_with('2') || code.starts_with('3') {
+                            true => Ok(text.join(" ")),
+                            false => Err(format!("{} failed: {}", what, line)),
+                        };
+                    }
+                }
+            }
+            MailProtocol::Pop3 => {
+                let line = self.read_line().await?;
+                match line.strip_prefix("+OK") {
+                    Some(text) => Ok(text.trim().to_string()),
+                    None => Err(format!("{} failed: {}", what, line)),
+                }
+            }
+            MailProtocol::Imap => {
+                // untagged lines come first, then the tagged status, the greeting's untagged
+                let tag = tag.unwrap_or_else(|| "*".to_string());
+                loop {
+                    let line = self.read_line().await?;
+                    let Some(status) = line.strip_prefix(&format!("{} ", tag)) else {
+                        continue;
+                    };
+                    return match status.starts_with("OK") || status.starts_with("PREAUTH") {
+                        true => Ok(status.to_string()),
+                        false => Err(format!("{} failed: {}", what, line)),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Reads the greeting and introduces ourselves
+    async fn greet(&mut self, helo_name: &str) -> Result<(), String> {
+        self.command(None, "Greeting").await?;
+        if self.protocol == MailProtocol::Smtp {
+            self.command(Some(&format!("EHLO {}", helo_name)), "EHLO")
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn starttls(&mut self) -> Result<(), String> {
+        let command = match self.protocol {
+            MailProtocol::Pop3 => "STLS",
+            MailProtocol::Smtp | MailProtocol::Imap => "STARTTLS",
+        };
+        self.command(Some(command), command).await.map(|_| ())
+    }
+
+    async fn login(&mut self, username: &str, password: &str) -> Result<(), String> {
+        match self.protocol {
+            MailProtocol::Smtp => {
+                let credentials = base64::engine::general_purpose::STANDARD
+                    .encode(format!("\0{}\0{}", username, password));
+                self.command(Some(&format!("AUTH PLAIN {}", credentials)), "Login")
+                    .await?;
+            }
+            MailProtocol::Imap => {
+                let quote = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+                self.command(
+                    Some(&format!(
+                        "LOGIN \"{}\" \"{}\"",
+                        quote(username),
+                        quote(password)
+                    )),
+                    "Login",
+                )
+                .await?;
+            }
+            MailProtocol::Pop3 => {
+                self.command(Some(&format!("USER {}", username)), "Login")
+                    .await?;
+                self.command(Some(&format!("PASS {}", password)), "Login")
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_message(&mut self, name: &str, from: &str, to: &str) -> Result<(), String> {
+        self.command(Some(&format!("MAIL FROM:<{}>", from)), "MAIL FROM")
+            .await?;
+        self.command(Some(&format!("RCPT TO:<{}>", to)), "RCPT TO")
+            .await?;
+        self.command(Some("DATA"), "DATA").await?;
+        let message = [
+            format!("From: <{}>", from),
+            format!("To: <{}>", to),
+            format!("Subject: Maremma test message from {}", name),
+            format!("Date: {}", Utc::now().to_rfc2822()),
+            format!("Message-ID: <{}@maremma>", Uuid::new_v4()),
+            String::new(),
+            format!("This is a test message from the Maremma check {}.", name),
+            ".".to_string(),
+        ];
+        for line in message {
+            self.send(&line).await?;
+        }
+        self.command(None, "Sending the test message").await?;
+        Ok(())
+    }
+
+    /// Says goodbye, it doesn't matter if the server doesn't answer nicely
+    async fn quit(&mut self) {
+        let command = match self.protocol {
+            MailProtocol::Imap => "LOGOUT",
+            MailProtocol::Smtp | MailProtocol::Pop3 => "QUIT",
+        };
+        if let Err(err) = self.command(Some(command), command).await {
+            debug!("Mail server didn't say goodbye nicely: {}", err);
+        }
+    }
+}
+
+/// What happened when the check talked to the server
+struct MailOutcome {
+    connect_ms: i64,
+    tls: Option<TlsPeerState>,
+    steps: Vec<String>,
+}
+
+impl MailService {
+    fn helo_name(&self) -> &str {
+        self.helo_name.as_deref().unwrap_or(DEFAULT_HELO_NAME)
+    }
+
+    /// Secures the connection, returning the stream and what was found out about the certificates
+    async fn tls_handshake(
+        &self,
+        host: &entities::host::Model,
+        roots: Option<Arc<RootCertStore>>,
+        stream: TcpStream,
+    ) -> Result<(tokio_rustls::client::TlsStream<TcpStream>, TlsPeerState), String> {
+        let (connector, tls_verifier) = recording_connector(roots);
+        let server_name = self
+            .sni_hostname
+            .clone()
+            .unwrap_or_else(|| host.hostname.clone());
+        let dnsname = ServerName::try_from(server_name.clone())
+            .map_err(|_| format!("Invalid hostname '{}'", server_name))?;
+        let handshake = connector.connect(dnsname, stream).await;
+        match (handshake, tls_verifier.take_state()) {
+            (Ok(stream), Some(state)) => Ok((stream, state)),
+            (Ok(_), None) => {
+                Err("TLS handshake finished without checking the certificate".to_string())
+            }
+            (Err(err), _) => Err(format!("TLS handshake failed: {}", err)),
+        }
+    }
+
+    /// Everything after the connection's secured, if it's going to be
+    async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        session: &mut MailSession<S>,
+        steps: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if let (Some(username), Some(password)) = (self.username.as_ref(), self.password.as_ref()) {
+            session.login(username, password).await?;
+            steps.push(format!("logged in as {}", username));
+        }
+        if let (Some(from), Some(to)) = (self.mail_from.as_ref(), self.send_to.as_ref()) {
+            session.send_message(&self.name, from, to).await?;
+            steps.push(format!("sent a test message to {}", to));
+        }
+        session.quit().await;
+        Ok(())
+    }
+
+    async fn check(
+        &self,
+        host: &entities::host::Model,
+        roots: Option<Arc<RootCertStore>>,
+    ) -> Result<MailOutcome, String> {
+        let start_time = Utc::now();
+        let port = self
+            .port
+            .map(u16::from)
+            .unwrap_or(self.protocol.default_port(self.tls));
+        let stream = TcpStream::connect(format!("{}:{}", host.hostname, port))
+            .await
+            .map_err(|err| format!("Failed to connect to {}:{}: {}", host.hostname, port, err))?;
+        let connect_ms = (Utc::now() - start_time).num_milliseconds();
+        let mut steps = Vec::new();
+
+        let tls = match self.tls {
+            MailTls::None => {
+                let mut session = MailSession::new(stream, self.protocol);
+                session.greet(self.helo_name()).await?;
+                self.converse(&mut session, &mut steps).await?;
+                None
+            }
+            MailTls::Starttls => {
+                let mut session = MailSession::new(stream, self.protocol);
+                session.greet(self.helo_name()).await?;
+                session.starttls().await?;
+                let (stream, state) = self
+                    .tls_handshake(host, roots, session.into_inner())
+                    .await?;
+                steps.push("STARTTLS".to_string());
+                let mut session = MailSession::new(stream, self.protocol);
+                if self.protocol == MailProtocol::Smtp {
+                    // the server forgets everything from before STARTTLS
+                    session
+                        .command(Some(&format!("EHLO {}", self.helo_name())), "EHLO")
+                        .await?;
+                }
+                self.converse(&mut session, &mut steps).await?;
+                Some(state)
+            }
+            MailTls::Implicit => {
+                let (stream, state) = self.tls_handshake(host, roots, stream).await?;
+                let mut session = MailSession::new(stream, self.protocol);
+                session.greet(self.helo_name()).await?;
+                self.converse(&mut session, &mut steps).await?;
+                Some(state)
+            }
+        };
+        Ok(MailOutcome {
+            connect_ms,
+            tls,
+            steps,
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for MailService {
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        let roots = custom_root_store(&config.root_store, config.ca_file.as_deref())?.map(Arc::new);
+
+        let timeout = config.timeout.unwrap_or(DEFAULT_MAIL_TIMEOUT_SECONDS);
+        let outcome = tokio::time::timeout(
+            Duration::from_secs(timeout as u64),
+            config.check(host, roots),
+        )
+        .await;
+        let time_elapsed = Utc::now() - start_time;
+
+        let outcome = match outcome {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(result_text)) => {
+                return Ok(CheckResult {
+                    timestamp: start_time,
+                    time_elapsed,
+                    status: ServiceStatus::Critical,
+                    result_text: format!("{}: {}", config.protocol, result_text),
+                    ..Default::default()
+                })
+            }
+            Err(_) => {
+                return Ok(CheckResult {
+                    timestamp: start_time,
+                    time_elapsed,
+                    status: ServiceStatus::Critical,
+                    result_text: format!(
+                        "{}: timed out after {} seconds",
+                        config.protocol, timeout
+                    ),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let mut status = ServiceStatus::Ok;
+        let mut problems = Vec::new();
+        let mut details = BTreeMap::from([
+            ("connect_ms".to_string(), outcome.connect_ms.to_string()),
+            (
+                "total_ms".to_string(),
+                time_elapsed.num_milliseconds().to_string(),
+            ),
+        ]);
+        if let Some(tls) = outcome.tls.as_ref() {
+            let certificate_problems = tls.certificate_problems(config.ca_file.is_some());
+            if !certificate_problems.is_empty() {
+                status = ServiceStatus::Critical;
+                problems.extend(certificate_problems);
+            }
+            if let Some((expiry_status, expiry_text)) =
+                tls.expiry_problem(config.expiry_warn, config.expiry_critical)
+            {
+                status = status.max(expiry_status);
+                problems.push(expiry_text);
+            }
+            details.insert(
+                "cert_expiry_days".to_string(),
+                tls.expiry_days().to_string(),
+            );
+        }
+
+        let mut summary = vec![format!(
+            "{} answered in {}ms",
+            config.protocol,
+            time_elapsed.num_milliseconds()
+        )];
+        summary.extend(outcome.steps);
+        let result_text = match problems.is_empty() {
+            true => summary.join(", "),
+            false => format!("{} ({})", problems.join(", "), summary.join(", ")),
+        };
+
+        Ok(CheckResult {
+            timestamp: start_time,
+            time_elapsed,
+            status,
+            result_text,
+            details,
+            ..Default::default()
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.username.is_some() != self.password.is_some() {
+            return Err(Error::Configuration(format!(
+                "{}: username and password have to be set together",
+                self.name
+            )));
+        }
+        if self.send_to.is_some() {
+            if self.protocol != MailProtocol::Smtp {
+                return Err(Error::Configuration(format!(
+                    "{}: send_to only works with SMTP",
+                    self.name
+                )));
+            }
+            if self.mail_from.is_none() {
+                return Err(Error::Configuration(format!(
+                    "{}: mail_from has to be set to send a test message",
+                    self.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::host::test_host;
+    use crate::testing::service_from_json;
+
+    /// A pretend mail server on localhost, `respond` answers each line the client sends
+    async fn fake_server(greeting: &'static str, respond: fn(&str) -> String) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to listen");
+        let port = listener
+            .local_addr()
+            .expect("Failed to get listener address")
+            .port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufStream::new(stream);
+                    stream.write_all(greeting.as_bytes()).await?;
+                    stream.flush().await?;
+                    let mut in_data = false;
+                    loop {
+                        let mut line = String::new();
+                        if stream.read_line(&mut line).await? == 0 {
+                            break;
+                        }
+                        let line = line.trim_end();
+                        let response = match in_data {
+                            true if line == "." => {
+                                in_data = false;
+                                "250 2.0.0 queued\r\n".to_string()
+                            }
+                            true => continue,
+                            false => respond(line),
+                        };
+                        in_data = response.starts_with("354");
+                        stream.write_all(response.as_bytes()).await?;
+                        stream.flush().await?;
+                    }
+                    Ok::<(), std::io::Error>(())
+                });
+            }
+        });
+        port
+    }
+
+    fn smtp_server(line: &str) -> String {
+        match line {
+            line if line.starts_with("EHLO") => "250-fake.example.com\r\n250 AUTH PLAIN\r\n",
+            "AUTH PLAIN AHVzZXIAaHVudGVyMg==" => "235 2.7.0 Authentication successful\r\n",
+            line if line.starts_with("AUTH") => "535 5.7.8 Authentication failed\r\n",
+            line if line.starts_with("MAIL FROM") => "250 2.1.0 OK\r\n",
+            "RCPT TO:<sink@example.com>" => "250 2.1.5 OK\r\n",
+            line if line.starts_with("RCPT TO") => "550 5.1.1 No such user\r\n",
+            "DATA" => "354 Go ahead\r\n",
+            "QUIT" => "221 2.0.0 Bye\r\n",
+            _ => "502 5.5.2 Command not recognised\r\n",
+        }
+        .to_string()
+    }
+
+    fn imap_server(line: &str) -> String {
+        let (tag, command) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "LOGIN \"user\" \"hunter2\"" => format!("{} OK LOGIN completed\r\n", tag),
+            "LOGOUT" => format!("* BYE\r\n{} OK LOGOUT completed\r\n", tag),
+            _ => format!("{} NO nope\r\n", tag),
+        }
+    }
+
+    fn pop3_server(line: &str) -> String {
+        match line {
+            "USER user" | "PASS hunter2" | "QUIT" => "+OK\r\n",
+            _ => "-ERR nope\r\n",
+        }
+        .to_string()
+    }
+
+    fn service(protocol: &str, port: u16, mut config: Value) -> MailService {
+        if let Some(config) = config.as_object_mut() {
+            for (key, value) in [
+                ("protocol", json!(protocol)),
+                ("port", json!(port)),
+                ("tls", json!("none")),
+                ("timeout", json!(5)),
+            ] {
+                config.entry(key).or_insert(value);
+            }
+        }
+        let service: MailService =
+            service_from_json("mail", config).expect("Failed to parse mail service");
+        service.validate().expect("Failed to validate mail service");
+        service
+    }
+
+    fn localhost() -> entities::host::Model {
+        entities::host::Model {
+            hostname: "127.0.0.1".to_string(),
+            check: crate::host::HostCheck::None,
+            ..test_host()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smtp() {
+        let port = fake_server("220 fake.example.com ESMTP\r\n", smtp_server).await;
+
+        let result = service(
+            "smtp",
+            port,
+            json!({
+                "username": "user",
+                "password": "hunter2",
+                "mail_from": "maremma@example.com",
+                "send_to": "sink@example.com",
+            }),
+        )
+        .run(&localhost())
+        .await
+        .expect("Failed to run SMTP check");
+        assert_eq!(result.status, ServiceStatus::Ok, "{:?}", result);
+        assert!(result.result_text.contains("logged in as user"));
+        assert!(result
+            .result_text
+            .contains("sent a test message to sink@example.com"));
+        assert!(result.details.contains_key("connect_ms"));
+
+        let result = service(
+            "smtp",
+            port,
+            json!({"username": "user", "password": "wrong"}),
+        )
+        .run(&localhost())
+        .await
+        .expect("Failed to run SMTP check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+        assert_eq!(
+            result.result_text,
+            "SMTP: Login failed: 535 5.7.8 Authentication failed"
+        );
+
+        let result = service(
+            "smtp",
+            port,
+            json!({"mail_from": "maremma@example.com", "send_to": "nobody@example.com"}),
+        )
+        .run(&localhost())
+        .await
+        .expect("Failed to run SMTP check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+        assert!(result.result_text.contains("RCPT TO failed"));
+
+        // the fake server doesn't do STARTTLS
+        let result = service("smtp", port, json!({"tls": "starttls"}))
+            .run(&localhost())
+            .await
+            .expect("Failed to run SMTP check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+        assert!(result.result_text.contains("STARTTLS failed"));
+    }
+
+    #[tokio::test]
+    async fn test_imap_and_pop3() {
+        let imap_port = fake_server("* OK IMAP4rev1 ready\r\n", imap_server).await;
+        let pop3_port = fake_server("+OK POP3 ready\r\n", pop3_server).await;
+
+        for (protocol, port) in [("imap", imap_port), ("pop3", pop3_port)] {
+            let result = service(
+                protocol,
+                port,
+                json!({"username": "user", "password": "hunter2"}),
+            )
+            .run(&localhost())
+            .await
+            .expect("Failed to run mail check");
+            assert_eq!(result.status, ServiceStatus::Ok, "{:?}", result);
+
+            let result = service(
+                protocol,
+                port,
+                json!({"username": "user", "password": "wrong"}),
+            )
+            .run(&localhost())
+            .await
+            .expect("Failed to run mail check");
+            assert_eq!(result.status, ServiceStatus::Critical, "{:?}", result);
+            assert!(result.result_text.contains("Login failed"));
+        }
+    }
+
+    #[test]
+    fn test_mail_validate() {
+        let parse = |config: Value| -> MailService {
+            service_from_json("mail", config).expect("Failed to parse mail service")
+        };
+        assert!(parse(json!({"protocol": "smtp"})).validate().is_ok());
+        assert!(parse(json!({"protocol": "smtp", "username": "user"}))
+            .validate()
+            .is_err());
+        assert!(
+            parse(json!({"protocol": "smtp", "send_to": "sink@example.com"}))
+                .validate()
+                .is_err()
+        );
+        assert!(parse(json!({
+            "protocol": "imap",
+            "send_to": "sink@example.com",
+            "mail_from": "maremma@example.com",
+        }))
+        .validate()
+        .is_err());
+        assert_eq!(MailProtocol::Imap.default_port(MailTls::Implicit), 993);
+        assert_eq!(MailProtocol::Smtp.default_port(MailTls::Starttls), 25);
+    }
+}
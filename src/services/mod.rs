@@ -7,21 +7,55 @@
 //! - [tls::TlsService]
 //! - [ping::PingService]
 //! - [kubernetes::KubernetesService]
-
+//! - [docker::DockerService]
+//! - [disk::DiskService]
+//! - [system::SystemService]
+//! - [mail::MailService]
+//! - [database::DatabaseService]
+//! - [ntp::NtpService]
+//! - [cert_file::CertFileService]
+//! - [websocket::WebsocketService]
+//! - [plugin::PluginService]
+//! - [passive::PassiveService]
+//! - [heartbeat::HeartbeatService]
+//! - [self_monitor::SelfMonitorService]
+
+pub mod cert_file;
 pub mod cli;
+pub mod database;
+pub mod disk;
+pub mod docker;
+pub mod heartbeat;
+pub mod host_variables;
 pub mod http;
 pub mod kubernetes;
+pub mod mail;
+pub mod ntp;
 pub mod oneshot;
+pub mod passive;
 pub mod ping;
+pub mod plugin;
 mod prelude;
+pub mod root_store;
+pub mod self_monitor;
 pub mod ssh;
+pub mod system;
+pub mod threshold;
+pub(crate) mod timings;
 pub mod tls;
+pub(crate) mod traceroute;
+pub mod websocket;
 
+use crate::actions::routing::NotificationRouting;
 use crate::check_loop::CheckResult;
 use crate::db::entities::{self, host};
+use crate::labels::Labels;
 use crate::prelude::*;
+use host_variables::{expand_host_vars, find_host_vars_in_value};
 use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
 
+use chrono_tz::Tz;
 use clap::ValueEnum;
 use sea_orm::{sea_query, DeriveActiveEnum, EnumIter, Iden};
 use serde::de::DeserializeOwned;
@@ -29,7 +63,17 @@ use serde_json::Map;
 
 use crate::errors::Error;
 #[derive(
-    Deserialize, Debug, Serialize, PartialEq, Eq, Copy, Clone, DeriveActiveEnum, EnumIter, Iden,
+    Deserialize,
+    Debug,
+    Serialize,
+    PartialEq,
+    Eq,
+    Copy,
+    Clone,
+    DeriveActiveEnum,
+    EnumIter,
+    Iden,
+    JsonSchema,
 )]
 #[serde(rename_all = "lowercase")]
 #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(16))")]
@@ -48,6 +92,10 @@ pub enum ServiceStatus {
     Warning,
     #[sea_orm(string_value = "error")]
     Error,
+    /// The check can't run because of a problem with the service or host configuration
+    #[sea_orm(string_value = "config_error")]
+    #[serde(rename = "config_error")]
+    ConfigError,
     #[sea_orm(string_value = "unknown")]
     Unknown,
     /// Run this as soon as possible
@@ -55,6 +103,12 @@ pub enum ServiceStatus {
     Urgent,
     #[sea_orm(string_value = "disabled")]
     Disabled,
+    /// The check was cancelled while it was running
+    #[sea_orm(string_value = "cancelled")]
+    Cancelled,
+    /// The check didn't finish within its `max_runtime`
+    #[sea_orm(string_value = "timeout")]
+    Timeout,
 }
 
 impl From<ServiceStatus> for i8 {
@@ -62,10 +116,13 @@ impl From<ServiceStatus> for i8 {
         match value {
             ServiceStatus::Critical => 127,
             ServiceStatus::Error => 96,
+            ServiceStatus::Timeout => 88,
+            ServiceStatus::ConfigError => 80,
             ServiceStatus::Urgent => 64,
             ServiceStatus::Checking => 48,
             ServiceStatus::Warning => 32,
             ServiceStatus::Ok => 16,
+            ServiceStatus::Cancelled => 0,
             ServiceStatus::Pending => -8,
             ServiceStatus::Disabled => -16,
             ServiceStatus::Unknown => -128,
@@ -109,12 +166,14 @@ impl ServiceStatus {
     pub fn as_html_class_background(self) -> &'static str {
         match self {
             ServiceStatus::Ok => "success",
-            ServiceStatus::Critical | ServiceStatus::Error => "danger",
+            ServiceStatus::Critical | ServiceStatus::Error | ServiceStatus::Timeout => "danger",
             ServiceStatus::Checking | ServiceStatus::Warning => "warning",
             ServiceStatus::Pending | ServiceStatus::Disabled | ServiceStatus::Unknown => {
                 "secondary"
             }
             ServiceStatus::Urgent => "primary",
+            ServiceStatus::Cancelled => "info",
+            ServiceStatus::ConfigError => "dark",
         }
     }
 
@@ -122,10 +181,12 @@ impl ServiceStatus {
     pub fn as_html_class_text(self) -> &'static str {
         match self {
             ServiceStatus::Ok => "light",
-            ServiceStatus::Critical | ServiceStatus::Error => "dark",
+            ServiceStatus::Critical | ServiceStatus::Error | ServiceStatus::Timeout => "dark",
             ServiceStatus::Checking | ServiceStatus::Warning => "light",
             ServiceStatus::Pending | ServiceStatus::Disabled | ServiceStatus::Unknown => "dark",
             ServiceStatus::Urgent => "light",
+            ServiceStatus::Cancelled => "dark",
+            ServiceStatus::ConfigError => "light",
         }
     }
 }
@@ -136,6 +197,15 @@ pub trait ServiceTrait: Debug + Sync + Send {
     /// Run the service check
     async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error>;
 
+    /// Run the service check on the Maremma server, where it can look at the database
+    async fn run_local(
+        &self,
+        host: &entities::host::Model,
+        _db: &DatabaseConnection,
+    ) -> Result<CheckResult, Error> {
+        self.run(host).await
+    }
+
     /// Validate the configuration against some extra rules
     fn validate(&self) -> Result<(), Error> {
         debug!("You're using the default always-ok validation for this service");
@@ -157,6 +227,42 @@ pub trait ServiceTrait: Debug + Sync + Send {
     fn jitter_value(&self) -> u32;
 }
 
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Default)]
+/// Warning and critical levels, where each check has its own defaults
+pub struct Thresholds {
+    /// Warn at or above this
+    pub warning: Option<f64>,
+    /// Critical at or above this
+    pub critical: Option<f64>,
+}
+
+impl Thresholds {
+    /// Fills in the defaults, returning (warning, critical)
+    pub fn or(&self, warning: f64, critical: f64) -> (f64, f64) {
+        (
+            self.warning.unwrap_or(warning),
+            self.critical.unwrap_or(critical),
+        )
+    }
+
+    /// The status of a value against warning and critical levels
+    pub fn status(value: f64, warning: f64, critical: f64) -> ServiceStatus {
+        if value >= critical {
+            ServiceStatus::Critical
+        } else if value >= warning {
+            ServiceStatus::Warning
+        } else {
+            ServiceStatus::Ok
+        }
+    }
+}
+
+/// Parses an IANA timezone name like `Europe/Berlin`
+pub(crate) fn parse_timezone(timezone: &str) -> Result<Tz, Error> {
+    Tz::from_str(timezone.trim())
+        .map_err(|_| Error::Configuration(format!("'{}' isn't a valid timezone", timezone)))
+}
+
 /// Allows you to overlay host-specific content for services
 pub trait ConfigOverlay: Serialize {
     /// Serialize to a string for viewing
@@ -218,13 +324,14 @@ pub trait ConfigOverlay: Serialize {
                     "Failed to extract field {} from host configuration: {:?}",
                     key, err
                 );
-                Error::from(err)
+                // it's the host's config that's wrong, not the thing being checked
+                Error::Configuration(format!("Invalid {} in host configuration: {}", key, err))
             }),
             None => Ok(default.to_owned()),
         }
     }
 
-    /// Pulls the host config out of the host model
+    /// Pulls the host config out of the host model, with the host's `vars` filled in
     fn get_host_config(&self, name: &str, host: &host::Model) -> Result<Map<String, Value>, Error> {
         let config = match host.config.as_object() {
             Some(val) => Ok(val.clone()),
@@ -234,12 +341,28 @@ pub trait ConfigOverlay: Serialize {
             ))),
         }?;
 
-        match config.get(name) {
-            Some(val) => val.as_object().cloned().ok_or(Error::Configuration(format!(
-                "Failed to parse {} config",
-                name
-            ))),
-            None => Ok(Map::new()),
+        let mut res = match config.get(name) {
+            Some(val) => val
+                .as_object()
+                .cloned()
+                .ok_or(Error::Configuration(format!(
+                    "Failed to parse {} config",
+                    name
+                )))?,
+            None => Map::new(),
+        };
+
+        // service fields that use the host's vars are overlaid with them filled in
+        if let Value::Object(fields) = serde_json::to_value(self)? {
+            for (key, value) in fields {
+                if !res.contains_key(&key) && !find_host_vars_in_value(&value).is_empty() {
+                    res.insert(key, value);
+                }
+            }
+        }
+        match expand_host_vars(&Value::Object(res), host)? {
+            Value::Object(res) => Ok(res),
+            _ => Ok(Map::new()),
         }
     }
 
@@ -265,6 +388,10 @@ pub struct Service {
     /// Host groups to apply it to
     pub host_groups: Vec<String>,
 
+    #[serde(default)]
+    /// Free-form labels, eg `{"team": "payments"}`, added to the host's labels in metrics
+    pub labels: Labels,
+
     /// What kind of service it is
     pub service_type: ServiceType,
     #[serde(with = "crate::serde::cron")]
@@ -272,6 +399,34 @@ pub struct Service {
     /// Cron schedule for the service, eg `@hourly`, `* * * * * *` or `0 0 * * *`
     pub cron_schedule: Cron,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The entry in `service_templates` this is based on, anything the service doesn't set comes from the template
+    pub template: Option<String>,
+
+    #[serde(default)]
+    /// Pin the service to a remote agent (by name or zone), it won't be run by the local check loop
+    pub agent: Option<String>,
+
+    #[serde(default)]
+    /// Include this service in the Prometheus alerting rules from `maremma export-prometheus-rules`
+    pub expose_alert_rule: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How long the check has to be failing before the Prometheus alert fires, eg `5m`
+    pub alert_rule_for: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Override the notification routing for this service
+    pub notifications: Option<NotificationRouting>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// How long the check can run before it's stopped and marked as timed out (seconds), defaults to [crate::constants::DEFAULT_MAX_RUNTIME_SECONDS]
+    pub max_runtime: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The timezone `cron_schedule` is evaluated in, eg `Europe/Berlin`, defaults to UTC. Hosts can override it in their config for the service
+    pub timezone: Option<String>,
+
     /// Catch-all for the other fields in the config
     #[serde(flatten)]
     pub extra_config: HashMap<String, Value>,
@@ -307,6 +462,54 @@ pub(crate) fn service_config_parse(
             tls::TlsService::from_config(value)
                 .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
         ) as Box<dyn ServiceTrait>,
+        ServiceType::Docker => Box::new(
+            docker::DockerService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Passive => Box::new(
+            passive::PassiveService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::SelfMonitor => Box::new(
+            self_monitor::SelfMonitorService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Heartbeat => Box::new(
+            heartbeat::HeartbeatService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Disk => Box::new(
+            disk::DiskService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::System => Box::new(
+            system::SystemService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Mail => Box::new(
+            mail::MailService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Database => Box::new(
+            database::DatabaseService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Ntp => Box::new(
+            ntp::NtpService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::CertFile => Box::new(
+            cert_file::CertFileService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Websocket => Box::new(
+            websocket::WebsocketService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
+        ServiceType::Plugin => Box::new(
+            plugin::PluginService::from_config(value)
+                .inspect_err(|_| error!("Failed to parse config for {}", service_identifier))?,
+        ) as Box<dyn ServiceTrait>,
     };
 
     res.validate()?;
@@ -329,13 +532,37 @@ impl Service {
             name,
             description,
             host_groups,
+            labels: Labels::new(),
             service_type,
             cron_schedule,
+            template: None,
+            agent: None,
+            expose_alert_rule: false,
+            alert_rule_for: None,
+            notifications: None,
+            max_runtime: None,
+            timezone: None,
             extra_config,
             config: None,
         }
     }
 
+    /// Pin the service to a remote agent or zone
+    pub fn with_agent(self, agent: impl ToString) -> Self {
+        Self {
+            agent: Some(agent.to_string()),
+            ..self
+        }
+    }
+
+    /// How long the check can run before it's stopped
+    pub fn max_runtime(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.max_runtime
+                .unwrap_or(crate::constants::DEFAULT_MAX_RUNTIME_SECONDS),
+        )
+    }
+
     /// Config getter
     pub fn config(&self) -> Option<&dyn ServiceTrait> {
         self.config.as_deref()
@@ -357,8 +584,16 @@ impl Service {
             name: self.name.to_owned(),
             description: self.description.to_owned(),
             host_groups: self.host_groups.to_owned(),
+            labels: self.labels.to_owned(),
             service_type: self.service_type.to_owned(),
             cron_schedule: self.cron_schedule.to_owned(),
+            template: self.template.to_owned(),
+            agent: self.agent.to_owned(),
+            expose_alert_rule: self.expose_alert_rule,
+            alert_rule_for: self.alert_rule_for.to_owned(),
+            notifications: self.notifications.to_owned(),
+            max_runtime: self.max_runtime,
+            timezone: self.timezone.to_owned(),
             extra_config: self.extra_config.to_owned(),
             config: Some(config),
         })
@@ -380,23 +615,33 @@ impl Service {
         value: &entities::service::Model,
         db: &DatabaseConnection,
     ) -> Result<Self, Error> {
-        let host_groups: Vec<String> = value
-            .find_linked(entities::service_group_link::ServiceToGroups)
-            .all(db)
-            .await?
-            .into_iter()
-            .map(|group| group.name)
-            .collect();
-
-        let extra_config = serde_json::from_value(value.extra_config.clone())?;
+        let host_groups = entities::service_group_link::Entity::selectors(db, value.id).await?;
+
+        let mut extra_config: HashMap<String, Value> =
+            serde_json::from_value(value.extra_config.clone())?;
+        // it's stored with the service's config, see [entities::service::Entity::update_db_from_config]
+        let max_runtime = extra_config
+            .remove("max_runtime")
+            .and_then(|max_runtime| max_runtime.as_u64());
+        let timezone = extra_config
+            .remove("timezone")
+            .and_then(|timezone| timezone.as_str().map(str::to_string));
 
         let service = Service {
             id: value.id,
             name: Some(value.name.clone()),
             description: value.description.clone(),
             host_groups,
+            labels: crate::labels::from_json(&value.labels),
             service_type: value.service_type.clone(),
             cron_schedule: Cron::new(&value.cron_schedule).parse()?,
+            template: None,
+            agent: value.agent.clone(),
+            expose_alert_rule: false,
+            alert_rule_for: None,
+            notifications: None,
+            max_runtime,
+            timezone,
             extra_config,
             config: None,
         }
@@ -438,6 +683,44 @@ pub enum ServiceType {
     /// TLS service
     #[sea_orm(string_value = "tls")]
     Tls,
+    /// Docker/Podman container service
+    #[sea_orm(string_value = "dock")]
+    Docker,
+    /// Passive service, results are submitted rather than checked
+    #[sea_orm(string_value = "pasv")]
+    Passive,
+    /// Self-monitoring service, checks Maremma itself
+    #[serde(rename = "self")]
+    #[value(name = "self")]
+    #[sea_orm(string_value = "self")]
+    SelfMonitor,
+    /// Heartbeat service, goes critical if its ping URL isn't hit often enough
+    #[sea_orm(string_value = "beat")]
+    Heartbeat,
+    /// Filesystem usage, over SSH or on the local checks host
+    #[sea_orm(string_value = "disk")]
+    Disk,
+    /// Load average, memory and swap use, over SSH or on the local checks host
+    #[sea_orm(string_value = "sys")]
+    System,
+    /// SMTP, IMAP or POP3 mail server
+    #[sea_orm(string_value = "mail")]
+    Mail,
+    /// Postgres, MySQL or Redis server
+    #[sea_orm(string_value = "db")]
+    Database,
+    /// Clock drift against NTP or over SSH
+    #[sea_orm(string_value = "ntp")]
+    Ntp,
+    /// Certificate files on the host
+    #[sea_orm(string_value = "cfile")]
+    CertFile,
+    /// WebSocket endpoint
+    #[sea_orm(string_value = "ws")]
+    Websocket,
+    /// An external executable, see [plugin]
+    #[sea_orm(string_value = "plug")]
+    Plugin,
 }
 
 impl Display for ServiceType {
@@ -448,6 +731,18 @@ impl Display for ServiceType {
             Self::Ping => write!(f, "Ping"),
             Self::Http => write!(f, "HTTP"),
             Self::Tls => write!(f, "TLS"),
+            Self::Docker => write!(f, "Docker"),
+            Self::Passive => write!(f, "Passive"),
+            Self::SelfMonitor => write!(f, "Maremma"),
+            Self::Heartbeat => write!(f, "Heartbeat"),
+            Self::Disk => write!(f, "Disk"),
+            Self::System => write!(f, "System"),
+            Self::Mail => write!(f, "Mail"),
+            Self::Database => write!(f, "Database"),
+            Self::Ntp => write!(f, "NTP"),
+            Self::CertFile => write!(f, "Certificate file"),
+            Self::Websocket => write!(f, "WebSocket"),
+            Self::Plugin => write!(f, "Plugin"),
         }
     }
 }
@@ -496,6 +791,12 @@ mod tests {
             "secondary"
         );
         assert_eq!(ServiceStatus::Urgent.as_html_class_background(), "primary");
+        assert_eq!(ServiceStatus::Cancelled.as_html_class_background(), "info");
+        assert_eq!(
+            ServiceStatus::ConfigError.as_html_class_background(),
+            "dark"
+        );
+        assert_eq!(ServiceStatus::Timeout.as_html_class_background(), "danger");
     }
 
     #[test]
@@ -507,6 +808,9 @@ mod tests {
         assert_eq!(ServiceStatus::Disabled.as_html_class_text(), "dark");
         assert_eq!(ServiceStatus::Unknown.as_html_class_text(), "dark");
         assert_eq!(ServiceStatus::Urgent.as_html_class_text(), "light");
+        assert_eq!(ServiceStatus::Cancelled.as_html_class_text(), "dark");
+        assert_eq!(ServiceStatus::ConfigError.as_html_class_text(), "light");
+        assert_eq!(ServiceStatus::Timeout.as_html_class_text(), "dark");
     }
 
     #[tokio::test]
@@ -518,12 +822,12 @@ mod tests {
 
         let service_model = entities::service::Entity::find()
             .filter(entities::service::Column::ServiceType.eq(ServiceType::Ping))
-            .one(&*db.read().await)
+            .one(&db)
             .await
             .unwrap()
             .unwrap();
 
-        let service_from_model = Service::try_from_service_model(&service_model, &*db.read().await)
+        let service_from_model = Service::try_from_service_model(&service_model, &db)
             .await
             .expect("Failed to convert model to service");
 
@@ -536,7 +840,7 @@ mod tests {
         };
 
         let service_without_host_groups_model =
-            Service::try_from_service_model(&model_without_host_groups, &*db.read().await)
+            Service::try_from_service_model(&model_without_host_groups, &db)
                 .await
                 .expect("Failed to take service without groups from model");
         dbg!(&service_without_host_groups_model.host_groups);
@@ -553,6 +857,16 @@ mod tests {
         assert_eq!(format!("{}", ServiceType::Ping), "Ping");
         assert_eq!(format!("{}", ServiceType::Http), "HTTP");
         assert_eq!(format!("{}", ServiceType::Tls), "TLS");
+        assert_eq!(format!("{}", ServiceType::Docker), "Docker");
+        assert_eq!(format!("{}", ServiceType::Passive), "Passive");
+        assert_eq!(format!("{}", ServiceType::SelfMonitor), "Maremma");
+        assert_eq!(format!("{}", ServiceType::Heartbeat), "Heartbeat");
+        assert_eq!(format!("{}", ServiceType::Disk), "Disk");
+        assert_eq!(format!("{}", ServiceType::System), "System");
+        assert_eq!(format!("{}", ServiceType::Mail), "Mail");
+        assert_eq!(format!("{}", ServiceType::Database), "Database");
+        assert_eq!(format!("{}", ServiceType::Ntp), "NTP");
+        assert_eq!(format!("{}", ServiceType::CertFile), "Certificate file");
     }
 
     #[test]
@@ -671,10 +985,13 @@ mod tests {
             vec![
                 ServiceStatus::Critical,
                 ServiceStatus::Error,
+                ServiceStatus::Timeout,
+                ServiceStatus::ConfigError,
                 ServiceStatus::Urgent,
                 ServiceStatus::Checking,
                 ServiceStatus::Warning,
                 ServiceStatus::Ok,
+                ServiceStatus::Cancelled,
                 ServiceStatus::Pending,
                 ServiceStatus::Disabled,
                 ServiceStatus::Unknown,
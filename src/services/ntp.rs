@@ -0,0 +1,527 @@
+//! Clock drift checks - ask an NTP server for the time, or read the host's clock over SSH, and compare it to ours
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use super::prelude::*;
+use super::ssh::SshConnection;
+use super::Thresholds;
+use crate::prelude::*;
+
+/// The port NTP servers listen on
+pub const DEFAULT_NTP_PORT: u16 = 123;
+/// How long to wait for an answer, in seconds
+pub const DEFAULT_NTP_TIMEOUT_SECONDS: u32 = 5;
+/// Default (warning, critical) for the clock offset in seconds, the same as `check_ntp_time`
+pub const DEFAULT_OFFSET_THRESHOLDS: (f64, f64) = (60.0, 120.0);
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+/// Reads the remote clock, busybox `date` doesn't do `%N` so that's handled when parsing
+const DATE_COMMAND: &str = "date -u +%s.%N";
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+/// Where the time's read from
+pub enum NtpMode {
+    /// Query an NTP server
+    #[default]
+    Ntp,
+    /// Run `date` on the host over SSH
+    Ssh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// One reading of the remote clock, all in seconds
+pub(crate) struct TimeSample {
+    /// How far the remote clock is ahead of ours, negative when it's behind
+    pub(crate) offset: f64,
+    /// The round trip, less the time the server spent answering
+    pub(crate) delay: f64,
+    /// Only for NTP
+    pub(crate) stratum: Option<u8>,
+}
+
+/// The current time as fractional seconds since the Unix epoch
+fn unix_seconds(time: DateTime<Utc>) -> f64 {
+    time.timestamp() as f64 + time.timestamp_subsec_nanos() as f64 / 1e9
+}
+
+/// Encodes Unix seconds as an NTP timestamp, 32 bits of seconds since 1900 and 32 bits of fraction
+fn to_ntp_timestamp(seconds: f64) -> [u8; 8] {
+    let seconds = seconds + NTP_UNIX_OFFSET;
+    let whole = seconds.trunc() as u32;
+    let fraction = (seconds.fract() * 4_294_967_296.0) as u32;
+    let mut timestamp = [0; 8];
+    timestamp[..4].copy_from_slice(&whole.to_be_bytes());
+    timestamp[4..].copy_from_slice(&fraction.to_be_bytes());
+    timestamp
+}
+
+/// Decodes an NTP timestamp to Unix seconds
+fn from_ntp_timestamp(timestamp: &[u8]) -> f64 {
+    let field = |range: std::ops::Range<usize>| {
+        timestamp
+            .get(range)
+            .and_then(|bytes| <[u8; 4]>::try_from(bytes).ok())
+            .map(u32::from_be_bytes)
+            .unwrap_or(0) as f64
+    };
+    field(0..4) + field(4..8) / 4_294_967_296.0 - NTP_UNIX_OFFSET
+}
+
+/// A client request, version 4 in client mode, with the time it was sent
+pub(crate) fn ntp_request(sent: f64) -> [u8; 48] {
+    let mut packet = [0; 48];
+    // leap indicator 0, version 4, mode 3 (client)
+    packet[0] = 0b00_100_011;
+    packet[40..48].copy_from_slice(&to_ntp_timestamp(sent));
+    packet
+}
+
+/// Checks the server's reply and works out the offset, `received` is when it arrived by our clock
+pub(crate) fn parse_ntp_reply(
+    reply: &[u8],
+    request: &[u8; 48],
+    received: f64,
+) -> Result<TimeSample, String> {
+    if reply.len() < 48 {
+        return Err(format!("The reply was too short ({} bytes)", reply.len()));
+    }
+    if reply[0] & 0b111 != 4 {
+        return Err("The reply wasn't from an NTP server".to_string());
+    }
+    if reply[1] == 0 {
+        return Err(format!(
+            "The server sent a kiss-o'-death ({})",
+            String::from_utf8_lossy(&reply[12..16]).trim_end_matches('\0')
+        ));
+    }
+    if reply[0] >> 6 == 3 {
+        return Err("The server's clock isn't synchronised".to_string());
+    }
+    if reply[24..32] != request[40..48] {
+        return Err("The reply didn't match the request".to_string());
+    }
+
+    let sent = from_ntp_timestamp(&request[40..48]);
+    let server_received = from_ntp_timestamp(&reply[32..40]);
+    let server_sent = from_ntp_timestamp(&reply[40..48]);
+    Ok(TimeSample {
+        offset: ((server_received - sent) + (server_sent - received)) / 2.0,
+        delay: (received - sent) - (server_sent - server_received),
+        stratum: Some(reply[1]),
+    })
+}
+
+/// Parses the output of [DATE_COMMAND], which is just whole seconds without `%N`
+pub(crate) fn parse_date(output: &str) -> Result<f64, Error> {
+    let output = output.trim();
+    output
+        .parse::<f64>()
+        .or_else(|_| {
+            output
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .parse::<i64>()
+                .map(|seconds| seconds as f64)
+        })
+        .map_err(|_| Error::Generic(format!("Couldn't parse the time from the host: {}", output)))
+}
+
+/// Splits `host`, `host:port` or `[::1]:port`
+fn split_server(server: &str) -> (String, u16) {
+    if let Some((host, port)) = server.rsplit_once(':') {
+        if let Ok(port) = port.parse::<u16>() {
+            if host.starts_with('[') || !host.contains(':') {
+                return (
+                    host.trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .to_string(),
+                    port,
+                );
+            }
+        }
+    }
+    (
+        server
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        DEFAULT_NTP_PORT,
+    )
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// Checks how far a clock has drifted, either an NTP server's or the host's own
+pub struct NtpService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// Query an NTP server (the default) or read the clock over SSH
+    #[serde(default)]
+    pub mode: NtpMode,
+
+    /// The NTP server to query, `host` or `host:port`, defaults to the host being checked
+    pub server: Option<String>,
+
+    /// How far off the clock can be in seconds, either way, defaults to [DEFAULT_OFFSET_THRESHOLDS]
+    #[serde(default)]
+    pub offset: Thresholds,
+
+    /// How to connect to the host in `ssh` mode, these sit alongside the other options,
+    /// in `ntp` mode `timeout` is how long to wait for a reply and defaults to [DEFAULT_NTP_TIMEOUT_SECONDS]
+    #[serde(flatten)]
+    pub ssh: SshConnection,
+}
+
+impl ConfigOverlay for NtpService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            mode: self.extract_value(value, "mode", &self.mode)?,
+            server: self.extract_value(value, "server", &self.server)?,
+            offset: self.extract_value(value, "offset", &self.offset)?,
+            ssh: *self.ssh.overlay_host_config(value)?,
+        }))
+    }
+}
+
+impl NtpService {
+    fn offset_thresholds(&self) -> (f64, f64) {
+        self.offset
+            .or(DEFAULT_OFFSET_THRESHOLDS.0, DEFAULT_OFFSET_THRESHOLDS.1)
+    }
+
+    /// Sends one request and waits for the answer
+    async fn query_ntp(&self, server: &str) -> Result<TimeSample, String> {
+        let (hostname, port) = split_server(server);
+        let address = tokio::net::lookup_host((hostname.as_str(), port))
+            .await
+            .map_err(|err| format!("Failed to resolve {}: {}", hostname, err))?
+            .next()
+            .ok_or_else(|| format!("{} didn't resolve to anything", hostname))?;
+        let bind_address: SocketAddr = match address {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_address)
+            .await
+            .map_err(|err| format!("Failed to open a socket: {}", err))?;
+        socket
+            .connect(address)
+            .await
+            .map_err(|err| format!("Failed to connect to {}: {}", address, err))?;
+
+        let request = ntp_request(unix_seconds(Utc::now()));
+        socket
+            .send(&request)
+            .await
+            .map_err(|err| format!("Failed to send to {}: {}", address, err))?;
+        let mut reply = [0; 1024];
+        let timeout = self.ssh.timeout.unwrap_or(DEFAULT_NTP_TIMEOUT_SECONDS);
+        let length =
+            tokio::time::timeout(Duration::from_secs(timeout as u64), socket.recv(&mut reply))
+                .await
+                .map_err(|_| format!("No reply from {} after {} seconds", address, timeout))?
+                .map_err(|err| format!("Failed to read from {}: {}", address, err))?;
+        parse_ntp_reply(&reply[..length], &request, unix_seconds(Utc::now()))
+    }
+
+    /// Runs `date` on the host, taking the middle of the round trip as our time
+    async fn query_ssh(
+        &self,
+        host: &entities::host::Model,
+    ) -> Result<Result<TimeSample, String>, Error> {
+        let before = unix_seconds(Utc::now());
+        let output = match self.ssh.run_on_host(&self.name, host, DATE_COMMAND).await? {
+            Ok(output) => output,
+            Err(err) => return Ok(Err(err)),
+        };
+        let after = unix_seconds(Utc::now());
+        Ok(Ok(TimeSample {
+            offset: parse_date(&output)? - (before + after) / 2.0,
+            delay: after - before,
+            stratum: None,
+        }))
+    }
+
+    /// Works out the status, returning it with the summary and details
+    fn evaluate(&self, sample: &TimeSample) -> (ServiceStatus, String, BTreeMap<String, String>) {
+        let (warning, critical) = self.offset_thresholds();
+        let status = Thresholds::status(sample.offset.abs(), warning, critical);
+        let mut summary = format!("offset {:+.6}s, delay {:.6}s", sample.offset, sample.delay);
+        let mut details = BTreeMap::from([
+            (
+                "offset_seconds".to_string(),
+                format!("{:.6}", sample.offset),
+            ),
+            ("delay_seconds".to_string(), format!("{:.6}", sample.delay)),
+        ]);
+        if let Some(stratum) = sample.stratum {
+            summary.push_str(&format!(", stratum {}", stratum));
+            details.insert("stratum".to_string(), stratum.to_string());
+        }
+        if status != ServiceStatus::Ok {
+            summary.push_str(&format!(" ({})", status));
+        }
+        (status, summary, details)
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for NtpService {
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = chrono::Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+
+        let sample = match config.mode {
+            NtpMode::Ntp => {
+                config
+                    .query_ntp(config.server.as_deref().unwrap_or(&host.hostname))
+                    .await
+            }
+            NtpMode::Ssh => config.query_ssh(host).await?,
+        };
+        let sample = match sample {
+            Ok(sample) => sample,
+            Err(result_text) => {
+                return Ok(CheckResult {
+                    timestamp: start_time,
+                    result_text,
+                    status: ServiceStatus::Critical,
+                    time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
+                })
+            }
+        };
+
+        let (status, result_text, details) = config.evaluate(&sample);
+        Ok(CheckResult {
+            timestamp: start_time,
+            status,
+            result_text,
+            details,
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..Default::default()
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let (warning, critical) = self.offset_thresholds();
+        if warning > critical {
+            return Err(Error::Configuration(format!(
+                "{}: offset warning ({}) can't be above critical ({})",
+                self.name, warning, critical
+            )));
+        }
+        if self.mode == NtpMode::Ssh && self.server.is_some() {
+            return Err(Error::Configuration(format!(
+                "{}: server only works in ntp mode",
+                self.name
+            )));
+        }
+        self.ssh.validate()
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::service_from_json;
+
+    /// Answers one request as a stratum 2 server whose clock is `skew` seconds out
+    async fn fake_ntp_server(skew: f64) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind socket");
+        let address = socket.local_addr().expect("Failed to get address");
+        tokio::spawn(async move {
+            let mut request = [0; 48];
+            if let Ok((_, client)) = socket.recv_from(&mut request).await {
+                let now = unix_seconds(Utc::now()) + skew;
+                let mut reply = [0; 48];
+                // leap indicator 0, version 4, mode 4 (server)
+                reply[0] = 0b00_100_100;
+                reply[1] = 2;
+                reply[24..32].copy_from_slice(&request[40..48]);
+                reply[32..40].copy_from_slice(&to_ntp_timestamp(now));
+                reply[40..48].copy_from_slice(&to_ntp_timestamp(now));
+                let _ = socket.send_to(&reply, client).await;
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_ntp_timestamps() {
+        let now = 1_700_000_000.25;
+        let timestamp = to_ntp_timestamp(now);
+        assert!((from_ntp_timestamp(&timestamp) - now).abs() < 1e-6);
+        assert_eq!(
+            u32::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]),
+            3_908_988_800
+        );
+    }
+
+    #[test]
+    fn test_parse_ntp_reply() {
+        let request = ntp_request(1_700_000_000.0);
+        let mut reply = [0; 48];
+        reply[0] = 0b00_100_100;
+        reply[1] = 1;
+        reply[24..32].copy_from_slice(&request[40..48]);
+        reply[32..40].copy_from_slice(&to_ntp_timestamp(1_700_000_002.0));
+        reply[40..48].copy_from_slice(&to_ntp_timestamp(1_700_000_002.5));
+
+        let sample =
+            parse_ntp_reply(&reply, &request, 1_700_000_001.0).expect("Failed to parse reply");
+        assert!((sample.offset - 1.75).abs() < 1e-6);
+        assert!((sample.delay - 0.5).abs() < 1e-6);
+        assert_eq!(sample.stratum, Some(1));
+
+        assert!(parse_ntp_reply(&reply[..40], &request, 1_700_000_001.0).is_err());
+        assert!(parse_ntp_reply(&reply, &ntp_request(1.0), 1_700_000_001.0).is_err());
+
+        let mut kiss = reply;
+        kiss[1] = 0;
+        kiss[12..16].copy_from_slice(b"RATE");
+        assert_eq!(
+            parse_ntp_reply(&kiss, &request, 1_700_000_001.0),
+            Err("The server sent a kiss-o'-death (RATE)".to_string())
+        );
+        let mut unsynchronised = reply;
+        unsynchronised[0] |= 0b11_000_000;
+        assert!(parse_ntp_reply(&unsynchronised, &request, 1_700_000_001.0).is_err());
+    }
+
+    #[test]
+    fn test_parse_date_and_server() {
+        assert_eq!(
+            parse_date("1700000000.500000000\n").expect("Failed to parse date"),
+            1_700_000_000.5
+        );
+        assert_eq!(
+            parse_date("1700000000.N").expect("Failed to parse busybox date"),
+            1_700_000_000.0
+        );
+        assert!(parse_date("Tue Nov 14 22:13:20 UTC 2023").is_err());
+
+        assert_eq!(
+            split_server("ntp.example.com"),
+            ("ntp.example.com".to_string(), 123)
+        );
+        assert_eq!(
+            split_server("127.0.0.1:1123"),
+            ("127.0.0.1".to_string(), 1123)
+        );
+        assert_eq!(split_server("[::1]:1123"), ("::1".to_string(), 1123));
+        assert_eq!(split_server("::1"), ("::1".to_string(), 123));
+    }
+
+    #[tokio::test]
+    async fn test_ntp_run() {
+        let host = entities::host::test_host();
+
+        let server = fake_ntp_server(0.0).await;
+        let result = service_from_json::<NtpService>("ntp", json!({"server": server.to_string()}))
+            .expect("Failed to parse ntp service")
+            .run(&host)
+            .await
+            .expect("Failed to run ntp check");
+        assert_eq!(result.status, ServiceStatus::Ok, "{}", result.result_text);
+        assert_eq!(result.details.get("stratum").map(String::as_str), Some("2"));
+
+        let server = fake_ntp_server(-90.0).await;
+        let result = service_from_json::<NtpService>("ntp", json!({"server": server.to_string()}))
+            .expect("Failed to parse ntp service")
+            .run(&host)
+            .await
+            .expect("Failed to run ntp check");
+        assert_eq!(
+            result.status,
+            ServiceStatus::Warning,
+            "{}",
+            result.result_text
+        );
+
+        let server = fake_ntp_server(2.0).await;
+        let result = service_from_json::<NtpService>(
+            "ntp",
+            json!({
+                "server": server.to_string(),
+                "offset": {"warning": 0.5, "critical": 1.0},
+            }),
+        )
+        .expect("Failed to parse ntp service")
+        .run(&host)
+        .await
+        .expect("Failed to run ntp check");
+        assert_eq!(
+            result.status,
+            ServiceStatus::Critical,
+            "{}",
+            result.result_text
+        );
+
+        // nothing's listening
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind socket");
+        let result = service_from_json::<NtpService>(
+            "ntp",
+            json!({
+                "server": socket.local_addr().expect("Failed to get address").to_string(),
+                "timeout": 1,
+            }),
+        )
+        .expect("Failed to parse ntp service")
+        .run(&host)
+        .await
+        .expect("Failed to run ntp check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+    }
+
+    #[test]
+    fn test_ntp_validate() {
+        assert!(service_from_json::<NtpService>("ntp", json!({}))
+            .expect("Failed to parse ntp service")
+            .validate()
+            .is_ok());
+        assert!(
+            service_from_json::<NtpService>("ntp", json!({"offset": {"warning": 200.0}}))
+                .expect("Failed to parse ntp service")
+                .validate()
+                .is_err()
+        );
+        assert!(service_from_json::<NtpService>(
+            "ntp",
+            json!({"mode": "ssh", "server": "ntp.example.com"})
+        )
+        .expect("Failed to parse ntp service")
+        .validate()
+        .is_err());
+    }
+}
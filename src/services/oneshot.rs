@@ -1,13 +1,25 @@
 //! Implements the `oneshot` CLI command and its related functions
 
-use crate::cli::OneShotCmd;
+use crate::cli::{OneShotCmd, OutputFormat};
 use crate::prelude::*;
+use crate::services::cert_file::CertFileService;
 use crate::services::cli::CliService;
+use crate::services::database::DatabaseService;
+use crate::services::disk::DiskService;
+use crate::services::docker::DockerService;
+use crate::services::heartbeat::HeartbeatService;
 use crate::services::http::HttpService;
+use crate::services::mail::MailService;
+use crate::services::ntp::NtpService;
+use crate::services::passive::PassiveService;
 use crate::services::ping::PingService;
+use crate::services::plugin::PluginService;
+use crate::services::self_monitor::SelfMonitorService;
 use crate::services::service_config_parse;
 use crate::services::ssh::SshService;
+use crate::services::system::SystemService;
 use crate::services::tls::TlsService;
+use crate::services::websocket::WebsocketService;
 
 /// Because I'm fancy and silly
 fn oneshot_uuid() -> Uuid {
@@ -23,6 +35,18 @@ fn export_config(cmd: &OneShotCmd) -> (String, String) {
         ServiceType::Ping => schema_for!(PingService),
         ServiceType::Http => schema_for!(HttpService),
         ServiceType::Tls => schema_for!(TlsService),
+        ServiceType::Docker => schema_for!(DockerService),
+        ServiceType::Passive => schema_for!(PassiveService),
+        ServiceType::SelfMonitor => schema_for!(SelfMonitorService),
+        ServiceType::Heartbeat => schema_for!(HeartbeatService),
+        ServiceType::Disk => schema_for!(DiskService),
+        ServiceType::System => schema_for!(SystemService),
+        ServiceType::Mail => schema_for!(MailService),
+        ServiceType::Database => schema_for!(DatabaseService),
+        ServiceType::Ntp => schema_for!(NtpService),
+        ServiceType::CertFile => schema_for!(CertFileService),
+        ServiceType::Websocket => schema_for!(WebsocketService),
+        ServiceType::Plugin => schema_for!(PluginService),
     };
     (
         format!("Dumping schema for {:?}", cmd.check),
@@ -33,8 +57,79 @@ fn export_config(cmd: &OneShotCmd) -> (String, String) {
     )
 }
 
-/// Runs a single check and exits
-pub async fn run_oneshot(cmd: OneShotCmd, _config: SendableConfig) -> Result<(), Error> {
+/// Applies a `--set key=value` override, dots in the key set nested values
+fn apply_override(service_config: &mut Map<String, Value>, item: &str) -> Result<(), Error> {
+    let (key, value) = item.split_once('=').ok_or_else(|| {
+        Error::Configuration(format!("Invalid --set '{}', should be key=value", item))
+    })?;
+    // numbers, bools and objects are handy to set, anything else is a string
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+
+    let mut keys = key.split('.').collect::<Vec<_>>();
+    let last = keys
+        .pop()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| Error::Configuration(format!("Invalid --set '{}', missing key", item)))?;
+    let mut target = service_config;
+    for key in keys {
+        let entry = target
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        target = entry.as_object_mut().ok_or_else(|| {
+            Error::Configuration(format!("Can't --set '{}', {} isn't an object", item, key))
+        })?;
+    }
+    target.insert(last.to_string(), value);
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+/// What's printed when a oneshot check's done
+pub struct OneShotResult {
+    /// The kind of check
+    pub service_type: ServiceType,
+    /// The hostname it ran against
+    pub hostname: String,
+    /// The result
+    pub status: ServiceStatus,
+    /// Any explanatory/returned text
+    pub result_text: String,
+    /// When the check finished
+    pub timestamp: DateTime<Utc>,
+    /// How long it took, in milliseconds
+    pub time_elapsed_ms: i64,
+}
+
+impl OneShotResult {
+    fn new(service_type: ServiceType, hostname: String, result: CheckResult) -> Self {
+        Self {
+            service_type,
+            hostname,
+            status: result.status,
+            result_text: result.result_text,
+            timestamp: result.timestamp,
+            time_elapsed_ms: result.time_elapsed.num_milliseconds(),
+        }
+    }
+
+    /// Renders the result for printing
+    fn render(&self, output: OutputFormat) -> Result<String, Error> {
+        match output {
+            OutputFormat::Text => Ok(format!(
+                "{} {}: {} ({}ms)\n{}",
+                self.service_type,
+                self.hostname,
+                self.status,
+                self.time_elapsed_ms,
+                self.result_text
+            )),
+            OutputFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+        }
+    }
+}
+
+/// Runs a single check and exits, failing if the result isn't OK so it can be used in scripts
+pub async fn run_oneshot(cmd: OneShotCmd, config: SendableConfig) -> Result<(), Error> {
     if cmd.show_config {
         let (msg, config) = export_config(&cmd);
         eprintln!("{}", msg);
@@ -45,6 +140,9 @@ pub async fn run_oneshot(cmd: OneShotCmd, _config: SendableConfig) -> Result<(),
 
     let service_config = match service_config.as_object_mut() {
         Some(obj) => {
+            for item in cmd.set.iter() {
+                apply_override(obj, item)?;
+            }
             obj.insert("name".to_string(), "oneshot".to_string().into());
             obj.insert("cron_schedule".to_string(), "* * * * *".to_string().into());
             debug!("{:?}", obj);
@@ -63,22 +161,40 @@ pub async fn run_oneshot(cmd: OneShotCmd, _config: SendableConfig) -> Result<(),
 
     service.validate()?;
 
+    // use a configured host's hostname if it's there, otherwise it's whatever we were given
+    let hostname = config
+        .read()
+        .await
+        .hosts
+        .iter()
+        .find(|(name, _)| entities::name_key(name) == entities::name_key(&cmd.hostname))
+        .and_then(|(_, host)| host.hostname.clone())
+        .unwrap_or_else(|| cmd.hostname.clone());
+
     let host = entities::host::Model {
         id: Uuid::new_v4(),
         name: cmd.hostname.clone(),
-        hostname: cmd.hostname.clone(),
+        slug: entities::slugify(&cmd.hostname),
+        hostname,
         check: crate::host::HostCheck::None,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     #[cfg(not(test))]
-    match service.run(&host).await {
-        Ok(res) => {
-            info!("Result: {:#?}", res);
-            Ok(())
-        }
-        Err(err) => {
+    {
+        let result = service.run(&host).await.inspect_err(|err| {
             error!("Failed to run service: {:#?}", err);
-            Err(err)
+        })?;
+        let result = OneShotResult::new(cmd.check.clone(), host.hostname.clone(), result);
+        println!("{}", result.render(cmd.output)?);
+        match result.status {
+            ServiceStatus::Ok => Ok(()),
+            _ => Err(Error::OneShotFailed),
         }
     }
     #[cfg(test)]
@@ -107,6 +223,8 @@ mod tests {
             check: ServiceType::Ping,
             hostname: "localhost".to_string(),
             service_config: json! {{"cron_schedule" : "@hourly"}}.to_string(),
+            set: vec![],
+            output: OutputFormat::Text,
             show_config: false,
         };
 
@@ -119,6 +237,8 @@ mod tests {
             check: ServiceType::Ping,
             hostname: "localhost".to_string(),
             service_config: json! {{}}.to_string(),
+            set: vec![],
+            output: OutputFormat::Text,
             show_config: false,
         };
 
@@ -136,7 +256,8 @@ mod tests {
             "username" : "test",
             "password" : "test",
             "command_line" : "echo",
-            "port" : 22
+            "port" : 22,
+            "containers" : ["test"]
         }}
         .to_string();
 
@@ -146,6 +267,8 @@ mod tests {
                 check,
                 hostname: "localhost".to_string(),
                 service_config: service_config.clone(),
+                set: vec![],
+                output: OutputFormat::Text,
                 show_config: true,
             };
 
@@ -157,6 +280,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_apply_override() {
+        let mut service_config = Map::new();
+        for item in [
+            "port=8443",
+            "http_uri=/health",
+            "validate_tls=false",
+            "basic_auth.username=admin",
+            "headers={\"X-Test\": \"yes\"}",
+        ] {
+            apply_override(&mut service_config, item).expect("Failed to apply override");
+        }
+        assert_eq!(
+            Value::Object(service_config.clone()),
+            json!({
+                "port": 8443,
+                "http_uri": "/health",
+                "validate_tls": false,
+                "basic_auth": {"username": "admin"},
+                "headers": {"X-Test": "yes"},
+            })
+        );
+        assert!(apply_override(&mut service_config, "port").is_err());
+        assert!(apply_override(&mut service_config, "=1").is_err());
+        assert!(apply_override(&mut service_config, "port.nested=1").is_err());
+    }
+
+    #[test]
+    fn test_oneshot_result_render() {
+        let result = OneShotResult::new(
+            ServiceType::Ping,
+            "example.com".to_string(),
+            CheckResult {
+                timestamp: chrono::Utc::now(),
+                time_elapsed: Duration::milliseconds(12),
+                status: ServiceStatus::Ok,
+                result_text: "pong".to_string(),
+                ..Default::default()
+            },
+        );
+        assert!(result
+            .render(OutputFormat::Text)
+            .expect("Failed to render text")
+            .contains("(12ms)"));
+        let parsed: Value = serde_json::from_str(
+            &result
+                .render(OutputFormat::Json)
+                .expect("Failed to render JSON"),
+        )
+        .expect("Failed to parse JSON");
+        assert_eq!(parsed["status"], json!("ok"));
+        assert_eq!(parsed["time_elapsed_ms"], json!(12));
+        assert_eq!(parsed["service_type"], json!("ping"));
+    }
+
     #[test]
     fn test_oneshot_uuid() {
         let uuid = oneshot_uuid();
@@ -172,6 +350,8 @@ mod tests {
             check: ServiceType::Ping,
             hostname: "localhost".to_string(),
             service_config,
+            set: vec![],
+            output: OutputFormat::Text,
             show_config: false,
         };
         let res = run_oneshot(cmd, config.clone()).await;
@@ -190,6 +370,8 @@ mod tests {
             check: ServiceType::Ssh,
             hostname: "localhost".to_string(),
             service_config,
+            set: vec![],
+            output: OutputFormat::Text,
             show_config: false,
         };
         let res = run_oneshot(cmd, config).await;
@@ -0,0 +1,395 @@
+//! Passive services, which Maremma doesn't run itself - results are submitted to it, eg from Alertmanager or `/api/v1/submit`
+
+use std::collections::BTreeMap;
+
+use super::heartbeat::HeartbeatService;
+use super::prelude::*;
+use crate::agent::service_value;
+use crate::check_loop::{record_check_result, CheckEnvironment};
+use crate::prelude::*;
+
+/// The schedule passive services are created with, it's not used to run anything
+pub const DEFAULT_PASSIVE_CRON: &str = "@daily";
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+/// Configuration for the `/api/v1/submit` endpoint that external systems push passive results to
+pub struct SubmitConfig {
+    /// Shared secret, send it as the bearer token with each result
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A result pushed in for a passive service check
+pub struct SubmittedResult {
+    /// The host the check belongs to
+    pub host: String,
+    /// The passive service the check is for
+    pub service: String,
+    /// The result
+    pub status: ServiceStatus,
+    /// A short summary of the result
+    #[serde(default)]
+    pub result_text: String,
+    /// Anything beyond the summary, eg the rest of a job's output
+    #[serde(default)]
+    pub long_output: Option<String>,
+    /// Key/value details, eg how many files were backed up
+    #[serde(default)]
+    pub details: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// A service whose results are pushed in from somewhere else
+pub struct PassiveService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service, passive services aren't run on it
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// If no result's been submitted for this many minutes the check goes Critical
+    pub freshness_minutes: Option<u32>,
+}
+
+impl ConfigOverlay for PassiveService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            freshness_minutes: self.extract_value(
+                value,
+                "freshness_minutes",
+                &self.freshness_minutes,
+            )?,
+        }))
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for PassiveService {
+    async fn run(&self, _host: &entities::host::Model) -> Result<CheckResult, Error> {
+        Err(Error::Generic(format!(
+            "{} is a passive service, results have to be submitted to Maremma",
+            self.name
+        )))
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.freshness_minutes == Some(0) {
+            return Err(Error::Configuration(format!(
+                "{}: freshness_minutes has to be more than 0",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How long a passive or heartbeat service check can go without a result, with the host's config for the service applied
+fn deadline_minutes(
+    service: &entities::service::Model,
+    host: &entities::host::Model,
+) -> Result<Option<u32>, Error> {
+    let value = service_value(service)?;
+    if service.service_type == ServiceType::Heartbeat {
+        let heartbeat = HeartbeatService::from_config(&value)?;
+        let heartbeat =
+            heartbeat.overlay_host_config(&heartbeat.get_host_config(&service.name, host)?)?;
+        return Ok(Some(heartbeat.deadline_minutes()));
+    }
+    let passive = PassiveService::from_config(&value)?;
+    let passive = passive.overlay_host_config(&passive.get_host_config(&service.name, host)?)?;
+    Ok(passive.freshness_minutes)
+}
+
+/// Records a submitted result against its passive service check, returning the service check's ID
+pub async fn submit_result(
+    db: DatabaseConnection,
+    submitted: &SubmittedResult,
+) -> Result<Uuid, Error> {
+    if !matches!(
+        submitted.status,
+        ServiceStatus::Ok
+            | ServiceStatus::Warning
+            | ServiceStatus::Critical
+            | ServiceStatus::Unknown
+            | ServiceStatus::Error
+    ) {
+        return Err(Error::InvalidInput(format!(
+            "Can't submit a result with status {}",
+            submitted.status
+        )));
+    }
+
+    let host = entities::host::Model::find_by_name(&submitted.host, &db)
+        .await?
+        .ok_or_else(|| Error::HostNotFoundByName(submitted.host.clone()))?;
+    let service = entities::service::Model::find_by_name(&submitted.service, &db)
+        .await?
+        .filter(|service| service.service_type == ServiceType::Passive)
+        .ok_or_else(|| Error::ServiceNotFoundByName(submitted.service.clone()))?;
+    let service_check = entities::service_check::Entity::find()
+        .filter(entities::service_check::Column::HostId.eq(host.id))
+        .filter(entities::service_check::Column::ServiceId.eq(service.id))
+        .one(&db)
+        .await?
+        .ok_or_else(|| {
+            Error::ServiceNotFoundByName(format!("{} on {}", submitted.service, submitted.host))
+        })?;
+
+    let result = CheckResult {
+        timestamp: chrono::Utc::now(),
+        time_elapsed: TimeDelta::zero(),
+        status: submitted.status,
+        result_text: submitted.result_text.clone(),
+        long_output: submitted.long_output.clone(),
+        details: submitted.details.clone(),
+        ..Default::default()
+    };
+    let environment = CheckEnvironment {
+        runner: "submit".to_string(),
+        ..CheckEnvironment::local(None)
+    };
+    record_check_result(db, &service_check, &service, &result, &environment, 0).await?;
+    Ok(service_check.id)
+}
+
+/// Marks passive service checks Critical if nothing's been submitted within their `freshness_minutes`, and heartbeat
+/// checks if they haven't been pinged in time, returning how many went stale
+pub async fn expire_stale_checks(
+    db: &DatabaseConnection,
+    now: DateTime<Utc>,
+) -> Result<usize, Error> {
+    let checks = entities::service_check::Entity::find()
+        .find_also_related(entities::service::Entity)
+        .filter(
+            entities::service::Column::ServiceType
+                .is_in([ServiceType::Passive, ServiceType::Heartbeat]),
+        )
+        .filter(
+            entities::service_check::Column::Status
+                .is_not_in([ServiceStatus::Critical, ServiceStatus::Disabled]),
+        )
+        .all(db)
+        .await?;
+
+    let mut expired = 0;
+    for (service_check, service) in checks {
+        let Some(service) = service else {
+            continue;
+        };
+        let Some(host) = entities::host::Entity::find_by_id(service_check.host_id)
+            .one(db)
+            .await?
+        else {
+            continue;
+        };
+        let minutes = match deadline_minutes(&service, &host) {
+            Ok(Some(minutes)) => minutes,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!(
+                    "Couldn't work out the freshness of {} on {}: {:?}",
+                    service.name, host.name, err
+                );
+                continue;
+            }
+        };
+        if service_check.last_check + TimeDelta::minutes(minutes.into()) > now {
+            continue;
+        }
+
+        debug!(
+            "Passive service check {} is stale, last result at {}",
+            service_check.id, service_check.last_check
+        );
+        let result = CheckResult {
+            timestamp: now,
+            time_elapsed: TimeDelta::zero(),
+            status: ServiceStatus::Critical,
+            result_text: match service.service_type {
+                ServiceType::Heartbeat => {
+                    format!("No heartbeat received in the last {} minutes", minutes)
+                }
+                _ => format!("No result submitted in the last {} minutes", minutes),
+            },
+            ..Default::default()
+        };
+        let environment = CheckEnvironment {
+            runner: "freshness".to_string(),
+            ..CheckEnvironment::local(None)
+        };
+        record_check_result(
+            db.clone(),
+            &service_check,
+            &service,
+            &result,
+            &environment,
+            0,
+        )
+        .await?;
+        expired += 1;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+/// Adds a passive service and a check for it on one of the test hosts
+pub(crate) async fn test_passive_check(
+    db: &DatabaseConnection,
+    freshness_minutes: Option<u32>,
+) -> (
+    entities::host::Model,
+    entities::service::Model,
+    entities::service_check::Model,
+) {
+    #![allow(clippy::expect_used)]
+    let host = entities::host::Entity::find()
+        .one(db)
+        .await
+        .expect("Failed to query hosts")
+        .expect("No hosts found");
+    let extra_config = match freshness_minutes {
+        Some(minutes) => json!({ "freshness_minutes": minutes }),
+        None => json!({}),
+    };
+    let service = entities::service::Model {
+        id: Uuid::new_v4(),
+        name: "nightly backup".to_string(),
+        slug: "nightly-backup".to_string(),
+        description: None,
+        service_type: ServiceType::Passive,
+        cron_schedule: DEFAULT_PASSIVE_CRON.to_string(),
+        extra_config,
+        agent: None,
+        labels: json!({}),
+    }
+    .into_active_model()
+    .insert(db)
+    .await
+    .expect("Failed to insert passive service");
+    let service_check = entities::service_check::Model {
+        id: Uuid::new_v4(),
+        service_id: service.id,
+        host_id: host.id,
+        status: ServiceStatus::Pending,
+        last_check: chrono::Utc::now(),
+        next_check: chrono::Utc::now(),
+        last_updated: chrono::Utc::now(),
+        paused_until: None,
+    }
+    .into_active_model()
+    .insert(db)
+    .await
+    .expect("Failed to insert passive service check");
+    (host, service, service_check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::host::test_host;
+
+    #[tokio::test]
+    async fn test_passive_service() {
+        let service: PassiveService = serde_json::from_value(json!({
+            "name": "passive",
+            "cron_schedule": DEFAULT_PASSIVE_CRON,
+        }))
+        .expect("Failed to parse passive service");
+        assert!(service.run(&test_host()).await.is_err());
+        assert!(service.as_json_pretty(&test_host()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_submit_and_expire() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let (host, service, service_check) = test_passive_check(&db, Some(60)).await;
+
+        let submitted = SubmittedResult {
+            host: host.name.clone(),
+            service: service.name.clone(),
+            status: ServiceStatus::Warning,
+            result_text: "Backup finished with warnings".to_string(),
+            long_output: None,
+            details: Default::default(),
+        };
+        let id = submit_result(db.clone(), &submitted)
+            .await
+            .expect("Failed to submit result");
+        assert_eq!(id, service_check.id);
+        let found = entities::service_check::Entity::find_by_id(id)
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Service check went missing");
+        assert_eq!(found.status, ServiceStatus::Warning);
+
+        assert!(submit_result(
+            db.clone(),
+            &SubmittedResult {
+                status: ServiceStatus::Pending,
+                ..submitted.clone()
+            }
+        )
+        .await
+        .is_err());
+        assert_eq!(
+            submit_result(
+                db.clone(),
+                &SubmittedResult {
+                    service: "not a real service".to_string(),
+                    ..submitted
+                }
+            )
+            .await,
+            Err(Error::ServiceNotFoundByName(
+                "not a real service".to_string()
+            ))
+        );
+
+        // still fresh
+        let now = chrono::Utc::now();
+        assert_eq!(
+            expire_stale_checks(&db, now + TimeDelta::minutes(30))
+                .await
+                .expect("Failed to expire checks"),
+            0
+        );
+        assert_eq!(
+            expire_stale_checks(&db, now + TimeDelta::minutes(61))
+                .await
+                .expect("Failed to expire checks"),
+            1
+        );
+        let found = entities::service_check::Entity::find_by_id(id)
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Service check went missing");
+        assert_eq!(found.status, ServiceStatus::Critical);
+
+        // already critical, so it's not touched again
+        assert_eq!(
+            expire_stale_checks(&db, now + TimeDelta::minutes(200))
+                .await
+                .expect("Failed to expire checks"),
+            0
+        );
+    }
+}
@@ -1,9 +1,13 @@
 //! Basic ping service
 
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use surge_ping::SurgeError;
-use tokio::net::lookup_host;
 
 use super::prelude::*;
+use super::{threshold, traceroute};
+use crate::group_status::is_failing;
+use crate::host::address::{combine, target_addresses, AddressFamily};
 use crate::prelude::*;
 
 const DEFAULT_COUNT: u8 = 3;
@@ -30,6 +34,88 @@ pub struct PingService {
 
     /// Minimum successes required for the check to be considered successful, defaults to the same as count
     pub required_successful: Option<u8>,
+
+    /// Which IP versions to ping over, defaults to the host's `address_family`
+    #[serde(default)]
+    pub address_family: Option<AddressFamily>,
+
+    /// Warn if the pings take longer than this many milliseconds on average
+    #[serde(default)]
+    pub warn_latency_ms: Option<u64>,
+
+    /// Critical if the pings take longer than this many milliseconds on average
+    #[serde(default)]
+    pub crit_latency_ms: Option<u64>,
+
+    /// Milliseconds between sending each ping, by default they're all sent at once
+    #[serde(default)]
+    pub interval_ms: Option<u64>,
+
+    /// Warn if more than this percentage of the pings are lost, setting either loss threshold means lost pings don't fail the check by themselves
+    #[serde(default)]
+    pub warn_loss_percent: Option<f64>,
+
+    /// Critical if more than this percentage of the pings are lost
+    #[serde(default)]
+    pub crit_loss_percent: Option<f64>,
+
+    /// Run a traceroute when the check fails, adding where the path stops answering to the result
+    #[serde(default)]
+    pub traceroute_on_failure: bool,
+}
+
+/// What came back from a round of pings
+#[derive(Debug, Clone, PartialEq)]
+struct PingStats {
+    sent: u8,
+    /// The round trip times of the pings that came back
+    rtts: Vec<std::time::Duration>,
+}
+
+impl PingStats {
+    fn received(&self) -> u8 {
+        self.rtts.len() as u8
+    }
+
+    /// Rounded to one decimal place, so it reads well
+    fn loss_percent(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => ((sent - self.received()) as f64 * 1000.0 / sent as f64).round() / 10.0,
+        }
+    }
+
+    fn avg(&self) -> Option<std::time::Duration> {
+        (!self.rtts.is_empty())
+            .then(|| self.rtts.iter().sum::<std::time::Duration>() / self.rtts.len() as u32)
+    }
+
+    /// Packet loss and min/avg/max round trip times, for the result's details
+    fn details(&self) -> Vec<(String, String)> {
+        let mut res = vec![(
+            "packet_loss".to_string(),
+            format!("{}%", self.loss_percent()),
+        )];
+        if let (Some(min), Some(avg), Some(max)) =
+            (self.rtts.iter().min(), self.avg(), self.rtts.iter().max())
+        {
+            for (key, value) in [
+                ("rtt_min_ms", min),
+                ("rtt_avg_ms", &avg),
+                ("rtt_max_ms", max),
+            ] {
+                res.push((
+                    key.to_string(),
+                    format!("{:.3}", value.as_secs_f64() * 1000.0),
+                ));
+            }
+            res.push((
+                threshold::LATENCY_DETAIL.to_string(),
+                avg.as_millis().to_string(),
+            ));
+        }
+        res
+    }
 }
 
 impl PingService {
@@ -47,6 +133,104 @@ impl PingService {
             res
         }
     }
+
+    /// If the check alerts on packet loss, rather than needing `required_successful` pings back
+    fn loss_mode(&self) -> bool {
+        self.warn_loss_percent.is_some() || self.crit_loss_percent.is_some()
+    }
+
+    /// Pings the address `count` times, `interval_ms` apart, timeouts count as lost
+    async fn ping(&self, address: IpAddr) -> Result<PingStats, Error> {
+        let interval = std::time::Duration::from_millis(self.interval_ms.unwrap_or(0));
+        let results = (0..self.get_count())
+            .map(|index| {
+                tokio::spawn(async move {
+                    tokio::time::sleep(interval * index as u32).await;
+                    surge_ping::ping(address, &[0; 8]).await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut stats = PingStats {
+            sent: self.get_count(),
+            rtts: Vec::new(),
+        };
+        for (index, result) in results.into_iter().enumerate() {
+            match result.await {
+                Ok(Ok((_, dur))) => stats.rtts.push(dur),
+                Ok(Err(err @ SurgeError::Timeout { .. })) => {
+                    debug!("Ping {} timed out: {}", index, err.to_string());
+                }
+                Ok(Err(err)) => return Err(Error::Generic(err.to_string())),
+                Err(err) => {
+                    return Err(Error::Generic(format!("Running task failed: {}", err)));
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Works out the status from the pings, returning an error if too few came back when it isn't alerting on loss
+    fn evaluate(
+        &self,
+        host_name: &str,
+        stats: &PingStats,
+    ) -> Result<(String, ServiceStatus), Error> {
+        let loss = stats.loss_percent();
+        let avg = match stats.avg() {
+            Some(avg) if self.loss_mode() || stats.received() >= self.get_required_successful() => {
+                avg
+            }
+            _ if !self.loss_mode() => {
+                return Err(Error::Generic(format!(
+                    "CRITICAL: Ping failed: {} successful, {} failed",
+                    stats.received(),
+                    stats.sent - stats.received()
+                )))
+            }
+            _ => {
+                return Ok((
+                    format!("CRITICAL: Ping to {} had 100% packet loss", host_name),
+                    ServiceStatus::Critical,
+                ))
+            }
+        };
+
+        let latency_ms = avg.as_millis() as u64;
+        let mut status = ServiceStatus::Ok;
+        let mut messages = Vec::new();
+        if let Some((latency_status, message)) =
+            threshold::latency(latency_ms, self.warn_latency_ms, self.crit_latency_ms)
+        {
+            status = status.max(latency_status);
+            messages.push(message);
+        }
+        match (self.warn_loss_percent, self.crit_loss_percent) {
+            (_, Some(critical)) if loss > critical => {
+                status = ServiceStatus::Critical;
+                messages.push(format!("critical over {}% loss", critical));
+            }
+            (Some(warn), _) if loss > warn => {
+                status = status.max(ServiceStatus::Warning);
+                messages.push(format!("warning over {}% loss", warn));
+            }
+            _ => {}
+        }
+
+        let mut text = format!(
+            "{}: Ping to {} took {}ms on average",
+            status.to_string().to_uppercase(),
+            host_name,
+            latency_ms
+        );
+        if loss > 0.0 {
+            text.push_str(&format!(" with {}% packet loss", loss));
+        }
+        for message in messages {
+            text.push_str(&format!(", {}", message));
+        }
+        Ok((text, status))
+    }
 }
 
 impl ConfigOverlay for PingService {
@@ -62,6 +246,25 @@ impl ConfigOverlay for PingService {
                 "required_successful",
                 &self.required_successful,
             )?,
+            address_family: self.extract_value(value, "address_family", &self.address_family)?,
+            warn_latency_ms: self.extract_value(value, "warn_latency_ms", &self.warn_latency_ms)?,
+            crit_latency_ms: self.extract_value(value, "crit_latency_ms", &self.crit_latency_ms)?,
+            interval_ms: self.extract_value(value, "interval_ms", &self.interval_ms)?,
+            warn_loss_percent: self.extract_value(
+                value,
+                "warn_loss_percent",
+                &self.warn_loss_percent,
+            )?,
+            crit_loss_percent: self.extract_value(
+                value,
+                "crit_loss_percent",
+                &self.crit_loss_percent,
+            )?,
+            traceroute_on_failure: self.extract_bool(
+                value,
+                "traceroute_on_failure",
+                self.traceroute_on_failure,
+            ),
         }))
     }
 }
@@ -78,65 +281,110 @@ impl ServiceTrait for PingService {
             None => host.hostname.clone(),
         };
 
-        let hostname = lookup_host(format!("{}:80", target))
-            .await?
-            .next()
-            .ok_or(Error::DnsFailed)?;
+        let family = config.address_family.unwrap_or(host.address_family);
+        let addresses = target_addresses(host, &target, family).await?;
+        let single = addresses.len() == 1;
 
-        let results = (0..self.get_count())
-            .map(|_| tokio::spawn(surge_ping::ping(hostname.ip(), &[0; 8])))
-            .collect::<Vec<_>>();
-
-        // check the results and ensure all three are OK
-        let mut total_duration = std::time::Duration::new(0, 0);
-        let mut success_count = 0;
-
-        for (index, result) in results.into_iter().enumerate() {
-            match result.await {
-                Ok(Ok((_, dur))) => {
-                    total_duration += dur;
-                    success_count += 1;
-                }
-                Ok(Err(err)) => {
-                    match err {
-                        SurgeError::Timeout { .. } => {
-                            debug!("Ping {} timed out: {}", index, err.to_string());
-                        }
-                        _ => {
-                            return Err(Error::Generic(err.to_string()));
-                        }
-                    }
-                    return Err(Error::Generic(err.to_string()));
-                }
-                Err(err) => {
-                    return Err(Error::Generic(format!("Running task failed: {}", err)));
+        let mut results = Vec::new();
+        let mut details = BTreeMap::new();
+        for address in addresses {
+            match config
+                .ping(address)
+                .await
+                .and_then(|stats| Ok((config.evaluate(&host.name, &stats)?, stats)))
+            {
+                Ok(((result_text, status), stats)) => {
+                    details.extend(
+                        stats
+                            .details()
+                            .into_iter()
+                            .map(|(key, value)| match single {
+                                true => (key, value),
+                                false => (format!("{} {}", address, key), value),
+                            }),
+                    );
+                    results.push((address, result_text, status));
                 }
+                // there's nothing to compare it with, so it fails the way it always has
+                Err(err) if single && !config.traceroute_on_failure => return Err(err),
+                Err(Error::Generic(err)) => results.push((address, err, ServiceStatus::Critical)),
+                Err(err) => results.push((address, format!("{:?}", err), ServiceStatus::Critical)),
             }
         }
 
-        if success_count == self.get_required_successful() {
-            let avg_duration = total_duration / success_count as u32;
-            Ok(CheckResult {
-                timestamp: start_time,
-                result_text: format!(
-                    "OK: Ping to {} took {}ms on average",
-                    host.name,
-                    avg_duration.as_millis()
-                ),
-                status: ServiceStatus::Ok,
-                time_elapsed: chrono::Utc::now() - start_time,
-            })
-        } else {
-            Err(Error::Generic(format!(
-                "CRITICAL: Ping failed: {} successful, {} failed",
-                success_count,
-                3 - success_count
-            )))
+        let failing = results
+            .iter()
+            .filter(|(_, _, status)| is_failing(*status))
+            .map(|(address, _, _)| *address)
+            .collect::<Vec<_>>();
+        let (result_text, status) = combine(results);
+        let mut result = CheckResult {
+            timestamp: start_time,
+            result_text,
+            status,
+            details,
+            ..Default::default()
+        };
+        if config.traceroute_on_failure {
+            // each one can take a while, so they run at the same time
+            let targets = failing
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>();
+            let traces =
+                futures::future::join_all(targets.iter().map(|target| traceroute::trace(target)))
+                    .await;
+            for ((address, target), traced) in failing.iter().zip(&targets).zip(traces) {
+                let key = match single {
+                    true => "traceroute".to_string(),
+                    false => format!("{} traceroute", address),
+                };
+                result = traceroute::add_trace(result, target, key, traced);
+            }
         }
+        Ok(CheckResult {
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..result
+        })
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.get_count() == 0 {
+            return Err(Error::Configuration(
+                "count has to be at least 1".to_string(),
+            ));
+        }
+        for percent in [self.warn_loss_percent, self.crit_loss_percent]
+            .into_iter()
+            .flatten()
+        {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(Error::Configuration(format!(
+                    "Packet loss thresholds are percentages, {} isn't between 0 and 100",
+                    percent
+                )));
+            }
+        }
+        if let (Some(warn), Some(critical)) = (self.warn_loss_percent, self.crit_loss_percent) {
+            if warn > critical {
+                return Err(Error::Configuration(
+                    "warn_loss_percent can't be more than crit_loss_percent".to_string(),
+                ));
+            }
+        }
+        if let (Some(warn), Some(critical)) = (self.warn_latency_ms, self.crit_latency_ms) {
+            if warn > critical {
+                return Err(Error::Configuration(
+                    "warn_latency_ms can't be more than crit_latency_ms".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
     fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
-        Ok(serde_json::to_string_pretty(&config)?)
+        crate::serde::secret::to_string_pretty(&config)
     }
 
     fn jitter_value(&self) -> u32 {
@@ -152,7 +400,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_ping_service_localhost() {
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, crate::log::LogFormat::Text);
 
         if std::env::var("CI").is_ok() {
             eprintln!("Skipping test because it fails in CI");
@@ -166,13 +414,27 @@ mod tests {
             count: Some(5),
             address: None,
             required_successful: None,
+            address_family: None,
+            warn_latency_ms: None,
+            crit_latency_ms: None,
+            interval_ms: None,
+            warn_loss_percent: None,
+            crit_loss_percent: None,
+            traceroute_on_failure: false,
         };
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: "localhost".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
         let res = test_service.run(&host).await;
         dbg!(&res);
@@ -180,7 +442,7 @@ mod tests {
     }
     #[tokio::test]
     async fn test_ping_service_127_0_0_1() {
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, crate::log::LogFormat::Text);
 
         if std::env::var("CI").is_ok() {
             eprintln!("Skipping test because it fails in CI");
@@ -193,16 +455,125 @@ mod tests {
             count: Some(5),
             address: Some("127.0.0.1".to_string()),
             required_successful: None,
+            address_family: None,
+            warn_latency_ms: None,
+            crit_latency_ms: None,
+            interval_ms: None,
+            warn_loss_percent: None,
+            crit_loss_percent: None,
+            traceroute_on_failure: false,
         };
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: "localhost".to_string(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
         let res = test_service.run(&host).await;
         dbg!(&res);
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn test_validate_latency() {
+        let service: PingService = serde_json::from_value(json!({
+            "name": "test",
+            "cron_schedule": "* * * * *",
+            "warn_latency_ms": 50,
+            "crit_latency_ms": 200,
+        }))
+        .expect("Failed to parse service");
+        assert!(service.validate().is_ok());
+
+        let service = service
+            .overlay_host_config(&serde_json::Map::from_iter([(
+                "warn_latency_ms".to_string(),
+                json!(500),
+            )]))
+            .expect("Failed to overlay host config");
+        assert_eq!(service.warn_latency_ms, Some(500));
+        assert!(service.validate().is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let service = |extra: Json| -> PingService {
+            let mut config = json!({"name": "test", "cron_schedule": "* * * * *", "count": 4});
+            config
+                .as_object_mut()
+                .expect("Config isn't an object")
+                .extend(extra.as_object().cloned().unwrap_or_default());
+            serde_json::from_value(config).expect("Failed to parse service")
+        };
+        let stats = |rtts: &[u64]| PingStats {
+            sent: 4,
+            rtts: rtts
+                .iter()
+                .map(|ms| std::time::Duration::from_millis(*ms))
+                .collect(),
+        };
+
+        let all_back = stats(&[10, 20, 30, 40]);
+        assert_eq!(all_back.loss_percent(), 0.0);
+        let details = all_back.details();
+        assert!(details.contains(&("packet_loss".to_string(), "0%".to_string())));
+        assert!(details.contains(&("rtt_min_ms".to_string(), "10.000".to_string())));
+        assert!(details.contains(&("rtt_avg_ms".to_string(), "25.000".to_string())));
+        assert!(details.contains(&("rtt_max_ms".to_string(), "40.000".to_string())));
+        assert_eq!(
+            service(json!({}))
+                .evaluate("test", &all_back)
+                .map(|(_, status)| status),
+            Ok(ServiceStatus::Ok)
+        );
+
+        // without loss thresholds, a lost ping fails it unless required_successful allows for it
+        let one_lost = stats(&[10, 20, 30]);
+        assert_eq!(one_lost.loss_percent(), 25.0);
+        assert!(service(json!({})).evaluate("test", &one_lost).is_err());
+        assert_eq!(
+            service(json!({"required_successful": 3}))
+                .evaluate("test", &one_lost)
+                .map(|(_, status)| status),
+            Ok(ServiceStatus::Ok)
+        );
+
+        let loss = service(json!({"warn_loss_percent": 20, "crit_loss_percent": 50}));
+        assert!(loss.validate().is_ok());
+        let (text, status) = loss
+            .evaluate("test", &one_lost)
+            .expect("Failed to evaluate");
+        assert_eq!(status, ServiceStatus::Warning);
+        assert_eq!(
+            text,
+            "WARNING: Ping to test took 20ms on average with 25% packet loss, warning over 20% loss"
+        );
+        assert_eq!(
+            loss.evaluate("test", &stats(&[10]))
+                .map(|(_, status)| status),
+            Ok(ServiceStatus::Critical)
+        );
+        assert_eq!(
+            loss.evaluate("test", &stats(&[])).map(|(_, status)| status),
+            Ok(ServiceStatus::Critical)
+        );
+
+        assert!(service(json!({"crit_loss_percent": 101}))
+            .validate()
+            .is_err());
+        assert!(
+            service(json!({"warn_loss_percent": 60, "crit_loss_percent": 50}))
+                .validate()
+                .is_err()
+        );
+        assert!(service(json!({"count": 0})).validate().is_err());
+    }
 }
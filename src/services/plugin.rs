@@ -0,0 +1,526 @@
+//! Out-of-tree service types, which are executables that are sent a JSON request on stdin and reply with a JSON response on stdout
+//!
+//! A check sends:
+//!
+//! ```json
+//! {"protocol": 1, "action": "check", "service": {"name": "...", "config": {...}}, "host": {"name": "...", "hostname": "...", "vars": {...}}}
+//! ```
+//!
+//! and the plugin replies with `{"status": "ok", "result_text": "...", "long_output": "..."}`, where `status` is one of
+//! `ok`, `warning`, `critical` or `unknown`. `maremma check-config` sends `"action": "validate"` without a host, and
+//! anything but `"status": "ok"` is reported as a problem with the `result_text` as the reason.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::prelude::*;
+use crate::prelude::*;
+
+/// Bumped when the request or response changes in a way plugins have to know about
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+/// How long a plugin can run before it's killed, in seconds
+pub const DEFAULT_PLUGIN_TIMEOUT_SECONDS: u32 = 30;
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq, JsonSchema)]
+/// The `plugins` section of the config
+pub struct PluginConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Executables in this directory are plugins, named after the file without its extension
+    pub directory: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Plugins that live somewhere else, by name, these win over ones with the same name in `directory`
+    pub paths: HashMap<String, PathBuf>,
+}
+
+/// Plugin names end up in config files and log lines, so they're kept simple
+fn check_plugin_name(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(Error::Configuration(format!(
+            "plugin name {:?} can only have letters, numbers, '-', '_' and '.'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+impl PluginConfig {
+    /// Checks the directory and paths are there, it's not an error if the directory's empty
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(directory) = &self.directory {
+            if !directory.is_dir() {
+                return Err(Error::Configuration(format!(
+                    "plugins directory {} doesn't exist",
+                    directory.display()
+                )));
+            }
+        }
+        for (name, path) in self.paths.iter() {
+            check_plugin_name(name)?;
+            if !is_executable(path) {
+                return Err(Error::Configuration(format!(
+                    "plugin {} at {} isn't an executable file",
+                    name,
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the plugins in the directory and adds the ones from `paths`
+    pub fn discover(&self) -> Result<HashMap<String, PathBuf>, Error> {
+        let mut res = HashMap::new();
+        if let Some(directory) = &self.directory {
+            for entry in std::fs::read_dir(directory)? {
+                let path = entry?.path();
+                let Some(name) = path.file_stem().map(|name| name.to_string_lossy()) else {
+                    continue;
+                };
+                if name.starts_with('.') || !is_executable(&path) {
+                    continue;
+                }
+                if check_plugin_name(&name).is_err() {
+                    warn!("Skipping plugin with an unusable name: {}", path.display());
+                    continue;
+                }
+                if let Some(existing) = res.insert(name.to_string(), path.clone()) {
+                    warn!(
+                        "Plugins {} and {} have the same name, using {}",
+                        existing.display(),
+                        path.display(),
+                        path.display()
+                    );
+                }
+            }
+        }
+        res.extend(self.paths.clone());
+        Ok(res)
+    }
+}
+
+#[derive(Default)]
+/// The plugins that can be run by name
+pub struct PluginRegistry {
+    plugins: std::sync::RwLock<HashMap<String, PathBuf>>,
+}
+
+/// The plugins used by the plugin service, set up from the config on startup or `--plugin-dir` on agents
+pub static PLUGINS: LazyLock<PluginRegistry> = LazyLock::new(PluginRegistry::default);
+
+impl PluginRegistry {
+    /// Replaces the known plugins with what's found from the config
+    pub fn configure(&self, config: &PluginConfig) -> Result<(), Error> {
+        let plugins = config.discover()?;
+        let mut names = plugins.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        if !names.is_empty() {
+            info!("Found plugins: {}", names.join(", "));
+        }
+        if let Ok(mut current) = self.plugins.write() {
+            *current = plugins;
+        }
+        Ok(())
+    }
+
+    /// Where the plugin's executable is
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        self.plugins
+            .read()
+            .ok()
+            .and_then(|plugins| plugins.get(name).cloned())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// What the plugin's being asked to do
+pub enum PluginAction {
+    /// Run the check against the host
+    Check,
+    /// Say if the service's config makes sense, there's no host
+    Validate,
+}
+
+#[derive(Serialize, Debug)]
+struct PluginRequestService<'a> {
+    name: &'a str,
+    config: &'a Map<String, Json>,
+}
+
+#[derive(Serialize, Debug)]
+struct PluginRequestHost<'a> {
+    name: &'a str,
+    hostname: &'a str,
+    vars: &'a Json,
+}
+
+#[derive(Serialize, Debug)]
+/// What's written to the plugin's stdin
+struct PluginRequest<'a> {
+    protocol: u32,
+    action: PluginAction,
+    service: PluginRequestService<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<PluginRequestHost<'a>>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+/// What the plugin writes to stdout
+pub struct PluginResponse {
+    /// How the check went
+    pub status: ServiceStatus,
+    #[serde(default)]
+    /// One line about the result
+    pub result_text: String,
+    #[serde(default)]
+    /// Anything else worth showing
+    pub long_output: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// A service check that's implemented by an external executable, see [self] for the protocol
+pub struct PluginService {
+    /// Name of the service
+    pub name: String,
+    /// Which plugin runs the check, from the `plugins` section of the config
+    pub plugin_name: String,
+    #[serde(with = "crate::serde::cron")]
+    #[schemars(with = "String")]
+    /// Cron schedule for the service
+    pub cron_schedule: Cron,
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+    /// How long the plugin can run before it's killed (seconds), defaults to [DEFAULT_PLUGIN_TIMEOUT_SECONDS]
+    pub timeout: Option<u32>,
+    #[serde(default)]
+    /// Passed to the plugin as-is
+    pub config: Map<String, Json>,
+}
+
+async fn read_pipe<R: AsyncRead + Unpin>(pipe: Option<R>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        if let Err(err) = pipe.read_to_end(&mut buf).await {
+            debug!("Failed to read plugin output: {:?}", err);
+        }
+    }
+    buf
+}
+
+impl PluginService {
+    /// Runs the plugin, `Err` has why it didn't give a usable response
+    async fn call(
+        &self,
+        action: PluginAction,
+        host: Option<&entities::host::Model>,
+    ) -> Result<PluginResponse, String> {
+        let path = PLUGINS
+            .path(&self.plugin_name)
+            .ok_or_else(|| format!("Plugin {} isn't installed", self.plugin_name))?;
+        let request = serde_json::to_vec(&PluginRequest {
+            protocol: PLUGIN_PROTOCOL_VERSION,
+            action,
+            service: PluginRequestService {
+                name: &self.name,
+                config: &self.config,
+            },
+            host: host.map(|host| PluginRequestHost {
+                name: &host.name,
+                hostname: &host.hostname,
+                vars: &host.vars,
+            }),
+        })
+        .map_err(|err| format!("Failed to build the plugin request: {:?}", err))?;
+
+        let mut child = tokio::process::Command::new(&path)
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("Failed to start plugin {}: {}", path.display(), err))?;
+
+        let stdin = child.stdin.take();
+        let write_request = async move {
+            if let Some(mut stdin) = stdin {
+                // plugins that don't need the request might not read it
+                if let Err(err) = stdin.write_all(&request).await {
+                    debug!("Failed to write the plugin request: {:?}", err);
+                }
+            }
+        };
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let timeout = self.timeout.unwrap_or(DEFAULT_PLUGIN_TIMEOUT_SECONDS);
+        let res = tokio::time::timeout(Duration::from_secs(timeout as u64), async {
+            tokio::join!(
+                child.wait(),
+                write_request,
+                read_pipe(stdout),
+                read_pipe(stderr)
+            )
+        })
+        .await;
+
+        let (status, stdout, stderr) = match res {
+            Ok((status, _, stdout, stderr)) => (
+                status.map_err(|err| format!("Failed to run plugin: {}", err))?,
+                stdout,
+                stderr,
+            ),
+            Err(_) => {
+                if let Err(err) = child.kill().await {
+                    warn!(
+                        "Failed to kill timed out plugin {}: {:?}",
+                        path.display(),
+                        err
+                    );
+                }
+                return Err(format!("Plugin timed out after {} seconds", timeout));
+            }
+        };
+
+        match serde_json::from_slice::<PluginResponse>(&stdout) {
+            Ok(response) => Ok(response),
+            Err(_) if !status.success() => Err(format!(
+                "Plugin exited with {}: {}",
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            )),
+            Err(err) => Err(format!("Plugin sent an invalid response: {}", err)),
+        }
+    }
+
+    /// Asks the plugin if the service's config makes sense, `None` if it's happy
+    pub async fn validate_with_plugin(&self) -> Option<String> {
+        match self.call(PluginAction::Validate, None).await {
+            Ok(response) if response.status == ServiceStatus::Ok => None,
+            Ok(response) => Some(response.result_text),
+            Err(err) => Some(err),
+        }
+    }
+}
+
+impl ConfigOverlay for PluginService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            plugin_name: self.extract_string(value, "plugin_name", &self.plugin_name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            timeout: self.extract_value(value, "timeout", &self.timeout)?,
+            config: self.extract_value(value, "config", &self.config)?,
+        }))
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for PluginService {
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = chrono::Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+
+        let result = match config.call(PluginAction::Check, Some(host)).await {
+            Ok(response) => CheckResult {
+                // those are for checks that haven't finished
+                status: match response.status {
+                    ServiceStatus::Pending | ServiceStatus::Checking => ServiceStatus::Unknown,
+                    status => status,
+                },
+                result_text: response.result_text,
+                long_output: response.long_output,
+                ..Default::default()
+            },
+            Err(err) => CheckResult {
+                status: ServiceStatus::Critical,
+                result_text: err,
+                ..Default::default()
+            },
+        };
+        Ok(CheckResult {
+            timestamp: chrono::Utc::now(),
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..result
+        })
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        check_plugin_name(&self.plugin_name)?;
+        if self.timeout == Some(0) {
+            return Err(Error::Configuration(format!(
+                "service {} timeout has to be more than 0",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use entities::host::test_host;
+
+    use super::*;
+
+    /// Writes an executable shell script plugin into the directory
+    fn write_plugin(directory: &Path, name: &str, script: &str) -> PathBuf {
+        let path = directory.join(name);
+        let mut file = std::fs::File::create(&path).expect("Failed to create plugin");
+        file.write_all(format!("#!/bin/sh\n{}\n", script).as_bytes())
+            .expect("Failed to write plugin");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("Failed to make plugin executable");
+        path
+    }
+
+    fn service(plugin_name: &str) -> PluginService {
+        PluginService {
+            name: "plugin".to_string(),
+            plugin_name: plugin_name.to_string(),
+            cron_schedule: "@hourly".parse().expect("Failed to parse cron schedule"),
+            jitter: None,
+            timeout: Some(1),
+            config: Map::from_iter([("answer".to_string(), json!(42))]),
+        }
+    }
+
+    #[test]
+    fn test_discover() {
+        let directory = tempfile::tempdir().expect("Failed to create temp dir");
+        write_plugin(directory.path(), "check_thing.sh", "true");
+        write_plugin(directory.path(), ".hidden", "true");
+        std::fs::write(directory.path().join("README"), "not a plugin")
+            .expect("Failed to write readme");
+        let elsewhere = tempfile::tempdir().expect("Failed to create temp dir");
+        let other = write_plugin(elsewhere.path(), "other", "true");
+
+        let config = PluginConfig {
+            directory: Some(directory.path().to_path_buf()),
+            paths: HashMap::from([("other".to_string(), other.clone())]),
+        };
+        assert!(config.validate().is_ok());
+        let plugins = config.discover().expect("Failed to discover plugins");
+        let mut names = plugins.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["check_thing".to_string(), "other".to_string()]);
+        assert_eq!(plugins.get("other"), Some(&other));
+
+        assert!(PluginConfig {
+            directory: Some(directory.path().join("nope")),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(PluginConfig {
+            paths: HashMap::from([("readme".to_string(), directory.path().join("README"))]),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+
+        assert!(service("has spaces").validate().is_err());
+        assert!(service("check_thing").validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_plugin_service() {
+        let directory = tempfile::tempdir().expect("Failed to create temp dir");
+        // echoes the request back so we can see what was sent
+        write_plugin(
+            directory.path(),
+            "echo",
+            r#"request=$(cat)
+case "$request" in
+  *'"action":"validate"'*) echo '{"status": "critical", "result_text": "answer is wrong"}' ;;
+  *) printf '{"status": "warning", "result_text": "checked", "long_output": %s}' "$(printf '%s' "$request" | sed 's/["\\]/\\&/g; s/^/"/; s/$/"/')" ;;
+esac"#,
+        );
+        write_plugin(directory.path(), "garbage", "echo 'not json'");
+        write_plugin(directory.path(), "broken", "echo oops >&2; exit 3");
+        write_plugin(directory.path(), "slow", "sleep 10");
+        PLUGINS
+            .configure(&PluginConfig {
+                directory: Some(directory.path().to_path_buf()),
+                ..Default::default()
+            })
+            .expect("Failed to configure plugins");
+
+        let host = entities::host::Model {
+            check: crate::host::HostCheck::None,
+            ..test_host()
+        };
+
+        let res = service("echo")
+            .run(&host)
+            .await
+            .expect("Failed to run plugin");
+        assert_eq!(res.status, ServiceStatus::Warning);
+        assert_eq!(res.result_text, "checked");
+        let request: Json = serde_json::from_str(&res.long_output.expect("No request echoed"))
+            .expect("Plugin request wasn't JSON");
+        assert_eq!(request["protocol"], json!(PLUGIN_PROTOCOL_VERSION));
+        assert_eq!(request["action"], json!("check"));
+        assert_eq!(request["service"]["config"]["answer"], json!(42));
+        assert_eq!(request["host"]["hostname"], json!(host.hostname));
+
+        assert_eq!(
+            service("echo").validate_with_plugin().await,
+            Some("answer is wrong".to_string())
+        );
+
+        let res = service("garbage")
+            .run(&host)
+            .await
+            .expect("Failed to run plugin");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(res.result_text.contains("invalid response"));
+
+        let res = service("broken")
+            .run(&host)
+            .await
+            .expect("Failed to run plugin");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(res.result_text.contains("oops"));
+
+        let res = service("slow")
+            .run(&host)
+            .await
+            .expect("Failed to run plugin");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(res.result_text.contains("timed out"));
+        assert!(res.time_elapsed < TimeDelta::seconds(5));
+
+        let res = service("missing")
+            .run(&host)
+            .await
+            .expect("Failed to run plugin");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert!(res.result_text.contains("isn't installed"));
+    }
+}
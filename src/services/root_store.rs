@@ -0,0 +1,208 @@
+//! Picking which CA certificates TLS and HTTP checks trust
+//!
+//! CA files are cached and re-read when they change on disk, so rotating an internal CA doesn't need a restart.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::RootCertStore;
+use schemars::JsonSchema;
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(try_from = "String", into = "String")]
+#[schemars(with = "String")]
+/// Which root certificates to trust, configured as `webpki`, `system` or `file:<path>`
+pub enum RootStore {
+    /// Mozilla's roots, bundled with Maremma
+    #[default]
+    Webpki,
+    /// The operating system's trust store
+    System,
+    /// A PEM bundle of CA certificates, for internal PKI
+    File(PathBuf),
+}
+
+impl FromStr for RootStore {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "webpki" => Ok(Self::Webpki),
+            "system" => Ok(Self::System),
+            other => match other.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(Self::File(PathBuf::from(path))),
+                _ => Err(Error::Configuration(format!(
+                    "Invalid root_store '{}', should be webpki, system or file:<path>",
+                    other
+                ))),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for RootStore {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RootStore> for String {
+    fn from(value: RootStore) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for RootStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Webpki => write!(f, "webpki"),
+            Self::System => write!(f, "system"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl RootStore {
+    /// The certificates to trust, or `None` for the bundled webpki roots
+    pub(crate) fn certificates(&self) -> Result<Option<Arc<Vec<CertificateDer<'static>>>>, Error> {
+        match self {
+            Self::Webpki => Ok(None),
+            Self::System => Ok(Some(SYSTEM_ROOTS.clone())),
+            Self::File(path) => load_ca_file(path).map(Some),
+        }
+    }
+}
+
+/// The OS trust store, only loaded once because it doesn't change often and is slow to read
+static SYSTEM_ROOTS: LazyLock<Arc<Vec<CertificateDer<'static>>>> = LazyLock::new(|| {
+    let res = rustls_native_certs::load_native_certs();
+    for err in res.errors.iter() {
+        warn!(
+            "Failed to load a certificate from the system trust store: {}",
+            err
+        );
+    }
+    Arc::new(res.certs)
+});
+
+type CaFileCache = HashMap<PathBuf, (SystemTime, Arc<Vec<CertificateDer<'static>>>)>;
+
+/// CA files that have already been parsed, along with when they were last modified
+static CA_FILE_CACHE: LazyLock<std::sync::Mutex<CaFileCache>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Reads the certificates from a PEM file, reusing the last result if the file hasn't changed
+pub(crate) fn load_ca_file(path: &Path) -> Result<Arc<Vec<CertificateDer<'static>>>, Error> {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|err| {
+            Error::TlsError(format!(
+                "Failed to read CA file {}: {:?}",
+                path.display(),
+                err
+            ))
+        })?;
+
+    if let Ok(cache) = CA_FILE_CACHE.lock() {
+        if let Some((cached_modified, certs)) = cache.get(path) {
+            if *cached_modified == modified {
+                return Ok(certs.clone());
+            }
+        }
+    }
+
+    debug!("Loading CA file {}", path.display());
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|err| {
+            Error::TlsError(format!(
+                "Failed to read CA file {}: {:?}",
+                path.display(),
+                err
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            Error::TlsError(format!(
+                "Failed to parse certificate in {}: {:?}",
+                path.display(),
+                err
+            ))
+        })?;
+    if certs.is_empty() {
+        return Err(Error::TlsError(format!(
+            "No certificates found in CA file {}",
+            path.display()
+        )));
+    }
+    let certs = Arc::new(certs);
+    if let Ok(mut cache) = CA_FILE_CACHE.lock() {
+        cache.insert(path.to_path_buf(), (modified, certs.clone()));
+    }
+    Ok(certs)
+}
+
+/// Builds a rustls store from the certificates, skipping ones it can't use rather than failing the check
+pub(crate) fn build_root_store(certs: &[CertificateDer<'static>]) -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+    let (added, ignored) = root_store.add_parsable_certificates(certs.iter().cloned());
+    if ignored > 0 {
+        debug!(
+            "Ignored {} unusable root certificates, added {}",
+            ignored, added
+        );
+    }
+    root_store
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_root_store() {
+        for (input, expected) in [
+            ("webpki", RootStore::Webpki),
+            ("system", RootStore::System),
+            (
+                "file:/etc/ssl/internal.pem",
+                RootStore::File(PathBuf::from("/etc/ssl/internal.pem")),
+            ),
+        ] {
+            let parsed: RootStore =
+                serde_json::from_value(json!(input)).expect("Failed to parse root store");
+            assert_eq!(parsed, expected);
+            assert_eq!(parsed.to_string(), input);
+        }
+        assert!(serde_json::from_value::<RootStore>(json!("file:")).is_err());
+        assert!(serde_json::from_value::<RootStore>(json!("everything")).is_err());
+    }
+
+    #[test]
+    fn test_load_ca_file() {
+        let certs = crate::tests::tls_utils::TestCertificateBuilder::new()
+            .with_name("localhost")
+            .build();
+        let path = certs.ca_file.path();
+
+        let first = load_ca_file(path).expect("Failed to load CA file");
+        let second = load_ca_file(path).expect("Failed to load CA file");
+        // unchanged, so it comes from the cache
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!build_root_store(&first).is_empty());
+
+        assert!(RootStore::Webpki
+            .certificates()
+            .expect("Failed to get webpki roots")
+            .is_none());
+        assert!(load_ca_file(Path::new("/this/does/not/exist.pem")).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! Self-monitoring, where Maremma checks its own database, scheduler and memory use
+//!
+//! Turn on `self_monitoring` in the config to add the built-in services to `local_services`.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::Instant;
+
+use sea_orm::{QueryOrder, QuerySelect, Statement};
+
+use super::prelude::*;
+use crate::prelude::*;
+
+/// How often the built-in self-monitoring services run
+pub const DEFAULT_SELF_MONITOR_CRON: &str = "*/5 * * * *";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+/// What to measure
+pub enum SelfMetric {
+    /// Size of the database file (MB)
+    DatabaseSize,
+    /// How long a simple query takes (ms)
+    QueryLatency,
+    /// Checks which are due but haven't been started
+    CheckBacklog,
+    /// How long the most overdue check has been waiting (seconds)
+    SchedulerLag,
+    /// Rows in the check history table
+    HistoryRows,
+    /// Resident memory of the Maremma process (MB)
+    Memory,
+}
+
+impl Display for SelfMetric {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::DatabaseSize => write!(f, "database size"),
+            Self::QueryLatency => write!(f, "query latency"),
+            Self::CheckBacklog => write!(f, "check backlog"),
+            Self::SchedulerLag => write!(f, "scheduler lag"),
+            Self::HistoryRows => write!(f, "history rows"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl SelfMetric {
+    /// All the metrics, in the order the built-in services are added
+    pub const ALL: [SelfMetric; 6] = [
+        Self::DatabaseSize,
+        Self::QueryLatency,
+        Self::CheckBacklog,
+        Self::SchedulerLag,
+        Self::HistoryRows,
+        Self::Memory,
+    ];
+
+    /// The key the value's stored under in the result details
+    pub fn detail_key(&self) -> &'static str {
+        match self {
+            Self::DatabaseSize => "database_size_mb",
+            Self::QueryLatency => "query_latency_ms",
+            Self::CheckBacklog => "check_backlog",
+            Self::SchedulerLag => "scheduler_lag_seconds",
+            Self::HistoryRows => "history_rows",
+            Self::Memory => "memory_mb",
+        }
+    }
+
+    /// Default (warning, critical) thresholds
+    pub fn default_thresholds(&self) -> (f64, f64) {
+        match self {
+            Self::DatabaseSize => (1024.0, 4096.0),
+            Self::QueryLatency => (500.0, 2000.0),
+            Self::CheckBacklog => (100.0, 500.0),
+            Self::SchedulerLag => (300.0, 900.0),
+            Self::HistoryRows => (5_000_000.0, 20_000_000.0),
+            Self::Memory => (1024.0, 2048.0),
+        }
+    }
+}
+
+/// The built-in services added by `self_monitoring`, keyed by service name
+pub(crate) fn builtin_services() -> Vec<(String, Value)> {
+    SelfMetric::ALL
+        .iter()
+        .map(|metric| {
+            let name = format!("Maremma {}", metric);
+            (
+                name.clone(),
+                json!({
+                    "name": name,
+                    "service_type": ServiceType::SelfMonitor,
+                    "host_groups": [],
+                    "cron_schedule": DEFAULT_SELF_MONITOR_CRON,
+                    "metric": metric,
+                }),
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// A service which watches Maremma itself, it only runs on the Maremma server
+pub struct SelfMonitorService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// What to measure
+    pub metric: SelfMetric,
+
+    /// Warn at or above this, each metric has its own default
+    #[serde(default)]
+    pub warning: Option<f64>,
+
+    /// Critical at or above this, each metric has its own default
+    #[serde(default)]
+    pub critical: Option<f64>,
+}
+
+impl SelfMonitorService {
+    fn thresholds(&self) -> (f64, f64) {
+        let (warning, critical) = self.metric.default_thresholds();
+        (
+            self.warning.unwrap_or(warning),
+            self.critical.unwrap_or(critical),
+        )
+    }
+
+    fn status(&self, value: f64) -> ServiceStatus {
+        let (warning, critical) = self.thresholds();
+        if value >= critical {
+            ServiceStatus::Critical
+        } else if value >= warning {
+            ServiceStatus::Warning
+        } else {
+            ServiceStatus::Ok
+        }
+    }
+
+    /// Measures the metric, returning the value and any extra details
+    async fn measure(
+        &self,
+        db: &DatabaseConnection,
+    ) -> Result<(Option<f64>, BTreeMap<String, String>), Error> {
+        let mut details = BTreeMap::new();
+        let value = match self.metric {
+            SelfMetric::DatabaseSize => {
+                let page_count = pragma(db, "page_count").await?;
+                let page_size = pragma(db, "page_size").await?;
+                Some((page_count * page_size) as f64 / 1024.0 / 1024.0)
+            }
+            SelfMetric::QueryLatency => {
+                let start = Instant::now();
+                entities::service_check::Entity::find().count(db).await?;
+                Some(start.elapsed().as_secs_f64() * 1000.0)
+            }
+            SelfMetric::CheckBacklog => Some(due_checks().count(db).await? as f64),
+            SelfMetric::SchedulerLag => {
+                let overdue = due_checks().count(db).await?;
+                details.insert("overdue_checks".to_string(), overdue.to_string());
+                let oldest = due_checks()
+                    .order_by_asc(entities::service_check::Column::NextCheck)
+                    .one(db)
+                    .await?;
+                Some(
+                    oldest
+                        .map(|check| (Utc::now() - check.next_check).num_seconds().max(0) as f64)
+                        .unwrap_or(0.0),
+                )
+            }
+            SelfMetric::HistoryRows => Some(
+                entities::service_check_history::Entity::find()
+                    .count(db)
+                    .await? as f64,
+            ),
+            SelfMetric::Memory => resident_memory_mb().await,
+        };
+        Ok((value, details))
+    }
+}
+
+/// Checks which the check loop should be running now but hasn't started, ignoring agent, passive and heartbeat ones
+fn due_checks() -> Select<entities::service_check::Entity> {
+    entities::service_check::Entity::find()
+        .inner_join(entities::service::Entity)
+        .filter(entities::service::Column::Agent.is_null())
+        .filter(
+            entities::service::Column::ServiceType
+                .is_not_in([ServiceType::Passive, ServiceType::Heartbeat]),
+        )
+        .filter(
+            entities::service_check::Column::Status
+                .ne(ServiceStatus::Disabled)
+                .and(entities::service_check::Column::Status.ne(ServiceStatus::Checking))
+                .and(
+                    entities::service_check::Column::NextCheck
+                        .lte(Utc::now())
+                        .or(entities::service_check::Column::Status.eq(ServiceStatus::Urgent)),
+                ),
+        )
+}
+
+async fn pragma(db: &DatabaseConnection, name: &str) -> Result<i64, Error> {
+    db.query_one(Statement::from_string(
+        db.get_database_backend(),
+        format!("PRAGMA {}", name),
+    ))
+    .await?
+    .ok_or_else(|| Error::Generic(format!("No result from PRAGMA {}", name)))?
+    .try_get_by_index::<i64>(0)
+    .map_err(Error::from)
+}
+
+/// Reads VmRSS from `/proc/self/status`, which only exists on Linux
+async fn resident_memory_mb() -> Option<f64> {
+    let status = tokio::fs::read_to_string("/proc/self/status").await.ok()?;
+    parse_vm_rss_kb(&status).map(|kb| kb as f64 / 1024.0)
+}
+
+fn parse_vm_rss_kb(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
+
+impl ConfigOverlay for SelfMonitorService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            metric: self.extract_value(value, "metric", &self.metric)?,
+            warning: self.extract_value(value, "warning", &self.warning)?,
+            critical: self.extract_value(value, "critical", &self.critical)?,
+        }))
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for SelfMonitorService {
+    async fn run(&self, _host: &entities::host::Model) -> Result<CheckResult, Error> {
+        Err(Error::Generic(format!(
+            "{} is a self-monitoring service, it can only run on the Maremma server",
+            self.name
+        )))
+    }
+
+    async fn run_local(
+        &self,
+        host: &entities::host::Model,
+        db: &DatabaseConnection,
+    ) -> Result<CheckResult, Error> {
+        let start_time = Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        let (value, mut details) = config.measure(db).await?;
+
+        let (status, result_text) = match value {
+            Some(value) => {
+                details.insert(
+                    config.metric.detail_key().to_string(),
+                    format!("{:.2}", value),
+                );
+                (
+                    config.status(value),
+                    format!("{} is {:.2}", config.metric, value),
+                )
+            }
+            None => (
+                ServiceStatus::Unknown,
+                format!("Couldn't measure {} on this platform", config.metric),
+            ),
+        };
+
+        Ok(CheckResult {
+            timestamp: Utc::now(),
+            time_elapsed: Utc::now() - start_time,
+            status,
+            result_text,
+            details,
+            ..Default::default()
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let (warning, critical) = self.thresholds();
+        if warning > critical {
+            return Err(Error::Configuration(format!(
+                "{}: warning ({}) can't be above critical ({})",
+                self.name, warning, critical
+            )));
+        }
+        Ok(())
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::host::test_host;
+
+    fn test_service(metric: SelfMetric) -> SelfMonitorService {
+        serde_json::from_value(json!({
+            "name": "self",
+            "cron_schedule": DEFAULT_SELF_MONITOR_CRON,
+            "metric": metric,
+        }))
+        .expect("Failed to parse self-monitoring service")
+    }
+
+    #[test]
+    fn test_parse_vm_rss() {
+        assert_eq!(
+            parse_vm_rss_kb("Name:\tmaremma\nVmRSS:\t   51200 kB\nVmSwap:\t0 kB\n"),
+            Some(51200)
+        );
+        assert_eq!(parse_vm_rss_kb("Name:\tmaremma\n"), None);
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let mut service = test_service(SelfMetric::CheckBacklog);
+        assert_eq!(service.status(0.0), ServiceStatus::Ok);
+        assert_eq!(service.status(100.0), ServiceStatus::Warning);
+        assert_eq!(service.status(500.0), ServiceStatus::Critical);
+        assert!(service.validate().is_ok());
+
+        service.warning = Some(1000.0);
+        assert!(service.validate().is_err());
+        service.critical = Some(2000.0);
+        assert_eq!(service.status(500.0), ServiceStatus::Ok);
+        assert!(service.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_self_monitor_service() {
+        let (db, _config) = test_setup().await.expect("Failed to set up test");
+        let host = test_host();
+
+        for metric in SelfMetric::ALL {
+            let service = test_service(metric);
+            assert!(service.run(&host).await.is_err());
+            let result = service
+                .run_local(&host, &db)
+                .await
+                .expect("Failed to run self-monitoring check");
+            if metric == SelfMetric::Memory && !cfg!(target_os = "linux") {
+                assert_eq!(result.status, ServiceStatus::Unknown);
+                continue;
+            }
+            assert!(
+                result.details.contains_key(metric.detail_key()),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_builtin_services() {
+        for (name, value) in builtin_services() {
+            let service = Service::try_from(&value).expect("Failed to parse built-in service");
+            assert_eq!(service.service_type, ServiceType::SelfMonitor, "{}", name);
+        }
+    }
+}
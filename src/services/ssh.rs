@@ -2,22 +2,16 @@
 
 use std::num::NonZeroU16;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use super::prelude::*;
+use super::threshold::{self, NumericThreshold};
+use crate::host::ssh::{DEFAULT_SSH_PORT, DEFAULT_SSH_TIMEOUT_SECONDS};
 use crate::prelude::*;
+use crate::ssh_client::{self, HostKeyVerification, PoolKey, SshAuth};
 
-fn serialize_password<S>(password: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    if let Some(password) = password {
-        // mask the password
-        let password_mask = "*".repeat(password.len());
-        serializer.serialize_str(&password_mask)
-    } else {
-        serializer.serialize_none()
-    }
-}
+/// How long a command can run before it's killed, in seconds
+pub const DEFAULT_SSH_COMMAND_TIMEOUT_SECONDS: u32 = 60;
 
 #[derive(Debug, Deserialize, JsonSchema, Serialize)]
 /// SSH-based service, SSH to a host and run a command
@@ -39,21 +33,51 @@ pub struct SshService {
     /// Username to connect with
     pub username: String,
 
-    /// SSH key to use, keys with passphrases are not currently supported (because of ssh-rs... so far)
+    /// SSH key to use
     pub private_key: Option<PathBuf>,
 
-    /// If you're bad, but you have to. Won't try this is the private key is set.
-    #[serde(serialize_with = "serialize_password")]
+    /// Passphrase for the SSH key, if it has one
+    #[serde(default, serialize_with = "crate::serde::secret::serialize")]
+    pub private_key_passphrase: Option<String>,
+
+    /// Use the keys in the SSH agent from `SSH_AUTH_SOCK`, tried after `private_key`
+    #[serde(default)]
+    pub use_agent: bool,
+
+    /// If you're bad, but you have to. Tried after the key and agent.
+    #[serde(serialize_with = "crate::serde::secret::serialize")]
     pub password: Option<String>,
 
+    /// How to verify the host key, defaults to checking known_hosts
+    #[serde(default)]
+    pub host_key_verification: HostKeyVerification,
+
+    /// The known_hosts file to check against, defaults to `~/.ssh/known_hosts`
+    pub known_hosts_file: Option<PathBuf>,
+
     /// Expected exit code (Defaults to 0)
     pub exit_code: Option<u32>,
 
-    /// Connection timeout (seconds), not runtime-timeout
+    /// Connection timeout (seconds), defaults to [DEFAULT_SSH_TIMEOUT_SECONDS]
     pub timeout: Option<u32>,
 
+    /// How long the command can run (seconds), defaults to [DEFAULT_SSH_COMMAND_TIMEOUT_SECONDS]
+    pub command_timeout: Option<u32>,
+
     /// Add random jitter in 0..n seconds to the check
     pub jitter: Option<u16>,
+
+    /// Warn when the output's different to the last successful run's, and show what changed
+    #[serde(default)]
+    pub detect_changes: bool,
+
+    /// Record how long getting a session took, the exit code and stderr with each result
+    #[serde(default)]
+    pub debug_capture: bool,
+
+    /// Warning and critical thresholds on numbers in the output, see [super::threshold]
+    #[serde(default)]
+    pub thresholds: Vec<NumericThreshold>,
 }
 
 impl Default for SshService {
@@ -68,10 +92,18 @@ impl Default for SshService {
             port: None,
             username: "maremma".to_string(),
             private_key: None,
+            private_key_passphrase: None,
+            use_agent: false,
             exit_code: None,
             password: None,
+            host_key_verification: HostKeyVerification::default(),
+            known_hosts_file: None,
             timeout: None,
+            command_timeout: None,
             jitter: None,
+            detect_changes: false,
+            debug_capture: false,
+            thresholds: Vec::new(),
         }
     }
 }
@@ -89,14 +121,267 @@ impl ConfigOverlay for SshService {
                 .extract_string(value, "username", &self.username)
                 .to_string(),
             private_key: self.extract_value(value, "private_key", &self.private_key)?,
+            private_key_passphrase: self.extract_value(
+                value,
+                "private_key_passphrase",
+                &self.private_key_passphrase,
+            )?,
+            use_agent: self.extract_bool(value, "use_agent", self.use_agent),
             password: self.extract_value(value, "password", &self.password)?,
+            host_key_verification: self.extract_value(
+                value,
+                "host_key_verification",
+                &self.host_key_verification,
+            )?,
+            known_hosts_file: self.extract_value(
+                value,
+                "known_hosts_file",
+                &self.known_hosts_file,
+            )?,
             exit_code: self.extract_value(value, "exit_code", &self.exit_code)?,
             timeout: self.extract_value(value, "timeout", &self.timeout)?,
+            command_timeout: self.extract_value(value, "command_timeout", &self.command_timeout)?,
             jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            detect_changes: self.extract_bool(value, "detect_changes", self.detect_changes),
+            debug_capture: self.extract_bool(value, "debug_capture", self.debug_capture),
+            thresholds: self.extract_value(value, "thresholds", &self.thresholds)?,
+        }))
+    }
+}
+
+impl SshService {
+    /// Connects, logs in and runs the command, returning the status, result text and diagnostics for `debug_capture`
+    async fn run_command(
+        &self,
+        host: &entities::host::Model,
+    ) -> Result<(ServiceStatus, String, Vec<(String, String)>), Error> {
+        let port = self.port.map(u16::from).unwrap_or(DEFAULT_SSH_PORT);
+        let key = PoolKey {
+            hostname: host.hostname.clone(),
+            port,
+            username: self.username.clone(),
+            private_key: self.private_key.clone(),
+            use_agent: self.use_agent,
+            password: self.password.clone(),
+            verification: self.host_key_verification.clone(),
+            known_hosts_file: self.known_hosts_file.clone(),
+        };
+        let auth = SshAuth {
+            private_key: self.private_key.as_deref(),
+            private_key_passphrase: self.private_key_passphrase.as_deref(),
+            use_agent: self.use_agent,
+            password: self.password.as_deref(),
+        };
+        let session_start = chrono::Utc::now();
+        let session = ssh_client::POOL
+            .session(
+                key,
+                &auth,
+                Duration::from_secs(
+                    self.timeout.unwrap_or(DEFAULT_SSH_TIMEOUT_SECONDS as u32) as u64
+                ),
+            )
+            .await?;
+        // pooled sessions are already connected and logged in
+        let mut diagnostics = vec![(
+            "session_ms".to_string(),
+            (chrono::Utc::now() - session_start)
+                .num_milliseconds()
+                .to_string(),
+        )];
+
+        debug!("Running ssh command: {:?}", &self.command_line);
+        let command_timeout = self
+            .command_timeout
+            .unwrap_or(DEFAULT_SSH_COMMAND_TIMEOUT_SECONDS);
+        let output = tokio::time::timeout(
+            Duration::from_secs(command_timeout as u64),
+            ssh_client::run_command(&session, &self.command_line),
+        )
+        .await;
+        // a timed out or broken channel might leave the connection in a bad way, so don't reuse it
+        if !matches!(output, Ok(Ok(_))) {
+            session.discard();
+        }
+
+        let output = match output {
+            Ok(output) => output?,
+            Err(_) => {
+                return Ok((
+                    ServiceStatus::Critical,
+                    format!("Command timed out after {} seconds", command_timeout),
+                    diagnostics,
+                ))
+            }
+        };
+
+        diagnostics.push((
+            "exit_code".to_string(),
+            output
+                .exit_status
+                .map(|exit_status| exit_status.to_string())
+                .unwrap_or_else(|| "none".to_string()),
+        ));
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if !stderr.is_empty() {
+            diagnostics.push(("stderr".to_string(), stderr));
+        }
+
+        let mut result_text = String::from_utf8_lossy(&output.stdout).to_string();
+        let expected = self.exit_code.unwrap_or(0);
+        let status = match output.exit_status {
+            Some(exit_status) if exit_status == expected => ServiceStatus::Ok,
+            Some(exit_status) => {
+                result_text = format!(
+                    "Exit code {}, expected {}: {}{}",
+                    exit_status,
+                    expected,
+                    result_text,
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                ServiceStatus::Critical
+            }
+            None => {
+                result_text = format!("Command didn't return an exit status: {}", result_text);
+                ServiceStatus::Critical
+            }
+        };
+        Ok((status, result_text, diagnostics))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Default)]
+/// How built-in checks like disk and system connect to hosts, they run locally on the local checks host
+pub struct SshConnection {
+    /// Username to connect with, not needed for the local checks host
+    pub username: Option<String>,
+
+    /// Port to connect to, defaults to 22
+    pub port: Option<NonZeroU16>,
+
+    /// SSH key to use
+    pub private_key: Option<PathBuf>,
+
+    /// Passphrase for the SSH key, if it has one
+    #[serde(default, serialize_with = "crate::serde::secret::serialize")]
+    pub private_key_passphrase: Option<String>,
+
+    /// Use the keys in the SSH agent from `SSH_AUTH_SOCK`, tried after `private_key`
+    #[serde(default)]
+    pub use_agent: bool,
+
+    /// Tried after the key and agent
+    #[serde(default, serialize_with = "crate::serde::secret::serialize")]
+    pub password: Option<String>,
+
+    /// How to verify the host key, defaults to checking known_hosts
+    #[serde(default)]
+    pub host_key_verification: HostKeyVerification,
+
+    /// The known_hosts file to check against, defaults to `~/.ssh/known_hosts`
+    pub known_hosts_file: Option<PathBuf>,
+
+    /// Connection timeout (seconds), defaults to [DEFAULT_SSH_TIMEOUT_SECONDS]
+    pub timeout: Option<u32>,
+}
+
+impl ConfigOverlay for SshConnection {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            username: self.extract_value(value, "username", &self.username)?,
+            port: self.extract_value(value, "port", &self.port)?,
+            private_key: self.extract_value(value, "private_key", &self.private_key)?,
+            private_key_passphrase: self.extract_value(
+                value,
+                "private_key_passphrase",
+                &self.private_key_passphrase,
+            )?,
+            use_agent: self.extract_bool(value, "use_agent", self.use_agent),
+            password: self.extract_value(value, "password", &self.password)?,
+            host_key_verification: self.extract_value(
+                value,
+                "host_key_verification",
+                &self.host_key_verification,
+            )?,
+            known_hosts_file: self.extract_value(
+                value,
+                "known_hosts_file",
+                &self.known_hosts_file,
+            )?,
+            timeout: self.extract_value(value, "timeout", &self.timeout)?,
         }))
     }
 }
 
+impl SshConnection {
+    /// Runs a command on the host, returning its output or the result text to record if it failed
+    pub(crate) async fn run_on_host(
+        &self,
+        name: &str,
+        host: &entities::host::Model,
+        command_line: &str,
+    ) -> Result<Result<String, String>, Error> {
+        if host.hostname == LOCAL_SERVICE_HOST_NAME {
+            let output = tokio::time::timeout(
+                Duration::from_secs(DEFAULT_SSH_COMMAND_TIMEOUT_SECONDS as u64),
+                tokio::process::Command::new("sh")
+                    .args(["-c", command_line])
+                    .kill_on_drop(true)
+                    .output(),
+            )
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(|err| Error::Generic(format!("Failed to run {}: {}", command_line, err)))?;
+            return Ok(match output.status.success() {
+                true => Ok(String::from_utf8_lossy(&output.stdout).to_string()),
+                false => Err(format!(
+                    "{} failed: {}",
+                    command_line,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+            });
+        }
+
+        let Some(username) = self.username.clone() else {
+            return Err(Error::Configuration(format!(
+                "{}: username has to be set to run checks over SSH",
+                name
+            )));
+        };
+        let ssh = SshService {
+            name: name.to_string(),
+            command_line: command_line.to_string(),
+            port: self.port,
+            username,
+            private_key: self.private_key.clone(),
+            private_key_passphrase: self.private_key_passphrase.clone(),
+            use_agent: self.use_agent,
+            password: self.password.clone(),
+            host_key_verification: self.host_key_verification.clone(),
+            known_hosts_file: self.known_hosts_file.clone(),
+            timeout: self.timeout,
+            ..Default::default()
+        };
+        Ok(match ssh.run_command(host).await {
+            Ok((ServiceStatus::Ok, stdout, _)) => Ok(stdout),
+            Ok((_, result_text, _)) => Err(result_text),
+            Err(Error::Timeout) => Err(format!("Timed out connecting to {}", host.hostname)),
+            Err(Error::Ssh(err)) => Err(err),
+            Err(err) => return Err(err),
+        })
+    }
+
+    /// Checks the options make sense together
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.private_key_passphrase.is_some() && self.private_key.is_none() {
+            return Err(Error::Configuration(
+                "private_key_passphrase is set but private_key isn't".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl ServiceTrait for SshService {
     /// ssh to the target host and run the command
@@ -105,9 +390,7 @@ impl ServiceTrait for SshService {
 
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
 
-        let mut session = ssh::create_session().username(&config.username);
-
-        // adds the SSH key if we have one, checking first that we have the key
+        // check the SSH key's there before we bother connecting
         if let Some(ssh_key) = &config.private_key {
             if !ssh_key.exists() {
                 return Ok(CheckResult {
@@ -115,81 +398,63 @@ impl ServiceTrait for SshService {
                     result_text: format!("SSH key not found: {}", ssh_key.display()),
                     status: ServiceStatus::Critical,
                     time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
                 });
             }
-
             debug!("Using SSH key {} for connection", ssh_key.display());
-            session = session.private_key_path(ssh_key);
-        } else if let Some(password) = &config.password {
-            debug!("Using password for connection");
-            session = session.password(password);
         }
 
-        let target = format!(
-            "{}:{}",
-            host.hostname.clone(),
-            config.port.map(u16::from).unwrap_or(22)
-        );
-
-        let mut session = session
-            .connect(&target)
-            .map_err(|err| {
-                error!("Failed to connect to {}", target);
-                Error::Generic(err.to_string())
-            })?
-            .run_local();
-
-        debug!("Running ssh command: {:?}", &config.command_line);
-
-        let mut exec = session.open_exec().map_err(|err| {
-            error!("Failed to open exec: {:?}", err);
-            Error::Generic(err.to_string())
-        })?;
-        exec.exec_command(&config.command_line).map_err(|err| {
-            error!("Failed to send SSH command: {:?}", err);
-            Error::Generic(err.to_string())
-        })?;
-
-        let output = exec.get_output().map_err(|err| {
-            error!("Failed to get output: {:?}", err);
-            Error::Generic(err.to_string())
-        })?;
-
-        let result_text = String::from_utf8_lossy(&output).to_string();
-        let exit_status = exec.exit_status().map_err(|err| {
-            error!("Failed to get exit status: {:?}", err);
-            Error::Generic(err.to_string())
-        })?;
-
-        let time_elapsed = chrono::Utc::now() - start_time;
-
-        let status = match exit_status == config.exit_code.unwrap_or(0) {
-            false => ServiceStatus::Critical,
-            true => ServiceStatus::Ok,
+        let (status, result_text, diagnostics) = match config.run_command(host).await {
+            Ok(res) => res,
+            Err(Error::Timeout) => (
+                ServiceStatus::Critical,
+                format!("Timed out connecting to {}", host.hostname),
+                vec![],
+            ),
+            Err(Error::Ssh(err)) => (ServiceStatus::Critical, err, vec![]),
+            Err(err) => return Err(err),
         };
 
-        Ok(CheckResult {
+        let result = CheckResult {
             timestamp: start_time,
-            result_text,
             status,
-            time_elapsed,
-        })
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..Default::default()
+        }
+        .with_plugin_output(&result_text)
+        .with_compare_output(config.detect_changes, &result_text)
+        .with_diagnostics(config.debug_capture, diagnostics);
+        Ok(threshold::apply(&config.thresholds, result, &result_text))
     }
 
     /// Validate the configuration
     fn validate(&self) -> Result<(), Error> {
         // TODO: this should overlay the host config too
-        if self.private_key.is_none() && self.password.is_none() {
+        if self.private_key.is_none() && self.password.is_none() && !self.use_agent {
             return Err(Error::Configuration(
-                "No SSH key or password provided, auth is going to fail!".to_string(),
+                "No SSH key, agent or password provided, auth is going to fail!".to_string(),
             ));
         }
-        Ok(())
+        if self.private_key_passphrase.is_some() && self.private_key.is_none() {
+            return Err(Error::Configuration(
+                "private_key_passphrase is set but private_key isn't".to_string(),
+            ));
+        }
+        if let HostKeyVerification::Fingerprint(fingerprint) = &self.host_key_verification {
+            if fingerprint.trim().is_empty() {
+                return Err(Error::Configuration(
+                    "Host key fingerprint can't be empty".to_string(),
+                ));
+            }
+        }
+        self.thresholds
+            .iter()
+            .try_for_each(NumericThreshold::validate)
     }
 
     fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
-        Ok(serde_json::to_string_pretty(&config)?)
+        crate::serde::secret::to_string_pretty(&config)
     }
 
     fn jitter_value(&self) -> u32 {
@@ -247,9 +512,16 @@ mod tests {
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: hostname.clone(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -302,9 +574,16 @@ mod tests {
         let host = entities::host::Model {
             id: Uuid::new_v4(),
             name: "test".to_string(),
+            slug: "test".to_string(),
             hostname: hostname.clone(),
             check: crate::host::HostCheck::None,
             config: json!({}),
+            vars: json!({}),
+            ipv4: None,
+            ipv6: None,
+            address_family: Default::default(),
+            display_name: None,
+            labels: json!({}),
         };
 
         let res = service.run(&host).await;
@@ -343,7 +622,15 @@ mod tests {
             id: Default::default(),
             description: None,
             host_groups: vec![],
+            labels: Default::default(),
             cron_schedule: Cron::new("@hourly").parse().expect("Failed to parse cron"),
+            agent: None,
+            expose_alert_rule: false,
+            alert_rule_for: None,
+            notifications: None,
+            max_runtime: None,
+            timezone: None,
+            template: None,
             extra_config: HashMap::from_iter([("hello".to_string(), json!("world"))]),
             config: None,
         };
@@ -376,13 +663,60 @@ mod tests {
         .expect("Failed to parse good_service");
 
         assert_eq!(good_service.validate(), Ok(()));
+
+        let agent_service: super::SshService = serde_json::from_value(json!({
+            "name": "check_ntp_time",
+            "command_line": "uptime",
+            "cron_schedule": "@hourly",
+            "username": "maremma",
+            "use_agent": true,
+            "host_key_verification": {"fingerprint": "SHA256:n19OSTQWsLsup1t5CFv4e0ZMqdt9rUQkBi7GM6g+c0Q"},
+            "command_timeout": 5,
+        }))
+        .expect("Failed to parse agent_service");
+        assert_eq!(agent_service.validate(), Ok(()));
+        assert_eq!(agent_service.command_timeout, Some(5));
+
+        let passphrase_without_key: super::SshService = serde_json::from_value(json!({
+            "name": "check_ntp_time",
+            "command_line": "uptime",
+            "cron_schedule": "@hourly",
+            "username": "maremma",
+            "password": "hunter2",
+            "private_key_passphrase": "hunter3",
+        }))
+        .expect("Failed to parse passphrase_without_key");
+        assert!(passphrase_without_key.validate().is_err());
+    }
+
+    #[test]
+    fn test_ssh_overlay_host_key_verification() {
+        let service = super::SshService {
+            password: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            service.host_key_verification,
+            HostKeyVerification::KnownHosts
+        );
+        let mut value = Map::new();
+        value.insert("host_key_verification".to_string(), json!("insecure"));
+        value.insert("known_hosts_file".to_string(), json!("/dev/null"));
+        let res = service
+            .overlay_host_config(&value)
+            .expect("Failed to overlay config");
+        assert_eq!(res.host_key_verification, HostKeyVerification::Insecure);
+        assert_eq!(res.known_hosts_file, Some(PathBuf::from("/dev/null")));
+
+        let serialized = serde_json::to_value(&*res).expect("Failed to serialize");
+        assert_eq!(serialized.get("password"), Some(&json!("*******")));
     }
 
     #[test]
     fn test_serialize_password() {
         #[derive(Serialize)]
         struct SecurePassword {
-            #[serde(serialize_with = "serialize_password")]
+            #[serde(serialize_with = "crate::serde::secret::serialize")]
             password: Option<String>,
         }
 
@@ -0,0 +1,365 @@
+//! Load average, memory and swap use, read from `/proc` over SSH or on the local checks host
+
+use std::collections::BTreeMap;
+
+use super::prelude::*;
+use super::ssh::SshConnection;
+use super::Thresholds;
+use crate::prelude::*;
+
+/// Reads everything in one go, the CPU count's on the second line
+const SYSTEM_COMMAND: &str = "cat /proc/loadavg && getconf _NPROCESSORS_ONLN && cat /proc/meminfo";
+
+/// Default (warning, critical) for the 5 minute load average, per CPU
+pub const DEFAULT_LOAD_THRESHOLDS: (f64, f64) = (1.0, 2.0);
+/// Default (warning, critical) for the percentage of memory used
+pub const DEFAULT_MEMORY_THRESHOLDS: (f64, f64) = (90.0, 95.0);
+/// Default (warning, critical) for the percentage of swap used
+pub const DEFAULT_SWAP_THRESHOLDS: (f64, f64) = (50.0, 80.0);
+
+#[derive(Debug, Clone, PartialEq)]
+/// What's read from the host
+pub(crate) struct SystemStats {
+    pub(crate) load: [f64; 3],
+    pub(crate) cpus: u32,
+    pub(crate) memory_total_kb: u64,
+    pub(crate) memory_available_kb: u64,
+    pub(crate) swap_total_kb: u64,
+    pub(crate) swap_free_kb: u64,
+}
+
+impl SystemStats {
+    /// The 5 minute load average divided by the number of CPUs
+    pub(crate) fn load_per_cpu(&self) -> f64 {
+        self.load[1] / self.cpus.max(1) as f64
+    }
+
+    pub(crate) fn memory_used_percent(&self) -> f64 {
+        match self.memory_total_kb {
+            0 => 0.0,
+            total => total.saturating_sub(self.memory_available_kb) as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// `None` when there's no swap
+    pub(crate) fn swap_used_percent(&self) -> Option<f64> {
+        match self.swap_total_kb {
+            0 => None,
+            total => Some(total.saturating_sub(self.swap_free_kb) as f64 * 100.0 / total as f64),
+        }
+    }
+}
+
+/// Parses the output of [SYSTEM_COMMAND]
+pub(crate) fn parse_system(output: &str) -> Result<SystemStats, Error> {
+    let mut lines = output.lines();
+    let invalid = |what: &str| Error::Generic(format!("Couldn't parse {} from the host", what));
+
+    let load = lines
+        .next()
+        .map(|line| {
+            line.split_whitespace()
+                .take(3)
+                .map(|value| value.parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .and_then(Result::ok)
+        .and_then(|load| <[f64; 3]>::try_from(load).ok())
+        .ok_or_else(|| invalid("the load average"))?;
+    let cpus = lines
+        .next()
+        .and_then(|line| line.trim().parse::<u32>().ok())
+        .ok_or_else(|| invalid("the number of CPUs"))?;
+
+    let meminfo = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let value = value.split_whitespace().next()?.parse::<u64>().ok()?;
+            Some((key.trim(), value))
+        })
+        .collect::<HashMap<_, _>>();
+    let field = |key: &str| {
+        meminfo
+            .get(key)
+            .copied()
+            .ok_or_else(|| invalid(&format!("{} from /proc/meminfo", key)))
+    };
+
+    Ok(SystemStats {
+        load,
+        cpus,
+        memory_total_kb: field("MemTotal")?,
+        memory_available_kb: field("MemAvailable")?,
+        swap_total_kb: field("SwapTotal").unwrap_or(0),
+        swap_free_kb: field("SwapFree").unwrap_or(0),
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// Checks a Linux host's load average, memory and swap use
+pub struct SystemService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// The 5 minute load average per CPU, defaults to [DEFAULT_LOAD_THRESHOLDS]
+    #[serde(default)]
+    pub load: Thresholds,
+
+    /// Percentage of memory used, not counting what can be reclaimed, defaults to [DEFAULT_MEMORY_THRESHOLDS]
+    #[serde(default)]
+    pub memory: Thresholds,
+
+    /// Percentage of swap used, defaults to [DEFAULT_SWAP_THRESHOLDS]
+    #[serde(default)]
+    pub swap: Thresholds,
+
+    /// How to connect to the host, these sit alongside the other options
+    #[serde(flatten)]
+    pub ssh: SshConnection,
+}
+
+impl ConfigOverlay for SystemService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            load: self.extract_value(value, "load", &self.load)?,
+            memory: self.extract_value(value, "memory", &self.memory)?,
+            swap: self.extract_value(value, "swap", &self.swap)?,
+            ssh: *self.ssh.overlay_host_config(value)?,
+        }))
+    }
+}
+
+impl SystemService {
+    fn load_thresholds(&self) -> (f64, f64) {
+        self.load
+            .or(DEFAULT_LOAD_THRESHOLDS.0, DEFAULT_LOAD_THRESHOLDS.1)
+    }
+
+    fn memory_thresholds(&self) -> (f64, f64) {
+        self.memory
+            .or(DEFAULT_MEMORY_THRESHOLDS.0, DEFAULT_MEMORY_THRESHOLDS.1)
+    }
+
+    fn swap_thresholds(&self) -> (f64, f64) {
+        self.swap
+            .or(DEFAULT_SWAP_THRESHOLDS.0, DEFAULT_SWAP_THRESHOLDS.1)
+    }
+
+    /// Works out the status, returning it with the summary and details
+    fn evaluate(&self, stats: &SystemStats) -> (ServiceStatus, String, BTreeMap<String, String>) {
+        let (load_warning, load_critical) = self.load_thresholds();
+        let (memory_warning, memory_critical) = self.memory_thresholds();
+        let (swap_warning, swap_critical) = self.swap_thresholds();
+
+        let mut checks = vec![
+            (
+                Thresholds::status(stats.load_per_cpu(), load_warning, load_critical),
+                format!(
+                    "load {:.2} {:.2} {:.2} on {} CPUs",
+                    stats.load[0], stats.load[1], stats.load[2], stats.cpus
+                ),
+            ),
+            (
+                Thresholds::status(stats.memory_used_percent(), memory_warning, memory_critical),
+                format!("memory {:.1}% used", stats.memory_used_percent()),
+            ),
+        ];
+        if let Some(swap) = stats.swap_used_percent() {
+            checks.push((
+                Thresholds::status(swap, swap_warning, swap_critical),
+                format!("swap {:.1}% used", swap),
+            ));
+        }
+
+        let status = checks
+            .iter()
+            .map(|(status, _)| *status)
+            .max()
+            .unwrap_or(ServiceStatus::Ok);
+        let summary = checks
+            .iter()
+            .map(|(status, text)| match status {
+                ServiceStatus::Ok => text.clone(),
+                _ => format!("{} ({})", text, status),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut details = BTreeMap::from([
+            ("load1".to_string(), format!("{:.2}", stats.load[0])),
+            ("load5".to_string(), format!("{:.2}", stats.load[1])),
+            ("load15".to_string(), format!("{:.2}", stats.load[2])),
+            ("cpus".to_string(), stats.cpus.to_string()),
+            (
+                "memory_used_percent".to_string(),
+                format!("{:.2}", stats.memory_used_percent()),
+            ),
+            (
+                "memory_available_mb".to_string(),
+                (stats.memory_available_kb / 1024).to_string(),
+            ),
+        ]);
+        if let Some(swap) = stats.swap_used_percent() {
+            details.insert("swap_used_percent".to_string(), format!("{:.2}", swap));
+        }
+        (status, summary, details)
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for SystemService {
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = chrono::Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+
+        let output = match config
+            .ssh
+            .run_on_host(&self.name, host, SYSTEM_COMMAND)
+            .await?
+        {
+            Ok(output) => output,
+            Err(result_text) => {
+                return Ok(CheckResult {
+                    timestamp: start_time,
+                    result_text,
+                    status: ServiceStatus::Critical,
+                    time_elapsed: chrono::Utc::now() - start_time,
+                    ..Default::default()
+                })
+            }
+        };
+
+        let (status, result_text, details) = config.evaluate(&parse_system(&output)?);
+        Ok(CheckResult {
+            timestamp: start_time,
+            status,
+            result_text,
+            details,
+            time_elapsed: chrono::Utc::now() - start_time,
+            ..Default::default()
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for (what, (warning, critical)) in [
+            ("load", self.load_thresholds()),
+            ("memory", self.memory_thresholds()),
+            ("swap", self.swap_thresholds()),
+        ] {
+            if warning > critical {
+                return Err(Error::Configuration(format!(
+                    "{}: {} warning ({}) can't be above critical ({})",
+                    self.name, what, warning, critical
+                )));
+            }
+        }
+        self.ssh.validate()
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::service_from_json;
+
+    const SYSTEM_OUTPUT: &str = "3.52 6.10 4.90 2/467 12345
+4
+MemTotal:       16318884 kB
+MemFree:          423164 kB
+MemAvailable:    1631888 kB
+Buffers:          321588 kB
+SwapTotal:       2097148 kB
+SwapFree:        2097148 kB
+";
+
+    #[test]
+    fn test_parse_system() {
+        let stats = parse_system(SYSTEM_OUTPUT).expect("Failed to parse system output");
+        assert_eq!(stats.load, [3.52, 6.10, 4.90]);
+        assert_eq!(stats.cpus, 4);
+        assert!((stats.load_per_cpu() - 1.525).abs() < 0.001);
+        assert!((stats.memory_used_percent() - 90.0).abs() < 0.01);
+        assert_eq!(stats.swap_used_percent(), Some(0.0));
+
+        assert!(parse_system("not a load average\n4\n").is_err());
+        assert!(parse_system("1.0 1.0 1.0 1/1 1\n4\nMemTotal: 100 kB\n").is_err());
+        let stats = parse_system("1.0 1.0 1.0 1/1 1\n4\nMemTotal: 100 kB\nMemAvailable: 50 kB\n")
+            .expect("Failed to parse system output without swap");
+        assert_eq!(stats.swap_used_percent(), None);
+    }
+
+    #[test]
+    fn test_system_evaluate() {
+        let stats = parse_system(SYSTEM_OUTPUT).expect("Failed to parse system output");
+
+        let (status, summary, details) = service_from_json::<SystemService>("system", json!({}))
+            .expect("Failed to parse system service")
+            .evaluate(&stats);
+        assert_eq!(status, ServiceStatus::Warning);
+        assert_eq!(
+            summary,
+            "load 3.52 6.10 4.90 on 4 CPUs (Warning), memory 90.0% used (Warning), swap 0.0% used"
+        );
+        assert_eq!(details.get("load5").map(String::as_str), Some("6.10"));
+        assert_eq!(
+            details.get("memory_available_mb").map(String::as_str),
+            Some("1593")
+        );
+
+        let (status, _, _) = service_from_json::<SystemService>(
+            "system",
+            json!({
+                "load": {"warning": 2.0, "critical": 4.0},
+                "memory": {"critical": 89.0},
+            }),
+        )
+        .expect("Failed to parse system service")
+        .evaluate(&stats);
+        assert_eq!(status, ServiceStatus::Critical);
+
+        let (status, summary, _) = service_from_json::<SystemService>(
+            "system",
+            json!({
+                "load": {"warning": 2.0, "critical": 4.0},
+                "memory": {"warning": 95.0, "critical": 99.0},
+            }),
+        )
+        .expect("Failed to parse system service")
+        .evaluate(&stats);
+        assert_eq!(status, ServiceStatus::Ok);
+        assert!(!summary.contains("Warning"));
+    }
+
+    #[test]
+    fn test_system_validate() {
+        assert!(service_from_json::<SystemService>("system", json!({}))
+            .expect("Failed to parse system service")
+            .validate()
+            .is_ok());
+        assert!(
+            service_from_json::<SystemService>("system", json!({"swap": {"warning": 90.0}}))
+                .expect("Failed to parse system service")
+                .validate()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Warning and critical thresholds on a number from a check's output, shared by the services that return numbers
+//!
+//! A threshold is either a comparison like `> 80` or `<= 5`, or a Nagios-style range: `10` alerts outside `0..=10`,
+//! `10:` below 10, `~:10` above 10, `10:20` outside `10..=20`, and `@10:20` inside it.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use super::http::json_path;
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// When a value's bad enough to alert on
+pub enum Threshold {
+    /// `> n`
+    Above(f64),
+    /// `>= n`
+    AtLeast(f64),
+    /// `< n`
+    Below(f64),
+    /// `<= n`
+    AtMost(f64),
+    /// `== n`
+    Equal(f64),
+    /// `!= n`
+    NotEqual(f64),
+    /// A Nagios-style range, alerting outside it unless `inside` is set (with a leading `@`)
+    Range {
+        /// The lower bound, `~` is negative infinity
+        start: f64,
+        /// The upper bound, it's infinity when it's left out
+        end: f64,
+        /// Alert when the value's inside the range
+        inside: bool,
+    },
+}
+
+impl Threshold {
+    /// If the value should be alerted on
+    pub fn matches(&self, value: f64) -> bool {
+        match *self {
+            Self::Above(n) => value > n,
+            Self::AtLeast(n) => value >= n,
+            Self::Below(n) => value < n,
+            Self::AtMost(n) => value <= n,
+            Self::Equal(n) => value == n,
+            Self::NotEqual(n) => value != n,
+            Self::Range { start, end, inside } => (start..=end).contains(&value) == inside,
+        }
+    }
+}
+
+fn parse_number(value: &str, threshold: &str) -> Result<f64, Error> {
+    value.trim().parse().map_err(|_| {
+        Error::Configuration(format!(
+            "Invalid threshold {:?}, {:?} isn't a number",
+            threshold, value
+        ))
+    })
+}
+
+impl FromStr for Threshold {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let threshold = s.trim();
+        // the two character operators have to go first
+        for (operator, build) in [
+            (">=", Self::AtLeast as fn(f64) -> Self),
+            ("<=", Self::AtMost),
+            ("==", Self::Equal),
+            ("!=", Self::NotEqual),
+            (">", Self::Above),
+            ("<", Self::Below),
+        ] {
+            if let Some(number) = threshold.strip_prefix(operator) {
+                return Ok(build(parse_number(number, s)?));
+            }
+        }
+
+        let (inside, range) = match threshold.strip_prefix('@') {
+            Some(range) => (true, range),
+            None => (false, threshold),
+        };
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (
+                match start.trim() {
+                    "" => 0.0,
+                    "~" => f64::NEG_INFINITY,
+                    start => parse_number(start, s)?,
+                },
+                match end.trim() {
+                    "" => f64::INFINITY,
+                    end => parse_number(end, s)?,
+                },
+            ),
+            None => (0.0, parse_number(range, s)?),
+        };
+        if start > end {
+            return Err(Error::Configuration(format!(
+                "Invalid threshold {:?}, the start's after the end",
+                s
+            )));
+        }
+        Ok(Self::Range { start, end, inside })
+    }
+}
+
+impl Display for Threshold {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Above(n) => write!(f, "> {}", n),
+            Self::AtLeast(n) => write!(f, ">= {}", n),
+            Self::Below(n) => write!(f, "< {}", n),
+            Self::AtMost(n) => write!(f, "<= {}", n),
+            Self::Equal(n) => write!(f, "== {}", n),
+            Self::NotEqual(n) => write!(f, "!= {}", n),
+            Self::Range { start, end, inside } => {
+                if *inside {
+                    write!(f, "@")?;
+                }
+                match (*start == f64::NEG_INFINITY, *end == f64::INFINITY) {
+                    (true, true) => write!(f, "~:"),
+                    (true, false) => write!(f, "~:{}", end),
+                    (false, true) => write!(f, "{}:", start),
+                    (false, false) if *start == 0.0 => write!(f, "{}", end),
+                    (false, false) => write!(f, "{}:{}", start, end),
+                }
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Threshold {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(|err| serde::de::Error::custom(format!("{:?}", err)))
+    }
+}
+
+impl Serialize for Threshold {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Default)]
+/// Alerts on a number from the check, by default the first word of the output
+pub struct NumericThreshold {
+    /// What to call the value in the result text, defaults to the perfdata key or JSONPath
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Take the number from this performance data key, eg `load1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perfdata: Option<String>,
+    /// Take the number from this JSONPath in the output, eg `$.queue.depth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_path: Option<String>,
+    /// Warning when the value matches, eg `> 80` or `@10:20`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub warn: Option<Threshold>,
+    /// Critical when the value matches, eg `>= 95`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub crit: Option<Threshold>,
+}
+
+/// Pulls the number off the front of a value like `85%` or `12.5ms`
+fn leading_number(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let end = value
+        .char_indices()
+        .find(|(index, c)| {
+            !(c.is_ascii_digit() || *c == '.' || ((*c == '-' || *c == '+') && *index == 0))
+        })
+        .map(|(index, _)| index)
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+impl NumericThreshold {
+    /// The name used in the result text
+    pub fn name(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.perfdata.as_deref())
+            .or(self.json_path.as_deref())
+            .unwrap_or("value")
+    }
+
+    /// Checks there's something to alert on, and only one place to find the number
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.warn.is_none() && self.crit.is_none() {
+            return Err(Error::Configuration(format!(
+                "Threshold {} needs warn or crit",
+                self.name()
+            )));
+        }
+        if self.perfdata.is_some() && self.json_path.is_some() {
+            return Err(Error::Configuration(format!(
+                "Threshold {} can only have one of perfdata and json_path",
+                self.name()
+            )));
+        }
+        if let Some(path) = self.json_path.as_ref() {
+            json_path(&Value::Null, path)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the number, from the result's details for perfdata, otherwise from the output
+    fn value(&self, details: &BTreeMap<String, String>, output: &str) -> Result<f64, String> {
+        if let Some(key) = self.perfdata.as_ref() {
+            let value = details
+                .get(key)
+                .ok_or_else(|| format!("{} not found in performance data", key))?;
+            return leading_number(value)
+                .ok_or_else(|| format!("{} is {}, not a number", key, value));
+        }
+        if let Some(path) = self.json_path.as_ref() {
+            let body: Value = serde_json::from_str(output)
+                .map_err(|err| format!("Failed to parse output as JSON: {}", err))?;
+            let found = json_path(&body, path)
+                .map_err(|err| format!("{:?}", err))?
+                .ok_or_else(|| format!("{} not found in output", path))?;
+            return found
+                .as_f64()
+                .or_else(|| found.as_str().and_then(leading_number))
+                .ok_or_else(|| format!("{} is {}, not a number", path, found));
+        }
+        let first = output.split_whitespace().next().unwrap_or_default();
+        leading_number(first)
+            .ok_or_else(|| format!("Output {:?} doesn't start with a number", first))
+    }
+
+    /// The status for the value, with a description if it's over a threshold
+    pub fn evaluate(
+        &self,
+        details: &BTreeMap<String, String>,
+        output: &str,
+    ) -> (ServiceStatus, Option<String>, Option<f64>) {
+        let value = match self.value(details, output) {
+            Ok(value) => value,
+            Err(message) => return (ServiceStatus::Critical, Some(message), None),
+        };
+        for (status, threshold) in [
+            (ServiceStatus::Critical, self.crit),
+            (ServiceStatus::Warning, self.warn),
+        ] {
+            if let Some(threshold) = threshold.filter(|threshold| threshold.matches(value)) {
+                return (
+                    status,
+                    Some(format!(
+                        "{} is {} ({} {})",
+                        self.name(),
+                        value,
+                        status,
+                        threshold
+                    )),
+                    Some(value),
+                );
+            }
+        }
+        (ServiceStatus::Ok, None, Some(value))
+    }
+}
+
+/// Checks the thresholds against a result that's otherwise OK or a warning, the worst status wins
+pub fn apply(
+    thresholds: &[NumericThreshold],
+    mut result: CheckResult,
+    output: &str,
+) -> CheckResult {
+    if !matches!(result.status, ServiceStatus::Ok | ServiceStatus::Warning) {
+        return result;
+    }
+    let mut messages = Vec::new();
+    for threshold in thresholds {
+        let (status, message, value) = threshold.evaluate(&result.details, output);
+        // perfdata's already in the details, keep the others for graphing
+        if let (None, Some(value)) = (threshold.perfdata.as_ref(), value) {
+            result
+                .details
+                .insert(threshold.name().to_string(), value.to_string());
+        }
+        result.status = result.status.max(status);
+        messages.extend(message);
+    }
+    if !messages.is_empty() {
+        let summary = messages.join(", ");
+        result.result_text = match result.result_text.is_empty() {
+            true => summary,
+            false => format!("{}: {}", summary, result.result_text),
+        };
+    }
+    result
+}
+
+/// The key slow-response checks record how long they took under
+pub const LATENCY_DETAIL: &str = "latency_ms";
+
+/// A response that took too long is a warning or critical even though it worked, returns the status and why
+pub fn latency(
+    elapsed_ms: u64,
+    warn_ms: Option<u64>,
+    critical_ms: Option<u64>,
+) -> Option<(ServiceStatus, String)> {
+    match (warn_ms, critical_ms) {
+        (_, Some(critical)) if elapsed_ms > critical => Some((
+            ServiceStatus::Critical,
+            format!("critical over {}ms", critical),
+        )),
+        (Some(warn), _) if elapsed_ms > warn => {
+            Some((ServiceStatus::Warning, format!("warning over {}ms", warn)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threshold(value: &str) -> Threshold {
+        value.parse().expect("Failed to parse threshold")
+    }
+
+    #[test]
+    fn test_threshold() {
+        assert!(threshold("> 80").matches(80.5));
+        assert!(!threshold(">80").matches(80.0));
+        assert!(threshold(">= 95").matches(95.0));
+        assert!(threshold("< 5").matches(4.9));
+        assert!(threshold("<=5").matches(5.0));
+        assert!(threshold("== 0").matches(0.0));
+        assert!(threshold("!= 0").matches(1.0));
+
+        // nagios ranges
+        assert!(threshold("10").matches(11.0));
+        assert!(threshold("10").matches(-1.0));
+        assert!(!threshold("10").matches(10.0));
+        assert!(threshold("10:").matches(9.0));
+        assert!(!threshold("10:").matches(1000.0));
+        assert!(threshold("~:10").matches(11.0));
+        assert!(!threshold("~:10").matches(-1000.0));
+        assert!(threshold("10:20").matches(21.0));
+        assert!(!threshold("10:20").matches(15.0));
+        assert!(threshold("@10:20").matches(10.0));
+        assert!(!threshold("@10:20").matches(9.0));
+
+        for text in ["> 80", ">= 95", "10", "10:", "~:10", "10:20", "@10:20"] {
+            assert_eq!(threshold(text).to_string(), text);
+        }
+        for bad in ["", "> lots", "20:10", "@", "1:2:3", "~"] {
+            assert!(bad.parse::<Threshold>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn test_numeric_threshold() {
+        let load: NumericThreshold = serde_json::from_value(json!({
+            "perfdata": "load1",
+            "warn": "> 4",
+            "crit": "> 8",
+        }))
+        .expect("Failed to parse threshold");
+        assert!(load.validate().is_ok());
+        let details = BTreeMap::from([("load1".to_string(), "5.5".to_string())]);
+        let (status, message, _) = load.evaluate(&details, "");
+        assert_eq!(status, ServiceStatus::Warning);
+        assert_eq!(message, Some("load1 is 5.5 (warning > 4)".to_string()));
+        assert_eq!(
+            load.evaluate(&BTreeMap::new(), "").0,
+            ServiceStatus::Critical
+        );
+
+        let queue = NumericThreshold {
+            json_path: Some("$.queue.depth".to_string()),
+            crit: Some(threshold("@100:")),
+            ..Default::default()
+        };
+        assert_eq!(
+            queue.evaluate(&details, r#"{"queue": {"depth": 150}}"#).0,
+            ServiceStatus::Critical
+        );
+        assert_eq!(
+            queue.evaluate(&details, r#"{"queue": {"depth": "12"}}"#).0,
+            ServiceStatus::Ok
+        );
+
+        let output = NumericThreshold {
+            warn: Some(threshold("> 80")),
+            ..Default::default()
+        };
+        assert_eq!(
+            output.evaluate(&details, "85% used").0,
+            ServiceStatus::Warning
+        );
+        assert_eq!(
+            output.evaluate(&details, "nothing").0,
+            ServiceStatus::Critical
+        );
+
+        assert!(NumericThreshold::default().validate().is_err());
+        assert!(NumericThreshold {
+            perfdata: Some("load1".to_string()),
+            json_path: Some("$.load".to_string()),
+            ..load.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(NumericThreshold {
+            json_path: Some("nope".to_string()),
+            ..output.clone()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let thresholds = vec![NumericThreshold {
+            name: Some("disk".to_string()),
+            warn: Some(threshold("> 80")),
+            crit: Some(threshold("> 95")),
+            ..Default::default()
+        }];
+        let result = CheckResult {
+            status: ServiceStatus::Ok,
+            result_text: "97".to_string(),
+            ..Default::default()
+        };
+        let res = apply(&thresholds, result.clone(), "97");
+        assert_eq!(res.status, ServiceStatus::Critical);
+        assert_eq!(res.result_text, "disk is 97 (critical > 95): 97");
+        assert_eq!(res.details.get("disk"), Some(&"97".to_string()));
+
+        let res = apply(&thresholds, result.clone(), "50");
+        assert_eq!(res.status, ServiceStatus::Ok);
+        assert_eq!(res.result_text, "97");
+
+        // checks that have already failed are left alone
+        let failed = CheckResult {
+            status: ServiceStatus::Error,
+            ..result
+        };
+        assert_eq!(
+            apply(&thresholds, failed, "97").status,
+            ServiceStatus::Error
+        );
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!(latency(1000, None, None), None);
+        assert_eq!(latency(100, Some(100), Some(500)), None);
+        assert_eq!(
+            latency(101, Some(100), Some(500)),
+            Some((ServiceStatus::Warning, "warning over 100ms".to_string()))
+        );
+        assert_eq!(
+            latency(501, Some(100), Some(500)),
+            Some((ServiceStatus::Critical, "critical over 500ms".to_string()))
+        );
+        assert_eq!(
+            latency(501, None, Some(500)).map(|(status, _)| status),
+            Some(ServiceStatus::Critical)
+        );
+    }
+}
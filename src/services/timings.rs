@@ -0,0 +1,139 @@
+//! Connection timings for services with `debug_capture` set, split up into the DNS lookup, TCP connect and TLS handshake
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+
+use super::tls::recording_connector;
+use crate::prelude::*;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// How long each step of making a connection took, the ones that didn't happen are `None`
+pub(crate) struct ConnectionTimings {
+    /// Looking up the hostname, skipped when there's already an address to connect to
+    pub dns: Option<TimeDelta>,
+    /// Opening the TCP connection
+    pub connect: Option<TimeDelta>,
+    /// The TLS handshake, for TLS connections
+    pub tls_handshake: Option<TimeDelta>,
+}
+
+impl ConnectionTimings {
+    /// The timings in milliseconds, for [CheckResult::with_diagnostics]
+    pub fn diagnostics(&self) -> Vec<(String, String)> {
+        [
+            ("dns_ms", self.dns),
+            ("connect_ms", self.connect),
+            ("tls_handshake_ms", self.tls_handshake),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| {
+            value.map(|value| (key.to_string(), value.num_milliseconds().to_string()))
+        })
+        .collect()
+    }
+
+    /// Looks the hostname up if there isn't an address to use already, then connects to the first address that answers
+    pub async fn connect(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        address: Option<IpAddr>,
+    ) -> std::io::Result<TcpStream> {
+        let addresses: Vec<SocketAddr> = match address {
+            Some(address) => vec![SocketAddr::new(address, port)],
+            None => {
+                let start = Utc::now();
+                let addresses = tokio::net::lookup_host((hostname, port)).await?.collect();
+                self.dns = Some(Utc::now() - start);
+                addresses
+            }
+        };
+        let start = Utc::now();
+        let stream = TcpStream::connect(addresses.as_slice()).await?;
+        self.connect = Some(Utc::now() - start);
+        Ok(stream)
+    }
+}
+
+/// Makes a connection like the check's own and times it, for services whose client doesn't say how long each
+/// step took. The handshake's only done if there's a `server_name`, and whatever didn't finish is left out.
+pub(crate) async fn probe(
+    hostname: &str,
+    port: u16,
+    address: Option<IpAddr>,
+    server_name: Option<ServerName<'static>>,
+    timeout: Duration,
+) -> ConnectionTimings {
+    let mut timings = ConnectionTimings::default();
+    let res = tokio::time::timeout(timeout, async {
+        let stream = timings.connect(hostname, port, address).await?;
+        if let Some(server_name) = server_name {
+            // the verifier accepts anything, the check itself cares about the certificate
+            let (connector, _) = recording_connector(None);
+            let start = Utc::now();
+            connector.connect(server_name, stream).await?;
+            timings.tls_handshake = Some(Utc::now() - start);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => debug!("Timing probe to {}:{} failed: {:?}", hostname, port, err),
+        Err(_) => debug!("Timing probe to {}:{} timed out", hostname, port),
+    }
+    timings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostics() {
+        let timings = ConnectionTimings {
+            dns: None,
+            connect: Some(TimeDelta::milliseconds(12)),
+            tls_handshake: Some(TimeDelta::milliseconds(34)),
+        };
+        assert_eq!(
+            timings.diagnostics(),
+            vec![
+                ("connect_ms".to_string(), "12".to_string()),
+                ("tls_handshake_ms".to_string(), "34".to_string()),
+            ]
+        );
+        assert!(ConnectionTimings::default().diagnostics().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind listener");
+        let port = listener
+            .local_addr()
+            .expect("Failed to get listener address")
+            .port();
+
+        let timings = probe("localhost", port, None, None, Duration::from_secs(5)).await;
+        assert!(timings.dns.is_some());
+        assert!(timings.connect.is_some());
+        assert!(timings.tls_handshake.is_none());
+
+        // the address skips the lookup
+        let timings = probe(
+            "localhost",
+            port,
+            Some(IpAddr::from([127, 0, 0, 1])),
+            None,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(timings.dns.is_none());
+        assert!(timings.connect.is_some());
+    }
+}
@@ -1,25 +1,28 @@
 //! TLS service checks
 
+pub(crate) mod ocsp;
 #[cfg(test)]
 mod tests;
 pub(crate) mod verifier;
 
 use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
 
+use ocsp::OcspStatus;
 use schemars::JsonSchema;
 use verifier::TlsCertVerifier;
 
 use rustls::pki_types::ServerName;
-use tokio::net::TcpStream;
+use rustls::{AlertDescription, PeerIncompatible, ProtocolVersion};
 use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 use tokio_rustls::TlsConnector;
 
 use super::prelude::*;
+use super::root_store::{build_root_store, load_ca_file, RootStore};
+use super::timings::ConnectionTimings;
+use super::traceroute;
 use crate::prelude::*;
 
-/// The IO error returns something like this and we want to find it: `IoError("unexpected error: {\"expiry\":\"2024-11-07T15:05:43Z\"}")`
-pub const UNEXPECTED_ERROR_PREFIX: &str = "unexpected error: ";
-
 /// Default value for "expires in days" to trigger a critical alert
 pub const DEFAULT_CRITICAL_DAYS: u16 = 0;
 /// Default value for "expires in days" to trigger a warning alert
@@ -28,8 +31,6 @@ pub const DEFAULT_WARNING_DAYS: u16 = 1;
 /// For when you want to check TLS things like certificate expiries etc
 #[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct TlsService {
-    // TODO: CA cert
-    // TODO: sni/hostname to check
     /// Name of the service
     pub name: String,
     #[serde(with = "crate::serde::cron")]
@@ -50,6 +51,60 @@ pub struct TlsService {
 
     /// Add random jitter in 0..n seconds to the check
     pub jitter: Option<u16>,
+
+    /// PEM bundle of CA certificates to verify the chain against, instead of the webpki roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Which roots to trust: `webpki` (the default), `system` or `file:<path>`, certificates in `ca_file` are trusted as well
+    #[serde(default)]
+    pub root_store: RootStore,
+
+    /// The name to send in SNI and check the certificate against, if it's different to the host's hostname
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni_hostname: Option<String>,
+
+    /// Check the stapled OCSP response - revoked is Critical, missing, unknown or stale is a Warning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_ocsp: Option<bool>,
+
+    /// Record how long the DNS lookup, connection and handshake took with each result
+    #[serde(default)]
+    pub debug_capture: bool,
+
+    /// Run a traceroute when the connection fails, adding where the path stops answering to the result
+    #[serde(default)]
+    pub traceroute_on_failure: bool,
+}
+
+impl TlsService {
+    /// Builds the trust store from `root_store` and `ca_file`, or returns `None` for the webpki roots
+    fn custom_root_store(&self) -> Result<Option<RootCertStore>, Error> {
+        custom_root_store(&self.root_store, self.ca_file.as_deref())
+    }
+}
+
+/// Builds the trust store from a `root_store` and `ca_file`, or returns `None` for the webpki roots
+pub(crate) fn custom_root_store(
+    root_store: &RootStore,
+    ca_file: Option<&Path>,
+) -> Result<Option<RootCertStore>, Error> {
+    let roots = root_store.certificates()?;
+    if roots.is_none() && ca_file.is_none() {
+        return Ok(None);
+    }
+    let mut certs = roots.map(|roots| roots.to_vec()).unwrap_or_default();
+    if let Some(ca_file) = ca_file {
+        certs.extend(load_ca_file(ca_file)?.iter().cloned());
+    }
+    let store = build_root_store(&certs);
+    if store.is_empty() {
+        return Err(Error::TlsError(format!(
+            "No usable CA certificates found in {}",
+            root_store
+        )));
+    }
+    Ok(Some(store))
 }
 
 impl ConfigOverlay for TlsService {
@@ -62,6 +117,16 @@ impl ConfigOverlay for TlsService {
             expiry_warn: self.extract_value(value, "expiry_warn", &self.expiry_warn)?,
             timeout: self.extract_value(value, "timeout", &self.timeout)?,
             jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            ca_file: self.extract_value(value, "ca_file", &self.ca_file)?,
+            root_store: self.extract_value(value, "root_store", &self.root_store)?,
+            sni_hostname: self.extract_value(value, "sni_hostname", &self.sni_hostname)?,
+            check_ocsp: self.extract_value(value, "check_ocsp", &self.check_ocsp)?,
+            debug_capture: self.extract_bool(value, "debug_capture", self.debug_capture),
+            traceroute_on_failure: self.extract_bool(
+                value,
+                "traceroute_on_failure",
+                self.traceroute_on_failure,
+            ),
         }))
     }
 }
@@ -75,43 +140,39 @@ impl ServiceTrait for TlsService {
     async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
         let start_time = chrono::Utc::now();
 
-        // this comes from the rustls example here: https://github.com/rustls/tokio-rustls/blob/HEAD/examples/client.rs
-        let root_store = RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.into(),
-        };
-        let mut client_config: ClientConfig = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        //  we use our own verifier because we want all the data
-        let tls_verifier = Arc::new(TlsCertVerifier);
-        // nosemgrep: rust.lang.security.rustls-dangerous.rustls-dangerous
-        client_config
-            .dangerous()
-            .set_certificate_verifier(tls_verifier);
-
-        let connector = TlsConnector::from(Arc::new(client_config));
-        let dnsname = match ServerName::try_from(host.hostname.clone()) {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+
+        let (connector, tls_verifier) =
+            recording_connector(config.custom_root_store()?.map(Arc::new));
+        // the name we ask for, which doesn't have to be the address we connect to
+        let server_name = config
+            .sni_hostname
+            .clone()
+            .unwrap_or_else(|| host.hostname.clone());
+        let dnsname = match ServerName::try_from(server_name.clone()) {
             Ok(val) => val,
             Err(_err) => {
                 debug!(
                     "Invalid hostname specified for TLS check hostname={}",
-                    host.hostname
+                    server_name
                 );
                 let timestamp = chrono::Utc::now();
                 return Ok(CheckResult {
                     time_elapsed: start_time - timestamp,
                     timestamp: chrono::Utc::now(),
                     status: ServiceStatus::Critical,
-                    result_text: format!("Invalid hostname '{}'", host.hostname),
+                    result_text: format!("Invalid hostname '{}'", server_name),
+                    ..Default::default()
                 });
             }
         };
 
-        let timeout_duration = tokio::time::Duration::from_secs(self.timeout.unwrap_or(10) as u64);
+        let timeout_duration =
+            tokio::time::Duration::from_secs(config.timeout.unwrap_or(10) as u64);
+        let mut timings = ConnectionTimings::default();
         let stream = match tokio::time::timeout(
             timeout_duration,
-            TcpStream::connect(format!("{}:{}", host.hostname, self.port)),
+            timings.connect(&host.hostname, config.port.get(), None),
         )
         .await
         {
@@ -123,7 +184,7 @@ impl ServiceTrait for TlsService {
                         host.hostname, err
                     );
                     let timestamp = chrono::Utc::now();
-                    return Ok(CheckResult {
+                    let result = CheckResult {
                         time_elapsed: start_time - timestamp,
                         timestamp: chrono::Utc::now(),
                         status: ServiceStatus::Critical,
@@ -131,81 +192,151 @@ impl ServiceTrait for TlsService {
                             "Failed to connect to hostname=\"{}\" error=\"{}\"",
                             host.hostname, err
                         ),
+                        ..Default::default()
+                    };
+                    return Ok(match config.traceroute_on_failure {
+                        true => {
+                            traceroute::enrich(result, &host.hostname, "traceroute".to_string())
+                                .await
+                        }
+                        false => result,
                     });
                 }
             },
+            // a timeout's an error unless there's a traceroute to show with it
+            Err(_) if config.traceroute_on_failure => {
+                let result = CheckResult {
+                    timestamp: chrono::Utc::now(),
+                    status: ServiceStatus::Timeout,
+                    result_text: format!("Timed out connecting to hostname=\"{}\"", host.hostname),
+                    ..Default::default()
+                };
+                let result =
+                    traceroute::enrich(result, &host.hostname, "traceroute".to_string()).await;
+                return Ok(CheckResult {
+                    time_elapsed: chrono::Utc::now() - start_time,
+                    ..result
+                });
+            }
             Err(_) => return Err(Error::Timeout),
         };
 
-        let result: TlsPeerState = match connector.connect(dnsname, stream).await {
-            Ok(_val) => return Err(Error::Generic(
-                "Something went hinky in the TLS check parser, it should always return an 'Error'!"
-                    .to_string(),
-            )),
+        let handshake_start = chrono::Utc::now();
+        let handshake = connector.connect(dnsname, stream).await.map(|stream| {
+            let (_, connection) = stream.get_ref();
+            (
+                connection.protocol_version(),
+                connection
+                    .negotiated_cipher_suite()
+                    .map(|suite| suite.suite()),
+            )
+        });
+        timings.tls_handshake = Some(chrono::Utc::now() - handshake_start);
+
+        let Some(mut result) = tls_verifier.take_state() else {
+            // we never got as far as seeing a certificate
+            return match handshake {
+                Err(err) if is_old_protocol_error(&err) => {
+                    let timestamp = chrono::Utc::now();
+                    Ok(CheckResult {
+                        timestamp,
+                        time_elapsed: timestamp - start_time,
+                        status: ServiceStatus::Warning,
+                        result_text: "Server doesn't support TLS 1.2 or newer".to_string(),
+                        ..Default::default()
+                    })
+                }
+                Err(err) => Err(err.into()),
+                Ok(_) => Err(Error::Generic(
+                    "TLS handshake finished without checking the certificate".to_string(),
+                )),
+            };
+        };
 
+        match handshake {
+            Ok((protocol_version, cipher_suite)) => {
+                result.protocol_version = protocol_version;
+                result.cipher_suite = cipher_suite.map(|suite| format!("{:?}", suite));
+            }
             Err(err) => {
-                // This is so bad. I know.
-                let err_string = err.to_string();
-                if err_string.starts_with(UNEXPECTED_ERROR_PREFIX) {
-                    let (_, val) = err_string.split_at(UNEXPECTED_ERROR_PREFIX.len());
-                    debug!("Found our sneaky serialized state in the error! {}", val);
-                    // let's try and deserialize this thing
-                    serde_json::from_str(val)?
-                } else {
-                    return Err(err.into());
-                }
+                debug!(
+                    "TLS handshake failed after checking the certificate: {:?}",
+                    err
+                );
+                result.handshake_error = Some(err.to_string());
             }
-        };
+        }
 
         let mut status = ServiceStatus::Ok;
         let mut result_strings = Vec::new();
 
-        let expiry_critical_seconds =
-            self.expiry_critical.unwrap_or(DEFAULT_CRITICAL_DAYS) as i64 * 86400;
-        let expiry_warn_seconds = self.expiry_warn.unwrap_or(DEFAULT_WARNING_DAYS) as i64 * 86400;
-
-        if result.cert_expired() {
+        let problems = result.certificate_problems(config.ca_file.is_some());
+        if !problems.is_empty() {
             status = ServiceStatus::Critical;
-            result_strings.push(format!(
-                "Certificate expired {} days ago",
-                -result.expiry_days()
-            ));
-        }
-        if !result.cert_name_matches {
-            status = ServiceStatus::Critical;
-            result_strings.push("Certificate name does not match".to_string());
-        }
-        if result.intermediate_expired {
-            status = ServiceStatus::Critical;
-            result_strings.push("Intermediate certificate expired".to_string());
+            result_strings.extend(problems);
         }
-        if result.intermediate_untrusted {
+        if let Some(handshake_error) = result.handshake_error.as_ref() {
             status = ServiceStatus::Critical;
-            result_strings.push("Intermediate certificate untrusted".to_string());
+            result_strings.push(format!("TLS handshake failed: {}", handshake_error));
         }
 
-        if result.expiry_seconds() <= expiry_critical_seconds {
-            status = ServiceStatus::Critical;
-            result_strings.push(format!(
-                "Certificate expires in {} days or {} seconds - min set to {}",
-                result.expiry_days(),
-                result.expiry_seconds(),
-                expiry_critical_seconds
+        let mut warnings = Vec::new();
+        if let Some(protocol_version) = result.protocol_version {
+            if !matches!(
+                protocol_version,
+                ProtocolVersion::TLSv1_2 | ProtocolVersion::TLSv1_3
+            ) {
+                warnings.push(format!(
+                    "Negotiated {:?}, which is older than TLS 1.2",
+                    protocol_version
+                ));
+            }
+        }
+        if result.weak_key {
+            warnings.push(format!(
+                "Certificate has a weak {} key",
+                result.key_description()
             ));
-        } else if result.expiry_seconds() <= expiry_warn_seconds {
-            status = ServiceStatus::Warning;
-            result_strings.push(format!(
-                "Certificate expires in {} days or {} seconds - min set to {}",
-                result.expiry_days(),
-                result.expiry_seconds(),
-                expiry_warn_seconds
+        }
+        if !result.weak_signatures.is_empty() {
+            warnings.push(format!(
+                "Certificate chain uses weak signature algorithms: {}",
+                result.weak_signatures.join(", ")
             ));
         }
+        if config.check_ocsp.unwrap_or(false) {
+            match &result.ocsp {
+                OcspStatus::Good { .. } if result.ocsp.is_stale() => {
+                    warnings.push("Stapled OCSP response is out of date".to_string())
+                }
+                OcspStatus::Good { .. } => {}
+                OcspStatus::Revoked => {
+                    status = ServiceStatus::Critical;
+                    result_strings.push("Certificate has been revoked".to_string());
+                }
+                OcspStatus::NotStapled => warnings.push("No OCSP response was stapled".to_string()),
+                OcspStatus::Unknown => {
+                    warnings.push("OCSP responder doesn't know about the certificate".to_string())
+                }
+                OcspStatus::Invalid(err) => warnings.push(err.clone()),
+            }
+        }
 
-        let result_text = result_strings.join(", ");
-        if result_text.is_empty() {
+        if let Some((expiry_status, expiry_text)) =
+            result.expiry_problem(config.expiry_warn, config.expiry_critical)
+        {
+            status = expiry_status;
+            result_strings.push(expiry_text);
+        }
+
+        if !warnings.is_empty() && status == ServiceStatus::Ok {
+            status = ServiceStatus::Warning;
+        }
+        result_strings.extend(warnings);
+        if result_strings.is_empty() {
             result_strings.push("OK".to_string());
         }
+        let result_text = format!("{} ({})", result_strings.join(", "), result.summary());
 
         let timestamp = chrono::Utc::now();
 
@@ -214,12 +345,14 @@ impl ServiceTrait for TlsService {
             time_elapsed: timestamp - start_time,
             status,
             result_text,
-        })
+            ..Default::default()
+        }
+        .with_diagnostics(config.debug_capture, timings.diagnostics()))
     }
 
     fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
         let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
-        Ok(serde_json::to_string_pretty(&config)?)
+        crate::serde::secret::to_string_pretty(&config)
     }
 
     fn jitter_value(&self) -> u32 {
@@ -227,13 +360,63 @@ impl ServiceTrait for TlsService {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// A connector which collects everything about the server's certificates in the returned verifier, for the
+/// webpki roots or the custom ones if they're set
+pub(crate) fn recording_connector(
+    custom_roots: Option<Arc<RootCertStore>>,
+) -> (TlsConnector, Arc<TlsCertVerifier>) {
+    // this comes from the rustls example here: https://github.com/rustls/tokio-rustls/blob/HEAD/examples/client.rs
+    let root_store = match custom_roots.as_ref() {
+        Some(roots) => roots.as_ref().clone(),
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+        },
+    };
+    let mut client_config: ClientConfig = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    //  we use our own verifier because we want all the data
+    let tls_verifier = Arc::new(TlsCertVerifier::new(custom_roots));
+    // nosemgrep: rust.lang.security.rustls-dangerous.rustls-dangerous
+    client_config
+        .dangerous()
+        .set_certificate_verifier(tls_verifier.clone());
+
+    (TlsConnector::from(Arc::new(client_config)), tls_verifier)
+}
+
+/// Returns true if the handshake failed because the server only speaks TLS versions older than 1.2
+fn is_old_protocol_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::AlertReceived(
+            AlertDescription::ProtocolVersion
+        )) | Some(rustls::Error::PeerIncompatible(
+            PeerIncompatible::ServerDoesNotSupportTls12Or13
+        ))
+    )
+}
+
+#[derive(Debug)]
+/// Everything we found out about the server's certificates and the connection
 pub(crate) struct TlsPeerState {
     cert_name_matches: bool,
     end_cert_expiry: DateTime<Utc>,
     intermediate_expired: bool,
     intermediate_untrusted: bool,
-    servername: Option<String>,
+    /// Why the chain couldn't be verified, if it couldn't
+    chain_error: Option<String>,
+    handshake_error: Option<String>,
+    protocol_version: Option<ProtocolVersion>,
+    cipher_suite: Option<String>,
+    key_algorithm: Option<String>,
+    key_bits: Option<usize>,
+    weak_key: bool,
+    signature_algorithm: Option<String>,
+    weak_signatures: Vec<String>,
+    ocsp: OcspStatus,
 }
 
 impl TlsPeerState {
@@ -243,17 +426,110 @@ impl TlsPeerState {
             cert_name_matches: false,
             intermediate_expired: false,
             intermediate_untrusted: false,
-            servername: None,
+            chain_error: None,
+            handshake_error: None,
+            protocol_version: None,
+            cipher_suite: None,
+            key_algorithm: None,
+            key_bits: None,
+            weak_key: false,
+            signature_algorithm: None,
+            weak_signatures: Vec::new(),
+            ocsp: OcspStatus::default(),
         }
     }
+    pub fn set_chain_untrusted(&mut self, reason: String) {
+        self.chain_error = Some(reason);
+    }
     pub fn set_intermediate_expired(&mut self) {
         self.intermediate_expired = true;
     }
-    #[allow(dead_code)] // because the verify function is busted
     pub fn set_intermediate_untrusted(&mut self) {
         self.intermediate_untrusted = true;
     }
 
+    /// eg `RSA 2048 bit`
+    pub fn key_description(&self) -> String {
+        let algorithm = self.key_algorithm.as_deref().unwrap_or("unknown");
+        match self.key_bits {
+            Some(bits) => format!("{} {} bit", algorithm, bits),
+            None => algorithm.to_string(),
+        }
+    }
+
+    /// The protocol, cipher, key and signature details, for the result text
+    pub fn summary(&self) -> String {
+        let mut res = Vec::new();
+        if let Some(protocol_version) = self.protocol_version {
+            res.push(format!("{:?}", protocol_version));
+        }
+        if let Some(cipher_suite) = self.cipher_suite.as_ref() {
+            res.push(cipher_suite.clone());
+        }
+        res.push(format!("{} key", self.key_description()));
+        if let Some(signature_algorithm) = self.signature_algorithm.as_ref() {
+            res.push(signature_algorithm.clone());
+        }
+        res.join(", ")
+    }
+
+    /// Problems with the certificates which make the check critical, `custom_ca` is set when a `ca_file`'s being used
+    pub(crate) fn certificate_problems(&self, custom_ca: bool) -> Vec<String> {
+        let mut res = Vec::new();
+        if self.cert_expired() {
+            res.push(format!(
+                "Certificate expired {} days ago",
+                -self.expiry_days()
+            ));
+        }
+        if !self.cert_name_matches {
+            res.push("Certificate name does not match".to_string());
+        }
+        if self.intermediate_expired {
+            res.push("Intermediate certificate expired".to_string());
+        }
+        if self.intermediate_untrusted {
+            res.push("Intermediate certificate untrusted".to_string());
+        }
+        if let Some(chain_error) = self.chain_error.as_ref() {
+            match custom_ca {
+                true => {
+                    res.push("Certificate chain is not trusted by the configured CA".to_string())
+                }
+                false => res.push(format!("Certificate chain is not trusted: {}", chain_error)),
+            }
+        }
+        res
+    }
+
+    /// The status and text if the certificate expires within the warning or critical days, which default to
+    /// [DEFAULT_WARNING_DAYS] and [DEFAULT_CRITICAL_DAYS]
+    pub(crate) fn expiry_problem(
+        &self,
+        expiry_warn: Option<u16>,
+        expiry_critical: Option<u16>,
+    ) -> Option<(ServiceStatus, String)> {
+        let expiry_critical_seconds =
+            expiry_critical.unwrap_or(DEFAULT_CRITICAL_DAYS) as i64 * 86400;
+        let expiry_warn_seconds = expiry_warn.unwrap_or(DEFAULT_WARNING_DAYS) as i64 * 86400;
+        let (status, min_seconds) = if self.expiry_seconds() <= expiry_critical_seconds {
+            (ServiceStatus::Critical, expiry_critical_seconds)
+        } else if self.expiry_seconds() <= expiry_warn_seconds {
+            (ServiceStatus::Warning, expiry_warn_seconds)
+        } else {
+            return None;
+        };
+        Some((
+            status,
+            format!(
+                "Certificate expires in {} days or {} seconds - min set to {}",
+                self.expiry_days(),
+                self.expiry_seconds(),
+                min_seconds
+            ),
+        ))
+    }
+
     /// Return if the cert has expired
     pub fn cert_expired(&self) -> bool {
         (self.end_cert_expiry - chrono::Utc::now()).num_seconds() <= 0
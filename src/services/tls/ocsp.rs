@@ -0,0 +1,286 @@
+//! Just enough DER parsing to read the certificate status out of a stapled OCSP response
+//!
+//! The response's signature isn't verified, so this is for reporting what the server says, not for trusting it.
+
+use chrono::NaiveDateTime;
+
+use crate::prelude::*;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_CONTEXT_0: u8 = 0xa0;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What the stapled OCSP response says about the end certificate
+pub(crate) enum OcspStatus {
+    /// The server didn't staple a response
+    #[default]
+    NotStapled,
+    /// The responder says the certificate's good, and when the response stops being valid
+    Good { next_update: Option<DateTime<Utc>> },
+    /// The certificate's been revoked
+    Revoked,
+    /// The responder doesn't know about the certificate
+    Unknown,
+    /// The response couldn't be parsed, or didn't cover the certificate
+    Invalid(String),
+}
+
+impl OcspStatus {
+    /// A good response which is past its next update time
+    pub(crate) fn is_stale(&self) -> bool {
+        match self {
+            Self::Good {
+                next_update: Some(next_update),
+            } => *next_update < chrono::Utc::now(),
+            _ => false,
+        }
+    }
+}
+
+/// Reads a single DER TLV, returning the tag, its contents and whatever's left after it
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let bytes = (first & 0x7f) as usize;
+        if bytes == 0 || bytes > std::mem::size_of::<usize>() || rest.len() < bytes {
+            return None;
+        }
+        let (length_bytes, rest) = rest.split_at(bytes);
+        let length = length_bytes
+            .iter()
+            .fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+        (length, rest)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(length);
+    Some((tag, contents, rest))
+}
+
+/// Reads a TLV and makes sure it's the tag we expected
+fn expect_tlv(input: &[u8], expected: u8) -> Option<(&[u8], &[u8])> {
+    match read_tlv(input)? {
+        (tag, contents, rest) if tag == expected => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+fn parse_generalized_time(value: &[u8]) -> Option<DateTime<Utc>> {
+    let value = std::str::from_utf8(value).ok()?;
+    NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S%.fZ")
+        .ok()
+        .map(|value| value.and_utc())
+}
+
+/// Skips leading zero bytes so serial numbers compare the same however they were encoded
+fn trim_serial(serial: &[u8]) -> &[u8] {
+    let start = serial
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(serial.len());
+    &serial[start..]
+}
+
+/// Finds the status for the certificate with `serial` in a stapled OCSP response
+pub(crate) fn parse_stapled_response(response: &[u8], serial: &[u8]) -> OcspStatus {
+    if response.is_empty() {
+        return OcspStatus::NotStapled;
+    }
+    parse_response(response, serial).unwrap_or_else(|| {
+        OcspStatus::Invalid("Failed to parse the stapled OCSP response".to_string())
+    })
+}
+
+fn parse_response(response: &[u8], serial: &[u8]) -> Option<OcspStatus> {
+    // OCSPResponse ::= SEQUENCE { responseStatus ENUMERATED, responseBytes [0] EXPLICIT ResponseBytes OPTIONAL }
+    let (ocsp_response, _) = expect_tlv(response, TAG_SEQUENCE)?;
+    let (response_status, rest) = expect_tlv(ocsp_response, TAG_ENUMERATED)?;
+    if response_status != [0] {
+        return Some(OcspStatus::Invalid(format!(
+            "OCSP responder returned status {:?}",
+            response_status
+        )));
+    }
+    // ResponseBytes ::= SEQUENCE { responseType OBJECT IDENTIFIER, response OCTET STRING }
+    let (response_bytes, _) = expect_tlv(rest, TAG_CONTEXT_0)?;
+    let (response_bytes, _) = expect_tlv(response_bytes, TAG_SEQUENCE)?;
+    let (_response_type, rest) = read_tlv(response_bytes)?;
+    let (basic_response, _) = expect_tlv(rest, TAG_OCTET_STRING)?;
+
+    // BasicOCSPResponse ::= SEQUENCE { tbsResponseData ResponseData, signatureAlgorithm, signature, certs }
+    let (basic_response, _) = expect_tlv(basic_response, TAG_SEQUENCE)?;
+    let (response_data, _) = expect_tlv(basic_response, TAG_SEQUENCE)?;
+
+    // ResponseData ::= SEQUENCE { version [0] OPTIONAL, responderID, producedAt, responses SEQUENCE OF SingleResponse, ... }
+    let (tag, _, mut rest) = read_tlv(response_data)?;
+    if tag == TAG_CONTEXT_0 {
+        // that was the version, the responder ID's next
+        (_, _, rest) = read_tlv(rest)?;
+    }
+    let (_produced_at, rest) = expect_tlv(rest, TAG_GENERALIZED_TIME)?;
+    let (mut responses, _) = expect_tlv(rest, TAG_SEQUENCE)?;
+
+    while !responses.is_empty() {
+        let (single_response, remaining) = expect_tlv(responses, TAG_SEQUENCE)?;
+        responses = remaining;
+
+        // CertID ::= SEQUENCE { hashAlgorithm, issuerNameHash, issuerKeyHash, serialNumber }
+        let (cert_id, rest) = expect_tlv(single_response, TAG_SEQUENCE)?;
+        let (_, _, cert_id) = read_tlv(cert_id)?;
+        let (_, _, cert_id) = read_tlv(cert_id)?;
+        let (_, _, cert_id) = read_tlv(cert_id)?;
+        let (response_serial, _) = expect_tlv(cert_id, TAG_INTEGER)?;
+        if trim_serial(response_serial) != trim_serial(serial) {
+            continue;
+        }
+
+        // certStatus ::= CHOICE { good [0] IMPLICIT NULL, revoked [1] IMPLICIT RevokedInfo, unknown [2] IMPLICIT UnknownInfo }
+        let (cert_status, _, rest) = read_tlv(rest)?;
+        return Some(match cert_status {
+            0x80 => {
+                let (_this_update, rest) = expect_tlv(rest, TAG_GENERALIZED_TIME)?;
+                let next_update = expect_tlv(rest, TAG_CONTEXT_0)
+                    .and_then(|(next_update, _)| expect_tlv(next_update, TAG_GENERALIZED_TIME))
+                    .and_then(|(next_update, _)| parse_generalized_time(next_update));
+                OcspStatus::Good { next_update }
+            }
+            0xa1 => OcspStatus::Revoked,
+            0x82 => OcspStatus::Unknown,
+            other => {
+                OcspStatus::Invalid(format!("Unexpected OCSP certificate status {:#x}", other))
+            }
+        });
+    }
+    Some(OcspStatus::Invalid(
+        "Stapled OCSP response doesn't cover the certificate".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps contents in a DER TLV
+    fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut res = vec![tag];
+        if contents.len() < 0x80 {
+            res.push(contents.len() as u8);
+        } else {
+            res.push(0x82);
+            res.extend((contents.len() as u16).to_be_bytes());
+        }
+        res.extend(contents);
+        res
+    }
+
+    fn test_response(serial: &[u8], cert_status: Vec<u8>, next_update: &str) -> Vec<u8> {
+        let cert_id = [
+            tlv(TAG_SEQUENCE, &tlv(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a])),
+            tlv(TAG_OCTET_STRING, &[1; 20]),
+            tlv(TAG_OCTET_STRING, &[2; 20]),
+            tlv(TAG_INTEGER, serial),
+        ]
+        .concat();
+        let single_response = [
+            tlv(TAG_SEQUENCE, &cert_id),
+            cert_status,
+            tlv(TAG_GENERALIZED_TIME, b"20250101000000Z"),
+            tlv(
+                TAG_CONTEXT_0,
+                &tlv(TAG_GENERALIZED_TIME, next_update.as_bytes()),
+            ),
+        ]
+        .concat();
+        let response_data = [
+            tlv(0xa2, &tlv(TAG_OCTET_STRING, &[3; 20])),
+            tlv(TAG_GENERALIZED_TIME, b"20250101000000Z"),
+            tlv(TAG_SEQUENCE, &tlv(TAG_SEQUENCE, &single_response)),
+        ]
+        .concat();
+        let basic_response = tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_SEQUENCE, &response_data),
+                tlv(TAG_SEQUENCE, &tlv(0x06, &[0x2a])),
+                tlv(0x03, &[0x00, 0x01]),
+            ]
+            .concat(),
+        );
+        let response_bytes = tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(
+                    0x06,
+                    &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01],
+                ),
+                tlv(TAG_OCTET_STRING, &basic_response),
+            ]
+            .concat(),
+        );
+        tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_ENUMERATED, &[0]),
+                tlv(TAG_CONTEXT_0, &response_bytes),
+            ]
+            .concat(),
+        )
+    }
+
+    #[test]
+    fn test_parse_stapled_response() {
+        assert_eq!(parse_stapled_response(&[], &[1]), OcspStatus::NotStapled);
+
+        let good = test_response(&[0x00, 0x99], tlv(0x80, &[]), "29990101000000Z");
+        let status = parse_stapled_response(&good, &[0x99]);
+        assert!(matches!(
+            status,
+            OcspStatus::Good {
+                next_update: Some(_)
+            }
+        ));
+        assert!(!status.is_stale());
+
+        let stale = test_response(&[0x99], tlv(0x80, &[]), "20000101000000.5Z");
+        assert!(parse_stapled_response(&stale, &[0x99]).is_stale());
+
+        let revoked = test_response(
+            &[0x99],
+            tlv(0xa1, &tlv(TAG_GENERALIZED_TIME, b"20250101000000Z")),
+            "29990101000000Z",
+        );
+        assert_eq!(
+            parse_stapled_response(&revoked, &[0x99]),
+            OcspStatus::Revoked
+        );
+
+        let unknown = test_response(&[0x99], tlv(0x82, &[]), "29990101000000Z");
+        assert_eq!(
+            parse_stapled_response(&unknown, &[0x99]),
+            OcspStatus::Unknown
+        );
+
+        // a response for some other certificate
+        assert!(matches!(
+            parse_stapled_response(&good, &[0x42]),
+            OcspStatus::Invalid(_)
+        ));
+        assert!(matches!(
+            parse_stapled_response(&[0x30, 0x05, 0x01], &[0x99]),
+            OcspStatus::Invalid(_)
+        ));
+        // the responder said no
+        assert!(matches!(
+            parse_stapled_response(&tlv(TAG_SEQUENCE, &tlv(TAG_ENUMERATED, &[6])), &[0x99]),
+            OcspStatus::Invalid(_)
+        ));
+    }
+}
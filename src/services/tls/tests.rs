@@ -36,6 +36,12 @@ async fn test_working_tls_service() {
         expiry_warn: Some(3),
         timeout: None,
         jitter: None,
+        ca_file: Some(certs.ca_file.path().to_path_buf()),
+        root_store: Default::default(),
+        sni_hostname: None,
+        check_ocsp: None,
+        debug_capture: false,
+        traceroute_on_failure: false,
     };
     let host: entities::host::Model = entities::host::Model {
         check: crate::host::HostCheck::None,
@@ -53,7 +59,32 @@ async fn test_working_tls_service() {
     let result = service.run(&host).await;
     dbg!(&result);
     assert!(result.is_ok());
-    assert!(result.unwrap().status == ServiceStatus::Ok);
+    let result = result.unwrap();
+    assert!(result.status == ServiceStatus::Ok);
+    assert!(result.result_text.contains("TLSv1_"));
+    assert!(result.result_text.contains("EC 256 bit key"));
+
+    // the test CA isn't in the webpki roots
+    let service = crate::services::tls::TlsService {
+        ca_file: None,
+        ..service
+    };
+    let result = service.run(&host).await.expect("Failed to run check");
+    assert_eq!(result.status, ServiceStatus::Critical);
+    assert!(result.result_text.contains("not trusted"));
+
+    // nothing's stapled by the test container
+    let service = crate::services::tls::TlsService {
+        ca_file: Some(certs.ca_file.path().to_path_buf()),
+        check_ocsp: Some(true),
+        debug_capture: true,
+        ..service
+    };
+    let result = service.run(&host).await.expect("Failed to run check");
+    assert_eq!(result.status, ServiceStatus::Warning);
+    assert!(result.result_text.contains("No OCSP response"));
+    assert!(result.diagnostics.contains_key("connect_ms"));
+    assert!(result.diagnostics.contains_key("tls_handshake_ms"));
 }
 
 #[tokio::test]
@@ -81,9 +112,16 @@ async fn test_expired_tls_service() {
         expiry_warn: Some(60),
         timeout: None,
         jitter: None,
+        ca_file: None,
+        root_store: Default::default(),
+        sni_hostname: None,
+        check_ocsp: None,
+        debug_capture: false,
+        traceroute_on_failure: false,
     };
     let host = entities::host::Model {
         name: "localhost".to_string(),
+        slug: "localhost".to_string(),
         check: crate::host::HostCheck::None,
         hostname: "localhost".to_string(),
         ..test_host()
@@ -117,10 +155,17 @@ async fn test_wrong_cert_host_name() {
     let service: TlsService = serde_json::from_value(service_def).expect("Failed to parse service");
     let host = entities::host::Model {
         name: "localhost".to_string(),
+        slug: "localhost".to_string(),
         check: crate::host::HostCheck::None,
         id: Uuid::new_v4(),
         hostname: "localhost".to_string(),
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     let result = service.run(&host).await;
     dbg!(&result);
@@ -128,6 +173,94 @@ async fn test_wrong_cert_host_name() {
     assert!(result.unwrap().status == ServiceStatus::Critical);
 }
 
+#[tokio::test]
+async fn test_sni_hostname_and_ca_file() {
+    use crate::prelude::*;
+    use crate::tests::tls_utils::TestCertificateBuilder;
+
+    let _ = test_setup().await.expect("Failed to set up test");
+
+    let certs = TestCertificateBuilder::new()
+        .with_name("maremma.example.com")
+        .with_expiry((chrono::Utc::now() + chrono::TimeDelta::days(30)).timestamp())
+        .with_issue_time((chrono::Utc::now() - chrono::TimeDelta::days(30)).timestamp())
+        .build();
+    let other_certs = TestCertificateBuilder::new().with_name("localhost").build();
+
+    let test_container = TestContainer::new(&certs, "test_sni_hostname_and_ca_file").await;
+
+    // connect to localhost, but ask for the name on the certificate and trust its CA
+    let service: TlsService = serde_json::from_value(serde_json::json! {{
+        "name": "test",
+        "cron_schedule": "0 0 * * *",
+        "port": test_container.tls_port,
+        "sni_hostname": "maremma.example.com",
+        "ca_file": certs.ca_file.path(),
+    }})
+    .expect("Failed to parse service");
+    let host = entities::host::Model {
+        check: crate::host::HostCheck::None,
+        hostname: "localhost".to_string(),
+        ..test_host()
+    };
+    let result = service.run(&host).await.expect("Failed to run check");
+    dbg!(&result);
+    assert_eq!(result.status, ServiceStatus::Ok);
+
+    // a CA that didn't sign it
+    let service = TlsService {
+        ca_file: Some(other_certs.ca_file.path().to_path_buf()),
+        ..service
+    };
+    let result = service.run(&host).await.expect("Failed to run check");
+    dbg!(&result);
+    assert_eq!(result.status, ServiceStatus::Critical);
+    assert!(result.result_text.contains("not trusted"));
+
+    // without the SNI override, the name won't match
+    let service = TlsService {
+        ca_file: Some(certs.ca_file.path().to_path_buf()),
+        sni_hostname: None,
+        ..service
+    };
+    let result = service.run(&host).await.expect("Failed to run check");
+    assert_eq!(result.status, ServiceStatus::Critical);
+}
+
+#[test]
+fn test_custom_root_store() {
+    let certs = TestCertificateBuilder::new().with_name("localhost").build();
+    let mut service: TlsService = serde_json::from_value(serde_json::json! {{
+        "name": "test",
+        "cron_schedule": "0 0 * * *",
+        "port": 443,
+    }})
+    .expect("Failed to parse service");
+    assert!(service
+        .custom_root_store()
+        .expect("Failed to build root store")
+        .is_none());
+
+    service.ca_file = Some(certs.ca_file.path().to_path_buf());
+    assert!(!service
+        .custom_root_store()
+        .expect("Failed to build root store")
+        .expect("Should have a root store")
+        .is_empty());
+
+    service.ca_file = Some("/this/does/not/exist.pem".into());
+    assert!(service.custom_root_store().is_err());
+
+    service.ca_file = None;
+    service.root_store =
+        crate::services::root_store::RootStore::File(certs.ca_file.path().to_path_buf());
+    assert!(!service
+        .custom_root_store()
+        .expect("Failed to build root store")
+        .expect("Should have a root store")
+        .is_empty());
+}
+
 #[tokio::test]
 async fn test_nxdomain() {
     use crate::prelude::*;
@@ -144,10 +277,17 @@ async fn test_nxdomain() {
     let bad_hostname = "11.22.33.44.55.66.77.example.com".to_string();
     let host = entities::host::Model {
         name: bad_hostname.clone(),
+        slug: bad_hostname.clone(),
         check: crate::host::HostCheck::None,
         id: Uuid::new_v4(),
         hostname: bad_hostname,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     let result = service.run(&host).await;
     dbg!(&result);
@@ -171,10 +311,17 @@ async fn test_invalid_hostname() {
     let bad_hostname = "".to_string();
     let host = entities::host::Model {
         name: bad_hostname.clone(),
+        slug: bad_hostname.clone(),
         check: crate::host::HostCheck::None,
         id: Uuid::new_v4(),
         hostname: bad_hostname,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     let result = service.run(&host).await;
     dbg!(&result);
@@ -201,16 +348,24 @@ async fn test_tls_sha1_intermediate() {
         "name": "test",
         "cron_schedule": "0 0 * * *",
         "port": test_container.tls_port,
+        "ca_file": certs.ca_file.path(),
     }};
 
     let service: TlsService = serde_json::from_value(service_def).expect("Failed to parse service");
     let bad_hostname = "localhost".to_string();
     let host = entities::host::Model {
         name: bad_hostname.clone(),
+        slug: bad_hostname.clone(),
         check: crate::host::HostCheck::None,
         id: Uuid::new_v4(),
         hostname: bad_hostname,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     let result = service.run(&host).await;
     dbg!(&result);
@@ -244,10 +399,17 @@ async fn test_tls_no_subject() {
     let bad_hostname = "localhost".to_string();
     let host = entities::host::Model {
         name: bad_hostname.clone(),
+        slug: bad_hostname.clone(),
         check: crate::host::HostCheck::None,
         id: Uuid::new_v4(),
         hostname: bad_hostname,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     let result = service.run(&host).await;
     dbg!(&result);
@@ -288,10 +450,17 @@ async fn test_timeout() {
     let bad_hostname = "example.com".to_string();
     let host = entities::host::Model {
         name: bad_hostname.clone(),
+        slug: bad_hostname.clone(),
         check: crate::host::HostCheck::None,
         id: Uuid::new_v4(),
         hostname: bad_hostname,
         config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
     };
     let result = service.run(&host).await;
     dbg!(&result);
@@ -309,8 +478,16 @@ async fn test_service_parser() {
         name: Some("Hello world".to_string()),
         description: None,
         host_groups: vec![],
+        labels: Default::default(),
         service_type: super::ServiceType::Tls,
         cron_schedule: "* * * * *".parse().expect("Failed to parse cron"),
+        agent: None,
+        expose_alert_rule: false,
+        alert_rule_for: None,
+        notifications: None,
+        max_runtime: None,
+        timezone: None,
+        template: None,
         extra_config,
         config: Some(Box::new(TlsService {
             name: "tls_service".to_string(),
@@ -320,12 +497,18 @@ async fn test_service_parser() {
             expiry_warn: Some(7),
             timeout: Some(5),
             jitter: None,
+            ca_file: None,
+            root_store: Default::default(),
+            sni_hostname: None,
+            check_ocsp: None,
+            debug_capture: false,
+            traceroute_on_failure: false,
         })),
     };
     let _ = service.parse_config().expect("Failed to parse config!");
 
     let host = entities::host::Entity::find()
-        .one(&*db.write().await)
+        .one(&db)
         .await
         .expect("Failed to search for host")
         .expect("Failed to find host");
@@ -344,8 +527,16 @@ fn test_failed_service_parser() {
         name: Some("Hello world".to_string()),
         description: None,
         host_groups: vec![],
+        labels: Default::default(),
         service_type: super::ServiceType::Tls,
         cron_schedule: "* * * * *".parse().expect("Failed to parse cron"),
+        agent: None,
+        expose_alert_rule: false,
+        alert_rule_for: None,
+        notifications: None,
+        max_runtime: None,
+        timezone: None,
+        template: None,
         extra_config: std::collections::HashMap::new(),
         config: Some(Box::new(TlsService {
             name: "tls_service".to_string(),
@@ -355,7 +546,49 @@ fn test_failed_service_parser() {
             expiry_warn: Some(7),
             timeout: Some(5),
             jitter: None,
+            ca_file: None,
+            root_store: Default::default(),
+            sni_hostname: None,
+            check_ocsp: None,
+            debug_capture: false,
+            traceroute_on_failure: false,
         })),
     };
     assert!(service.parse_config().is_err());
 }
+
+#[test]
+fn test_key_details() {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+
+    let certs = TestCertificateBuilder::new().with_name("localhost").build();
+    let cert = CertificateDer::pem_file_iter(certs.cert_file.path())
+        .expect("Failed to read cert file")
+        .next()
+        .expect("No certificate in file")
+        .expect("Failed to parse cert");
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).expect("Failed to parse");
+
+    let (algorithm, bits, weak) = super::verifier::key_details(&cert);
+    assert_eq!(algorithm, "EC");
+    assert_eq!(bits, Some(256));
+    assert!(!weak);
+    assert_eq!(super::verifier::weak_signature(&cert), None);
+}
+
+#[test]
+fn test_tls_peer_state_summary() {
+    let mut state = super::TlsPeerState::new(chrono::Utc::now());
+    assert_eq!(state.summary(), "unknown key");
+
+    state.protocol_version = Some(rustls::ProtocolVersion::TLSv1_3);
+    state.cipher_suite = Some("TLS13_AES_256_GCM_SHA384".to_string());
+    state.key_algorithm = Some("RSA".to_string());
+    state.key_bits = Some(2048);
+    state.signature_algorithm = Some("sha256WithRSAEncryption".to_string());
+    assert_eq!(
+        state.summary(),
+        "TLSv1_3, TLS13_AES_256_GCM_SHA384, RSA 2048 bit key, sha256WithRSAEncryption"
+    );
+}
@@ -1,29 +1,99 @@
+use std::sync::Mutex;
+
+use super::ocsp::parse_stapled_response;
 use super::TlsPeerState;
 use crate::prelude::*;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified};
 use rustls::client::verify_server_name;
+use rustls::crypto::WebPkiSupportedAlgorithms;
 use rustls::pki_types::{CertificateDer, ServerName};
 use rustls::server::ParsedCertificate;
-use rustls::SignatureScheme;
+use rustls::{CertificateError, RootCertStore, SignatureScheme};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::objects::{oid2sn, oid_registry};
+use x509_parser::oid_registry::Oid;
 use x509_parser::parse_x509_certificate;
+use x509_parser::public_key::PublicKey;
+
+/// RSA keys smaller than this are reported as weak
+pub const MIN_RSA_KEY_BITS: usize = 2048;
+/// EC keys smaller than this are reported as weak
+pub const MIN_EC_KEY_BITS: usize = 256;
+
+/// Collects everything about the server's certificates, and lets the handshake carry on so we can see what was negotiated
+#[derive(Debug)]
+pub(crate) struct TlsCertVerifier {
+    /// If this is set, the chain has to lead back to one of these, otherwise it's the webpki roots
+    roots: Option<Arc<RootCertStore>>,
+    algorithms: WebPkiSupportedAlgorithms,
+    state: Mutex<Option<TlsPeerState>>,
+}
+
+impl TlsCertVerifier {
+    pub(crate) fn new(roots: Option<Arc<RootCertStore>>) -> Self {
+        Self {
+            roots,
+            algorithms: rustls::crypto::aws_lc_rs::default_provider()
+                .signature_verification_algorithms,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Returns what was found in the certificates, or `None` if the server never sent any
+    pub(crate) fn take_state(&self) -> Option<TlsPeerState> {
+        self.state.lock().ok().and_then(|mut state| state.take())
+    }
+}
+
+/// Turns an OID into its short name if we know it, eg `sha256WithRSAEncryption`
+fn oid_name(oid: &Oid) -> String {
+    oid2sn(oid, oid_registry())
+        .map(|name| name.to_string())
+        .unwrap_or_else(|_| oid.to_id_string())
+}
+
+/// Returns the key algorithm, its size in bits if we can work it out, and whether it's too small
+pub(crate) fn key_details(cert: &X509Certificate<'_>) -> (String, Option<usize>, bool) {
+    let public_key = cert.public_key();
+    match public_key.parsed() {
+        Ok(PublicKey::RSA(key)) => (
+            "RSA".to_string(),
+            Some(key.key_size()),
+            key.key_size() < MIN_RSA_KEY_BITS,
+        ),
+        Ok(PublicKey::EC(key)) => (
+            "EC".to_string(),
+            Some(key.key_size()),
+            key.key_size() < MIN_EC_KEY_BITS,
+        ),
+        // DSA hasn't been allowed in TLS certificates for a long time
+        Ok(PublicKey::DSA(_)) => ("DSA".to_string(), None, true),
+        _ => (oid_name(&public_key.algorithm.algorithm), None, false),
+    }
+}
 
-#[derive(Debug, Default)]
-pub(crate) struct TlsCertVerifier;
+/// SHA1 and MD5 signatures can be forged, so they shouldn't be in a chain
+pub(crate) fn weak_signature(cert: &X509Certificate<'_>) -> Option<String> {
+    let name = oid_name(&cert.signature_algorithm.algorithm);
+    let lower = name.to_lowercase();
+    (lower.contains("sha1") || lower.contains("md5")).then_some(name)
+}
 
 impl rustls::client::danger::ServerCertVerifier for TlsCertVerifier {
     #[instrument(level = "debug", skip_all, fields(server_name=server_name.to_str().to_string()))]
-    /// This is ALWAYS going to throw a [rustls::Error] error, because we don't have a way to pass state back out
+    /// Records what it finds and always accepts the certificate, the check works out the status afterwards
     fn verify_server_cert(
         &self,
         end_entity: &CertificateDer<'_>,
         intermediates: &[CertificateDer<'_>],
         server_name: &ServerName<'_>,
-        _ocsp_response: &[u8],
+        ocsp_response: &[u8],
         now: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+    ) -> Result<ServerCertVerified, rustls::Error> {
         // parse the end cert
         let (_, cert) = parse_x509_certificate(end_entity.as_ref()).map_err(|err| {
             error!("Failed to parse TLS certificate {:?}", err);
-            rustls::Error::General("{}".to_string())
+            rustls::Error::InvalidCertificate(CertificateError::BadEncoding)
         })?;
 
         // let this just fail out if it fails because well, too bad
@@ -39,100 +109,89 @@ impl rustls::client::danger::ServerCertVerifier for TlsCertVerifier {
 
         tls_peer_state.cert_name_matches = verify_server_name(&parsed_cert, server_name).is_ok();
 
+        let (key_algorithm, key_bits, weak_key) = key_details(&cert);
+        tls_peer_state.key_algorithm = Some(key_algorithm);
+        tls_peer_state.key_bits = key_bits;
+        tls_peer_state.weak_key = weak_key;
+        tls_peer_state.signature_algorithm = Some(oid_name(&cert.signature_algorithm.algorithm));
+        tls_peer_state.weak_signatures.extend(weak_signature(&cert));
+
         for (index, intermediate) in intermediates.iter().enumerate() {
-            // TODO: for some reason this won't work with letsencrypt certs and I can't work out why :'(
             debug!("Checking intermediate at index {} at {:?}", index, now);
-            // if let Ok(parsed_intermediate) = ParsedCertificate::try_from(intermediate) {
-            //     let remaining_intermediates: Vec<_> =
-            //         intermediates.iter().skip(index).cloned().collect();
-            //     debug!("Remaining intermediates {:?}", remaining_intermediates);
-            //     if rustls::client::verify_server_cert_signed_by_trust_anchor(
-            //         &parsed_intermediate,
-            //         &rustls::RootCertStore {
-            //             roots: webpki_roots::TLS_SERVER_ROOTS.into(),
-            //         },
-            //         &remaining_intermediates,
-            //         now,
-            //         webpki::ALL_VERIFICATION_ALGS,
-            //     )
-            //     .is_err()
-            //     {
-            //         tls_peer_state.set_intermediate_untrusted();
-            //         debug!("Intermediate at index {} is untrusted", index);
-            //     } else {
-            //         debug!("Intermediate at index {} is trusted", index);
-            //     }
-            // }
-
             if let Ok((_, cert)) = parse_x509_certificate(intermediate.as_ref()) {
                 if !cert.validity.is_valid() {
                     tls_peer_state.set_intermediate_expired();
                 }
+                tls_peer_state.weak_signatures.extend(weak_signature(&cert));
             }
         }
 
-        Err(rustls::Error::General(
-            #[cfg(not(tarpaulin_include))]
-            // We're unlikely to hit this, so testing it's not really helpful.
-            serde_json::to_string(&tls_peer_state).map_err(|err| {
-                error!("Failed to serialize TLS state {:?}", err);
-                rustls::Error::General("{}".to_string())
-            })?,
-        ))
+        // walk the whole chain, from the end cert through the intermediates the server sent to a trusted root
+        let default_roots;
+        let roots = match self.roots.as_ref() {
+            Some(roots) => roots.as_ref(),
+            None => {
+                default_roots = RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+                };
+                &default_roots
+            }
+        };
+        match rustls::client::verify_server_cert_signed_by_trust_anchor(
+            &parsed_cert,
+            roots,
+            intermediates,
+            now,
+            self.algorithms.all,
+        ) {
+            Ok(()) => {}
+            // expiry is reported separately, with more detail
+            Err(_) if tls_peer_state.cert_expired() || tls_peer_state.intermediate_expired => {}
+            // we can't verify SHA1/MD5 signatures, they're reported as weak instead
+            Err(rustls::Error::InvalidCertificate(CertificateError::BadSignature))
+                if !tls_peer_state.weak_signatures.is_empty() => {}
+            Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer))
+                if self.roots.is_none() && !intermediates.is_empty() =>
+            {
+                debug!("Intermediates don't lead back to a trusted root");
+                tls_peer_state.set_intermediate_untrusted();
+            }
+            Err(err) => {
+                debug!("Chain doesn't lead back to a trusted root: {:?}", err);
+                tls_peer_state.set_chain_untrusted(err.to_string());
+            }
+        }
+
+        tls_peer_state.ocsp = parse_stapled_response(ocsp_response, cert.raw_serial());
+
+        if let Ok(mut state) = self.state.lock() {
+            *state = Some(tls_peer_state);
+        }
+        Ok(ServerCertVerified::assertion())
     }
 
-    #[instrument(level = "debug")]
-    /// This is ALWAYS going to throw a [rustls::Error::General] error if we ever get to it, because we don't have a way to pass state back out
+    #[instrument(level = "debug", skip_all)]
     fn verify_tls12_signature(
         &self,
-        _message: &[u8],
-        _cert: &rustls::pki_types::CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Err(rustls::Error::General(
-            "verify_tls12_signature is unimplemented, but you shouldn't get here anyway!"
-                .to_string(),
-        ))
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
     }
 
-    #[instrument(level = "debug")]
-    /// This is ALWAYS going to throw a [rustls::Error] error, because we don't have a way to pass state back out
+    #[instrument(level = "debug", skip_all)]
     fn verify_tls13_signature(
         &self,
-        _message: &[u8],
-        _cert: &rustls::pki_types::CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Err(rustls::Error::General(
-            "verify_tls13_signature is unimplemented, but you shouldn't get here anyway!"
-                .to_string(),
-        ))
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
     }
 
-    #[instrument(level = "debug")]
-    /// We really do support everything, we're nice like that.
+    /// Everything we can actually verify a handshake signature with
     fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        self.all_signature_schemes()
-    }
-}
-
-impl TlsCertVerifier {
-    /// Returns all the possible schemes
-    fn all_signature_schemes(&self) -> Vec<SignatureScheme> {
-        vec![
-            SignatureScheme::RSA_PKCS1_SHA1,
-            SignatureScheme::ECDSA_SHA1_Legacy,
-            SignatureScheme::RSA_PKCS1_SHA256,
-            SignatureScheme::ECDSA_NISTP256_SHA256,
-            SignatureScheme::RSA_PKCS1_SHA384,
-            SignatureScheme::ECDSA_NISTP384_SHA384,
-            SignatureScheme::RSA_PKCS1_SHA512,
-            SignatureScheme::ECDSA_NISTP521_SHA512,
-            SignatureScheme::RSA_PSS_SHA256,
-            SignatureScheme::RSA_PSS_SHA384,
-            SignatureScheme::RSA_PSS_SHA512,
-            SignatureScheme::ED25519,
-            SignatureScheme::ED448,
-        ]
+        self.algorithms.supported_schemes()
     }
 }
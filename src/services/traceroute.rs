@@ -0,0 +1,214 @@
+//! Runs a traceroute when a connectivity check fails, for services with `traceroute_on_failure` set, so the result
+//! shows where along the path things stopped answering
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::prelude::*;
+
+/// The furthest the traceroute goes
+pub(crate) const MAX_HOPS: u8 = 20;
+
+/// Each hop gets one probe with this long to answer, so the whole thing's bounded by `MAX_HOPS` of these
+const HOP_WAIT_SECONDS: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// One line of the traceroute
+pub(crate) struct Hop {
+    pub ttl: u8,
+    /// `None` if nothing answered
+    pub address: Option<String>,
+    pub rtt_ms: Option<String>,
+}
+
+/// Parses `traceroute -n -q 1` output, skipping the header and anything it doesn't understand
+pub(crate) fn parse(output: &str) -> Vec<Hop> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let ttl = words.next()?.parse().ok()?;
+            let address = words.next()?;
+            Some(match address {
+                "*" => Hop {
+                    ttl,
+                    address: None,
+                    rtt_ms: None,
+                },
+                address => Hop {
+                    ttl,
+                    address: Some(address.to_string()),
+                    rtt_ms: words.next().map(str::to_string),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Where the path stops answering, for the result text
+pub(crate) fn summary(hops: &[Hop]) -> String {
+    match hops.iter().rev().find(|hop| hop.address.is_some()) {
+        Some(Hop {
+            ttl,
+            address: Some(address),
+            ..
+        }) => match hops.last() {
+            Some(last) if last.ttl == *ttl => {
+                format!("traceroute reached {} in {} hops", address, ttl)
+            }
+            _ => format!(
+                "traceroute's last answer was from {} at hop {}",
+                address, ttl
+            ),
+        },
+        _ => "nothing answered the traceroute".to_string(),
+    }
+}
+
+/// The hops one per line, with the run of unanswered ones at the end squashed into one line
+pub(crate) fn hop_list(hops: &[Hop]) -> String {
+    let answered = hops
+        .iter()
+        .rposition(|hop| hop.address.is_some())
+        .map(|index| index + 1)
+        .unwrap_or_default();
+    let mut lines = hops[..answered]
+        .iter()
+        .map(|hop| match (&hop.address, &hop.rtt_ms) {
+            (Some(address), Some(rtt_ms)) => format!("{} {} {}ms", hop.ttl, address, rtt_ms),
+            (Some(address), None) => format!("{} {}", hop.ttl, address),
+            (None, _) => format!("{} *", hop.ttl),
+        })
+        .collect::<Vec<_>>();
+    match &hops[answered..] {
+        [] => {}
+        [hop] => lines.push(format!("{} *", hop.ttl)),
+        [first, .., last] => lines.push(format!("{}-{} *", first.ttl, last.ttl)),
+    }
+    lines.join("\n")
+}
+
+/// Runs the system's `traceroute` to the target, giving up once every hop's had its chance to answer
+pub(crate) async fn trace(target: &str) -> Result<Vec<Hop>, Error> {
+    let mut command = tokio::process::Command::new("traceroute");
+    command
+        .args(["-n", "-q", "1", "-w"])
+        .arg(HOP_WAIT_SECONDS.to_string())
+        .arg("-m")
+        .arg(MAX_HOPS.to_string())
+        .arg(target)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null());
+    let timeout = Duration::from_secs(MAX_HOPS as u64 * HOP_WAIT_SECONDS + 5);
+    let output = tokio::time::timeout(timeout, command.output())
+        .await
+        .map_err(|_| Error::Timeout)?
+        .map_err(|err| Error::Generic(format!("Failed to run traceroute: {}", err)))?;
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Adds a traceroute to a failed result, the summary goes on the result text and the hops in its diagnostics
+pub(crate) async fn enrich(result: CheckResult, target: &str, key: String) -> CheckResult {
+    let traced = trace(target).await;
+    add_trace(result, target, key, traced)
+}
+
+/// Adds a traceroute that's already been run to a result, see [enrich]
+pub(crate) fn add_trace(
+    result: CheckResult,
+    target: &str,
+    key: String,
+    traced: Result<Vec<Hop>, Error>,
+) -> CheckResult {
+    match traced {
+        Ok(hops) => CheckResult {
+            result_text: format!("{} ({})", result.result_text, summary(&hops)),
+            ..result
+        }
+        .with_diagnostics(true, [(key, hop_list(&hops))]),
+        Err(err) => {
+            debug!("Traceroute to {} failed: {:?}", target, err);
+            result.with_diagnostics(true, [(key, format!("traceroute failed: {:?}", err))])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceroute() {
+        let output = "traceroute to 192.0.2.1 (192.0.2.1), 20 hops max, 60 byte packets
+ 1  10.0.0.1  0.512 ms
+ 2  *
+ 3  198.51.100.7  3.101 ms
+ 4  *
+ 5  *
+ 6  *
+";
+        let hops = parse(output);
+        assert_eq!(hops.len(), 6);
+        assert_eq!(
+            hops[0],
+            Hop {
+                ttl: 1,
+                address: Some("10.0.0.1".to_string()),
+                rtt_ms: Some("0.512".to_string()),
+            }
+        );
+        assert_eq!(hops[1].address, None);
+        assert_eq!(
+            summary(&hops),
+            "traceroute's last answer was from 198.51.100.7 at hop 3"
+        );
+        assert_eq!(
+            hop_list(&hops),
+            "1 10.0.0.1 0.512ms\n2 *\n3 198.51.100.7 3.101ms\n4-6 *"
+        );
+
+        let hops = parse(" 1  10.0.0.1  0.512 ms\n 2  192.0.2.1  1.2 ms\n");
+        assert_eq!(summary(&hops), "traceroute reached 192.0.2.1 in 2 hops");
+        assert_eq!(hop_list(&hops), "1 10.0.0.1 0.512ms\n2 192.0.2.1 1.2ms");
+
+        let hops = parse(" 1  *\n");
+        assert_eq!(summary(&hops), "nothing answered the traceroute");
+        assert_eq!(hop_list(&hops), "1 *");
+        assert_eq!(summary(&[]), "nothing answered the traceroute");
+    }
+
+    #[test]
+    fn test_add_trace() {
+        let failed = CheckResult {
+            result_text: "ping failed".to_string(),
+            ..Default::default()
+        };
+        let result = add_trace(
+            failed.clone(),
+            "192.0.2.1",
+            "traceroute".to_string(),
+            Ok(parse(" 1  10.0.0.1  0.512 ms\n 2  *\n")),
+        );
+        assert_eq!(
+            result.result_text,
+            "ping failed (traceroute's last answer was from 10.0.0.1 at hop 1)"
+        );
+        assert_eq!(
+            result.diagnostics.get("traceroute").map(String::as_str),
+            Some("1 10.0.0.1 0.512ms\n2 *")
+        );
+
+        let result = add_trace(
+            failed,
+            "192.0.2.1",
+            "traceroute".to_string(),
+            Err(Error::Timeout),
+        );
+        assert_eq!(result.result_text, "ping failed");
+        assert_eq!(
+            result.diagnostics.get("traceroute").map(String::as_str),
+            Some("traceroute failed: Timeout")
+        );
+    }
+}
@@ -0,0 +1,571 @@
+//! WebSocket checks - do the upgrade handshake, optionally send a message and check the reply
+
+use std::collections::BTreeMap;
+use std::num::NonZeroU16;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue, Request};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use super::http::DEFAULT_TIMEOUT;
+use super::prelude::*;
+use super::root_store::RootStore;
+use super::tls::custom_root_store;
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// Checks a WebSocket endpoint
+pub struct WebsocketService {
+    /// Name of the service
+    pub name: String,
+    #[serde(with = "crate::serde::cron")]
+    /// The cron schedule for this service
+    #[schemars(with = "String")]
+    pub cron_schedule: Cron,
+
+    /// Add random jitter in 0..n seconds to the check
+    pub jitter: Option<u16>,
+
+    /// The path to upgrade, defaults to `/`
+    pub path: Option<String>,
+
+    /// Port to connect to, defaults to 443 (or 80 with `use_ws`)
+    pub port: Option<NonZeroU16>,
+
+    /// Use plain ws://, not wss://
+    pub use_ws: Option<bool>,
+
+    /// Extra headers to send with the upgrade request, values of credential-looking headers are masked when the config's displayed
+    #[serde(default, serialize_with = "crate::serde::secret::serialize_headers")]
+    pub headers: HashMap<String, String>,
+
+    /// Ask for this subprotocol, the server has to agree to it
+    pub subprotocol: Option<String>,
+
+    /// A text message to send once connected
+    pub send: Option<String>,
+
+    /// The first message back has to contain this, if `send` isn't set it waits for the server to send something
+    pub contains_string: Option<String>,
+
+    /// How long the whole check can take (seconds), defaults to 10 ([DEFAULT_TIMEOUT])
+    pub timeout: Option<u64>,
+
+    /// Warn if connecting and upgrading takes longer than this many milliseconds
+    pub handshake_warn_ms: Option<u64>,
+
+    /// Critical if connecting and upgrading takes longer than this many milliseconds
+    pub handshake_critical_ms: Option<u64>,
+
+    /// PEM bundle of CA certificates to verify the chain against, instead of the webpki roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Which roots to trust: `webpki` (the default), `system` or `file:<path>`
+    #[serde(default)]
+    pub root_store: RootStore,
+}
+
+impl ConfigOverlay for WebsocketService {
+    fn overlay_host_config(&self, value: &Map<String, Json>) -> Result<Box<Self>, Error> {
+        Ok(Box::new(Self {
+            name: self.extract_string(value, "name", &self.name),
+            cron_schedule: self.extract_cron(value, "cron_schedule", &self.cron_schedule)?,
+            jitter: self.extract_value(value, "jitter", &self.jitter)?,
+            path: self.extract_value(value, "path", &self.path)?,
+            port: self.extract_value(value, "port", &self.port)?,
+            use_ws: self.extract_value(value, "use_ws", &self.use_ws)?,
+            headers: self.extract_value(value, "headers", &self.headers)?,
+            subprotocol: self.extract_value(value, "subprotocol", &self.subprotocol)?,
+            send: self.extract_value(value, "send", &self.send)?,
+            contains_string: self.extract_value(value, "contains_string", &self.contains_string)?,
+            timeout: self.extract_value(value, "timeout", &self.timeout)?,
+            handshake_warn_ms: self.extract_value(
+                value,
+                "handshake_warn_ms",
+                &self.handshake_warn_ms,
+            )?,
+            handshake_critical_ms: self.extract_value(
+                value,
+                "handshake_critical_ms",
+                &self.handshake_critical_ms,
+            )?,
+            ca_file: self.extract_value(value, "ca_file", &self.ca_file)?,
+            root_store: self.extract_value(value, "root_store", &self.root_store)?,
+        }))
+    }
+}
+
+/// What happened, the times are in milliseconds from when we started connecting
+struct WebsocketOutcome {
+    connect_ms: i64,
+    handshake_ms: i64,
+    reply: Option<String>,
+}
+
+impl WebsocketService {
+    fn use_tls(&self) -> bool {
+        !self.use_ws.unwrap_or(false)
+    }
+
+    fn url(&self, hostname: &str) -> String {
+        let (scheme, default_port) = match self.use_tls() {
+            true => ("wss", 443),
+            false => ("ws", 80),
+        };
+        let path = self.path.as_deref().unwrap_or("/");
+        format!(
+            "{}://{}:{}{}{}",
+            scheme,
+            hostname,
+            self.port.map(u16::from).unwrap_or(default_port),
+            match path.starts_with('/') {
+                true => "",
+                false => "/",
+            },
+            path
+        )
+    }
+
+    /// The upgrade request with the extra headers and subprotocol
+    fn request(&self, hostname: &str) -> Result<Request<()>, Error> {
+        let mut request = self
+            .url(hostname)
+            .into_client_request()
+            .map_err(|err| Error::Configuration(format!("Invalid WebSocket URL: {}", err)))?;
+        for (name, value) in self.headers.iter() {
+            request.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| Error::Configuration(format!("Invalid HTTP header: {}", name)))?,
+                HeaderValue::from_str(value)
+                    .map_err(|_| Error::Configuration(format!("Invalid HTTP header: {}", name)))?,
+            );
+        }
+        if let Some(subprotocol) = self.subprotocol.as_ref() {
+            request.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_str(subprotocol).map_err(|_| {
+                    Error::Configuration(format!("Invalid subprotocol: {}", subprotocol))
+                })?,
+            );
+        }
+        Ok(request)
+    }
+
+    /// Upgrades the connection, then sends and waits for a message if that's been asked for
+    async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        request: Request<()>,
+        stream: S,
+        start_time: DateTime<Utc>,
+        connect_ms: i64,
+    ) -> Result<WebsocketOutcome, String> {
+        let (mut websocket, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(|err| match err {
+                WsError::Http(response) => {
+                    format!("Upgrade failed with status {}", response.status())
+                }
+                err => format!("Upgrade failed: {}", err),
+            })?;
+        let handshake_ms = (Utc::now() - start_time).num_milliseconds();
+
+        if let Some(subprotocol) = self.subprotocol.as_ref() {
+            let accepted = response
+                .headers()
+                .get("Sec-WebSocket-Protocol")
+                .and_then(|value| value.to_str().ok());
+            if accepted != Some(subprotocol.as_str()) {
+                return Err(format!(
+                    "The server didn't accept the {} subprotocol",
+                    subprotocol
+                ));
+            }
+        }
+
+        if let Some(send) = self.send.as_ref() {
+            websocket
+                .send(Message::Text(send.clone()))
+                .await
+                .map_err(|err| format!("Failed to send message: {}", err))?;
+        }
+        let mut reply = None;
+        if self.send.is_some() || self.contains_string.is_some() {
+            while let Some(message) = websocket.next().await {
+                match message.map_err(|err| format!("Failed to read reply: {}", err))? {
+                    Message::Text(text) => {
+                        reply = Some(text);
+                        break;
+                    }
+                    Message::Binary(data) => {
+                        reply = Some(String::from_utf8_lossy(&data).to_string());
+                        break;
+                    }
+                    Message::Close(_) => break,
+                    // pings are answered while reading
+                    _ => continue,
+                }
+            }
+            if reply.is_none() {
+                return Err("The server closed the connection without replying".to_string());
+            }
+        }
+        // it's polite, but the check's already done
+        let _ = websocket.close(None).await;
+
+        Ok(WebsocketOutcome {
+            connect_ms,
+            handshake_ms,
+            reply,
+        })
+    }
+
+    async fn check(
+        &self,
+        host: &entities::host::Model,
+        roots: RootCertStore,
+    ) -> Result<WebsocketOutcome, String> {
+        let start_time = Utc::now();
+        let request = self
+            .request(&host.hostname)
+            .map_err(|err| format!("{:?}", err))?;
+        let port = self
+            .port
+            .map(u16::from)
+            .unwrap_or(if self.use_tls() { 443 } else { 80 });
+        let stream = TcpStream::connect((host.hostname.as_str(), port))
+            .await
+            .map_err(|err| format!("Failed to connect to {}:{}: {}", host.hostname, port, err))?;
+        let connect_ms = (Utc::now() - start_time).num_milliseconds();
+
+        if !self.use_tls() {
+            return self.converse(request, stream, start_time, connect_ms).await;
+        }
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(host.hostname.clone())
+            .map_err(|err| format!("Invalid hostname {}: {}", host.hostname, err))?;
+        let stream = TlsConnector::from(Arc::new(client_config))
+            .connect(server_name, stream)
+            .await
+            .map_err(|err| format!("TLS handshake failed: {}", err))?;
+        self.converse(request, stream, start_time, connect_ms).await
+    }
+
+    /// Works out the status from the handshake time and reply
+    fn evaluate(&self, outcome: &WebsocketOutcome) -> (ServiceStatus, String) {
+        if let (Some(expected), Some(reply)) =
+            (self.contains_string.as_ref(), outcome.reply.as_ref())
+        {
+            if !reply.contains(expected.as_str()) {
+                return (
+                    ServiceStatus::Critical,
+                    format!("Expected string '{}' not found in reply", expected),
+                );
+            }
+        }
+        let handshake_ms = outcome.handshake_ms.max(0) as u64;
+        if let Some(critical) = self.handshake_critical_ms {
+            if handshake_ms > critical {
+                return (
+                    ServiceStatus::Critical,
+                    format!(
+                        "Handshake took {}ms, critical over {}ms",
+                        handshake_ms, critical
+                    ),
+                );
+            }
+        }
+        if let Some(warn) = self.handshake_warn_ms {
+            if handshake_ms > warn {
+                return (
+                    ServiceStatus::Warning,
+                    format!("Handshake took {}ms, warning over {}ms", handshake_ms, warn),
+                );
+            }
+        }
+        (ServiceStatus::Ok, format!("Upgraded in {}ms", handshake_ms))
+    }
+}
+
+#[async_trait]
+impl ServiceTrait for WebsocketService {
+    async fn run(&self, host: &entities::host::Model) -> Result<CheckResult, Error> {
+        let start_time = Utc::now();
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        let roots = custom_root_store(&config.root_store, config.ca_file.as_deref())?
+            .unwrap_or_else(|| RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+            });
+
+        let timeout = config.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let outcome = tokio::time::timeout(Duration::from_secs(timeout), config.check(host, roots))
+            .await
+            .unwrap_or_else(|_| Err(format!("Timed out after {} seconds", timeout)));
+        let time_elapsed = Utc::now() - start_time;
+
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(result_text) => {
+                return Ok(CheckResult {
+                    timestamp: start_time,
+                    time_elapsed,
+                    status: ServiceStatus::Critical,
+                    result_text,
+                    ..Default::default()
+                })
+            }
+        };
+
+        let (status, result_text) = config.evaluate(&outcome);
+        let details = BTreeMap::from([
+            ("connect_ms".to_string(), outcome.connect_ms.to_string()),
+            ("handshake_ms".to_string(), outcome.handshake_ms.to_string()),
+        ]);
+        Ok(CheckResult {
+            timestamp: start_time,
+            time_elapsed,
+            status,
+            result_text,
+            details,
+            ..Default::default()
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for (name, value) in self.headers.iter() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+            {
+                return Err(Error::Configuration(format!(
+                    "{}: invalid HTTP header: {}",
+                    self.name, name
+                )));
+            }
+        }
+        if let (Some(warn), Some(critical)) = (self.handshake_warn_ms, self.handshake_critical_ms) {
+            if warn > critical {
+                return Err(Error::Configuration(format!(
+                    "{}: handshake_warn_ms can't be more than handshake_critical_ms",
+                    self.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn as_json_pretty(&self, host: &entities::host::Model) -> Result<String, Error> {
+        let config = self.overlay_host_config(&self.get_host_config(&self.name, host)?)?;
+        crate::serde::secret::to_string_pretty(&config)
+    }
+
+    fn jitter_value(&self) -> u32 {
+        self.jitter.unwrap_or(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::handshake::server::{
+        ErrorResponse, Request as ServerRequest, Response as ServerResponse,
+    };
+    use tokio_tungstenite::tungstenite::http::StatusCode;
+
+    use super::*;
+    use crate::testing::service_from_json;
+
+    /// Echoes messages back with a prefix, only upgrading `/ws` and agreeing to the `echo` subprotocol
+    async fn echo_server() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind listener");
+        let port = listener.local_addr().expect("Failed to get address").port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let callback = |request: &ServerRequest, mut response: ServerResponse| {
+                        if request.uri().path() != "/ws" {
+                            let mut not_found = ErrorResponse::new(None);
+                            *not_found.status_mut() = StatusCode::NOT_FOUND;
+                            return Err(not_found);
+                        }
+                        if let Some(protocol) = request.headers().get("Sec-WebSocket-Protocol") {
+                            if protocol == "echo" {
+                                response
+                                    .headers_mut()
+                                    .insert("Sec-WebSocket-Protocol", protocol.clone());
+                            }
+                        }
+                        Ok(response)
+                    };
+                    let Ok(mut websocket) =
+                        tokio_tungstenite::accept_hdr_async(stream, callback).await
+                    else {
+                        return;
+                    };
+                    while let Some(Ok(message)) = websocket.next().await {
+                        if let Message::Text(text) = message {
+                            if websocket
+                                .send(Message::Text(format!("echo: {}", text)))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_websocket_url() {
+        let service = service_from_json::<WebsocketService>("websocket", json!({"use_ws": false}))
+            .expect("Failed to parse websocket service");
+        assert_eq!(service.url("example.com"), "wss://example.com:443/");
+        let service = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({"use_ws": true, "path": "live?x=1", "port": 8080}),
+        )
+        .expect("Failed to parse websocket service");
+        assert_eq!(service.url("example.com"), "ws://example.com:8080/live?x=1");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_run() {
+        let port = echo_server().await;
+        let host = entities::host::Model {
+            hostname: "127.0.0.1".to_string(),
+            ..entities::host::test_host()
+        };
+
+        let result = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({"use_ws": true, "port": port, "path": "/ws"}),
+        )
+        .expect("Failed to parse websocket service")
+        .run(&host)
+        .await
+        .expect("Failed to run websocket check");
+        assert_eq!(result.status, ServiceStatus::Ok, "{}", result.result_text);
+        assert!(result.details.contains_key("handshake_ms"));
+
+        let result = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({
+                "use_ws": true,
+                "port": port,
+                "path": "/ws",
+                "subprotocol": "echo",
+                "send": "hello",
+                "contains_string": "echo: hello",
+            }),
+        )
+        .expect("Failed to parse websocket service")
+        .run(&host)
+        .await
+        .expect("Failed to run websocket check");
+        assert_eq!(result.status, ServiceStatus::Ok, "{}", result.result_text);
+
+        let result = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({
+                "use_ws": true,
+                "port": port,
+                "path": "/ws",
+                "send": "hello",
+                "contains_string": "goodbye",
+            }),
+        )
+        .expect("Failed to parse websocket service")
+        .run(&host)
+        .await
+        .expect("Failed to run websocket check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+        assert_eq!(
+            result.result_text,
+            "Expected string 'goodbye' not found in reply"
+        );
+
+        let result = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({"use_ws": true, "port": port, "path": "/ws", "subprotocol": "chat"}),
+        )
+        .expect("Failed to parse websocket service")
+        .run(&host)
+        .await
+        .expect("Failed to run websocket check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+
+        let result = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({"use_ws": true, "port": port, "path": "/nope"}),
+        )
+        .expect("Failed to parse websocket service")
+        .run(&host)
+        .await
+        .expect("Failed to run websocket check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+        assert_eq!(
+            result.result_text,
+            "Upgrade failed with status 404 Not Found"
+        );
+
+        // the server never says anything unprompted
+        let result = service_from_json::<WebsocketService>(
+            "websocket",
+            json!({
+                "use_ws": true,
+                "port": port,
+                "path": "/ws",
+                "contains_string": "hello",
+                "timeout": 1,
+            }),
+        )
+        .expect("Failed to parse websocket service")
+        .run(&host)
+        .await
+        .expect("Failed to run websocket check");
+        assert_eq!(result.status, ServiceStatus::Critical);
+        assert_eq!(result.result_text, "Timed out after 1 seconds");
+    }
+
+    #[test]
+    fn test_websocket_validate() {
+        assert!(
+            service_from_json::<WebsocketService>("websocket", json!({"use_ws": true}))
+                .expect("Failed to parse websocket service")
+                .validate()
+                .is_ok()
+        );
+        assert!(service_from_json::<WebsocketService>(
+            "websocket",
+            json!({"use_ws": true, "headers": {"bad header": "x"}})
+        )
+        .expect("Failed to parse websocket service")
+        .validate()
+        .is_err());
+        assert!(service_from_json::<WebsocketService>(
+            "websocket",
+            json!({
+                "use_ws": true,
+                "handshake_warn_ms": 500,
+                "handshake_critical_ms": 100,
+            })
+        )
+        .expect("Failed to parse websocket service")
+        .validate()
+        .is_err());
+    }
+}
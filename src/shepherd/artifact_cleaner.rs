@@ -0,0 +1,20 @@
+//! Deletes artifacts once the history entries they belong to have been cleaned up
+
+use super::prelude::*;
+use crate::artifacts::ARTIFACTS;
+
+pub(crate) struct ArtifactCleanTask {}
+
+#[async_trait]
+impl CronTaskTrait for ArtifactCleanTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let deleted = ARTIFACTS.sweep(&db, Utc::now()).await?;
+        if deleted > 0 {
+            info!(
+                "Deleted {} artifacts that are no longer in the history",
+                deleted
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! Backs up the database on the schedule in the `backup` config
+
+use super::prelude::*;
+use crate::backup::backup_database;
+
+pub(crate) struct BackupTask {
+    pub(crate) config: SendableConfig,
+    /// When the last backup was taken, or when the task started if there hasn't been one yet
+    pub(crate) last_backup: DateTime<Utc>,
+}
+
+impl BackupTask {
+    pub(crate) fn new(config: SendableConfig) -> Self {
+        Self {
+            config,
+            last_backup: Utc::now(),
+        }
+    }
+
+    /// Takes a backup if the schedule's come around since the last one, returns its name if it did
+    async fn backup_due(
+        &mut self,
+        db: &DatabaseConnection,
+        now: DateTime<Utc>,
+    ) -> Result<Option<String>, Error> {
+        let Some(backup_config) = self.config.read().await.backup.clone() else {
+            return Ok(None);
+        };
+        if backup_config
+            .cron()?
+            .find_next_occurrence(&self.last_backup, false)?
+            > now
+        {
+            return Ok(None);
+        }
+        // don't try again every minute if it fails, it'll be logged and tried at the next scheduled time
+        self.last_backup = now;
+        let name = backup_database(db, &backup_config, now)
+            .await
+            .inspect_err(|err| error!("Failed to back up the database: {:?}", err))?;
+        info!("Backed up the database to {}", name);
+        Ok(Some(name))
+    }
+}
+
+#[async_trait]
+impl CronTaskTrait for BackupTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        self.backup_due(&db, Utc::now()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupConfig;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_backup_task() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        let mut task = BackupTask::new(config.clone());
+        // there's no backup config, so nothing happens
+        assert!(task
+            .backup_due(&db, Utc::now() + Duration::days(1))
+            .await
+            .expect("Failed to run backup task")
+            .is_none());
+
+        let directory = std::env::temp_dir().join(format!("maremma-backups-{}", Uuid::new_v4()));
+        config.write().await.backup = Some(BackupConfig {
+            schedule: "0 * * * *".to_string(),
+            keep: 1,
+            directory: Some(directory.clone()),
+            s3: None,
+        });
+        let started = task.last_backup;
+        assert!(task
+            .backup_due(&db, started)
+            .await
+            .expect("Failed to run backup task")
+            .is_none());
+        let later = started + Duration::hours(1);
+        assert!(task
+            .backup_due(&db, later)
+            .await
+            .expect("Failed to run backup task")
+            .is_some());
+        assert_eq!(task.last_backup, later);
+        // and it's not taken twice
+        assert!(task
+            .backup_due(&db, later)
+            .await
+            .expect("Failed to run backup task")
+            .is_none());
+        tokio::fs::remove_dir_all(&directory)
+            .await
+            .expect("Failed to clean up");
+    }
+}
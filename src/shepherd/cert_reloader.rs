@@ -1,6 +1,7 @@
 //! Reloads the web server when the cert or key changes
 
 use super::prelude::*;
+use crate::web::proxy::ListenScheme;
 
 /// Task to check if any certificates have changed
 pub(crate) struct CertReloaderTask {
@@ -8,6 +9,8 @@ pub(crate) struct CertReloaderTask {
     config: SendableConfig,
     cert_time: DateTime<Utc>,
     key_time: DateTime<Utc>,
+    /// There's nothing to watch with ACME, which the web server swaps in itself, or plain HTTP
+    disabled: bool,
 }
 
 /// Get the last modified time of a file
@@ -55,6 +58,16 @@ impl CertReloaderTask {
         // get the time for the cert
         let config_reader = config.read().await;
 
+        if config_reader.acme.is_some() || config_reader.listen_scheme == ListenScheme::Http {
+            return Ok(Self {
+                tx,
+                config: config.clone(),
+                cert_time: Utc::now(),
+                key_time: Utc::now(),
+                disabled: true,
+            });
+        }
+
         if !config_reader.cert_file.exists() {
             return Err(Error::Configuration(format!(
                 "Couldn't find cert file at {}",
@@ -75,13 +88,17 @@ impl CertReloaderTask {
             config: config.clone(),
             cert_time,
             key_time,
+            disabled: false,
         })
     }
 }
 
 #[async_trait]
 impl CronTaskTrait for CertReloaderTask {
-    async fn run(&mut self, _db: Arc<RwLock<DatabaseConnection>>) -> Result<(), Error> {
+    async fn run(&mut self, _db: DatabaseConnection) -> Result<(), Error> {
+        if self.disabled {
+            return Ok(());
+        }
         let (cert_time, key_time) = get_file_times(self.config.clone()).await?;
 
         if cert_time != self.cert_time || key_time != self.key_time {
@@ -103,6 +120,7 @@ impl CronTaskTrait for CertReloaderTask {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
     use tokio::sync::RwLock;
 
     use crate::config::Configuration;
@@ -135,6 +153,7 @@ mod tests {
             config: Arc::new(RwLock::new(bad_config)),
             cert_time: chrono::Utc::now(),
             key_time: chrono::Utc::now(),
+            disabled: false,
         };
 
         let res = task.run(db).await;
@@ -142,4 +161,39 @@ mod tests {
         dbg!(&res);
         assert!(res.is_err());
     }
+
+    #[tokio::test]
+    async fn test_cert_reloader_without_cert_files() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let config = Configuration {
+            cert_file: std::path::PathBuf::from("bad_cert_file"),
+            cert_key: std::path::PathBuf::from("bad_cert_key"),
+            acme: Some(
+                serde_json::from_value(serde_json::json!({})).expect("Failed to parse acme"),
+            ),
+            ..Default::default()
+        };
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+
+        let mut task = CertReloaderTask::new(tx, Arc::new(RwLock::new(config)))
+            .await
+            .expect("ACME doesn't need cert files");
+        task.run(db.clone())
+            .await
+            .expect("Nothing to check with ACME");
+
+        let config = Configuration {
+            cert_file: std::path::PathBuf::from("bad_cert_file"),
+            cert_key: std::path::PathBuf::from("bad_cert_key"),
+            listen_scheme: ListenScheme::Http,
+            ..Default::default()
+        };
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let mut task = CertReloaderTask::new(tx, Arc::new(RwLock::new(config)))
+            .await
+            .expect("Plain HTTP doesn't need cert files");
+        task.run(db)
+            .await
+            .expect("Nothing to check with plain HTTP");
+    }
 }
@@ -0,0 +1,125 @@
+//! Keeps the configured discovery sources in sync, adding their hosts to the config if they're set to `auto_accept`
+
+use std::path::PathBuf;
+
+use super::prelude::*;
+use crate::config::{apply_config, Configuration};
+use crate::db::update_db_from_config;
+use crate::discovery::{accept_hosts, sync_source};
+
+pub(crate) struct DiscoverySyncTask {
+    pub(crate) config: SendableConfig,
+    pub(crate) config_file: PathBuf,
+}
+
+#[async_trait]
+impl CronTaskTrait for DiscoverySyncTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let sources = self.config.read().await.discovery.sources.clone();
+        let mut changed = false;
+        for (name, source) in sources.iter() {
+            // one broken source shouldn't stop the others syncing
+            let sync = match sync_source(&db, &*self.config.read().await, name, source).await {
+                Ok(sync) => sync,
+                Err(err) => {
+                    error!("Failed to sync discovery source {}: {:?}", name, err);
+                    continue;
+                }
+            };
+            if source.auto_accept {
+                let changes =
+                    accept_hosts(&self.config_file, &db, sync.hosts, &sync.proposals).await?;
+                for change in changes.iter() {
+                    info!("Discovery source {}: {}", name, change);
+                }
+                changed |= !changes.is_empty();
+            } else if !sync.proposals.is_empty() {
+                info!(
+                    "Discovery source {} has {} hosts waiting for review",
+                    name,
+                    sync.proposals.len()
+                );
+            }
+        }
+
+        if changed {
+            info!("Reloading the config after adding discovered hosts");
+            let config = Configuration::new(&self.config_file).await?;
+            apply_config(&config).await?;
+            *self.config.write().await = config;
+            update_db_from_config(db.clone(), self.config.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::prelude::test_setup;
+
+    #[tokio::test]
+    async fn test_discovery_sync_task() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let _lock = crate::config::APPLY_CONFIG_LOCK.lock().await;
+        let zone_file = tempfile::NamedTempFile::new().expect("Failed to create zone file");
+        tokio::fs::write(zone_file.path(), "node1 IN A 192.0.2.30\n")
+            .await
+            .expect("Failed to write zone file");
+
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create config file");
+        let mut contents: serde_json::Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        contents["discovery"] = serde_json::json!({
+            "sources": {
+                "lab": {
+                    "type": "zone_file",
+                    "path": zone_file.path(),
+                    "zone": "lab.example.com",
+                    "host_groups": ["lab"],
+                    "auto_accept": true,
+                },
+            },
+        });
+        tokio::fs::write(
+            config_file.path(),
+            serde_json::to_string_pretty(&contents).expect("Failed to serialize config"),
+        )
+        .await
+        .expect("Failed to write config file");
+
+        let config_file = config_file.path().to_path_buf();
+        let config = Arc::new(RwLock::new(
+            Configuration::new(&config_file)
+                .await
+                .expect("Failed to load config"),
+        ));
+        let mut task = DiscoverySyncTask {
+            config: config.clone(),
+            config_file,
+        };
+        task.run(db.clone())
+            .await
+            .expect("Failed to run DiscoverySyncTask");
+
+        let host = config
+            .read()
+            .await
+            .hosts
+            .get("node1.lab.example.com")
+            .cloned()
+            .expect("Discovered host wasn't added to the config");
+        assert!(host.host_groups.contains(&"lab".to_string()));
+        assert!(entities::discovered_host::Entity::pending(&db)
+            .await
+            .expect("Failed to list pending hosts")
+            .is_empty());
+    }
+}
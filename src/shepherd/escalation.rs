@@ -0,0 +1,104 @@
+//! Sends the next level of a check's escalation policy when it's been critical for long enough and nobody's acknowledged it
+
+use chrono::TimeDelta;
+use entities::service_check_escalation::Entity as Escalation;
+
+use super::prelude::*;
+use crate::actions::dispatcher::DISPATCHER;
+use crate::check_loop::CheckResult;
+
+pub(crate) struct EscalationTask {
+    pub(crate) config: SendableConfig,
+}
+
+#[async_trait]
+impl CronTaskTrait for EscalationTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let now = Utc::now();
+        Escalation::track(&db, now)
+            .await
+            .inspect_err(|err| error!("Failed to track escalations: {:?}", err))?;
+
+        let policies = self.config.read().await.notifications.escalations.clone();
+        if policies.is_empty() {
+            return Ok(());
+        }
+        for (escalation, check) in Escalation::unacknowledged(&db).await? {
+            let routing = DISPATCHER
+                .lock()
+                .await
+                .routing(&check.host_name, &check.service_name);
+            let Some(policy) = routing
+                .escalation
+                .as_ref()
+                .and_then(|name| policies.get(name))
+            else {
+                continue;
+            };
+            let mut level = escalation.level;
+            for (index, step) in policy.due_steps(level.max(0) as usize, escalation.started_at, now)
+            {
+                level = index as i32 + 1;
+                info!(
+                    "Escalating service_check={} to level {} after {} minutes",
+                    check.id, level, step.after_minutes
+                );
+                let notification = CheckResult {
+                    timestamp: now,
+                    time_elapsed: TimeDelta::zero(),
+                    status: check.status,
+                    result_text: format!(
+                        "Escalation level {}: {} on {} has been {} for {} minutes without being acknowledged",
+                        level,
+                        check.service_name,
+                        check.host_name,
+                        check.status,
+                        (now - escalation.started_at).num_minutes()
+                    ),
+                    ..Default::default()
+                };
+                DISPATCHER
+                    .lock()
+                    .await
+                    .escalate(&step.targets, &notification)
+                    .await;
+            }
+            if level != escalation.level {
+                Escalation::set_level(&db, check.id, level).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use sea_orm::{ActiveModelTrait, IntoActiveModel, Set};
+
+    #[tokio::test]
+    async fn test_escalation_task() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        let check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let mut active = check.clone().into_active_model();
+        active.status = Set(ServiceStatus::Critical);
+        active.update(&db).await.expect("Failed to update check");
+
+        EscalationTask { config }
+            .run(db.clone())
+            .await
+            .expect("Failed to run EscalationTask");
+        let escalation = Escalation::find_by_id(check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query escalations")
+            .expect("Critical check isn't being tracked");
+        // there's no policy, so nothing's been sent
+        assert_eq!(escalation.level, 0);
+    }
+}
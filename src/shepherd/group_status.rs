@@ -0,0 +1,44 @@
+//! Rolls each host group's status up from its hosts, so the web UI doesn't have to work it out on every page load
+
+use super::prelude::*;
+
+pub(crate) struct GroupStatusTask {
+    pub(crate) config: SendableConfig,
+}
+
+#[async_trait]
+impl CronTaskTrait for GroupStatusTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let group_status = self.config.read().await.group_status.clone();
+        let updated = entities::host_group_status::Entity::refresh(&db, &group_status)
+            .await
+            .inspect_err(|err| error!("Failed to refresh host group statuses: {:?}", err))?;
+        debug!("Refreshed the status of {} host groups", updated);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use sea_orm::PaginatorTrait;
+
+    #[tokio::test]
+    async fn test_group_status_task() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        GroupStatusTask { config }
+            .run(db.clone())
+            .await
+            .expect("Failed to run GroupStatusTask");
+        let groups = entities::host_group::Entity::find()
+            .count(&db)
+            .await
+            .expect("Failed to count host groups");
+        let statuses = entities::host_group_status::Entity::find()
+            .count(&db)
+            .await
+            .expect("Failed to count host group statuses");
+        assert_eq!(groups, statuses);
+    }
+}
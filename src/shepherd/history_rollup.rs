@@ -0,0 +1,145 @@
+//! Rolls the service check history up into hourly and daily summaries, so the raw history can be kept short
+
+use entities::service_check_rollup::{epoch, Entity as Rollup, RollupPeriod};
+
+use super::prelude::*;
+
+/// How many periods of each kind get rolled up in one run, so catching up on a big history doesn't lock the database for long
+const MAX_PERIODS_PER_RUN: usize = 48;
+
+/// How long hourly rollups are kept, the daily ones are kept until the check's deleted
+const HOURLY_ROLLUP_RETENTION_DAYS: i64 = 31;
+
+pub(crate) struct HistoryRollupTask {}
+
+impl HistoryRollupTask {
+    /// Rolls up any complete `period`s which haven't been done yet, up to `until`
+    async fn roll_up_period(
+        db: &DatabaseConnection,
+        period: RollupPeriod,
+        until: DateTime<Utc>,
+    ) -> Result<u64, Error> {
+        let mut next = match Rollup::latest_period_start(db, period).await? {
+            Some(latest) => latest + period.duration(),
+            None => epoch(),
+        };
+        let mut written = 0;
+        for _ in 0..MAX_PERIODS_PER_RUN {
+            // skip over any gaps in the history
+            let period_start = match Rollup::next_source_time(db, period, next).await? {
+                Some(source_time) => period.floor(source_time),
+                None => break,
+            };
+            if period_start + period.duration() > until {
+                break;
+            }
+            written += Rollup::roll_up(db, period, period_start).await?;
+            next = period_start + period.duration();
+        }
+        Ok(written)
+    }
+}
+
+#[async_trait]
+impl CronTaskTrait for HistoryRollupTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let now = Utc::now();
+        let hourly = Self::roll_up_period(&db, RollupPeriod::Hour, RollupPeriod::Hour.floor(now))
+            .await
+            .inspect_err(|err| error!("Failed to roll up hourly history: {:?}", err))?;
+
+        // a day's only done once all its hours are
+        let hours_done = Rollup::latest_period_start(&db, RollupPeriod::Hour)
+            .await?
+            .map(|latest| latest + RollupPeriod::Hour.duration())
+            .unwrap_or(epoch());
+        let daily = Self::roll_up_period(
+            &db,
+            RollupPeriod::Day,
+            RollupPeriod::Day.floor(hours_done.min(now)),
+        )
+        .await
+        .inspect_err(|err| error!("Failed to roll up daily history: {:?}", err))?;
+
+        let pruned = Rollup::prune(
+            &db,
+            RollupPeriod::Hour,
+            now - Duration::days(HOURLY_ROLLUP_RETENTION_DAYS),
+        )
+        .await?;
+
+        if hourly + daily + pruned > 0 {
+            info!(
+                "Wrote {} hourly and {} daily history rollups, pruned {} old hourly rollups",
+                hourly, daily, pruned
+            );
+        } else {
+            debug!("No history to roll up");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_loop::CheckEnvironment;
+    use crate::db::tests::test_setup;
+    use sea_orm::{ActiveModelTrait, IntoActiveModel};
+
+    #[tokio::test]
+    async fn test_history_rollup_task() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let service_check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Failed to find service check");
+
+        let start = RollupPeriod::Day.floor(Utc::now() - Duration::days(3));
+        for offset in [1, 61, 24 * 60 + 5] {
+            let mut entry = entities::service_check_history::Model::from_service_check_result(
+                service_check.id,
+                &crate::prelude::CheckResult {
+                    timestamp: Utc::now(),
+                    time_elapsed: Duration::milliseconds(10),
+                    status: ServiceStatus::Ok,
+                    result_text: "test".to_string(),
+                    ..Default::default()
+                },
+                &CheckEnvironment::local(None),
+            );
+            entry.timestamp = start + Duration::minutes(offset);
+            entry
+                .into_active_model()
+                .insert(&db)
+                .await
+                .expect("Failed to save service check history");
+        }
+
+        let mut task = HistoryRollupTask {};
+        task.run(db.clone()).await.expect("Failed to run task");
+
+        let hourly = Rollup::latest_period_start(&db, RollupPeriod::Hour)
+            .await
+            .expect("Failed to query rollups")
+            .expect("No hourly rollups");
+        assert!(hourly >= start + Duration::days(1));
+        assert!(hourly < RollupPeriod::Hour.floor(Utc::now()));
+
+        let summary = Rollup::summary(&db, service_check.id, RollupPeriod::Day, start)
+            .await
+            .expect("Failed to summarise");
+        assert!(summary.checks >= 2);
+        assert_eq!(summary.availability_text(), "100.00%");
+
+        // running it again doesn't double count
+        task.run(db.clone()).await.expect("Failed to run task");
+        assert_eq!(
+            Rollup::summary(&db, service_check.id, RollupPeriod::Day, start)
+                .await
+                .expect("Failed to summarise"),
+            summary
+        );
+    }
+}
@@ -1,17 +1,41 @@
 //! The shepherd wanders around making sure things are in order.
 
+mod artifact_cleaner;
+mod backup;
 mod cert_reloader;
+mod discovery_sync;
+mod escalation;
+mod group_status;
+mod history_rollup;
+mod notification_flusher;
+mod passive_freshness;
+mod pause_resumer;
 pub(crate) mod prelude;
+mod reports;
+mod secrets_refresh;
 mod service_check_cleaner;
 mod service_check_history_cleaner;
 mod session_cleaner;
 
+use crate::health::{Component, HEARTBEATS};
+use artifact_cleaner::ArtifactCleanTask;
+use backup::BackupTask;
 use cert_reloader::CertReloaderTask;
+use discovery_sync::DiscoverySyncTask;
+use escalation::EscalationTask;
+use group_status::GroupStatusTask;
+use history_rollup::HistoryRollupTask;
+use notification_flusher::NotificationFlushTask;
+use passive_freshness::PassiveFreshnessTask;
+use pause_resumer::PauseResumeTask;
 use prelude::*;
+use reports::ReportTask;
+use secrets_refresh::SecretsRefreshTask;
 use service_check_cleaner::ServiceCheckCleanTask;
 use service_check_history_cleaner::ServiceCheckHistoryCleanerTask;
 use session_cleaner::SessionCleanTask;
-use tokio::sync::RwLock;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 pub(crate) struct CronTask {
     name: String,
@@ -35,7 +59,7 @@ impl CronTask {
     }
 
     #[instrument(level = "INFO", skip_all)]
-    async fn run_task(&mut self, db: Arc<RwLock<DatabaseConnection>>) -> Result<bool, Error> {
+    async fn run_task(&mut self, db: DatabaseConnection) -> Result<bool, Error> {
         if self.should_run()? {
             self.task
                 .run(db)
@@ -56,14 +80,16 @@ impl CronTask {
 
 #[async_trait]
 pub(crate) trait CronTaskTrait {
-    async fn run(&mut self, db: Arc<RwLock<DatabaseConnection>>) -> Result<(), Error>;
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error>;
 }
 
 /// The shepherd wanders around making sure things are in order.
 pub async fn shepherd(
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
     config: SendableConfig,
+    config_file: PathBuf,
     web_tx: tokio::sync::mpsc::Sender<WebServerControl>,
+    metrics_meter: Arc<Meter>,
 ) -> Result<(), Error> {
     // run the clean_up_checking loop every x minutes
     let mut service_check_clean = CronTask::new(
@@ -88,10 +114,97 @@ pub async fn shepherd(
     let mut service_check_history_cleaner: CronTask = CronTask::new(
         "ServiceCheckHistoryCleaner".to_string(),
         Cron::new("27 * * * *").parse()?,
-        Box::new(ServiceCheckHistoryCleanerTask::new(config.clone())),
+        Box::new(ServiceCheckHistoryCleanerTask::new(
+            config.clone(),
+            &metrics_meter,
+        )),
     )
     .with_last_run(Utc::now() + Duration::minutes(5));
 
+    // the history cleaner doesn't know about artifacts, so their files are tidied up afterwards
+    let mut artifact_clean = CronTask::new(
+        "ArtifactClean".to_string(),
+        Cron::new("41 * * * *").parse()?,
+        Box::new(ArtifactCleanTask {}),
+    );
+
+    let mut history_rollup = CronTask::new(
+        "HistoryRollup".to_string(),
+        Cron::new("7 * * * *").parse()?,
+        Box::new(HistoryRollupTask {}),
+    );
+
+    let mut notification_flush = CronTask::new(
+        "NotificationFlush".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(NotificationFlushTask {}),
+    );
+
+    // passive checks that haven't had a result submitted in time go critical, as do late heartbeats
+    let mut passive_freshness = CronTask::new(
+        "PassiveFreshness".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(PassiveFreshnessTask {}),
+    );
+
+    let mut pause_resume = CronTask::new(
+        "PauseResume".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(PauseResumeTask {}),
+    );
+
+    // send the next level to anyone who needs to know about checks that have been critical for too long
+    let mut escalation = CronTask::new(
+        "Escalation".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(EscalationTask {
+            config: config.clone(),
+        }),
+    );
+
+    let mut group_status = CronTask::new(
+        "GroupStatus".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(GroupStatusTask {
+            config: config.clone(),
+        }),
+    );
+
+    // keep the discovery sources in sync, adding hosts if they're set to auto_accept
+    let mut discovery_sync = CronTask::new(
+        "DiscoverySync".to_string(),
+        Cron::new("*/15 * * * *").parse()?,
+        Box::new(DiscoverySyncTask {
+            config: config.clone(),
+            config_file: config_file.clone(),
+        }),
+    );
+
+    // send the scheduled summary reports, each one checks its own schedule
+    let mut reports = CronTask::new(
+        "Reports".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(ReportTask::new(config.clone())),
+    );
+
+    // back up the database, the task checks the schedule in the backup config
+    let mut backup = CronTask::new(
+        "Backup".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(BackupTask::new(config.clone())),
+    );
+
+    // pick up secrets that have been rotated in vault
+    let mut secrets_refresh = CronTask::new(
+        "SecretsRefresh".to_string(),
+        Cron::new("* * * * *").parse()?,
+        Box::new(SecretsRefreshTask {
+            config: config.clone(),
+            config_file,
+            last_refresh: Utc::now(),
+        }),
+    );
+
     loop {
         let start_time = std::time::SystemTime::now();
         debug!("The shepherd is checking the herd...");
@@ -101,9 +214,21 @@ pub async fn shepherd(
             session_cleaner.run_task(db.clone()),
             check_cert_changed.run_task(db.clone()),
             service_check_history_cleaner.run_task(db.clone()),
+            history_rollup.run_task(db.clone()),
+            notification_flush.run_task(db.clone()),
+            discovery_sync.run_task(db.clone()),
+            pause_resume.run_task(db.clone()),
+            passive_freshness.run_task(db.clone()),
+            group_status.run_task(db.clone()),
+            escalation.run_task(db.clone()),
+            reports.run_task(db.clone()),
+            secrets_refresh.run_task(db.clone()),
+            backup.run_task(db.clone()),
+            artifact_clean.run_task(db.clone()),
         ];
 
         futures::future::try_join_all(tasks).await?;
+        HEARTBEATS.beat(Component::Shepherd);
 
         // work out how long it took and go through to clean up
         let elapsed = start_time
@@ -124,6 +249,7 @@ mod tests {
 
     use super::*;
     use crate::db::tests::test_setup;
+    use opentelemetry::metrics::MeterProvider;
 
     #[tokio::test]
     async fn test_servicecheckcleantask() {
@@ -135,6 +261,13 @@ mod tests {
             .expect("Failed to run ServiceCheckCleanTask");
     }
     #[tokio::test]
+    async fn test_artifactcleantask() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+
+        let mut task = ArtifactCleanTask {};
+        task.run(db).await.expect("Failed to run ArtifactCleanTask");
+    }
+    #[tokio::test]
     async fn test_sessioncleantask() {
         let (db, _config) = test_setup().await.expect("Failed to set up tests");
 
@@ -163,10 +296,17 @@ mod tests {
         let (db, config) = test_setup().await.expect("Failed to set up tests");
 
         let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let (provider, _registry) = crate::metrics::new().expect("Failed to set up metrics");
 
         let res = tokio::time::timeout(
             std::time::Duration::from_secs(1),
-            super::shepherd(db, config, tx.clone()),
+            super::shepherd(
+                db,
+                config,
+                PathBuf::from("maremma.example.json"),
+                tx.clone(),
+                Arc::new(MeterProvider::meter(&provider, "maremma")),
+            ),
         )
         .await;
 
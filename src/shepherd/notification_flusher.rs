@@ -0,0 +1,14 @@
+//! Sends summaries of notifications that were held back by rate limiting
+
+use super::prelude::*;
+use crate::actions::dispatcher::DISPATCHER;
+
+pub(crate) struct NotificationFlushTask {}
+
+#[async_trait]
+impl CronTaskTrait for NotificationFlushTask {
+    async fn run(&mut self, _db: DatabaseConnection) -> Result<(), Error> {
+        DISPATCHER.lock().await.flush().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+//! Marks passive and heartbeat service checks Critical when nothing's been submitted for them in time
+
+use super::prelude::*;
+use crate::services::passive::expire_stale_checks;
+
+pub(crate) struct PassiveFreshnessTask {}
+
+#[async_trait]
+impl CronTaskTrait for PassiveFreshnessTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let expired = expire_stale_checks(&db, Utc::now())
+            .await
+            .inspect_err(|err| error!("Failed to check passive service freshness: {:?}", err))?;
+        if expired > 0 {
+            info!("{} passive service checks have gone stale", expired);
+        }
+        Ok(())
+    }
+}
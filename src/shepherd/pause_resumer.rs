@@ -0,0 +1,69 @@
+//! Re-enables paused service checks once their auto-resume time has passed
+
+use super::prelude::*;
+
+pub(crate) struct PauseResumeTask {}
+
+#[async_trait]
+impl CronTaskTrait for PauseResumeTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let resumed = entities::service_check::Entity::resume_expired(&db, Utc::now())
+            .await
+            .inspect_err(|err| error!("Failed to resume paused service checks: {:?}", err))?;
+        if resumed > 0 {
+            info!("Resumed {} paused service checks", resumed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use entities::service_check::{Entity as ServiceCheck, PauseTarget};
+
+    use super::*;
+    use crate::db::tests::test_setup;
+
+    #[tokio::test]
+    async fn test_pause_resume_task() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let service_check = ServiceCheck::find()
+            .one(&db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let target = PauseTarget::Host(service_check.host_id);
+
+        // one that's not due to resume yet, and one that is
+        let paused = ServiceCheck::pause(&db, target, Some(Utc::now() + Duration::hours(1)))
+            .await
+            .expect("Failed to pause checks");
+        assert!(paused > 0);
+        PauseResumeTask {}
+            .run(db.clone())
+            .await
+            .expect("Failed to run PauseResumeTask");
+        let found = ServiceCheck::find_by_id(service_check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Service check went missing");
+        assert_eq!(found.status, ServiceStatus::Disabled);
+        assert!(found.paused_until.is_some());
+
+        ServiceCheck::pause(&db, target, Some(Utc::now() - Duration::minutes(1)))
+            .await
+            .expect("Failed to pause checks");
+        PauseResumeTask {}
+            .run(db.clone())
+            .await
+            .expect("Failed to run PauseResumeTask");
+        let found = ServiceCheck::find_by_id(service_check.id)
+            .one(&db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Service check went missing");
+        assert_eq!(found.status, ServiceStatus::Pending);
+        assert_eq!(found.paused_until, None);
+    }
+}
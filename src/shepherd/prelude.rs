@@ -1,13 +1,13 @@
 pub(crate) use axum::async_trait;
 pub(crate) use chrono::{DateTime, Duration, Utc};
 pub(crate) use croner::Cron;
+pub(crate) use opentelemetry::metrics::{Counter, Meter};
+pub(crate) use opentelemetry::KeyValue;
 pub(crate) use sea_orm::prelude::Expr;
 pub(crate) use sea_orm::{
     ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult, Order, QueryFilter, QueryOrder,
     QuerySelect,
 };
-pub(crate) use std::sync::Arc;
-pub(crate) use tokio::sync::RwLock;
 pub(crate) use tracing::{debug, error, info, instrument, warn};
 pub(crate) use uuid::Uuid;
 
@@ -0,0 +1,121 @@
+//! Sends the scheduled summary reports from the `reports` config
+
+use std::collections::HashMap;
+
+use super::prelude::*;
+use crate::reports::Report;
+
+pub(crate) struct ReportTask {
+    pub(crate) config: SendableConfig,
+    /// When each report was last sent, or when the task started if it hasn't been yet
+    pub(crate) last_sent: HashMap<String, DateTime<Utc>>,
+    pub(crate) started: DateTime<Utc>,
+}
+
+impl ReportTask {
+    pub(crate) fn new(config: SendableConfig) -> Self {
+        Self {
+            config,
+            last_sent: HashMap::new(),
+            started: Utc::now(),
+        }
+    }
+
+    /// Sends any reports whose schedule has come around since they were last sent, returns how many were sent
+    async fn send_due(
+        &mut self,
+        db: &DatabaseConnection,
+        now: DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        // don't hold the config lock while the reports are sent
+        let (reports, targets) = {
+            let config = self.config.read().await;
+            (config.reports.clone(), config.notifications.targets.clone())
+        };
+        let mut sent = 0;
+        for (name, report_config) in reports.iter() {
+            let last_sent = *self.last_sent.entry(name.clone()).or_insert(self.started);
+            if report_config
+                .cron()?
+                .find_next_occurrence(&last_sent, false)?
+                > now
+            {
+                continue;
+            }
+            let report = Report::build(db, name, report_config, now)
+                .await
+                .inspect_err(|err| error!("Failed to build report '{}': {:?}", name, err))?;
+            let report_targets = report_config
+                .targets
+                .iter()
+                .filter_map(|target| targets.get(target))
+                .collect::<Vec<_>>();
+            info!(
+                "Sending report '{}' to {} targets",
+                name,
+                report.send(&report_targets).await
+            );
+            self.last_sent.insert(name.clone(), now);
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+#[async_trait]
+impl CronTaskTrait for ReportTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        self.send_due(&db, Utc::now()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use crate::reports::{ReportConfig, ReportPeriod};
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn test_report_task() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        config.write().await.reports.insert(
+            "daily".to_string(),
+            ReportConfig {
+                schedule: "0 8 * * *".to_string(),
+                period: ReportPeriod::Day,
+                // there's no target with this name, so nothing actually gets sent anywhere
+                targets: vec!["nowhere".to_string()],
+                host_groups: vec![],
+            },
+        );
+        let mut task = ReportTask::new(config);
+        task.started = Utc
+            .with_ymd_and_hms(2025, 1, 10, 9, 0, 0)
+            .single()
+            .expect("Failed to make a start time");
+        // it's not 8am again yet
+        assert_eq!(
+            task.send_due(&db, task.started + Duration::minutes(1))
+                .await
+                .expect("Failed to run report task"),
+            0
+        );
+        let tomorrow = task.started + Duration::days(1);
+        assert_eq!(
+            task.send_due(&db, tomorrow)
+                .await
+                .expect("Failed to run report task"),
+            1
+        );
+        assert_eq!(task.last_sent.get("daily"), Some(&tomorrow));
+        // and it's not sent twice
+        assert_eq!(
+            task.send_due(&db, tomorrow)
+                .await
+                .expect("Failed to run report task"),
+            0
+        );
+    }
+}
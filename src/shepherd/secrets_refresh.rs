@@ -0,0 +1,124 @@
+//! Fetches secrets from Vault again every so often, reloading the config if any of them have changed
+
+use std::path::PathBuf;
+
+use super::prelude::*;
+use crate::config::{apply_config, Configuration};
+use crate::db::update_db_from_config;
+use crate::secrets::secrets_changed;
+
+pub(crate) struct SecretsRefreshTask {
+    pub(crate) config: SendableConfig,
+    pub(crate) config_file: PathBuf,
+    pub(crate) last_refresh: DateTime<Utc>,
+}
+
+impl SecretsRefreshTask {
+    /// Loads the config again, handing the new secrets to the globals and the database
+    async fn reload(&self, db: DatabaseConnection) -> Result<(), Error> {
+        let config = Configuration::new(&self.config_file).await?;
+        apply_config(&config).await?;
+        *self.config.write().await = config;
+        update_db_from_config(db, self.config.clone()).await
+    }
+}
+
+#[async_trait]
+impl CronTaskTrait for SecretsRefreshTask {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
+        let Some(vault) = self.config.read().await.vault.clone() else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        if now - self.last_refresh < Duration::minutes(vault.refresh_minutes() as i64) {
+            return Ok(());
+        }
+        self.last_refresh = now;
+
+        if secrets_changed(&vault).await? {
+            info!("Secrets in Vault have changed, reloading the config");
+            self.reload(db).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::dispatcher::DISPATCHER;
+    use crate::actions::routing::NotificationTarget;
+    use crate::prelude::{json, test_setup};
+
+    #[tokio::test]
+    async fn test_secrets_refresh_without_vault() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        let last_refresh = Utc::now() - Duration::days(1);
+        let mut task = SecretsRefreshTask {
+            config,
+            config_file: PathBuf::from("maremma.example.json"),
+            last_refresh,
+        };
+        task.run(db)
+            .await
+            .expect("Failed to run SecretsRefreshTask");
+        // nothing to do without vault configured
+        assert_eq!(task.last_refresh, last_refresh);
+    }
+
+    #[tokio::test]
+    async fn test_secrets_refresh_reaches_dispatcher() {
+        let (db, config) = test_setup().await.expect("Failed to set up tests");
+        let _lock = crate::config::APPLY_CONFIG_LOCK.lock().await;
+
+        let token_file = tempfile::NamedTempFile::new().expect("Failed to create token file");
+        tokio::fs::write(token_file.path(), "first_token\n")
+            .await
+            .expect("Failed to write token file");
+        let target_name = format!("refresh_{}", Uuid::new_v4().simple());
+        let mut contents: serde_json::Value = serde_json::from_str(
+            &tokio::fs::read_to_string("maremma.example.json")
+                .await
+                .expect("Failed to read example config"),
+        )
+        .expect("Failed to parse example config");
+        contents["notifications"]["targets"][&target_name] = json!({
+            "type": "pushover",
+            "token": {"$secret": {"file": token_file.path()}},
+            "user": "user_key",
+        });
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create config file");
+        tokio::fs::write(config_file.path(), contents.to_string())
+            .await
+            .expect("Failed to write config file");
+
+        let task = SecretsRefreshTask {
+            config,
+            config_file: config_file.path().to_path_buf(),
+            last_refresh: Utc::now(),
+        };
+        let token = |expected: &str| NotificationTarget::Pushover {
+            token: expected.to_string(),
+            user: "user_key".to_string(),
+            device: None,
+            title: None,
+            rate_limit: None,
+        };
+
+        task.reload(db.clone()).await.expect("Failed to reload");
+        assert_eq!(
+            DISPATCHER.lock().await.target_config(&target_name),
+            Some(&token("first_token"))
+        );
+
+        // the secret's rotated
+        tokio::fs::write(token_file.path(), "second_token\n")
+            .await
+            .expect("Failed to write token file");
+        task.reload(db).await.expect("Failed to reload");
+        assert_eq!(
+            DISPATCHER.lock().await.target_config(&target_name),
+            Some(&token("second_token"))
+        );
+    }
+}
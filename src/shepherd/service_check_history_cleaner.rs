@@ -2,13 +2,24 @@
 
 use super::prelude::*;
 
+/// How many history entries are deleted at a time when pruning by age
+const AGE_PRUNE_BATCH_SIZE: u64 = 1000;
+
 pub(crate) struct ServiceCheckHistoryCleanerTask {
     config: SendableConfig,
+    history_purged: Counter<u64>,
 }
 
 impl ServiceCheckHistoryCleanerTask {
-    pub(crate) fn new(config: SendableConfig) -> Self {
-        Self { config }
+    pub(crate) fn new(config: SendableConfig, metrics_meter: &Meter) -> Self {
+        let history_purged = metrics_meter
+            .u64_counter("service_check_history_purged")
+            .with_description("Service check history entries deleted by the history cleaner")
+            .build();
+        Self {
+            config,
+            history_purged,
+        }
     }
 }
 
@@ -39,11 +50,10 @@ fn sch_counts_query() -> sea_orm::Select<entities::service_check_history::Entity
 
 #[async_trait]
 impl CronTaskTrait for ServiceCheckHistoryCleanerTask {
-    async fn run(&mut self, db: Arc<RwLock<DatabaseConnection>>) -> Result<(), Error> {
-        let db_writer = db.write().await;
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
         let sch_counts: Vec<SimpleSchCounts> = sch_counts_query()
             .into_model::<SimpleSchCounts>()
-            .all(&*db_writer)
+            .all(&db)
             .await
             .inspect_err(|err| error!("Service check history cleaner failed: {:?}", err))?;
 
@@ -52,7 +62,29 @@ impl CronTaskTrait for ServiceCheckHistoryCleanerTask {
             .map(|x| (x.service_check_id, x.count))
             .collect::<Vec<(_, _)>>();
 
-        let target_num = self.config.read().await.max_history_entries_per_check;
+        let (target_num, max_age_days) = {
+            let config = self.config.read().await;
+            (
+                config.max_history_entries_per_check,
+                config.max_history_age_days,
+            )
+        };
+
+        if let Some(max_age_days) = max_age_days {
+            let cutoff = Utc::now() - Duration::days(max_age_days as i64);
+            let res = entities::service_check_history::Entity::prune_in_batches(
+                &db,
+                cutoff,
+                AGE_PRUNE_BATCH_SIZE,
+            )
+            .await?;
+            self.history_purged
+                .add(res, &[KeyValue::new("reason", "age")]);
+            info!(
+                "Deleted {} service check history entries older than {} days",
+                res, max_age_days
+            );
+        }
 
         for (id, count) in sch_counts {
             if count as u64 <= target_num {
@@ -63,15 +95,17 @@ impl CronTaskTrait for ServiceCheckHistoryCleanerTask {
                 continue;
             }
             if let Some(target_service_check) = entities::service_check::Entity::find_by_id(id)
-                .one(&*db_writer)
+                .one(&db)
                 .await?
             {
                 let res = entities::service_check_history::Entity::head(
-                    &db_writer,
+                    &db,
                     Some(target_service_check.id),
                     target_num,
                 )
                 .await?;
+                self.history_purged
+                    .add(res, &[KeyValue::new("reason", "count")]);
                 info!(
                     "Deleted {} old service check history entries for {}",
                     res, target_service_check.id
@@ -87,18 +121,23 @@ mod tests {
     use crate::db::tests::test_setup_quieter;
     use crate::prelude::test_setup;
     use entities::service_check_history;
+    use opentelemetry::metrics::MeterProvider;
     use sea_orm::{ActiveModelTrait, ConnectionTrait, QueryTrait, Set};
     use uuid::Uuid;
 
     use super::*;
 
+    fn test_meter() -> Meter {
+        let (provider, _registry) = crate::metrics::new().expect("Failed to set up metrics");
+        MeterProvider::meter(&provider, "maremma")
+    }
+
     #[tokio::test]
     async fn test_service_check_history_cleaner() {
         let (db, config) = test_setup_quieter().await.expect("Failed to do test setup");
         config.write().await.max_history_entries_per_check = 1;
-        let db_writer = db.write().await;
         let valid_service_check = entities::service_check::Entity::find()
-            .one(&*db_writer)
+            .one(&db)
             .await
             .expect("Failed to query DB for service check")
             .expect("Failed to find service check");
@@ -116,22 +155,62 @@ mod tests {
                 time_elapsed: Set(0 as i64),
                 ..Default::default()
             }
-            .insert(&*db_writer)
+            .insert(&db)
             .await
             .expect("Failed to insert service check history for check 1");
         }
-        drop(db_writer);
 
-        let mut task = ServiceCheckHistoryCleanerTask::new(config);
+        let mut task = ServiceCheckHistoryCleanerTask::new(config, &test_meter());
 
         task.run(db).await.expect("Failed to run task");
     }
 
+    #[tokio::test]
+    async fn test_service_check_history_cleaner_by_age() {
+        let (db, config) = test_setup().await.expect("Failed to do test setup");
+        config.write().await.max_history_age_days = Some(7);
+        let valid_service_check = entities::service_check::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query DB for service check")
+            .expect("Failed to find service check");
+
+        for days_ago in [1, 30] {
+            service_check_history::ActiveModel {
+                id: Set(Uuid::new_v4()),
+                service_check_id: Set(valid_service_check.id),
+                timestamp: Set(chrono::Utc::now() - Duration::days(days_ago)),
+                status: Set(ServiceStatus::Ok),
+                result_text: Set("test".to_string()),
+                time_elapsed: Set(0),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await
+            .expect("Failed to insert service check history");
+        }
+
+        let mut task = ServiceCheckHistoryCleanerTask::new(config, &test_meter());
+        task.run(db.clone()).await.expect("Failed to run task");
+
+        let old_entries = service_check_history::Entity::find()
+            .filter(service_check_history::Column::Timestamp.lt(Utc::now() - Duration::days(7)))
+            .all(&db)
+            .await
+            .expect("Failed to query history");
+        assert!(old_entries.is_empty());
+        assert!(!service_check_history::Entity::find()
+            .all(&db)
+            .await
+            .expect("Failed to query history")
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn test_sch_counts_query() {
         let (db, _config) = test_setup().await.expect("Failed to do test setup");
         let query_as_string = sch_counts_query()
-            .build(db.read().await.get_database_backend())
+            .build(db.get_database_backend())
             .to_string();
         println!("{}", query_as_string);
 
@@ -6,7 +6,7 @@ pub(crate) struct SessionCleanTask {}
 
 #[async_trait]
 impl CronTaskTrait for SessionCleanTask {
-    async fn run(&mut self, db: Arc<RwLock<DatabaseConnection>>) -> Result<(), Error> {
+    async fn run(&mut self, db: DatabaseConnection) -> Result<(), Error> {
         debug!("Checking sessions for cleanup...");
 
         let res = entities::session::Entity::delete_many()
@@ -14,7 +14,7 @@ impl CronTaskTrait for SessionCleanTask {
                 entities::session::Column::Expiry
                     .lt(Utc::now() - chrono::Duration::hours(SESSION_EXPIRY_WINDOW_HOURS)),
             )
-            .exec(&*db.write().await)
+            .exec(&db)
             .await
             .inspect_err(|err| error!("Session cleaner failed: {:?}", err))?;
         if res.rows_affected == 0 {
@@ -0,0 +1,31 @@
+//! Waits for the signals that mean it's time to stop
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::error;
+
+/// Resolves when SIGINT (Ctrl-C) or SIGTERM is received, returning the signal's name
+pub async fn shutdown_signal() -> &'static str {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => Some(sigterm),
+        Err(err) => {
+            error!("Failed to listen for SIGTERM: {:?}", err);
+            None
+        }
+    };
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            if let Err(err) = res {
+                error!("Failed to listen for SIGINT: {:?}", err);
+                // don't shut down just because we couldn't listen
+                std::future::pending::<()>().await;
+            }
+            "SIGINT"
+        }
+        _ = async {
+            match sigterm.as_mut() {
+                Some(sigterm) => sigterm.recv().await,
+                None => std::future::pending().await,
+            }
+        } => "SIGTERM",
+    }
+}
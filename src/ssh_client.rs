@@ -0,0 +1,666 @@
+//! Async SSH client bits, shared by the SSH service and the SSH host check
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use russh::client;
+use russh::ChannelMsg;
+use russh_keys::key::PublicKey;
+use schemars::JsonSchema;
+use tokio::net::TcpStream;
+
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+/// How the server's host key is verified
+pub enum HostKeyVerification {
+    /// The key has to be in the known_hosts file, which defaults to `~/.ssh/known_hosts`
+    #[default]
+    KnownHosts,
+    /// The key's SHA256 fingerprint has to match, eg `SHA256:n19OSTQWsLsup1t5CFv4e0ZMqdt9rUQkBi7GM6g+c0Q`
+    Fingerprint(String),
+    /// Don't verify the host key at all, the connection can be intercepted
+    Insecure,
+}
+
+/// Strips the `SHA256:` prefix and any padding so fingerprints compare the same however they were copied
+fn normalize_fingerprint(fingerprint: &str) -> &str {
+    fingerprint
+        .trim()
+        .trim_start_matches("SHA256:")
+        .trim_end_matches('=')
+}
+
+/// Checks the server's host key, returning an error describing why it was rejected
+pub(crate) fn verify_host_key(
+    hostname: &str,
+    port: u16,
+    server_public_key: &PublicKey,
+    verification: &HostKeyVerification,
+    known_hosts_file: Option<&Path>,
+) -> Result<(), Error> {
+    match verification {
+        HostKeyVerification::Insecure => {
+            debug!(
+                "Not verifying host key for {}:{}, fingerprint is SHA256:{}",
+                hostname,
+                port,
+                server_public_key.fingerprint()
+            );
+            Ok(())
+        }
+        HostKeyVerification::Fingerprint(expected) => {
+            let fingerprint = server_public_key.fingerprint();
+            if normalize_fingerprint(&fingerprint) == normalize_fingerprint(expected) {
+                Ok(())
+            } else {
+                Err(Error::Ssh(format!(
+                    "Host key fingerprint for {}:{} is SHA256:{}, expected {}",
+                    hostname, port, fingerprint, expected
+                )))
+            }
+        }
+        HostKeyVerification::KnownHosts => {
+            let res = match known_hosts_file {
+                Some(known_hosts_file) => russh_keys::check_known_hosts_path(
+                    hostname,
+                    port,
+                    server_public_key,
+                    known_hosts_file,
+                ),
+                None => russh_keys::check_known_hosts(hostname, port, server_public_key),
+            };
+            match res {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(Error::Ssh(format!(
+                    "Host key for {}:{} isn't in known_hosts, fingerprint is SHA256:{}",
+                    hostname,
+                    port,
+                    server_public_key.fingerprint()
+                ))),
+                Err(russh_keys::Error::KeyChanged { line }) => Err(Error::Ssh(format!(
+                    "Host key for {}:{} doesn't match known_hosts line {}, fingerprint is SHA256:{}",
+                    hostname,
+                    port,
+                    line,
+                    server_public_key.fingerprint()
+                ))),
+                Err(err) => Err(Error::Ssh(format!(
+                    "Failed to check known_hosts for {}:{}: {}",
+                    hostname, port, err
+                ))),
+            }
+        }
+    }
+}
+
+/// Handles the client side of the SSH session, which for us is just checking the host key
+pub(crate) struct SshClient {
+    hostname: String,
+    port: u16,
+    verification: HostKeyVerification,
+    known_hosts_file: Option<PathBuf>,
+}
+
+#[async_trait]
+impl client::Handler for SshClient {
+    type Error = Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        verify_host_key(
+            &self.hostname,
+            self.port,
+            server_public_key,
+            &self.verification,
+            self.known_hosts_file.as_deref(),
+        )?;
+        Ok(true)
+    }
+}
+
+/// Connects to the server and verifies its host key, giving up after `timeout`
+///
+/// The session's closed once it's been idle for `inactivity_timeout`.
+pub(crate) async fn connect(
+    hostname: &str,
+    port: u16,
+    verification: &HostKeyVerification,
+    known_hosts_file: Option<&Path>,
+    timeout: Duration,
+    inactivity_timeout: Duration,
+) -> Result<client::Handle<SshClient>, Error> {
+    let stream = tokio::time::timeout(timeout, TcpStream::connect((hostname, port)))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    handshake(
+        stream,
+        hostname,
+        port,
+        verification,
+        known_hosts_file,
+        timeout,
+        inactivity_timeout,
+    )
+    .await
+}
+
+/// Starts an SSH session over an existing connection and verifies the host key
+pub(crate) async fn handshake(
+    stream: TcpStream,
+    hostname: &str,
+    port: u16,
+    verification: &HostKeyVerification,
+    known_hosts_file: Option<&Path>,
+    timeout: Duration,
+    inactivity_timeout: Duration,
+) -> Result<client::Handle<SshClient>, Error> {
+    let config = Arc::new(client::Config {
+        inactivity_timeout: Some(inactivity_timeout),
+        ..Default::default()
+    });
+    let handler = SshClient {
+        hostname: hostname.to_string(),
+        port,
+        verification: verification.clone(),
+        known_hosts_file: known_hosts_file.map(Path::to_path_buf),
+    };
+    tokio::time::timeout(timeout, client::connect_stream(config, stream, handler))
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+#[derive(Debug, Default)]
+/// How to log in, each method that's set is tried in order: key, agent, password
+pub(crate) struct SshAuth<'a> {
+    pub private_key: Option<&'a Path>,
+    pub private_key_passphrase: Option<&'a str>,
+    pub use_agent: bool,
+    pub password: Option<&'a str>,
+}
+
+/// Logs in as `username`, trying each of the configured methods until one works
+pub(crate) async fn authenticate(
+    handle: &mut client::Handle<SshClient>,
+    username: &str,
+    auth: &SshAuth<'_>,
+) -> Result<(), Error> {
+    let mut tried = Vec::new();
+
+    if let Some(private_key) = auth.private_key {
+        let key = russh_keys::load_secret_key(private_key, auth.private_key_passphrase).map_err(
+            |err| {
+                Error::Ssh(format!(
+                    "Failed to load SSH key {}: {}",
+                    private_key.display(),
+                    err
+                ))
+            },
+        )?;
+        if handle
+            .authenticate_publickey(username, Arc::new(key))
+            .await?
+        {
+            return Ok(());
+        }
+        tried.push("private key");
+    }
+
+    if auth.use_agent {
+        let mut agent = russh_keys::agent::client::AgentClient::connect_env()
+            .await
+            .map_err(|err| Error::Ssh(format!("Failed to connect to SSH agent: {}", err)))?;
+        let identities = agent
+            .request_identities()
+            .await
+            .map_err(|err| Error::Ssh(format!("Failed to list SSH agent keys: {}", err)))?;
+        for key in identities {
+            let (returned_agent, res) = handle.authenticate_future(username, key, agent).await;
+            agent = returned_agent;
+            if res.map_err(|err| Error::Ssh(format!("SSH agent failed to sign: {:?}", err)))? {
+                return Ok(());
+            }
+        }
+        tried.push("agent");
+    }
+
+    if let Some(password) = auth.password {
+        if handle.authenticate_password(username, password).await? {
+            return Ok(());
+        }
+        tried.push("password");
+    }
+
+    Err(Error::Ssh(match tried.is_empty() {
+        true => "No SSH authentication methods configured".to_string(),
+        false => format!(
+            "Authentication failed for {}, tried {}",
+            username,
+            tried.join(", ")
+        ),
+    }))
+}
+
+#[derive(Debug, Default)]
+/// What came back from running a command
+pub(crate) struct CommandOutput {
+    pub exit_status: Option<u32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Runs a command and collects its output, wrap it in a timeout because it waits for the command to finish
+pub(crate) async fn run_command(
+    handle: &client::Handle<SshClient>,
+    command: &str,
+) -> Result<CommandOutput, Error> {
+    let mut channel = handle.channel_open_session().await?;
+    channel.exec(true, command).await?;
+
+    let mut output = CommandOutput::default();
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::Data { ref data } => output.stdout.extend_from_slice(data),
+            // 1 is SSH_EXTENDED_DATA_STDERR
+            ChannelMsg::ExtendedData { ref data, ext: 1 } => output.stderr.extend_from_slice(data),
+            ChannelMsg::ExitStatus { exit_status } => output.exit_status = Some(exit_status),
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+/// Closes the connection, errors are ignored because we're done with it anyway
+pub(crate) async fn disconnect(handle: &client::Handle<SshClient>) {
+    if let Err(err) = handle
+        .disconnect(russh::Disconnect::ByApplication, "", "en")
+        .await
+    {
+        debug!("Failed to cleanly disconnect SSH session: {:?}", err);
+    }
+}
+
+/// How long an unused pooled connection is kept open, in seconds
+pub const DEFAULT_SSH_POOL_IDLE_TIMEOUT_SECONDS: u64 = 60;
+/// How many commands can run at once over a pooled connection, OpenSSH's `MaxSessions` defaults to 10
+pub const DEFAULT_SSH_POOL_MAX_SESSIONS: usize = 10;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_idle_timeout_seconds() -> u64 {
+    DEFAULT_SSH_POOL_IDLE_TIMEOUT_SECONDS
+}
+
+fn default_max_sessions() -> usize {
+    DEFAULT_SSH_POOL_MAX_SESSIONS
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+/// Settings for sharing SSH connections between checks on the same host
+pub struct SshPoolConfig {
+    /// Reuse connections, if it's off every check opens its own, defaults to true
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// How long an unused connection is kept open, defaults to [DEFAULT_SSH_POOL_IDLE_TIMEOUT_SECONDS]
+    #[serde(default = "default_idle_timeout_seconds")]
+    pub idle_timeout_seconds: u64,
+    /// How many commands can run at once over a connection before another's opened, defaults to [DEFAULT_SSH_POOL_MAX_SESSIONS]
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+}
+
+impl Default for SshPoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_timeout_seconds: DEFAULT_SSH_POOL_IDLE_TIMEOUT_SECONDS,
+            max_sessions: DEFAULT_SSH_POOL_MAX_SESSIONS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Connections are only shared when they were set up and logged into the same way
+pub(crate) struct PoolKey {
+    pub hostname: String,
+    pub port: u16,
+    pub username: String,
+    pub private_key: Option<PathBuf>,
+    pub use_agent: bool,
+    pub password: Option<String>,
+    pub verification: HostKeyVerification,
+    pub known_hosts_file: Option<PathBuf>,
+}
+
+struct PooledConnection {
+    handle: Arc<client::Handle<SshClient>>,
+    in_use: usize,
+    last_used: Instant,
+}
+
+/// Disconnects in the background, because it's called from places that can't wait
+fn disconnect_later(handle: Arc<client::Handle<SshClient>>) {
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        runtime.spawn(async move { disconnect(&handle).await });
+    }
+}
+
+#[derive(Default)]
+/// Logged-in SSH connections, shared by checks against the same host so they're not opening a session each
+pub struct SshPool {
+    config: std::sync::RwLock<SshPoolConfig>,
+    connections: std::sync::Mutex<HashMap<PoolKey, Vec<PooledConnection>>>,
+}
+
+/// The pool used by the SSH service and host checks
+pub static POOL: LazyLock<SshPool> = LazyLock::new(SshPool::default);
+
+impl SshPool {
+    /// Updates the pool settings, existing connections are dropped if pooling's turned off
+    pub fn configure(&self, config: SshPoolConfig) {
+        if let Ok(mut current) = self.config.write() {
+            *current = config;
+        }
+        if !config.enabled {
+            self.clear();
+        }
+    }
+
+    fn config(&self) -> SshPoolConfig {
+        self.config.read().map(|config| *config).unwrap_or_default()
+    }
+
+    /// Disconnects everything in the pool
+    pub fn clear(&self) {
+        if let Ok(mut connections) = self.connections.lock() {
+            for (_, pooled) in connections.drain() {
+                pooled
+                    .into_iter()
+                    .for_each(|conn| disconnect_later(conn.handle));
+            }
+        }
+    }
+
+    /// Drops closed connections and ones that have been idle too long
+    fn reap(connections: &mut HashMap<PoolKey, Vec<PooledConnection>>, idle_timeout: Duration) {
+        for pooled in connections.values_mut() {
+            pooled.retain(|conn| {
+                let expired = conn.handle.is_closed()
+                    || (conn.in_use == 0 && conn.last_used.elapsed() > idle_timeout);
+                if expired {
+                    disconnect_later(conn.handle.clone());
+                }
+                !expired
+            });
+        }
+        connections.retain(|_, pooled| !pooled.is_empty());
+    }
+
+    /// Finds a pooled connection with a free session and marks it used
+    fn checkout(
+        &self,
+        key: &PoolKey,
+        config: &SshPoolConfig,
+    ) -> Option<Arc<client::Handle<SshClient>>> {
+        let mut connections = self.connections.lock().ok()?;
+        Self::reap(
+            &mut connections,
+            Duration::from_secs(config.idle_timeout_seconds),
+        );
+        let conn = connections
+            .get_mut(key)?
+            .iter_mut()
+            .find(|conn| conn.in_use < config.max_sessions.max(1))?;
+        conn.in_use += 1;
+        conn.last_used = Instant::now();
+        Some(conn.handle.clone())
+    }
+
+    fn release(&self, key: &PoolKey, handle: &Arc<client::Handle<SshClient>>, discard: bool) {
+        let Ok(mut connections) = self.connections.lock() else {
+            return;
+        };
+        if let Some(pooled) = connections.get_mut(key) {
+            if discard {
+                pooled.retain(|conn| !Arc::ptr_eq(&conn.handle, handle));
+            } else if let Some(conn) = pooled
+                .iter_mut()
+                .find(|conn| Arc::ptr_eq(&conn.handle, handle))
+            {
+                conn.in_use = conn.in_use.saturating_sub(1);
+                conn.last_used = Instant::now();
+            }
+        }
+    }
+
+    /// If there's an open connection to the host, for the host check to use instead of connecting again
+    pub(crate) fn has_connection(&self, hostname: &str, port: u16) -> bool {
+        self.connections
+            .lock()
+            .map(|connections| {
+                connections.iter().any(|(key, pooled)| {
+                    key.hostname == hostname
+                        && key.port == port
+                        && pooled.iter().any(|conn| !conn.handle.is_closed())
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Gets a logged-in session, reusing a pooled connection if there's one free
+    pub(crate) async fn session(
+        &self,
+        key: PoolKey,
+        auth: &SshAuth<'_>,
+        timeout: Duration,
+    ) -> Result<PooledSession<'_>, Error> {
+        let config = self.config();
+        if config.enabled {
+            if let Some(handle) = self.checkout(&key, &config) {
+                debug!("Reusing SSH connection to {}:{}", key.hostname, key.port);
+                return Ok(PooledSession {
+                    pool: self,
+                    key,
+                    handle,
+                    pooled: true,
+                    discard: false,
+                });
+            }
+        }
+
+        let inactivity_timeout = match config.enabled {
+            true => timeout.max(Duration::from_secs(config.idle_timeout_seconds)),
+            false => timeout,
+        };
+        let mut handle = connect(
+            &key.hostname,
+            key.port,
+            &key.verification,
+            key.known_hosts_file.as_deref(),
+            timeout,
+            inactivity_timeout,
+        )
+        .await?;
+        if let Err(err) = authenticate(&mut handle, &key.username, auth).await {
+            disconnect(&handle).await;
+            return Err(err);
+        }
+        let handle = Arc::new(handle);
+
+        if config.enabled {
+            if let Ok(mut connections) = self.connections.lock() {
+                connections
+                    .entry(key.clone())
+                    .or_default()
+                    .push(PooledConnection {
+                        handle: handle.clone(),
+                        in_use: 1,
+                        last_used: Instant::now(),
+                    });
+            }
+        }
+        Ok(PooledSession {
+            pool: self,
+            key,
+            handle,
+            pooled: config.enabled,
+            discard: false,
+        })
+    }
+}
+
+/// A logged-in session, handed back to the pool when it's dropped
+pub(crate) struct PooledSession<'a> {
+    pool: &'a SshPool,
+    key: PoolKey,
+    handle: Arc<client::Handle<SshClient>>,
+    pooled: bool,
+    discard: bool,
+}
+
+impl PooledSession<'_> {
+    /// Don't reuse this connection, because something went wrong with it
+    pub(crate) fn discard(mut self) {
+        self.discard = true;
+    }
+}
+
+impl Deref for PooledSession<'_> {
+    type Target = client::Handle<SshClient>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl Drop for PooledSession<'_> {
+    fn drop(&mut self) {
+        if self.pooled {
+            self.pool.release(&self.key, &self.handle, self.discard);
+        }
+        if !self.pooled || self.discard {
+            disconnect_later(self.handle.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIL/tDFrWAgNxDJTNAEAuemGBTJGUzdT7wHS9V9kT8TD4";
+    const TEST_KEY_FINGERPRINT: &str = "SHA256:n19OSTQWsLsup1t5CFv4e0ZMqdt9rUQkBi7GM6g+c0Q";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAILc7zLP/CEklzn3y117blik1gfUVFpORfleblqwWuJg+";
+
+    #[test]
+    fn test_verify_host_key() {
+        let key = russh_keys::parse_public_key_base64(TEST_KEY).expect("Failed to parse test key");
+
+        assert!(verify_host_key(
+            "example.com",
+            22,
+            &key,
+            &HostKeyVerification::Insecure,
+            None
+        )
+        .is_ok());
+        assert!(verify_host_key(
+            "example.com",
+            22,
+            &key,
+            &HostKeyVerification::Fingerprint(TEST_KEY_FINGERPRINT.to_string()),
+            None
+        )
+        .is_ok());
+        assert!(verify_host_key(
+            "example.com",
+            22,
+            &key,
+            &HostKeyVerification::Fingerprint("SHA256:nope".to_string()),
+            None
+        )
+        .is_err());
+
+        let known_hosts = tempfile::NamedTempFile::new().expect("Failed to create known_hosts");
+        std::fs::write(
+            known_hosts.path(),
+            format!(
+                "example.com ssh-ed25519 {}\n[example.com]:2222 ssh-ed25519 {}\n",
+                TEST_KEY, OTHER_KEY
+            ),
+        )
+        .expect("Failed to write known_hosts");
+
+        let verify = |hostname: &str, port: u16| {
+            verify_host_key(
+                hostname,
+                port,
+                &key,
+                &HostKeyVerification::KnownHosts,
+                Some(known_hosts.path()),
+            )
+        };
+        assert!(verify("example.com", 22).is_ok());
+        // the key's changed
+        assert!(
+            matches!(verify("example.com", 2222), Err(Error::Ssh(message)) if message.contains("line 2"))
+        );
+        // never seen it before
+        assert!(verify("example.org", 22).is_err());
+    }
+
+    #[test]
+    fn test_host_key_verification_parse() {
+        for (value, expected) in [
+            (json!("known_hosts"), HostKeyVerification::KnownHosts),
+            (json!("insecure"), HostKeyVerification::Insecure),
+            (
+                json!({"fingerprint": TEST_KEY_FINGERPRINT}),
+                HostKeyVerification::Fingerprint(TEST_KEY_FINGERPRINT.to_string()),
+            ),
+        ] {
+            assert_eq!(
+                serde_json::from_value::<HostKeyVerification>(value)
+                    .expect("Failed to parse host key verification"),
+                expected
+            );
+        }
+        assert_eq!(normalize_fingerprint(" SHA256:abc= "), "abc");
+    }
+
+    #[tokio::test]
+    async fn test_pool_config() {
+        assert_eq!(
+            serde_json::from_value::<SshPoolConfig>(json!({}))
+                .expect("Failed to parse pool config"),
+            SshPoolConfig::default()
+        );
+
+        let pool = SshPool::default();
+        pool.configure(SshPoolConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        assert!(!pool.config().enabled);
+        assert!(!pool.has_connection("example.com", 22));
+
+        let key = PoolKey {
+            hostname: "example.com".to_string(),
+            port: 22,
+            username: "maremma".to_string(),
+            private_key: None,
+            use_agent: false,
+            password: None,
+            verification: HostKeyVerification::Insecure,
+            known_hosts_file: None,
+        };
+        assert!(pool.checkout(&key, &pool.config()).is_none());
+    }
+}
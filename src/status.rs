@@ -0,0 +1,232 @@
+//! Implements the `status` CLI command, a terminal view of the service checks
+
+use entities::service_check::FullServiceCheck;
+use sea_orm::{Condition, QueryOrder};
+
+use crate::cli::{OutputFormat, StatusCmd};
+use crate::prelude::*;
+
+/// How much of the result text is shown in the table
+const RESULT_TEXT_WIDTH: usize = 60;
+
+/// Parses a status name for the `--status` filter
+pub fn parse_status(value: &str) -> Result<ServiceStatus, String> {
+    serde_json::from_value(json!(value.to_lowercase()))
+        .map_err(|_| format!("Unknown status '{}'", value))
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// A row in the status output
+pub struct StatusRow {
+    /// The service check ID
+    pub id: Uuid,
+    /// The host's name
+    pub host: String,
+    /// The service's name
+    pub service: String,
+    /// The kind of service
+    pub service_type: ServiceType,
+    /// The current status
+    pub status: ServiceStatus,
+    /// When it was last checked
+    pub last_check: DateTime<Utc>,
+    /// When it's next due
+    pub next_check: DateTime<Utc>,
+    /// The text from the last result, if there is one
+    pub result_text: Option<String>,
+}
+
+/// Finds the service checks matching the command's filters, worst first
+pub async fn get_status(db: &DatabaseConnection, cmd: &StatusCmd) -> Result<Vec<StatusRow>, Error> {
+    let mut query = FullServiceCheck::all_query();
+    if let Some(host) = &cmd.host {
+        query = query.filter(
+            Condition::any()
+                .add(entities::host::Column::Name.eq(host))
+                .add(entities::host::Column::Hostname.eq(host)),
+        );
+    }
+    if let Some(service) = &cmd.service {
+        query = query.filter(entities::service::Column::Name.eq(service));
+    }
+    if let Some(status) = cmd.status {
+        query = query.filter(entities::service_check::Column::Status.eq(status));
+    }
+    let mut checks = query
+        .order_by_asc(entities::host::Column::Name)
+        .order_by_asc(entities::service::Column::Name)
+        .into_model::<FullServiceCheck>()
+        .all(db)
+        .await?;
+    checks.sort_by(|a, b| b.status.cmp(&a.status));
+
+    let mut rows = Vec::with_capacity(checks.len());
+    for check in checks {
+        let result_text = entities::service_check_history::Entity::find()
+            .filter(entities::service_check_history::Column::ServiceCheckId.eq(check.id))
+            .order_by_desc(entities::service_check_history::Column::Timestamp)
+            .one(db)
+            .await?
+            .map(|history| history.result_text);
+        rows.push(StatusRow {
+            id: check.id,
+            host: check.host_name,
+            service: check.service_name,
+            service_type: check.service_type,
+            status: check.status,
+            last_check: check.last_check,
+            next_check: check.next_check,
+            result_text,
+        });
+    }
+    Ok(rows)
+}
+
+/// Squashes the result text onto one line and cuts it to fit the table
+fn short_text(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(RESULT_TEXT_WIDTH) {
+        Some((index, _)) => format!("{}...", &text[..index]),
+        None => text,
+    }
+}
+
+/// Renders the rows as a table or JSON
+pub fn render_status(rows: &[StatusRow], output: OutputFormat) -> Result<String, Error> {
+    if output == OutputFormat::Json {
+        return Ok(serde_json::to_string_pretty(rows)?);
+    }
+    if rows.is_empty() {
+        return Ok("No matching service checks".to_string());
+    }
+
+    let mut table = vec![[
+        "HOST".to_string(),
+        "SERVICE".to_string(),
+        "STATUS".to_string(),
+        "LAST CHECK".to_string(),
+        "NEXT CHECK".to_string(),
+        "RESULT".to_string(),
+    ]];
+    let time_format = "%Y-%m-%d %H:%M:%S";
+    table.extend(rows.iter().map(|row| {
+        [
+            row.host.clone(),
+            row.service.clone(),
+            row.status.to_string(),
+            row.last_check
+                .with_timezone(&Local)
+                .format(time_format)
+                .to_string(),
+            row.next_check
+                .with_timezone(&Local)
+                .format(time_format)
+                .to_string(),
+            row.result_text
+                .as_deref()
+                .map(short_text)
+                .unwrap_or_default(),
+        ]
+    }));
+
+    let mut widths = [0; 6];
+    for line in table.iter() {
+        for (width, cell) in widths.iter_mut().zip(line.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    Ok(table
+        .iter()
+        .map(|line| {
+            line.iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Prints the status of the matching service checks
+pub async fn run_status(cmd: StatusCmd, db: &DatabaseConnection) -> Result<(), Error> {
+    let rows = get_status(db, &cmd).await?;
+    println!("{}", render_status(&rows, cmd.format)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::SharedOpts;
+    use crate::db::tests::test_setup;
+
+    fn cmd() -> StatusCmd {
+        StatusCmd {
+            sharedopts: SharedOpts::default(),
+            host: None,
+            service: None,
+            status: None,
+            format: OutputFormat::Text,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let (db, _config) = test_setup().await.expect("Failed to setup test");
+
+        let all = get_status(&db, &cmd()).await.expect("Failed to get status");
+        assert!(!all.is_empty());
+        assert!(all.windows(2).all(|pair| pair[0].status >= pair[1].status));
+
+        let first = all.first().expect("No rows");
+        let host_rows = get_status(
+            &db,
+            &StatusCmd {
+                host: Some(first.host.clone()),
+                ..cmd()
+            },
+        )
+        .await
+        .expect("Failed to get status");
+        assert!(!host_rows.is_empty());
+        assert!(host_rows.iter().all(|row| row.host == first.host));
+
+        let none = get_status(
+            &db,
+            &StatusCmd {
+                service: Some("this service doesn't exist".to_string()),
+                ..cmd()
+            },
+        )
+        .await
+        .expect("Failed to get status");
+        assert!(none.is_empty());
+        assert_eq!(
+            render_status(&none, OutputFormat::Text).expect("Failed to render"),
+            "No matching service checks"
+        );
+
+        let table = render_status(&all, OutputFormat::Text).expect("Failed to render");
+        assert!(table.starts_with("HOST"));
+        assert_eq!(table.lines().count(), all.len() + 1);
+        let parsed: Value = serde_json::from_str(
+            &render_status(&all, OutputFormat::Json).expect("Failed to render"),
+        )
+        .expect("Failed to parse JSON");
+        assert_eq!(parsed.as_array().map(|rows| rows.len()), Some(all.len()));
+    }
+
+    #[test]
+    fn test_parse_status_and_short_text() {
+        assert_eq!(parse_status("Critical"), Ok(ServiceStatus::Critical));
+        assert!(parse_status("sideways").is_err());
+        assert_eq!(short_text("one\ntwo   three"), "one two three");
+        assert_eq!(
+            short_text(&"x".repeat(100)),
+            format!("{}...", "x".repeat(RESULT_TEXT_WIDTH))
+        );
+    }
+}
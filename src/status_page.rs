@@ -0,0 +1,392 @@
+//! A public status page for a curated set of services, which only ever shows display names
+
+use std::collections::HashSet;
+
+use sea_orm::{Condition, QueryOrder};
+
+use crate::db::entities::incident::duration_text;
+use crate::db::entities::service_check::FullServiceCheck;
+use crate::group_status::{host_status, is_failing};
+use crate::prelude::*;
+
+/// How many incidents the status page shows
+const MAX_STATUS_PAGE_INCIDENTS: usize = 20;
+
+fn default_incident_days() -> u32 {
+    7
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// Something shown on the status page, made up of the checks that match its host groups and services
+pub struct StatusComponent {
+    /// The name shown on the page
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Shown under the name
+    pub description: Option<String>,
+    #[serde(default)]
+    /// Only include hosts in these groups, every host if it's empty
+    pub host_groups: Vec<String>,
+    #[serde(default)]
+    /// Only include these services, every service if it's empty
+    pub services: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `status_page` section of the config, the page is served at `/status` without logging in
+pub struct StatusPageConfig {
+    #[serde(default)]
+    /// Serve the status page
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The page's title, defaults to "Status"
+    pub title: Option<String>,
+    #[serde(default)]
+    /// What's shown, in order
+    pub components: Vec<StatusComponent>,
+    #[serde(default = "default_incident_days")]
+    /// How many days of incidents to show
+    pub incident_days: u32,
+}
+
+impl Default for StatusPageConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            title: None,
+            components: vec![],
+            incident_days: default_incident_days(),
+        }
+    }
+}
+
+impl StatusPageConfig {
+    /// Checks the components can be told apart, and that they don't match everything by accident
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.incident_days == 0 {
+            return Err(Error::Configuration(
+                "status_page incident_days must be at least 1".to_string(),
+            ));
+        }
+        let mut names = HashSet::new();
+        for component in self.components.iter() {
+            if component.name.trim().is_empty() {
+                return Err(Error::Configuration(
+                    "status_page components need a name".to_string(),
+                ));
+            }
+            if !names.insert(entities::name_key(&component.name)) {
+                return Err(Error::Configuration(format!(
+                    "status_page component {} is listed more than once",
+                    component.name
+                )));
+            }
+            if component.host_groups.is_empty() && component.services.is_empty() {
+                return Err(Error::Configuration(format!(
+                    "status_page component {} needs host_groups or services",
+                    component.name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+/// The public version of a status, without the detail of what went wrong, ordered from best to worst
+pub enum ComponentState {
+    /// Everything's fine
+    Operational,
+    /// There aren't any results yet
+    Unknown,
+    /// Something's not right, but it's still working
+    Degraded,
+    /// It's down
+    Outage,
+}
+
+impl From<ServiceStatus> for ComponentState {
+    fn from(status: ServiceStatus) -> Self {
+        match status {
+            ServiceStatus::Ok => Self::Operational,
+            status if is_failing(status) => Self::Outage,
+            ServiceStatus::Warning | ServiceStatus::ConfigError => Self::Degraded,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for ComponentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Operational => "Operational",
+            Self::Degraded => "Degraded",
+            Self::Outage => "Outage",
+            Self::Unknown => "Unknown",
+        })
+    }
+}
+
+impl ComponentState {
+    /// The bootstrap colour for the state
+    pub fn as_html_class(self) -> &'static str {
+        match self {
+            Self::Operational => "success",
+            Self::Degraded => "warning",
+            Self::Outage => "danger",
+            Self::Unknown => "secondary",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// A component's current state
+pub struct ComponentStatus {
+    /// The component's display name
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The component's description
+    pub description: Option<String>,
+    /// The worst state of its checks
+    pub state: ComponentState,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// An incident, only identified by the component it affected
+pub struct PublicIncident {
+    /// The component's display name
+    pub component: String,
+    /// When it started
+    pub started_at: DateTime<Utc>,
+    /// When it was resolved, if it has been
+    pub ended_at: Option<DateTime<Utc>>,
+    /// The worst it got
+    pub state: ComponentState,
+}
+
+impl PublicIncident {
+    /// How long it lasted, for showing on the page
+    pub fn duration_text(&self) -> String {
+        duration_text(self.ended_at.unwrap_or(Utc::now()) - self.started_at)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// Everything on the status page
+pub struct StatusPage {
+    /// The page title
+    pub title: String,
+    /// The worst state of all the components
+    pub overall: ComponentState,
+    /// Each component's state, in the order they're configured
+    pub components: Vec<ComponentStatus>,
+    /// Recent incidents, newest first
+    pub incidents: Vec<PublicIncident>,
+    /// When this was worked out
+    pub updated: DateTime<Utc>,
+}
+
+impl StatusPage {
+    /// Works out the state of each component, and its recent incidents
+    pub async fn build(db: &DatabaseConnection, config: &StatusPageConfig) -> Result<Self, Error> {
+        let now = Utc::now();
+        let group_names = entities::host_group::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|group| (group.id, entities::name_key(&group.name)))
+            .collect::<HashMap<_, _>>();
+        // a host in a nested group is in the groups that include it too
+        let mut host_groups: HashMap<Uuid, HashSet<String>> = HashMap::new();
+        for (group_id, hosts) in entities::host_group_nesting::Entity::hosts_by_group(db).await? {
+            if let Some(name) = group_names.get(&group_id) {
+                for host_id in hosts {
+                    host_groups.entry(host_id).or_default().insert(name.clone());
+                }
+            }
+        }
+        let checks = FullServiceCheck::all(db).await?;
+
+        let mut components = Vec::with_capacity(config.components.len());
+        let mut component_checks: HashMap<Uuid, String> = HashMap::new();
+        for component in config.components.iter() {
+            let wanted_groups = component
+                .host_groups
+                .iter()
+                .map(|group| entities::name_key(group))
+                .collect::<HashSet<_>>();
+            let wanted_services = component
+                .services
+                .iter()
+                .map(|service| entities::name_key(service))
+                .collect::<HashSet<_>>();
+            let matching = checks
+                .iter()
+                .filter(|check| {
+                    wanted_services.is_empty()
+                        || wanted_services.contains(&entities::name_key(&check.service_name))
+                })
+                .filter(|check| {
+                    wanted_groups.is_empty()
+                        || host_groups
+                            .get(&check.host_id)
+                            .is_some_and(|groups| !groups.is_disjoint(&wanted_groups))
+                })
+                .collect::<Vec<_>>();
+            for check in matching.iter() {
+                // the first component a check's in is the one its incidents are shown against
+                component_checks
+                    .entry(check.id)
+                    .or_insert_with(|| component.name.clone());
+            }
+            components.push(ComponentStatus {
+                name: component.name.clone(),
+                description: component.description.clone(),
+                state: host_status(matching.iter().map(|check| check.status))
+                    .map(ComponentState::from)
+                    .unwrap_or(ComponentState::Unknown),
+            });
+        }
+
+        let mut incidents = vec![];
+        if !component_checks.is_empty() {
+            let since = now - TimeDelta::days(config.incident_days as i64);
+            let found = entities::incident::Entity::find()
+                .filter(
+                    entities::incident::Column::ServiceCheckId
+                        .is_in(component_checks.keys().copied()),
+                )
+                .filter(
+                    Condition::any()
+                        .add(entities::incident::Column::EndedAt.is_null())
+                        .add(entities::incident::Column::EndedAt.gte(since)),
+                )
+                .order_by_desc(entities::incident::Column::StartedAt)
+                .all(db)
+                .await?;
+            for incident in found {
+                let Some(component) = component_checks.get(&incident.service_check_id) else {
+                    continue;
+                };
+                // lots of hosts failing at once is one incident as far as the public's concerned
+                if let Some(existing) =
+                    incidents.iter_mut().find(|existing: &&mut PublicIncident| {
+                        existing.component == *component
+                            && existing.started_at <= incident.ended_at.unwrap_or(now)
+                            && incident.started_at <= existing.ended_at.unwrap_or(now)
+                    })
+                {
+                    existing.started_at = existing.started_at.min(incident.started_at);
+                    existing.ended_at = match (existing.ended_at, incident.ended_at) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        _ => None,
+                    };
+                    existing.state = existing
+                        .state
+                        .max(ComponentState::from(incident.worst_status));
+                    continue;
+                }
+                if incidents.len() >= MAX_STATUS_PAGE_INCIDENTS {
+                    continue;
+                }
+                incidents.push(PublicIncident {
+                    component: component.clone(),
+                    started_at: incident.started_at,
+                    ended_at: incident.ended_at,
+                    state: ComponentState::from(incident.worst_status),
+                });
+            }
+        }
+
+        Ok(Self {
+            title: config.title.clone().unwrap_or("Status".to_string()),
+            overall: components
+                .iter()
+                .map(|component| component.state)
+                .max()
+                .unwrap_or(ComponentState::Unknown),
+            components,
+            incidents,
+            updated: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_setup;
+    use sea_orm::Set;
+
+    #[tokio::test]
+    async fn test_build_status_page() {
+        let (db, _config) = test_setup().await.expect("Failed to set up tests");
+        let member = entities::host_group_members::Entity::find()
+            .one(&db)
+            .await
+            .expect("Failed to query group members")
+            .expect("No group members found");
+        let group = entities::host_group::Entity::find_by_id(member.group_id)
+            .one(&db)
+            .await
+            .expect("Failed to query group")
+            .expect("Group not found");
+        let host = entities::host::Entity::find_by_id(member.host_id)
+            .one(&db)
+            .await
+            .expect("Failed to query host")
+            .expect("Host not found");
+        let check = entities::service_check::Entity::find()
+            .filter(entities::service_check::Column::HostId.eq(host.id))
+            .one(&db)
+            .await
+            .expect("Failed to query checks")
+            .expect("Host has no checks");
+        let mut active = check.clone().into_active_model();
+        active.status = Set(ServiceStatus::Critical);
+        active.update(&db).await.expect("Failed to update check");
+        entities::incident::Entity::record(&db, check.id, ServiceStatus::Critical, Utc::now())
+            .await
+            .expect("Failed to record incident");
+
+        let config = StatusPageConfig {
+            enabled: true,
+            components: vec![StatusComponent {
+                name: "Website".to_string(),
+                description: None,
+                host_groups: vec![group.name.to_uppercase()],
+                services: vec![],
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let page = StatusPage::build(&db, &config)
+            .await
+            .expect("Failed to build status page");
+        assert_eq!(page.overall, ComponentState::Outage);
+        assert_eq!(
+            page.components.first().map(|component| component.state),
+            Some(ComponentState::Outage)
+        );
+        assert_eq!(
+            page.incidents
+                .first()
+                .map(|incident| incident.component.as_str()),
+            Some("Website")
+        );
+        let json = serde_json::to_string(&page).expect("Failed to serialize page");
+        assert!(!json.contains(&host.hostname));
+
+        let config = StatusPageConfig {
+            components: vec![StatusComponent {
+                name: "Everything".to_string(),
+                description: None,
+                host_groups: vec![],
+                services: vec![],
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
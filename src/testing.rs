@@ -0,0 +1,169 @@
+//! Helpers for testing service checks, including ones that live outside this crate.
+//!
+//! Turn on the `testing` feature to use them, eg `maremma = { version = "*", features = ["testing"] }` in `[dev-dependencies]`.
+
+use crate::db::entities::host;
+use crate::host::HostCheck;
+use crate::prelude::*;
+use serde::de::DeserializeOwned;
+
+/// Connects to a fresh in-memory database with all the migrations applied
+pub async fn test_db() -> Result<DatabaseConnection, Error> {
+    Ok(crate::db::test_connect().await?)
+}
+
+/// Builds a configuration from JSON, filling in what a test doesn't care about.
+///
+/// The database is in-memory, and the web-related settings get placeholder values unless they're set.
+pub async fn test_config(config: Value) -> Result<SendableConfig, Error> {
+    let mut config = match config {
+        Value::Object(config) => config,
+        _ => {
+            return Err(Error::Configuration(
+                "Test config must be a JSON object".to_string(),
+            ))
+        }
+    };
+    let defaults = [
+        ("database_file", json!(":memory:")),
+        ("hosts", json!({})),
+        ("frontend_url", json!("https://localhost:8888")),
+        ("oidc_issuer", json!("https://localhost")),
+        ("oidc_client_id", json!("maremma-test")),
+        // the real static files aren't needed, it just has to exist
+        ("static_path", json!(std::env::temp_dir())),
+    ];
+    for (key, value) in defaults {
+        config.entry(key).or_insert(value);
+    }
+    let config = Configuration::new_from_string(&Value::Object(config).to_string()).await?;
+    Ok(Arc::new(RwLock::new(config)))
+}
+
+/// Sets up an in-memory database and loads the configuration into it, like `maremma run` does at startup
+pub async fn setup_with_config(
+    config: Value,
+) -> Result<(DatabaseConnection, SendableConfig), Error> {
+    let config = test_config(config).await?;
+    let db = test_db().await?;
+    crate::db::update_db_from_config(db.clone(), config.clone()).await?;
+    Ok((db, config))
+}
+
+/// A host that's never saved to the database, for passing to [ServiceTrait::run]
+pub fn fake_host(name: &str, hostname: &str) -> host::Model {
+    host::Model {
+        id: Uuid::new_v4(),
+        name: name.to_string(),
+        slug: entities::slugify(name),
+        hostname: hostname.to_string(),
+        check: HostCheck::None,
+        config: json!({}),
+        vars: json!({}),
+        ipv4: None,
+        ipv6: None,
+        address_family: Default::default(),
+        display_name: None,
+        labels: json!({}),
+    }
+}
+
+/// Parses a service from its config, validates it, and runs it against the host
+pub async fn run_service<S>(config: &Value, host: &host::Model) -> Result<CheckResult, Error>
+where
+    S: ServiceTrait + DeserializeOwned,
+{
+    let service = S::from_config(config)?;
+    service.validate()?;
+    service.run(host).await
+}
+
+/// Parses a service from `overrides` on top of a `name` and an hourly `cron_schedule`, for tests that only care about a few settings
+pub fn service_from_json<S>(name: &str, overrides: Value) -> Result<S, Error>
+where
+    S: ServiceTrait + DeserializeOwned,
+{
+    let mut config = json!({
+        "name": name,
+        "cron_schedule": "@hourly",
+    });
+    if let (Some(config), Value::Object(overrides)) = (config.as_object_mut(), overrides) {
+        config.extend(overrides);
+    }
+    S::from_config(&config)
+}
+
+/// Asserts the check finished with the expected status, showing the result text if it didn't
+#[track_caller]
+pub fn assert_status(result: &CheckResult, status: ServiceStatus) {
+    assert_eq!(
+        result.status, status,
+        "Expected {} but got {}: {}",
+        status, result.status, result.result_text
+    );
+}
+
+/// Asserts the result text contains `text`
+#[track_caller]
+pub fn assert_result_contains(result: &CheckResult, text: &str) {
+    assert!(
+        result.result_text.contains(text),
+        "Expected result text to contain {:?}, got {:?}",
+        text,
+        result.result_text
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::cli::CliService;
+
+    #[tokio::test]
+    async fn test_testing_helpers() {
+        let host = fake_host("Test Host", "localhost");
+        assert_eq!(host.slug, "test-host");
+
+        let result = run_service::<CliService>(
+            &json!({
+                "name": "echo",
+                "command_line": "echo #HOSTNAME#",
+                "cron_schedule": "* * * * *",
+            }),
+            &host,
+        )
+        .await
+        .expect("Failed to run service");
+        assert_status(&result, ServiceStatus::Ok);
+        assert_result_contains(&result, "localhost");
+
+        let service: CliService = service_from_json("echo", json!({"command_line": "echo hello"}))
+            .expect("Failed to parse service");
+        assert_eq!(service.name, "echo");
+
+        let (db, config) = setup_with_config(json!({
+            "hosts": {"example": {"hostname": "example.com", "host_groups": ["test"]}},
+            "services": {
+                "echo": {
+                    "service_type": "cli",
+                    "host_groups": ["test"],
+                    "command_line": "echo hello",
+                    "cron_schedule": "* * * * *",
+                }
+            }
+        }))
+        .await
+        .expect("Failed to set up");
+        assert_eq!(config.read().await.hosts.len(), 1);
+        assert_eq!(
+            entities::service_check::Entity::find()
+                .all(&db)
+                .await
+                .expect("Failed to query service checks")
+                .len(),
+            1
+        );
+
+        assert!(test_config(json!([])).await.is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Getting and renewing the web server's certificate from an ACME CA, like Let's Encrypt
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use axum::http::Uri;
+use futures::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::ResolvesServerCertAcme;
+use tokio::task::JoinHandle;
+
+use crate::constants::DEFAULT_ACME_STATE_DIR;
+use crate::prelude::*;
+
+/// Let's Encrypt's production directory
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Let's Encrypt's staging directory, which has much higher rate limits but untrusted certificates
+pub const LETS_ENCRYPT_STAGING_DIRECTORY: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_ACME_STATE_DIR)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `acme` section of the config, when it's set `cert_file` and `cert_key` aren't used
+pub struct AcmeConfig {
+    #[serde(default)]
+    /// Names to put on the certificate, defaults to the host in `frontend_url`
+    pub domains: Vec<String>,
+    #[serde(default)]
+    /// Email addresses the CA can send expiry and account notices to
+    pub contact: Vec<String>,
+    #[serde(default = "default_state_dir")]
+    /// Where the account key and certificates are kept between restarts, defaults to `./acme`
+    pub state_dir: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The ACME directory URL, defaults to Let's Encrypt
+    pub directory: Option<String>,
+    #[serde(default)]
+    /// Use Let's Encrypt's staging directory, for testing
+    pub staging: bool,
+}
+
+impl AcmeConfig {
+    pub(crate) fn validate(&self, frontend_url: &str) -> Result<(), Error> {
+        self.domains(frontend_url)?;
+        if self.staging && self.directory.is_some() {
+            return Err(Error::Configuration(
+                "acme can't have both staging and directory set".to_string(),
+            ));
+        }
+        if let Some(contact) = self.contact.iter().find(|contact| !contact.contains('@')) {
+            return Err(Error::Configuration(format!(
+                "acme contact {} isn't an email address",
+                contact
+            )));
+        }
+        Ok(())
+    }
+
+    /// The names the certificate is for
+    pub(crate) fn domains(&self, frontend_url: &str) -> Result<Vec<String>, Error> {
+        if !self.domains.is_empty() {
+            return Ok(self.domains.clone());
+        }
+        Uri::from_str(frontend_url)
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_string))
+            .map(|host| vec![host])
+            .ok_or_else(|| {
+                Error::Configuration(format!(
+                    "acme.domains isn't set and there's no host in frontend_url {}",
+                    frontend_url
+                ))
+            })
+    }
+
+    pub(crate) fn directory_url(&self) -> &str {
+        match (&self.directory, self.staging) {
+            (Some(directory), _) => directory,
+            (None, true) => LETS_ENCRYPT_STAGING_DIRECTORY,
+            (None, false) => LETS_ENCRYPT_DIRECTORY,
+        }
+    }
+}
+
+/// The background task which orders and renews the certificate, it's stopped when this is dropped
+pub(crate) struct AcmeTask(JoinHandle<()>);
+
+impl Drop for AcmeTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Starts ordering and renewing the certificate, the resolver hands out the current one to rustls
+pub(crate) fn start(
+    config: &AcmeConfig,
+    frontend_url: &str,
+) -> Result<(Arc<ResolvesServerCertAcme>, AcmeTask), Error> {
+    let domains = config.domains(frontend_url)?;
+    info!(
+        "Getting a certificate for {} from {}",
+        domains.join(", "),
+        config.directory_url()
+    );
+    let mut state = rustls_acme::AcmeConfig::new(domains)
+        .contact(
+            config
+                .contact
+                .iter()
+                .map(|contact| format!("mailto:{}", contact)),
+        )
+        .cache(DirCache::new(config.state_dir.clone()))
+        .directory(config.directory_url())
+        .state();
+    let resolver = state.resolver();
+
+    let task = tokio::spawn(async move {
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => info!("ACME: {:?}", event),
+                Err(err) => error!("ACME error: {:?}", err),
+            }
+        }
+    });
+    Ok((resolver, AcmeTask(task)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acme_config() {
+        let config: AcmeConfig = serde_json::from_value(json!({})).expect("Failed to parse");
+        assert_eq!(config.state_dir, PathBuf::from(DEFAULT_ACME_STATE_DIR));
+        assert_eq!(config.directory_url(), LETS_ENCRYPT_DIRECTORY);
+        assert_eq!(
+            config
+                .domains("https://maremma.example.com:8888")
+                .expect("Failed to get domains"),
+            vec!["maremma.example.com".to_string()]
+        );
+        assert!(config.validate("https://maremma.example.com").is_ok());
+        assert!(config.validate("not a url").is_err());
+
+        let config: AcmeConfig = serde_json::from_value(json!({
+            "domains": ["a.example.com", "b.example.com"],
+            "contact": ["admin@example.com"],
+            "staging": true,
+        }))
+        .expect("Failed to parse");
+        assert_eq!(config.domains("not a url").expect("Failed").len(), 2);
+        assert_eq!(config.directory_url(), LETS_ENCRYPT_STAGING_DIRECTORY);
+        assert!(config.validate("not a url").is_ok());
+
+        let config = AcmeConfig {
+            directory: Some("https://acme.example.com/directory".to_string()),
+            ..config
+        };
+        assert!(config.validate("not a url").is_err());
+
+        let config = AcmeConfig {
+            staging: false,
+            contact: vec!["admin".to_string()],
+            ..config
+        };
+        assert_eq!(config.directory_url(), "https://acme.example.com/directory");
+        assert!(config.validate("not a url").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Choosing how users log in, and the local username and password mode for when there's no OIDC provider
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_oidc::{EmptyAdditionalClaims, OidcClaims};
+use openidconnect::{EndUserUsername, IdTokenClaims, IssuerUrl, StandardClaims, SubjectIdentifier};
+use tower_sessions::Session;
+
+use super::urls::Urls;
+use super::WebState;
+use crate::cli::LocalUserCmd;
+use crate::prelude::*;
+
+/// Where the logged in local user is kept in their session
+pub(crate) const SESSION_LOCAL_USER: &str = "local_user";
+
+/// `maremma local-user` reads the password from here if it's set, otherwise from stdin
+pub const LOCAL_USER_PASSWORD_ENV: &str = "MAREMMA_LOCAL_USER_PASSWORD";
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// How users log in to the web UI
+pub enum AuthMode {
+    #[default]
+    /// Through the OIDC provider in `oidc_issuer`
+    Oidc,
+    /// With a username and password, users are managed with `maremma local-user`
+    Local,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq, JsonSchema)]
+/// The `auth` section of the config
+pub struct AuthConfig {
+    #[serde(default)]
+    /// How users log in, the `oidc_*` settings aren't needed when it's `local`
+    pub mode: AuthMode,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// A local user who's logged in
+pub(crate) struct LocalSession {
+    pub id: Uuid,
+    pub username: String,
+}
+
+impl LocalSession {
+    /// Claims like an OIDC provider would hand out, so the views don't need to care how the user logged in
+    pub(crate) fn claims(&self, issuer: &str) -> Result<OidcClaims<EmptyAdditionalClaims>, Error> {
+        claims_for(issuer, &format!("local:{}", self.username), &self.username)
+    }
+}
+
+/// Builds claims for users who didn't log in through the OIDC provider
+pub(crate) fn claims_for(
+    issuer: &str,
+    subject: &str,
+    username: &str,
+) -> Result<OidcClaims<EmptyAdditionalClaims>, Error> {
+    let issuer = IssuerUrl::new(issuer.to_string())
+        .map_err(|err| Error::Configuration(format!("Invalid frontend_url: {:?}", err)))?;
+    let now = chrono::Utc::now();
+    Ok(OidcClaims(IdTokenClaims::new(
+        issuer,
+        vec![],
+        now + TimeDelta::hours(1),
+        now,
+        StandardClaims::new(SubjectIdentifier::new(subject.to_string()))
+            .set_preferred_username(Some(EndUserUsername::new(username.to_string()))),
+        EmptyAdditionalClaims {},
+    )))
+}
+
+/// Adds the logged in local user's claims to the request, where the views look for them
+pub(crate) async fn local_auth_layer(
+    State(state): State<WebState>,
+    session: Session,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match session.get::<LocalSession>(SESSION_LOCAL_USER).await {
+        Ok(Some(user)) => {
+            let issuer = state.configuration.read().await.frontend_url.clone();
+            match user.claims(&issuer) {
+                Ok(claims) => {
+                    request.extensions_mut().insert(claims);
+                }
+                Err(err) => error!("Failed to build claims for {}: {:?}", user.username, err),
+            }
+        }
+        Ok(None) => {}
+        Err(err) => error!("Failed to read local user from session: {:?}", err),
+    }
+    next.run(request).await
+}
+
+/// Sends anyone who isn't logged in to the login form
+pub(crate) async fn require_local_login(
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if claims.is_none() {
+        return Redirect::to(Urls::Login.as_ref()).into_response();
+    }
+    next.run(request).await
+}
+
+/// Implements `maremma local-user`
+pub async fn run_local_user(cmd: &LocalUserCmd, db: &DatabaseConnection) -> Result<(), Error> {
+    if cmd.delete {
+        if !entities::local_user::Entity::delete_by_username(db, &cmd.username).await? {
+            return Err(Error::InvalidInput(format!(
+                "There's no local user called {}",
+                cmd.username
+            )));
+        }
+        println!("Removed {}", cmd.username);
+        return Ok(());
+    }
+
+    let password = match std::env::var(LOCAL_USER_PASSWORD_ENV) {
+        Ok(password) => password,
+        Err(_) => {
+            eprint!("Password for {}: ", cmd.username);
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            password.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    let user = entities::local_user::Entity::set_password(db, &cmd.username, &password).await?;
+    println!("Set the password for {}", user.username);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::oidc::User;
+
+    #[test]
+    fn test_local_session_claims() {
+        let session = LocalSession {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+        };
+        let user = User::from(
+            session
+                .claims("https://maremma.example.com")
+                .expect("Failed to build claims"),
+        );
+        assert_eq!(user.username(), "admin");
+        assert_eq!(user.subject(), "local:admin");
+
+        assert!(session.claims("not a url").is_err());
+    }
+
+    #[test]
+    fn test_auth_config() {
+        let config: AuthConfig = serde_json::from_value(json!({})).expect("Failed to parse");
+        assert_eq!(config.mode, AuthMode::Oidc);
+        let config: AuthConfig =
+            serde_json::from_value(json!({"mode": "local"})).expect("Failed to parse");
+        assert_eq!(config.mode, AuthMode::Local);
+    }
+}
@@ -1,10 +1,13 @@
 //! Messages for controlling the server backend
 //!
 
+/// Tells the web server to stop or reload
 pub enum WebServerControl {
+    /// Stop the server
     Stop,
     /// Stop the server after a certain amount of milliseconds (1000 = 1 second)
     StopAfter(u64),
+    /// Reload the server, eg to pick up new certificates
     Reload,
     /// Reload the server after a certain amount of milliseconds (1000 = 1 second)
     ReloadAfter(u64),
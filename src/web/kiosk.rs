@@ -0,0 +1,188 @@
+//! Signed links to a read-only dashboard for wall-mounted displays, which can't keep a login session going
+//!
+//! The link carries its expiry time and an HMAC of it, so there's nothing to store and changing the
+//! secret invalidates every link that's been handed out.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::urls::Urls;
+use crate::prelude::*;
+
+/// The secret has to be at least this long, so the signatures can't be guessed
+const MIN_SECRET_LENGTH: usize = 32;
+
+fn default_page_refresh() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `kiosk` section of the config, links are made with `maremma kiosk-url`
+pub struct KioskConfig {
+    #[serde(serialize_with = "crate::serde::secret::serialize_str")]
+    /// Signs the links, changing it invalidates all of them. Can be a secret reference
+    pub secret: String,
+    #[serde(default = "default_page_refresh")]
+    /// How often the page reloads, in seconds
+    pub page_refresh: u64,
+}
+
+impl KioskConfig {
+    /// Checks the secret's long enough and the page doesn't reload constantly
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.secret.len() < MIN_SECRET_LENGTH {
+            return Err(Error::Configuration(format!(
+                "kiosk secret must be at least {} characters",
+                MIN_SECRET_LENGTH
+            )));
+        }
+        if self.page_refresh < 5 {
+            return Err(Error::Configuration(
+                "kiosk page_refresh must be at least 5 seconds".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn mac(&self, expires: i64) -> Result<Hmac<Sha256>, Error> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .map_err(|err| Error::Configuration(format!("Invalid kiosk secret: {:?}", err)))?;
+        mac.update(format!("kiosk:{}", expires).as_bytes());
+        Ok(mac)
+    }
+
+    /// The signature for a link which stops working at `expires`
+    pub fn sign(&self, expires: DateTime<Utc>) -> Result<String, Error> {
+        Ok(URL_SAFE_NO_PAD.encode(self.mac(expires.timestamp())?.finalize().into_bytes()))
+    }
+
+    /// Checks the link's signature, and that it hasn't expired
+    pub fn verify(&self, expires: i64, signature: &str, now: DateTime<Utc>) -> Result<(), Error> {
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| Error::Unauthorized)?;
+        self.mac(expires)?
+            .verify_slice(&signature)
+            .map_err(|_| Error::Unauthorized)?;
+        if expires <= now.timestamp() {
+            return Err(Error::Unauthorized);
+        }
+        Ok(())
+    }
+
+    /// The full link to the kiosk page
+    pub fn url(&self, frontend_url: &str, expires: DateTime<Utc>) -> Result<String, Error> {
+        Ok(format!(
+            "{}{}?expires={}&signature={}",
+            frontend_url.trim_end_matches('/'),
+            Urls::Kiosk,
+            expires.timestamp(),
+            self.sign(expires)?
+        ))
+    }
+}
+
+/// Implements `maremma kiosk-url`, the link works for `days` from now
+pub fn kiosk_url(config: &Configuration, days: u32) -> Result<String, Error> {
+    if days == 0 {
+        return Err(Error::InvalidInput(
+            "The link has to work for at least a day".to_string(),
+        ));
+    }
+    config
+        .kiosk
+        .as_ref()
+        .ok_or_else(|| {
+            Error::Configuration("Kiosk links need a kiosk section in the config".to_string())
+        })?
+        .url(
+            &config.frontend_url,
+            Utc::now() + TimeDelta::days(days as i64),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> KioskConfig {
+        KioskConfig {
+            secret: "a".repeat(MIN_SECRET_LENGTH),
+            page_refresh: default_page_refresh(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config().validate().is_ok());
+        assert!(KioskConfig {
+            secret: "short".to_string(),
+            ..config()
+        }
+        .validate()
+        .is_err());
+        assert!(KioskConfig {
+            page_refresh: 1,
+            ..config()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let config = config();
+        let now = Utc::now();
+        let expires = now + TimeDelta::days(1);
+        let signature = config.sign(expires).expect("Failed to sign");
+
+        assert!(config.verify(expires.timestamp(), &signature, now).is_ok());
+        // expired
+        assert!(config
+            .verify(
+                expires.timestamp(),
+                &signature,
+                expires + TimeDelta::seconds(1)
+            )
+            .is_err());
+        // someone moved the expiry
+        assert!(config
+            .verify(expires.timestamp() + 86400, &signature, now)
+            .is_err());
+        // a different secret
+        assert!(KioskConfig {
+            secret: "b".repeat(MIN_SECRET_LENGTH),
+            ..config.clone()
+        }
+        .verify(expires.timestamp(), &signature, now)
+        .is_err());
+        assert!(config
+            .verify(expires.timestamp(), "not base64!", now)
+            .is_err());
+
+        let mut maremma_config = Configuration {
+            frontend_url: "https://maremma.example.com".to_string(),
+            ..Default::default()
+        };
+        assert!(kiosk_url(&maremma_config, 7).is_err());
+        maremma_config.kiosk = Some(config.clone());
+        assert!(kiosk_url(&maremma_config, 0).is_err());
+        assert!(kiosk_url(&maremma_config, 7)
+            .expect("Failed to make a kiosk link")
+            .starts_with("https://maremma.example.com/kiosk?expires="));
+
+        let url = config
+            .url("https://maremma.example.com/", expires)
+            .expect("Failed to build URL");
+        assert_eq!(
+            url,
+            format!(
+                "https://maremma.example.com/kiosk?expires={}&signature={}",
+                expires.timestamp(),
+                signature
+            )
+        );
+    }
+}
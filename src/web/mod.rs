@@ -1,41 +1,49 @@
 //! Web server related functionality
 //!
 
-pub(crate) mod controller;
+pub mod acme;
+pub mod auth;
+pub mod controller;
+pub mod kiosk;
+pub mod mtls;
 pub(crate) mod oidc;
+pub(crate) mod preferences;
+pub mod proxy;
+pub mod session;
+pub mod theme;
+pub(crate) mod tls;
 pub(crate) mod urls;
 pub(crate) mod views;
 #[cfg(test)]
 use tempfile::NamedTempFile;
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use askama_axum::IntoResponse;
 use axum::error_handling::HandleErrorLayer;
-use axum::extract::State;
+use axum::extract::{DefaultBodyLimit, State};
 use axum::http::{StatusCode, Uri};
 use axum::response::Redirect;
 use axum::routing::{get, post};
 use axum::Router;
 use axum_oidc::error::MiddlewareError;
 use axum_oidc::{EmptyAdditionalClaims, OidcAuthLayer, OidcLoginLayer};
-use axum_server::bind_rustls;
-use axum_server::tls_rustls::RustlsConfig;
+use opentelemetry::metrics::Counter;
 use prometheus::Registry;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::RwLockReadGuard;
 use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
-use tower_sessions::{
-    cookie::{time::Duration, SameSite},
-    Expiry, SessionManagerLayer,
-};
 
+use crate::check_loop::{check_claim_conflicts, RunningChecks};
 use crate::constants::WEB_SERVER_DEFAULT_STATIC_PATH;
 use crate::prelude::*;
+use auth::AuthMode;
 use controller::WebServerControl;
+use proxy::{ClientAddress, ListenScheme};
 use urls::Urls;
 use views::handler_404;
 use views::host_group::{host_group, host_group_delete, host_group_member_delete, host_groups};
@@ -44,16 +52,18 @@ use views::service_check::{service_check_delete, service_check_get};
 
 #[derive(Clone)]
 pub(crate) struct WebState {
-    pub db: Arc<RwLock<DatabaseConnection>>,
+    pub db: DatabaseConnection,
     pub configuration: SendableConfig,
     pub registry: Option<Arc<Registry>>,
     pub web_tx: Option<Sender<WebServerControl>>,
     pub config_filepath: PathBuf,
+    pub running_checks: Arc<RunningChecks>,
+    pub check_claim_conflicts: Option<Counter<u64>>,
 }
 
 impl WebState {
     pub fn new(
-        db: Arc<RwLock<DatabaseConnection>>,
+        db: DatabaseConnection,
         configuration: SendableConfig,
         registry: Option<Arc<Registry>>,
         web_tx: Option<Sender<WebServerControl>>,
@@ -65,9 +75,23 @@ impl WebState {
             registry,
             web_tx,
             config_filepath,
+            running_checks: Arc::new(RunningChecks::default()),
+            check_claim_conflicts: None,
         }
     }
 
+    /// Share the check loop's running checks, so they can be cancelled from the UI
+    pub fn with_running_checks(mut self, running_checks: Arc<RunningChecks>) -> Self {
+        self.running_checks = running_checks;
+        self
+    }
+
+    /// Count the checks agents lose to another claimant with the check loop's metrics
+    pub fn with_metrics(mut self, metrics_meter: &Meter) -> Self {
+        self.check_claim_conflicts = Some(check_claim_conflicts(metrics_meter));
+        self
+    }
+
     #[cfg(test)]
     pub async fn test() -> Self {
         let (db, config) = crate::db::tests::test_setup()
@@ -115,7 +139,7 @@ async fn up(State(_state): State<WebState>) -> impl IntoResponse {
 }
 
 /// Create the database-backed session store
-pub fn get_session_store(db: &Arc<RwLock<DatabaseConnection>>) -> entities::session::ModelStore {
+pub fn get_session_store(db: &DatabaseConnection) -> entities::session::ModelStore {
     crate::db::entities::session::ModelStore::new(db.clone())
 }
 
@@ -142,10 +166,13 @@ impl OidcErrorHandler {
     }
 }
 
+/// Puts the login-required routes behind the OIDC provider
 #[cfg(not(tarpaulin_include))]
-pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
-    // get all the config variables we need, quickly, so we can drop the lock
-
+async fn with_oidc_auth(
+    app: Router<WebState>,
+    api: Router<WebState>,
+    state: &WebState,
+) -> Result<Router<WebState>, Error> {
     let config_reader = state.configuration.read().await;
     let oidc_issuer = config_reader.oidc_issuer.clone();
     let oidc_client_id = config_reader.oidc_client_id.clone();
@@ -153,14 +180,6 @@ pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
     let frontend_url = config_reader.frontend_url.clone();
     drop(config_reader);
 
-    let session_store = get_session_store(&state.db);
-
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(true)
-        .with_same_site(SameSite::Lax)
-        .with_http_only(true)
-        .with_expiry(Expiry::OnInactivity(Duration::seconds(1800)));
-
     let frontend_url = Uri::from_str(&frontend_url)
         .map_err(|err| Error::Configuration(format!("Failed to parse base_url: {:?}", err)))?;
     debug!("Frontend URL: {:?}", frontend_url);
@@ -200,13 +219,75 @@ pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
             })?,
         );
 
-    let app = Router::new()
+    Ok(app
         .route(
             Urls::Login.as_ref(),
             get(Redirect::temporary(Urls::Index.as_ref())),
         )
+        .route(Urls::RpLogout.as_ref(), get(oidc::rp_logout))
+        .layer(oidc_login_service)
+        // after here, the routers don't *require* auth
+        .merge(api)
+        .route(Urls::Index.as_ref(), get(views::index::index))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            preferences::preferences_layer,
+        ))
+        .layer(oidc_auth_layer))
+}
+
+/// Puts the login-required routes behind the local login form, for when there's no OIDC provider
+fn with_local_auth(
+    app: Router<WebState>,
+    api: Router<WebState>,
+    state: &WebState,
+) -> Router<WebState> {
+    app.layer(axum::middleware::from_fn(auth::require_local_login))
+        // after here, the routers don't *require* auth
+        .merge(api)
+        .route(Urls::Index.as_ref(), get(views::index::index))
+        .route(
+            Urls::Login.as_ref(),
+            get(views::login::login_form).post(views::login::login),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            preferences::preferences_layer,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::local_auth_layer,
+        ))
+}
+
+#[cfg(not(tarpaulin_include))]
+pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
+    let (auth_mode, session_layer) = {
+        let config = state.configuration.read().await;
+        (
+            config.auth.mode,
+            config
+                .session
+                .layer(get_session_store(&state.db), &config.frontend_url),
+        )
+    };
+
+    let app = Router::new()
         .route(Urls::Profile.as_ref(), get(views::profile::profile))
+        .route(
+            Urls::ProfilePreferences.as_ref(),
+            post(views::profile::set_preferences),
+        )
+        .route(
+            Urls::ProfileFavorite.as_ref(),
+            post(views::profile::toggle_favorite),
+        )
+        .route(Urls::Search.as_ref(), get(views::search::search))
         .route(Urls::Services.as_ref(), get(views::service::services))
+        .route(
+            &format!("{}/bulk", Urls::ServiceCheck),
+            post(views::bulk::bulk_service_checks),
+        )
         .route(
             &format!("{}/:service_check_id/urgent", Urls::ServiceCheck),
             post(views::service_check::set_service_check_urgent),
@@ -219,10 +300,29 @@ pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
             &format!("{}/:service_check_id/enable", Urls::ServiceCheck),
             post(views::service_check::set_service_check_enabled),
         )
+        .route(
+            &format!("{}/:service_check_id/cancel", Urls::ServiceCheck),
+            post(views::service_check::cancel_service_check),
+        )
+        .route(
+            &format!("{}/:service_check_id/acknowledge", Urls::ServiceCheck),
+            post(views::service_check::acknowledge_service_check),
+        )
         .route(
             &format!("{}/:service_check_id/delete", Urls::ServiceCheck),
             post(service_check_delete),
         )
+        .route(
+            &format!("{}/:service_check_id/notifications", Urls::ServiceCheck),
+            get(views::service_check::service_check_notifications),
+        )
+        .route(
+            &format!(
+                "{}/:service_check_id/artifact/:history_id",
+                Urls::ServiceCheck
+            ),
+            get(views::service_check::service_check_artifact),
+        )
         .route(
             &format!("{}/:service_check_id", Urls::ServiceCheck),
             get(service_check_get),
@@ -233,7 +333,23 @@ pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
             &format!("{}/:host_id/delete", Urls::Host),
             post(views::host::delete_host),
         )
+        .route(
+            &format!("{}/:host_id/pause", Urls::Host),
+            post(views::pause::host_pause),
+        )
+        .route(
+            &format!("{}/:host_id/resume", Urls::Host),
+            post(views::pause::host_resume),
+        )
         .route(&format!("{}/:service_id", Urls::Service), get(service))
+        .route(
+            &format!("{}/:service_id/pause", Urls::Service),
+            post(views::pause::service_pause),
+        )
+        .route(
+            &format!("{}/:service_id/resume", Urls::Service),
+            post(views::pause::service_resume),
+        )
         .route(&format!("{}/:group_id", Urls::HostGroup), get(host_group))
         .route(
             &format!("{}/:group_id/delete", Urls::HostGroup),
@@ -244,19 +360,128 @@ pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
             post(host_group_member_delete),
         )
         .route(Urls::HostGroups.as_ref(), get(host_groups))
+        .route(Urls::Map.as_ref(), get(views::map::map))
         .route(
             Urls::Tools.as_ref(),
             get(views::tools::tools).post(views::tools::tools),
         )
+        .route(Urls::Discovery.as_ref(), get(views::discovery::discovery))
+        .route(
+            &format!("{}/:discovered_host_id/accept", Urls::Discovery),
+            post(views::discovery::discovery_accept),
+        )
+        .route(
+            &format!("{}/:discovered_host_id/dismiss", Urls::Discovery),
+            post(views::discovery::discovery_dismiss),
+        )
         .route(Urls::ToolsExportDb.as_ref(), post(views::tools::export_db))
-        .route(Urls::RpLogout.as_ref(), get(oidc::rp_logout))
-        .layer(oidc_login_service)
-        // after here, the routers don't *require* auth
-        .route(Urls::Index.as_ref(), get(views::index::index))
-        .layer(oidc_auth_layer)
+        .route(
+            Urls::ToolsExport.as_ref(),
+            post(views::tools::export_archive),
+        )
+        .route(
+            Urls::ToolsImport.as_ref(),
+            post(views::tools::import_archive).layer(DefaultBodyLimit::max(
+                views::tools::MAX_ARCHIVE_UPLOAD_BYTES,
+            )),
+        );
+
+    // the API answers with a 401 rather than a login redirect, and accepts client certificates
+    let api = Router::new()
+        .route(
+            &format!("{}/:host_id/pause", Urls::HostApi),
+            post(views::pause::api_host_pause),
+        )
+        .route(
+            &format!("{}/:host_id/resume", Urls::HostApi),
+            post(views::pause::api_host_resume),
+        )
+        .route(
+            &format!("{}/:service_id/pause", Urls::ServiceApi),
+            post(views::pause::api_service_pause),
+        )
+        .route(
+            &format!("{}/:service_id/resume", Urls::ServiceApi),
+            post(views::pause::api_service_resume),
+        )
+        .route(
+            Urls::IncidentsApi.as_ref(),
+            get(views::incident::api_incidents),
+        )
+        .route(
+            Urls::CalendarApi.as_ref(),
+            get(views::calendar::api_calendar),
+        )
+        .route(
+            Urls::ConfigEventsApi.as_ref(),
+            get(views::config_events::api_config_events),
+        )
+        .route(Urls::MapApi.as_ref(), get(views::map::api_map))
+        .route(
+            &format!("{}/bulk", Urls::ServiceCheckApi),
+            post(views::bulk::api_bulk_service_checks),
+        )
+        .route(
+            &format!("{}/:service_check_id/schedule", Urls::ServiceCheckApi),
+            get(views::service_check::api_service_check_schedule),
+        )
+        .route(
+            &format!("{}/:service_check_id/run", Urls::ServiceCheckApi),
+            post(views::service_check::api_service_check_run),
+        )
+        .route(
+            &format!("{}/:service_check_id/events", Urls::ServiceCheckApi),
+            get(views::service_check::api_service_check_events),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            mtls::client_certificate_layer,
+        ));
+
+    let app = match auth_mode {
+        AuthMode::Oidc => with_oidc_auth(app, api, &state).await?,
+        AuthMode::Local => with_local_auth(app, api, &state),
+    };
+
+    let app = app
         .route(Urls::Metrics.as_ref(), get(views::metrics::metrics))
         // after here, the URLs cannot have auth
         .route(Urls::HealthCheck.as_ref(), get(up))
+        .route(Urls::Healthz.as_ref(), get(views::health::healthz))
+        .route(Urls::Readyz.as_ref(), get(views::health::readyz))
+        // the public status page, which only shows what's in the status_page config
+        .route(
+            Urls::StatusPage.as_ref(),
+            get(views::status_page::status_page),
+        )
+        .route(
+            Urls::StatusPageJson.as_ref(),
+            get(views::status_page::status_page_json),
+        )
+        // kiosk links are signed, so they work without a login session
+        .route(Urls::Kiosk.as_ref(), get(views::kiosk::kiosk))
+        // agents authenticate with their own tokens
+        .route(
+            &format!("{}/:agent_name/register", Urls::AgentApi),
+            post(views::agent::agent_register),
+        )
+        .route(
+            &format!("{}/:agent_name/checks", Urls::AgentApi),
+            get(views::agent::agent_checks),
+        )
+        .route(
+            &format!("{}/:agent_name/result", Urls::AgentApi),
+            post(views::agent::agent_result),
+        )
+        .route(
+            Urls::AlertmanagerApi.as_ref(),
+            post(views::alertmanager::alertmanager_webhook),
+        )
+        .route(Urls::SubmitApi.as_ref(), post(views::submit::submit_result))
+        .route(
+            &format!("{}/:token", Urls::HeartbeatApi),
+            get(views::heartbeat::heartbeat_ping).post(views::heartbeat::heartbeat_ping),
+        )
         .route(Urls::Logout.as_ref(), get(oidc::logout))
         .nest_service(
             Urls::Static.as_ref(),
@@ -272,7 +497,30 @@ pub(crate) async fn build_app(state: WebState) -> Result<Router, Error> {
             .precompressed_br(),
         )
         .fallback(handler_404)
-        .layer(TraceLayer::new_for_http())
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|request: &axum::extract::Request| {
+                let client = request
+                    .extensions()
+                    .get::<ClientAddress>()
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                tracing::debug_span!(
+                    "request",
+                    method = %request.method(),
+                    uri = %request.uri(),
+                    version = ?request.version(),
+                    client = %client,
+                )
+            }),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            proxy::client_address_layer,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            session::max_lifetime_layer,
+        ))
         .layer(session_layer);
     // here... we... go!
     Ok(app.with_state(state))
@@ -305,33 +553,64 @@ pub async fn start_web_server(configuration: SendableConfig, app: Router) -> Res
     let configuration_reader = configuration.read().await;
 
     let listen_address = configuration_reader.listen_addr();
-    let (cert_file, cert_key) = check_certs_exist(&configuration_reader)?;
+    let listen_address: SocketAddr = listen_address.parse().map_err(|err| {
+        Error::Generic(format!(
+            "Failed to parse listen address {}: {:?}",
+            listen_address, err
+        ))
+    })?;
+
+    if configuration_reader.listen_scheme == ListenScheme::Http {
+        drop(configuration_reader);
+        warn!("Listening with plain HTTP, TLS needs to be handled by a reverse proxy");
+        return axum_server::bind(listen_address)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .map_err(|err| Error::Generic(format!("Web server failed: {:?}", err)));
+    }
+
+    let mtls_config = configuration_reader.mtls.clone();
+    let acme_config = configuration_reader.acme.clone();
+    let frontend_url = configuration_reader.frontend_url.clone();
+    let (cert_file, cert_key) = match acme_config {
+        Some(_) => Default::default(),
+        None => check_certs_exist(&configuration_reader)?,
+    };
     drop(configuration_reader);
 
-    let tls_config = RustlsConfig::from_pem_file(&cert_file.as_path(), &cert_key.as_path())
+    // the ACME task renews the certificate in the background, it's stopped when this returns
+    let (certificate, _acme_task) = match &acme_config {
+        Some(acme_config) => {
+            let (resolver, task) = acme::start(acme_config, &frontend_url)?;
+            (tls::ServerCertificate::Acme(resolver), Some(task))
+        }
+        None => (
+            tls::ServerCertificate::Files {
+                cert_file: &cert_file,
+                cert_key: &cert_key,
+            },
+            None,
+        ),
+    };
+    let tls_config = tls::tls_config(certificate, mtls_config.as_ref())?;
+
+    axum_server::bind(listen_address)
+        .acceptor(mtls::ClientCertAcceptor::new(tls_config))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
-        .map_err(|err| Error::Generic(format!("Failed to load TLS config: {:?}", err)))?;
-    bind_rustls(
-        listen_address.parse().map_err(|err| {
-            Error::Generic(format!(
-                "Failed to parse listen address {}: {:?}",
-                listen_address, err
-            ))
-        })?,
-        tls_config,
-    )
-    .serve(app.into_make_service())
-    .await
-    .map_err(|err| Error::Generic(format!("Web server failed: {:?}", err)))
+        .map_err(|err| Error::Generic(format!("Web server failed: {:?}", err)))
 }
 
 #[cfg(not(tarpaulin_include))]
 /// Starts up the web server
+#[allow(clippy::too_many_arguments)]
 pub async fn run_web_server(
     config_filepath: PathBuf,
     configuration: SendableConfig,
-    db: Arc<RwLock<DatabaseConnection>>,
+    db: DatabaseConnection,
     registry: Arc<Registry>,
+    running_checks: Arc<RunningChecks>,
+    metrics_meter: Arc<Meter>,
     web_tx: Sender<WebServerControl>,
     mut web_server_controller: Receiver<WebServerControl>,
 ) -> Result<(), Error> {
@@ -343,7 +622,9 @@ pub async fn run_web_server(
             Some(registry),
             Some(web_tx),
             config_filepath,
-        ),
+        )
+        .with_running_checks(running_checks)
+        .with_metrics(&metrics_meter),
     )
     .await?;
 
@@ -434,7 +715,7 @@ mod tests {
             .expect("Failed to run app");
 
         let host = host::Entity::find()
-            .one(&*db.write().await)
+            .one(&db)
             .await
             .expect("Failed to query db for host")
             .expect("Failed to find host");
@@ -446,7 +727,7 @@ mod tests {
             .unwrap_or_else(|err| panic!("Failed to GET {} {:?}", url, err));
 
         let service_check = entities::service_check::Entity::find()
-            .one(&*db.write().await)
+            .one(&db)
             .await
             .expect("Failed to query db for service_check")
             .expect("Failed to find service_check");
@@ -0,0 +1,307 @@
+//! Mutual TLS, where clients present a certificate and the names in it can log in to the API
+
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use axum::extract::{Request, State};
+use axum::middleware::{AddExtension, Next};
+use axum::response::Response;
+use axum::Extension;
+use axum_oidc::{EmptyAdditionalClaims, OidcClaims};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls_acme::acme::ACME_TLS_ALPN_NAME;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use x509_parser::extensions::GeneralName;
+
+use super::WebState;
+use crate::prelude::*;
+use crate::services::root_store::{build_root_store, load_ca_file};
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// Whether clients have to present a certificate
+pub enum ClientVerification {
+    #[default]
+    /// Certificates are checked if they're presented, browsers without one still get the login page
+    Optional,
+    /// Connections without a valid client certificate are dropped during the handshake
+    Required,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `mtls` section of the config
+pub struct MtlsConfig {
+    /// PEM bundle of the CAs client certificates have to be issued by
+    pub client_ca: PathBuf,
+    #[serde(default)]
+    /// Whether clients have to present a certificate, `optional` by default
+    pub verification: ClientVerification,
+    #[serde(default)]
+    /// Certificate names (the CN or a DNS, email or URI SAN) and the user they log in to the API as
+    pub identities: HashMap<String, String>,
+}
+
+impl MtlsConfig {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if let Some(name) = self
+            .identities
+            .iter()
+            .find_map(|(name, identity)| identity.trim().is_empty().then_some(name))
+        {
+            return Err(Error::Configuration(format!(
+                "mtls identity for certificate name {} can't be empty",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// The identity of the first name that's mapped, names are in the order they're in the certificate
+    pub(crate) fn identity(&self, names: &[String]) -> Option<&str> {
+        names
+            .iter()
+            .find_map(|name| self.identities.get(name))
+            .map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The names from a verified client certificate, added to each request on the connection
+pub(crate) struct ClientCertificate {
+    pub names: Vec<String>,
+}
+
+/// Pulls the CN and SANs out of a DER-encoded certificate, CNs first
+pub(crate) fn certificate_names(der: &[u8]) -> Vec<String> {
+    let cert = match x509_parser::parse_x509_certificate(der) {
+        Ok((_, cert)) => cert,
+        Err(err) => {
+            warn!("Failed to parse client certificate: {:?}", err);
+            return Vec::new();
+        }
+    };
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            if let GeneralName::DNSName(name)
+            | GeneralName::RFC822Name(name)
+            | GeneralName::URI(name) = name
+            {
+                if !names.iter().any(|existing| existing == name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Checks client certificates against `client_ca`
+pub(crate) fn client_verifier(mtls: &MtlsConfig) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let roots = build_root_store(&load_ca_file(&mtls.client_ca)?);
+    let builder = WebPkiClientVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+    );
+    match mtls.verification {
+        ClientVerification::Optional => builder.allow_unauthenticated().build(),
+        ClientVerification::Required => builder.build(),
+    }
+    .map_err(|err| Error::TlsError(format!("Failed to set up client verification: {:?}", err)))
+}
+
+#[derive(Clone)]
+/// Does the TLS handshake, then hands the client certificate's names to the requests on the connection
+///
+/// Connections which were only for an ACME TLS-ALPN-01 challenge are closed after the handshake.
+pub(crate) struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub(crate) fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCertificate>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let connection = stream.get_ref().1;
+            if connection.alpn_protocol() == Some(ACME_TLS_ALPN_NAME) {
+                debug!("Answered an ACME challenge");
+                return Err(io::Error::other("ACME challenge connection"));
+            }
+            let names = connection
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| certificate_names(cert.as_ref()))
+                .unwrap_or_default();
+            Ok((
+                stream,
+                Extension(ClientCertificate { names }).layer(service),
+            ))
+        })
+    }
+}
+
+/// Logs API requests in as the client certificate's identity, if they aren't already logged in
+pub(crate) async fn client_certificate_layer(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if claims.is_none() {
+        let cert = request
+            .extensions()
+            .get::<ClientCertificate>()
+            .filter(|cert| !cert.names.is_empty())
+            .cloned();
+        if let Some(cert) = cert {
+            let config = state.configuration.read().await;
+            if let Some(identity) = config.mtls.as_ref().and_then(|m| m.identity(&cert.names)) {
+                match super::auth::claims_for(
+                    &config.frontend_url,
+                    &format!("mtls:{}", identity),
+                    identity,
+                ) {
+                    Ok(claims) => {
+                        request.extensions_mut().insert(claims);
+                    }
+                    Err(err) => error!("Failed to build claims for {}: {:?}", identity, err),
+                }
+            } else {
+                debug!(
+                    "Client certificate names {:?} aren't mapped to an identity",
+                    cert.names
+                );
+            }
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::tls_utils::TestCertificateBuilder;
+    use crate::web::oidc::User;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+    use tower::ServiceExt;
+
+    fn test_config(client_ca: PathBuf) -> MtlsConfig {
+        MtlsConfig {
+            client_ca,
+            verification: ClientVerification::Required,
+            identities: HashMap::from([("localhost".to_string(), "robot".to_string())]),
+        }
+    }
+
+    #[test]
+    fn test_certificate_names() {
+        let certs = TestCertificateBuilder::new().with_name("localhost").build();
+        let cert = CertificateDer::pem_file_iter(certs.cert_file.path())
+            .expect("Failed to read cert file")
+            .next()
+            .expect("No certificate in file")
+            .expect("Failed to parse cert");
+        let names = certificate_names(cert.as_ref());
+        assert_eq!(names, vec!["localhost".to_string()]);
+
+        let config = test_config(certs.ca_file.path().to_path_buf());
+        assert_eq!(config.identity(&names), Some("robot"));
+        assert_eq!(config.identity(&["example.com".to_string()]), None);
+
+        assert!(certificate_names(b"not a cert").is_empty());
+    }
+
+    #[test]
+    fn test_mtls_config() {
+        let config: MtlsConfig =
+            serde_json::from_value(json!({"client_ca": "/etc/maremma/clients.pem"}))
+                .expect("Failed to parse");
+        assert_eq!(config.verification, ClientVerification::Optional);
+        assert!(config.validate().is_ok());
+
+        let mut config = test_config(PathBuf::from("/etc/maremma/clients.pem"));
+        assert!(config.validate().is_ok());
+        config
+            .identities
+            .insert("bad.example.com".to_string(), " ".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_client_verifier() {
+        let certs = TestCertificateBuilder::new().with_name("localhost").build();
+        client_verifier(&test_config(certs.ca_file.path().to_path_buf()))
+            .expect("Failed to build client verifier");
+        assert!(client_verifier(&test_config(PathBuf::from("/this/does/not/exist.pem"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_layer() {
+        let state = WebState::test().await;
+        state.configuration.write().await.mtls =
+            Some(test_config(PathBuf::from("/etc/maremma/clients.pem")));
+
+        async fn whoami(claims: Option<OidcClaims<EmptyAdditionalClaims>>) -> String {
+            claims
+                .map(|claims| User::from(claims).subject())
+                .unwrap_or_default()
+        }
+        let app = Router::new()
+            .route("/", get(whoami))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                client_certificate_layer,
+            ))
+            .with_state(state);
+
+        for (names, expected) in [
+            (vec!["localhost".to_string()], "mtls:robot"),
+            (vec!["example.com".to_string()], ""),
+            (vec![], ""),
+        ] {
+            let mut request = Request::builder()
+                .uri("/")
+                .body(Body::empty())
+                .expect("Failed to build request");
+            request.extensions_mut().insert(ClientCertificate { names });
+            let response = app.clone().oneshot(request).await.expect("Request failed");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            assert_eq!(body, expected.as_bytes());
+        }
+    }
+}
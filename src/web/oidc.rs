@@ -50,12 +50,18 @@ pub async fn rp_logout(
 #[derive(Debug)]
 pub(crate) struct User {
     username: String,
+    subject: String,
 }
 
 impl User {
     pub fn username(&self) -> String {
         self.username.to_owned()
     }
+
+    /// The OIDC subject, which doesn't change when the user's name does
+    pub fn subject(&self) -> String {
+        self.subject.to_owned()
+    }
 }
 
 impl<AC> From<OidcClaims<AC>> for User
@@ -63,12 +69,13 @@ where
     AC: AdditionalClaims,
 {
     fn from(value: OidcClaims<AC>) -> Self {
+        let subject = value.subject().as_str().to_string();
         let username = match value.preferred_username() {
             Some(username) => username.as_str().to_string(),
-            None => value.subject().as_str().to_string(),
+            None => subject.clone(),
         };
 
-        Self { username }
+        Self { username, subject }
     }
 }
 
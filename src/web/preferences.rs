@@ -0,0 +1,127 @@
+//! Each user's preferences are stored against their OIDC subject, and kept in their session while they're logged in
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_oidc::{EmptyAdditionalClaims, OidcClaims};
+use tower_sessions::Session;
+
+use super::oidc::User;
+use super::WebState;
+use crate::db::entities::user_preferences::{self, Preferences};
+use crate::prelude::*;
+
+/// Where the user's preferences are kept in their session
+pub(crate) const SESSION_PREFERENCES: &str = "preferences";
+
+/// The preferences in the user's session, or the defaults if there aren't any
+pub(crate) async fn session_preferences(session: &Session) -> Result<Preferences, Error> {
+    Ok(session
+        .get::<Preferences>(SESSION_PREFERENCES)
+        .await?
+        .unwrap_or_default())
+}
+
+/// Copies the user's stored preferences into their session, if they're not there already
+pub(crate) async fn load_preferences(
+    db: &DatabaseConnection,
+    session: &Session,
+    subject: &str,
+) -> Result<(), Error> {
+    if session
+        .get::<Preferences>(SESSION_PREFERENCES)
+        .await?
+        .is_none()
+    {
+        let preferences = user_preferences::Entity::get_preferences(db, subject).await?;
+        session.insert(SESSION_PREFERENCES, preferences).await?;
+    }
+    Ok(())
+}
+
+/// Stores the preferences for next time and updates the session
+pub(crate) async fn save_preferences(
+    db: &DatabaseConnection,
+    session: &Session,
+    subject: &str,
+    preferences: Preferences,
+) -> Result<(), Error> {
+    user_preferences::Entity::set_preferences(db, subject, &preferences).await?;
+    session.insert(SESSION_PREFERENCES, preferences).await?;
+    Ok(())
+}
+
+/// Loads a logged in user's preferences when their session starts
+pub(crate) async fn preferences_layer(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(claims) = claims {
+        let user = User::from(claims);
+        if let Err(err) = load_preferences(&state.db, &session, &user.subject()).await {
+            error!(
+                "Failed to load preferences for {}: {:?}",
+                user.username(),
+                err
+            );
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::theme::Theme;
+
+    #[tokio::test]
+    async fn test_preferences() {
+        let state = WebState::test().await;
+        let session = state.get_session();
+        assert_eq!(
+            session_preferences(&session)
+                .await
+                .expect("Failed to get preferences"),
+            Preferences::default()
+        );
+
+        let preferences = Preferences {
+            theme: Some(Theme::Dark),
+            ..Default::default()
+        };
+        user_preferences::Entity::set_preferences(&state.db, "testuser", &preferences)
+            .await
+            .expect("Failed to set preferences");
+        load_preferences(&state.db, &session, "testuser")
+            .await
+            .expect("Failed to load preferences");
+        assert_eq!(
+            session_preferences(&session)
+                .await
+                .expect("Failed to get preferences"),
+            preferences
+        );
+
+        // a new session picks up what was saved
+        let saved = Preferences {
+            page_size: Some(10),
+            ..Default::default()
+        };
+        save_preferences(&state.db, &session, "testuser", saved.clone())
+            .await
+            .expect("Failed to save preferences");
+        let session = state.get_session();
+        load_preferences(&state.db, &session, "testuser")
+            .await
+            .expect("Failed to load preferences");
+        assert_eq!(
+            session_preferences(&session)
+                .await
+                .expect("Failed to get preferences"),
+            saved
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Working out who the client is when Maremma's behind a TLS-terminating reverse proxy
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use ipnet::IpNet;
+
+use super::WebState;
+use crate::prelude::*;
+
+/// Set by proxies to the chain of client addresses
+pub(crate) const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// Set by proxies to the scheme the client used
+pub(crate) const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// What the web server listens with
+pub enum ListenScheme {
+    #[default]
+    /// TLS, with `cert_file` and `cert_key` or ACME
+    Https,
+    /// Plain HTTP, for running behind a reverse proxy which handles TLS
+    Http,
+}
+
+/// Parses the `trusted_proxies` config
+pub(crate) fn parse_trusted_proxies(proxies: &[String]) -> Result<Vec<IpNet>, Error> {
+    proxies
+        .iter()
+        .map(|proxy| {
+            let proxy = proxy.trim();
+            IpNet::from_str(proxy)
+                .or_else(|_| IpAddr::from_str(proxy).map(IpNet::from))
+                .map_err(|err| {
+                    Error::Configuration(format!(
+                        "trusted_proxies entry {} isn't an IP or CIDR: {:?}",
+                        proxy, err
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The client at the other end of a request, after looking through trusted proxies
+pub(crate) struct ClientAddress {
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`
+    pub scheme: String,
+}
+
+impl std::fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{} ({})", ip, self.scheme),
+            None => write!(f, "unknown ({})", self.scheme),
+        }
+    }
+}
+
+impl ClientAddress {
+    /// Only believes the forwarding headers if the connection came from a trusted proxy
+    pub(crate) fn new(
+        peer: Option<IpAddr>,
+        headers: &HeaderMap,
+        trusted_proxies: &[IpNet],
+        tls: bool,
+    ) -> Self {
+        let scheme = if tls { "https" } else { "http" }.to_string();
+        let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+        let peer_ip = match peer {
+            Some(ip) if is_trusted(&ip) => ip,
+            _ => return Self { ip: peer, scheme },
+        };
+
+        // the last address that isn't one of our proxies is the client, anything before it can be spoofed
+        let forwarded: Vec<IpAddr> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| IpAddr::from_str(ip.trim()).ok())
+            .collect();
+        let ip = forwarded
+            .iter()
+            .rev()
+            .find(|ip| !is_trusted(ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer_ip);
+
+        let scheme = headers
+            .get(X_FORWARDED_PROTO)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .filter(|value| value == "http" || value == "https")
+            .unwrap_or(scheme);
+
+        Self {
+            ip: Some(ip),
+            scheme,
+        }
+    }
+}
+
+/// Adds the [ClientAddress] to each request, for logging
+pub(crate) async fn client_address_layer(
+    State(state): State<WebState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let config = state.configuration.read().await;
+    let tls = config.listen_scheme == ListenScheme::Https;
+    let client = match parse_trusted_proxies(&config.trusted_proxies) {
+        Ok(proxies) => ClientAddress::new(peer, request.headers(), &proxies, tls),
+        Err(err) => {
+            error!("{:?}", err);
+            ClientAddress::new(peer, request.headers(), &[], tls)
+        }
+    };
+    drop(config);
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_parse_trusted_proxies() {
+        let proxies = parse_trusted_proxies(&[
+            "10.0.0.0/8".to_string(),
+            " 192.168.1.1 ".to_string(),
+            "::1".to_string(),
+        ])
+        .expect("Failed to parse proxies");
+        assert_eq!(proxies.len(), 3);
+        assert!(proxies[1].contains(&IpAddr::from([192, 168, 1, 1])));
+
+        assert!(parse_trusted_proxies(&["proxy.example.com".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_client_address() {
+        let proxies = parse_trusted_proxies(&["10.0.0.0/8".to_string()]).expect("Failed to parse");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, 203.0.113.5, 10.0.0.2"),
+        );
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+
+        // from the proxy, so the headers are believed
+        let client =
+            ClientAddress::new(Some(IpAddr::from([10, 0, 0, 1])), &headers, &proxies, false);
+        assert_eq!(client.ip, Some(IpAddr::from([203, 0, 113, 5])));
+        assert_eq!(client.scheme, "https");
+        assert_eq!(client.to_string(), "203.0.113.5 (https)");
+
+        // from somewhere else, so they're not
+        let client = ClientAddress::new(
+            Some(IpAddr::from([198, 51, 100, 1])),
+            &headers,
+            &proxies,
+            false,
+        );
+        assert_eq!(client.ip, Some(IpAddr::from([198, 51, 100, 1])));
+        assert_eq!(client.scheme, "http");
+
+        // from the proxy without any headers
+        let client = ClientAddress::new(
+            Some(IpAddr::from([10, 0, 0, 1])),
+            &HeaderMap::new(),
+            &proxies,
+            true,
+        );
+        assert_eq!(client.ip, Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(client.scheme, "https");
+
+        assert_eq!(
+            ClientAddress::new(None, &headers, &proxies, true).to_string(),
+            "unknown (https)"
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! How long web sessions last and how their cookie's set, from the `session` section of the config
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_sessions::cookie::{time::Duration, SameSite};
+use tower_sessions::{Expiry, Session, SessionManagerLayer, SessionStore};
+
+use super::WebState;
+use crate::prelude::*;
+
+/// When the session was started, for `max_lifetime_seconds`
+pub(crate) const SESSION_STARTED: &str = "started";
+
+/// Nobody wants to log in again every few seconds
+const MIN_INACTIVITY_SECONDS: u64 = 60;
+/// A year, which is also well inside what a cookie's expiry can hold
+const MAX_LIFETIME_SECONDS: u64 = 365 * 86400;
+
+fn default_inactivity_seconds() -> u64 {
+    1800
+}
+
+fn default_cookie_name() -> String {
+    "id".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// The cookie's `SameSite` attribute
+pub enum CookieSameSite {
+    /// Only sent on requests from Maremma itself, which breaks the OIDC redirect back to Maremma with some providers
+    Strict,
+    #[default]
+    /// Sent when following a link to Maremma, but not on cross-site POSTs
+    Lax,
+    /// Always sent, needs `secure`
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(value: CookieSameSite) -> Self {
+        match value {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+/// The `session` section of the config
+pub struct SessionConfig {
+    #[serde(default = "default_inactivity_seconds")]
+    /// Log users out after this many seconds without a request
+    pub inactivity_seconds: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Log users out this many seconds after their session started, even if they're active
+    pub max_lifetime_seconds: Option<u64>,
+    #[serde(default = "default_cookie_name")]
+    /// The session cookie's name, change it if something else on the same host uses `id`
+    pub cookie_name: String,
+    #[serde(default)]
+    /// The cookie's `SameSite` attribute
+    pub same_site: CookieSameSite,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Only send the cookie over HTTPS, by default it is unless `frontend_url` is `http://`
+    pub secure: Option<bool>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            inactivity_seconds: default_inactivity_seconds(),
+            max_lifetime_seconds: None,
+            cookie_name: default_cookie_name(),
+            same_site: CookieSameSite::default(),
+            secure: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Whether the cookie's only sent over HTTPS
+    pub fn secure(&self, frontend_url: &str) -> bool {
+        // the browser might be talking HTTP to us or a proxy
+        self.secure
+            .unwrap_or_else(|| !frontend_url.starts_with("http://"))
+    }
+
+    /// Checks the times make sense, and that browsers will accept the cookie
+    pub fn validate(&self, frontend_url: &str) -> Result<(), Error> {
+        if !(MIN_INACTIVITY_SECONDS..=MAX_LIFETIME_SECONDS).contains(&self.inactivity_seconds) {
+            return Err(Error::Configuration(format!(
+                "session inactivity_seconds must be between {} and {}",
+                MIN_INACTIVITY_SECONDS, MAX_LIFETIME_SECONDS
+            )));
+        }
+        if let Some(max_lifetime_seconds) = self.max_lifetime_seconds {
+            if !(self.inactivity_seconds..=MAX_LIFETIME_SECONDS).contains(&max_lifetime_seconds) {
+                return Err(Error::Configuration(format!(
+                    "session max_lifetime_seconds must be between inactivity_seconds and {}",
+                    MAX_LIFETIME_SECONDS
+                )));
+            }
+        }
+        if self.cookie_name.is_empty()
+            || !self
+                .cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            return Err(Error::Configuration(format!(
+                "session cookie_name {:?} can only have letters, numbers, '-', '_' and '.'",
+                self.cookie_name
+            )));
+        }
+        if self.same_site == CookieSameSite::None && !self.secure(frontend_url) {
+            return Err(Error::Configuration(
+                "session same_site none needs secure, browsers drop the cookie otherwise"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sets up the session cookie for the web server
+    pub(crate) fn layer<S: SessionStore>(
+        &self,
+        store: S,
+        frontend_url: &str,
+    ) -> SessionManagerLayer<S> {
+        SessionManagerLayer::new(store)
+            .with_name(self.cookie_name.clone())
+            .with_secure(self.secure(frontend_url))
+            .with_same_site(self.same_site.into())
+            .with_http_only(true)
+            .with_expiry(Expiry::OnInactivity(Duration::seconds(
+                self.inactivity_seconds as i64,
+            )))
+    }
+
+    /// If a session that started at `started` has outlived `max_lifetime_seconds`
+    pub(crate) fn expired(&self, started: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.max_lifetime_seconds
+            .is_some_and(|max_lifetime_seconds| {
+                now - started > TimeDelta::seconds(max_lifetime_seconds as i64)
+            })
+    }
+}
+
+/// Enforces `max_lifetime_seconds` by throwing away sessions that have lasted too long
+pub(crate) async fn max_lifetime_layer(
+    State(state): State<WebState>,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = state.configuration.read().await.session.clone();
+    if config.max_lifetime_seconds.is_some() && !session.is_empty().await {
+        let now = Utc::now();
+        match session.get::<DateTime<Utc>>(SESSION_STARTED).await {
+            Ok(Some(started)) => {
+                if config.expired(started, now) {
+                    debug!("Session started at {} has expired", started);
+                    if let Err(err) = session.flush().await {
+                        error!("Failed to remove an expired session: {:?}", err);
+                    }
+                }
+            }
+            Ok(None) => {
+                if let Err(err) = session.insert(SESSION_STARTED, now).await {
+                    error!("Failed to store when the session started: {:?}", err);
+                }
+            }
+            Err(err) => error!("Failed to read when the session started: {:?}", err),
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let https = "https://maremma.example.com";
+        let http = "http://localhost:8888";
+        assert!(SessionConfig::default().validate(https).is_ok());
+        assert!(SessionConfig::default().validate(http).is_ok());
+        assert!(SessionConfig::default().secure(https));
+        assert!(!SessionConfig::default().secure(http));
+
+        for bad in [
+            SessionConfig {
+                inactivity_seconds: 1,
+                ..Default::default()
+            },
+            SessionConfig {
+                max_lifetime_seconds: Some(60),
+                ..Default::default()
+            },
+            SessionConfig {
+                cookie_name: "has spaces".to_string(),
+                ..Default::default()
+            },
+            SessionConfig {
+                cookie_name: String::new(),
+                ..Default::default()
+            },
+            SessionConfig {
+                same_site: CookieSameSite::None,
+                secure: Some(false),
+                ..Default::default()
+            },
+        ] {
+            assert!(bad.validate(https).is_err(), "{:?}", bad);
+        }
+
+        let same_site_none = SessionConfig {
+            same_site: CookieSameSite::None,
+            ..Default::default()
+        };
+        assert!(same_site_none.validate(https).is_ok());
+        assert!(same_site_none.validate(http).is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let now = Utc::now();
+        let started = now - TimeDelta::hours(2);
+        assert!(!SessionConfig::default().expired(started, now));
+        let config = SessionConfig {
+            max_lifetime_seconds: Some(3600),
+            ..Default::default()
+        };
+        assert!(config.expired(started, now));
+        assert!(!config.expired(now - TimeDelta::minutes(5), now));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let config: SessionConfig = serde_json::from_value(serde_json::json!({
+            "inactivity_seconds": 28800,
+            "max_lifetime_seconds": 86400,
+            "cookie_name": "maremma_session",
+            "same_site": "strict"
+        }))
+        .expect("Failed to parse session config");
+        assert_eq!(config.same_site, CookieSameSite::Strict);
+        assert_eq!(config.secure, None);
+        assert!(config.validate("https://maremma.example.com").is_ok());
+    }
+}
@@ -0,0 +1,218 @@
+//! Themes, branding and display settings for the web UI
+
+use tower_sessions::Session;
+
+use super::preferences::session_preferences;
+use super::urls::Urls;
+use super::WebState;
+use crate::prelude::*;
+
+/// Makes sure a timezone name looks like an IANA one, eg `Australia/Brisbane` or `UTC`, the browser does the rest
+pub(crate) fn check_timezone(timezone: &str) -> Result<(), Error> {
+    if timezone.is_empty()
+        || timezone.len() > 64
+        || !timezone
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+'))
+    {
+        return Err(Error::Configuration(format!(
+            "'{}' isn't a valid timezone",
+            timezone
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// The colour scheme for the UI
+pub enum Theme {
+    #[default]
+    /// Dark text on a light background
+    Light,
+    /// Light text on a dark background, good for NOC screens
+    Dark,
+}
+
+impl std::fmt::Display for Theme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Light => "light",
+            Self::Dark => "dark",
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq, JsonSchema)]
+/// The `theme` section of the config, for branding the UI
+pub struct ThemeConfig {
+    #[serde(default)]
+    /// The theme for users who haven't picked one
+    pub default: Theme,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Shown in the navbar and page titles instead of "Maremma"
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// URL of the logo in the navbar, defaults to the Maremma icon
+    pub logo: Option<String>,
+}
+
+impl ThemeConfig {
+    /// Makes sure the branding won't leave the navbar empty
+    pub fn validate(&self) -> Result<(), Error> {
+        if self
+            .title
+            .as_ref()
+            .is_some_and(|title| title.trim().is_empty())
+        {
+            return Err(Error::Configuration(
+                "theme title can't be empty".to_string(),
+            ));
+        }
+        if self
+            .logo
+            .as_ref()
+            .is_some_and(|logo| logo.trim().is_empty())
+        {
+            return Err(Error::Configuration(
+                "theme logo can't be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What the base template needs to draw the page
+pub(crate) struct PageTheme {
+    pub theme: Theme,
+    pub title: String,
+    pub logo: String,
+    /// The user's or configured refresh interval, pages use their own if it's not set
+    pub page_refresh: Option<u64>,
+    /// Times are shown in the browser's timezone if this isn't set
+    pub timezone: Option<String>,
+}
+
+impl Default for PageTheme {
+    fn default() -> Self {
+        Self {
+            theme: Theme::default(),
+            title: "Maremma".to_string(),
+            logo: format!("{}/img/maremma-icon.svg", Urls::Static),
+            page_refresh: None,
+            timezone: None,
+        }
+    }
+}
+
+impl PageTheme {
+    /// The configured theme, for pages which don't have a session
+    pub(crate) fn from_config(config: &Configuration) -> Self {
+        let default = Self::default();
+        Self {
+            theme: config.theme.default,
+            title: config.theme.title.clone().unwrap_or(default.title),
+            logo: config.theme.logo.clone().unwrap_or(default.logo),
+            page_refresh: config.page_refresh_seconds,
+            timezone: config.display_timezone.clone(),
+        }
+    }
+
+    /// The user's theme if they've picked one, otherwise the configured default
+    pub(crate) async fn new(state: &WebState, session: &Session) -> Result<Self, Error> {
+        let config = Self::from_config(&*state.configuration.read().await);
+        let preferences = session_preferences(session).await?;
+        Ok(Self {
+            theme: preferences.theme.unwrap_or(config.theme),
+            page_refresh: preferences.page_refresh.or(config.page_refresh),
+            timezone: preferences.timezone.or(config.timezone.clone()),
+            ..config
+        })
+    }
+
+    /// How often the page should refresh itself, given the page's own default
+    pub(crate) fn refresh_or(&self, default: u64) -> u64 {
+        self.page_refresh.unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::user_preferences::Preferences;
+    use crate::web::preferences::SESSION_PREFERENCES;
+
+    #[tokio::test]
+    async fn test_page_theme() {
+        let state = WebState::test().await;
+        let session = state.get_session();
+        assert_eq!(
+            PageTheme::new(&state, &session)
+                .await
+                .expect("Failed to get theme"),
+            PageTheme::default()
+        );
+
+        {
+            let mut config = state.configuration.write().await;
+            config.theme.default = Theme::Dark;
+            config.theme.title = Some("NOC".to_string());
+        }
+        let theme = PageTheme::new(&state, &session)
+            .await
+            .expect("Failed to get theme");
+        assert_eq!(theme.theme, Theme::Dark);
+        assert_eq!(theme.title, "NOC");
+
+        // the user's choice wins
+        let mut preferences = Preferences {
+            theme: Some(Theme::Light),
+            ..Default::default()
+        };
+        session
+            .insert(SESSION_PREFERENCES, &preferences)
+            .await
+            .expect("Failed to set theme");
+        assert_eq!(
+            PageTheme::new(&state, &session)
+                .await
+                .expect("Failed to get theme")
+                .theme,
+            Theme::Light
+        );
+
+        {
+            let mut config = state.configuration.write().await;
+            config.page_refresh_seconds = Some(120);
+            config.display_timezone = Some("Australia/Brisbane".to_string());
+        }
+        preferences.page_refresh = Some(0);
+        session
+            .insert(SESSION_PREFERENCES, &preferences)
+            .await
+            .expect("Failed to set page refresh");
+        let theme = PageTheme::new(&state, &session)
+            .await
+            .expect("Failed to get theme");
+        assert_eq!(theme.refresh_or(30), 0);
+        assert_eq!(theme.timezone.as_deref(), Some("Australia/Brisbane"));
+        assert_eq!(PageTheme::default().refresh_or(30), 30);
+
+        assert!(ThemeConfig {
+            title: Some(" ".to_string()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_timezone() {
+        assert!(check_timezone("UTC").is_ok());
+        assert!(check_timezone("America/Argentina/Buenos_Aires").is_ok());
+        assert!(check_timezone("Etc/GMT+10").is_ok());
+        assert!(check_timezone("").is_err());
+        assert!(check_timezone("\"><script>").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Building the web server's TLS config
+
+use std::path::Path;
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::ResolvesServerCert;
+use rustls::ServerConfig;
+use rustls_acme::acme::ACME_TLS_ALPN_NAME;
+
+use super::mtls::{client_verifier, MtlsConfig};
+use crate::prelude::*;
+
+/// Where the web server's certificate comes from
+pub(crate) enum ServerCertificate<'a> {
+    /// `cert_file` and `cert_key`
+    Files {
+        cert_file: &'a Path,
+        cert_key: &'a Path,
+    },
+    /// Issued and renewed through ACME
+    Acme(Arc<dyn ResolvesServerCert>),
+}
+
+/// Builds the web server's TLS config, asking for client certificates if `mtls` is configured
+pub(crate) fn tls_config(
+    certificate: ServerCertificate<'_>,
+    mtls: Option<&MtlsConfig>,
+) -> Result<RustlsConfig, Error> {
+    let builder = ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::aws_lc_rs::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?;
+    let builder = match mtls {
+        Some(mtls) => builder.with_client_cert_verifier(client_verifier(mtls)?),
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let mut config = match certificate {
+        ServerCertificate::Files {
+            cert_file,
+            cert_key,
+        } => {
+            let certs = CertificateDer::pem_file_iter(cert_file)
+                .map_err(|err| Error::TlsError(format!("Failed to read cert_file: {:?}", err)))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| Error::TlsError(format!("Failed to parse cert_file: {:?}", err)))?;
+            let key = PrivateKeyDer::from_pem_file(cert_key)
+                .map_err(|err| Error::TlsError(format!("Failed to read cert_key: {:?}", err)))?;
+            builder.with_single_cert(certs, key)?
+        }
+        ServerCertificate::Acme(resolver) => {
+            alpn_protocols.push(ACME_TLS_ALPN_NAME.to_vec());
+            builder.with_cert_resolver(resolver)
+        }
+    };
+    config.alpn_protocols = alpn_protocols;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::tests::tls_utils::TestCertificateBuilder;
+    use crate::web::mtls::ClientVerification;
+
+    #[test]
+    fn test_tls_config() {
+        let certs = TestCertificateBuilder::new().with_name("localhost").build();
+        let files = || ServerCertificate::Files {
+            cert_file: certs.cert_file.path(),
+            cert_key: certs.key_file.path(),
+        };
+        let mtls = MtlsConfig {
+            client_ca: certs.ca_file.path().to_path_buf(),
+            verification: ClientVerification::Required,
+            identities: HashMap::new(),
+        };
+
+        tls_config(files(), None).expect("Failed to build TLS config");
+        tls_config(files(), Some(&mtls)).expect("Failed to build mTLS config");
+
+        let missing = ServerCertificate::Files {
+            cert_file: Path::new("/this/does/not/exist.pem"),
+            cert_key: certs.key_file.path(),
+        };
+        assert!(tls_config(missing, None).is_err());
+    }
+}
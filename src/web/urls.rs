@@ -1,43 +1,89 @@
 pub(crate) enum Urls {
+    AgentApi,
+    AlertmanagerApi,
+    CalendarApi,
+    ConfigEventsApi,
+    Discovery,
     HealthCheck,
+    HeartbeatApi,
+    Healthz,
     Host,
+    HostApi,
     Hosts,
     HostGroup,
     HostGroups,
+    IncidentsApi,
     Index,
+    Kiosk,
     Login,
     Logout,
+    Map,
+    MapApi,
     Metrics,
+    Readyz,
     RpLogout,
     Profile,
+    ProfileFavorite,
+    ProfilePreferences,
     Service,
     Services,
+    Search,
+    ServiceApi,
     ServiceCheck,
+    ServiceCheckApi,
     Static,
+    StatusPage,
+    StatusPageJson,
+    SubmitApi,
     Tools,
     ToolsExportDb,
+    ToolsExport,
+    ToolsImport,
 }
 
 impl AsRef<str> for Urls {
     fn as_ref(&self) -> &str {
         match self {
+            Self::AgentApi => "/api/v1/agent",
+            Self::AlertmanagerApi => "/api/v1/alertmanager",
+            Self::CalendarApi => "/api/v1/calendar.ics",
+            Self::ConfigEventsApi => "/api/v1/config/events",
+            Self::Discovery => "/discovery",
             Self::HealthCheck => "/healthcheck",
+            Self::HeartbeatApi => "/api/v1/heartbeat",
+            Self::Healthz => "/healthz",
             Self::Host => "/host",
+            Self::HostApi => "/api/v1/host",
             Self::Hosts => "/hosts",
             Self::HostGroup => "/host_group",
             Self::HostGroups => "/host_groups",
+            Self::IncidentsApi => "/api/v1/incidents",
             Self::Index => "/",
+            Self::Kiosk => "/kiosk",
             Self::Login => "/auth/login",
             Self::Logout => "/auth/logout",
+            Self::Map => "/map",
+            Self::MapApi => "/api/v1/map",
             Self::Metrics => "/metrics",
+            Self::Readyz => "/readyz",
             Self::RpLogout => "/auth/rp-logout",
             Self::Profile => "/profile",
+            Self::ProfileFavorite => "/profile/favorite",
+            Self::ProfilePreferences => "/profile/preferences",
             Self::Service => "/service",
             Self::Services => "/services",
+            Self::Search => "/search",
+            Self::ServiceApi => "/api/v1/service",
             Self::ServiceCheck => "/service_check",
+            Self::ServiceCheckApi => "/api/v1/service_check",
             Self::Static => "/static",
+            Self::StatusPage => "/status",
+            Self::StatusPageJson => "/status.json",
+            Self::SubmitApi => "/api/v1/submit",
             Self::Tools => "/tools",
             Self::ToolsExportDb => "/tools/db_export",
+            Self::ToolsExport => "/tools/export",
+            Self::ToolsImport => "/tools/import",
         }
     }
 }
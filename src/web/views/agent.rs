@@ -0,0 +1,361 @@
+//! API endpoints for remote agents
+
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use axum::Json;
+use hmac::{Hmac, Mac};
+use sea_orm::ModelTrait;
+use sha2::Sha256;
+use tracing::warn;
+
+use super::prelude::*;
+use crate::agent::{
+    service_value, AgentCheckAssignment, AgentCheckResult, AgentConfig, AgentRegistration,
+    AgentRegistrationResponse,
+};
+use crate::check_loop::{record_check_result, CheckEnvironment};
+use crate::constants::DEFAULT_AGENT_POLL_INTERVAL_SECS;
+use crate::db::get_agent_service_checks;
+use crate::errors::Error;
+use crate::services::service_config_parse;
+
+/// Pull the bearer token out of the request headers
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Compares a token from a request with the configured one in constant time, so how long it takes doesn't give away
+/// how much of it matched
+pub(crate) fn tokens_match(given: &str, expected: &str) -> bool {
+    // both go through the same HMAC so they're the same length, then verify_slice compares them in constant time
+    let mac = |token: &str| {
+        Hmac::<Sha256>::new_from_slice(b"maremma-bearer-token").map(|mut mac| {
+            mac.update(token.as_bytes());
+            mac
+        })
+    };
+    match (mac(given), mac(expected)) {
+        (Ok(given), Ok(expected)) => given
+            .verify_slice(&expected.finalize().into_bytes())
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Makes sure the request's bearer token is `token`
+pub(crate) fn require_bearer(headers: &HeaderMap, token: &str) -> Result<(), Error> {
+    match bearer_token(headers) {
+        Some(given) if tokens_match(given, token) => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+/// Makes sure the agent is configured and the token matches, returning its configuration
+pub(crate) async fn check_agent_auth(
+    state: &WebState,
+    agent_name: &str,
+    headers: &HeaderMap,
+) -> Result<AgentConfig, Error> {
+    let config_reader = state.configuration.read().await;
+    let agent = config_reader.agents.get(agent_name).ok_or_else(|| {
+        warn!("Request from unknown agent={}", agent_name);
+        Error::Unauthorized
+    })?;
+
+    require_bearer(headers, &agent.token)
+        .inspect_err(|_| warn!("Invalid token for agent={}", agent_name))?;
+    Ok(agent.clone())
+}
+
+pub(crate) async fn agent_register(
+    Path(agent_name): Path<String>,
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Json(registration): Json<AgentRegistration>,
+) -> Result<Json<AgentRegistrationResponse>, Error> {
+    let agent = check_agent_auth(&state, &agent_name, &headers).await?;
+    info!(
+        "Agent registered agent={} zone={:?} version={}",
+        agent_name, agent.zone, registration.version
+    );
+    Ok(Json(AgentRegistrationResponse {
+        zone: agent.zone,
+        poll_interval: DEFAULT_AGENT_POLL_INTERVAL_SECS,
+    }))
+}
+
+pub(crate) async fn agent_checks(
+    Path(agent_name): Path<String>,
+    State(state): State<WebState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<AgentCheckAssignment>>, Error> {
+    let agent = check_agent_auth(&state, &agent_name, &headers).await?;
+
+    let checks = get_agent_service_checks(&state.db, &agent_name, agent.zone.as_deref()).await?;
+
+    let mut res = Vec::with_capacity(checks.len());
+    for (service_check, service) in checks {
+        // another agent or the check loop might've got to it first, stuck checks get picked up by the shepherd if the
+        // agent never reports back
+        let Some(service_check) = service_check.claim(&state.db).await? else {
+            debug!(
+                "service_check={} was claimed before agent={} could take it",
+                service_check.id.hyphenated(),
+                agent_name
+            );
+            if let Some(check_claim_conflicts) = state.check_claim_conflicts.as_ref() {
+                check_claim_conflicts.add(1, &[]);
+            }
+            continue;
+        };
+        let host = service_check
+            .find_related(entities::host::Entity)
+            .one(&state.db)
+            .await?
+            .ok_or(Error::HostNotFound(service_check.host_id))?;
+        res.push(AgentCheckAssignment {
+            service_check_id: service_check.id,
+            service,
+            host: host.into(),
+        });
+    }
+    debug!("Handing {} checks to agent={}", res.len(), agent_name);
+    Ok(Json(res))
+}
+
+pub(crate) async fn agent_result(
+    Path(agent_name): Path<String>,
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Json(result): Json<AgentCheckResult>,
+) -> Result<StatusCode, Error> {
+    let agent = check_agent_auth(&state, &agent_name, &headers).await?;
+
+    let (service_check, service) =
+        entities::service_check::Entity::find_by_id(result.service_check_id)
+            .find_also_related(entities::service::Entity)
+            .one(&state.db)
+            .await?
+            .ok_or(Error::ServiceCheckNotFound(result.service_check_id))?;
+    let service = service.ok_or(Error::ServiceNotFound(service_check.service_id))?;
+
+    // agents can only report on the checks pinned to them
+    if !service
+        .agent
+        .as_deref()
+        .is_some_and(|pin| agent.matches(&agent_name, pin))
+    {
+        warn!(
+            "Agent {} tried to submit a result for service_check={} which isn't pinned to it",
+            agent_name, service_check.id
+        );
+        return Err(Error::Unauthorized);
+    }
+
+    let jitter = service_value(&service)
+        .and_then(|value| service_config_parse(&service.name, &service.service_type, &value))
+        .map(|service| service.jitter_value())
+        .unwrap_or(0);
+
+    let environment = CheckEnvironment::agent(
+        &agent_name,
+        result.maremma_version.clone(),
+        result.target_address.clone(),
+    );
+    record_check_result(
+        state.db.clone(),
+        &service_check,
+        &service,
+        &result.into(),
+        &environment,
+        jitter,
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use sea_orm::{ColumnTrait, QueryFilter};
+
+    const TEST_AGENT: &str = "test_agent";
+    const TEST_TOKEN: &str = "hunter2";
+
+    async fn agent_state() -> WebState {
+        let state = WebState::test().await;
+        state.configuration.write().await.agents.insert(
+            TEST_AGENT.to_string(),
+            AgentConfig {
+                token: TEST_TOKEN.to_string(),
+                zone: Some("dc2".to_string()),
+            },
+        );
+        state
+    }
+
+    /// Headers with `token` as the bearer token
+    pub(crate) fn auth_headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).expect("Invalid header"),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match(TEST_TOKEN, TEST_TOKEN));
+        assert!(!tokens_match("hunter", TEST_TOKEN));
+        assert!(!tokens_match("", TEST_TOKEN));
+        assert!(!tokens_match("hunter22", TEST_TOKEN));
+    }
+
+    #[tokio::test]
+    async fn test_check_agent_auth() {
+        let state = agent_state().await;
+
+        assert!(
+            check_agent_auth(&state, TEST_AGENT, &auth_headers(TEST_TOKEN))
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            check_agent_auth(&state, TEST_AGENT, &auth_headers("wrong")).await,
+            Err(Error::Unauthorized)
+        );
+        assert_eq!(
+            check_agent_auth(&state, TEST_AGENT, &HeaderMap::new()).await,
+            Err(Error::Unauthorized)
+        );
+        assert_eq!(
+            check_agent_auth(&state, "not_an_agent", &auth_headers(TEST_TOKEN)).await,
+            Err(Error::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_register() {
+        let state = agent_state().await;
+
+        let res = agent_register(
+            Path(TEST_AGENT.to_string()),
+            State(state.clone()),
+            auth_headers(TEST_TOKEN),
+            Json(AgentRegistration {
+                version: "test".to_string(),
+            }),
+        )
+        .await
+        .expect("Failed to register");
+        assert_eq!(res.0.zone, Some("dc2".to_string()));
+        assert_eq!(res.0.poll_interval, DEFAULT_AGENT_POLL_INTERVAL_SECS);
+    }
+
+    #[tokio::test]
+    async fn test_agent_checks_and_result() {
+        let state = agent_state().await;
+
+        // pin the cli service to the agent's zone
+        let service = entities::service::Entity::find()
+            .filter(entities::service::Column::ServiceType.eq(crate::prelude::ServiceType::Cli))
+            .one(&state.db)
+            .await
+            .expect("Failed to query services")
+            .expect("Failed to find cli service");
+        let mut service = service.into_active_model();
+        service.agent.set_if_not_equals(Some("dc2".to_string()));
+        let service = service
+            .update(&state.db)
+            .await
+            .expect("Failed to pin service");
+
+        // the local loop should never see it
+        loop {
+            let next_check = crate::db::get_next_service_check(&state.db)
+                .await
+                .expect("Failed to get next service check");
+            let Some((service_check, next_service)) = next_check else {
+                break;
+            };
+            assert_ne!(next_service.id, service.id);
+            service_check
+                .set_status(ServiceStatus::Disabled, state.db.clone())
+                .await
+                .expect("Failed to disable service check");
+        }
+
+        let checks = agent_checks(
+            Path(TEST_AGENT.to_string()),
+            State(state.clone()),
+            auth_headers(TEST_TOKEN),
+        )
+        .await
+        .expect("Failed to get checks")
+        .0;
+        assert!(!checks.is_empty());
+        assert!(checks.iter().all(|check| check.service.id == service.id));
+
+        // they've been claimed, so polling again doesn't hand them out twice
+        let again = agent_checks(
+            Path(TEST_AGENT.to_string()),
+            State(state.clone()),
+            auth_headers(TEST_TOKEN),
+        )
+        .await
+        .expect("Failed to get checks")
+        .0;
+        assert!(again.is_empty());
+
+        let check = checks.first().expect("No checks returned");
+        let result = check.run().await;
+
+        let res = agent_result(
+            Path(TEST_AGENT.to_string()),
+            State(state.clone()),
+            auth_headers(TEST_TOKEN),
+            Json(result.clone()),
+        )
+        .await
+        .expect("Failed to submit result");
+        assert_eq!(res, StatusCode::NO_CONTENT);
+
+        let history = entities::service_check_history::Entity::find()
+            .filter(
+                entities::service_check_history::Column::ServiceCheckId.eq(check.service_check_id),
+            )
+            .all(&state.db)
+            .await
+            .expect("Failed to query history");
+        assert_eq!(history.len(), 1);
+        let entry = history.first().expect("No history entry");
+        assert_eq!(entry.runner.as_deref(), Some("agent:test_agent"));
+        assert_eq!(
+            entry.maremma_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+
+        // a different agent can't report on it
+        state.configuration.write().await.agents.insert(
+            "other_agent".to_string(),
+            AgentConfig {
+                token: TEST_TOKEN.to_string(),
+                zone: None,
+            },
+        );
+        let res = agent_result(
+            Path("other_agent".to_string()),
+            State(state.clone()),
+            auth_headers(TEST_TOKEN),
+            Json(result),
+        )
+        .await;
+        assert_eq!(res, Err(Error::Unauthorized));
+    }
+}
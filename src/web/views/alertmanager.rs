@@ -0,0 +1,87 @@
+//! Webhook receiver for Prometheus Alertmanager
+
+use axum::http::HeaderMap;
+use axum::Json;
+use tracing::warn;
+
+use super::agent::require_bearer;
+use super::prelude::*;
+use crate::alertmanager::{process_alerts, AlertmanagerPayload};
+use crate::errors::Error;
+
+pub(crate) async fn alertmanager_webhook(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Json(payload): Json<AlertmanagerPayload>,
+) -> Result<StatusCode, Error> {
+    let config = state
+        .configuration
+        .read()
+        .await
+        .alertmanager
+        .clone()
+        .ok_or_else(|| {
+            warn!("Alertmanager webhook received but it isn't configured");
+            Error::Unauthorized
+        })?;
+
+    require_bearer(&headers, &config.token)
+        .inspect_err(|_| warn!("Invalid token for Alertmanager webhook"))?;
+
+    let processed = process_alerts(state.db.clone(), &config, &payload).await?;
+    debug!(
+        "Processed {} alerts from Alertmanager receiver={}",
+        processed, payload.receiver
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alertmanager::{test_payload, AlertmanagerConfig, DEFAULT_ALERTMANAGER_HOST};
+    use crate::db::entities::MaremmaEntity;
+    use crate::web::views::agent::tests::auth_headers;
+
+    #[tokio::test]
+    async fn test_alertmanager_webhook() {
+        let state = WebState::test().await;
+
+        // not configured
+        let res = alertmanager_webhook(
+            State(state.clone()),
+            auth_headers("hunter2"),
+            Json(test_payload("firing")),
+        )
+        .await;
+        assert_eq!(res, Err(Error::Unauthorized));
+
+        state.configuration.write().await.alertmanager = Some(AlertmanagerConfig {
+            token: "hunter2".to_string(),
+            host: DEFAULT_ALERTMANAGER_HOST.to_string(),
+        });
+
+        let res = alertmanager_webhook(
+            State(state.clone()),
+            auth_headers("wrong"),
+            Json(test_payload("firing")),
+        )
+        .await;
+        assert_eq!(res, Err(Error::Unauthorized));
+
+        let res = alertmanager_webhook(
+            State(state.clone()),
+            auth_headers("hunter2"),
+            Json(test_payload("firing")),
+        )
+        .await;
+        assert_eq!(res, Ok(StatusCode::NO_CONTENT));
+
+        assert!(
+            entities::host::Model::find_by_name(DEFAULT_ALERTMANAGER_HOST, &state.db)
+                .await
+                .expect("Failed to query hosts")
+                .is_some()
+        );
+    }
+}
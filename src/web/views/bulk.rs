@@ -0,0 +1,297 @@
+//! Enabling, disabling, running or deleting a selection of service checks at once
+
+use axum::{Form, Json};
+use serde::Serialize;
+
+use super::prelude::*;
+use super::tools::check_csrf_token;
+use crate::constants::SESSION_CSRF_TOKEN;
+use crate::db::entities::service_check::BulkAction;
+use crate::web::Error;
+
+/// The checkboxes on the index and host pages, each selected check is its own `service_check_id` field
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BulkForm {
+    pub(crate) csrf_token: String,
+    pub(crate) action: BulkAction,
+    pub(crate) service_check_ids: Vec<Uuid>,
+    pub(crate) redirect_to: Option<String>,
+}
+
+impl TryFrom<Vec<(String, String)>> for BulkForm {
+    type Error = Error;
+
+    fn try_from(fields: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let mut csrf_token = None;
+        let mut action = None;
+        let mut service_check_ids = Vec::new();
+        let mut redirect_to = None;
+        for (key, value) in fields {
+            match key.as_str() {
+                SESSION_CSRF_TOKEN => csrf_token = Some(value),
+                "action" => {
+                    action = Some(
+                        serde_json::from_value(serde_json::Value::String(value.clone())).map_err(
+                            |_| Error::InvalidInput(format!("Unknown bulk action {}", value)),
+                        )?,
+                    )
+                }
+                "service_check_id" => {
+                    service_check_ids.push(Uuid::parse_str(&value).map_err(|_| {
+                        Error::InvalidInput(format!("Invalid service check ID {}", value))
+                    })?)
+                }
+                // only redirect within Maremma
+                "redirect_to" if value.starts_with('/') && !value.starts_with("//") => {
+                    redirect_to = Some(value)
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            csrf_token: csrf_token.ok_or(Error::CsrfTokenMissing)?,
+            action: action.ok_or_else(|| Error::InvalidInput("No action selected".to_string()))?,
+            service_check_ids,
+            redirect_to,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct BulkRequest {
+    pub(crate) action: BulkAction,
+    pub(crate) service_check_ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct BulkResponse {
+    pub(crate) action: BulkAction,
+    /// How many service checks were changed
+    pub(crate) service_checks: u64,
+}
+
+async fn bulk(
+    state: &WebState,
+    user: &User,
+    action: BulkAction,
+    service_check_ids: &[Uuid],
+) -> Result<BulkResponse, Error> {
+    let service_checks =
+        entities::service_check::Entity::bulk(&state.db, service_check_ids, action).await?;
+    info!(
+        "user={} Bulk {} of {} service checks",
+        user.username(),
+        action,
+        service_checks
+    );
+    Ok(BulkResponse {
+        action,
+        service_checks,
+    })
+}
+
+/// The selection form on the index and host pages
+pub(crate) async fn bulk_service_checks(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> Result<Redirect, Error> {
+    let user = claims.map(User::from).ok_or(Error::Unauthorized)?;
+    let form = BulkForm::try_from(fields)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+    bulk(&state, &user, form.action, &form.service_check_ids).await?;
+    Ok(Redirect::to(
+        form.redirect_to.as_deref().unwrap_or(Urls::Index.as_ref()),
+    ))
+}
+
+/// `POST /api/v1/service_check/bulk`
+pub(crate) async fn api_bulk_service_checks(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(request): Json<BulkRequest>,
+) -> Result<Json<BulkResponse>, Error> {
+    let user = claims.map(User::from).ok_or(Error::Unauthorized)?;
+    Ok(Json(
+        bulk(&state, &user, request.action, &request.service_check_ids).await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{ColumnTrait, QueryFilter, QuerySelect};
+
+    use super::*;
+    use crate::web::views::tools::test_user_claims;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_bulk_form() {
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let form = BulkForm::try_from(fields(&[
+            (SESSION_CSRF_TOKEN, "token"),
+            ("action", "urgent"),
+            ("service_check_id", &first.to_string()),
+            ("service_check_id", &second.to_string()),
+            ("redirect_to", "/host/example"),
+        ]))
+        .expect("Failed to parse form");
+        assert_eq!(
+            form,
+            BulkForm {
+                csrf_token: "token".to_string(),
+                action: BulkAction::Urgent,
+                service_check_ids: vec![first, second],
+                redirect_to: Some("/host/example".to_string()),
+            }
+        );
+
+        let form = BulkForm::try_from(fields(&[
+            (SESSION_CSRF_TOKEN, "token"),
+            ("action", "delete"),
+            ("redirect_to", "//example.com"),
+        ]))
+        .expect("Failed to parse form");
+        assert_eq!(form.redirect_to, None);
+
+        assert!(BulkForm::try_from(fields(&[("action", "delete")])).is_err());
+        assert!(BulkForm::try_from(fields(&[
+            (SESSION_CSRF_TOKEN, "token"),
+            ("action", "explode")
+        ]))
+        .is_err());
+        assert!(BulkForm::try_from(fields(&[
+            (SESSION_CSRF_TOKEN, "token"),
+            ("action", "enable"),
+            ("service_check_id", "not-a-uuid"),
+        ]))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_service_checks() {
+        let state = WebState::test().await;
+        let ids: Vec<Uuid> = entities::service_check::Entity::find()
+            .select_only()
+            .column(entities::service_check::Column::Id)
+            .limit(2)
+            .into_tuple()
+            .all(&state.db)
+            .await
+            .expect("Failed to query service checks");
+        let session = state.get_session();
+        session
+            .insert(SESSION_CSRF_TOKEN, "bulk".to_string())
+            .await
+            .expect("Failed to insert CSRF token into session");
+
+        let form = |csrf_token: &str| {
+            let mut form = fields(&[(SESSION_CSRF_TOKEN, csrf_token), ("action", "disable")]);
+            form.extend(
+                ids.iter()
+                    .map(|id| ("service_check_id".to_string(), id.to_string())),
+            );
+            Form(form)
+        };
+
+        assert!(
+            bulk_service_checks(State(state.clone()), None, session.clone(), form("bulk"))
+                .await
+                .is_err()
+        );
+        assert!(bulk_service_checks(
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+            form("wrong"),
+        )
+        .await
+        .is_err());
+
+        bulk_service_checks(
+            State(state.clone()),
+            Some(test_user_claims()),
+            session,
+            form("bulk"),
+        )
+        .await
+        .expect("Failed to disable service checks");
+        let checks = entities::service_check::Entity::find()
+            .filter(entities::service_check::Column::Id.is_in(ids.clone()))
+            .all(&state.db)
+            .await
+            .expect("Failed to query service checks");
+        assert_eq!(checks.len(), ids.len());
+        assert!(checks
+            .iter()
+            .all(|check| check.status == ServiceStatus::Disabled));
+    }
+
+    #[tokio::test]
+    async fn test_api_bulk_service_checks() {
+        let state = WebState::test().await;
+        let ids: Vec<Uuid> = entities::service_check::Entity::find()
+            .select_only()
+            .column(entities::service_check::Column::Id)
+            .limit(2)
+            .into_tuple()
+            .all(&state.db)
+            .await
+            .expect("Failed to query service checks");
+
+        assert!(api_bulk_service_checks(
+            State(state.clone()),
+            None,
+            Json(BulkRequest {
+                action: BulkAction::Urgent,
+                service_check_ids: ids.clone(),
+            }),
+        )
+        .await
+        .is_err());
+
+        let Json(response) = api_bulk_service_checks(
+            State(state.clone()),
+            Some(test_user_claims()),
+            Json(BulkRequest {
+                action: BulkAction::Urgent,
+                service_check_ids: ids.clone(),
+            }),
+        )
+        .await
+        .expect("Failed to set service checks urgent");
+        assert_eq!(
+            response,
+            BulkResponse {
+                action: BulkAction::Urgent,
+                service_checks: ids.len() as u64,
+            }
+        );
+
+        let Json(response) = api_bulk_service_checks(
+            State(state.clone()),
+            Some(test_user_claims()),
+            Json(BulkRequest {
+                action: BulkAction::Delete,
+                service_check_ids: ids.clone(),
+            }),
+        )
+        .await
+        .expect("Failed to delete service checks");
+        assert_eq!(response.service_checks, ids.len() as u64);
+        assert!(entities::service_check::Entity::find()
+            .filter(entities::service_check::Column::Id.is_in(ids))
+            .all(&state.db)
+            .await
+            .expect("Failed to query service checks")
+            .is_empty());
+    }
+}
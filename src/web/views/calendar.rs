@@ -0,0 +1,249 @@
+//! An iCalendar feed of paused checks and recent incidents, so teams can subscribe to it from their calendar app
+
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue};
+use chrono::{TimeDelta, Utc};
+use sea_orm::{ColumnTrait, FromQueryResult, QueryFilter};
+
+use super::prelude::*;
+use crate::db::entities::incident::IncidentFilter;
+use crate::db::entities::service_check::FullServiceCheck;
+use crate::web::Error;
+
+/// How far back the feed's incidents go
+const CALENDAR_INCIDENT_DAYS: i64 = 30;
+
+/// The most incidents in the feed
+const MAX_CALENDAR_INCIDENTS: u64 = 1000;
+
+/// iCalendar lines longer than this many bytes get folded onto the next line
+const MAX_LINE_LENGTH: usize = 75;
+
+/// A check that's paused until a set time, which is as close as Maremma gets to scheduled maintenance
+#[derive(Debug, FromQueryResult)]
+struct PausedCheck {
+    id: Uuid,
+    host_name: String,
+    host_display_name: Option<String>,
+    service_name: String,
+    /// When it was paused, nothing else updates a check while it's paused
+    last_updated: DateTime<Utc>,
+    paused_until: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CalendarEvent {
+    uid: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    summary: String,
+    description: String,
+    url: String,
+}
+
+/// Escapes the characters iCalendar gives a meaning to in text values
+fn escape_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Splits long lines up, continuation lines start with a space and nothing's split in the middle of a character
+fn fold_line(line: &str) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            res.push_str("\r\n ");
+            length = 1;
+        }
+        res.push(c);
+        length += c.len_utf8();
+    }
+    res
+}
+
+fn ics_time(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Builds the feed, lines end with CRLF like the spec says
+fn to_ics(events: &[CalendarEvent], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//Maremma//Maremma//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Maremma".to_string(),
+    ];
+    for event in events {
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{}", event.uid),
+            format!("DTSTAMP:{}", ics_time(now)),
+            format!("DTSTART:{}", ics_time(event.start)),
+            format!("DTEND:{}", ics_time(event.end)),
+            format!("SUMMARY:{}", escape_text(&event.summary)),
+            format!("DESCRIPTION:{}", escape_text(&event.description)),
+            format!("URL:{}", event.url),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold_line(line) + "\r\n").collect()
+}
+
+/// `GET /api/v1/calendar.ics`, checks which are paused until a set time and the last 30 days of incidents
+pub(crate) async fn api_calendar(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<(HeaderMap, String), Error> {
+    claims.ok_or(Error::Unauthorized)?;
+    let now = Utc::now();
+    let frontend_url = state
+        .configuration
+        .read()
+        .await
+        .frontend_url
+        .trim_end_matches('/')
+        .to_string();
+    let check_url = |service_check_id: Uuid| {
+        format!(
+            "{}{}/{}",
+            frontend_url,
+            Urls::ServiceCheck,
+            service_check_id
+        )
+    };
+
+    let paused = FullServiceCheck::all_query()
+        .filter(entities::service_check::Column::PausedUntil.is_not_null())
+        .into_model::<PausedCheck>()
+        .all(&state.db)
+        .await?;
+    let mut events = paused
+        .into_iter()
+        .map(|check| {
+            let host = check.host_display_name.unwrap_or(check.host_name);
+            CalendarEvent {
+                uid: format!(
+                    "pause-{}-{}@maremma",
+                    check.id,
+                    check.paused_until.timestamp()
+                ),
+                start: check.last_updated.min(check.paused_until),
+                end: check.paused_until,
+                summary: format!("Paused: {} on {}", check.service_name, host),
+                description: format!(
+                    "{} on {} is paused until {}",
+                    check.service_name, host, check.paused_until
+                ),
+                url: check_url(check.id),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let incidents = entities::incident::Entity::search(
+        &state.db,
+        &IncidentFilter {
+            since: Some(now - TimeDelta::days(CALENDAR_INCIDENT_DAYS)),
+            limit: MAX_CALENDAR_INCIDENTS,
+            ..Default::default()
+        },
+    )
+    .await?;
+    events.extend(incidents.into_iter().map(|incident| {
+        let summary = format!(
+            "{}: {} on {}",
+            incident.worst_status,
+            incident.service_name,
+            incident.host_display_name()
+        );
+        CalendarEvent {
+            uid: format!("incident-{}@maremma", incident.id),
+            start: incident.started_at,
+            // open incidents run until now, and grow each time the feed's fetched
+            end: incident.ended_at.unwrap_or(now),
+            description: match incident.ended_at {
+                Some(_) => format!(
+                    "{} ({} failing results, last {})",
+                    summary, incident.results, incident.last_status
+                ),
+                None => format!(
+                    "{} (still failing, {} failing results so far)",
+                    summary, incident.results
+                ),
+            },
+            summary,
+            url: check_url(incident.service_check_id),
+        }
+    }));
+    events.sort_by_key(|event| event.start);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/calendar; charset=utf-8"),
+    );
+    Ok((headers, to_ics(&events, now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::views::tools::test_user_claims;
+
+    #[test]
+    fn test_escape_and_fold() {
+        assert_eq!(escape_text("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+
+        let line = format!("SUMMARY:{}", "é".repeat(50));
+        let folded = fold_line(&line);
+        assert!(folded
+            .split("\r\n")
+            .all(|part| part.len() <= MAX_LINE_LENGTH));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+
+    #[tokio::test]
+    async fn test_api_calendar() {
+        let state = WebState::test().await;
+        assert!(api_calendar(State(state.clone()), None).await.is_err());
+
+        let check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        entities::incident::Entity::record(
+            &state.db,
+            check.id,
+            ServiceStatus::Critical,
+            Utc::now() - TimeDelta::hours(1),
+        )
+        .await
+        .expect("Failed to record result");
+        entities::service_check::Entity::pause(
+            &state.db,
+            entities::service_check::PauseTarget::Host(check.host_id),
+            Some(Utc::now() + TimeDelta::hours(2)),
+        )
+        .await
+        .expect("Failed to pause checks");
+
+        let (headers, body) = api_calendar(State(state.clone()), Some(test_user_claims()))
+            .await
+            .expect("Failed to get calendar");
+        assert_eq!(
+            headers.get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("text/calendar; charset=utf-8"))
+        );
+        assert!(body.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(body.ends_with("END:VCALENDAR\r\n"));
+        assert!(body.contains("UID:incident-"));
+        assert!(body.contains("SUMMARY:Paused: "));
+        assert!(body.contains(&format!("/service_check/{}", check.id)));
+    }
+}
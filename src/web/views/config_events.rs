@@ -0,0 +1,84 @@
+//! Streams the [crate::config_events] as server-sent events, for things that would rather not run a webhook receiver
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use super::prelude::*;
+use crate::config_events::CONFIG_EVENTS;
+use crate::web::Error;
+
+/// `GET /api/v1/config/events`, a `config` event each time hosts, services or host groups change
+pub(crate) async fn api_config_events(
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, Error> {
+    claims.ok_or(Error::Unauthorized)?;
+    let events = futures::stream::unfold(CONFIG_EVENTS.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((event, events)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Config event stream skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .map(|event| Event::default().event("config").json_data(event));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::config_events::{publish, ChangeSource, Snapshot};
+    use crate::web::views::tools::test_user_claims;
+
+    #[tokio::test]
+    async fn test_api_config_events() {
+        let state = WebState::test().await;
+        assert!(api_config_events(None).await.is_err());
+
+        let mut body = api_config_events(Some(test_user_claims()))
+            .await
+            .expect("Failed to get events")
+            .into_response()
+            .into_body()
+            .into_data_stream();
+
+        let before = Snapshot::take(&state.db)
+            .await
+            .expect("Failed to take snapshot");
+        let host = entities::host::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+        host.clone()
+            .delete(&state.db)
+            .await
+            .expect("Failed to delete host");
+        publish(&state.db, &state.configuration, &before, ChangeSource::Web)
+            .await
+            .expect("Failed to publish");
+
+        // other tests share the channel, so look for this one
+        loop {
+            let event = body
+                .next()
+                .await
+                .expect("Stream ended")
+                .expect("Failed to read event");
+            let event = String::from_utf8_lossy(&event);
+            if event.contains(&format!("\"name\":\"{}\"", host.name))
+                && event.contains("\"removed\"")
+            {
+                assert!(event.contains("event: config"));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Reviewing the hosts found by `maremma discover`
+
+use axum::Form;
+use sea_orm::DatabaseConnection;
+
+use super::prelude::*;
+use super::tools::{check_csrf_token, ActionStatus, CsrfTokenForm, ToolsQuery};
+use crate::config::apply_config;
+use crate::constants::SESSION_CSRF_TOKEN;
+use crate::db::entities::discovered_host;
+use crate::db::update_db_from_config;
+use crate::web::{Configuration, Error};
+
+#[derive(Template, Debug)]
+#[template(path = "discovery.html")]
+pub(crate) struct DiscoveryTemplate {
+    title: String,
+    username: Option<String>,
+    theme: PageTheme,
+    message: Option<String>,
+    status: ActionStatus,
+    csrf_token: String,
+    discovered_hosts: Vec<discovered_host::Model>,
+}
+
+/// Seen at `/discovery`
+pub(crate) async fn discovery(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Query(results): Query<ToolsQuery>,
+    session: Session,
+) -> Result<DiscoveryTemplate, Error> {
+    let Some(claims) = claims else {
+        // TODO: check that the user is an admin
+        return Err(Error::Unauthorized);
+    };
+
+    let discovered_hosts = discovered_host::Entity::pending(&state.db).await?;
+
+    let csrf_token = state.new_csrf_token();
+    session.insert(SESSION_CSRF_TOKEN, &csrf_token).await?;
+
+    Ok(DiscoveryTemplate {
+        title: "Discovery".to_string(),
+        username: Some(User::from(claims).username()),
+        theme: PageTheme::new(&state, &session).await?,
+        message: results.result,
+        status: results.status,
+        csrf_token,
+        discovered_hosts,
+    })
+}
+
+async fn find_discovered_host(
+    db: &DatabaseConnection,
+    id: Uuid,
+) -> Result<discovered_host::Model, Error> {
+    discovered_host::Entity::find_by_id(id)
+        .one(db)
+        .await?
+        .ok_or_else(|| Error::InvalidInput(format!("Discovered host {} not found", id)))
+}
+
+/// Adds the host to the config file, then reloads the config so it's checked straight away
+pub(crate) async fn discovery_accept(
+    Path(id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<CsrfTokenForm>,
+) -> Result<Redirect, Error> {
+    let Some(claims) = claims else {
+        // TODO: check that the user is an admin
+        return Err(Error::Unauthorized);
+    };
+    check_csrf_token(&form.csrf_token, &session).await?;
+
+    let proposal = find_discovered_host(&state.db, id).await?;
+    crate::discovery::accept(&state.config_filepath, &state.db, &[proposal.clone()])
+        .await
+        .inspect_err(|err| error!("Failed to accept discovered host: {:?}", err))?;
+    info!(
+        "user={} Added discovered host {} to the config",
+        User::from(claims).username(),
+        proposal.name()
+    );
+
+    let config = Configuration::new(&state.config_filepath).await?;
+    apply_config(&config).await?;
+    *state.configuration.write().await = config;
+    update_db_from_config(state.db.clone(), state.configuration.clone()).await?;
+
+    Ok(Redirect::to(&format!(
+        "{}?result=Added {} to the config&status={}",
+        Urls::Discovery,
+        proposal.name(),
+        ActionStatus::Success,
+    )))
+}
+
+/// Hides the host, it won't be proposed again
+pub(crate) async fn discovery_dismiss(
+    Path(id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<CsrfTokenForm>,
+) -> Result<Redirect, Error> {
+    if claims.is_none() {
+        // TODO: check that the user is an admin
+        return Err(Error::Unauthorized);
+    }
+    check_csrf_token(&form.csrf_token, &session).await?;
+
+    let mut proposal = find_discovered_host(&state.db, id)
+        .await?
+        .into_active_model();
+    proposal.dismissed = sea_orm::Set(true);
+    let proposal = proposal.update(&state.db).await?;
+
+    Ok(Redirect::to(&format!(
+        "{}?result=Dismissed {}&status={}",
+        Urls::Discovery,
+        proposal.name(),
+        ActionStatus::Success,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::db::entities::MaremmaEntity;
+    use crate::discovery::DiscoveredHost;
+    use crate::prelude::ServiceType;
+    use crate::web::views::tools::test_user_claims;
+
+    async fn state_with_proposal() -> (WebState, discovered_host::Model, String) {
+        let state = WebState::test().await;
+        let proposal = discovered_host::Entity::record(
+            &state.db,
+            "scan",
+            &DiscoveredHost {
+                address: "192.0.2.20".parse().expect("Failed to parse address"),
+                hostname: None,
+                open_ports: vec![443],
+                suggested_checks: vec![ServiceType::Http, ServiceType::Tls],
+            },
+            &BTreeSet::new(),
+            false,
+        )
+        .await
+        .expect("Failed to record host");
+        let csrf_token = "discovery".to_string();
+        (state, proposal, csrf_token)
+    }
+
+    #[tokio::test]
+    async fn test_discovery_page() {
+        let (state, proposal, _csrf_token) = state_with_proposal().await;
+
+        let res = discovery(
+            State(state.clone()),
+            None,
+            Query(ToolsQuery::default()),
+            state.get_session(),
+        )
+        .await;
+        assert_eq!(res.into_response().status(), StatusCode::UNAUTHORIZED);
+
+        let page = discovery(
+            State(state.clone()),
+            Some(test_user_claims()),
+            Query(ToolsQuery::default()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to render discovery page");
+        assert_eq!(page.discovered_hosts, vec![proposal]);
+        assert!(page.to_string().contains("192.0.2.20"));
+    }
+
+    #[tokio::test]
+    async fn test_discovery_accept_and_dismiss() {
+        let (mut state, proposal, csrf_token) = state_with_proposal().await;
+        let _lock = crate::config::APPLY_CONFIG_LOCK.lock().await;
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create config file");
+        tokio::fs::copy("maremma.example.json", config_file.path())
+            .await
+            .expect("Failed to copy example config");
+        state.config_filepath = config_file.path().to_path_buf();
+
+        let session = state.get_session();
+        session
+            .insert(SESSION_CSRF_TOKEN, csrf_token.clone())
+            .await
+            .expect("Failed to insert CSRF token into session");
+
+        // the token has to match
+        assert!(discovery_dismiss(
+            Path(proposal.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+            Form(CsrfTokenForm {
+                csrf_token: "wrong".to_string(),
+            }),
+        )
+        .await
+        .is_err());
+
+        discovery_accept(
+            Path(proposal.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+            Form(CsrfTokenForm {
+                csrf_token: csrf_token.clone(),
+            }),
+        )
+        .await
+        .expect("Failed to accept host");
+        assert!(state
+            .configuration
+            .read()
+            .await
+            .hosts
+            .contains_key("192.0.2.20"));
+        assert!(entities::host::Model::find_by_name("192.0.2.20", &state.db)
+            .await
+            .expect("Failed to search for host")
+            .is_some());
+
+        // it's gone from the list, so there's nothing left to dismiss
+        assert!(discovery_dismiss(
+            Path(proposal.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session,
+            Form(CsrfTokenForm { csrf_token }),
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,28 @@
+//! Filters for the templates, askama finds them through the views prelude
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// A timestamp which `maremma.js` shows relative to now, in the user's timezone
+pub(crate) fn localtime(timestamp: &DateTime<Utc>) -> askama::Result<String> {
+    Ok(format!(
+        "<time class=\"maremma-time\" datetime=\"{}\">{}</time>",
+        timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+        timestamp.format("%Y-%m-%d %H:%M:%S UTC")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_localtime() {
+        let timestamp = DateTime::parse_from_rfc3339("2025-01-18T01:02:03Z")
+            .expect("Failed to parse timestamp")
+            .with_timezone(&Utc);
+        assert_eq!(
+            localtime(&timestamp).expect("Failed to format timestamp"),
+            "<time class=\"maremma-time\" datetime=\"2025-01-18T01:02:03Z\">2025-01-18 01:02:03 UTC</time>"
+        );
+    }
+}
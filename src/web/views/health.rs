@@ -0,0 +1,50 @@
+//! `/healthz` and `/readyz`, for Kubernetes probes, they don't need a login
+
+use axum::Json;
+
+use super::prelude::*;
+use crate::health::{check_health, HealthReport, HEARTBEATS};
+
+fn respond(report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status = if report.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// `GET /healthz`, fails when something's broken in a way a restart might fix
+pub(crate) async fn healthz(State(state): State<WebState>) -> impl IntoResponse {
+    respond(check_health(&state.db, &HEARTBEATS, false).await)
+}
+
+/// `GET /readyz`, also fails until the check loop and the shepherd have started
+pub(crate) async fn readyz(State(state): State<WebState>) -> impl IntoResponse {
+    respond(check_health(&state.db, &HEARTBEATS, true).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::Component;
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let state = WebState::test().await;
+
+        let response = healthz(State(state.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        HEARTBEATS.beat(Component::CheckLoop);
+        HEARTBEATS.beat(Component::Shepherd);
+        let response = readyz(State(state.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let report: serde_json::Value = serde_json::from_slice(&body).expect("Failed to parse");
+        assert_eq!(report["status"], "ok");
+        assert_eq!(report["components"]["database"]["status"], "ok");
+    }
+}
@@ -0,0 +1,110 @@
+//! Ping URLs for heartbeat service checks
+
+use tracing::warn;
+
+use super::prelude::*;
+use crate::check_loop::{record_check_result, CheckEnvironment, CheckResult};
+use crate::errors::Error;
+use crate::services::ServiceType;
+
+pub(crate) async fn heartbeat_ping(
+    Path(token): Path<String>,
+    State(state): State<WebState>,
+) -> Result<StatusCode, Error> {
+    let now = chrono::Utc::now();
+    let heartbeat = entities::service_check_heartbeat::Entity::ping(&state.db, &token, now)
+        .await?
+        .ok_or_else(|| {
+            warn!("Heartbeat ping with an unknown token");
+            Error::Unauthorized
+        })?;
+
+    let (service_check, service) =
+        entities::service_check::Entity::find_by_id(heartbeat.service_check_id)
+            .find_also_related(entities::service::Entity)
+            .one(&state.db)
+            .await?
+            .ok_or(Error::ServiceCheckNotFound(heartbeat.service_check_id))?;
+    let service = service.ok_or(Error::ServiceNotFound(service_check.service_id))?;
+    if service.service_type != ServiceType::Heartbeat {
+        warn!(
+            "Heartbeat ping for service_check={} which isn't a heartbeat service",
+            service_check.id
+        );
+        return Err(Error::Unauthorized);
+    }
+
+    let result = CheckResult {
+        timestamp: now,
+        status: ServiceStatus::Ok,
+        result_text: "Heartbeat received".to_string(),
+        ..Default::default()
+    };
+    let environment = CheckEnvironment {
+        runner: "heartbeat".to_string(),
+        ..CheckEnvironment::local(None)
+    };
+    record_check_result(
+        state.db.clone(),
+        &service_check,
+        &service,
+        &result,
+        &environment,
+        0,
+    )
+    .await?;
+    debug!("Heartbeat received for service_check={}", service_check.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::Set;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_heartbeat_ping() {
+        let state = WebState::test().await;
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let heartbeat = entities::service_check_heartbeat::Entity::for_service_check(
+            &state.db,
+            service_check.id,
+        )
+        .await
+        .expect("Failed to create heartbeat");
+
+        let res = heartbeat_ping(Path("not a token".to_string()), State(state.clone())).await;
+        assert_eq!(res, Err(Error::Unauthorized));
+
+        // it's not a heartbeat service yet
+        let res = heartbeat_ping(Path(heartbeat.token.clone()), State(state.clone())).await;
+        assert_eq!(res, Err(Error::Unauthorized));
+
+        let mut service = entities::service::Entity::find_by_id(service_check.service_id)
+            .one(&state.db)
+            .await
+            .expect("Failed to query service")
+            .expect("Service went missing")
+            .into_active_model();
+        service.service_type = Set(ServiceType::Heartbeat);
+        service.extra_config = Set(json!({"interval_minutes": 60}));
+        service
+            .update(&state.db)
+            .await
+            .expect("Failed to update service");
+
+        let res = heartbeat_ping(Path(heartbeat.token.clone()), State(state.clone())).await;
+        assert_eq!(res, Ok(StatusCode::NO_CONTENT));
+        let found = entities::service_check::Entity::find_by_id(service_check.id)
+            .one(&state.db)
+            .await
+            .expect("Failed to query service check")
+            .expect("Service check went missing");
+        assert_eq!(found.status, ServiceStatus::Ok);
+    }
+}
@@ -1,12 +1,15 @@
 use super::index::SortQueries;
 use super::prelude::*;
 
-use crate::constants::SESSION_CSRF_TOKEN;
+use crate::config_events::{publish, ChangeSource, Snapshot};
+use crate::constants::{DEFAULT_PAGE_SIZE, RECENT_INCIDENTS, SESSION_CSRF_TOKEN};
+use crate::db::entities::incident::{FullIncident, IncidentFilter};
 use crate::db::entities::service_check::FullServiceCheck;
 use crate::errors::Error;
+use crate::labels::LabelFilter;
 use axum::Form;
 use entities::host_group;
-use sea_orm::{ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder};
+use sea_orm::{ColumnTrait, EntityTrait, ModelTrait, PaginatorTrait, QueryFilter, QueryOrder};
 use uuid::Uuid;
 
 #[derive(Template, Debug)]
@@ -14,11 +17,16 @@ use uuid::Uuid;
 pub(crate) struct HostTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     host: entities::host::Model,
     checks: Vec<entities::service_check::FullServiceCheck>,
     host_groups: Vec<host_group::Model>,
+    /// The most recent incidents on the host's checks
+    incidents: Vec<FullIncident>,
     page_refresh: u64,
     csrf_token: String,
+    /// If it's one of the user's favorites
+    favorite: bool,
 }
 
 #[derive(Default, Deserialize, Debug)]
@@ -31,7 +39,7 @@ pub(crate) enum Order {
 
 /// Host view
 pub(crate) async fn host(
-    Path(host_id): Path<Uuid>,
+    Path(host_id): Path<String>,
     State(state): State<WebState>,
     Query(queries): Query<SortQueries>,
     session: Session,
@@ -57,11 +65,14 @@ pub(crate) async fn host(
         OrderFields::NextCheck => entities::service_check::Column::NextCheck,
     };
 
-    let db_reader = state.db.read().await;
-
-    let (host, host_groups) = match entities::host::Entity::find_by_id(host_id)
+    let (host, host_groups) = match entities::host::Entity::find()
+        .filter(entities::id_or_slug(
+            entities::host::Column::Id,
+            entities::host::Column::Slug,
+            &host_id,
+        ))
         .find_with_linked(entities::host_group_members::HostToGroups)
-        .all(&*db_reader)
+        .all(&state.db)
         .await
         .map_err(Error::from)?
         .into_iter()
@@ -80,21 +91,40 @@ pub(crate) async fn host(
         .filter(entities::service_check::Column::HostId.eq(host.id))
         .order_by(order_column, queries.ord.unwrap_or_default().into())
         .into_model::<FullServiceCheck>()
-        .all(&*db_reader)
+        .all(&state.db)
         .await
         .map_err(|err| {
             error!("Failed to look up service checks for host={host_id} error={err:?}");
             Error::from(err)
         })?;
 
+    let incidents = entities::incident::Entity::search(
+        &state.db,
+        &IncidentFilter {
+            host_id: Some(host.id),
+            limit: RECENT_INCIDENTS,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let theme = PageTheme::new(&state, &session).await?;
+    let favorite = session_preferences(&session)
+        .await?
+        .favorite_hosts
+        .contains(&host.id);
+
     Ok(HostTemplate {
-        title: host.hostname.to_owned(),
+        title: host.display_name().to_owned(),
         checks,
+        incidents,
         host,
         host_groups,
         username: Some(user.username()),
-        page_refresh: 30,
+        page_refresh: theme.refresh_or(30),
+        theme,
         csrf_token,
+        favorite,
     })
 }
 
@@ -103,21 +133,31 @@ pub(crate) async fn host(
 pub(crate) struct HostsTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     hosts: Vec<entities::host::Model>,
     search_string: String,
+    /// Only showing hosts with this label
+    label: Option<LabelFilter>,
+    pagination: Pagination,
 }
 
 #[derive(Deserialize, Debug, Default)]
 pub(crate) struct HostsQuery {
     pub(crate) search: Option<String>,
-    #[serde(flatten)]
-    pub(crate) queries: SortQueries,
+    /// Only show hosts with this label, eg `env:prod`
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub(crate) label: Option<LabelFilter>,
+    pub(crate) ord: Option<Order>,
+    pub(crate) field: Option<OrderFields>,
+    /// Starts at 1
+    pub(crate) page: Option<u64>,
+    pub(crate) per_page: Option<u64>,
 }
 
 pub(crate) async fn hosts(
     State(state): State<WebState>,
     Query(queries): Query<HostsQuery>,
-    _session: Session,
+    session: Session,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
 ) -> Result<HostsTemplate, (StatusCode, String)> {
     let user = check_login(claims)?;
@@ -129,13 +169,17 @@ pub(crate) async fn hosts(
             hosts = hosts.filter(
                 entities::host::Column::Hostname
                     .like(search_string.clone())
-                    .or(entities::host::Column::Name.like(search_string)),
+                    .or(entities::host::Column::Name.like(search_string.clone()))
+                    .or(entities::host::Column::DisplayName.like(search_string)),
             );
         }
     }
+    if let Some(label) = &queries.label {
+        hosts = hosts.filter(label.condition(entities::host::Column::Labels));
+    }
 
-    let ord = queries.queries.ord.unwrap_or(super::prelude::Order::Asc);
-    let order_column = match queries.queries.field.unwrap_or_default() {
+    let ord = queries.ord.unwrap_or(super::prelude::Order::Asc);
+    let order_column = match queries.field.unwrap_or_default() {
         OrderFields::Host => entities::host::Column::Hostname,
         OrderFields::Service => entities::host::Column::Hostname,
         OrderFields::LastUpdated => entities::host::Column::Hostname,
@@ -143,17 +187,38 @@ pub(crate) async fn hosts(
         OrderFields::Status => entities::host::Column::Check,
         OrderFields::Check => entities::host::Column::Check,
     };
-    let hosts = hosts
+    let per_page = Pagination::page_size(
+        queries.per_page,
+        session_preferences(&session)
+            .await?
+            .page_size
+            .unwrap_or(DEFAULT_PAGE_SIZE),
+    );
+    let paginator = hosts
         .order_by(order_column, ord.into())
-        .all(&*state.db.read().await)
+        .paginate(&state.db, per_page);
+    let pagination = Pagination::new(
+        queries.page,
+        per_page,
+        paginator.num_items().await.map_err(Error::from)?,
+    )
+    .with_param("search", queries.search.as_ref())
+    .with_param("label", queries.label.as_ref())
+    .with_param("ord", queries.ord)
+    .with_param("field", queries.field);
+    let hosts = paginator
+        .fetch_page(pagination.page - 1)
         .await
         .map_err(Error::from)?;
 
     Ok(HostsTemplate {
         title: "Hosts".to_string(),
         username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
         hosts,
         search_string: queries.search.unwrap_or_default(),
+        label: queries.label,
+        pagination,
     })
 }
 
@@ -196,9 +261,8 @@ pub(crate) async fn delete_host(
         return Err((StatusCode::FORBIDDEN, "CSRF Token mismatch".to_string()));
     }
 
-    let db_writer = state.db.write().await;
     let host = match entities::host::Entity::find_by_id(host_id)
-        .one(&*db_writer)
+        .one(&state.db)
         .await
         .map_err(Error::from)?
     {
@@ -211,7 +275,9 @@ pub(crate) async fn delete_host(
         }
     };
 
-    host.delete(&*db_writer).await.map_err(Error::from)?;
+    let before = Snapshot::take(&state.db).await?;
+    host.delete(&state.db).await.map_err(Error::from)?;
+    publish(&state.db, &state.configuration, &before, ChangeSource::Web).await?;
     Ok(Redirect::to(Urls::Hosts.as_ref()))
 }
 
@@ -228,7 +294,7 @@ mod tests {
         let state = WebState::test().await;
 
         let host = entities::host::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
@@ -248,12 +314,13 @@ mod tests {
                 Some(OrderFields::Check),
             ] {
                 let res = super::host(
-                    Path(host.id),
+                    Path(host.id.to_string()),
                     State(state.clone()),
                     Query(SortQueries {
                         ord,
                         field,
                         search: None,
+                        ..Default::default()
                     }),
                     state.get_session(),
                     Some(crate::web::views::tools::test_user_claims()),
@@ -270,13 +337,13 @@ mod tests {
         let _ = test_setup().await.expect("Failed to set up test");
         let state = WebState::test().await;
         let host = entities::host::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
 
         let res = super::host(
-            Path(host.id),
+            Path(host.id.to_string()),
             State(state.clone()),
             Query(SortQueries::default()),
             state.get_session(),
@@ -296,7 +363,7 @@ mod tests {
 
         let mut host_id = Uuid::new_v4();
         while entities::host::Entity::find_by_id(host_id)
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for host")
             .is_some()
@@ -305,7 +372,7 @@ mod tests {
         }
 
         let res = super::host(
-            Path(host_id),
+            Path(host_id.to_string()),
             State(state.clone()),
             Query(SortQueries::default()),
             state.get_session(),
@@ -331,11 +398,9 @@ mod tests {
                         State(state.clone()),
                         Query(HostsQuery {
                             search: search.clone(),
-                            queries: SortQueries {
-                                field,
-                                ord,
-                                search: None,
-                            },
+                            field,
+                            ord,
+                            ..Default::default()
                         }),
                         session,
                         Some(test_user_claims()),
@@ -352,6 +417,43 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_view_hosts_label_filter() {
+        use super::*;
+        use sea_orm::{ActiveModelTrait, IntoActiveModel, Set};
+
+        let state = WebState::test().await;
+        let host = entities::host::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+        let mut labelled = host.clone().into_active_model();
+        labelled.labels = Set(serde_json::json!({"env": "prod"}));
+        labelled
+            .update(&state.db)
+            .await
+            .expect("Failed to update host");
+
+        for (label, found) in [("env:prod", true), ("env", true), ("env:dev", false)] {
+            let page = super::hosts(
+                State(state.clone()),
+                Query(HostsQuery {
+                    label: Some(label.parse().expect("Failed to parse label")),
+                    ..Default::default()
+                }),
+                state.get_session(),
+                Some(test_user_claims()),
+            )
+            .await
+            .expect("Failed to list hosts");
+            assert_eq!(page.hosts.iter().any(|h| h.id == host.id), found);
+            if found {
+                assert!(page.to_string().contains("env: prod"));
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_view_delete_host_with_auth() {
         use super::*;
@@ -359,7 +461,7 @@ mod tests {
         let state = WebState::test().await;
 
         let host = entities::host::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for host")
             .expect("No host found");
@@ -395,7 +497,7 @@ mod tests {
 
         let mut nonexistent_host_id = Uuid::new_v4();
         while entities::host::Entity::find_by_id(nonexistent_host_id)
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for host")
             .is_some()
@@ -8,11 +8,13 @@ use axum::response::Redirect;
 use axum_oidc::{EmptyAdditionalClaims, OidcClaims};
 use sea_orm::{ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder};
 use serde::Deserialize;
+use std::collections::HashMap;
 use tracing::{debug, info};
 use uuid::Uuid;
 
 use super::prelude::*;
-use crate::db::entities::{host, host_group, host_group_members};
+use crate::config_events::{publish, ChangeSource, Snapshot};
+use crate::db::entities::{host, host_group, host_group_members, host_group_status};
 use crate::web::oidc::User;
 use crate::web::{Error, WebState};
 
@@ -21,18 +23,22 @@ use crate::web::{Error, WebState};
 pub(crate) struct HostGroupsTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     host_groups: Vec<HostGroupData>,
 }
 
 pub(crate) struct HostGroupData {
-    id: Uuid,
+    slug: String,
     name: String,
     hosts: usize,
+    /// Not set until the shepherd's worked it out
+    status: Option<host_group_status::Model>,
 }
 
 pub(crate) async fn host_groups(
     State(state): State<WebState>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
 ) -> Result<HostGroupsTemplate, (StatusCode, String)> {
     if claims.is_none() {
         return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
@@ -40,17 +46,25 @@ pub(crate) async fn host_groups(
     let res = host_group::Entity::find()
         .order_by_asc(host_group::Column::Name)
         .find_with_linked(host_group_members::GroupToHosts)
-        .all(&*state.db.read().await)
+        .all(&state.db)
         .await
         .map_err(|e| {
             error!("Failed to fetch host groups: {}", e);
             Error::from(e)
         })?;
+    let mut statuses = host_group_status::Entity::find()
+        .all(&state.db)
+        .await
+        .map_err(Error::from)?
+        .into_iter()
+        .map(|status| (status.host_group_id, status))
+        .collect::<HashMap<_, _>>();
 
     let host_groups = res
         .into_iter()
         .map(|(group, hosts)| HostGroupData {
-            id: group.id,
+            status: statuses.remove(&group.id),
+            slug: group.slug,
             name: group.name,
             hosts: hosts.len(),
         })
@@ -59,6 +73,7 @@ pub(crate) async fn host_groups(
     Ok(HostGroupsTemplate {
         title: "Host Groups".to_string(),
         username: None,
+        theme: PageTheme::new(&state, &session).await?,
         host_groups,
     })
 }
@@ -68,6 +83,7 @@ pub(crate) async fn host_groups(
 pub(crate) struct HostGroupTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     host_group: host_group::Model,
     members: Vec<host::Model>,
     message: Option<String>,
@@ -80,10 +96,11 @@ pub(crate) struct HostGroupQueries {
 }
 
 pub(crate) async fn host_group(
-    Path(id): Path<Uuid>,
+    Path(id): Path<String>,
     Query(query): Query<HostGroupQueries>,
     State(state): State<WebState>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
 ) -> Result<HostGroupTemplate, (StatusCode, String)> {
     if claims.is_none() {
         // TODO: check that the user is an admin
@@ -91,9 +108,13 @@ pub(crate) async fn host_group(
     }
 
     let host_group = host_group::Entity::find()
-        .filter(host_group::Column::Id.eq(id))
+        .filter(entities::id_or_slug(
+            host_group::Column::Id,
+            host_group::Column::Slug,
+            &id,
+        ))
         .find_with_linked(host_group_members::GroupToHosts)
-        .all(&*state.db.read().await)
+        .all(&state.db)
         .await
         .map_err(|e| {
             error!("Failed to fetch host groups: {}", e);
@@ -113,6 +134,7 @@ pub(crate) async fn host_group(
     Ok(HostGroupTemplate {
         title: format!("Host Group: {}", host_group.name),
         username: None,
+        theme: PageTheme::new(&state, &session).await?,
         host_group,
         members,
         message: query.message,
@@ -133,6 +155,7 @@ pub(crate) async fn host_group_member_delete(
     };
 
     debug!("looking for group {:?} host {:?}", group_id, host_id);
+    let before = Snapshot::take(&state.db).await?;
 
     let hgm = host_group_members::Entity::find()
         .filter(
@@ -140,7 +163,7 @@ pub(crate) async fn host_group_member_delete(
                 .eq(group_id)
                 .and(host_group_members::Column::HostId.eq(host_id)),
         )
-        .one(&*state.db.read().await)
+        .one(&state.db)
         .await
         .map_err(|e| {
             error!("Failed to fetch host group membership: {}", e);
@@ -156,7 +179,7 @@ pub(crate) async fn host_group_member_delete(
         }
     };
 
-    let res = hgm.delete(&*state.db.write().await).await.map_err(|e| {
+    let res = hgm.delete(&state.db).await.map_err(|e| {
         error!("Failed to delete host group membership: {}", e);
         Error::from(e)
     })?;
@@ -167,6 +190,7 @@ pub(crate) async fn host_group_member_delete(
         host_id.hyphenated(),
         group_id.hyphenated()
     );
+    publish(&state.db, &state.configuration, &before, ChangeSource::Web).await?;
 
     Ok(Redirect::to(&format!("{}/{}", Urls::HostGroup, group_id)))
 }
@@ -184,8 +208,9 @@ pub(crate) async fn host_group_delete(
         Some(val) => val.into(),
     };
 
+    let before = Snapshot::take(&state.db).await?;
     let res = host_group::Entity::delete_by_id(group_id)
-        .exec(&*state.db.write().await)
+        .exec(&state.db)
         .await
         .map_err(|e| {
             error!("Failed to delete host group: {}", e);
@@ -194,6 +219,7 @@ pub(crate) async fn host_group_delete(
     if res.rows_affected == 0 {
         return Err((StatusCode::NOT_FOUND, "Host Group not found".to_string()));
     }
+    publish(&state.db, &state.configuration, &before, ChangeSource::Web).await?;
 
     Ok(Redirect::to(Urls::HostGroups.as_ref()))
 }
@@ -211,11 +237,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_unauthed_endpoints() {
-        let (_db, _config) =
-            test_setup().await.expect("Failed to setup test harness");
+        let (_db, _config) = test_setup().await.expect("Failed to setup test harness");
         let state = WebState::test().await;
 
-        let res = super::host_groups(State(state.clone()), None).await;
+        let res = super::host_groups(State(state.clone()), None, state.get_session()).await;
         assert!(res.is_err());
         assert_eq!(
             res.into_response().status(),
@@ -223,10 +248,11 @@ mod tests {
         );
 
         let res = super::host_group(
-            Path(Uuid::new_v4()),
+            Path(Uuid::new_v4().to_string()),
             Query(HostGroupQueries::default()),
             State(state.clone()),
             None,
+            state.get_session(),
         )
         .await;
         assert!(res.is_err());
@@ -255,17 +281,18 @@ mod tests {
         test_setup().await.expect("Failed to setup test harness");
 
         let host_group = host_group::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for host group")
             .expect("No host group found");
         for ord in [Some(Order::Asc), Some(Order::Desc), None].into_iter() {
             for message in [None, Some("Test Message".to_string())].into_iter() {
                 let res = super::host_group(
-                    Path(host_group.id),
+                    Path(host_group.id.to_string()),
                     Query(HostGroupQueries { ord, message }),
                     State(state.clone()),
                     Some(test_user_claims()),
+                    state.get_session(),
                 )
                 .await;
 
@@ -283,9 +310,13 @@ mod tests {
         use super::*;
         let state = WebState::test().await;
 
-        let (_db, _config) =
-            test_setup().await.expect("Failed to setup test harness");
-        let res = super::host_groups(State(state.clone()), Some(test_user_claims())).await;
+        let (_db, _config) = test_setup().await.expect("Failed to setup test harness");
+        let res = super::host_groups(
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await;
 
         assert!(res.is_ok());
 
@@ -299,8 +330,7 @@ mod tests {
         use super::*;
         let state = WebState::test().await;
 
-        let (_db, _config) =
-            test_setup().await.expect("Failed to setup test harness");
+        let (_db, _config) = test_setup().await.expect("Failed to setup test harness");
         let res = super::host_group_delete(
             Path(Uuid::new_v4()),
             State(state.clone()),
@@ -313,7 +343,7 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
         let host_group = host_group::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for host group")
             .expect("No host group found");
@@ -334,8 +364,7 @@ mod tests {
         use super::*;
         let state = WebState::test().await;
 
-        let (_db, _config) =
-            test_setup().await.expect("Failed to setup test harness");
+        let (_db, _config) = test_setup().await.expect("Failed to setup test harness");
         let res = super::host_group_delete(Path(Uuid::new_v4()), State(state.clone()), None).await;
         dbg!(&res);
         assert!(res.is_err());
@@ -343,7 +372,7 @@ mod tests {
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 
         let host_group = host_group::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for host group")
             .expect("No host group found");
@@ -364,8 +393,7 @@ mod tests {
         use super::*;
         let state = WebState::test().await;
 
-        let (db, _config) =
-            test_setup().await.expect("Failed to setup test harness");
+        let (db, _config) = test_setup().await.expect("Failed to setup test harness");
 
         let state = WebState {
             db: db.clone(),
@@ -373,7 +401,7 @@ mod tests {
         };
 
         let hgm = host_group_members::Entity::find()
-            .one(&*db.write().await)
+            .one(&db)
             .await
             .expect("Failed to find host group members")
             .expect("No host group members found");
@@ -385,7 +413,7 @@ mod tests {
                     .eq(hgm.group_id)
                     .and(host_group_members::Column::HostId.eq(hgm.host_id))
             )
-            .one(&*db.write().await)
+            .one(&db)
             .await
             .expect("failed to look up hgm")
             .is_some());
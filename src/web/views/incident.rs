@@ -0,0 +1,124 @@
+//! Incidents, for SLA reports and deduplicating alerts
+
+use axum::Json;
+use sea_orm::QueryFilter;
+
+use super::prelude::*;
+use crate::db::entities::incident::{FullIncident, IncidentFilter};
+use crate::labels::LabelFilter;
+use crate::web::Error;
+
+/// The most incidents the API returns in one go
+const MAX_INCIDENTS: u64 = 1000;
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct IncidentQuery {
+    /// The host's ID or slug
+    pub(crate) host: Option<String>,
+    pub(crate) service_check: Option<Uuid>,
+    /// Only return incidents that haven't recovered
+    #[serde(default)]
+    pub(crate) open: bool,
+    /// Only return incidents where the host or service has this label, eg `env:prod`
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub(crate) label: Option<LabelFilter>,
+    pub(crate) limit: Option<u64>,
+}
+
+/// `GET /api/v1/incidents`, newest first
+pub(crate) async fn api_incidents(
+    Query(query): Query<IncidentQuery>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<Vec<FullIncident>>, Error> {
+    claims.ok_or(Error::Unauthorized)?;
+    let host_id = match &query.host {
+        Some(host) => Some(
+            entities::host::Entity::find()
+                .filter(entities::id_or_slug(
+                    entities::host::Column::Id,
+                    entities::host::Column::Slug,
+                    host,
+                ))
+                .one(&state.db)
+                .await?
+                .ok_or_else(|| Error::InvalidInput(format!("Host {} not found", host)))?
+                .id,
+        ),
+        None => None,
+    };
+    let filter = IncidentFilter {
+        host_id,
+        service_check_id: query.service_check,
+        open: query.open,
+        since: None,
+        label: query.label,
+        limit: query
+            .limit
+            .unwrap_or(IncidentFilter::default().limit)
+            .min(MAX_INCIDENTS),
+    };
+    Ok(Json(
+        entities::incident::Entity::search(&state.db, &filter).await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::views::tools::test_user_claims;
+
+    #[tokio::test]
+    async fn test_api_incidents() {
+        let state = WebState::test().await;
+        let (check, host) = entities::service_check::Entity::find()
+            .find_also_related(entities::host::Entity)
+            .one(&state.db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        let host = host.expect("Service check has no host");
+        entities::incident::Entity::record(
+            &state.db,
+            check.id,
+            ServiceStatus::Critical,
+            chrono::Utc::now(),
+        )
+        .await
+        .expect("Failed to record result");
+
+        assert!(
+            api_incidents(Query(IncidentQuery::default()), State(state.clone()), None)
+                .await
+                .is_err()
+        );
+
+        let Json(incidents) = api_incidents(
+            Query(IncidentQuery {
+                host: Some(host.slug.clone()),
+                open: true,
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to list incidents");
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(
+            incidents.first().map(|incident| incident.service_check_id),
+            Some(check.id)
+        );
+
+        assert!(api_incidents(
+            Query(IncidentQuery {
+                host: Some("not-a-host".to_string()),
+                ..Default::default()
+            }),
+            State(state),
+            Some(test_user_claims()),
+        )
+        .await
+        .is_err());
+    }
+}
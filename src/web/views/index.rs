@@ -1,7 +1,13 @@
 use entities::service_check::FullServiceCheck;
-use sea_orm::{ColumnTrait, Order as SeaOrmOrder, QueryFilter, QueryOrder};
+use sea_orm::{
+    ActiveEnum, ColumnTrait, Iterable, Order as SeaOrmOrder, PaginatorTrait, QueryFilter,
+    QueryOrder,
+};
+
+use crate::constants::{DEFAULT_PAGE_SIZE, SESSION_CSRF_TOKEN};
 
 use crate::errors::Error;
+use crate::labels::LabelFilter;
 
 use super::prelude::*;
 
@@ -9,13 +15,27 @@ use super::prelude::*;
 #[template(path = "index.html")]
 pub struct IndexTemplate {
     pub title: String,
-    pub num_checks: usize,
     pub checks: Vec<FullServiceCheck>,
+    pub pagination: Pagination,
+    /// Only showing checks with this status
+    pub status: Option<ServiceStatus>,
     pub page_refresh: u64,
     pub username: Option<String>,
+    pub theme: PageTheme,
     pub search: String,
+    /// Only showing checks where the host or service has this label
+    pub label: Option<LabelFilter>,
     pub ord: Order,
     pub field: OrderFields,
+    /// The user's favorite hosts, with their worst check status
+    pub favorites: Vec<(entities::host::Model, ServiceStatus)>,
+    /// Host groups with their rolled up status, worst first
+    pub group_statuses: Vec<(
+        entities::host_group::Model,
+        entities::host_group_status::Model,
+    )>,
+    /// For the selection form
+    pub csrf_token: String,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -23,14 +43,30 @@ pub(crate) struct SortQueries {
     pub ord: Option<Order>,
     pub field: Option<OrderFields>,
     pub search: Option<String>,
+    /// Only show checks with this status
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub status: Option<ServiceStatus>,
+    /// Only show checks where the host or service has this label, eg `env:prod`
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub label: Option<LabelFilter>,
+    /// Starts at 1
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
 }
 
 #[instrument(level = "info", skip(state, claims), fields(http.uri=Urls::Index.as_ref(), ))]
 pub(crate) async fn index(
-    Query(queries): Query<SortQueries>,
+    Query(mut queries): Query<SortQueries>,
     State(state): State<WebState>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
 ) -> Result<IndexTemplate, (StatusCode, String)> {
+    let preferences = session_preferences(&session).await?;
+    // the user's default filters, unless they've picked some
+    if queries.search.is_none() && queries.status.is_none() && queries.label.is_none() {
+        queries.search = preferences.dashboard_search.clone();
+        queries.status = preferences.dashboard_status;
+    }
     let sort_order: SeaOrmOrder = queries.ord.unwrap_or_default().into();
     let order_field = queries.field.unwrap_or(OrderFields::Status);
     debug!("Sorting home page by: {:?} {:?}", order_field, sort_order);
@@ -44,6 +80,15 @@ pub(crate) async fn index(
                 .or(entities::service_check::Column::Status.contains(search)),
         );
     }
+    if let Some(status) = queries.status {
+        checks = checks.filter(entities::service_check::Column::Status.eq(status));
+    }
+    if let Some(label) = &queries.label {
+        checks = checks.filter(label.condition_any(
+            entities::host::Column::Labels,
+            entities::service::Column::Labels,
+        ));
+    }
     checks = match order_field {
         OrderFields::LastUpdated => checks.order_by(
             entities::service_check::Column::LastUpdated,
@@ -53,43 +98,63 @@ pub(crate) async fn index(
             checks.order_by(entities::service::Column::Name, sort_order.clone())
         }
         OrderFields::Host => checks.order_by(entities::host::Column::Name, sort_order.clone()),
-        OrderFields::Status => checks.order_by(
-            entities::service_check::Column::LastUpdated,
-            sort_order.clone(),
-        ),
+        OrderFields::Status => checks.order_by(FullServiceCheck::status_rank(), sort_order.clone()),
         OrderFields::Check => checks.order_by(entities::service::Column::Name, sort_order.clone()),
         OrderFields::NextCheck => checks.order_by(
             entities::service_check::Column::NextCheck,
             sort_order.clone(),
         ),
     };
-    debug!("Getting reader...");
-    let db_handle = state.db.read().await;
-    debug!("got reader");
-    let mut checks = checks
-        .into_model()
-        .all(&*db_handle)
+    let per_page = Pagination::page_size(
+        queries.per_page,
+        preferences.page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+    );
+    let paginator = checks
+        .into_model::<FullServiceCheck>()
+        .paginate(&state.db, per_page);
+    let pagination = Pagination::new(
+        queries.page,
+        per_page,
+        paginator.num_items().await.map_err(Error::from)?,
+    )
+    .with_param("search", queries.search.as_ref())
+    .with_param("label", queries.label.as_ref())
+    .with_param("ord", queries.ord)
+    .with_param("field", Some(order_field))
+    .with_param("status", queries.status.map(|status| status.to_value()));
+    let checks = paginator
+        .fetch_page(pagination.page - 1)
         .await
         .map_err(Error::from)?;
-    drop(db_handle);
     debug!("query done");
 
-    if order_field == OrderFields::Status {
-        checks.sort_by(|a: &FullServiceCheck, b: &FullServiceCheck| a.status.cmp(&b.status));
-        if sort_order == SeaOrmOrder::Desc {
-            checks.reverse();
-        }
-    }
+    let group_statuses = entities::host_group_status::Entity::with_groups(&state.db).await?;
+    let favorites =
+        entities::host::Entity::with_worst_status(&state.db, &preferences.favorite_hosts).await?;
+
+    let theme = PageTheme::new(&state, &session).await?;
+
+    let csrf_token = state.new_csrf_token();
+    session
+        .insert(SESSION_CSRF_TOKEN, &csrf_token)
+        .await
+        .map_err(Error::from)?;
 
     Ok(IndexTemplate {
         title: "".to_string(),
-        num_checks: checks.len(),
         checks,
-        page_refresh: 90,
+        pagination,
+        status: queries.status,
+        page_refresh: theme.refresh_or(90),
         username: claims.map(|c| User::from(c).username()),
+        theme,
         search: queries.search.unwrap_or_default(),
+        label: queries.label,
         ord: queries.ord.unwrap_or_default(),
         field: order_field,
+        favorites,
+        group_statuses,
+        csrf_token,
     })
 }
 
@@ -108,9 +173,11 @@ mod tests {
                 ord: None,
                 field: None,
                 search: None,
+                ..Default::default()
             }),
-            State(state),
+            State(state.clone()),
             None,
+            state.get_session(),
         )
         .await;
         assert!(res.is_ok());
@@ -126,14 +193,18 @@ mod tests {
                 ord: None,
                 field: None,
                 search: None,
+                ..Default::default()
             }),
-            State(state),
+            State(state.clone()),
             Some(test_user_claims()),
+            state.get_session(),
         )
         .await;
         assert!(res.is_ok());
 
-        assert!(res.unwrap().to_string().contains("Maremma"));
+        let page = res.unwrap().to_string();
+        assert!(page.contains("Maremma"));
+        assert!(page.contains("id=\"bulkForm\""));
     }
 
     #[tokio::test]
@@ -144,9 +215,11 @@ mod tests {
                 ord: None,
                 field: None,
                 search: Some("example.com".to_string()),
+                ..Default::default()
             }),
-            State(state),
+            State(state.clone()),
             None,
+            state.get_session(),
         )
         .await;
         assert!(res.is_ok());
@@ -156,4 +229,147 @@ mod tests {
         assert!(page_content.contains("example.com"));
         assert!(!page_content.contains("local_lslah"));
     }
+
+    #[tokio::test]
+    async fn test_index_label() {
+        use sea_orm::{ActiveModelTrait, EntityTrait, IntoActiveModel, Set};
+
+        let state = WebState::test().await;
+        let service = entities::service::Entity::find()
+            .filter(entities::service::Column::Name.eq("local_lslah"))
+            .one(&state.db)
+            .await
+            .expect("Failed to query services")
+            .expect("Failed to find local_lslah");
+        let mut active = service.into_active_model();
+        active.labels = Set(serde_json::json!({"team": "payments"}));
+        active
+            .update(&state.db)
+            .await
+            .expect("Failed to update service");
+
+        let page = index(
+            Query(SortQueries {
+                label: Some("team:payments".parse().expect("Failed to parse label")),
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to get index");
+        assert!(!page.checks.is_empty());
+        assert!(page
+            .checks
+            .iter()
+            .all(|check| check.service_name == "local_lslah"));
+        assert!(page.to_string().contains("value=\"team:payments\""));
+    }
+
+    #[tokio::test]
+    async fn test_index_pages() {
+        use sea_orm::{ActiveModelTrait, EntityTrait, IntoActiveModel, Set};
+
+        let state = WebState::test().await;
+        let check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query checks")
+            .expect("No checks found");
+        let mut active = check.into_active_model();
+        active.status = Set(ServiceStatus::Critical);
+        active
+            .update(&state.db)
+            .await
+            .expect("Failed to update check");
+
+        let page = index(
+            Query(SortQueries {
+                page: Some(2),
+                per_page: Some(1),
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to get index");
+        assert_eq!(page.checks.len(), 1);
+        assert_eq!(page.pagination.page, 2);
+        assert!(page.pagination.total > 1);
+        assert_eq!(page.pagination.prev(), Some(1));
+        assert!(page.to_string().contains("page=1&amp;per_page=1"));
+
+        let page = index(
+            Query(SortQueries {
+                status: Some(ServiceStatus::Critical),
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to get index");
+        assert_eq!(page.pagination.total, 1);
+        assert!(page
+            .checks
+            .iter()
+            .all(|check| check.status == ServiceStatus::Critical));
+    }
+
+    #[tokio::test]
+    async fn test_index_preferences() {
+        use crate::db::entities::user_preferences::Preferences;
+        use crate::web::preferences::SESSION_PREFERENCES;
+
+        let state = WebState::test().await;
+        let host = entities::host::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+        let session = state.get_session();
+        session
+            .insert(
+                SESSION_PREFERENCES,
+                Preferences {
+                    page_size: Some(1),
+                    dashboard_status: Some(ServiceStatus::Pending),
+                    favorite_hosts: vec![host.id],
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("Failed to set preferences");
+
+        let page = index(
+            Query(SortQueries::default()),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+        )
+        .await
+        .expect("Failed to get index");
+        assert_eq!(page.pagination.per_page, 1);
+        assert_eq!(page.status, Some(ServiceStatus::Pending));
+        assert_eq!(page.favorites.len(), 1);
+        assert_eq!(page.favorites[0].0.id, host.id);
+
+        // picking a filter overrides the default
+        let page = index(
+            Query(SortQueries {
+                search: Some("".to_string()),
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session,
+        )
+        .await
+        .expect("Failed to get index");
+        assert_eq!(page.status, None);
+    }
 }
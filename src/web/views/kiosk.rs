@@ -0,0 +1,122 @@
+//! The read-only dashboard behind signed kiosk links, it doesn't use a session so it never logs out
+
+use sea_orm::{QueryOrder, QuerySelect};
+
+use super::prelude::*;
+use crate::constants::MAX_PAGE_SIZE;
+use crate::db::entities::service_check::FullServiceCheck;
+use crate::web::Error;
+
+#[derive(Template, Debug)]
+#[template(path = "kiosk.html")]
+pub(crate) struct KioskTemplate {
+    theme: PageTheme,
+    page_refresh: u64,
+    /// Worst first
+    checks: Vec<FullServiceCheck>,
+    /// How many checks have each status, worst first
+    counts: Vec<(ServiceStatus, usize)>,
+    group_statuses: Vec<(
+        entities::host_group::Model,
+        entities::host_group_status::Model,
+    )>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct KioskQuery {
+    /// When the link stops working, as a unix timestamp
+    pub(crate) expires: i64,
+    pub(crate) signature: String,
+}
+
+/// `GET /kiosk`
+pub(crate) async fn kiosk(
+    Query(query): Query<KioskQuery>,
+    State(state): State<WebState>,
+) -> Result<KioskTemplate, Error> {
+    let (config, theme) = {
+        let configuration = state.configuration.read().await;
+        (
+            configuration.kiosk.clone(),
+            PageTheme::from_config(&configuration),
+        )
+    };
+    // it's the same response whether kiosk links are turned off or the link's bad, so there's nothing to probe
+    let config = config.ok_or(Error::Unauthorized)?;
+    config.verify(query.expires, &query.signature, Utc::now())?;
+
+    let checks = FullServiceCheck::all_query()
+        .order_by(FullServiceCheck::status_rank(), sea_orm::Order::Desc)
+        .order_by_asc(entities::host::Column::Name)
+        .order_by_asc(entities::service::Column::Name)
+        .limit(MAX_PAGE_SIZE)
+        .into_model::<FullServiceCheck>()
+        .all(&state.db)
+        .await?;
+
+    let mut counts: Vec<(ServiceStatus, usize)> = Vec::new();
+    for check in checks.iter() {
+        match counts
+            .iter_mut()
+            .find(|(status, _)| *status == check.status)
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((check.status, 1)),
+        }
+    }
+
+    Ok(KioskTemplate {
+        theme,
+        page_refresh: config.page_refresh,
+        checks,
+        counts,
+        group_statuses: entities::host_group_status::Entity::with_groups(&state.db).await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::kiosk::KioskConfig;
+
+    #[tokio::test]
+    async fn test_kiosk() {
+        let state = WebState::test().await;
+        let expires = Utc::now() + chrono::TimeDelta::hours(1);
+        let config = KioskConfig {
+            secret: "x".repeat(32),
+            page_refresh: 30,
+        };
+        let query = || KioskQuery {
+            expires: expires.timestamp(),
+            signature: config.sign(expires).expect("Failed to sign"),
+        };
+
+        // kiosk links are off by default
+        assert!(kiosk(Query(query()), State(state.clone())).await.is_err());
+
+        state.configuration.write().await.kiosk = Some(config.clone());
+        assert!(kiosk(
+            Query(KioskQuery {
+                signature: "bad".to_string(),
+                ..query()
+            }),
+            State(state.clone())
+        )
+        .await
+        .is_err());
+
+        let page = kiosk(Query(query()), State(state.clone()))
+            .await
+            .expect("Failed to load the kiosk page");
+        assert!(!page.checks.is_empty());
+        assert_eq!(
+            page.counts.iter().map(|(_, count)| count).sum::<usize>(),
+            page.checks.len()
+        );
+        let page = page.to_string();
+        assert!(page.contains("http-equiv=\"refresh\" content=\"30\""));
+        // it's read-only
+        assert!(!page.contains("<form"));
+    }
+}
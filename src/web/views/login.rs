@@ -0,0 +1,185 @@
+//! The login form, when `auth.mode` is `local`
+
+use axum::{Extension, Form};
+
+use super::prelude::*;
+use super::tools::check_csrf_token;
+use crate::constants::SESSION_CSRF_TOKEN;
+use crate::errors::Error;
+use crate::web::auth::{LocalSession, SESSION_LOCAL_USER};
+use crate::web::proxy::ClientAddress;
+use tracing::warn;
+
+#[derive(Template, Debug)]
+#[template(path = "login.html")]
+pub(crate) struct LoginTemplate {
+    title: String,
+    username: Option<String>,
+    theme: PageTheme,
+    csrf_token: String,
+    message: Option<String>,
+}
+
+impl LoginTemplate {
+    async fn new(
+        state: &WebState,
+        session: &Session,
+        message: Option<String>,
+    ) -> Result<Self, Error> {
+        let csrf_token = state.new_csrf_token();
+        session.insert(SESSION_CSRF_TOKEN, &csrf_token).await?;
+        Ok(Self {
+            title: "Log in".to_string(),
+            username: None,
+            theme: PageTheme::new(state, session).await?,
+            csrf_token,
+            message,
+        })
+    }
+}
+
+pub(crate) async fn login_form(
+    State(state): State<WebState>,
+    session: Session,
+) -> Result<LoginTemplate, (StatusCode, String)> {
+    Ok(LoginTemplate::new(&state, &session, None).await?)
+}
+
+fn client_description(client: &Option<Extension<ClientAddress>>) -> String {
+    client
+        .as_ref()
+        .map(|Extension(client)| client.to_string())
+        .unwrap_or_else(|| "an unknown address".to_string())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginForm {
+    pub(crate) csrf_token: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+/// Checks the password, and keeps the user in the session if it's right
+pub(crate) async fn login(
+    State(state): State<WebState>,
+    session: Session,
+    client: Option<Extension<ClientAddress>>,
+    Form(form): Form<LoginForm>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    check_csrf_token(&form.csrf_token, &session).await?;
+
+    match entities::local_user::Entity::authenticate(&state.db, &form.username, &form.password)
+        .await?
+    {
+        Some(user) => {
+            // a new session ID, so one handed out before logging in can't be reused
+            session.cycle_id().await.map_err(Error::from)?;
+            session
+                .insert(
+                    SESSION_LOCAL_USER,
+                    LocalSession {
+                        id: user.id,
+                        username: user.username.clone(),
+                    },
+                )
+                .await
+                .map_err(Error::from)?;
+            info!(
+                "{} logged in from {}",
+                user.username,
+                client_description(&client)
+            );
+            Ok(Redirect::to(Urls::Index.as_ref()).into_response())
+        }
+        None => {
+            warn!(
+                "Failed login for {} from {}",
+                form.username,
+                client_description(&client)
+            );
+            let page = LoginTemplate::new(
+                &state,
+                &session,
+                Some("Wrong username or password".to_string()),
+            )
+            .await?;
+            Ok((StatusCode::UNAUTHORIZED, page).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_login() {
+        let state = WebState::test().await;
+        let session = state.get_session();
+        entities::local_user::Entity::set_password(
+            &state.db,
+            "admin",
+            "correct horse battery staple",
+        )
+        .await
+        .expect("Failed to create user");
+
+        let page = login_form(State(state.clone()), session.clone())
+            .await
+            .expect("Failed to get login form");
+        let csrf_token = page.csrf_token.clone();
+        assert!(page.to_string().contains("password"));
+
+        let form = |password: &str| {
+            Form(LoginForm {
+                csrf_token: csrf_token.clone(),
+                username: "admin".to_string(),
+                password: password.to_string(),
+            })
+        };
+
+        let res = login(
+            State(state.clone()),
+            session.clone(),
+            None,
+            form("wrong password"),
+        )
+        .await
+        .expect("Failed to post login");
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(session
+            .get::<LocalSession>(SESSION_LOCAL_USER)
+            .await
+            .expect("Failed to read session")
+            .is_none());
+
+        // the failed attempt handed out a new token
+        let csrf_token: String = session
+            .get(SESSION_CSRF_TOKEN)
+            .await
+            .expect("Failed to read session")
+            .expect("No CSRF token");
+        let res = login(
+            State(state.clone()),
+            session.clone(),
+            Some(Extension(ClientAddress {
+                ip: Some([192, 0, 2, 1].into()),
+                scheme: "https".to_string(),
+            })),
+            Form(LoginForm {
+                csrf_token,
+                username: "admin".to_string(),
+                password: "correct horse battery staple".to_string(),
+            }),
+        )
+        .await
+        .expect("Failed to post login");
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let user = session
+            .get::<LocalSession>(SESSION_LOCAL_USER)
+            .await
+            .expect("Failed to read session")
+            .expect("Should be logged in");
+        assert_eq!(user.username, "admin");
+    }
+}
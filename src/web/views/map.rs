@@ -0,0 +1,290 @@
+//! A map of how host groups, hosts and services fit together, coloured by status so it's obvious which part's red
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::Json;
+use sea_orm::{QueryOrder, QuerySelect};
+use serde::Serialize;
+
+use super::prelude::*;
+use crate::db::entities::{
+    host, host_group, host_group_members, host_group_nesting, host_group_status, service,
+};
+use crate::group_status::host_status;
+use crate::web::Error;
+
+#[derive(Template, Debug)]
+#[template(path = "map.html")]
+pub(crate) struct MapTemplate {
+    title: String,
+    username: Option<String>,
+    theme: PageTheme,
+}
+
+/// `GET /map`, the page's drawn by `map.js` from [api_map]
+pub(crate) async fn map(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+) -> Result<MapTemplate, (StatusCode, String)> {
+    let user = check_login(claims)?;
+    Ok(MapTemplate {
+        title: "Map".to_string(),
+        username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
+    })
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NodeKind {
+    Group,
+    Host,
+    Service,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MapNode {
+    /// eg `host:<uuid>`, which is what the edges point at
+    id: String,
+    kind: NodeKind,
+    name: String,
+    /// The worst result of the checks under it, `None` if there aren't any results yet
+    status: Option<ServiceStatus>,
+    /// The bootstrap colour for the status
+    colour: &'static str,
+    url: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MapEdge {
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TopologyMap {
+    nodes: Vec<MapNode>,
+    /// Groups to the groups nested in them and their hosts, and hosts to their services
+    edges: Vec<MapEdge>,
+}
+
+fn node_id(kind: NodeKind, id: Uuid) -> String {
+    match kind {
+        NodeKind::Group => format!("group:{}", id),
+        NodeKind::Host => format!("host:{}", id),
+        NodeKind::Service => format!("service:{}", id),
+    }
+}
+
+fn node(
+    kind: NodeKind,
+    id: Uuid,
+    name: String,
+    status: Option<ServiceStatus>,
+    url: String,
+) -> MapNode {
+    MapNode {
+        id: node_id(kind, id),
+        kind,
+        name,
+        status,
+        colour: status
+            .unwrap_or(ServiceStatus::Pending)
+            .as_html_class_background(),
+        url,
+    }
+}
+
+impl TopologyMap {
+    /// Works out the map from what's in the database
+    pub(crate) async fn build(db: &DatabaseConnection) -> Result<Self, Error> {
+        let checks: Vec<(Uuid, Uuid, ServiceStatus)> = entities::service_check::Entity::find()
+            .select_only()
+            .column(entities::service_check::Column::HostId)
+            .column(entities::service_check::Column::ServiceId)
+            .column(entities::service_check::Column::Status)
+            .into_tuple()
+            .all(db)
+            .await?;
+        let mut host_checks: HashMap<Uuid, Vec<ServiceStatus>> = HashMap::new();
+        let mut service_checks: HashMap<Uuid, Vec<ServiceStatus>> = HashMap::new();
+        // BTreeMap so the edges come out in the same order each time
+        let mut host_services: BTreeMap<Uuid, Vec<Uuid>> = BTreeMap::new();
+        for (host_id, service_id, status) in checks {
+            host_checks.entry(host_id).or_default().push(status);
+            service_checks.entry(service_id).or_default().push(status);
+            host_services.entry(host_id).or_default().push(service_id);
+        }
+
+        let mut res = Self::default();
+        for host in host::Entity::find()
+            .order_by_asc(host::Column::Name)
+            .all(db)
+            .await?
+        {
+            res.nodes.push(node(
+                NodeKind::Host,
+                host.id,
+                host.display_name().to_string(),
+                host_status(host_checks.remove(&host.id).unwrap_or_default()),
+                format!("{}/{}", Urls::Host, host.slug),
+            ));
+        }
+        for service in service::Entity::find()
+            .order_by_asc(service::Column::Name)
+            .all(db)
+            .await?
+        {
+            res.nodes.push(node(
+                NodeKind::Service,
+                service.id,
+                service.name,
+                host_status(service_checks.remove(&service.id).unwrap_or_default()),
+                format!("{}/{}", Urls::Service, service.slug),
+            ));
+        }
+
+        let members = host_group_members::Entity::find().all(db).await?;
+        let nesting = host_group_nesting::Entity::find().all(db).await?;
+        // the shepherd's rolled the groups up already, nested groups and status policies included
+        let group_statuses = host_group_status::Entity::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|status| (status.host_group_id, status.status))
+            .collect::<HashMap<_, _>>();
+        for group in host_group::Entity::find()
+            .order_by_asc(host_group::Column::Name)
+            .all(db)
+            .await?
+        {
+            res.nodes.push(node(
+                NodeKind::Group,
+                group.id,
+                group.name,
+                group_statuses.get(&group.id).copied(),
+                format!("{}/{}", Urls::HostGroup, group.slug),
+            ));
+        }
+
+        for nested in nesting {
+            res.edges.push(MapEdge {
+                from: node_id(NodeKind::Group, nested.parent_id),
+                to: node_id(NodeKind::Group, nested.child_id),
+            });
+        }
+        for member in members {
+            res.edges.push(MapEdge {
+                from: node_id(NodeKind::Group, member.group_id),
+                to: node_id(NodeKind::Host, member.host_id),
+            });
+        }
+        for (host_id, mut service_ids) in host_services {
+            service_ids.sort();
+            service_ids.dedup();
+            res.edges
+                .extend(service_ids.into_iter().map(|service_id| MapEdge {
+                    from: node_id(NodeKind::Host, host_id),
+                    to: node_id(NodeKind::Service, service_id),
+                }));
+        }
+        res.nodes.sort_by(|a, b| a.kind.cmp(&b.kind));
+        Ok(res)
+    }
+}
+
+/// `GET /api/v1/map`, the nodes and edges for the map page
+pub(crate) async fn api_map(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<TopologyMap>, Error> {
+    claims.ok_or(Error::Unauthorized)?;
+    Ok(Json(TopologyMap::build(&state.db).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{ColumnTrait, QueryFilter};
+
+    use super::*;
+    use crate::group_status::is_failing;
+    use crate::web::views::tools::test_user_claims;
+
+    #[tokio::test]
+    async fn test_map() {
+        let state = WebState::test().await;
+        assert!(super::map(State(state.clone()), None, state.get_session())
+            .await
+            .is_err());
+        let page = super::map(
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to render map");
+        assert!(page.to_string().contains("map.js"));
+    }
+
+    #[tokio::test]
+    async fn test_api_map() {
+        let state = WebState::test().await;
+        assert!(api_map(State(state.clone()), None).await.is_err());
+
+        let member = entities::host_group_members::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query group members")
+            .expect("No group members found");
+        let check = entities::service_check::Entity::find()
+            .filter(entities::service_check::Column::HostId.eq(member.host_id))
+            .one(&state.db)
+            .await
+            .expect("Failed to query service checks")
+            .expect("No service checks found");
+        check
+            .set_status(ServiceStatus::Critical, state.db.clone())
+            .await
+            .expect("Failed to set status");
+        entities::host_group_status::Entity::refresh(
+            &state.db,
+            &crate::group_status::GroupStatusConfig::default(),
+        )
+        .await
+        .expect("Failed to refresh group statuses");
+
+        let Json(map) = api_map(State(state.clone()), Some(test_user_claims()))
+            .await
+            .expect("Failed to get map");
+        let host_id = node_id(NodeKind::Host, check.host_id);
+        let host = map
+            .nodes
+            .iter()
+            .find(|node| node.id == host_id)
+            .expect("Host isn't on the map");
+        assert!(host.status.is_some_and(is_failing));
+        assert!(map.edges.contains(&MapEdge {
+            from: host_id.clone(),
+            to: node_id(NodeKind::Service, check.service_id),
+        }));
+
+        // the host's groups are red too
+        let groups = map
+            .edges
+            .iter()
+            .filter(|edge| edge.to == host_id)
+            .map(|edge| edge.from.clone())
+            .collect::<Vec<_>>();
+        assert!(!groups.is_empty());
+        for group in groups {
+            let group = map
+                .nodes
+                .iter()
+                .find(|node| node.id == group)
+                .expect("Group isn't on the map");
+            assert_eq!(group.kind, NodeKind::Group);
+            assert!(group.status >= host.status);
+        }
+    }
+}
@@ -15,7 +15,16 @@ pub(crate) async fn metrics(State(state): State<WebState>) -> Result<String, cra
             encoder
                 .encode(&metric_families, &mut result)
                 .map_err(|err| Error::Generic(err.to_string()))?;
-            Ok(String::from_utf8(result).map_err(|err| Error::Generic(err.to_string()))?)
+            let mut result =
+                String::from_utf8(result).map_err(|err| Error::Generic(err.to_string()))?;
+            result.push_str(
+                &crate::metrics::rules::status_metrics(
+                    &state.db,
+                    &*state.configuration.read().await,
+                )
+                .await?,
+            );
+            Ok(result)
         }
         None => Err(crate::errors::Error::NotImplemented),
     }
@@ -1,13 +1,30 @@
 use axum::http::StatusCode;
 
+pub(crate) mod agent;
+pub(crate) mod alertmanager;
+pub(crate) mod bulk;
+pub(crate) mod calendar;
+pub(crate) mod config_events;
+pub(crate) mod discovery;
+pub(crate) mod filters;
+pub(crate) mod health;
+pub(crate) mod heartbeat;
 pub(crate) mod host;
 pub(crate) mod host_group;
+pub(crate) mod incident;
 pub(crate) mod index;
+pub(crate) mod kiosk;
+pub(crate) mod login;
+pub(crate) mod map;
 pub(crate) mod metrics;
+pub(crate) mod pause;
 pub(crate) mod prelude;
 pub(crate) mod profile;
+pub(crate) mod search;
 pub(crate) mod service;
 pub(crate) mod service_check;
+pub(crate) mod status_page;
+pub(crate) mod submit;
 pub(crate) mod tools;
 
 pub(crate) async fn handler_404() -> (StatusCode, &'static str) {
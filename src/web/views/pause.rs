@@ -0,0 +1,367 @@
+//! Pausing all the checks on a host, or a service's checks across all hosts
+
+use axum::{Form, Json};
+use chrono::{TimeDelta, Utc};
+use sea_orm::{ColumnTrait, DatabaseConnection, QueryFilter};
+use serde::Serialize;
+
+use super::prelude::*;
+use super::tools::{check_csrf_token, CsrfTokenForm};
+use crate::db::entities::service_check::PauseTarget;
+use crate::web::Error;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct PauseForm {
+    pub(crate) csrf_token: String,
+    /// How long to pause for, 0 pauses them until they're resumed by hand
+    #[serde(default)]
+    pub(crate) pause_minutes: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub(crate) struct PauseRequest {
+    /// When the shepherd should resume the checks, they stay paused until they're resumed by hand if it's not set
+    #[serde(default)]
+    pub(crate) until: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct PauseResponse {
+    /// How many service checks were changed
+    pub(crate) service_checks: u64,
+    pub(crate) paused_until: Option<DateTime<Utc>>,
+}
+
+/// What's being paused, with its name for the logs and the page to go back to
+struct Target {
+    target: PauseTarget,
+    name: String,
+    url: String,
+}
+
+async fn host_target(db: &DatabaseConnection, host_id: &str) -> Result<Target, Error> {
+    let host = entities::host::Entity::find()
+        .filter(entities::id_or_slug(
+            entities::host::Column::Id,
+            entities::host::Column::Slug,
+            host_id,
+        ))
+        .one(db)
+        .await?
+        .ok_or_else(|| Error::InvalidInput(format!("Host {} not found", host_id)))?;
+    Ok(Target {
+        target: PauseTarget::Host(host.id),
+        url: format!("{}/{}", Urls::Host, host.slug),
+        name: host.name,
+    })
+}
+
+async fn service_target(db: &DatabaseConnection, service_id: &str) -> Result<Target, Error> {
+    let service = entities::service::Entity::find()
+        .filter(entities::id_or_slug(
+            entities::service::Column::Id,
+            entities::service::Column::Slug,
+            service_id,
+        ))
+        .one(db)
+        .await?
+        .ok_or_else(|| Error::InvalidInput(format!("Service {} not found", service_id)))?;
+    Ok(Target {
+        target: PauseTarget::Service(service.id),
+        url: format!("{}/{}", Urls::Service, service.slug),
+        name: service.name,
+    })
+}
+
+async fn pause(
+    db: &DatabaseConnection,
+    user: &User,
+    target: &Target,
+    until: Option<DateTime<Utc>>,
+) -> Result<PauseResponse, Error> {
+    if until.is_some_and(|until| until <= Utc::now()) {
+        return Err(Error::InvalidInput(
+            "The resume time needs to be in the future".to_string(),
+        ));
+    }
+    let service_checks = entities::service_check::Entity::pause(db, target.target, until).await?;
+    info!(
+        "user={} Paused {} service checks for {} until={:?}",
+        user.username(),
+        service_checks,
+        target.name,
+        until
+    );
+    Ok(PauseResponse {
+        service_checks,
+        paused_until: until,
+    })
+}
+
+async fn resume(
+    db: &DatabaseConnection,
+    user: &User,
+    target: &Target,
+) -> Result<PauseResponse, Error> {
+    let service_checks = entities::service_check::Entity::resume(db, target.target).await?;
+    info!(
+        "user={} Resumed {} service checks for {}",
+        user.username(),
+        service_checks,
+        target.name
+    );
+    Ok(PauseResponse {
+        service_checks,
+        paused_until: None,
+    })
+}
+
+fn logged_in(claims: Option<OidcClaims<EmptyAdditionalClaims>>) -> Result<User, Error> {
+    claims.map(User::from).ok_or(Error::Unauthorized)
+}
+
+impl PauseForm {
+    fn until(&self) -> Option<DateTime<Utc>> {
+        (self.pause_minutes > 0).then(|| Utc::now() + TimeDelta::minutes(self.pause_minutes as i64))
+    }
+}
+
+/// Pauses all the checks on the host, from the host page
+pub(crate) async fn host_pause(
+    Path(host_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<PauseForm>,
+) -> Result<Redirect, Error> {
+    let user = logged_in(claims)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+    let target = host_target(&state.db, &host_id).await?;
+    pause(&state.db, &user, &target, form.until()).await?;
+    Ok(Redirect::to(&target.url))
+}
+
+/// Re-enables all the disabled checks on the host, from the host page
+pub(crate) async fn host_resume(
+    Path(host_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<CsrfTokenForm>,
+) -> Result<Redirect, Error> {
+    let user = logged_in(claims)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+    let target = host_target(&state.db, &host_id).await?;
+    resume(&state.db, &user, &target).await?;
+    Ok(Redirect::to(&target.url))
+}
+
+/// Pauses the service's checks on every host, from the service page
+pub(crate) async fn service_pause(
+    Path(service_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<PauseForm>,
+) -> Result<Redirect, Error> {
+    let user = logged_in(claims)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+    let target = service_target(&state.db, &service_id).await?;
+    pause(&state.db, &user, &target, form.until()).await?;
+    Ok(Redirect::to(&target.url))
+}
+
+/// Re-enables the service's disabled checks on every host, from the service page
+pub(crate) async fn service_resume(
+    Path(service_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<CsrfTokenForm>,
+) -> Result<Redirect, Error> {
+    let user = logged_in(claims)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+    let target = service_target(&state.db, &service_id).await?;
+    resume(&state.db, &user, &target).await?;
+    Ok(Redirect::to(&target.url))
+}
+
+/// `POST /api/v1/host/:host_id/pause`
+pub(crate) async fn api_host_pause(
+    Path(host_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(request): Json<PauseRequest>,
+) -> Result<Json<PauseResponse>, Error> {
+    let user = logged_in(claims)?;
+    let target = host_target(&state.db, &host_id).await?;
+    Ok(Json(pause(&state.db, &user, &target, request.until).await?))
+}
+
+/// `POST /api/v1/host/:host_id/resume`
+pub(crate) async fn api_host_resume(
+    Path(host_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<PauseResponse>, Error> {
+    let user = logged_in(claims)?;
+    let target = host_target(&state.db, &host_id).await?;
+    Ok(Json(resume(&state.db, &user, &target).await?))
+}
+
+/// `POST /api/v1/service/:service_id/pause`
+pub(crate) async fn api_service_pause(
+    Path(service_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(request): Json<PauseRequest>,
+) -> Result<Json<PauseResponse>, Error> {
+    let user = logged_in(claims)?;
+    let target = service_target(&state.db, &service_id).await?;
+    Ok(Json(pause(&state.db, &user, &target, request.until).await?))
+}
+
+/// `POST /api/v1/service/:service_id/resume`
+pub(crate) async fn api_service_resume(
+    Path(service_id): Path<String>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<PauseResponse>, Error> {
+    let user = logged_in(claims)?;
+    let target = service_target(&state.db, &service_id).await?;
+    Ok(Json(resume(&state.db, &user, &target).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::SESSION_CSRF_TOKEN;
+    use crate::web::views::tools::test_user_claims;
+
+    async fn checks_for(
+        state: &WebState,
+        target: PauseTarget,
+    ) -> Vec<entities::service_check::Model> {
+        let query = entities::service_check::Entity::find();
+        match target {
+            PauseTarget::Host(host_id) => {
+                query.filter(entities::service_check::Column::HostId.eq(host_id))
+            }
+            PauseTarget::Service(service_id) => {
+                query.filter(entities::service_check::Column::ServiceId.eq(service_id))
+            }
+        }
+        .all(&state.db)
+        .await
+        .expect("Failed to query service checks")
+    }
+
+    #[tokio::test]
+    async fn test_host_pause_and_resume() {
+        let state = WebState::test().await;
+        let host = entities::host::Entity::find()
+            .inner_join(entities::service_check::Entity)
+            .one(&state.db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts with checks found");
+        let session = state.get_session();
+        session
+            .insert(SESSION_CSRF_TOKEN, "pause".to_string())
+            .await
+            .expect("Failed to insert CSRF token into session");
+
+        assert!(host_pause(
+            Path(host.id.to_string()),
+            State(state.clone()),
+            None,
+            session.clone(),
+            Form(PauseForm {
+                csrf_token: "pause".to_string(),
+                pause_minutes: 0,
+            }),
+        )
+        .await
+        .is_err());
+
+        host_pause(
+            Path(host.slug.clone()),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+            Form(PauseForm {
+                csrf_token: "pause".to_string(),
+                pause_minutes: 60,
+            }),
+        )
+        .await
+        .expect("Failed to pause host");
+        let checks = checks_for(&state, PauseTarget::Host(host.id)).await;
+        assert!(checks
+            .iter()
+            .all(|check| check.status == ServiceStatus::Disabled
+                && check.paused_until.is_some_and(|until| until > Utc::now())));
+
+        host_resume(
+            Path(host.id.to_string()),
+            State(state.clone()),
+            Some(test_user_claims()),
+            session,
+            Form(CsrfTokenForm {
+                csrf_token: "pause".to_string(),
+            }),
+        )
+        .await
+        .expect("Failed to resume host");
+        let checks = checks_for(&state, PauseTarget::Host(host.id)).await;
+        assert!(checks
+            .iter()
+            .all(|check| check.status == ServiceStatus::Pending && check.paused_until.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_api_service_pause_and_resume() {
+        let state = WebState::test().await;
+        let service = entities::service::Entity::find()
+            .inner_join(entities::service_check::Entity)
+            .one(&state.db)
+            .await
+            .expect("Failed to query services")
+            .expect("No services with checks found");
+        let expected = checks_for(&state, PauseTarget::Service(service.id))
+            .await
+            .len() as u64;
+
+        // resuming in the past doesn't make sense
+        assert!(api_service_pause(
+            Path(service.id.to_string()),
+            State(state.clone()),
+            Some(test_user_claims()),
+            Json(PauseRequest {
+                until: Some(Utc::now() - TimeDelta::minutes(1)),
+            }),
+        )
+        .await
+        .is_err());
+
+        let Json(paused) = api_service_pause(
+            Path(service.id.to_string()),
+            State(state.clone()),
+            Some(test_user_claims()),
+            Json(PauseRequest::default()),
+        )
+        .await
+        .expect("Failed to pause service");
+        assert_eq!(paused.service_checks, expected);
+        assert_eq!(paused.paused_until, None);
+
+        let Json(resumed) = api_service_resume(
+            Path(service.slug.clone()),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to resume service");
+        assert_eq!(resumed.service_checks, expected);
+    }
+}
@@ -1,7 +1,11 @@
+use crate::constants::MAX_PAGE_SIZE;
 pub(crate) use crate::db::entities;
 pub(crate) use crate::services::ServiceStatus;
 pub(crate) use crate::web::oidc::User;
+pub(crate) use crate::web::preferences::session_preferences;
+pub(crate) use crate::web::theme::PageTheme;
 pub(crate) use crate::web::urls::Urls;
+pub(crate) use crate::web::views::filters;
 pub(crate) use crate::web::WebState;
 
 pub(crate) use askama_axum::Template;
@@ -119,6 +123,55 @@ impl PartialOrd for Check {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where a paged list is up to, used by the `pagination.html` template
+pub(crate) struct Pagination {
+    /// Starts at 1
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+    /// The rest of the query, so the links keep the list's sorting and filters
+    pub params: Vec<(&'static str, String)>,
+}
+
+impl Pagination {
+    /// The page size to use, given what was asked for
+    pub(crate) fn page_size(per_page: Option<u64>, default: u64) -> u64 {
+        per_page.unwrap_or(default).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Keeps the page in range, so asking for page 100 of 3 shows page 3
+    pub(crate) fn new(page: Option<u64>, per_page: u64, total: u64) -> Self {
+        let pages = total.div_ceil(per_page).max(1);
+        Self {
+            page: page.unwrap_or(1).clamp(1, pages),
+            per_page,
+            total,
+            params: vec![],
+        }
+    }
+
+    /// Adds a query parameter to the page links, if it's set
+    pub(crate) fn with_param(mut self, key: &'static str, value: Option<impl Display>) -> Self {
+        if let Some(value) = value {
+            self.params.push((key, value.to_string()));
+        }
+        self
+    }
+
+    pub(crate) fn pages(&self) -> u64 {
+        self.total.div_ceil(self.per_page).max(1)
+    }
+
+    pub(crate) fn prev(&self) -> Option<u64> {
+        (self.page > 1).then(|| self.page - 1)
+    }
+
+    pub(crate) fn next(&self) -> Option<u64> {
+        (self.page < self.pages()).then(|| self.page + 1)
+    }
+}
+
 pub(crate) fn check_login(
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
 ) -> Result<User, (StatusCode, String)> {
@@ -162,4 +215,25 @@ mod tests {
         assert_eq!(check1.cmp(&check2), std::cmp::Ordering::Less);
         assert_eq!(check1.partial_cmp(&check2), Some(std::cmp::Ordering::Less));
     }
+
+    #[test]
+    fn test_pagination() {
+        assert_eq!(Pagination::page_size(None, 50), 50);
+        assert_eq!(Pagination::page_size(Some(0), 50), 1);
+        assert_eq!(Pagination::page_size(Some(1_000_000), 50), MAX_PAGE_SIZE);
+
+        let pagination = Pagination::new(Some(100), 10, 25);
+        assert_eq!(pagination.pages(), 3);
+        assert_eq!(pagination.page, 3);
+        assert_eq!(pagination.prev(), Some(2));
+        assert_eq!(pagination.next(), None);
+
+        let pagination = Pagination::new(None, 10, 0)
+            .with_param("search", Some("example"))
+            .with_param("status", None::<ServiceStatus>);
+        assert_eq!(pagination.page, 1);
+        assert_eq!(pagination.prev(), None);
+        assert_eq!(pagination.next(), None);
+        assert_eq!(pagination.params, vec![("search", "example".to_string())]);
+    }
 }
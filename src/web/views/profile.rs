@@ -1,26 +1,161 @@
 use super::prelude::*;
+use super::tools::check_csrf_token;
+use crate::constants::{MAX_PAGE_SIZE, SESSION_CSRF_TOKEN};
+use crate::db::entities::user_preferences::Preferences;
+use crate::errors::Error;
+use crate::web::preferences::save_preferences;
+use crate::web::theme::{check_timezone, Theme};
+use axum::Form;
+use sea_orm::{ActiveEnum, ColumnTrait, Iterable, QueryFilter, QueryOrder};
 
 #[derive(Template, Debug)]
 #[template(path = "profile.html")]
 pub(crate) struct ProfileTemplate {
     title: String,
     username: Option<String>, // for the header
+    theme: PageTheme,
     profile_user: User,
+    csrf_token: String,
+    preferences: Preferences,
+    favorite_hosts: Vec<entities::host::Model>,
 }
 
 pub(crate) async fn profile(
-    State(_state): State<WebState>,
+    State(state): State<WebState>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
 ) -> Result<ProfileTemplate, (StatusCode, String)> {
     let user = check_login(claims)?;
 
+    let csrf_token = state.new_csrf_token();
+    session
+        .insert(SESSION_CSRF_TOKEN, &csrf_token)
+        .await
+        .map_err(Error::from)?;
+
+    let preferences = session_preferences(&session).await?;
+    let favorite_hosts = entities::host::Entity::find()
+        .filter(entities::host::Column::Id.is_in(preferences.favorite_hosts.clone()))
+        .order_by_asc(entities::host::Column::Name)
+        .all(&state.db)
+        .await
+        .map_err(Error::from)?;
+
     Ok(ProfileTemplate {
         title: user.username(),
         username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
         profile_user: user,
+        csrf_token,
+        preferences,
+        favorite_hosts,
     })
 }
 
+#[derive(Deserialize, Debug)]
+pub(crate) struct PreferencesForm {
+    pub(crate) csrf_token: String,
+    pub(crate) theme: Theme,
+    /// Seconds, or empty to use the default
+    #[serde(default)]
+    pub(crate) page_refresh: String,
+    /// Empty to use the default
+    #[serde(default)]
+    pub(crate) timezone: String,
+    /// Rows per page, or empty to use the default
+    #[serde(default)]
+    pub(crate) page_size: String,
+    /// Empty to show every status on the dashboard
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub(crate) dashboard_status: Option<ServiceStatus>,
+    #[serde(default)]
+    pub(crate) dashboard_search: String,
+}
+
+/// Parses an optional number from a form field
+fn optional_number(value: &str, what: &str) -> Result<Option<u64>, (StatusCode, String)> {
+    match value.trim() {
+        "" => Ok(None),
+        value => value.parse::<u64>().map(Some).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("'{}' isn't a number of {}", value, what),
+            )
+        }),
+    }
+}
+
+/// Stores the user's preferences, so they're there next time they log in
+pub(crate) async fn set_preferences(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<PreferencesForm>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let user = check_login(claims)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+
+    let page_refresh = optional_number(&form.page_refresh, "seconds")?;
+    let page_size = optional_number(&form.page_size, "rows")?;
+    if page_size.is_some_and(|page_size| page_size == 0 || page_size > MAX_PAGE_SIZE) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The page size has to be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+    let timezone = match form.timezone.trim() {
+        "" => None,
+        value => {
+            check_timezone(value).map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("'{}' isn't a valid timezone", value),
+                )
+            })?;
+            Some(value.to_string())
+        }
+    };
+
+    let dashboard_search = match form.dashboard_search.trim() {
+        "" => None,
+        value => Some(value.to_string()),
+    };
+
+    let preferences = Preferences {
+        theme: Some(form.theme),
+        page_refresh,
+        timezone,
+        page_size,
+        dashboard_status: form.dashboard_status,
+        dashboard_search,
+        ..session_preferences(&session).await?
+    };
+    save_preferences(&state.db, &session, &user.subject(), preferences).await?;
+    Ok(Redirect::to(Urls::Profile.as_ref()))
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct FavoriteForm {
+    pub(crate) csrf_token: String,
+    pub(crate) host_id: Uuid,
+}
+
+/// Adds a host to the user's favorites, or takes it off
+pub(crate) async fn toggle_favorite(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<FavoriteForm>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let user = check_login(claims)?;
+    check_csrf_token(&form.csrf_token, &session).await?;
+
+    let mut preferences = session_preferences(&session).await?;
+    preferences.toggle_favorite(form.host_id);
+    save_preferences(&state.db, &session, &user.subject(), preferences).await?;
+    Ok(Redirect::to(&format!("{}/{}", Urls::Host, form.host_id)))
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -32,6 +167,7 @@ mod tests {
         let res = super::profile(
             State(state.clone()),
             Some(crate::web::views::tools::test_user_claims()),
+            state.get_session(),
         )
         .await;
         dbg!(&res);
@@ -40,6 +176,7 @@ mod tests {
         let res = super::profile(
             State(state.clone()),
             Some(crate::web::views::tools::test_user_claims()),
+            state.get_session(),
         )
         .await;
         dbg!(&res);
@@ -56,10 +193,156 @@ mod tests {
         use super::*;
         let state = WebState::test().await;
 
-        let res = super::profile(State(state.clone()), None).await;
+        let res = super::profile(State(state.clone()), None, state.get_session()).await;
 
         dbg!(&res);
         assert!(res.is_err());
         assert_eq!(res.into_response().status(), StatusCode::UNAUTHORIZED)
     }
+
+    #[tokio::test]
+    async fn test_set_preferences() {
+        use super::*;
+        let state = WebState::test().await;
+        let session = state.get_session();
+        session
+            .insert(SESSION_CSRF_TOKEN, "12345")
+            .await
+            .expect("Failed to insert CSRF token into session");
+
+        let form = |csrf_token: &str, page_refresh: &str, timezone: &str, page_size: &str| {
+            Form(PreferencesForm {
+                csrf_token: csrf_token.to_string(),
+                theme: Theme::Dark,
+                page_refresh: page_refresh.to_string(),
+                timezone: timezone.to_string(),
+                page_size: page_size.to_string(),
+                dashboard_status: Some(ServiceStatus::Critical),
+                dashboard_search: " web ".to_string(),
+            })
+        };
+
+        for (csrf_token, page_refresh, timezone, page_size) in [
+            ("wrong", "", "", ""),
+            ("12345", "soon", "", ""),
+            ("12345", "", "<script>", ""),
+            ("12345", "", "", "0"),
+            ("12345", "", "", "lots"),
+        ] {
+            let res = super::set_preferences(
+                State(state.clone()),
+                Some(crate::web::views::tools::test_user_claims()),
+                session.clone(),
+                form(csrf_token, page_refresh, timezone, page_size),
+            )
+            .await;
+            assert!(res.is_err());
+        }
+
+        let res = super::set_preferences(
+            State(state.clone()),
+            Some(crate::web::views::tools::test_user_claims()),
+            session.clone(),
+            form("12345", "300", "Australia/Brisbane", "25"),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        // they're stored against the user, not just in the session
+        let stored =
+            entities::user_preferences::Entity::get_preferences(&state.db, "testuser@example.com")
+                .await
+                .expect("Failed to get stored preferences");
+        assert_eq!(
+            stored,
+            session_preferences(&session)
+                .await
+                .expect("Failed to get preferences")
+        );
+        assert_eq!(stored.page_size, Some(25));
+        assert_eq!(stored.dashboard_status, Some(ServiceStatus::Critical));
+        assert_eq!(stored.dashboard_search.as_deref(), Some("web"));
+        let theme = PageTheme::new(&state, &session)
+            .await
+            .expect("Failed to get theme");
+        assert_eq!(theme.page_refresh, Some(300));
+        assert_eq!(theme.timezone.as_deref(), Some("Australia/Brisbane"));
+
+        let page = super::profile(
+            State(state.clone()),
+            Some(crate::web::views::tools::test_user_claims()),
+            session,
+        )
+        .await
+        .expect("Failed to get profile")
+        .to_string();
+        assert!(page.contains("data-bs-theme=\"dark\""));
+        assert!(page.contains("data-timezone=\"Australia/Brisbane\""));
+    }
+
+    #[tokio::test]
+    async fn test_toggle_favorite() {
+        use super::*;
+        let state = WebState::test().await;
+        let session = state.get_session();
+        session
+            .insert(SESSION_CSRF_TOKEN, "12345")
+            .await
+            .expect("Failed to insert CSRF token into session");
+        let host = entities::host::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+
+        let form = || {
+            Form(FavoriteForm {
+                csrf_token: "12345".to_string(),
+                host_id: host.id,
+            })
+        };
+        assert!(
+            super::toggle_favorite(State(state.clone()), None, session.clone(), form())
+                .await
+                .is_err()
+        );
+
+        super::toggle_favorite(
+            State(state.clone()),
+            Some(crate::web::views::tools::test_user_claims()),
+            session.clone(),
+            form(),
+        )
+        .await
+        .expect("Failed to add favorite");
+        let page = super::profile(
+            State(state.clone()),
+            Some(crate::web::views::tools::test_user_claims()),
+            session.clone(),
+        )
+        .await
+        .expect("Failed to get profile");
+        assert_eq!(page.favorite_hosts.len(), 1);
+        assert_eq!(page.favorite_hosts[0].id, host.id);
+
+        // the profile page hands out a new token
+        session
+            .insert(SESSION_CSRF_TOKEN, "12345")
+            .await
+            .expect("Failed to insert CSRF token into session");
+
+        super::toggle_favorite(
+            State(state.clone()),
+            Some(crate::web::views::tools::test_user_claims()),
+            session.clone(),
+            form(),
+        )
+        .await
+        .expect("Failed to remove favorite");
+        assert!(session_preferences(&session)
+            .await
+            .expect("Failed to get preferences")
+            .favorite_hosts
+            .is_empty());
+    }
 }
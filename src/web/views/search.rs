@@ -0,0 +1,172 @@
+//! Searching everything at once, at `/search?q=`
+
+use sea_orm::{ColumnTrait, Condition, QueryFilter, QueryOrder, QuerySelect};
+
+use super::prelude::*;
+use crate::constants::{SEARCH_HISTORY_DAYS, SEARCH_RESULT_LIMIT};
+use crate::db::entities::service_check_history::FullHistoryEntry;
+use crate::errors::Error;
+
+#[derive(Template, Debug)]
+#[template(path = "search.html")]
+pub(crate) struct SearchTemplate {
+    title: String,
+    username: Option<String>,
+    theme: PageTheme,
+    query: String,
+    hosts: Vec<entities::host::Model>,
+    services: Vec<entities::service::Model>,
+    host_groups: Vec<entities::host_group::Model>,
+    results: Vec<FullHistoryEntry>,
+}
+
+impl SearchTemplate {
+    fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+            && self.services.is_empty()
+            && self.host_groups.is_empty()
+            && self.results.is_empty()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct SearchQuery {
+    pub(crate) q: Option<String>,
+}
+
+/// Matches host, service and group names, and the text of recent check results
+pub(crate) async fn search(
+    State(state): State<WebState>,
+    Query(query): Query<SearchQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+) -> Result<SearchTemplate, (StatusCode, String)> {
+    let user = check_login(claims)?;
+    let text = query.q.unwrap_or_default().trim().to_string();
+
+    let mut template = SearchTemplate {
+        title: "Search".to_string(),
+        username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
+        query: text.clone(),
+        hosts: vec![],
+        services: vec![],
+        host_groups: vec![],
+        results: vec![],
+    };
+    if text.is_empty() {
+        return Ok(template);
+    }
+
+    template.hosts = entities::host::Entity::find()
+        .filter(
+            Condition::any()
+                .add(entities::host::Column::Name.contains(&text))
+                .add(entities::host::Column::Hostname.contains(&text))
+                .add(entities::host::Column::DisplayName.contains(&text)),
+        )
+        .order_by_asc(entities::host::Column::Name)
+        .limit(SEARCH_RESULT_LIMIT)
+        .all(&state.db)
+        .await
+        .map_err(Error::from)?;
+    template.services = entities::service::Entity::find()
+        .filter(
+            Condition::any()
+                .add(entities::service::Column::Name.contains(&text))
+                .add(entities::service::Column::Description.contains(&text)),
+        )
+        .order_by_asc(entities::service::Column::Name)
+        .limit(SEARCH_RESULT_LIMIT)
+        .all(&state.db)
+        .await
+        .map_err(Error::from)?;
+    template.host_groups = entities::host_group::Entity::find()
+        .filter(entities::host_group::Column::Name.contains(&text))
+        .order_by_asc(entities::host_group::Column::Name)
+        .limit(SEARCH_RESULT_LIMIT)
+        .all(&state.db)
+        .await
+        .map_err(Error::from)?;
+    template.results = entities::service_check_history::Entity::search(
+        &state.db,
+        &text,
+        chrono::Utc::now() - chrono::TimeDelta::days(SEARCH_HISTORY_DAYS),
+        SEARCH_RESULT_LIMIT,
+    )
+    .await?;
+
+    Ok(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{ActiveModelTrait, IntoActiveModel, Set};
+
+    use super::*;
+    use crate::web::views::tools::test_user_claims;
+
+    #[tokio::test]
+    async fn test_search() {
+        let state = WebState::test().await;
+        let host = entities::host::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query hosts")
+            .expect("No hosts found");
+
+        assert!(search(
+            State(state.clone()),
+            Query(SearchQuery {
+                q: Some(host.name.clone()),
+            }),
+            None,
+            state.get_session(),
+        )
+        .await
+        .is_err());
+
+        let page = search(
+            State(state.clone()),
+            Query(SearchQuery {
+                q: Some(host.name.clone()),
+            }),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to search");
+        assert!(page.hosts.iter().any(|found| found.id == host.id));
+
+        // hosts can be found by the name the UI shows for them
+        let mut named = host.clone().into_active_model();
+        named.display_name = Set(Some("Payments DB primary".to_string()));
+        named
+            .update(&state.db)
+            .await
+            .expect("Failed to update host");
+        let page = search(
+            State(state.clone()),
+            Query(SearchQuery {
+                q: Some("Payments DB".to_string()),
+            }),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to search");
+        assert!(page.hosts.iter().any(|found| found.id == host.id));
+        assert!(page.to_string().contains("Payments DB primary"));
+
+        let page = search(
+            State(state.clone()),
+            Query(SearchQuery::default()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to search");
+        assert!(page.is_empty());
+        assert!(page.to_string().contains("Search"));
+    }
+}
@@ -2,7 +2,9 @@
 
 use super::index::SortQueries;
 use super::prelude::*;
+use crate::constants::SESSION_CSRF_TOKEN;
 use crate::errors::Error;
+use crate::labels::LabelFilter;
 use entities::service_check::FullServiceCheck;
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
 use uuid::Uuid;
@@ -12,23 +14,39 @@ use uuid::Uuid;
 pub(crate) struct ServiceTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     service: entities::service::Model,
+    /// The host groups the service runs on
+    applies_to: Vec<entities::host_group::Model>,
+    /// The host groups whose hosts it doesn't run on
+    excludes: Vec<entities::host_group::Model>,
     service_checks: Vec<FullServiceCheck>,
+    csrf_token: String,
 }
 
 /// Host view
 pub(crate) async fn service(
-    Path(service_id): Path<Uuid>,
+    Path(service_id): Path<String>,
     State(state): State<WebState>,
     Query(_queries): Query<SortQueries>,
+    session: Session,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
 ) -> Result<ServiceTemplate, (StatusCode, String)> {
     let user = check_login(claims)?;
 
-    let reader = state.db.read().await;
+    let csrf_token = state.new_csrf_token();
+    session
+        .insert(SESSION_CSRF_TOKEN, &csrf_token)
+        .await
+        .map_err(Error::from)?;
 
-    let service = match entities::service::Entity::find_by_id(service_id)
-        .one(&*reader)
+    let service = match entities::service::Entity::find()
+        .filter(entities::id_or_slug(
+            entities::service::Column::Id,
+            entities::service::Column::Slug,
+            &service_id,
+        ))
+        .one(&state.db)
         .await
         .map_err(Error::from)?
     {
@@ -41,15 +59,26 @@ pub(crate) async fn service(
         }
     };
 
-    let service_checks = FullServiceCheck::get_by_service_id(service_id, &reader)
+    let service_checks = FullServiceCheck::get_by_service_id(service.id, &state.db)
         .await
         .map_err(Error::from)?;
+    let (excludes, applies_to): (Vec<_>, Vec<_>) =
+        entities::service_group_link::Entity::groups(&state.db, service.id)
+            .await?
+            .into_iter()
+            .partition(|(_, exclude)| *exclude);
+    let applies_to = applies_to.into_iter().map(|(group, _)| group).collect();
+    let excludes = excludes.into_iter().map(|(group, _)| group).collect();
 
     Ok(ServiceTemplate {
         title: service.name.clone(),
         service,
+        applies_to,
+        excludes,
         service_checks,
         username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
+        csrf_token,
     })
 }
 
@@ -58,19 +87,26 @@ pub(crate) async fn service(
 pub(crate) struct ServicesTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     services: Vec<entities::service::Model>,
+    /// Only showing services with this label
+    label: Option<LabelFilter>,
 }
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct ServicesQuery {
     pub(crate) search: Option<String>,
     pub(crate) ord: Option<Order>,
+    /// Only show services with this label, eg `team:payments`
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub(crate) label: Option<LabelFilter>,
 }
 
 pub(crate) async fn services(
     State(state): State<WebState>,
     Query(queries): Query<ServicesQuery>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
 ) -> Result<ServicesTemplate, (StatusCode, String)> {
     let user = check_login(claims)?;
 
@@ -80,17 +116,22 @@ pub(crate) async fn services(
     if let Some(search) = queries.search {
         services = services.filter(entities::service::Column::Name.contains(search));
     }
+    if let Some(label) = &queries.label {
+        services = services.filter(label.condition(entities::service::Column::Labels));
+    }
 
     let services = services
         .order_by(entities::service::Column::Name, order.into())
-        .all(&*state.db.read().await)
+        .all(&state.db)
         .await
         .map_err(Error::from)?;
 
     Ok(ServicesTemplate {
         title: "Services".to_string(),
         services,
+        label: queries.label,
         username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
     })
 }
 
@@ -104,40 +145,45 @@ mod tests {
         let state = WebState::test().await;
 
         let service = entities::service::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
 
-        let res = super::service(
-            Path(service.id),
-            State(state.clone()),
-            Query(SortQueries::default()),
-            Some(crate::web::views::tools::test_user_claims()),
-        )
-        .await
-        .expect("Failed to auth!");
+        // it should be reachable by ID or slug
+        for service_id in [service.id.to_string(), service.slug.clone()] {
+            let res = super::service(
+                Path(service_id),
+                State(state.clone()),
+                Query(SortQueries::default()),
+                state.get_session(),
+                Some(crate::web::views::tools::test_user_claims()),
+            )
+            .await
+            .expect("Failed to auth!");
 
-        let res = res.to_string();
+            let res = res.to_string();
 
-        dbg!(&res);
+            dbg!(&res);
 
-        assert!(res.contains("Maremma"))
+            assert!(res.contains("Maremma"))
+        }
     }
     #[tokio::test]
     async fn test_view_service_without_auth() {
         use super::*;
         let state = WebState::test().await;
         let service = entities::service::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
 
         let res = super::service(
-            Path(service.id),
+            Path(service.id.to_string()),
             State(state.clone()),
             Query(SortQueries::default()),
+            state.get_session(),
             None,
         )
         .await;
@@ -153,7 +199,7 @@ mod tests {
 
         let mut service_id = Uuid::new_v4();
         while entities::service::Entity::find_by_id(service_id)
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for service")
             .is_some()
@@ -161,9 +207,10 @@ mod tests {
             service_id = Uuid::new_v4();
         }
         let res = super::service(
-            Path(service_id),
+            Path(service_id.to_string()),
             State(state.clone()),
             Query(SortQueries::default()),
+            state.get_session(),
             Some(crate::web::views::tools::test_user_claims()),
         )
         .await;
@@ -190,8 +237,10 @@ mod tests {
                 Query(ServicesQuery {
                     search: Some("example".to_string()),
                     ord,
+                    label: None,
                 }),
                 Some(test_user_claims()),
+                state.get_session(),
             )
             .await;
 
@@ -1,7 +1,22 @@
-use axum::Form;
-use sea_orm::{ColumnTrait, ModelTrait, QueryFilter, QueryOrder, QuerySelect};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::{Form, Json};
+use futures::{Stream, StreamExt};
+use sea_orm::{
+    ActiveEnum, ColumnTrait, Iterable, ModelTrait, PaginatorTrait, QueryFilter, QueryOrder,
+};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::constants::DEFAULT_SERVICE_CHECK_HISTORY_VIEW_ENTRIES;
+use crate::actions::routing::{EffectiveRouting, NotificationRoutes};
+use crate::artifacts::ARTIFACTS;
+use crate::constants::{DEFAULT_SERVICE_CHECK_HISTORY_VIEW_ENTRIES, RECENT_INCIDENTS};
+use crate::db::entities::incident::{FullIncident, IncidentFilter};
+use crate::db::entities::service_check_rollup::{RollupPeriod, RollupSummary};
+use crate::result_writer::{CheckUpdate, CHECK_UPDATES};
+use crate::schedule_insight::ScheduleInsight;
+use crate::services::ServiceType;
 use crate::web::Error;
 
 use super::prelude::*;
@@ -11,24 +26,54 @@ use super::prelude::*;
 pub(crate) struct ServiceCheckTemplate {
     title: String,
     username: Option<String>, // for the header
+    theme: PageTheme,
     message: Option<String>,
     status: String,
     service_check: entities::service_check::Model,
     host: entities::host::Model,
     service: entities::service::Model,
     service_check_history: Vec<entities::service_check_history::Model>,
+    /// For the history
+    pagination: Pagination,
+    /// Only showing history with this status
+    history_status: Option<ServiceStatus>,
     parsed_config: Option<String>,
+    /// The last 24 hours from the hourly rollups, and the last 30 days from the daily ones
+    availability: Vec<(&'static str, RollupSummary)>,
+    /// Set while the check's critical, so it can be acknowledged
+    escalation: Option<entities::service_check_escalation::Model>,
+    /// The check's most recent incidents
+    incidents: Vec<FullIncident>,
+    /// Set for heartbeat checks, with the URL to ping
+    heartbeat: Option<entities::service_check_heartbeat::Model>,
+    heartbeat_url: Option<String>,
+    /// Why and when the check runs next, empty if it couldn't be worked out
+    schedule: Option<ScheduleInsight>,
+    /// The last change in the output, for checks with `detect_changes` set
+    output_change: Option<entities::service_check_output::Model>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct HistoryQuery {
+    /// Only show history with this status
+    #[serde(default, deserialize_with = "crate::serde::empty_as_none::deserialize")]
+    pub(crate) status: Option<ServiceStatus>,
+    /// Starts at 1
+    pub(crate) page: Option<u64>,
+    pub(crate) per_page: Option<u64>,
 }
 
 pub(crate) async fn service_check_get(
     Path(service_check_id): Path<Uuid>,
+    Query(query): Query<HistoryQuery>,
     State(state): State<WebState>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
 ) -> Result<ServiceCheckTemplate, (StatusCode, String)> {
     let user = check_login(claims)?;
 
     let res = entities::service_check::Entity::find_by_id(service_check_id)
-        .one(&*state.db.read().await)
+        .one(&state.db)
         .await
         .map_err(|err| {
             error!(
@@ -45,11 +90,29 @@ pub(crate) async fn service_check_get(
         format!("Service check with id={} not found", service_check_id),
     ))?;
 
-    let service_check_history = entities::service_check_history::Entity::find()
-        .filter(entities::service_check_history::Column::ServiceCheckId.eq(service_check_id))
+    let mut history = entities::service_check_history::Entity::find()
+        .filter(entities::service_check_history::Column::ServiceCheckId.eq(service_check_id));
+    if let Some(status) = query.status {
+        history = history.filter(entities::service_check_history::Column::Status.eq(status));
+    }
+    let per_page = Pagination::page_size(
+        query.per_page,
+        session_preferences(&session)
+            .await?
+            .page_size
+            .unwrap_or(DEFAULT_SERVICE_CHECK_HISTORY_VIEW_ENTRIES),
+    );
+    let paginator = history
         .order_by_desc(entities::service_check_history::Column::Timestamp)
-        .limit(DEFAULT_SERVICE_CHECK_HISTORY_VIEW_ENTRIES)
-        .all(&*state.db.read().await)
+        .paginate(&state.db, per_page);
+    let pagination = Pagination::new(
+        query.page,
+        per_page,
+        paginator.num_items().await.map_err(Error::from)?,
+    )
+    .with_param("status", query.status.map(|status| status.to_value()));
+    let service_check_history = paginator
+        .fetch_page(pagination.page - 1)
         .await
         .map_err(|err| {
             error!(
@@ -59,9 +122,39 @@ pub(crate) async fn service_check_get(
             Error::from(err)
         })?;
 
+    let last_day = entities::service_check_rollup::Entity::summary(
+        &state.db,
+        service_check_id,
+        RollupPeriod::Hour,
+        Utc::now() - TimeDelta::days(1),
+    )
+    .await?;
+    let last_month = entities::service_check_rollup::Entity::summary(
+        &state.db,
+        service_check_id,
+        RollupPeriod::Day,
+        Utc::now() - TimeDelta::days(30),
+    )
+    .await?;
+
+    let incidents = entities::incident::Entity::search(
+        &state.db,
+        &IncidentFilter {
+            service_check_id: Some(service_check_id),
+            limit: RECENT_INCIDENTS,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let escalation = entities::service_check_escalation::Entity::find_by_id(service_check_id)
+        .one(&state.db)
+        .await
+        .map_err(Error::from)?;
+
     let host = service_check
         .find_related(entities::host::Entity)
-        .one(&*state.db.read().await)
+        .one(&state.db)
         .await
         .map_err(|err| {
             error!(
@@ -79,7 +172,7 @@ pub(crate) async fn service_check_get(
 
     let service = service_check
         .find_related(entities::service::Entity)
-        .one(&*state.db.read().await)
+        .one(&state.db)
         .await
         .map_err(|err| {
             error!(
@@ -98,16 +191,15 @@ pub(crate) async fn service_check_get(
             )
         })?;
 
-    let mut parsed_service =
-        crate::services::Service::try_from_service_model(&service, &*state.db.read().await)
-            .await
-            .map_err(|err| {
-                error!(
-                    "Failed to render service_check {} into service {:?}",
-                    service_check_id, err
-                );
-                Error::Configuration("Failed to parse service definition".to_string())
-            })?;
+    let mut parsed_service = crate::services::Service::try_from_service_model(&service, &state.db)
+        .await
+        .map_err(|err| {
+            error!(
+                "Failed to render service_check {} into service {:?}",
+                service_check_id, err
+            );
+            Error::Configuration("Failed to parse service definition".to_string())
+        })?;
 
     parsed_service.parse_config().map_err(|err| {
         error!(
@@ -117,6 +209,36 @@ pub(crate) async fn service_check_get(
         Error::Configuration("Failed to parse service definition to config".to_string())
     })?;
 
+    let heartbeat = match service.service_type {
+        ServiceType::Heartbeat => Some(
+            entities::service_check_heartbeat::Entity::for_service_check(
+                &state.db,
+                service_check_id,
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+    let frontend_url = state.configuration.read().await.frontend_url.clone();
+    let heartbeat_url = heartbeat
+        .as_ref()
+        .map(|heartbeat| heartbeat.url(&frontend_url));
+
+    let schedule = ScheduleInsight::for_service_check(&state.db, service_check_id, Utc::now())
+        .await
+        .inspect_err(|err| {
+            warn!(
+                "Failed to work out the schedule for service_check={}: {:?}",
+                service_check_id, err
+            )
+        })
+        .ok();
+
+    let output_change = entities::service_check_output::Entity::find_by_id(service_check_id)
+        .one(&state.db)
+        .await?
+        .filter(|output| output.diff.is_some());
+
     let parsed_config = parsed_service.config().map(|liveservice| {
         let res = liveservice
             .as_json_pretty(&host)
@@ -135,16 +257,243 @@ pub(crate) async fn service_check_get(
     Ok(ServiceCheckTemplate {
         title: format!("Service Check: {}", &service.name),
         username: Some(user.username()),
+        theme: PageTheme::new(&state, &session).await?,
         message: None,
         status: "".to_string(),
         service_check,
         host,
         service,
         service_check_history,
+        pagination,
+        history_status: query.status,
         parsed_config,
+        availability: vec![("Last 24 hours", last_day), ("Last 30 days", last_month)],
+        escalation,
+        incidents,
+        heartbeat,
+        heartbeat_url,
+        schedule,
+        output_change,
     })
 }
 
+/// `GET /api/v1/service_check/:service_check_id/schedule`, why and when the check runs next
+pub(crate) async fn api_service_check_schedule(
+    Path(service_check_id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<ScheduleInsight>, Error> {
+    claims.ok_or(Error::Unauthorized)?;
+    Ok(Json(
+        ScheduleInsight::for_service_check(&state.db, service_check_id, Utc::now()).await?,
+    ))
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct RunResponse {
+    service_check_id: Uuid,
+    /// Pass this as `since` to the events endpoint, so a result that lands before it's connected isn't missed
+    requested_at: DateTime<Utc>,
+    /// Where the result turns up
+    events: String,
+}
+
+/// `POST /api/v1/service_check/:service_check_id/run`, marks the check urgent so it runs as soon as there's a slot
+pub(crate) async fn api_service_check_run(
+    Path(service_check_id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<RunResponse>, Error> {
+    let user = User::from(claims.ok_or(Error::Unauthorized)?);
+    let service_check = entities::service_check::Entity::find_by_id(service_check_id)
+        .one(&state.db)
+        .await?
+        .ok_or(Error::ServiceCheckNotFound(service_check_id))?;
+
+    let requested_at = Utc::now();
+    let mut service_check = service_check.into_active_model();
+    service_check
+        .status
+        .set_if_not_equals(ServiceStatus::Urgent);
+    service_check.paused_until.set_if_not_equals(None);
+    service_check.last_updated.set_if_not_equals(requested_at);
+    if service_check.is_changed() {
+        service_check.save(&state.db).await?;
+    }
+    info!(
+        "{} asked for service_check_id={} to run now",
+        user.username(),
+        service_check_id
+    );
+    Ok(Json(RunResponse {
+        service_check_id,
+        requested_at,
+        events: format!(
+            "{}/{}/events",
+            Urls::ServiceCheckApi,
+            service_check_id.hyphenated()
+        ),
+    }))
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct EventsQuery {
+    /// If the check's got a result from this time or later, it's sent straight away
+    since: Option<DateTime<Utc>>,
+}
+
+/// `GET /api/v1/service_check/:service_check_id/events`, a server-sent event stream of the check's results as
+/// they're written
+pub(crate) async fn api_service_check_events(
+    Path(service_check_id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, Error> {
+    claims.ok_or(Error::Unauthorized)?;
+    entities::service_check::Entity::find_by_id(service_check_id)
+        .one(&state.db)
+        .await?
+        .ok_or(Error::ServiceCheckNotFound(service_check_id))?;
+
+    // subscribe before looking for the latest result, so one that's written in between isn't missed
+    let updates = CHECK_UPDATES.subscribe();
+    let latest = match query.since {
+        Some(since) => entities::service_check_history::Entity::find()
+            .filter(entities::service_check_history::Column::ServiceCheckId.eq(service_check_id))
+            .filter(entities::service_check_history::Column::Timestamp.gte(since))
+            .order_by_desc(entities::service_check_history::Column::Timestamp)
+            .one(&state.db)
+            .await?
+            .map(CheckUpdate::from),
+        None => None,
+    };
+
+    let updates = futures::stream::unfold(updates, move |mut updates| async move {
+        loop {
+            match updates.recv().await {
+                Ok(update) if update.service_check_id == service_check_id => {
+                    return Some((update, updates))
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!(
+                        "Events for service_check_id={} skipped {} updates",
+                        service_check_id, skipped
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = futures::stream::iter(latest)
+        .chain(updates)
+        .map(|update| Event::default().event("result").json_data(update));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Acknowledges a critical check, so its escalation policy doesn't go any further
+pub(crate) async fn acknowledge_service_check(
+    Path(service_check_id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Form(form): Form<RedirectTo>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let user = check_login(claims)?;
+    if !entities::service_check_escalation::Entity::acknowledge(
+        &state.db,
+        service_check_id,
+        &user.username(),
+        Utc::now(),
+    )
+    .await?
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!(
+                "Service check with id={} isn't waiting to be acknowledged",
+                service_check_id
+            ),
+        ));
+    }
+    info!(
+        "user={} Acknowledged service_check={}",
+        user.username(),
+        service_check_id
+    );
+    if let Some(redirect_to) = &form.redirect_to {
+        Ok(Redirect::to(redirect_to))
+    } else {
+        Ok(Redirect::to(&format!(
+            "{}/{}",
+            Urls::ServiceCheck,
+            service_check_id.hyphenated()
+        )))
+    }
+}
+
+/// Shows the notification routing for a service check once all the overrides are applied, for debugging
+pub(crate) async fn service_check_notifications(
+    Path(service_check_id): Path<Uuid>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<EffectiveRouting>, (StatusCode, String)> {
+    check_login(claims)?;
+
+    let (service_check, host) = entities::service_check::Entity::find_by_id(service_check_id)
+        .find_also_related(entities::host::Entity)
+        .one(&state.db)
+        .await
+        .map_err(Error::from)?
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Service check with id={} not found", service_check_id),
+        ))?;
+    let host = host.ok_or(Error::HostNotFound(service_check.host_id))?;
+    let service = service_check
+        .find_related(entities::service::Entity)
+        .one(&state.db)
+        .await
+        .map_err(Error::from)?
+        .ok_or(Error::ServiceNotFound(service_check.service_id))?;
+
+    let routes = NotificationRoutes::new(&*state.configuration.read().await);
+    Ok(Json(routes.resolve(&host.name, &service.name)))
+}
+
+/// The full output of a history entry that was too big to keep in the database, from the artifact store
+pub(crate) async fn service_check_artifact(
+    Path((service_check_id, history_id)): Path<(Uuid, Uuid)>,
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<(StatusCode, HeaderMap, Vec<u8>), (StatusCode, String)> {
+    check_login(claims)?;
+
+    let artifact = entities::service_check_history::Entity::find_by_id(history_id)
+        .filter(entities::service_check_history::Column::ServiceCheckId.eq(service_check_id))
+        .one(&state.db)
+        .await
+        .map_err(Error::from)?
+        .and_then(|history| history.artifact)
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("There's no artifact for history entry {}", history_id),
+        ))?;
+    let contents = ARTIFACTS.get(&artifact).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("inline; filename=\"{}\"", artifact))
+            .map_err(Error::from)?,
+    );
+    Ok((StatusCode::OK, headers, contents))
+}
+
 pub(crate) async fn set_service_check_urgent(
     Path(service_check_id): Path<Uuid>,
     State(state): State<WebState>,
@@ -175,7 +524,7 @@ pub(crate) async fn set_service_check_status(
     form: RedirectTo,
 ) -> Result<Redirect, (StatusCode, String)> {
     let service_check = entities::service_check::Entity::find_by_id(service_check_id)
-        .one(&*state.db.read().await)
+        .one(&state.db)
         .await
         .map_err(|err| {
             error!(
@@ -197,6 +546,8 @@ pub(crate) async fn set_service_check_status(
 
     let mut service_check = service_check.into_active_model();
     service_check.status.set_if_not_equals(status);
+    // changing a single check by hand takes it out of any bulk pause
+    service_check.paused_until.set_if_not_equals(None);
     service_check
         .last_updated
         .set_if_not_equals(chrono::Utc::now());
@@ -204,16 +555,13 @@ pub(crate) async fn set_service_check_status(
     let host_id = service_check.host_id.clone().unwrap();
 
     if service_check.is_changed() {
-        service_check
-            .save(&*state.db.write().await)
-            .await
-            .map_err(|err| {
-                error!(
-                    "Failed to set service_check_id={} to status={}: {:?}",
-                    service_check_id, status, err
-                );
-                Error::from(err)
-            })?;
+        service_check.save(&state.db).await.map_err(|err| {
+            error!(
+                "Failed to set service_check_id={} to status={}: {:?}",
+                service_check_id, status, err
+            );
+            Error::from(err)
+        })?;
     };
     // TODO: make it so we can redirect to... elsewhere based on a query string?
     if let Some(redirect_to) = &form.redirect_to {
@@ -227,6 +575,29 @@ pub(crate) async fn set_service_check_status(
     }
 }
 
+/// Cancels a check that's running, the check loop records it as cancelled and schedules the next run
+pub(crate) async fn cancel_service_check(
+    Path(service_check_id): Path<Uuid>,
+    State(state): State<WebState>,
+    Form(form): Form<RedirectTo>,
+) -> Result<Redirect, (StatusCode, String)> {
+    if !state.running_checks.cancel(&service_check_id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Service check with id={} isn't running", service_check_id),
+        ));
+    }
+    if let Some(redirect_to) = &form.redirect_to {
+        Ok(Redirect::to(redirect_to))
+    } else {
+        Ok(Redirect::to(&format!(
+            "{}/{}",
+            Urls::ServiceCheck,
+            service_check_id.hyphenated()
+        )))
+    }
+}
+
 /// For when you want to redirect people back to where they came from
 #[derive(Deserialize, Debug)]
 pub(crate) struct RedirectTo {
@@ -254,7 +625,7 @@ pub(crate) async fn service_check_delete(
     })?;
 
     entities::service_check::Entity::delete_by_id(service_check_id)
-        .exec(&*state.db.write().await)
+        .exec(&state.db)
         .await
         .map_err(|err| {
             error!(
@@ -263,6 +634,7 @@ pub(crate) async fn service_check_delete(
             );
             Error::from(err)
         })?;
+    crate::schedule_insight::forget(service_check_id);
 
     if let Some(redirect_to) = redirect_form.redirect_to {
         Ok(Redirect::to(&redirect_to))
@@ -285,11 +657,18 @@ mod tests {
         let state = WebState::test().await;
 
         let service_check = entities::service_check::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
-        let res = service_check_get(Path(service_check.id), State(state.clone()), None).await;
+        let res = service_check_get(
+            Path(service_check.id),
+            Query(HistoryQuery::default()),
+            State(state.clone()),
+            None,
+            state.get_session(),
+        )
+        .await;
 
         assert!(res.is_err()); // because authentication failed
     }
@@ -299,15 +678,17 @@ mod tests {
         let state = WebState::test().await;
 
         let service_check = entities::service_check::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
 
         let res = service_check_get(
             Path(service_check.id),
+            Query(HistoryQuery::default()),
             State(state.clone()),
             Some(test_user_claims()),
+            state.get_session(),
         )
         .await
         .expect("Failed to auth!");
@@ -326,7 +707,7 @@ mod tests {
         let state = WebState::new(db, config, None, None, PathBuf::new());
 
         let service_check = entities::service_check::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
@@ -359,12 +740,148 @@ mod tests {
 
         assert!(res.is_err());
     }
+    #[tokio::test]
+    async fn test_service_check_notifications() {
+        let state = WebState::test().await;
+        state
+            .configuration
+            .write()
+            .await
+            .notifications
+            .routing
+            .targets = Some(vec!["oncall".to_string()]);
+
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to get service check")
+            .expect("No service checks found");
+
+        assert!(
+            service_check_notifications(Path(service_check.id), State(state.clone()), None)
+                .await
+                .is_err()
+        );
+        let res = service_check_notifications(
+            Path(service_check.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to get notification routing");
+        assert_eq!(res.0.targets, vec!["oncall".to_string()]);
+        assert_eq!(
+            res.0.sources.get("targets").map(String::as_str),
+            Some("global")
+        );
+
+        let res = service_check_notifications(
+            Path(Uuid::new_v4()),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await;
+        assert_eq!(
+            res.err().map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_service_check() {
+        let state = WebState::test().await;
+        let service_check_id = Uuid::new_v4();
+
+        let res = cancel_service_check(
+            Path(service_check_id),
+            State(state.clone()),
+            Form(RedirectTo::from(None)),
+        )
+        .await;
+        assert_eq!(
+            res.err().map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+
+        let (check, abort_handle) = futures::future::abortable(std::future::pending::<()>());
+        state.running_checks.insert(service_check_id, abort_handle);
+        let res = cancel_service_check(
+            Path(service_check_id),
+            State(state.clone()),
+            Form(RedirectTo::from(None)),
+        )
+        .await;
+        assert!(res.is_ok());
+        assert!(check.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge_service_check() {
+        let state = WebState::test().await;
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to get service check")
+            .expect("No service checks found");
+
+        // it's not critical, so there's nothing to acknowledge
+        let res = acknowledge_service_check(
+            Path(service_check.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+            Form(RedirectTo::from(None)),
+        )
+        .await;
+        assert_eq!(
+            res.err().map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+
+        let mut active = service_check.clone().into_active_model();
+        active.status.set_if_not_equals(ServiceStatus::Critical);
+        active
+            .update(&state.db)
+            .await
+            .expect("Failed to update check");
+        entities::service_check_escalation::Entity::track(&state.db, Utc::now())
+            .await
+            .expect("Failed to track escalations");
+
+        assert!(acknowledge_service_check(
+            Path(service_check.id),
+            State(state.clone()),
+            None,
+            Form(RedirectTo::from(None)),
+        )
+        .await
+        .is_err());
+        acknowledge_service_check(
+            Path(service_check.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+            Form(RedirectTo::from(None)),
+        )
+        .await
+        .expect("Failed to acknowledge check");
+        let page = service_check_get(
+            Path(service_check.id),
+            Query(HistoryQuery::default()),
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to load service check")
+        .to_string();
+        assert!(page.contains("Acknowledged by"));
+    }
+
     #[tokio::test]
     async fn test_set_service_check_disabled() {
         let state = WebState::test().await;
 
         let service_check = entities::service_check::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
@@ -391,7 +908,7 @@ mod tests {
         let state = WebState::new(db, config, None, None, PathBuf::new());
 
         let service_check = entities::service_check::Entity::find()
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
@@ -419,7 +936,7 @@ mod tests {
 
         let mut service_check_id = Uuid::new_v4();
         while entities::service_check::Entity::find_by_id(service_check_id)
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for service_check")
             .is_some()
@@ -428,8 +945,10 @@ mod tests {
         }
         let res = super::service_check_get(
             Path(service_check_id),
+            Query(HistoryQuery::default()),
             State(state.clone()),
             Some(test_user_claims()),
+            state.get_session(),
         )
         .await;
 
@@ -447,7 +966,7 @@ mod tests {
 
         let mut service_check_id = Uuid::new_v4();
         while entities::service_check::Entity::find_by_id(service_check_id)
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for service_check")
             .is_some()
@@ -473,7 +992,7 @@ mod tests {
 
         let mut service_check_id = Uuid::new_v4();
         while entities::service_check::Entity::find_by_id(service_check_id)
-            .one(&*state.db.read().await)
+            .one(&state.db)
             .await
             .expect("Failed to search for service_check")
             .is_some()
@@ -494,7 +1013,7 @@ mod tests {
 
         // find a valid service check
         let service_check = entities::service_check::Entity::find()
-            .one(&*db.write().await)
+            .one(&db)
             .await
             .expect("Failed to get service check")
             .expect("No service checks found");
@@ -508,4 +1027,269 @@ mod tests {
         .await;
         assert!(res.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_service_check_history_pages() {
+        use sea_orm::Set;
+
+        let state = WebState::test().await;
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to query checks")
+            .expect("No checks found");
+        for (minutes, status) in [
+            (3, ServiceStatus::Ok),
+            (2, ServiceStatus::Critical),
+            (1, ServiceStatus::Ok),
+        ] {
+            entities::service_check_history::ActiveModel {
+                id: Set(Uuid::new_v4()),
+                service_check_id: Set(service_check.id),
+                timestamp: Set(chrono::Utc::now() - chrono::TimeDelta::minutes(minutes)),
+                status: Set(status),
+                result_text: Set("test".to_string()),
+                time_elapsed: Set(0),
+                ..Default::default()
+            }
+            .insert(&state.db)
+            .await
+            .expect("Failed to insert history");
+        }
+
+        let page = service_check_get(
+            Path(service_check.id),
+            Query(HistoryQuery {
+                page: Some(2),
+                per_page: Some(2),
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to load service check");
+        assert_eq!(page.pagination.total, 3);
+        assert_eq!(page.service_check_history.len(), 1);
+        // newest first, so the oldest is on the last page
+        assert_eq!(
+            page.service_check_history.first().map(|entry| entry.status),
+            Some(ServiceStatus::Ok)
+        );
+
+        let page = service_check_get(
+            Path(service_check.id),
+            Query(HistoryQuery {
+                status: Some(ServiceStatus::Critical),
+                ..Default::default()
+            }),
+            State(state.clone()),
+            Some(test_user_claims()),
+            state.get_session(),
+        )
+        .await
+        .expect("Failed to load service check");
+        assert_eq!(page.pagination.total, 1);
+        assert!(page.to_string().contains("value=\"critical\" selected"));
+    }
+
+    #[tokio::test]
+    async fn test_api_service_check_schedule() {
+        let state = WebState::test().await;
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to get service check")
+            .expect("No service checks found");
+
+        assert!(
+            api_service_check_schedule(Path(service_check.id), State(state.clone()), None)
+                .await
+                .is_err()
+        );
+
+        let Json(schedule) = api_service_check_schedule(
+            Path(service_check.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to get the schedule");
+        assert_eq!(schedule.service_check_id, service_check.id);
+        assert_eq!(schedule.next_check, service_check.next_check);
+
+        assert!(api_service_check_schedule(
+            Path(Uuid::new_v4()),
+            State(state),
+            Some(test_user_claims()),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_service_check_run() {
+        use axum::response::IntoResponse;
+        use sea_orm::Set;
+
+        let state = WebState::test().await;
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to get service check")
+            .expect("No service checks found");
+
+        assert!(
+            api_service_check_run(Path(service_check.id), State(state.clone()), None)
+                .await
+                .is_err()
+        );
+        assert!(api_service_check_run(
+            Path(Uuid::new_v4()),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .is_err());
+
+        let Json(run) = api_service_check_run(
+            Path(service_check.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to run the check");
+        assert_eq!(run.service_check_id, service_check.id);
+        assert!(run.events.ends_with("/events"));
+        let service_check = entities::service_check::Entity::find_by_id(service_check.id)
+            .one(&state.db)
+            .await
+            .expect("Failed to get service check")
+            .expect("Service check went missing");
+        assert_eq!(service_check.status, ServiceStatus::Urgent);
+
+        assert!(api_service_check_events(
+            Path(service_check.id),
+            State(state.clone()),
+            None,
+            Query(EventsQuery::default()),
+        )
+        .await
+        .is_err());
+
+        // a result that landed before the page connected is sent straight away
+        entities::service_check_history::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            service_check_id: Set(service_check.id),
+            timestamp: Set(run.requested_at + chrono::TimeDelta::seconds(1)),
+            status: Set(ServiceStatus::Ok),
+            result_text: Set("ran now".to_string()),
+            time_elapsed: Set(0),
+            ..Default::default()
+        }
+        .insert(&state.db)
+        .await
+        .expect("Failed to insert history");
+        let events = api_service_check_events(
+            Path(service_check.id),
+            State(state.clone()),
+            Some(test_user_claims()),
+            Query(EventsQuery {
+                since: Some(run.requested_at),
+            }),
+        )
+        .await
+        .expect("Failed to get events");
+        let first = events
+            .into_response()
+            .into_body()
+            .into_data_stream()
+            .next()
+            .await
+            .expect("No events")
+            .expect("Failed to read event");
+        let first = String::from_utf8_lossy(&first);
+        assert!(first.contains("event: result"));
+        assert!(first.contains("ran now"));
+    }
+
+    #[tokio::test]
+    async fn test_service_check_artifact() {
+        let state = WebState::test().await;
+        let service_check = entities::service_check::Entity::find()
+            .one(&state.db)
+            .await
+            .expect("Failed to get service check")
+            .expect("No service checks found");
+        let directory = std::env::temp_dir().join(format!("maremma-artifacts-{}", Uuid::new_v4()));
+        ARTIFACTS
+            .configure(Some(&crate::artifacts::ArtifactConfig {
+                max_inline_bytes: 10,
+                directory: Some(directory.clone()),
+                s3: None,
+            }))
+            .expect("Failed to configure artifacts");
+
+        let mut result = crate::check_loop::CheckResult {
+            long_output: Some("lots of output".repeat(10)),
+            ..Default::default()
+        };
+        let artifact = ARTIFACTS.offload(&mut result, chrono::Utc::now()).await;
+        assert!(artifact.is_some());
+        let history = entities::service_check_history::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            service_check_id: Set(service_check.id),
+            timestamp: Set(chrono::Utc::now()),
+            status: Set(ServiceStatus::Ok),
+            result_text: Set("big".to_string()),
+            time_elapsed: Set(0),
+            long_output: Set(result.long_output),
+            artifact: Set(artifact),
+            ..Default::default()
+        }
+        .insert(&state.db)
+        .await
+        .expect("Failed to insert history");
+
+        assert!(service_check_artifact(
+            Path((service_check.id, history.id)),
+            State(state.clone()),
+            None,
+        )
+        .await
+        .is_err());
+        let (status, headers, contents) = service_check_artifact(
+            Path((service_check.id, history.id)),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to get artifact");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers.get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("text/plain; charset=utf-8"))
+        );
+        assert_eq!(contents, "lots of output".repeat(10).as_bytes());
+
+        // it has to be the check's own history
+        let res = service_check_artifact(
+            Path((Uuid::new_v4(), history.id)),
+            State(state.clone()),
+            Some(test_user_claims()),
+        )
+        .await;
+        assert_eq!(
+            res.map(|res| res.0).map_err(|err| err.0),
+            Err(StatusCode::NOT_FOUND)
+        );
+
+        ARTIFACTS
+            .configure(None)
+            .expect("Failed to reset artifacts");
+        tokio::fs::remove_dir_all(&directory)
+            .await
+            .expect("Failed to clean up");
+    }
 }
@@ -0,0 +1,93 @@
+//! The public status page, it doesn't need a login so it only shows what's in the `status_page` config
+
+use axum::http::header;
+use axum::Json;
+
+use super::prelude::*;
+use crate::status_page::StatusPage;
+
+#[derive(Template, Debug)]
+#[template(path = "status_page.html")]
+pub(crate) struct StatusPageTemplate {
+    page: StatusPage,
+    page_refresh: u64,
+}
+
+async fn build(state: &WebState) -> Result<StatusPage, (StatusCode, String)> {
+    let config = state.configuration.read().await.status_page.clone();
+    if !config.enabled {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    }
+    Ok(StatusPage::build(&state.db, &config).await?)
+}
+
+/// `GET /status`
+pub(crate) async fn status_page(
+    State(state): State<WebState>,
+) -> Result<StatusPageTemplate, (StatusCode, String)> {
+    Ok(StatusPageTemplate {
+        page: build(&state).await?,
+        page_refresh: 60,
+    })
+}
+
+/// `GET /status.json`, which other sites can embed
+pub(crate) async fn status_page_json(
+    State(state): State<WebState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    Ok((
+        [(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        Json(build(&state).await?),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status_page::StatusComponent;
+
+    #[tokio::test]
+    async fn test_status_page() {
+        let state = WebState::test().await;
+        // it's off by default
+        assert_eq!(
+            status_page(State(state.clone()))
+                .await
+                .err()
+                .map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+
+        {
+            let mut config = state.configuration.write().await;
+            config.status_page.enabled = true;
+            config.status_page.title = Some("Example Status".to_string());
+            config.status_page.components = vec![StatusComponent {
+                name: "Everything pingable".to_string(),
+                description: Some("Our servers".to_string()),
+                host_groups: vec![],
+                services: vec!["ping".to_string()],
+            }];
+        }
+        let page = status_page(State(state.clone()))
+            .await
+            .expect("Failed to get status page")
+            .render()
+            .expect("Failed to render status page");
+        assert!(page.contains("Example Status"));
+        assert!(page.contains("Everything pingable"));
+
+        let response = status_page_json(State(state))
+            .await
+            .expect("Failed to get status JSON")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .and_then(|value| value.to_str().ok()),
+            Some("*")
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Accepts results for passive service checks from external systems, eg cron jobs and backup scripts
+
+use axum::http::HeaderMap;
+use axum::Json;
+use tracing::warn;
+
+use super::agent::require_bearer;
+use super::prelude::*;
+use crate::errors::Error;
+use crate::services::passive::{self, SubmittedResult};
+
+pub(crate) async fn submit_result(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Json(submitted): Json<SubmittedResult>,
+) -> Result<StatusCode, Error> {
+    let config = state
+        .configuration
+        .read()
+        .await
+        .submit
+        .clone()
+        .ok_or_else(|| {
+            warn!("Passive result submitted but submissions aren't configured");
+            Error::Unauthorized
+        })?;
+
+    require_bearer(&headers, &config.token)
+        .inspect_err(|_| warn!("Invalid token for passive result submission"))?;
+
+    let service_check_id = passive::submit_result(state.db.clone(), &submitted).await?;
+    debug!(
+        "Recorded submitted result for service_check={} status={}",
+        service_check_id, submitted.status
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::passive::{test_passive_check, SubmitConfig};
+    use crate::web::views::agent::tests::auth_headers;
+
+    #[tokio::test]
+    async fn test_submit_result() {
+        let state = WebState::test().await;
+        let (host, service, _service_check) = test_passive_check(&state.db, None).await;
+        let submitted = SubmittedResult {
+            host: host.name.clone(),
+            service: service.name.clone(),
+            status: ServiceStatus::Ok,
+            result_text: "Backed up 123 files".to_string(),
+            long_output: None,
+            details: Default::default(),
+        };
+
+        // not configured
+        let res = submit_result(
+            State(state.clone()),
+            auth_headers("hunter2"),
+            Json(submitted.clone()),
+        )
+        .await;
+        assert_eq!(res, Err(Error::Unauthorized));
+
+        state.configuration.write().await.submit = Some(SubmitConfig {
+            token: "hunter2".to_string(),
+        });
+
+        let res = submit_result(
+            State(state.clone()),
+            auth_headers("wrong"),
+            Json(submitted.clone()),
+        )
+        .await;
+        assert_eq!(res, Err(Error::Unauthorized));
+
+        let res = submit_result(
+            State(state.clone()),
+            auth_headers("hunter2"),
+            Json(submitted.clone()),
+        )
+        .await;
+        assert_eq!(res, Ok(StatusCode::NO_CONTENT));
+
+        let res = submit_result(
+            State(state.clone()),
+            auth_headers("hunter2"),
+            Json(SubmittedResult {
+                host: "not a real host".to_string(),
+                ..submitted
+            }),
+        )
+        .await
+        .expect_err("Submitting to a missing host should fail");
+        assert_eq!(res.into_response().status(), StatusCode::NOT_FOUND);
+    }
+}
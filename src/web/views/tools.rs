@@ -1,12 +1,14 @@
 use super::prelude::*;
+use crate::archive::Archive;
+use crate::config::apply_config;
 use crate::constants::SESSION_CSRF_TOKEN;
 use crate::db::update_db_from_config;
 use crate::web::{Configuration, Error};
+use axum::extract::Multipart;
 use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use axum::http::{HeaderMap, HeaderValue};
 use axum::Form;
 use sea_orm::prelude::Expr;
-use tokio::sync::RwLock;
 
 #[cfg(test)]
 use openidconnect::{IssuerUrl, StandardClaims, SubjectIdentifier};
@@ -20,6 +22,7 @@ use std::str::FromStr;
 pub(crate) struct ToolsTemplate {
     title: String,
     username: Option<String>,
+    theme: PageTheme,
     message: Option<String>,
     status: ActionStatus,
     csrf_token: String,
@@ -76,9 +79,9 @@ pub(crate) struct ToolsForm {
 }
 #[derive(Deserialize, Default)]
 pub(crate) struct ToolsQuery {
-    result: Option<String>,
+    pub(crate) result: Option<String>,
     #[serde(default)]
-    status: ActionStatus,
+    pub(crate) status: ActionStatus,
 }
 
 #[instrument(level = "info", skip_all)]
@@ -96,19 +99,17 @@ async fn tools_reload_config(state: &WebState) -> Result<(), Redirect> {
             ))
         })?;
 
+    apply_config(&new_config).await.map_err(|e| {
+        error!("Failed to reload config: {:?}", e);
+        Redirect::to(&format!(
+            "{}?result=Failed to reload config&status={}",
+            Urls::Tools,
+            ActionStatus::Error,
+        ))
+    })?;
     *state.configuration.write().await = new_config;
 
-    let new_config = Configuration::new(&state.config_filepath)
-        .await
-        .map_err(|e| {
-            error!("Failed to reload config: {:?}", e);
-            Redirect::to(&format!(
-                "{}?result=Failed to load config from file&status={}",
-                Urls::Tools,
-                ActionStatus::Error,
-            ))
-        })?;
-    update_db_from_config(state.db.clone(), Arc::new(RwLock::new(new_config)))
+    update_db_from_config(state.db.clone(), state.configuration.clone())
         .await
         .map_err(|e| {
             error!("Failed to reload config: {:?}", e);
@@ -128,7 +129,7 @@ async fn tools_reload_config(state: &WebState) -> Result<(), Redirect> {
     )))
 }
 
-async fn check_csrf_token(csrf_token: &str, session: &Session) -> Result<(), Error> {
+pub(crate) async fn check_csrf_token(csrf_token: &str, session: &Session) -> Result<(), Error> {
     let session_csrf_token = session
         .get::<String>(SESSION_CSRF_TOKEN)
         .await
@@ -178,7 +179,7 @@ pub(crate) async fn tools(
                         entities::service_check::Column::Status,
                         Expr::value(ServiceStatus::Urgent),
                     )
-                    .exec(&*state.db.write().await)
+                    .exec(&state.db)
                     .await
                     .map_err(|e| {
                         error!("Failed to set all to urgent: {:?}", e);
@@ -208,10 +209,14 @@ pub(crate) async fn tools(
         .insert(SESSION_CSRF_TOKEN, &csrf_token)
         .await
         .map_err(|err| Error::from(err).into_response())?;
+    let theme = PageTheme::new(&state, &session)
+        .await
+        .map_err(|err| err.into_response())?;
 
     Ok(ToolsTemplate {
         title: "Tools".to_string(),
         username: claims.map(|c: OidcClaims<EmptyAdditionalClaims>| User::from(c).username()),
+        theme,
         message: results.result,
         status: results.status,
         csrf_token,
@@ -220,7 +225,7 @@ pub(crate) async fn tools(
 
 #[derive(Deserialize)]
 pub(crate) struct CsrfTokenForm {
-    csrf_token: String,
+    pub(crate) csrf_token: String,
 }
 
 pub(crate) async fn export_db(
@@ -257,6 +262,95 @@ pub(crate) async fn export_db(
     Ok((StatusCode::OK, headers, file_contents))
 }
 
+/// Archives can hold a lot of history, so uploads get a bigger limit than the default
+pub(crate) const MAX_ARCHIVE_UPLOAD_BYTES: usize = 1024 * 1024 * 1024;
+
+/// Downloads an archive of the config and database, seen at `/tools/export`
+pub(crate) async fn export_archive(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    Form(form): Form<CsrfTokenForm>,
+) -> Result<(StatusCode, HeaderMap, Vec<u8>), Error> {
+    if claims.is_none() {
+        // TODO: check that the user is an admin
+        return Err(Error::Unauthorized);
+    }
+
+    check_csrf_token(&form.csrf_token, &session).await?;
+
+    let archive = Archive::from_db(&state.db, Some(&state.config_filepath)).await?;
+    let file_contents = archive.to_bytes()?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/zstd"));
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!(
+            "attachment; filename=\"maremma-{}.tar.zst\"",
+            archive.manifest.created.format("%Y%m%d-%H%M%S")
+        ))
+        .map_err(Error::from)?,
+    );
+
+    Ok((StatusCode::OK, headers, file_contents))
+}
+
+/// Restores an uploaded archive into the database, seen at `/tools/import`. The config file's left alone.
+pub(crate) async fn import_archive(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    session: Session,
+    mut multipart: Multipart,
+) -> Result<Redirect, Error> {
+    if claims.is_none() {
+        // TODO: check that the user is an admin
+        return Err(Error::Unauthorized);
+    }
+
+    let mut csrf_token = None;
+    let mut data = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| Error::InvalidInput(format!("Failed to read upload: {}", err)))?
+    {
+        match field.name() {
+            Some(SESSION_CSRF_TOKEN) => {
+                csrf_token = Some(field.text().await.map_err(|err| {
+                    Error::InvalidInput(format!("Failed to read upload: {}", err))
+                })?)
+            }
+            Some("archive") => {
+                data = Some(field.bytes().await.map_err(|err| {
+                    Error::InvalidInput(format!("Failed to read upload: {}", err))
+                })?)
+            }
+            _ => {}
+        }
+    }
+    check_csrf_token(&csrf_token.ok_or(Error::CsrfTokenMissing)?, &session).await?;
+    let data = data.ok_or_else(|| Error::InvalidInput("No archive uploaded".to_string()))?;
+
+    let archive = tokio::task::spawn_blocking(move || Archive::read(data.as_ref()))
+        .await
+        .map_err(|err| Error::Generic(format!("Failed to read archive: {:?}", err)))??;
+    let restored: usize = archive
+        .restore(&state.db)
+        .await
+        .inspect_err(|err| error!("Failed to restore archive: {:?}", err))?
+        .iter()
+        .map(|(_table, count)| count)
+        .sum();
+
+    Ok(Redirect::to(&format!(
+        "{}?result=Restored {} rows from the archive&status={}",
+        Urls::Tools,
+        restored,
+        ActionStatus::Success,
+    )))
+}
+
 #[cfg(test)]
 /// Use this when you want to be "authenticated"
 pub(crate) fn test_user_claims() -> OidcClaims<EmptyAdditionalClaims> {
@@ -368,6 +462,7 @@ mod tests {
     #[tokio::test]
     async fn test_tools_reload_config() {
         test_setup().await.expect("Failed to start test harness");
+        let _lock = crate::config::APPLY_CONFIG_LOCK.lock().await;
         use super::*;
 
         let state = WebState::test().await;
@@ -451,6 +546,94 @@ mod tests {
         }
     }
 
+    async fn multipart(body: Vec<u8>, boundary: &str) -> Multipart {
+        use axum::extract::FromRequest;
+        let request = axum::http::Request::builder()
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(axum::body::Body::from(body))
+            .expect("Failed to build request");
+        Multipart::from_request(request, &())
+            .await
+            .expect("Failed to parse multipart request")
+    }
+
+    #[tokio::test]
+    async fn test_tools_archive_export_import() {
+        let mut state = WebState::test().await;
+        state.config_filepath =
+            PathBuf::from_str("maremma.example.json").expect("failed to pathbuf test config");
+        let session = state.get_session();
+        let csrf_token = "foo".to_string();
+        session
+            .insert(SESSION_CSRF_TOKEN, csrf_token.clone())
+            .await
+            .expect("Failed to insert CSRF token into session");
+
+        assert!(export_archive(
+            State(state.clone()),
+            None,
+            session.clone(),
+            Form(CsrfTokenForm {
+                csrf_token: csrf_token.clone(),
+            }),
+        )
+        .await
+        .is_err());
+
+        let (status, _headers, archive) = export_archive(
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+            Form(CsrfTokenForm {
+                csrf_token: csrf_token.clone(),
+            }),
+        )
+        .await
+        .expect("Failed to export archive");
+        assert_eq!(status, StatusCode::OK);
+        assert!(Archive::read(archive.as_slice())
+            .expect("Failed to read archive")
+            .config
+            .is_some());
+
+        let boundary = "maremmaboundary";
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{SESSION_CSRF_TOKEN}\"\r\n\r\n{csrf_token}\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"archive\"; filename=\"backup.tar.zst\"\r\nContent-Type: application/zstd\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(&archive);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let res = import_archive(
+            State(state.clone()),
+            Some(test_user_claims()),
+            session.clone(),
+            multipart(body.clone(), boundary).await,
+        )
+        .await
+        .expect("Failed to import archive")
+        .into_response();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        session
+            .insert(SESSION_CSRF_TOKEN, "bar".to_string())
+            .await
+            .expect("Failed to insert CSRF token into session");
+        assert!(matches!(
+            import_archive(
+                State(state.clone()),
+                Some(test_user_claims()),
+                session,
+                multipart(body, boundary).await,
+            )
+            .await,
+            Err(Error::CsrfValidationFailed)
+        ));
+    }
+
     #[tokio::test]
     async fn test_tools_db_export_invalid_token() {
         test_setup().await.expect("Failed to start test harness");
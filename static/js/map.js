@@ -0,0 +1,115 @@
+// draws the /map page, groups on the left, then hosts, then services, with lines for how they're linked
+const MAP_COLUMNS = ["group", "host", "service"];
+const MAP_COLUMN_WIDTH = 280;
+const MAP_ROW_HEIGHT = 28;
+const MAP_NODE_WIDTH = 220;
+const MAP_NODE_HEIGHT = 22;
+const SVG_NS = "http://www.w3.org/2000/svg";
+
+function mapElement(name, attributes) {
+    const element = document.createElementNS(SVG_NS, name);
+    for (const [key, value] of Object.entries(attributes)) {
+        element.setAttribute(key, value);
+    }
+    return element;
+}
+
+function renderMap(svg, map) {
+    svg.replaceChildren();
+    const positions = {};
+    const rows = {};
+    for (const node of map.nodes) {
+        const column = MAP_COLUMNS.indexOf(node.kind);
+        const row = rows[node.kind] || 0;
+        rows[node.kind] = row + 1;
+        positions[node.id] = {
+            x: column * MAP_COLUMN_WIDTH + 50,
+            y: row * MAP_ROW_HEIGHT + 10,
+        };
+    }
+    const height = Math.max(1, ...Object.values(rows)) * MAP_ROW_HEIGHT + 20;
+    svg.setAttribute("width", MAP_COLUMNS.length * MAP_COLUMN_WIDTH + 50);
+    svg.setAttribute("height", height);
+
+    const edges = mapElement("g", { "stroke": "var(--bs-secondary)", "fill": "none" });
+    for (const edge of map.edges) {
+        const from = positions[edge.from];
+        const to = positions[edge.to];
+        if (!from || !to) {
+            continue;
+        }
+        // nested groups are in the same column, so those curve out to the left
+        const x1 = from.x === to.x ? from.x : from.x + MAP_NODE_WIDTH;
+        const y1 = from.y + MAP_NODE_HEIGHT / 2;
+        const x2 = to.x;
+        const y2 = to.y + MAP_NODE_HEIGHT / 2;
+        const bend = from.x === to.x ? x1 - 40 : (x1 + x2) / 2;
+        edges.appendChild(mapElement("path", {
+            "d": `M ${x1} ${y1} C ${bend} ${y1}, ${bend} ${y2}, ${x2} ${y2}`,
+            "data-from": edge.from,
+            "data-to": edge.to,
+            "opacity": "0.4",
+        }));
+    }
+    svg.appendChild(edges);
+
+    for (const node of map.nodes) {
+        const position = positions[node.id];
+        const link = mapElement("a", { "href": node.url });
+        const title = mapElement("title", {});
+        title.textContent = `${node.name}: ${node.status || "no results"}`;
+        link.appendChild(title);
+        link.appendChild(mapElement("rect", {
+            "x": position.x,
+            "y": position.y,
+            "width": MAP_NODE_WIDTH,
+            "height": MAP_NODE_HEIGHT,
+            "rx": 4,
+            "fill": `var(--bs-${node.colour})`,
+        }));
+        const text = mapElement("text", {
+            "x": position.x + 6,
+            "y": position.y + MAP_NODE_HEIGHT - 6,
+            "fill": "var(--bs-white)",
+            "font-size": "13",
+        });
+        text.textContent = node.name.length > 30 ? node.name.slice(0, 29) + "…" : node.name;
+        link.appendChild(text);
+        // highlight what's linked to the node under the mouse
+        link.addEventListener("mouseenter", function() {
+            for (const path of edges.children) {
+                if (path.dataset.from === node.id || path.dataset.to === node.id) {
+                    path.setAttribute("opacity", "1");
+                }
+            }
+        });
+        link.addEventListener("mouseleave", function() {
+            for (const path of edges.children) {
+                path.setAttribute("opacity", "0.4");
+            }
+        });
+        svg.appendChild(link);
+    }
+}
+
+function drawMap(elementId, url) {
+    document.addEventListener('DOMContentLoaded', function() {
+        const svg = document.getElementById(elementId);
+        if (!svg) {
+            return;
+        }
+        fetch(url, { credentials: "same-origin" })
+            .then(response => {
+                if (!response.ok) {
+                    throw new Error(`Failed to load the map: ${response.status}`);
+                }
+                return response.json();
+            })
+            .then(map => renderMap(svg, map))
+            .catch(err => {
+                const text = mapElement("text", { "x": 10, "y": 20, "fill": "var(--bs-danger)" });
+                text.textContent = err.message;
+                svg.replaceChildren(text);
+            });
+    });
+}
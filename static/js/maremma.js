@@ -24,4 +24,126 @@ function resetSearch(formElementId, searchElementId) {
             });
         }
     });
-}
\ No newline at end of file
+}
+// shows times relative to now, with the full time in the user's timezone on hover
+function relativeTime(date) {
+    const seconds = Math.round((date.getTime() - Date.now()) / 1000);
+    const units = [
+        ["d", 86400],
+        ["h", 3600],
+        ["m", 60],
+    ];
+    let text = Math.abs(seconds) + "s";
+    for (const [unit, size] of units) {
+        if (Math.abs(seconds) >= size) {
+            text = Math.floor(Math.abs(seconds) / size) + unit;
+            break;
+        }
+    }
+    return seconds > 0 ? "in " + text : text + " ago";
+}
+
+function localiseTimes() {
+    const timeZone = document.documentElement.dataset.timezone;
+    let formatter;
+    try {
+        formatter = new Intl.DateTimeFormat(undefined, {
+            dateStyle: "medium",
+            timeStyle: "long",
+            timeZone: timeZone,
+        });
+    } catch (err) {
+        // an unknown timezone, so use the browser's
+        formatter = new Intl.DateTimeFormat(undefined, {
+            dateStyle: "medium",
+            timeStyle: "long",
+        });
+    }
+    document.querySelectorAll("time.maremma-time").forEach(function(element) {
+        const date = new Date(element.getAttribute("datetime"));
+        if (isNaN(date.getTime())) {
+            return;
+        }
+        element.title = formatter.format(date);
+        element.textContent = relativeTime(date);
+    });
+}
+
+document.addEventListener('DOMContentLoaded', function() {
+    localiseTimes();
+    setInterval(localiseTimes, 15000);
+});
+
+// the bulk action form, its checkboxes sit in the table rows and point at it with their form attribute
+function bulkForm(formElementId, selectAllElementId) {
+    document.addEventListener('DOMContentLoaded', function() {
+        const form = document.getElementById(formElementId);
+        if (!form) {
+            return;
+        }
+        const checkboxes = function() {
+            return document.querySelectorAll('input[form="' + formElementId + '"][name="service_check_id"]');
+        };
+        const selectAll = document.getElementById(selectAllElementId);
+        if (selectAll) {
+            selectAll.addEventListener('change', function() {
+                checkboxes().forEach(function(checkbox) {
+                    checkbox.checked = selectAll.checked;
+                });
+            });
+        }
+        form.addEventListener('submit', function(event) {
+            const selected = Array.from(checkboxes()).filter(function(checkbox) {
+                return checkbox.checked;
+            }).length;
+            if (selected === 0) {
+                event.preventDefault();
+                alert("Select some checks first");
+            } else if (form.elements["action"].value === "delete"
+                && !confirm("Are you sure you want to delete " + selected + " checks?")) {
+                event.preventDefault();
+            }
+        });
+    });
+}
+
+// the "Run now" button, marks the check urgent then shows the result when it's written, falls back to the plain form
+function runNow(formElementId, runUrl, statusElementId, resultElementId) {
+    document.addEventListener('DOMContentLoaded', function() {
+        const form = document.getElementById(formElementId);
+        if (!form || !window.EventSource || !window.fetch) {
+            return;
+        }
+        const button = form.querySelector('input[type="submit"]');
+        const status = document.getElementById(statusElementId);
+        const result = document.getElementById(resultElementId);
+        form.addEventListener('submit', function(event) {
+            event.preventDefault();
+            button.disabled = true;
+            fetch(runUrl, { method: "POST", credentials: "same-origin" })
+                .then(function(response) {
+                    if (!response.ok) {
+                        throw new Error(response.statusText);
+                    }
+                    return response.json();
+                })
+                .then(function(run) {
+                    status.textContent = "Urgent";
+                    const events = new EventSource(run.events + "?since=" + encodeURIComponent(run.requested_at));
+                    events.addEventListener("result", function(message) {
+                        const update = JSON.parse(message.data);
+                        status.className = "badge bg-" + update.background + " text-" + update.text;
+                        status.textContent = update.label;
+                        result.textContent = update.result_text;
+                        result.classList.remove("d-none");
+                        button.disabled = false;
+                        events.close();
+                    });
+                })
+                .catch(function() {
+                    // let the form do it the old way
+                    form.submit();
+                });
+        });
+    });
+}